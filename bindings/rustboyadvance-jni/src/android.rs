@@ -0,0 +1,145 @@
+//! Draws the frames straight into an android `Bitmap` or `Surface`, through the jnigraphics and
+//! native window APIs of the NDK
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use jni::objects::JObject;
+use jni::sys::{self, jobject};
+use jni::JNIEnv;
+
+use rustboyadvance_core::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+const ANDROID_BITMAP_RESULT_SUCCESS: c_int = 0;
+const ANDROID_BITMAP_FORMAT_RGBA_8888: i32 = 1;
+const WINDOW_FORMAT_RGBX_8888: i32 = 2;
+
+#[repr(C)]
+struct AndroidBitmapInfo {
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct ANativeWindowBuffer {
+    width: i32,
+    height: i32,
+    stride: i32,
+    format: i32,
+    bits: *mut c_void,
+    reserved: [u32; 6],
+}
+
+enum ANativeWindow {}
+
+#[link(name = "jnigraphics")]
+extern "C" {
+    fn AndroidBitmap_getInfo(
+        env: *mut sys::JNIEnv,
+        bitmap: jobject,
+        info: *mut AndroidBitmapInfo,
+    ) -> c_int;
+    fn AndroidBitmap_lockPixels(
+        env: *mut sys::JNIEnv,
+        bitmap: jobject,
+        pixels: *mut *mut c_void,
+    ) -> c_int;
+    fn AndroidBitmap_unlockPixels(env: *mut sys::JNIEnv, bitmap: jobject) -> c_int;
+}
+
+#[link(name = "android")]
+extern "C" {
+    fn ANativeWindow_fromSurface(env: *mut sys::JNIEnv, surface: jobject) -> *mut ANativeWindow;
+    fn ANativeWindow_release(window: *mut ANativeWindow);
+    fn ANativeWindow_setBuffersGeometry(
+        window: *mut ANativeWindow,
+        width: i32,
+        height: i32,
+        format: i32,
+    ) -> i32;
+    fn ANativeWindow_lock(
+        window: *mut ANativeWindow,
+        buffer: *mut ANativeWindowBuffer,
+        dirty_bounds: *mut c_void,
+    ) -> i32;
+    fn ANativeWindow_unlockAndPost(window: *mut ANativeWindow) -> i32;
+}
+
+/// 0x00RRGGBB to the R, G, B and A bytes of RGBA_8888
+fn to_rgba(pixel: u32) -> u32 {
+    0xff00_0000 | (pixel & 0xff) << 16 | (pixel & 0xff00) | (pixel >> 16) & 0xff
+}
+
+/// Copies `frame` into rows of `stride` pixels starting at `pixels`
+unsafe fn copy_frame(frame: &[u32], pixels: *mut u32, stride: usize) {
+    for (y, line) in frame.chunks_exact(DISPLAY_WIDTH).enumerate() {
+        let row = slice::from_raw_parts_mut(pixels.add(y * stride), DISPLAY_WIDTH);
+        for (dst, src) in row.iter_mut().zip(line) {
+            *dst = to_rgba(*src);
+        }
+    }
+}
+
+/// The bitmap has to be a mutable ARGB_8888 one of the display size
+pub unsafe fn draw_to_bitmap(env: &JNIEnv, bitmap: JObject, frame: &[u32]) -> Result<(), String> {
+    let raw_env = env.get_native_interface();
+    let bitmap = bitmap.into_inner();
+
+    let mut info: AndroidBitmapInfo = mem::zeroed();
+    if AndroidBitmap_getInfo(raw_env, bitmap, &mut info) != ANDROID_BITMAP_RESULT_SUCCESS {
+        return Err(String::from("could not get the bitmap info"));
+    }
+    if info.width as usize != DISPLAY_WIDTH
+        || info.height as usize != DISPLAY_HEIGHT
+        || info.format != ANDROID_BITMAP_FORMAT_RGBA_8888
+    {
+        return Err(format!(
+            "the bitmap is {}x{} with format {}, expected {}x{} ARGB_8888",
+            info.width, info.height, info.format, DISPLAY_WIDTH, DISPLAY_HEIGHT
+        ));
+    }
+
+    let mut pixels = ptr::null_mut();
+    if AndroidBitmap_lockPixels(raw_env, bitmap, &mut pixels) != ANDROID_BITMAP_RESULT_SUCCESS {
+        return Err(String::from("could not lock the bitmap pixels"));
+    }
+    copy_frame(frame, pixels as *mut u32, info.stride as usize / 4);
+    AndroidBitmap_unlockPixels(raw_env, bitmap);
+    Ok(())
+}
+
+/// The frame is posted at the display size, the surface scales it to its view
+pub unsafe fn draw_to_surface(env: &JNIEnv, surface: JObject, frame: &[u32]) -> Result<(), String> {
+    let window = ANativeWindow_fromSurface(env.get_native_interface(), surface.into_inner());
+    if window.is_null() {
+        return Err(String::from(
+            "could not get the native window of the surface",
+        ));
+    }
+    let result = draw_to_window(window, frame);
+    ANativeWindow_release(window);
+    result
+}
+
+unsafe fn draw_to_window(window: *mut ANativeWindow, frame: &[u32]) -> Result<(), String> {
+    if ANativeWindow_setBuffersGeometry(
+        window,
+        DISPLAY_WIDTH as i32,
+        DISPLAY_HEIGHT as i32,
+        WINDOW_FORMAT_RGBX_8888,
+    ) != 0
+    {
+        return Err(String::from("could not set the surface geometry"));
+    }
+    let mut buffer: ANativeWindowBuffer = mem::zeroed();
+    if ANativeWindow_lock(window, &mut buffer, ptr::null_mut()) != 0 {
+        return Err(String::from("could not lock the surface"));
+    }
+    copy_frame(frame, buffer.bits as *mut u32, buffer.stride as usize);
+    ANativeWindow_unlockAndPost(window);
+    Ok(())
+}
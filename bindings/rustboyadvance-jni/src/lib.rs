@@ -1,5 +1,7 @@
 /// JNI Bindings for rustboyadvance
 ///
+#[cfg(target_os = "android")]
+mod android;
 mod rom_helper;
 
 use std::cell::RefCell;
@@ -25,26 +27,15 @@ use rustboyadvance_core::util::audio::AudioRingBuffer;
 use rustboyadvance_core::StereoSample;
 
 struct Hardware {
-    jvm: JavaVM,
-    frame_buffer_global_ref: GlobalRef,
     audio_buffer: AudioRingBuffer,
     key_state: u16,
 }
 
-impl VideoInterface for Hardware {
-    fn render(&mut self, buffer: &[u32]) {
-        let env = self.jvm.get_env().unwrap();
-        unsafe {
-            env.set_int_array_region(
-                self.frame_buffer_global_ref.as_obj().into_inner(),
-                0,
-                std::mem::transmute::<&[u32], &[i32]>(buffer),
-            )
-            .unwrap();
-        }
-    }
-}
-impl AudioInterface for Hardware {
+/// The frames are copied out once by `runFrame`, or drawn by `runFrameToBitmap` and
+/// `runFrameToSurface`
+impl VideoSink for Hardware {}
+
+impl AudioSink for Hardware {
     fn push_sample(&mut self, sample: StereoSample<i16>) {
         if self.audio_buffer.prod.push(sample.0).is_err() {
             warn!("failed to push audio sample");
@@ -54,7 +45,7 @@ impl AudioInterface for Hardware {
         }
     }
 }
-impl InputInterface for Hardware {
+impl InputSource for Hardware {
     fn poll(&mut self) -> u16 {
         self.key_state
     }
@@ -74,7 +65,6 @@ unsafe fn internal_open_context(
     env: &JNIEnv,
     bios: jbyteArray,
     rom: jbyteArray,
-    frame_buffer: jintArray,
     save_file: JString,
     skip_bios: jboolean,
 ) -> Result<Context, String> {
@@ -99,19 +89,13 @@ unsafe fn internal_open_context(
 
     info!("Loaded ROM file {:?}", gamepak.header);
 
-    let frame_buffer_global_ref = env
-        .new_global_ref(JObject::from(frame_buffer))
-        .map_err(|e| format!("failed to add new global ref, error: {:?}", e))?;
-
     let hw = Hardware {
-        jvm: env.get_java_vm().unwrap(),
-        frame_buffer_global_ref: frame_buffer_global_ref,
         audio_buffer: AudioRingBuffer::new(),
         key_state: 0xffff,
     };
     let hw = Rc::new(RefCell::new(hw));
 
-    let mut gba = GameBoyAdvance::new(bios, gamepak, hw.clone(), hw.clone(), hw.clone());
+    let mut gba = GameBoyAdvance::new(bios, gamepak, Frontend::from_device(hw.clone()));
 
     if skip_bios != 0 {
         debug!("skipping bios");
@@ -177,11 +161,12 @@ pub mod bindings {
         _obj: JClass,
         bios: jbyteArray,
         rom: jbyteArray,
-        frame_buffer: jintArray,
+        // unused, the frames are copied out by `runFrame`, kept for the Java signature
+        _frame_buffer: jintArray,
         save_file: JString,
         skip_bios: jboolean,
     ) -> jlong {
-        match internal_open_context(&env, bios, rom, frame_buffer, save_file, skip_bios) {
+        match internal_open_context(&env, bios, rom, save_file, skip_bios) {
             Ok(ctx) => Box::into_raw(Box::new(Mutex::new(ctx))) as jlong,
             Err(msg) => {
                 env.throw_new(NATIVE_EXCEPTION_CLASS, msg).unwrap();
@@ -190,28 +175,18 @@ pub mod bindings {
         }
     }
 
-    fn internal_open_saved_state(
-        env: &JNIEnv,
-        state: jbyteArray,
-        frame_buffer: jintArray,
-    ) -> Result<Context, String> {
+    fn internal_open_saved_state(env: &JNIEnv, state: jbyteArray) -> Result<Context, String> {
         let state = env
             .convert_byte_array(state)
             .map_err(|e| format!("could not get state buffer, error {}", e))?;
 
-        let frame_buffer_global_ref = env
-            .new_global_ref(JObject::from(frame_buffer))
-            .map_err(|e| format!("failed to add new global ref, error: {:?}", e))?;
-
         let hw = Hardware {
-            jvm: env.get_java_vm().unwrap(),
-            frame_buffer_global_ref: frame_buffer_global_ref,
             audio_buffer: AudioRingBuffer::new(),
             key_state: 0xffff,
         };
         let hw = Rc::new(RefCell::new(hw));
 
-        let gba = GameBoyAdvance::from_saved_state(&state, hw.clone(), hw.clone(), hw.clone())
+        let gba = GameBoyAdvance::from_saved_state(&state, Frontend::from_device(hw.clone()))
             .map_err(|e| {
                 format!(
                     "failed to create GameBoyAdvance from saved state, error {:?}",
@@ -230,9 +205,10 @@ pub mod bindings {
         env: JNIEnv,
         _obj: JClass,
         state: jbyteArray,
-        frame_buffer: jintArray,
+        // unused, the frames are copied out by `runFrame`, kept for the Java signature
+        _frame_buffer: jintArray,
     ) -> jlong {
-        match internal_open_saved_state(&env, state, frame_buffer) {
+        match internal_open_saved_state(&env, state) {
             Ok(ctx) => Box::into_raw(Box::new(Mutex::new(ctx))) as jlong,
             Err(msg) => {
                 env.throw_new(NATIVE_EXCEPTION_CLASS, msg).unwrap();
@@ -252,6 +228,17 @@ pub mod bindings {
         let _ = Box::from_raw(ctx as *mut Mutex<Context>);
     }
 
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_skipBios(
+        _env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+    ) {
+        let mut ctx = lock_ctx(ctx);
+        debug!("skipping bios");
+        ctx.gba.skip_bios();
+    }
+
     #[no_mangle]
     pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_runFrame(
         env: JNIEnv,
@@ -262,6 +249,51 @@ pub mod bindings {
         let mut ctx = lock_ctx(ctx);
 
         ctx.gba.frame();
+
+        if !frame_buffer.is_null() {
+            let buffer = ctx.gba.get_frame_buffer();
+            if let Err(e) = env.set_int_array_region(
+                frame_buffer,
+                0,
+                std::mem::transmute::<&[u32], &[i32]>(buffer),
+            ) {
+                warn!("failed to copy frame buffer, error: {:?}", e);
+            }
+        }
+    }
+
+    #[cfg(target_os = "android")]
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_runFrameToBitmap(
+        env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+        bitmap: JObject,
+    ) {
+        let mut ctx = lock_ctx(ctx);
+
+        ctx.gba.frame();
+
+        if let Err(msg) = android::draw_to_bitmap(&env, bitmap, ctx.gba.get_frame_buffer()) {
+            env.throw_new(NATIVE_EXCEPTION_CLASS, msg).unwrap();
+        }
+    }
+
+    #[cfg(target_os = "android")]
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_runFrameToSurface(
+        env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+        surface: JObject,
+    ) {
+        let mut ctx = lock_ctx(ctx);
+
+        ctx.gba.frame();
+
+        if let Err(msg) = android::draw_to_surface(&env, surface, ctx.gba.get_frame_buffer()) {
+            env.throw_new(NATIVE_EXCEPTION_CLASS, msg).unwrap();
+        }
     }
 
     #[no_mangle]
@@ -286,6 +318,49 @@ pub mod bindings {
         return arr;
     }
 
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_collectAudioSamplesInto(
+        env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+        buffer: jshortArray,
+    ) -> jint {
+        let ctx = lock_ctx(ctx);
+
+        let mut hw = ctx.hwif.borrow_mut();
+
+        let capacity = match env.get_array_length(buffer) {
+            Ok(len) => len as usize,
+            Err(e) => {
+                env.throw_new(
+                    NATIVE_EXCEPTION_CLASS,
+                    format!("failed to get audio buffer length, error: {:?}", e),
+                )
+                .unwrap();
+                return 0;
+            }
+        };
+
+        let mut samples = Vec::with_capacity(capacity);
+        while samples.len() < capacity {
+            match hw.audio_buffer.cons.pop() {
+                Some(sample) => samples.push(sample),
+                None => break,
+            }
+        }
+
+        if let Err(e) = env.set_short_array_region(buffer, 0, &samples) {
+            env.throw_new(
+                NATIVE_EXCEPTION_CLASS,
+                format!("failed to copy audio samples, error: {:?}", e),
+            )
+            .unwrap();
+            return 0;
+        }
+
+        samples.len() as jint
+    }
+
     #[no_mangle]
     pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_setKeyState(
         _env: JNIEnv,
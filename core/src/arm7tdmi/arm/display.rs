@@ -1,9 +1,10 @@
-use std::fmt;
+use alloc::string::ToString;
+use core::fmt;
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 use crate::bit::BitIndex;
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 use super::{ArmFormat, ArmInstruction};
 
 use super::{AluOpCode, ArmCond, ArmHalfwordTransferType};
@@ -28,6 +29,7 @@ impl fmt::Display for ArmCond {
             GT => write!(f, "gt"),
             LE => write!(f, "le"),
             AL => write!(f, ""), // the dissasembly should ignore this
+            NV => write!(f, "nv"),
         }
     }
 }
@@ -102,7 +104,7 @@ impl fmt::Display for ShiftedRegister {
     }
 }
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 impl ArmInstruction {
     fn fmt_bx(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "bx\t{Rn}", Rn = reg_string(self.rn()))
@@ -412,7 +414,7 @@ impl ArmInstruction {
     }
 }
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 impl fmt::Display for ArmInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use ArmFormat::*;
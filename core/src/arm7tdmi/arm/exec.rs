@@ -38,10 +38,8 @@ impl Core {
     }
 
     pub fn arm_undefined(&mut self, _: &mut SysBus, insn: &ArmInstruction) -> CpuAction {
-        panic!(
-            "executing undefined arm instruction {:08x} at @{:08x}",
-            insn.raw, insn.pc
-        )
+        self.set_fault(insn.pc, insn.raw, "undefined arm instruction");
+        CpuAction::AdvancePC
     }
 
     /// Cycles 2S+1N
@@ -146,7 +144,8 @@ impl Core {
         match self.cpsr.mode() {
             CpuMode::User => {
                 if is_spsr {
-                    panic!("User mode can't access SPSR")
+                    self.set_fault(insn.pc, insn.raw, "User mode can't access SPSR");
+                    return CpuAction::AdvancePC;
                 }
                 self.cpsr.set_flag_bits(value);
             }
@@ -414,6 +413,18 @@ impl Core {
         let mut result = CpuAction::AdvancePC;
 
         let load = insn.load_flag();
+        // stores only exist for unsigned halfwords
+        let transfer_type = match insn.halfword_data_transfer_type() {
+            Ok(transfer_type)
+                if load || transfer_type == ArmHalfwordTransferType::UnsignedHalfwords =>
+            {
+                transfer_type
+            }
+            _ => {
+                self.set_fault(insn.pc, insn.raw, "invalid halfword transfer type");
+                return result;
+            }
+        };
         let pre_index = insn.pre_index_flag();
         let writeback = insn.write_back_flag();
         let base_reg = insn.raw.bit_range(16..20) as usize;
@@ -440,7 +451,7 @@ impl Core {
 
         if load {
             self.S_cycle32(sb, self.pc);
-            let data = match insn.halfword_data_transfer_type().unwrap() {
+            let data = match transfer_type {
                 ArmHalfwordTransferType::SignedByte => {
                     self.N_cycle8(sb, addr);
                     sb.read_8(addr) as u8 as i8 as u32
@@ -471,14 +482,9 @@ impl Core {
                 self.get_reg(dest_reg)
             };
 
-            match insn.halfword_data_transfer_type().unwrap() {
-                ArmHalfwordTransferType::UnsignedHalfwords => {
-                    self.N_cycle32(sb, addr);
-                    self.write_16(addr, value as u16, sb);
-                    self.N_cycle32(sb, self.pc);
-                }
-                _ => panic!("invalid HS flags for L=0"),
-            };
+            self.N_cycle32(sb, addr);
+            self.write_16(addr, value as u16, sb);
+            self.N_cycle32(sb, self.pc);
         }
 
         if !load || base_reg != dest_reg {
@@ -508,7 +514,8 @@ impl Core {
         if s_flag {
             match self.cpsr.mode() {
                 CpuMode::User | CpuMode::System => {
-                    panic!("LDM/STM with S bit in unprivileged mode")
+                    self.set_fault(insn.pc, insn.raw, "LDM/STM with S bit in unprivileged mode");
+                    return CpuAction::AdvancePC;
                 }
                 _ => {}
             };
@@ -757,7 +764,6 @@ impl Core {
     }
 
     pub fn exec_arm_swi(&mut self, sb: &mut SysBus, insn: &ArmInstruction) -> CpuAction {
-        self.software_interrupt(sb, self.pc - 4, insn.swi_comment());
-        CpuAction::FlushPipeline
+        self.software_interrupt(sb, self.pc - 4, (insn.swi_comment() >> 16) & 0xff)
     }
 }
@@ -7,9 +7,10 @@ use super::alu::*;
 use crate::arm7tdmi::{Addr, InstructionDecoder};
 
 use crate::bit::BitIndex;
-use crate::byteorder::{LittleEndian, ReadBytesExt};
+use crate::byteorder::{ByteOrder, LittleEndian};
 use crate::num::FromPrimitive;
 
+#[cfg(feature = "std")]
 use std::io;
 
 #[derive(Debug, PartialEq)]
@@ -19,6 +20,7 @@ pub enum ArmDecodeErrorKind {
     UndefinedConditionCode(u32),
     InvalidShiftType(u32),
     InvalidHSBits(u32),
+    #[cfg(feature = "std")]
     IoError(io::ErrorKind),
 }
 use ArmDecodeErrorKind::*;
@@ -57,6 +59,8 @@ pub enum ArmCond {
     GT = 0b1100,
     LE = 0b1101,
     AL = 0b1110,
+    /// Never, reserved on ARMv4
+    NV = 0b1111,
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
@@ -150,8 +154,7 @@ impl InstructionDecoder for ArmInstruction {
     }
 
     fn decode_from_bytes(bytes: &[u8], addr: Addr) -> Self {
-        let mut rdr = std::io::Cursor::new(bytes);
-        let raw = rdr.read_u32::<LittleEndian>().unwrap();
+        let raw = LittleEndian::read_u32(bytes);
         Self::decode(raw, addr)
     }
 
@@ -206,7 +209,7 @@ impl ArmInstruction {
     }
 
     pub fn opcode(&self) -> AluOpCode {
-        use std::hint::unreachable_unchecked;
+        use core::hint::unreachable_unchecked;
 
         unsafe {
             if let Some(opc) = AluOpCode::from_u16(self.raw.bit_range(21..25) as u16) {
@@ -1,10 +1,13 @@
 #[cfg(feature = "debugger")]
 use super::reg_string;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
 #[cfg(feature = "debugger")]
 use ansi_term::{Colour, Style};
-use serde::{Deserialize, Serialize};
 #[cfg(feature = "debugger")]
-use std::fmt;
+use core::fmt;
+use serde::{Deserialize, Serialize};
 
 use super::arm::ArmCond;
 
@@ -15,22 +18,49 @@ include!(concat!(env!("OUT_DIR"), "/arm_lut.rs"));
 #[cfg(feature = "arm7tdmi_dispatch_table")]
 include!(concat!(env!("OUT_DIR"), "/thumb_lut.rs"));
 
-#[cfg(not(feature = "arm7tdmi_dispatch_table"))]
 use super::InstructionDecoder;
 
 pub use super::exception::Exception;
 
 use super::CpuAction;
-#[cfg(feature = "debugger")]
 use super::DecodedInstruction;
 use super::{arm::*, psr::RegPSR, thumb::ThumbInstruction, Addr, CpuMode, CpuState};
 
 use crate::bus::Bus;
 use crate::sysbus::{MemoryAccessType::*, MemoryAccessWidth::*, SysBus};
+#[cfg(feature = "trace")]
+use crate::trace::MemAccess;
 
 use bit::BitIndex;
 use num::FromPrimitive;
 
+/// Number of executed instructions kept for crash reports
+pub const EXECUTION_HISTORY_LEN: usize = 32;
+
+/// An unrecoverable error, the cpu stops instead of panicking
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CpuFault {
+    pub pc: Addr,
+    pub opcode: u32,
+    pub reason: String,
+}
+
+/// What `Core::step_instruction` executed
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepInfo {
+    pub instruction: DecodedInstruction,
+    pub pc: Addr,
+    pub opcode: u32,
+    /// False when the condition of an ARM instruction failed
+    pub executed: bool,
+    pub cycles: usize,
+    /// The data accesses of the instruction, the opcode fetches left out
+    #[cfg(feature = "trace")]
+    pub accesses: Vec<MemAccess>,
+    /// (exception, return address) when the instruction raised one
+    pub exception: Option<(Exception, Addr)>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Core {
     pub pc: u32,
@@ -66,6 +96,23 @@ pub struct Core {
     pub trace_opcodes: bool,
 
     pub trace_exceptions: bool,
+
+    /// (pc, opcode) of the last executed instructions, a ring buffer written at `history_index`
+    #[serde(skip)]
+    history: [(Addr, u32); EXECUTION_HISTORY_LEN],
+    #[serde(skip)]
+    history_index: usize,
+    #[serde(skip)]
+    history_len: usize,
+    /// Set when an instruction can't be executed, see `GameBoyAdvance::crash_report`
+    #[serde(skip)]
+    pub fault: Option<CpuFault>,
+    /// (return address, number) of the last software interrupt, see `swi_trace`
+    #[serde(skip)]
+    pub(crate) last_swi: Option<(Addr, u32)>,
+    /// (exception, return address) of the last exception taken, for the debugger catchpoints
+    #[serde(skip)]
+    pub last_exception: Option<(Exception, Addr)>,
 }
 
 impl Core {
@@ -78,6 +125,28 @@ impl Core {
         }
     }
 
+    pub(super) fn set_fault(&mut self, pc: Addr, opcode: u32, reason: &str) {
+        self.fault = Some(CpuFault {
+            pc,
+            opcode,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// The last executed instructions as (pc, opcode), oldest first
+    pub fn execution_history(&self) -> Vec<(Addr, u32)> {
+        let (newest, oldest) = self.history.split_at(self.history_index);
+        let history: Vec<(Addr, u32)> = oldest.iter().chain(newest.iter()).cloned().collect();
+        history[EXECUTION_HISTORY_LEN - self.history_len..].to_vec()
+    }
+
+    #[inline(always)]
+    fn record_history(&mut self, pc: Addr, opcode: u32) {
+        self.history[self.history_index] = (pc, opcode);
+        self.history_index = (self.history_index + 1) % EXECUTION_HISTORY_LEN;
+        self.history_len = EXECUTION_HISTORY_LEN.min(self.history_len + 1);
+    }
+
     pub fn set_verbose(&mut self, v: bool) {
         self.verbose = v;
     }
@@ -188,6 +257,18 @@ impl Core {
         self.gpr.clone()
     }
 
+    /// The sp and lr of `mode`, whether or not it is the current mode
+    pub fn get_banked_sp_lr(&self, mode: CpuMode) -> (u32, u32) {
+        if mode.bank_index() == self.cpsr.mode().bank_index() {
+            (self.gpr[13], self.gpr[14])
+        } else {
+            (
+                self.gpr_banked_r13[mode.bank_index()],
+                self.gpr_banked_r14[mode.bank_index()],
+            )
+        }
+    }
+
     pub(super) fn change_mode(&mut self, old_mode: CpuMode, new_mode: CpuMode) {
         let new_index = new_mode.bank_index();
         let old_index = old_mode.bank_index();
@@ -306,6 +387,7 @@ impl Core {
             GT => !self.cpsr.Z() && (self.cpsr.N() == self.cpsr.V()),
             LE => self.cpsr.Z() || (self.cpsr.N() != self.cpsr.V()),
             AL => true,
+            NV => false,
         }
     }
 
@@ -360,24 +442,36 @@ impl Core {
 
     #[inline(always)]
     pub fn reload_pipeline16(&mut self, sb: &mut SysBus) {
-        self.pipeline[0] = sb.read_16(self.pc) as u32;
+        sb.on_cpu_fetch(self.pc, true);
+        self.pipeline[0] = sb.fetch_16(self.pc) as u32;
         self.N_cycle16(sb, self.pc);
         self.advance_thumb();
-        self.pipeline[1] = sb.read_16(self.pc) as u32;
+        self.pipeline[1] = sb.fetch_16(self.pc) as u32;
         self.S_cycle16(sb, self.pc);
         self.advance_thumb();
     }
 
     #[inline(always)]
     pub fn reload_pipeline32(&mut self, sb: &mut SysBus) {
-        self.pipeline[0] = sb.read_32(self.pc);
+        sb.on_cpu_fetch(self.pc, false);
+        self.pipeline[0] = sb.fetch_32(self.pc);
         self.N_cycle16(sb, self.pc);
         self.advance_arm();
-        self.pipeline[1] = sb.read_32(self.pc);
+        self.pipeline[1] = sb.fetch_32(self.pc);
         self.S_cycle16(sb, self.pc);
         self.advance_arm();
     }
 
+    /// Fetches the two instructions in the pipeline again, after the memory they came from
+    /// changed under the CPU
+    pub fn refill_pipeline(&mut self, sb: &mut SysBus) {
+        self.pc = self.pc.wrapping_sub(2 * self.word_size() as u32);
+        match self.cpsr.state() {
+            CpuState::ARM => self.reload_pipeline32(sb),
+            CpuState::THUMB => self.reload_pipeline16(sb),
+        }
+    }
+
     #[inline]
     pub(super) fn advance_thumb(&mut self) {
         self.pc = self.pc.wrapping_add(2)
@@ -392,13 +486,15 @@ impl Core {
     /// If an instruction was executed in this step, return it.
     pub fn step(&mut self, bus: &mut SysBus) {
         let pc = self.pc;
+        bus.on_cpu_fetch(pc, self.cpsr.state() == CpuState::THUMB);
 
         match self.cpsr.state() {
             CpuState::ARM => {
-                let fetched_now = bus.read_32(pc);
+                let fetched_now = bus.fetch_32(pc);
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now;
+                self.record_history(pc.wrapping_sub(8), insn);
                 let cond =
                     ArmCond::from_u32(insn.bit_range(28..32)).expect("invalid arm condition");
                 if cond != ArmCond::AL {
@@ -414,10 +510,11 @@ impl Core {
                 }
             }
             CpuState::THUMB => {
-                let fetched_now = bus.read_16(pc);
+                let fetched_now = bus.fetch_16(pc);
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now as u32;
+                self.record_history(pc.wrapping_sub(4), insn);
                 match self.step_thumb_exec(insn as u16, bus) {
                     CpuAction::AdvancePC => self.advance_thumb(),
                     CpuAction::FlushPipeline => {}
@@ -426,6 +523,59 @@ impl Core {
         }
     }
 
+    /// Executes the next instruction alone, reporting what it did. Only the cpu runs: an
+    /// interrupt the instruction requests is taken at the next step of the emulator, and the
+    /// cycles aren't handed to the rest of the hardware.
+    pub fn step_instruction(&mut self, sb: &mut SysBus) -> StepInfo {
+        let pc = self.get_next_pc();
+        let opcode = self.get_next_opcode();
+        let (instruction, executed) = match self.cpsr.state() {
+            CpuState::ARM => {
+                let cond =
+                    ArmCond::from_u32(opcode.bit_range(28..32)).expect("invalid arm condition");
+                (
+                    DecodedInstruction::Arm(ArmInstruction::decode(opcode, pc)),
+                    cond == ArmCond::AL || self.check_arm_cond(cond),
+                )
+            }
+            CpuState::THUMB => (
+                DecodedInstruction::Thumb(ThumbInstruction::decode(opcode as u16, pc)),
+                true,
+            ),
+        };
+        #[cfg(feature = "trace")]
+        let log = sb.trace_log.replace(Some(Vec::new()));
+        self.last_exception = None;
+        let cycles = self.cycles;
+
+        self.step(sb);
+
+        #[cfg(feature = "trace")]
+        let accesses = {
+            let accesses = sb.trace_log.replace(log).unwrap_or_default();
+            // an execution trace still gets them
+            if let Some(log) = sb.trace_log.borrow_mut().as_mut() {
+                log.extend_from_slice(&accesses);
+            }
+            accesses
+        };
+        StepInfo {
+            instruction,
+            pc,
+            opcode,
+            executed,
+            cycles: self.cycles - cycles,
+            #[cfg(feature = "trace")]
+            accesses,
+            exception: self.last_exception,
+        }
+    }
+
+    /// The opcode of the next instruction that is going to be executed
+    pub fn get_next_opcode(&self) -> u32 {
+        self.pipeline[0]
+    }
+
     /// Get's the address of the next instruction that is going to be executed
     pub fn get_next_pc(&self) -> Addr {
         let insn_size = self.word_size() as u32;
@@ -444,11 +594,30 @@ impl Core {
         self.gpr_banked_r13[4] = 0x0300_7f00; // ABT
         self.gpr_banked_r13[5] = 0x0300_7f00; // UND
 
+        for reg in &mut self.gpr[0..13] {
+            *reg = 0;
+        }
         self.gpr[13] = 0x0300_7f00;
+        self.gpr[14] = 0;
         self.pc = 0x0800_0000;
 
+        // System mode, ARM state, interrupts enabled
         self.cpsr.set(0x5f);
     }
+
+    /// Leaves the registers the way the BIOS SoftReset function does, the same as after the
+    /// boot sequence but for the return addresses and saved PSRs of the IRQ and supervisor
+    /// modes, which are cleared too. Then jumps to `entry`.
+    pub fn soft_reset(&mut self, sb: &mut SysBus, entry: Addr) {
+        self.change_mode(self.cpsr.mode(), CpuMode::System);
+        self.skip_bios();
+        for mode in [CpuMode::Irq, CpuMode::Supervisor].iter() {
+            self.gpr_banked_r14[mode.bank_index()] = 0;
+            self.spsr_bank[mode.bank_index()] = RegPSR::new(0);
+        }
+        self.pc = entry;
+        self.reload_pipeline32(sb);
+    }
 }
 
 #[cfg(feature = "debugger")]
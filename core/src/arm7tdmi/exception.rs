@@ -1,8 +1,22 @@
+use super::super::bios;
+use super::super::logging;
 use super::super::sysbus::SysBus;
 use super::cpu::Core;
-use super::{CpuMode, CpuState};
+use super::{CpuAction, CpuMode, CpuState};
+#[cfg(feature = "std")]
 use colored::*;
 
+/// Colors the exceptions in the trace, with `std` only
+#[cfg(feature = "std")]
+fn highlight(s: &str) -> ColoredString {
+    s.cyan()
+}
+
+#[cfg(not(feature = "std"))]
+fn highlight(s: &str) -> &str {
+    s
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[allow(dead_code)]
 /// Models a CPU exception, and maps to the relavnt entry in the exception vector
@@ -30,15 +44,17 @@ impl Core {
             Irq => (CpuMode::Irq, true, false),
             Fiq => (CpuMode::Fiq, true, true),
         };
-        trace!(
+        trace!(target: logging::CPU,
             "{}: {:?}, pc: {:#x}, new_mode: {:?} old_mode: {:?}",
-            "Exception".cyan(),
+            highlight("Exception"),
             e,
             self.pc,
             new_mode,
             self.cpsr.mode(),
         );
 
+        self.last_exception = Some((e, lr));
+
         let new_bank = new_mode.bank_index();
         self.spsr_bank[new_bank] = self.cpsr;
         self.gpr_banked_r14[new_bank] = lr;
@@ -66,11 +82,19 @@ impl Core {
         }
     }
 
-    pub fn software_interrupt(&mut self, sb: &mut SysBus, lr: u32, _cmt: u32) {
+    /// `number` is the BIOS function, the low byte of a thumb swi or bits 16-23 of an arm swi
+    pub fn software_interrupt(&mut self, sb: &mut SysBus, lr: u32, number: u32) -> CpuAction {
         match self.cpsr.state() {
             CpuState::ARM => self.N_cycle32(sb, self.pc),
             CpuState::THUMB => self.N_cycle16(sb, self.pc),
         };
+        self.last_swi = Some((lr, number));
+        if sb.is_hle_bios() {
+            // the BIOS isn't entered, but it's still an exception to the debugger
+            self.last_exception = Some((Exception::SoftwareInterrupt, lr));
+            return bios::hle_swi(self, sb, lr, number);
+        }
         self.exception(sb, Exception::SoftwareInterrupt, lr);
+        CpuAction::FlushPipeline
     }
 }
@@ -1,4 +1,4 @@
-use std::fmt;
+use core::fmt;
 
 use num::Num;
 use serde::{Deserialize, Serialize};
@@ -43,7 +43,7 @@ impl DecodedInstruction {
     }
 }
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 impl fmt::Display for DecodedInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -99,6 +99,16 @@ pub enum CpuMode {
 }
 
 impl CpuMode {
+    /// A mode of every bank of registers, System for User too
+    pub const BANKS: [CpuMode; 6] = [
+        CpuMode::System,
+        CpuMode::Fiq,
+        CpuMode::Irq,
+        CpuMode::Supervisor,
+        CpuMode::Abort,
+        CpuMode::Undefined,
+    ];
+
     pub fn spsr_index(&self) -> Option<usize> {
         match self {
             CpuMode::Fiq => Some(0),
@@ -1,5 +1,5 @@
 /// The program status register
-use std::fmt;
+use core::fmt;
 
 use serde::{Deserialize, Serialize};
 
@@ -8,6 +8,7 @@ use crate::num::FromPrimitive;
 
 use super::{CpuMode, CpuState};
 
+#[cfg(feature = "std")]
 use colored::*;
 
 impl From<CpuState> for bool {
@@ -136,6 +137,7 @@ impl RegPSR {
 
 impl fmt::Display for RegPSR {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(feature = "std")]
         let disabled_string = |disabled: bool| -> ColoredString {
             if disabled {
                 "disabled".bright_red()
@@ -143,6 +145,8 @@ impl fmt::Display for RegPSR {
                 "enabled".bright_green()
             }
         };
+        #[cfg(not(feature = "std"))]
+        let disabled_string = |disabled: bool| if disabled { "disabled" } else { "enabled" };
         write!(
             f,
             "{{ [{raw:#010x}] mode: {mode}, state: {state}, irq: {irq}, fiq: {fiq}, condition_flags: (N={N} Z={Z} C={C} V={V}) }}",
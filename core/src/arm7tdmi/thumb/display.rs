@@ -1,13 +1,15 @@
-use std::fmt;
+#[cfg(feature = "disassembler")]
+use alloc::string::String;
+use core::fmt;
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 use crate::bit::BitIndex;
 
 use super::*;
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 use crate::arm7tdmi::*;
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 impl ThumbInstruction {
     fn fmt_thumb_move_shifted_reg(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -271,7 +273,7 @@ impl ThumbInstruction {
     }
 }
 
-#[cfg(feature = "debugger")]
+#[cfg(feature = "disassembler")]
 impl fmt::Display for ThumbInstruction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.fmt {
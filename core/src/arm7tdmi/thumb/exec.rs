@@ -584,11 +584,9 @@ impl Core {
     pub(in super::super) fn exec_thumb_swi(
         &mut self,
         sb: &mut SysBus,
-        _insn: &ThumbInstruction,
+        insn: &ThumbInstruction,
     ) -> CpuAction {
-        self.N_cycle16(sb, self.pc);
-        self.exception(sb, Exception::SoftwareInterrupt, self.pc - 2);
-        CpuAction::FlushPipeline
+        self.software_interrupt(sb, self.pc - 2, (insn.raw & 0xff) as u32)
     }
 
     /// Format 18
@@ -629,10 +627,8 @@ impl Core {
     }
 
     pub fn thumb_undefined(&mut self, _: &mut SysBus, insn: &ThumbInstruction) -> CpuAction {
-        panic!(
-            "executing undefind thumb instruction {:04x} at @{:08x}",
-            insn.raw, insn.pc
-        )
+        self.set_fault(insn.pc, insn.raw as u32, "undefined thumb instruction");
+        CpuAction::AdvancePC
     }
 
     pub fn exec_thumb(&mut self, bus: &mut SysBus, insn: &ThumbInstruction) -> CpuAction {
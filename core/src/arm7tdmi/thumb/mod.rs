@@ -2,7 +2,7 @@ use super::alu::*;
 use super::arm::*;
 use super::{Addr, InstructionDecoder};
 use crate::bit::BitIndex;
-use crate::byteorder::{LittleEndian, ReadBytesExt};
+use crate::byteorder::{ByteOrder, LittleEndian};
 use crate::num::FromPrimitive;
 
 pub mod display;
@@ -118,8 +118,7 @@ impl InstructionDecoder for ThumbInstruction {
     }
 
     fn decode_from_bytes(bytes: &[u8], addr: Addr) -> Self {
-        let mut rdr = std::io::Cursor::new(bytes);
-        let raw = rdr.read_u16::<LittleEndian>().unwrap();
+        let raw = LittleEndian::read_u16(bytes);
         Self::decode(raw, addr)
     }
 
@@ -0,0 +1,257 @@
+//! Running many emulators in lockstep, for reinforcement learning.
+//!
+//! A `BatchRunner` owns N instances, usually started from the same savestate, and steps them
+//! all by one action with a key mask per instance. The observations of every instance are
+//! stacked in buffers that are reused from one step to the next:
+//! ```ignore
+//! let mut batch = BatchRunner::from_state(&gba.save_state()?, 16)?;
+//! let hp = batch.add_probe(MemoryProbe::u16(0x0202_4284));
+//! loop {
+//!     batch.step(&actions);
+//!     let frames = batch.frames(); // 16 * 240 * 160 pixels
+//!     let hp_values = batch.probe_values(hp);
+//! }
+//! ```
+//! The instances only see their key masks, so a step is deterministic: the same states and
+//! actions always give the same observations.
+use super::bus::{Addr, DebugRead};
+use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::{CoreResult, Frontend, GameBoyAdvance};
+
+/// Pixels of one frame in the stacked frame buffer
+pub const FRAME_PIXELS: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
+
+/// A value read from the memory of every instance after each step, like the score or the
+/// health of the player. Reads have no side effects on the emulation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryProbe {
+    pub addr: Addr,
+    /// 1, 2 or 4 bytes
+    pub size: u8,
+}
+
+impl MemoryProbe {
+    pub fn u8(addr: Addr) -> MemoryProbe {
+        MemoryProbe { addr, size: 1 }
+    }
+
+    pub fn u16(addr: Addr) -> MemoryProbe {
+        MemoryProbe { addr, size: 2 }
+    }
+
+    pub fn u32(addr: Addr) -> MemoryProbe {
+        MemoryProbe { addr, size: 4 }
+    }
+
+    fn read(&self, gba: &GameBoyAdvance) -> u32 {
+        match self.size {
+            1 => gba.sysbus.debug_read_8(self.addr) as u32,
+            2 => gba.sysbus.debug_read_16(self.addr) as u32,
+            _ => gba.sysbus.debug_read_32(self.addr),
+        }
+    }
+}
+
+pub struct BatchRunner {
+    instances: Vec<GameBoyAdvance>,
+    probes: Vec<MemoryProbe>,
+    /// Frames emulated per step with the same keys, only the last one is observed
+    frames_per_step: usize,
+    frames: Vec<u32>,
+    /// The values of every probe for instance 0, then for instance 1...
+    probe_values: Vec<u32>,
+}
+
+impl BatchRunner {
+    pub fn new(instances: Vec<GameBoyAdvance>) -> BatchRunner {
+        let frames = vec![0; instances.len() * FRAME_PIXELS];
+        BatchRunner {
+            instances,
+            probes: Vec::new(),
+            frames_per_step: 1,
+            frames,
+            probe_values: Vec::new(),
+        }
+    }
+
+    /// `count` instances restored from `state`. The instances have no frontend devices, the
+    /// audio is still emulated but discarded.
+    pub fn from_state(state: &[u8], count: usize) -> CoreResult<BatchRunner> {
+        let mut instances = Vec::with_capacity(count);
+        for _ in 0..count {
+            instances.push(GameBoyAdvance::from_saved_state(
+                state,
+                Frontend::default(),
+            )?);
+        }
+        Ok(BatchRunner::new(instances))
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn instances(&self) -> &[GameBoyAdvance] {
+        &self.instances
+    }
+
+    pub fn instances_mut(&mut self) -> &mut [GameBoyAdvance] {
+        &mut self.instances
+    }
+
+    /// Repeats every action for `frames` frames (at least 1), the frames before the last one
+    /// are neither drawn nor observed
+    pub fn set_frames_per_step(&mut self, frames: usize) {
+        self.frames_per_step = frames.max(1);
+    }
+
+    /// Adds a probe, returns its index for `probe_values`
+    pub fn add_probe(&mut self, probe: MemoryProbe) -> usize {
+        self.probes.push(probe);
+        self.probe_values = vec![0; self.instances.len() * self.probes.len()];
+        self.read_probes();
+        self.probes.len() - 1
+    }
+
+    /// Runs one step of every instance, with the KEYINPUT value in `keys` of the same index
+    /// (a cleared bit is a pressed key). Panics when there isn't one value per instance.
+    pub fn step(&mut self, keys: &[u16]) {
+        assert_eq!(
+            keys.len(),
+            self.instances.len(),
+            "one key mask per instance"
+        );
+        for (gba, keys) in self.instances.iter_mut().zip(keys) {
+            gba.set_keyinput_override(Some(*keys));
+            gba.set_frame_skipping(true);
+            for _ in 1..self.frames_per_step {
+                gba.frame();
+            }
+            gba.set_frame_skipping(false);
+            gba.frame();
+        }
+        for (gba, frame) in self
+            .instances
+            .iter()
+            .zip(self.frames.chunks_exact_mut(FRAME_PIXELS))
+        {
+            frame.copy_from_slice(gba.get_frame_buffer());
+        }
+        self.read_probes();
+    }
+
+    /// Restores every instance from `state`, to start a new episode
+    pub fn reset(&mut self, state: &[u8]) -> CoreResult<()> {
+        for gba in &mut self.instances {
+            gba.restore_state(state)?;
+        }
+        self.read_probes();
+        Ok(())
+    }
+
+    /// The last frames of all instances, one after another in the frame buffer format
+    pub fn frames(&self) -> &[u32] {
+        &self.frames
+    }
+
+    /// The last frame of instance `index`
+    pub fn frame(&self, index: usize) -> &[u32] {
+        &self.frames[index * FRAME_PIXELS..][..FRAME_PIXELS]
+    }
+
+    /// The values of all probes, the probes of instance 0 come first
+    pub fn all_probe_values(&self) -> &[u32] {
+        &self.probe_values
+    }
+
+    /// The values of probe `probe` in every instance
+    pub fn probe_values(&self, probe: usize) -> Vec<u32> {
+        self.probe_values
+            .iter()
+            .skip(probe)
+            .step_by(self.probes.len())
+            .copied()
+            .collect()
+    }
+
+    fn read_probes(&mut self) {
+        let probes = &self.probes;
+        if probes.is_empty() {
+            return;
+        }
+        for (gba, values) in self
+            .instances
+            .iter()
+            .zip(self.probe_values.chunks_exact_mut(probes.len()))
+        {
+            for (probe, value) in probes.iter().zip(values) {
+                *value = probe.read(gba);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::gba::test_util::{make_arm_rom, make_mock_gba};
+    use super::super::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+
+    fn make_batch(count: usize) -> BatchRunner {
+        // mov r0, #0x04000000; add r0, r0, #0x130; mov r1, #0x03000000
+        // loop: ldrh r2, [r0]; ldr r3, [r1]; add r3, r3, r2; str r3, [r1]; b loop
+        let code: [u32; 8] = [
+            0xe3a0_0301,
+            0xe280_0e13,
+            0xe3a0_1403,
+            0xe1d0_20b0,
+            0xe591_3000,
+            0xe083_3002,
+            0xe581_3000,
+            0xeaff_fffa,
+        ];
+        let gba = make_mock_gba(&make_arm_rom(&code));
+        BatchRunner::from_state(&gba.save_state().unwrap(), count).unwrap()
+    }
+
+    #[test]
+    fn test_step_observes_every_instance() {
+        let mut batch = make_batch(3);
+        let sum = batch.add_probe(MemoryProbe::u32(0x0300_0000));
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.frames().len(), 3 * FRAME_PIXELS);
+        assert_eq!(batch.probe_values(sum), [0, 0, 0]);
+
+        let pressed_a = KEYINPUT_ALL_RELEASED & !(1 << Keys::ButtonA as u16);
+        let keys = [KEYINPUT_ALL_RELEASED, pressed_a, KEYINPUT_ALL_RELEASED];
+        batch.step(&keys);
+        let values = batch.probe_values(sum);
+        // the game sums the KEYINPUT values it reads
+        assert_eq!(values[0], values[2]);
+        assert!(values[1] < values[0]);
+    }
+
+    #[test]
+    fn test_steps_are_deterministic() {
+        let keys = [KEYINPUT_ALL_RELEASED; 2];
+        let mut batch = make_batch(2);
+        batch.add_probe(MemoryProbe::u32(0x0300_0000));
+        batch.set_frames_per_step(3);
+        let state = batch.instances()[0].save_state().unwrap();
+
+        batch.step(&keys);
+        let first = batch.all_probe_values().to_vec();
+        assert_eq!(first[0], first[1]);
+        assert_eq!(batch.frame(0), batch.frame(1));
+
+        batch.reset(&state).unwrap();
+        assert_eq!(batch.all_probe_values(), [0, 0]);
+        batch.step(&keys);
+        assert_eq!(batch.all_probe_values(), first.as_slice());
+    }
+}
@@ -0,0 +1,333 @@
+//! High level emulation of the BIOS, used when no BIOS image is supplied.
+//!
+//! Interrupts still go through a tiny ARM stub mapped at the BIOS region, which follows the real
+//! BIOS convention so games that install their own IRQ handler behave the same as with LLE:
+//! r0-r3, r12 and lr are pushed on the IRQ stack, the handler is called through the pointer at
+//! 0x03007FFC with `r0 = 0x04000000` and returns to the stub with `bx lr`.
+//!
+//! Software interrupts are handled in Rust, without entering supervisor mode. Only the ones that
+//! interact with interrupts are implemented for now: Halt, IntrWait and VBlankIntrWait, along with
+//! the resets games call on A+B+Start+Select: SoftReset, and HardReset which power-cycles the
+//! console, see `GameBoyAdvance::hard_reset`.
+use super::arm7tdmi::{Core, CpuAction, CpuState};
+use super::bus::Bus;
+use super::iodev::HaltState;
+use super::logging;
+use super::sysbus::consts::BIOS_SIZE;
+use super::sysbus::SysBus;
+use alloc::boxed::Box;
+
+/// The IRQ handler the BIOS jumps to
+pub const IRQ_HANDLER_ADDR: u32 = 0x0300_7ffc;
+/// Interrupts acknowledged by the IRQ handler for IntrWait, usually called BIOS_IF
+pub const INTR_CHECK_FLAGS_ADDR: u32 = 0x0300_7ff8;
+
+/// Set by multiboot games for SoftReset to restart them from EWRAM
+const SOFT_RESET_FLAG_ADDR: u32 = 0x0300_7ffa;
+/// SoftReset clears the IWRAM the BIOS uses, its variables and the stacks, from there to the end
+const BIOS_IWRAM_ADDR: u32 = 0x0300_7e00;
+
+const SWI_SOFT_RESET: u32 = 0x00;
+const SWI_HALT: u32 = 0x02;
+const SWI_INTR_WAIT: u32 = 0x04;
+const SWI_VBLANK_INTR_WAIT: u32 = 0x05;
+const SWI_HARD_RESET: u32 = 0x26;
+
+/// What a BIOS function takes and returns, for tracing
+#[derive(Debug)]
+pub struct SwiInfo {
+    pub name: &'static str,
+    /// The arguments in r0, r1, ..., an empty name skips the register
+    pub args: &'static [&'static str],
+    /// The registers holding the results
+    pub results: &'static [usize],
+}
+
+const fn swi(
+    name: &'static str,
+    args: &'static [&'static str],
+    results: &'static [usize],
+) -> SwiInfo {
+    SwiInfo {
+        name,
+        args,
+        results,
+    }
+}
+
+static SWI_TABLE: [SwiInfo; 0x2b] = [
+    swi("SoftReset", &[], &[]),
+    swi("RegisterRamReset", &["flags"], &[]),
+    swi("Halt", &[], &[]),
+    swi("Stop", &[], &[]),
+    swi("IntrWait", &["discard", "flags"], &[]),
+    swi("VBlankIntrWait", &[], &[]),
+    swi("Div", &["num", "denom"], &[0, 1, 3]),
+    swi("DivArm", &["denom", "num"], &[0, 1, 3]),
+    swi("Sqrt", &["value"], &[0]),
+    swi("ArcTan", &["tan"], &[0]),
+    swi("ArcTan2", &["x", "y"], &[0]),
+    swi("CpuSet", &["src", "dst", "control"], &[]),
+    swi("CpuFastSet", &["src", "dst", "control"], &[]),
+    swi("GetBiosChecksum", &[], &[0]),
+    swi("BgAffineSet", &["src", "dst", "count"], &[]),
+    swi("ObjAffineSet", &["src", "dst", "count", "stride"], &[]),
+    swi("BitUnPack", &["src", "dst", "info"], &[]),
+    swi("LZ77UnCompWram", &["src", "dst"], &[]),
+    swi("LZ77UnCompVram", &["src", "dst"], &[]),
+    swi("HuffUnComp", &["src", "dst"], &[]),
+    swi("RLUnCompWram", &["src", "dst"], &[]),
+    swi("RLUnCompVram", &["src", "dst"], &[]),
+    swi("Diff8bitUnFilterWram", &["src", "dst"], &[]),
+    swi("Diff8bitUnFilterVram", &["src", "dst"], &[]),
+    swi("Diff16bitUnFilter", &["src", "dst"], &[]),
+    swi("SoundBias", &["level"], &[]),
+    swi("SoundDriverInit", &["area"], &[]),
+    swi("SoundDriverMode", &["mode"], &[]),
+    swi("SoundDriverMain", &[], &[]),
+    swi("SoundDriverVSync", &[], &[]),
+    swi("SoundChannelClear", &[], &[]),
+    swi("MidiKey2Freq", &["wave", "key", "fine"], &[0]),
+    swi("MusicPlayerOpen", &[], &[]),
+    swi("MusicPlayerStart", &[], &[]),
+    swi("MusicPlayerStop", &[], &[]),
+    swi("MusicPlayerContinue", &[], &[]),
+    swi("MusicPlayerFadeOut", &[], &[]),
+    swi("MultiBoot", &["param", "mode"], &[0]),
+    swi("HardReset", &[], &[]),
+    swi("CustomHalt", &["", "", "mode"], &[]),
+    swi("SoundDriverVSyncOff", &[], &[]),
+    swi("SoundDriverVSyncOn", &[], &[]),
+    swi("SoundGetJumpList", &["dst"], &[]),
+];
+
+/// The function of software interrupt `number`, `None` past the last one
+pub fn swi_info(number: u32) -> Option<&'static SwiInfo> {
+    SWI_TABLE.get(number as usize)
+}
+
+/// Whether the HLE implements `number`, the others do nothing
+pub fn hle_implements(number: u32) -> bool {
+    matches!(
+        number,
+        SWI_SOFT_RESET | SWI_HALT | SWI_INTR_WAIT | SWI_VBLANK_INTR_WAIT | SWI_HARD_RESET
+    )
+}
+
+/// (address, opcode) pairs making up the HLE BIOS image
+const STUB: [(usize, u32); 9] = [
+    // reset: mov pc, #0x08000000
+    (0x00, 0xe3a0_f302),
+    // swi, only reached through a savestate from a LLE BIOS: movs pc, lr
+    (0x08, 0xe1b0_f00e),
+    // irq: b 0x128
+    (0x18, 0xea00_0042),
+    // stmfd sp!, {r0-r3, r12, lr}
+    (0x128, 0xe92d_500f),
+    // mov r0, #0x04000000
+    (0x12c, 0xe3a0_0301),
+    // add lr, pc, #0
+    (0x130, 0xe28f_e000),
+    // ldr pc, [r0, #-4]
+    (0x134, 0xe510_f004),
+    // ldmfd sp!, {r0-r3, r12, lr}
+    (0x138, 0xe8bd_500f),
+    // subs pc, lr, #4
+    (0x13c, 0xe25e_f004),
+];
+
+/// Builds the BIOS image used in HLE mode
+pub fn hle_bios_image() -> Box<[u8]> {
+    let mut image = vec![0; BIOS_SIZE];
+    for (addr, opcode) in STUB.iter() {
+        image[*addr..*addr + 4].copy_from_slice(&opcode.to_le_bytes());
+    }
+    image.into_boxed_slice()
+}
+
+/// A minimal BIOS that runs on the cpu like a dump would, free of the proprietary code.
+///
+/// It boots straight to the cartridge with the stacks and modes the real BIOS leaves behind,
+/// dispatches interrupts to the game's handler the same way, and implements the same software
+/// interrupts as the HLE: Halt, Stop (as Halt), IntrWait and VBlankIntrWait. The others return
+/// right away. As with the real BIOS, software interrupts run in system mode with the caller's
+/// IRQ disable flag, and may change r0 and r1.
+#[cfg(feature = "builtin-bios")]
+const BUILTIN: [u32; 77] = [
+    0xea00_0006, // b reset
+    0xe1b0_f00e, // undefined: movs pc, lr
+    0xea00_001a, // b swi
+    0xe25e_f004, // prefetch abort: subs pc, lr, #4
+    0xe25e_f004, // data abort: subs pc, lr, #4
+    0xe1b0_f00e, // reserved: movs pc, lr
+    0xea00_0010, // b irq
+    0xe25e_f004, // fiq: subs pc, lr, #4
+    // reset:
+    0xe3a0_00d2, // mov r0, #0xd2 (IRQ mode)
+    0xe129_f000, // msr cpsr_fc, r0
+    0xe3a0_d403, // mov sp, #0x03000000
+    0xe28d_dc7f, // add sp, sp, #0x7f00
+    0xe28d_d0a0, // add sp, sp, #0xa0
+    0xe3a0_00d3, // mov r0, #0xd3 (SVC mode)
+    0xe129_f000, // msr cpsr_fc, r0
+    0xe3a0_d403, // mov sp, #0x03000000
+    0xe28d_dc7f, // add sp, sp, #0x7f00
+    0xe28d_d0e0, // add sp, sp, #0xe0
+    0xe3a0_005f, // mov r0, #0x5f (System mode, IRQs enabled)
+    0xe129_f000, // msr cpsr_fc, r0
+    0xe3a0_d403, // mov sp, #0x03000000
+    0xe28d_dc7f, // add sp, sp, #0x7f00
+    0xe3a0_e302, // mov lr, #0x08000000
+    0xe12f_ff1e, // bx lr
+    // irq:
+    0xe92d_500f, // stmfd sp!, {r0-r3, r12, lr}
+    0xe3a0_0301, // mov r0, #0x04000000
+    0xe28f_e000, // add lr, pc, #0
+    0xe510_f004, // ldr pc, [r0, #-4]
+    0xe8bd_500f, // ldmfd sp!, {r0-r3, r12, lr}
+    0xe25e_f004, // subs pc, lr, #4
+    // swi:
+    0xe92d_5800, // stmfd sp!, {r11, r12, lr}
+    0xe55e_c002, // ldrb r12, [lr, #-2]
+    0xe14f_b000, // mrs r11, spsr
+    0xe92d_0800, // stmfd sp!, {r11}
+    0xe20b_b080, // and r11, r11, #0x80
+    0xe38b_b01f, // orr r11, r11, #0x1f
+    0xe129_f00b, // msr cpsr_fc, r11
+    0xe92d_400c, // stmfd sp!, {r2, r3, lr}
+    0xe35c_0002, // cmp r12, #2
+    0x135c_0003, // cmpne r12, #3
+    0x0a00_000a, // beq halt
+    0xe35c_0004, // cmp r12, #4
+    0x0a00_000e, // beq intr_wait
+    0xe35c_0005, // cmp r12, #5
+    0x0a00_000a, // beq vblank_intr_wait
+    // swi_return:
+    0xe8bd_400c, // ldmfd sp!, {r2, r3, lr}
+    0xe3a0_c0d3, // mov r12, #0xd3
+    0xe129_f00c, // msr cpsr_fc, r12
+    0xe8bd_0800, // ldmfd sp!, {r11}
+    0xe169_f00b, // msr spsr_fc, r11
+    0xe8bd_5800, // ldmfd sp!, {r11, r12, lr}
+    0xe1b0_f00e, // movs pc, lr
+    // halt:
+    0xe3a0_c301, // mov r12, #0x04000000
+    0xe3a0_2000, // mov r2, #0
+    0xe5cc_2301, // strb r2, [r12, #0x301] (HALTCNT)
+    0xeaff_fff4, // b swi_return
+    // vblank_intr_wait:
+    0xe3a0_0001, // mov r0, #1
+    0xe3a0_1001, // mov r1, #1
+    // intr_wait:
+    0xe3a0_c301, // mov r12, #0x04000000
+    0xe28c_cc02, // add r12, r12, #0x200
+    0xe3a0_3301, // mov r3, #0x04000000 (INTR_CHECK_FLAGS is mirrored at -8)
+    0xe350_0000, // cmp r0, #0
+    0x1153_20b8, // ldrneh r2, [r3, #-8]
+    0x11c2_2001, // bicne r2, r2, r1
+    0x1143_20b8, // strneh r2, [r3, #-8]
+    // intr_wait_check:
+    0xe3a0_2000, // mov r2, #0
+    0xe1cc_20b8, // strh r2, [r12, #8] (IME)
+    0xe153_20b8, // ldrh r2, [r3, #-8]
+    0xe011_0002, // ands r0, r1, r2
+    0x1022_2000, // eorne r2, r2, r0
+    0x1143_20b8, // strneh r2, [r3, #-8]
+    0xe3a0_2001, // mov r2, #1
+    0xe1cc_20b8, // strh r2, [r12, #8]
+    0x1aff_ffe2, // bne swi_return
+    0xe3a0_2000, // mov r2, #0
+    0xe5cc_2101, // strb r2, [r12, #0x101] (HALTCNT)
+    0xeaff_fff3, // b intr_wait_check
+];
+
+/// Builds the image of the built-in BIOS, used in place of a dump when the frontend has none
+#[cfg(feature = "builtin-bios")]
+pub fn builtin_bios_image() -> Box<[u8]> {
+    let mut image = vec![0; BIOS_SIZE];
+    for (i, opcode) in BUILTIN.iter().enumerate() {
+        image[i * 4..i * 4 + 4].copy_from_slice(&opcode.to_le_bytes());
+    }
+    image.into_boxed_slice()
+}
+
+/// Executes the software interrupt `number` on behalf of the BIOS.
+/// `lr` is the return address, right after the swi instruction.
+pub(crate) fn hle_swi(cpu: &mut Core, sb: &mut SysBus, lr: u32, number: u32) -> CpuAction {
+    match number {
+        SWI_SOFT_RESET => soft_reset(cpu, sb),
+        SWI_HALT => {
+            sb.io.haltcnt = HaltState::Halt;
+            CpuAction::AdvancePC
+        }
+        SWI_INTR_WAIT => intr_wait(cpu, sb, lr),
+        SWI_VBLANK_INTR_WAIT => {
+            cpu.gpr[0] = 1;
+            cpu.gpr[1] = 1;
+            intr_wait(cpu, sb, lr)
+        }
+        SWI_HARD_RESET => {
+            // the whole console starts over, which is out of reach from here. The swi runs again
+            // until the emulator gets to it, as it never returns.
+            sb.request_hard_reset();
+            execute_again(cpu, sb, lr)
+        }
+        _ => {
+            warn!(target: logging::CPU, "HLE: unimplemented swi {:#x} at {:#x}", number, lr);
+            CpuAction::AdvancePC
+        }
+    }
+}
+
+/// Restarts the game from the cartridge, or from EWRAM when the flag at 0x03007FFA is set
+fn soft_reset(cpu: &mut Core, sb: &mut SysBus) -> CpuAction {
+    let entry = if sb.read_8(SOFT_RESET_FLAG_ADDR) == 0 {
+        0x0800_0000
+    } else {
+        0x0200_0000
+    };
+    for addr in (BIOS_IWRAM_ADDR..0x0300_8000).step_by(4) {
+        sb.write_32(addr, 0);
+    }
+    cpu.soft_reset(sb, entry);
+    CpuAction::FlushPipeline
+}
+
+/// IntrWait halts until one of the interrupts in r1 has been acknowledged by the game's IRQ
+/// handler in INTR_CHECK_FLAGS. With r0 != 0, flags that were already set are discarded first.
+///
+/// The BIOS loops around halt internally, here the swi is re-executed instead, with r0 cleared
+/// so old flags are only discarded on the first pass.
+fn intr_wait(cpu: &mut Core, sb: &mut SysBus, lr: u32) -> CpuAction {
+    let wait_flags = cpu.gpr[1] as u16;
+    sb.io.intc.interrupt_master_enable = true;
+
+    let mut check_flags = sb.read_16(INTR_CHECK_FLAGS_ADDR);
+    if cpu.gpr[0] != 0 {
+        check_flags &= !wait_flags;
+    }
+    if check_flags & wait_flags != 0 {
+        sb.write_16(INTR_CHECK_FLAGS_ADDR, check_flags & !wait_flags);
+        return CpuAction::AdvancePC;
+    }
+    sb.write_16(INTR_CHECK_FLAGS_ADDR, check_flags);
+
+    cpu.gpr[0] = 0;
+    sb.io.haltcnt = HaltState::Halt;
+    execute_again(cpu, sb, lr)
+}
+
+/// Goes back to the swi returning to `lr`, for it to execute again
+fn execute_again(cpu: &mut Core, sb: &mut SysBus, lr: u32) -> CpuAction {
+    match cpu.cpsr.state() {
+        CpuState::ARM => {
+            cpu.pc = lr.wrapping_sub(4);
+            cpu.reload_pipeline32(sb);
+        }
+        CpuState::THUMB => {
+            cpu.pc = lr.wrapping_sub(2);
+            cpu.reload_pipeline16(sb);
+        }
+    }
+    CpuAction::FlushPipeline
+}
@@ -0,0 +1,177 @@
+//! Recognizing the BIOS dump the user provided.
+//!
+//! A slightly corrupt BIOS boots most games and then breaks a few in ways that look like
+//! emulation bugs, so the BIOS is hashed when the `SysBus` is built and compared against the
+//! known dumps. What happens to an unknown or corrupt BIOS is up to the `BiosCheck`.
+//!
+//! Replacement BIOSes built from source (Normmatt's for instance) don't have a single hash, as
+//! it depends on the assembler used to build them; they are reported as unknown unless their
+//! hash is listed here.
+use alloc::string::String;
+use alloc::string::ToString;
+use core::convert::TryFrom;
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+use super::logging;
+use super::sysbus::consts::BIOS_SIZE;
+use super::{CoreError, CoreResult};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiosKind {
+    /// Dumped from the hardware
+    Official,
+    /// A freely licensed reimplementation
+    Replacement,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct KnownBios {
+    pub name: &'static str,
+    pub kind: BiosKind,
+    pub sha256: [u8; 32],
+}
+
+pub const KNOWN_BIOSES: &[KnownBios] = &[
+    KnownBios {
+        // the GBA and the GBA SP share the same BIOS
+        name: "Game Boy Advance (AGB/AGS)",
+        kind: BiosKind::Official,
+        sha256: hex!("fd2547724b505f487e6dcb29ec2ecff3af35a841a77ab2e85fd87350abd36570"),
+    },
+    KnownBios {
+        // also recognized in a build without it, when saved to a file
+        name: "rustboyadvance built-in",
+        kind: BiosKind::Replacement,
+        sha256: hex!("25ec921d7a5ccb55d2106ecc3549be6a7faa41b28a9745ee9836350dfc6d12ec"),
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BiosStatus {
+    Known(&'static KnownBios),
+    Unknown,
+    /// Can't be a working BIOS, whatever its origin
+    Corrupt(&'static str),
+}
+
+impl BiosStatus {
+    /// Only the official dumps are fully compatible
+    pub fn is_official(&self) -> bool {
+        match self {
+            BiosStatus::Known(bios) => bios.kind == BiosKind::Official,
+            _ => false,
+        }
+    }
+
+    /// Reports the status as `check` says, an error if the BIOS shouldn't be used
+    pub fn check(&self, check: BiosCheck) -> CoreResult<()> {
+        match (self, check) {
+            (_, BiosCheck::Off) => Ok(()),
+            (BiosStatus::Known(_), _) => {
+                info!(target: logging::CPU, "{}", self);
+                Ok(())
+            }
+            (_, BiosCheck::Warn) => {
+                warn!(
+                    target: logging::CPU,
+                    "{}, some games may not be compatible", self
+                );
+                Ok(())
+            }
+            (_, BiosCheck::Error) => Err(CoreError::BiosError(self.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for BiosStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BiosStatus::Known(bios) => match bios.kind {
+                BiosKind::Official => write!(f, "verified bios: {}", bios.name),
+                BiosKind::Replacement => write!(f, "replacement bios: {}", bios.name),
+            },
+            BiosStatus::Unknown => write!(f, "unknown bios, this is not a known dump"),
+            BiosStatus::Corrupt(reason) => write!(f, "corrupt bios: {}", reason),
+        }
+    }
+}
+
+/// What to do with a BIOS that isn't a known dump
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BiosCheck {
+    Off,
+    /// Log a warning and use it anyway
+    #[default]
+    Warn,
+    /// Refuse to use it
+    Error,
+}
+
+/// Parses "off", "warn" or "error"
+impl TryFrom<&str> for BiosCheck {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, String> {
+        match s {
+            "off" => Ok(BiosCheck::Off),
+            "warn" => Ok(BiosCheck::Warn),
+            "error" => Ok(BiosCheck::Error),
+            _ => Err(format!("{} is not a valid bios check", s)),
+        }
+    }
+}
+
+pub fn identify(bios: &[u8]) -> BiosStatus {
+    if bios.len() != BIOS_SIZE {
+        return BiosStatus::Corrupt("the dump should be 16KiB");
+    }
+    if bios.iter().all(|byte| *byte == bios[0]) {
+        return BiosStatus::Corrupt("the dump is blank");
+    }
+    let mut hasher = Sha256::new();
+    hasher.input(bios);
+    let digest = hasher.result();
+    KNOWN_BIOSES
+        .iter()
+        .find(|known| digest.as_slice() == &known.sha256[..])
+        .map_or(BiosStatus::Unknown, BiosStatus::Known)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify() {
+        assert_eq!(
+            identify(&[0; 0x1000]),
+            BiosStatus::Corrupt("the dump should be 16KiB")
+        );
+        assert_eq!(
+            identify(&[0xff; BIOS_SIZE]),
+            BiosStatus::Corrupt("the dump is blank")
+        );
+        let mut bios = vec![0; BIOS_SIZE];
+        bios[0] = 0x18;
+        let status = identify(&bios);
+        assert_eq!(status, BiosStatus::Unknown);
+        assert!(!status.is_official());
+
+        assert!(status.check(BiosCheck::Off).is_ok());
+        assert!(status.check(BiosCheck::Warn).is_ok());
+        assert!(status.check(BiosCheck::Error).is_err());
+        assert!(BiosStatus::Known(&KNOWN_BIOSES[0])
+            .check(BiosCheck::Error)
+            .is_ok());
+    }
+
+    #[cfg(feature = "builtin-bios")]
+    #[test]
+    fn test_builtin_bios_is_known() {
+        let status = identify(&crate::bios::builtin_bios_image());
+        assert_eq!(status, BiosStatus::Known(&KNOWN_BIOSES[1]));
+        assert!(!status.is_official());
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::vec::Vec;
+
 pub type Addr = u32;
 
 pub trait Bus {
@@ -33,7 +35,7 @@ pub trait Bus {
 
     fn write_8(&mut self, addr: Addr, value: u8);
 
-    fn get_bytes(&self, range: std::ops::Range<u32>) -> Vec<u8> {
+    fn get_bytes(&self, range: core::ops::Range<u32>) -> Vec<u8> {
         let mut bytes = Vec::new();
         for b in range {
             bytes.push(self.read_8(b));
@@ -54,7 +56,7 @@ pub trait DebugRead: Bus {
 
     fn debug_read_8(&self, addr: Addr) -> u8;
 
-    fn debug_get_bytes(&self, range: std::ops::Range<u32>) -> Vec<u8> {
+    fn debug_get_bytes(&self, range: core::ops::Range<u32>) -> Vec<u8> {
         let mut bytes = Vec::new();
         for b in range {
             bytes.push(self.debug_read_8(b));
@@ -62,3 +64,19 @@ pub trait DebugRead: Bus {
         bytes
     }
 }
+
+/// Helper trait for writing memory as if we were an all-powerfull debugger, the ROM and BIOS
+/// included
+pub trait DebugWrite: DebugRead {
+    fn debug_write_32(&mut self, addr: Addr, value: u32) {
+        self.debug_write_16(addr, (value & 0xffff) as u16);
+        self.debug_write_16(addr + 2, (value >> 16) as u16);
+    }
+
+    fn debug_write_16(&mut self, addr: Addr, value: u16) {
+        self.debug_write_8(addr, (value & 0xff) as u8);
+        self.debug_write_8(addr + 1, (value >> 8) as u8);
+    }
+
+    fn debug_write_8(&mut self, addr: Addr, value: u8);
+}
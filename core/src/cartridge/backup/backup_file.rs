@@ -1,37 +1,63 @@
-use std::fmt;
-use std::fs::{File, OpenOptions};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::fs::{self, File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
 use std::io::prelude::*;
+#[cfg(feature = "std")]
 use std::io::SeekFrom;
-use std::path::PathBuf;
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::time::SystemTime;
 
 use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 
-use super::BackupMemoryInterface;
+use super::{BackupMemoryInterface, BackupPath, BackupRestore};
+use crate::logging;
+#[cfg(feature = "std")]
 use crate::util::write_bin_file;
 
+/// Backup memory along with the file it is saved to. Without `std` it is only kept in memory.
 #[derive(Debug)]
 pub struct BackupFile {
     size: usize,
-    path: Option<PathBuf>,
+    path: Option<BackupPath>,
+    #[cfg(feature = "std")]
     file: Option<File>,
     buffer: Vec<u8>,
+    /// Modification time of the file when it was last known to match the buffer
+    #[cfg(feature = "std")]
+    modified: Option<SystemTime>,
 }
 
 impl Clone for BackupFile {
     fn clone(&self) -> Self {
-        BackupFile::new(self.size, self.path.clone())
+        BackupFile {
+            size: self.size,
+            path: self.path.clone(),
+            #[cfg(feature = "std")]
+            file: self.file.as_ref().and_then(|file| file.try_clone().ok()),
+            buffer: self.buffer.clone(),
+            #[cfg(feature = "std")]
+            modified: self.modified,
+        }
     }
 }
 
+/// The contents are part of the state, the file is reopened by `reconnect`
 impl Serialize for BackupFile {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("BackupFile", 2)?;
+        let mut state = serializer.serialize_struct("BackupFile", 3)?;
         state.serialize_field("size", &self.size)?;
         state.serialize_field("path", &self.path)?;
+        state.serialize_field("buffer", &self.buffer)?;
         state.end()
     }
 }
@@ -57,52 +83,79 @@ impl<'de> Deserialize<'de> for BackupFile {
                 let size = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                let path: Option<PathBuf> = seq
+                let path: Option<BackupPath> = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                Ok(BackupFile::new(size, path))
+                let buffer: Vec<u8> = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(2, &self))?;
+                Ok(BackupFile {
+                    size,
+                    path,
+                    #[cfg(feature = "std")]
+                    file: None,
+                    buffer,
+                    #[cfg(feature = "std")]
+                    modified: None,
+                })
             }
         }
 
-        const FIELDS: &'static [&'static str] = &["size", "path"];
+        const FIELDS: &'static [&'static str] = &["size", "path", "buffer"];
         deserializer.deserialize_struct("BackupFile", FIELDS, BackupFileVisitor)
     }
 }
 
 impl BackupFile {
-    pub fn new(size: usize, path: Option<PathBuf>) -> BackupFile {
-        // TODO handle errors without unwrap
-        let mut file: Option<File> = None;
-        let buffer = if let Some(path) = &path {
-            if !path.is_file() {
-                write_bin_file(&path, &vec![0xff; size]).unwrap();
-            }
-
-            let mut _file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&path)
-                .unwrap();
-
-            let mut buffer = Vec::new();
-            _file.read_to_end(&mut buffer).unwrap();
-            buffer.resize(size, 0xff);
-
-            file = Some(_file);
-
-            buffer
-        } else {
-            vec![0xff; size]
+    /// When the backup file can't be opened, the backup is kept in memory only and the error is
+    /// logged, the game still runs but won't be able to save.
+    #[cfg(feature = "std")]
+    pub fn new(size: usize, path: Option<BackupPath>) -> BackupFile {
+        let (file, buffer) = match &path {
+            Some(path) => match BackupFile::open(path, size) {
+                Ok((file, buffer)) => (Some(file), buffer),
+                Err(err) => {
+                    error!(target: logging::CART, "can't open backup file {}: {}", path.display(), err);
+                    (None, vec![0xff; size])
+                }
+            },
+            None => (None, vec![0xff; size]),
         };
+        let modified = file.as_ref().and_then(modification_time);
 
         BackupFile {
             size,
             path,
             file: file,
             buffer: buffer,
+            modified,
         }
     }
 
+    #[cfg(not(feature = "std"))]
+    pub fn new(size: usize, path: Option<BackupPath>) -> BackupFile {
+        BackupFile {
+            size,
+            path,
+            buffer: vec![0xff; size],
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn open(path: &Path, size: usize) -> io::Result<(File, Vec<u8>)> {
+        if !path.is_file() {
+            write_bin_file(path, &vec![0xff; size])?;
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+        buffer.resize(size, 0xff);
+
+        Ok((file, buffer))
+    }
+
     pub fn bytes(&self) -> &[u8] {
         &self.buffer
     }
@@ -111,21 +164,128 @@ impl BackupFile {
         &mut self.buffer
     }
 
+    /// Whether another program changed the file since it was opened or reloaded.
+    /// Our own writes change the modification time too, the contents tell them apart.
+    #[cfg(feature = "std")]
+    pub fn changed_on_disk(&mut self) -> bool {
+        let path = match (&self.path, &self.file) {
+            (Some(path), Some(_)) => path,
+            _ => return false,
+        };
+        // by path, editors often replace the file rather than write to it
+        let modified = fs::metadata(path).ok().and_then(|m| m.modified().ok());
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+        match fs::read(path) {
+            Ok(mut bytes) => {
+                bytes.resize(self.size, 0xff);
+                if bytes == self.buffer {
+                    self.modified = modified;
+                    false
+                } else {
+                    true
+                }
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Reads the file again, replacing what the game saved since it was opened
+    #[cfg(feature = "std")]
+    pub fn reload(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let (file, buffer) = BackupFile::open(path, self.size)?;
+            self.modified = modification_time(&file);
+            self.file = Some(file);
+            self.buffer = buffer;
+        }
+        Ok(())
+    }
+
+    /// Opens the file again after the backup was loaded from a savestate, `restore` decides
+    /// whether the contents of the state or of the file are kept.
+    /// Returns whether they were different.
+    #[cfg(feature = "std")]
+    pub fn reconnect(&mut self, restore: BackupRestore) -> bool {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return false,
+        };
+        let (file, on_disk) = match BackupFile::open(&path, self.size) {
+            Ok(opened) => opened,
+            Err(err) => {
+                error!(target: logging::CART, "can't open backup file {}: {}", path.display(), err);
+                return false;
+            }
+        };
+        let differs = on_disk != self.buffer;
+        self.file = Some(file);
+        match restore {
+            BackupRestore::FromState if differs => self.flush(),
+            BackupRestore::FromState => {}
+            BackupRestore::KeepOnDisk => self.buffer = on_disk,
+        }
+        self.modified = self.file.as_ref().and_then(modification_time);
+        differs
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn reconnect(&mut self, _restore: BackupRestore) -> bool {
+        false
+    }
+
+    /// Replaces the contents, bytes past `bytes` are erased (0xff)
+    pub fn load_bytes(&mut self, bytes: &[u8]) {
+        if bytes.len() > self.size {
+            warn!(target: logging::CART,
+                "backup data is {} bytes, truncated to {} bytes",
+                bytes.len(),
+                self.size
+            );
+        }
+        let len = bytes.len().min(self.size);
+        self.buffer.iter_mut().for_each(|byte| *byte = 0xff);
+        self.buffer[..len].copy_from_slice(&bytes[..len]);
+        self.flush();
+    }
+
     pub fn flush(&mut self) {
-        if let Some(file) = &mut self.file {
-            file.seek(SeekFrom::Start(0)).unwrap();
-            file.write_all(&self.buffer).unwrap();
+        self.write_to_file(0, self.buffer.len());
+    }
+
+    /// Writing stops after the first error, so a failing disk doesn't flood the log
+    #[cfg(feature = "std")]
+    fn write_to_file(&mut self, offset: usize, len: usize) {
+        let result = match &mut self.file {
+            Some(file) => {
+                let bytes = &self.buffer[offset..offset + len];
+                file.seek(SeekFrom::Start(offset as u64))
+                    .and_then(|_| file.write_all(bytes))
+            }
+            None => return,
+        };
+        if let Err(err) = result {
+            error!(target: logging::CART, "can't write backup file, saving is disabled: {}", err);
+            self.file = None;
         }
     }
+
+    #[cfg(not(feature = "std"))]
+    fn write_to_file(&mut self, _offset: usize, _len: usize) {}
+}
+
+#[cfg(feature = "std")]
+fn modification_time(file: &File) -> Option<SystemTime> {
+    file.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
 }
 
 impl BackupMemoryInterface for BackupFile {
     fn write(&mut self, offset: usize, value: u8) {
         self.buffer[offset] = value;
-        if let Some(file) = &mut self.file {
-            file.seek(SeekFrom::Start(offset as u64)).unwrap();
-            file.write_all(&[value]).unwrap();
-        }
+        self.write_to_file(offset, 1);
     }
 
     fn read(&self, offset: usize) -> u8 {
@@ -1,14 +1,17 @@
-use super::{BackupFile, BackupMemoryInterface};
+use super::{BackupFile, BackupMemoryInterface, BackupPath};
+use crate::logging;
+use alloc::vec::Vec;
 
+#[cfg(feature = "std")]
 use bytesize;
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
-use std::cell::RefCell;
+use core::cell::RefCell;
+#[cfg(feature = "std")]
 use std::fs;
-use std::path::PathBuf;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EepromType {
     Eeprom512,
     Eeprom8k,
@@ -21,6 +24,15 @@ enum EepromAddressBits {
 }
 
 impl EepromType {
+    /// The type of an EEPROM dump of `size` bytes
+    pub(crate) fn from_size(size: usize) -> Option<EepromType> {
+        match size {
+            0x0200 => Some(EepromType::Eeprom512),
+            0x2000 => Some(EepromType::Eeprom8k),
+            _ => None,
+        }
+    }
+
     fn size(&self) -> usize {
         match self {
             EepromType::Eeprom512 => 0x0200,
@@ -142,7 +154,7 @@ impl EepromChip {
         use SpiState::*;
 
         // Read the si signal into the rx_buffer
-        trace!("({:?}) addr={:#x} RX bit {}", self.state, address, si);
+        trace!(target: logging::CART, "({:?}) addr={:#x} RX bit {}", self.state, address, si);
         self.rx_buffer = (self.rx_buffer << 1) | (if si & 1 != 0 { 1 } else { 0 });
         self.rx_count += 1;
 
@@ -152,18 +164,20 @@ impl EepromChip {
             RxInstruction => {
                 // If instruction was recvd, proceed to recv the address
                 if self.rx_count >= 2 {
-                    let insn = SpiInstruction::from_u64(self.rx_buffer).expect(&format!(
-                        "invalid spi command {:#010b}",
-                        self.rx_buffer as u8
-                    ));
-                    next_state = Some(RxAddress(insn));
+                    match SpiInstruction::from_u64(self.rx_buffer) {
+                        Some(insn) => next_state = Some(RxAddress(insn)),
+                        None => {
+                            warn!(target: logging::CART, "invalid spi command {:#010b}", self.rx_buffer as u8)
+                        }
+                    }
                     self.reset_rx_buffer();
                 }
             }
             RxAddress(insn) => {
-                if self.rx_count == self.addr_bits.into() {
+                let addr_bits: usize = self.addr_bits.into();
+                if self.rx_count == addr_bits {
                     self.address = (self.rx_buffer as usize) * 8;
-                    trace!(
+                    trace!(target: logging::CART,
                         "{:?} mode , recvd address = {:#x} (rx_buffer={:#x})",
                         insn,
                         self.address,
@@ -189,7 +203,7 @@ impl EepromChip {
             RxData => {
                 if self.rx_count == 64 {
                     let mut data = self.rx_buffer;
-                    debug!("writing {:#x} to memory address {:#x}", data, self.address);
+                    debug!(target: logging::CART, "writing {:#x} to memory address {:#x}", data, self.address);
                     for i in 0..8 {
                         self.memory
                             .write(self.address + (7 - i), (data & 0xff) as u8);
@@ -222,7 +236,7 @@ impl EepromChip {
                 if self.tx_count == 4 {
                     next_state = Some(TxData);
                     self.fill_tx_buffer();
-                    trace!("transmitting data bits, tx_buffer = {:#x}", self.tx_buffer);
+                    trace!(target: logging::CART, "transmitting data bits, tx_buffer = {:#x}", self.tx_buffer);
                 }
                 0
             }
@@ -246,7 +260,7 @@ impl EepromChip {
             }
         };
 
-        trace!("({:?}) addr={:#x} TX bit {}", self.state, address, result);
+        trace!(target: logging::CART, "({:?}) addr={:#x} TX bit {}", self.state, address, result);
         if let Some(next_state) = next_state {
             self.state = next_state;
         }
@@ -254,7 +268,7 @@ impl EepromChip {
         result
     }
 
-    pub(in crate) fn is_transmitting(&self) -> bool {
+    pub(crate) fn is_transmitting(&self) -> bool {
         use SpiState::*;
         match self.state {
             TxData | TxDummy => true,
@@ -262,7 +276,7 @@ impl EepromChip {
         }
     }
 
-    pub(in crate) fn reset(&mut self) {
+    pub(crate) fn reset(&mut self) {
         self.state = SpiState::RxInstruction;
         self.reset_rx_buffer();
         self.reset_tx_buffer();
@@ -276,42 +290,48 @@ impl EepromChip {
 //     rx_count: usize,
 // }
 
+/// The type of EEPROM an existing save file was made with, given by its size
+#[cfg(feature = "std")]
+fn eeprom_type_of_file(path: &BackupPath) -> Option<EepromType> {
+    let metadata = fs::metadata(path).ok()?;
+    let human_size = bytesize::ByteSize::b(metadata.len());
+    let assumed_type = match metadata.len() {
+        512 => EepromType::Eeprom512,
+        8192 => EepromType::Eeprom8k,
+        _ => panic!("invalid file size ({}) for eeprom save", human_size),
+    };
+    info!(target: logging::CART,
+        "save file is size {}, assuming eeprom type is {:?}",
+        human_size, assumed_type
+    );
+    Some(assumed_type)
+}
+
+#[cfg(not(feature = "std"))]
+fn eeprom_type_of_file(path: &BackupPath) -> Option<EepromType> {
+    match *path {}
+}
+
 /// The Eeprom controller is usually mapped to the top 256 bytes of the cartridge memory
 /// Eeprom controller can programmed with DMA accesses in 16bit mode
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EepromController {
-    pub(in crate) chip: RefCell<EepromChip>,
+    pub(crate) chip: RefCell<EepromChip>,
     detect: bool,
 }
 
 impl EepromController {
-    pub fn new(path: Option<PathBuf>) -> EepromController {
-        let mut detect = true;
-        let mut eeprom_type = EepromType::Eeprom512;
-        if let Some(path) = &path {
-            if let Ok(metadata) = fs::metadata(&path) {
-                let human_size = bytesize::ByteSize::b(metadata.len());
-                let assumed_type = match metadata.len() {
-                    512 => EepromType::Eeprom512,
-                    8192 => EepromType::Eeprom8k,
-                    _ => panic!("invalid file size ({}) for eeprom save", human_size),
-                };
-                detect = false;
-                info!(
-                    "save file is size {}, assuming eeprom type is {:?}",
-                    human_size, assumed_type
-                );
-                eeprom_type = assumed_type;
-            }
-        }
+    pub fn new(path: Option<BackupPath>) -> EepromController {
+        let file_type = path.as_ref().and_then(eeprom_type_of_file);
+        let eeprom_type = file_type.unwrap_or(EepromType::Eeprom512);
 
         let mut result = EepromController::new_with_type(path, eeprom_type);
-        result.detect = detect;
+        result.detect = file_type.is_none();
 
         result
     }
 
-    pub fn new_with_type(path: Option<PathBuf>, eeprom_type: EepromType) -> EepromController {
+    pub fn new_with_type(path: Option<BackupPath>, eeprom_type: EepromType) -> EepromController {
         let memory = BackupFile::new(eeprom_type.size(), path);
         EepromController {
             chip: RefCell::new(EepromChip::new(eeprom_type, memory)),
@@ -319,6 +339,19 @@ impl EepromController {
         }
     }
 
+    /// Whether no transfer is in progress, when the contents can be swapped safely
+    pub(crate) fn is_idle(&self) -> bool {
+        self.chip.borrow().state == SpiState::RxInstruction
+    }
+
+    pub(crate) fn memory_mut(&mut self) -> &mut BackupFile {
+        &mut self.chip.get_mut().memory
+    }
+
+    pub(crate) fn bytes(&self) -> Vec<u8> {
+        self.chip.borrow().memory.bytes().to_vec()
+    }
+
     pub fn write_half(&mut self, address: u32, value: u16) {
         assert!(!self.detect);
         self.chip.borrow_mut().clock_data_in(address, value as u8);
@@ -336,7 +369,10 @@ impl EepromController {
             match (src, dst) {
                 // DMA to EEPROM
                 (_, 0x0d000000..=0x0dffffff) => {
-                    debug!("caught eeprom dma transfer src={:#x} dst={:#x} count={}", src, dst, count);
+                    debug!(target: logging::CART,
+                        "caught eeprom dma transfer src={:#x} dst={:#x} count={}",
+                        src, dst, count
+                    );
                     let eeprom_type = match count {
                         // Read(11) + 6bit address + stop bit
                         9 => Eeprom512,
@@ -346,9 +382,12 @@ impl EepromController {
                         73 => Eeprom512,
                         // Write(11) + 14bit address + 64bit value + stop bit
                         81 => Eeprom8k,
-                        _ => panic!("unexpected bit count ({}) when detecting eeprom size", count)
+                        _ => panic!(
+                            "unexpected bit count ({}) when detecting eeprom size",
+                            count
+                        ),
                     };
-                    info!("detected eeprom type: {:?}", eeprom_type);
+                    info!(target: logging::CART, "detected eeprom type: {:?}", eeprom_type);
                     self.chip.borrow_mut().set_type(eeprom_type);
                     self.detect = false;
                 }
@@ -356,7 +395,7 @@ impl EepromController {
                 (0x0d000000..=0x0dffffff, _) => {
                     panic!("reading from eeprom when real size is not detected yet is not supported by this emulator")
                 }
-                _ => {/* Not a eeprom dma, doing nothing */}
+                _ => { /* Not a eeprom dma, doing nothing */ }
             }
         } else {
             // this might be a eeprom request, so we need to reset the eeprom state machine if its dirty (due to bad behaving games, or tests roms)
@@ -1,10 +1,9 @@
-use super::{BackupFile, BackupMemoryInterface};
+use super::{BackupFile, BackupMemoryInterface, BackupPath};
+use crate::logging;
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
-use std::path::PathBuf;
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum FlashWriteSequence {
     Initial,
@@ -66,7 +65,7 @@ const SECTOR_SIZE: usize = 0x1000;
 const BANK_SIZE: usize = 0x10000;
 
 impl Flash {
-    pub fn new(flash_path: Option<PathBuf>, flash_size: FlashSize) -> Flash {
+    pub fn new(flash_path: Option<BackupPath>, flash_size: FlashSize) -> Flash {
         let chip_id = match flash_size {
             FlashSize::Flash64k => MACRONIX_64K_CHIP_ID,
             FlashSize::Flash128k => MACRONIX_128K_CHIP_ID,
@@ -85,6 +84,24 @@ impl Flash {
         }
     }
 
+    /// Reports another manufacturer's chip in ID mode
+    pub fn set_chip_id(&mut self, chip_id: u16) {
+        self.chip_id = chip_id;
+    }
+
+    /// Whether the game is between two commands, when the contents can be swapped safely
+    pub(crate) fn is_idle(&self) -> bool {
+        matches!(self.wrseq, FlashWriteSequence::Initial) && self.mode == FlashMode::Initial
+    }
+
+    pub(crate) fn memory(&self) -> &BackupFile {
+        &self.memory
+    }
+
+    pub(crate) fn memory_mut(&mut self) -> &mut BackupFile {
+        &mut self.memory
+    }
+
     fn reset_sequence(&mut self) {
         self.wrseq = FlashWriteSequence::Initial;
     }
@@ -163,7 +180,7 @@ impl Flash {
     }
 
     pub fn write(&mut self, addr: u32, value: u8) {
-        trace!("[FLASH] write {:#x}={:#x}", addr, value);
+        trace!(target: logging::CART, "[FLASH] write {:#x}={:#x}", addr, value);
         match self.wrseq {
             FlashWriteSequence::Initial => {
                 if addr == 0x0E00_5555 && value == 0xAA {
@@ -1,11 +1,20 @@
-use std::convert::TryFrom;
-use std::fmt;
+use alloc::string::String;
+use core::convert::TryFrom;
+use core::fmt;
 
 mod backup_file;
 pub use backup_file::BackupFile;
 pub mod eeprom;
 pub mod flash;
 
+/// Where a backup is saved. Without `std` there are no files, so no path can be given and the
+/// backups are kept in memory.
+#[cfg(feature = "std")]
+pub type BackupPath = std::path::PathBuf;
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BackupPath {}
+
 #[derive(Debug, Primitive, Serialize, Deserialize, Copy, Clone, PartialEq)]
 pub enum BackupType {
     Eeprom = 0,
@@ -32,6 +41,30 @@ impl TryFrom<&str> for BackupType {
     }
 }
 
+/// What loading a savestate does with the save file
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BackupRestore {
+    /// The save file is overwritten with the backup memory of the state, which is what the
+    /// game saved up to that point
+    FromState,
+    /// The game keeps seeing the save file, the backup memory of the state is dropped. Saves
+    /// made after the state was taken aren't lost.
+    #[default]
+    KeepOnDisk,
+}
+
+impl TryFrom<&str> for BackupRestore {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "from-state" => Ok(BackupRestore::FromState),
+            "keep-on-disk" => Ok(BackupRestore::KeepOnDisk),
+            _ => Err(format!("{} is not a valid save restore mode", s)),
+        }
+    }
+}
+
 pub trait BackupMemoryInterface: Sized + fmt::Debug {
     fn write(&mut self, offset: usize, value: u8);
     fn read(&self, offset: usize) -> u8;
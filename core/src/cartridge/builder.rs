@@ -1,19 +1,43 @@
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::path::{Path, PathBuf};
 
+#[cfg(feature = "std")]
 use memmem::{Searcher, TwoWaySearcher};
 use num::FromPrimitive;
+use sha2::{Digest, Sha256};
 
-use super::super::overrides;
-use super::super::{GBAError, GBAResult};
+use super::super::logging;
+use super::super::profile::GameProfiles;
+use super::super::quirks::{self, GameQuirks};
+#[cfg(feature = "rom_db")]
+use super::super::rom_db::{DumpStatus, RomDatabase};
+use super::super::util::RomBuffer;
+#[cfg(feature = "std")]
+use super::super::ResultExt;
+use super::super::{CoreError, CoreResult};
 use super::backup::eeprom::*;
 use super::backup::flash::*;
-use super::backup::{BackupFile, BackupType};
+use super::backup::{BackupFile, BackupPath, BackupType};
 use super::gpio::Gpio;
 use super::header;
+use super::rom_flash::ROM_FLASH_SIZE;
+#[cfg(feature = "std")]
+use super::save_location::{SaveLocation, SaveNaming};
+#[cfg(feature = "std")]
+use super::symbols::{find_symbol_file, load_symbol_file};
 use super::BackupMedia;
-use super::Cartridge;
+use super::{Cartridge, CartridgeVariant, PeripheralKind, SymbolTable};
 
-use super::loader::{load_from_bytes, load_from_file, LoadRom};
+#[cfg(feature = "mmap")]
+use super::loader::map_file;
+#[cfg(feature = "std")]
+use super::loader::{is_container, load_from_bytes, load_from_file, LoadRom};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -26,51 +50,133 @@ pub enum GpioDeviceType {
 
 #[derive(Debug)]
 pub struct GamepakBuilder {
+    #[cfg(feature = "std")]
     path: Option<PathBuf>,
-    bytes: Option<Box<[u8]>>,
+    bytes: Option<RomBuffer>,
+    #[cfg(feature = "std")]
     save_path: Option<PathBuf>,
     save_type: BackupType,
+    backup_data: Option<Vec<u8>>,
     gpio_device: GpioDeviceType,
+    #[cfg(feature = "std")]
     create_backup_file: bool,
+    variant: Option<CartridgeVariant>,
+    #[cfg(feature = "std")]
+    symbol_file: Option<PathBuf>,
+    #[cfg(feature = "std")]
+    save_location: SaveLocation,
+    #[cfg(feature = "std")]
+    save_naming: SaveNaming,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+    profiles: Option<GameProfiles>,
+    #[cfg(feature = "rom_db")]
+    rom_database: Option<RomDatabase>,
 }
 
 impl GamepakBuilder {
     pub fn new() -> GamepakBuilder {
         GamepakBuilder {
             save_type: BackupType::AutoDetect,
+            backup_data: None,
+            #[cfg(feature = "std")]
             path: None,
+            #[cfg(feature = "std")]
             save_path: None,
             bytes: None,
             gpio_device: GpioDeviceType::None,
+            #[cfg(feature = "std")]
             create_backup_file: true,
+            variant: None,
+            #[cfg(feature = "std")]
+            symbol_file: None,
+            #[cfg(feature = "std")]
+            save_location: SaveLocation::default(),
+            #[cfg(feature = "std")]
+            save_naming: SaveNaming::default(),
+            #[cfg(feature = "mmap")]
+            mmap: false,
+            profiles: None,
+            #[cfg(feature = "rom_db")]
+            rom_database: None,
         }
     }
 
     pub fn take_buffer(mut self, bytes: Box<[u8]>) -> Self {
-        self.bytes = Some(bytes);
+        self.bytes = Some(bytes.into());
         self
     }
 
     pub fn buffer(mut self, bytes: &[u8]) -> Self {
+        self.bytes = Some(bytes.to_vec().into());
+        self
+    }
+
+    /// Uses a ROM buffer shared with the frontend, a raw ROM is not copied
+    pub fn shared_buffer(mut self, bytes: Arc<[u8]>) -> Self {
         self.bytes = Some(bytes.into());
         self
     }
 
+    /// Like `shared_buffer`, a borrowed ROM is not copied
+    pub fn cow_buffer(mut self, bytes: Cow<'static, [u8]>) -> Self {
+        self.bytes = Some(bytes.into());
+        self
+    }
+
+    /// Reads the ROM straight from a host owned buffer such as a memory mapped file.
+    /// Zipped and ELF ROMs still get decoded into memory.
+    pub fn mapped_buffer(mut self, mapping: Arc<dyn AsRef<[u8]> + Send + Sync>) -> Self {
+        self.bytes = Some(RomBuffer::Mapped(mapping));
+        self
+    }
+
+    #[cfg(feature = "std")]
     pub fn file(mut self, path: &Path) -> Self {
         self.path = Some(path.to_path_buf());
         self
     }
 
+    /// Reads the `file()` through a read-only memory map instead of loading it on the heap.
+    /// Zipped and ELF ROMs are still loaded in memory.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self) -> Self {
+        self.mmap = true;
+        self
+    }
+
+    #[cfg(feature = "std")]
     pub fn save_path(mut self, path: &Path) -> Self {
         self.save_path = Some(path.to_path_buf());
         self
     }
 
+    /// Where the save file goes when `save_path` isn't given, next to the ROM by default
+    #[cfg(feature = "std")]
+    pub fn save_location(mut self, location: SaveLocation) -> Self {
+        self.save_location = location;
+        self
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save_naming(mut self, naming: SaveNaming) -> Self {
+        self.save_naming = naming;
+        self
+    }
+
     pub fn save_type(mut self, save_type: BackupType) -> Self {
         self.save_type = save_type;
         self
     }
 
+    /// A `.sym` or `.map` file, merged with the symbols of the ROM. Without one, a symbol file
+    /// with the name of the ROM file is loaded if there is one.
+    #[cfg(feature = "std")]
+    pub fn symbol_file(mut self, path: &Path) -> Self {
+        self.symbol_file = Some(path.to_path_buf());
+        self
+    }
+
     pub fn with_sram(mut self) -> Self {
         self.save_type = BackupType::Sram;
         self
@@ -91,72 +197,192 @@ impl GamepakBuilder {
         self
     }
 
+    /// Starts with `data` in the backup memory rather than what the save file holds, e.g. a
+    /// save synced over the network. The size of the data selects the EEPROM and flash sizes.
+    pub fn with_backup_data(mut self, save_type: BackupType, data: Vec<u8>) -> Self {
+        self.save_type = save_type;
+        self.backup_data = Some(data);
+        self
+    }
+
+    #[cfg(feature = "std")]
     pub fn without_backup_to_file(mut self) -> Self {
         self.create_backup_file = false;
         self
     }
 
+    /// Without `std` the backups are never saved to a file
+    #[cfg(not(feature = "std"))]
+    pub fn without_backup_to_file(self) -> Self {
+        self
+    }
+
     pub fn with_rtc(mut self) -> Self {
         self.gpio_device = GpioDeviceType::Rtc;
         self
     }
 
-    pub fn build(mut self) -> GBAResult<Cartridge> {
-        let (bytes, symbols) = if let Some(bytes) = self.bytes {
+    /// Forces the board type instead of detecting it from the ROM
+    pub fn variant(mut self, variant: CartridgeVariant) -> Self {
+        self.variant = Some(variant);
+        self
+    }
+
+    /// Maps the ROM as 8MB of writable flash, like DACS and bootleg flash carts
+    pub fn with_rom_flash(self) -> Self {
+        self.variant(CartridgeVariant::rom_flash())
+    }
+
+    /// Maps the registers of a device like the Play-Yan over its firmware, see `PeripheralKind`
+    pub fn with_peripheral(self, kind: PeripheralKind) -> Self {
+        self.variant(CartridgeVariant::peripheral(kind))
+    }
+
+    /// Game profiles to look the cartridge up in, a matching profile takes priority over the
+    /// built-in quirks but not over settings forced on the builder
+    pub fn profiles(mut self, profiles: &GameProfiles) -> Self {
+        self.profiles = Some(profiles.clone());
+        self
+    }
+
+    /// No-Intro database to verify the ROM against. A match also gives the game code to look the
+    /// built-in quirks up with, which works for ROM hacks and dumps with a broken header.
+    #[cfg(feature = "rom_db")]
+    pub fn rom_database(mut self, rom_database: &RomDatabase) -> Self {
+        self.rom_database = Some(rom_database.clone());
+        self
+    }
+
+    /// The game code to look the built-in quirks up with
+    fn quirks_game_code(&self, bytes: &[u8], header: &header::CartridgeHeader) -> String {
+        #[cfg(feature = "rom_db")]
+        {
+            if let Some(rom_database) = &self.rom_database {
+                let status = rom_database.verify(bytes, &header.game_code);
+                match status {
+                    DumpStatus::Good(_) => info!(target: logging::CART, "ROM verified: {}", status),
+                    _ => warn!(target: logging::CART, "ROM verification: {}", status),
+                }
+                if let Some(serial) = status.entry().and_then(|entry| entry.serial.clone()) {
+                    return serial;
+                }
+            }
+        }
+        #[cfg(not(feature = "rom_db"))]
+        let _ = bytes;
+        header.game_code.clone()
+    }
+
+    /// Loads the ROM image and its symbols from the buffer or file given to the builder
+    #[cfg(feature = "std")]
+    fn load_rom(&mut self) -> CoreResult<(RomBuffer, Option<SymbolTable>)> {
+        if let Some(bytes) = self.bytes.take() {
+            if !is_container(&bytes) {
+                return Ok((bytes, None));
+            }
             match load_from_bytes(bytes.to_vec())? {
                 #[cfg(feature = "elf_support")]
-                LoadRom::Elf { data, symbols } => Ok((data, Some(symbols))),
-                LoadRom::Raw(data) => Ok((data, None)),
+                LoadRom::Elf { data, symbols } => Ok((data.into(), Some(symbols))),
+                LoadRom::Raw(data) => Ok((data.into(), None)),
             }
         } else if let Some(path) = &self.path {
-            match load_from_file(&path)? {
+            #[cfg(feature = "mmap")]
+            {
+                if self.mmap {
+                    if let Some(mapping) =
+                        map_file(path).with_context(|| format!("mapping {}", path.display()))?
+                    {
+                        return Ok((mapping, None));
+                    }
+                }
+            }
+            match load_from_file(path).with_context(|| format!("loading {}", path.display()))? {
                 #[cfg(feature = "elf_support")]
-                LoadRom::Elf { data, symbols } => Ok((data, Some(symbols))),
-                LoadRom::Raw(data) => Ok((data, None)),
+                LoadRom::Elf { data, symbols } => Ok((data.into(), Some(symbols))),
+                LoadRom::Raw(data) => Ok((data.into(), None)),
             }
         } else {
-            Err(GBAError::CartridgeLoadError(
+            Err(CoreError::CartridgeLoadError(
                 "either provide file() or buffer()".to_string(),
             ))
-        }?;
+        }
+    }
+
+    /// Without `std` the ROM comes from a buffer, which is used as is
+    #[cfg(not(feature = "std"))]
+    fn load_rom(&mut self) -> CoreResult<(RomBuffer, Option<SymbolTable>)> {
+        match self.bytes.take() {
+            Some(bytes) => Ok((bytes, None)),
+            None => Err(CoreError::CartridgeLoadError(
+                "provide buffer()".to_string(),
+            )),
+        }
+    }
+
+    pub fn build(mut self) -> CoreResult<Cartridge> {
+        #[cfg(feature = "std")]
+        let (bytes, mut symbols) = self.load_rom()?;
+        #[cfg(not(feature = "std"))]
+        let (bytes, symbols) = self.load_rom()?;
+
+        #[cfg(feature = "std")]
+        {
+            let symbol_file = self
+                .symbol_file
+                .take()
+                .or_else(|| self.path.as_deref().and_then(find_symbol_file));
+            if let Some(path) = symbol_file {
+                let table = load_symbol_file(&path)
+                    .with_context(|| format!("loading symbols {}", path.display()))?;
+                info!(target: logging::CART, "Loaded {} symbols from {}", table.len(), path.display());
+                symbols.get_or_insert_with(Default::default).extend(table);
+            }
+        }
 
         let header = header::parse(&bytes)?;
-        info!("Loaded ROM: {:?}", header);
+        info!(target: logging::CART, "Loaded ROM: {:?}", header);
 
-        if !self.create_backup_file {
-            self.save_path = None;
-        } else if self.save_path.is_none() {
-            if let Some(path) = &self.path {
-                self.save_path = Some(path.with_extension(BACKUP_FILE_EXT));
-            } else {
-                warn!("can't create save file as no save path was provided")
+        #[cfg(feature = "std")]
+        {
+            if !self.create_backup_file {
+                self.save_path = None;
+            } else if self.save_path.is_none() {
+                self.save_path = self
+                    .save_location
+                    .resolve(self.save_naming, self.path.as_deref(), &header)
+                    .context("creating the saves directory")?;
+                if self.save_path.is_none() {
+                    warn!(target: logging::CART, "can't create save file as no save path was provided")
+                }
             }
         }
 
+        let game_code = self.quirks_game_code(&bytes, &header);
         let mut save_type = self.save_type;
+        let gpio_forced = !matches!(self.gpio_device, GpioDeviceType::None);
         let mut gpio_device = self.gpio_device;
 
-        if let Some(overrides) = overrides::get_game_overrides(&header.game_code) {
-            info!(
-                "Found game overrides for {}: {:#?}",
-                header.game_code, overrides
-            );
-            if let Some(override_save_type) = overrides.save_type() {
-                if override_save_type != save_type && save_type != BackupType::AutoDetect {
-                    warn!(
+        let quirks = quirks::get_game_quirks(&game_code)
+            .cloned()
+            .unwrap_or_default();
+        if quirks != GameQuirks::default() {
+            info!(target: logging::CART, "Found game quirks for {}: {:#?}", game_code, quirks);
+            if let Some(quirks_save_type) = quirks.save_type {
+                if quirks_save_type != save_type && save_type != BackupType::AutoDetect {
+                    warn!(target: logging::CART,
                         "Forced save type {:?} takes priority of {:?}",
-                        save_type, override_save_type
+                        save_type, quirks_save_type
                     );
                 }
-                save_type = override_save_type;
+                save_type = quirks_save_type;
             }
 
-            if overrides.force_rtc() {
+            if quirks.force_rtc {
                 match gpio_device {
                     GpioDeviceType::None => gpio_device = GpioDeviceType::Rtc,
                     GpioDeviceType::Rtc => {}
                     _ => {
-                        warn!(
+                        warn!(target: logging::CART,
                             "Can't use RTC due to forced gpio device type {:?}",
                             gpio_device
                         );
@@ -165,52 +391,140 @@ impl GamepakBuilder {
             }
         }
 
+        let profile = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.find(&header))
+            .cloned();
+        if let Some(profile) = &profile {
+            info!(target: logging::CART,
+                "Found game profile for {}: {:#?}",
+                header.game_code, profile
+            );
+            if let Some(profile_save_type) = profile.save_type() {
+                if self.save_type == BackupType::AutoDetect {
+                    save_type = profile_save_type;
+                } else {
+                    warn!(target: logging::CART,
+                        "Forced save type {:?} takes priority of profile {:?}",
+                        self.save_type, profile_save_type
+                    );
+                }
+            }
+            match profile.rtc {
+                Some(true) if !gpio_forced => gpio_device = GpioDeviceType::Rtc,
+                Some(false) if !gpio_forced => gpio_device = GpioDeviceType::None,
+                _ => {}
+            }
+        }
+
+        let variant = self
+            .variant
+            .take()
+            .unwrap_or_else(|| CartridgeVariant::detect(&bytes));
+        if !matches!(variant, CartridgeVariant::Standard) {
+            info!(target: logging::CART, "Cartridge variant: {:?}", variant);
+        }
+        if let CartridgeVariant::SramPatched = variant {
+            if save_type != BackupType::AutoDetect && save_type != BackupType::Sram {
+                warn!(target: logging::CART, "SRAM patched cartridge ignores save type {:?}", save_type);
+            }
+            save_type = BackupType::Sram;
+        }
+
         if save_type == BackupType::AutoDetect {
             if let Some(detected) = detect_backup_type(&bytes) {
-                info!("Detected Backup: {:?}", detected);
+                info!(target: logging::CART, "Detected Backup: {:?}", detected);
                 save_type = detected;
             } else {
-                warn!("could not detect backup save type");
+                warn!(target: logging::CART, "could not detect backup save type");
             }
         }
 
-        let backup = create_backup(save_type, self.save_path);
+        // without `std` the backups are kept in memory
+        #[cfg(feature = "std")]
+        let backup_path = self
+            .save_path
+            .map(|path| path.with_extension(BACKUP_FILE_EXT));
+        #[cfg(not(feature = "std"))]
+        let backup_path = None;
+        let mut backup = create_backup(
+            save_type,
+            backup_path,
+            &quirks,
+            self.backup_data.as_ref().map(|data| data.len()),
+        );
+        if let (Some(data), Some(memory)) = (&self.backup_data, backup.file_mut()) {
+            memory.load_bytes(data);
+        }
 
         let gpio = match gpio_device {
             GpioDeviceType::None => None,
             GpioDeviceType::Rtc => {
-                info!("Emulating RTC!");
+                info!(target: logging::CART, "Emulating RTC!");
                 Some(Gpio::new_rtc())
             }
             _ => unimplemented!("Gpio device {:?} not implemented", gpio_device),
         };
 
+        let mut bytes = bytes;
+        if let CartridgeVariant::RomFlash(_) = variant {
+            if bytes.len() < ROM_FLASH_SIZE {
+                let mut padded = bytes.to_vec();
+                padded.resize(ROM_FLASH_SIZE, 0xff);
+                bytes = padded.into();
+            }
+        }
+
         let size = bytes.len();
+        let mut hasher = Sha256::new();
+        hasher.input(&bytes[..]);
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(hasher.result().as_slice());
         Ok(Cartridge {
             header: header,
             gpio: gpio,
-            bytes: bytes.into_boxed_slice(),
+            variant: variant,
+            bytes: bytes,
+            sha256: sha256,
             size: size,
             backup: backup,
+            removed: false,
             symbols: symbols,
+            quirks: quirks,
+            profile: profile,
         })
     }
 }
 
+#[cfg(feature = "std")]
 const BACKUP_FILE_EXT: &'static str = "sav";
-fn create_backup(backup_type: BackupType, rom_path: Option<PathBuf>) -> BackupMedia {
-    let backup_path = if let Some(rom_path) = rom_path {
-        Some(rom_path.with_extension(BACKUP_FILE_EXT))
-    } else {
-        None
+/// `data_size` is the size of the initial contents, if given
+fn create_backup(
+    backup_type: BackupType,
+    backup_path: Option<BackupPath>,
+    quirks: &GameQuirks,
+    data_size: Option<usize>,
+) -> BackupMedia {
+    let flash = |size| {
+        let mut flash = Flash::new(backup_path.clone(), size);
+        if let Some(chip_id) = quirks.flash_id {
+            flash.set_chip_id(chip_id);
+        }
+        BackupMedia::Flash(flash)
     };
+    let eeprom_size = quirks
+        .eeprom_size
+        .or_else(|| data_size.and_then(EepromType::from_size));
     match backup_type {
-        BackupType::Flash | BackupType::Flash512 => {
-            BackupMedia::Flash(Flash::new(backup_path, FlashSize::Flash64k))
-        }
-        BackupType::Flash1M => BackupMedia::Flash(Flash::new(backup_path, FlashSize::Flash128k)),
+        BackupType::Flash if data_size == Some(0x20000) => flash(FlashSize::Flash128k),
+        BackupType::Flash | BackupType::Flash512 => flash(FlashSize::Flash64k),
+        BackupType::Flash1M => flash(FlashSize::Flash128k),
         BackupType::Sram => BackupMedia::Sram(BackupFile::new(0x8000, backup_path)),
-        BackupType::Eeprom => BackupMedia::Eeprom(EepromController::new(backup_path)),
+        BackupType::Eeprom => BackupMedia::Eeprom(match eeprom_size {
+            Some(eeprom_type) => EepromController::new_with_type(backup_path, eeprom_type),
+            None => EepromController::new(backup_path),
+        }),
         BackupType::AutoDetect => BackupMedia::Undetected,
     }
 }
@@ -220,11 +534,19 @@ fn detect_backup_type(bytes: &[u8]) -> Option<BackupType> {
         &["EEPROM", "SRAM", "FLASH_", "FLASH512_", "FLASH1M_"];
 
     for i in 0..5 {
-        let search = TwoWaySearcher::new(ID_STRINGS[i].as_bytes());
-        match search.search_in(bytes) {
-            Some(_) => return Some(BackupType::from_u8(i as u8).unwrap()),
-            _ => {}
+        if contains(bytes, ID_STRINGS[i].as_bytes()) {
+            return Some(BackupType::from_u8(i as u8).unwrap());
         }
     }
     None
 }
+
+#[cfg(feature = "std")]
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    TwoWaySearcher::new(needle).search_in(bytes).is_some()
+}
+
+#[cfg(not(feature = "std"))]
+fn contains(bytes: &[u8], needle: &[u8]) -> bool {
+    bytes.windows(needle.len()).any(|window| window == needle)
+}
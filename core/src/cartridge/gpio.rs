@@ -25,11 +25,15 @@ pub trait GpioDevice: Sized {
     fn read(&self, gpio_state: &GpioState) -> u16;
 }
 
+const GPIO_PINS_MASK: u16 = 0b1111;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Gpio {
-    pub(in crate) rtc: Option<Rtc>,
+    pub(crate) rtc: Option<Rtc>,
     direction: GpioState,
     control: GpioPortControl,
+    /// Last value the GBA drove on the output pins
+    data: u16,
 }
 
 impl Gpio {
@@ -38,37 +42,44 @@ impl Gpio {
             rtc: None,
             direction: [GpioDirection::Out; 4],
             control: GpioPortControl::WriteOnly,
+            data: 0,
         }
     }
 
     pub fn new_rtc() -> Self {
         Gpio {
             rtc: Some(Rtc::new()),
-            direction: [GpioDirection::Out; 4],
-            control: GpioPortControl::WriteOnly,
+            ..Gpio::new_none()
         }
     }
 
+    /// When the port is write-only, reads from the GPIO registers return the ROM contents
     pub fn is_readable(&self) -> bool {
         self.control != GpioPortControl::WriteOnly
     }
 
+    /// Pins configured as outputs, as a bitmask
+    fn output_mask(&self) -> u16 {
+        let mut mask = 0u16;
+        for i in 0..4 {
+            mask.set_bit(i, self.direction[i] == GpioDirection::Out);
+        }
+        mask
+    }
+
+    /// Must only be called when `is_readable()`
     pub fn read(&self, addr: u32) -> u16 {
         match addr {
             GPIO_PORT_DATA => {
-                if let Some(rtc) = &self.rtc {
+                let output_mask = self.output_mask();
+                let input = if let Some(rtc) = &self.rtc {
                     rtc.read(&self.direction)
                 } else {
                     0
-                }
-            }
-            GPIO_PORT_DIRECTION => {
-                let mut direction = 0u16;
-                for i in 0..4 {
-                    direction.set_bit(i, self.direction[i] == GpioDirection::Out);
-                }
-                direction
+                };
+                (self.data & output_mask) | (input & !output_mask & GPIO_PINS_MASK)
             }
+            GPIO_PORT_DIRECTION => self.output_mask(),
             GPIO_PORT_CONTROL => self.control as u16,
             _ => unreachable!(),
         }
@@ -77,8 +88,10 @@ impl Gpio {
     pub fn write(&mut self, addr: u32, value: u16) {
         match addr {
             GPIO_PORT_DATA => {
+                // Input pins are driven by the device, writes to them are lost
+                self.data = value & self.output_mask();
                 if let Some(rtc) = &mut self.rtc {
-                    rtc.write(&self.direction, value);
+                    rtc.write(&self.direction, self.data);
                 }
             }
             GPIO_PORT_DIRECTION => {
@@ -91,7 +104,7 @@ impl Gpio {
                 }
             }
             GPIO_PORT_CONTROL => {
-                self.control = if value != 0 {
+                self.control = if value.bit(0) {
                     GpioPortControl::ReadWrite
                 } else {
                     GpioPortControl::WriteOnly
@@ -101,3 +114,33 @@ impl Gpio {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_pins_ignore_writes() {
+        let mut gpio = Gpio::new_none();
+        gpio.write(GPIO_PORT_CONTROL, 1);
+        gpio.write(GPIO_PORT_DIRECTION, 0b0101);
+        gpio.write(GPIO_PORT_DATA, 0xffff);
+        assert_eq!(gpio.read(GPIO_PORT_DATA), 0b0101);
+        assert_eq!(gpio.read(GPIO_PORT_DIRECTION), 0b0101);
+    }
+
+    #[test]
+    fn test_rtc_probe() {
+        // Games detecting the RTC enable reads and expect the output pins to read back what
+        // they wrote, with SIO left as an input
+        let mut gpio = Gpio::new_rtc();
+        gpio.write(GPIO_PORT_CONTROL, 1);
+        assert_eq!(gpio.read(GPIO_PORT_CONTROL), 1);
+        gpio.write(GPIO_PORT_DIRECTION, 0b0101);
+        gpio.write(GPIO_PORT_DATA, 0b0001);
+        assert_eq!(gpio.read(GPIO_PORT_DATA) & 0b0101, 0b0001);
+
+        gpio.write(GPIO_PORT_CONTROL, 0);
+        assert!(!gpio.is_readable());
+    }
+}
@@ -1,7 +1,9 @@
+use alloc::string::String;
+use alloc::string::ToString;
+use core::str::from_utf8;
 use serde::{Deserialize, Serialize};
-use std::str::from_utf8;
 
-use super::super::{GBAError, GBAResult};
+use super::super::{CoreError, CoreResult};
 
 /// From GBATEK
 ///
@@ -48,28 +50,33 @@ fn calculate_checksum(bytes: &[u8]) -> u8 {
         .wrapping_sub(0x19)
 }
 
-pub fn parse(bytes: &[u8]) -> GBAResult<CartridgeHeader> {
+/// Writes the complement check of a header, `bytes` has to hold the whole header
+pub fn fix_checksum(bytes: &mut [u8]) {
+    bytes[0xbd] = calculate_checksum(&bytes[0xa0..=0xbc]);
+}
+
+pub fn parse(bytes: &[u8]) -> CoreResult<CartridgeHeader> {
     if bytes.len() < 0xc0 {
-        return Err(GBAError::CartridgeLoadError(
+        return Err(CoreError::CartridgeLoadError(
             "incomplete cartridge header".to_string(),
         ));
     }
 
     let checksum = bytes[0xbd];
     if calculate_checksum(&bytes[0xa0..=0xbc]) != checksum {
-        return Err(GBAError::CartridgeLoadError(
+        return Err(CoreError::CartridgeLoadError(
             "invalid header checksum".to_string(),
         ));
     }
 
     let game_title = from_utf8(&bytes[0xa0..0xac])
-        .map_err(|_| GBAError::CartridgeLoadError("invalid game title".to_string()))?;
+        .map_err(|_| CoreError::CartridgeLoadError("invalid game title".to_string()))?;
 
     let game_code = from_utf8(&bytes[0xac..0xb0])
-        .map_err(|_| GBAError::CartridgeLoadError("invalid game code".to_string()))?;
+        .map_err(|_| CoreError::CartridgeLoadError("invalid game code".to_string()))?;
 
     let maker_code = from_utf8(&bytes[0xb0..0xb2])
-        .map_err(|_| GBAError::CartridgeLoadError("invalid marker code".to_string()))?;
+        .map_err(|_| CoreError::CartridgeLoadError("invalid marker code".to_string()))?;
 
     // let (_, rom_entry_point) = le_u32(bytes).unwrap();
     // let (_, ram_entry_point) = le_u32(&bytes[0xc0..]).unwrap();
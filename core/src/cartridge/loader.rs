@@ -1,12 +1,17 @@
-use super::super::{GBAError, GBAResult};
+use super::super::{CoreError, CoreResult};
 
-#[cfg(feature = "elf_support")]
-use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::path::Path;
+#[cfg(feature = "mmap")]
+use std::sync::Arc;
 
+#[cfg(feature = "elf_support")]
+use super::SymbolTable;
+use crate::logging;
 use crate::util::read_bin_file;
+#[cfg(feature = "mmap")]
+use crate::util::RomBuffer;
 use zip::ZipArchive;
 
 #[cfg(feature = "elf_support")]
@@ -16,16 +21,16 @@ pub enum LoadRom {
     #[cfg(feature = "elf_support")]
     Elf {
         data: Vec<u8>,
-        symbols: HashMap<String, u32>,
+        symbols: SymbolTable,
     },
     Raw(Vec<u8>),
 }
-type LoadRomResult = GBAResult<LoadRom>;
+type LoadRomResult = CoreResult<LoadRom>;
 
 #[cfg(feature = "elf_support")]
-impl From<goblin::error::Error> for GBAError {
-    fn from(err: goblin::error::Error) -> GBAError {
-        GBAError::CartridgeLoadError(format!("elf parsing error: {}", err))
+impl From<goblin::error::Error> for CoreError {
+    fn from(err: goblin::error::Error) -> CoreError {
+        CoreError::CartridgeLoadError(format!("elf parsing error: {}", err))
     }
 }
 
@@ -40,7 +45,7 @@ fn try_load_zip(data: &[u8]) -> LoadRomResult {
             return Ok(LoadRom::Raw(buf));
         }
     }
-    Err(GBAError::CartridgeLoadError(
+    Err(CoreError::CartridgeLoadError(
         "no .gba files found within the zip archive".to_owned(),
     ))
 }
@@ -53,7 +58,7 @@ fn try_load_elf(elf_bytes: &[u8]) -> LoadRomResult {
 
     let entry = elf.entry;
     if entry != (CART_BASE as u64) {
-        return Err(GBAError::CartridgeLoadError(
+        return Err(CoreError::CartridgeLoadError(
             "bad elf entry point, maybe multiboot rom ?".to_owned(),
         ));
     }
@@ -68,11 +73,11 @@ fn try_load_elf(elf_bytes: &[u8]) -> LoadRomResult {
                 ..(phdr.p_paddr as usize + phdr.p_memsz as usize - CART_BASE);
 
             if phys_range_adjusted.start + (phdr.p_filesz as usize) >= rom.len() {
-                warn!("ELF: skipping program header {:?}", phdr);
+                warn!(target: logging::CART, "ELF: skipping program header {:?}", phdr);
                 continue;
             }
 
-            info!(
+            info!(target: logging::CART,
                 "ELF: loading segment phdr: {:?} range {:#x?} vec range {:#x?}",
                 phdr, file_range, phys_range,
             );
@@ -83,7 +88,7 @@ fn try_load_elf(elf_bytes: &[u8]) -> LoadRomResult {
         }
     }
 
-    let mut symbols = HashMap::new();
+    let mut symbols = SymbolTable::new();
 
     let strtab = elf.strtab;
     for sym in elf.syms.iter() {
@@ -91,7 +96,7 @@ fn try_load_elf(elf_bytes: &[u8]) -> LoadRomResult {
             // TODO do I also want to save the symbol size ?
             symbols.insert(name.to_owned(), sym.st_value as u32);
         } else {
-            warn!("failed to parse symbol name sym {:?}", sym);
+            warn!(target: logging::CART, "failed to parse symbol name sym {:?}", sym);
         }
     }
 
@@ -101,6 +106,18 @@ fn try_load_elf(elf_bytes: &[u8]) -> LoadRomResult {
     })
 }
 
+/// Maps a raw ROM file read-only, returns `None` for files that need decoding
+#[cfg(feature = "mmap")]
+pub(super) fn map_file(path: &Path) -> CoreResult<Option<RomBuffer>> {
+    let file = std::fs::File::open(path)?;
+    // Safety: the mapping is read-only, the ROM file must not be modified while it is mapped
+    let mapping = unsafe { memmap::Mmap::map(&file)? };
+    if is_container(&mapping) {
+        return Ok(None);
+    }
+    Ok(Some(RomBuffer::Mapped(Arc::new(mapping))))
+}
+
 pub(super) fn load_from_file(path: &Path) -> LoadRomResult {
     let bytes = read_bin_file(path)?;
 
@@ -110,7 +127,7 @@ pub(super) fn load_from_file(path: &Path) -> LoadRomResult {
             #[cfg(feature = "elf_support")]
             Some("elf") => try_load_elf(&bytes),
             _ => {
-                warn!("unknown file extension, loading as raw binary file");
+                warn!(target: logging::CART, "unknown file extension, loading as raw binary file");
                 Ok(LoadRom::Raw(bytes))
             }
         },
@@ -118,6 +135,19 @@ pub(super) fn load_from_file(path: &Path) -> LoadRomResult {
     }
 }
 
+/// Whether the buffer needs to be decoded by `load_from_bytes`, rather than used as a raw ROM
+pub(super) fn is_container(bytes: &[u8]) -> bool {
+    const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+    #[cfg(feature = "elf_support")]
+    {
+        const ELF_MAGIC: &[u8] = b"\x7fELF";
+        if bytes.starts_with(ELF_MAGIC) {
+            return true;
+        }
+    }
+    bytes.starts_with(ZIP_MAGIC)
+}
+
 pub(super) fn load_from_bytes(bytes: Vec<u8>) -> LoadRomResult {
     // first try as zip
     if let Ok(result) = try_load_zip(&bytes) {
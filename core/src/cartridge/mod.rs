@@ -1,26 +1,52 @@
-use std::collections::HashMap;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io;
 
 use serde::{Deserialize, Serialize};
 
 use super::bus::*;
+use super::profile::GameProfile;
+use super::quirks::GameQuirks;
+use super::util::RomBuffer;
 
 pub mod header;
 use header::CartridgeHeader;
 
 mod backup;
 use backup::eeprom::EepromController;
+pub use backup::eeprom::EepromType;
 use backup::flash::Flash;
-pub use backup::BackupType;
 use backup::{BackupFile, BackupMemoryInterface};
+pub use backup::{BackupRestore, BackupType};
 
 mod gpio;
+mod peripheral;
+mod rom_flash;
 mod rtc;
 use gpio::Gpio;
+pub use peripheral::{Peripheral, PeripheralKind, RegisterWindow};
+pub use rom_flash::RomFlash;
 
 mod builder;
+#[cfg(feature = "std")]
 mod loader;
 pub use builder::GamepakBuilder;
 
+#[cfg(feature = "std")]
+mod save_location;
+#[cfg(feature = "std")]
+pub use save_location::{SaveLocation, SaveNaming};
+
+mod symbols;
+#[cfg(feature = "std")]
+pub use symbols::load_symbol_file;
+pub use symbols::{parse_map, parse_sym};
+
 pub const GPIO_PORT_DATA: u32 = 0xC4;
 pub const GPIO_PORT_DIRECTION: u32 = 0xC6;
 pub const GPIO_PORT_CONTROL: u32 = 0xC8;
@@ -33,31 +59,282 @@ pub enum BackupMedia {
     Undetected,
 }
 
-pub type SymbolTable = HashMap<String, u32>;
+impl BackupMedia {
+    fn file_mut(&mut self) -> Option<&mut BackupFile> {
+        match self {
+            BackupMedia::Sram(memory) => Some(memory),
+            BackupMedia::Flash(flash) => Some(flash.memory_mut()),
+            BackupMedia::Eeprom(eeprom) => Some(eeprom.memory_mut()),
+            BackupMedia::Undetected => None,
+        }
+    }
+}
+
+/// How the cartridge maps its ROM, for the boards that differ from a plain mask ROM
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub enum CartridgeVariant {
+    #[default]
+    Standard,
+    /// Repro board with SRAM, running a game that was patched from flash or EEPROM saves.
+    /// The save type detection would find the original library strings, so SRAM is forced.
+    SramPatched,
+    /// The ROM is writable flash, as on DACS development carts and bootleg flash carts
+    RomFlash(RomFlash),
+    /// N-in-1 multicart with a ROM larger than the 32MB address space. The game selected by the
+    /// menu is mapped by writing its offset in 1MB units to the bank register at 0x0E000002.
+    Multicart {
+        bank: usize,
+    },
+    /// A device with registers on the gamepak bus, the ROM being its firmware
+    Peripheral(Peripheral),
+}
+
+pub const MULTICART_BANK_REGISTER: u32 = 0x0E00_0002;
+const GAMEPAK_ROM_MAX_SIZE: usize = 32 * 1024 * 1024;
+const MULTICART_BANK_SIZE: usize = 1024 * 1024;
+
+impl CartridgeVariant {
+    pub fn rom_flash() -> CartridgeVariant {
+        CartridgeVariant::RomFlash(RomFlash::new())
+    }
+
+    pub fn multicart() -> CartridgeVariant {
+        CartridgeVariant::Multicart { bank: 0 }
+    }
+
+    pub fn peripheral(kind: PeripheralKind) -> CartridgeVariant {
+        CartridgeVariant::Peripheral(Peripheral::new(kind))
+    }
+
+    /// Recognizes boards that can't be told apart from the header alone
+    pub fn detect(bytes: &[u8]) -> CartridgeVariant {
+        if bytes.len() > GAMEPAK_ROM_MAX_SIZE {
+            CartridgeVariant::multicart()
+        } else {
+            CartridgeVariant::Standard
+        }
+    }
+}
+
+impl TryFrom<&str> for CartridgeVariant {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "standard" => Ok(CartridgeVariant::Standard),
+            "sram-patched" => Ok(CartridgeVariant::SramPatched),
+            "rom-flash" => Ok(CartridgeVariant::rom_flash()),
+            "multicart" => Ok(CartridgeVariant::multicart()),
+            _ => match PeripheralKind::try_from(s) {
+                Ok(kind) => Ok(CartridgeVariant::peripheral(kind)),
+                Err(_) => Err(format!("{} is not a valid cartridge variant", s)),
+            },
+        }
+    }
+}
+
+/// Addresses by symbol name, a `BTreeMap` so that it only needs `alloc`
+pub type SymbolTable = BTreeMap<String, u32>;
+
+/// Which ROM a savestate was made with. The game code alone can't tell a patched ROM or
+/// another revision from the original, the hash of the whole image does.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RomIdentity {
+    pub game_code: String,
+    pub sha256: [u8; 32],
+}
+
+impl RomIdentity {
+    /// Why a state made with `self` must not run with the ROM `loaded`, `None` if it can
+    pub fn mismatch(&self, loaded: &RomIdentity) -> Option<String> {
+        if self == loaded {
+            None
+        } else if self.game_code != loaded.game_code {
+            Some(format!(
+                "the state is of game {}, the loaded ROM is {}",
+                self.game_code, loaded.game_code
+            ))
+        } else {
+            Some(format!(
+                "the state was made with another version of {} ({}, the loaded ROM is {})",
+                self.game_code, self, loaded
+            ))
+        }
+    }
+}
+
+/// The game code and the start of the hash, e.g. `BPEE-1f1c08fb`
+impl fmt::Display for RomIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-", self.game_code)?;
+        for byte in &self.sha256[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Cartridge {
     pub header: CartridgeHeader,
-    bytes: Box<[u8]>,
+    bytes: RomBuffer,
+    /// Of `bytes`, see `RomIdentity`
+    sha256: [u8; 32],
     size: usize,
     gpio: Option<Gpio>,
+    variant: CartridgeVariant,
     symbols: Option<SymbolTable>, // TODO move it somewhere else
-    pub(in crate) backup: BackupMedia,
+    pub(crate) backup: BackupMedia,
+    /// Pulled out of the slot, the gamepak bus is left floating
+    removed: bool,
+    /// The built-in quirks of the game
+    #[serde(skip)]
+    pub quirks: GameQuirks,
+    /// The user profile matched when the cartridge was built
+    #[serde(skip)]
+    pub profile: Option<GameProfile>,
 }
 
 impl Cartridge {
     pub fn get_symbols(&self) -> &Option<SymbolTable> {
         &self.symbols
     }
+
+    /// Adds the symbols of `table`, those already known under the same names are replaced
+    pub fn merge_symbols(&mut self, table: SymbolTable) {
+        self.symbols
+            .get_or_insert_with(Default::default)
+            .extend(table);
+    }
+
+    /// Returns the address the symbol had before
+    pub fn add_symbol(&mut self, name: &str, addr: Addr) -> Option<Addr> {
+        self.symbols
+            .get_or_insert_with(Default::default)
+            .insert(name.to_string(), addr)
+    }
+
+    pub fn remove_symbol(&mut self, name: &str) -> Option<Addr> {
+        self.symbols.as_mut()?.remove(name)
+    }
+
+    /// Takes the ROM of `cartridge`, along with its header, board type, symbols and game
+    /// settings. The save memory and the GPIO devices are kept, and a removed cartridge is back
+    /// in the slot.
+    pub fn replace_rom(&mut self, cartridge: Cartridge) {
+        self.header = cartridge.header;
+        self.bytes = cartridge.bytes;
+        self.sha256 = cartridge.sha256;
+        self.size = cartridge.size;
+        self.variant = cartridge.variant;
+        self.symbols = cartridge.symbols;
+        self.quirks = cartridge.quirks;
+        self.profile = cartridge.profile;
+        self.removed = false;
+    }
+
+    /// Clears what a power cycle clears: the multicart bank, the mode of the ROM flash and the
+    /// registers of a peripheral. The save memory and the RTC keep their contents.
+    pub fn reset(&mut self) {
+        self.variant = match &self.variant {
+            CartridgeVariant::RomFlash(_) => CartridgeVariant::rom_flash(),
+            CartridgeVariant::Multicart { .. } => CartridgeVariant::multicart(),
+            CartridgeVariant::Peripheral(peripheral) => {
+                CartridgeVariant::peripheral(peripheral.kind())
+            }
+            variant => variant.clone(),
+        };
+    }
+
+    /// Pulls the cartridge out, see `GameBoyAdvance::remove_cartridge`
+    pub fn remove(&mut self) {
+        self.removed = true;
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    pub fn identity(&self) -> RomIdentity {
+        RomIdentity {
+            game_code: self.header.game_code.clone(),
+            sha256: self.sha256,
+        }
+    }
+
     pub fn get_gpio(&self) -> &Option<Gpio> {
         &self.gpio
     }
+    pub fn variant(&self) -> &CartridgeVariant {
+        &self.variant
+    }
+
+    /// The contents of the backup memory, `None` when the game has none or it wasn't detected
+    pub fn backup_bytes(&self) -> Option<Vec<u8>> {
+        match &self.backup {
+            BackupMedia::Sram(memory) => Some(memory.bytes().to_vec()),
+            BackupMedia::Flash(flash) => Some(flash.memory().bytes().to_vec()),
+            BackupMedia::Eeprom(eeprom) => Some(eeprom.bytes()),
+            BackupMedia::Undetected => None,
+        }
+    }
+
+    /// Whether another program changed the save file, e.g. a save editor
+    #[cfg(feature = "std")]
+    pub fn backup_changed_on_disk(&mut self) -> bool {
+        self.backup
+            .file_mut()
+            .is_some_and(|memory| memory.changed_on_disk())
+    }
+
+    /// Whether the game is in the middle of a flash command or an EEPROM transfer, the save
+    /// must not be reloaded then
+    pub fn backup_busy(&self) -> bool {
+        match &self.backup {
+            BackupMedia::Flash(flash) => !flash.is_idle(),
+            BackupMedia::Eeprom(eeprom) => !eeprom.is_idle(),
+            _ => false,
+        }
+    }
+
+    /// Reopens the save file after a savestate was loaded, see `BackupRestore`.
+    /// Returns whether the save of the state differed from the file.
+    pub fn reconnect_backup(&mut self, restore: BackupRestore) -> bool {
+        self.backup
+            .file_mut()
+            .is_some_and(|memory| memory.reconnect(restore))
+    }
+
+    /// Reads the save file again, what the game saved since it was loaded is lost
+    #[cfg(feature = "std")]
+    pub fn reload_backup(&mut self) -> io::Result<()> {
+        match self.backup.file_mut() {
+            Some(memory) => memory.reload(),
+            None => Ok(()),
+        }
+    }
+
+    /// Offset in the ROM image of a gamepak address
+    #[inline]
+    fn rom_offset(&self, addr: Addr) -> usize {
+        let offset = (addr & 0x01ff_ffff) as usize;
+        match self.variant {
+            CartridgeVariant::Multicart { bank } => bank * MULTICART_BANK_SIZE + offset,
+            _ => offset,
+        }
+    }
 }
 
 use super::sysbus::consts::*;
 
 pub const EEPROM_BASE_ADDR: u32 = 0x0DFF_FF00;
 
+/// What a ROM read finds with nothing driving the bus: the address the cartridge latched, in
+/// halfwords
+fn rom_open_bus(addr: u32) -> u8 {
+    ((addr >> 1) as u16 >> ((addr & 1) * 8)) as u8
+}
+
 fn is_gpio_access(addr: u32) -> bool {
     match addr & 0x1ff_ffff {
         GPIO_PORT_DATA | GPIO_PORT_DIRECTION | GPIO_PORT_CONTROL => true,
@@ -67,7 +344,18 @@ fn is_gpio_access(addr: u32) -> bool {
 
 impl Bus for Cartridge {
     fn read_8(&self, addr: Addr) -> u8 {
-        let offset = (addr & 0x01ff_ffff) as usize;
+        if self.removed {
+            return match addr & 0xff000000 {
+                SRAM_LO | SRAM_HI => 0xff,
+                _ => rom_open_bus(addr),
+            };
+        }
+        if let CartridgeVariant::Peripheral(peripheral) = &self.variant {
+            if let Some(value) = peripheral.read(addr & !1) {
+                return (value >> ((addr & 1) * 8)) as u8;
+            }
+        }
+        let offset = self.rom_offset(addr);
         match addr & 0xff000000 {
             SRAM_LO | SRAM_HI => match &self.backup {
                 BackupMedia::Sram(memory) => memory.read((addr & 0x7FFF) as usize),
@@ -76,7 +364,7 @@ impl Bus for Cartridge {
             },
             _ => {
                 if offset >= self.size {
-                    0xDD // TODO - open bus implementation
+                    rom_open_bus(addr)
                 } else {
                     unsafe { *self.bytes.get_unchecked(offset as usize) }
                 }
@@ -85,15 +373,29 @@ impl Bus for Cartridge {
     }
 
     fn read_16(&self, addr: u32) -> u16 {
+        if self.removed {
+            return self.default_read_16(addr);
+        }
         if let Some(gpio) = &self.gpio {
-            if is_gpio_access(addr) {
-                if !(gpio.is_readable()) {
-                    warn!("trying to read GPIO when reads are not allowed");
-                }
+            if is_gpio_access(addr) && gpio.is_readable() {
                 return gpio.read(addr & 0x1ff_ffff);
             }
         }
 
+        if let CartridgeVariant::Peripheral(peripheral) = &self.variant {
+            if let Some(value) = peripheral.read(addr) {
+                return value;
+            }
+        }
+
+        if let CartridgeVariant::RomFlash(rom_flash) = &self.variant {
+            if addr & 0xff000000 < SRAM_LO {
+                if let Some(value) = rom_flash.read((addr & 0x1ff_ffff) as usize) {
+                    return value;
+                }
+            }
+        }
+
         if addr & 0xff000000 == GAMEPAK_WS2_HI
             && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
         {
@@ -105,17 +407,34 @@ impl Bus for Cartridge {
     }
 
     fn write_8(&mut self, addr: u32, value: u8) {
+        if self.removed {
+            return;
+        }
+        if let CartridgeVariant::Multicart { bank } = &mut self.variant {
+            if addr == MULTICART_BANK_REGISTER {
+                *bank = value as usize;
+                return;
+            }
+        }
         match addr & 0xff000000 {
             SRAM_LO | SRAM_HI => match &mut self.backup {
                 BackupMedia::Flash(flash) => flash.write(addr, value),
                 BackupMedia::Sram(memory) => memory.write((addr & 0x7FFF) as usize, value),
                 _ => {}
             },
-            _ => {} // TODO allow the debugger to write
+            _ => {
+                // the bus is 16 bits wide, the byte is on both halves
+                if let CartridgeVariant::Peripheral(peripheral) = &mut self.variant {
+                    peripheral.write(addr & !1, value as u16 * 0x0101);
+                }
+            }
         };
     }
 
     fn write_16(&mut self, addr: u32, value: u16) {
+        if self.removed {
+            return;
+        }
         if let Some(gpio) = &mut self.gpio {
             if is_gpio_access(addr) {
                 gpio.write(addr & 0x1ff_ffff, value);
@@ -123,6 +442,13 @@ impl Bus for Cartridge {
             }
         }
 
+        if let CartridgeVariant::RomFlash(rom_flash) = &mut self.variant {
+            if addr & 0xff000000 == GAMEPAK_WS0_LO {
+                rom_flash.write(self.bytes.to_mut(), (addr & 0x1ff_ffff) as usize, value);
+                return;
+            }
+        }
+
         if addr & 0xff000000 == GAMEPAK_WS2_HI
             && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
         {
@@ -130,13 +456,191 @@ impl Bus for Cartridge {
                 return spi.write_half(addr, value);
             }
         }
+
+        if let CartridgeVariant::Peripheral(peripheral) = &mut self.variant {
+            if addr < SRAM_LO {
+                peripheral.write(addr, value);
+                return;
+            }
+        }
         self.default_write_16(addr, value);
     }
 }
 
 impl DebugRead for Cartridge {
+    /// Past the end of the ROM, finds the open bus like the cpu would
     fn debug_read_8(&self, addr: Addr) -> u8 {
-        let offset = (addr & 0x01ff_ffff) as usize;
-        self.bytes[offset]
+        match addr & 0xff000000 {
+            SRAM_LO | SRAM_HI => self.read_8(addr),
+            _ => {
+                let offset = self.rom_offset(addr);
+                if offset < self.bytes.len() {
+                    self.bytes[offset]
+                } else {
+                    rom_open_bus(addr)
+                }
+            }
+        }
+    }
+}
+
+impl DebugWrite for Cartridge {
+    /// Patches the ROM, the backup memory is written like the game would
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        match addr & 0xff000000 {
+            SRAM_LO | SRAM_HI => self.write_8(addr, value),
+            _ => {
+                let offset = self.rom_offset(addr);
+                if offset < self.bytes.len() {
+                    self.bytes.debug_write_8(offset as Addr, value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multicart_bank_switch() {
+        let mut rom = vec![0; 2 * MULTICART_BANK_SIZE];
+        rom[MULTICART_BANK_SIZE + 0x100] = 0x42;
+        header::fix_checksum(&mut rom);
+        let mut cartridge = GamepakBuilder::new()
+            .buffer(&rom)
+            .variant(CartridgeVariant::multicart())
+            .without_backup_to_file()
+            .build()
+            .unwrap();
+
+        assert_eq!(cartridge.read_8(0x0800_0100), 0);
+        cartridge.write_8(MULTICART_BANK_REGISTER, 1);
+        assert_eq!(cartridge.read_8(0x0800_0100), 0x42);
+    }
+
+    #[test]
+    fn test_peripheral_registers() {
+        let mut rom = vec![0; 0x200];
+        rom[0x100] = 0x42;
+        header::fix_checksum(&mut rom);
+        let mut cartridge = GamepakBuilder::new()
+            .buffer(&rom)
+            .variant(CartridgeVariant::try_from("play-yan").unwrap())
+            .without_backup_to_file()
+            .build()
+            .unwrap();
+
+        cartridge.write_16(0x0b00_0010, 0x1234);
+        assert_eq!(cartridge.read_16(0x0b00_0010), 0x1234);
+        assert_eq!(cartridge.read_8(0x0b00_0011), 0x12);
+        cartridge.write_8(0x0900_0004, 0x56);
+        assert_eq!(cartridge.read_16(0x0900_0004), 0x5656);
+        // the firmware is still there
+        cartridge.write_16(0x0800_0100, 0xffff);
+        assert_eq!(cartridge.read_8(0x0800_0100), 0x42);
+    }
+
+    #[test]
+    fn test_removed_cartridge_reads_open_bus() {
+        let mut rom = vec![0x42; 0x200];
+        rom[0] = 0;
+        header::fix_checksum(&mut rom);
+        let mut cartridge = GamepakBuilder::new()
+            .buffer(&rom)
+            .with_sram()
+            .without_backup_to_file()
+            .build()
+            .unwrap();
+        cartridge.write_8(0x0e00_0000, 0x12);
+        // past the end of the ROM
+        assert_eq!(cartridge.read_16(0x0800_1234), 0x091a);
+
+        cartridge.remove();
+        assert_eq!(cartridge.read_8(0x0800_0100), 0x80);
+        assert_eq!(cartridge.read_8(0x0800_0101), 0x00);
+        assert_eq!(cartridge.read_32(0x0800_0100), 0x0081_0080);
+        assert_eq!(cartridge.read_8(0x0e00_0000), 0xff);
+        cartridge.write_8(0x0e00_0001, 0x34);
+
+        let gamepak = GamepakBuilder::new()
+            .buffer(&rom)
+            .without_backup_to_file()
+            .build()
+            .unwrap();
+        cartridge.replace_rom(gamepak);
+        assert!(!cartridge.is_removed());
+        assert_eq!(cartridge.read_8(0x0800_0100), 0x42);
+        assert_eq!(cartridge.read_8(0x0e00_0000), 0x12);
+        assert_eq!(cartridge.read_8(0x0e00_0001), 0xff);
+    }
+
+    #[test]
+    fn test_backup_from_data() {
+        let mut rom = vec![0; 0x200];
+        header::fix_checksum(&mut rom);
+        let build = |save_type, data: Vec<u8>| {
+            GamepakBuilder::new()
+                .buffer(&rom)
+                .with_backup_data(save_type, data)
+                .without_backup_to_file()
+                .build()
+                .unwrap()
+        };
+
+        let eeprom: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+        let cartridge = build(BackupType::Eeprom, eeprom.clone());
+        assert_eq!(cartridge.backup_bytes(), Some(eeprom));
+
+        let flash = vec![0x5a; 0x20000];
+        let cartridge = build(BackupType::Flash, flash.clone());
+        assert_eq!(cartridge.backup_bytes(), Some(flash));
+
+        let cartridge = build(BackupType::Sram, vec![1, 2, 3]);
+        assert_eq!(cartridge.read_8(SRAM_LO + 2), 3);
+        let sram = cartridge.backup_bytes().unwrap();
+        assert_eq!(sram.len(), 0x8000);
+        assert_eq!(sram[..4], [1, 2, 3, 0xff]);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_backup_reloaded_after_external_change() {
+        use std::fs;
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let path = std::env::temp_dir().join(format!("rba-reload-{}.sav", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut rom = vec![0; 0x200];
+        header::fix_checksum(&mut rom);
+        let mut cartridge = GamepakBuilder::new()
+            .buffer(&rom)
+            .with_sram()
+            .save_path(&path)
+            .build()
+            .unwrap();
+
+        // the game's own saves are not external changes
+        cartridge.write_8(SRAM_LO + 1, 0x11);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        assert!(!cartridge.backup_changed_on_disk());
+
+        let mut edited = fs::read(&path).unwrap();
+        edited[0] = 0x42;
+        fs::write(&path, &edited).unwrap();
+        assert!(cartridge.backup_changed_on_disk());
+        assert!(!cartridge.backup_busy());
+        cartridge.reload_backup().unwrap();
+        assert!(!cartridge.backup_changed_on_disk());
+        assert_eq!(cartridge.read_8(SRAM_LO), 0x42);
+        assert_eq!(cartridge.read_8(SRAM_LO + 1), 0x11);
+
+        fs::remove_file(&path).unwrap();
     }
 }
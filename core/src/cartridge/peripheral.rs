@@ -0,0 +1,139 @@
+//! Gamepaks that are devices more than games, talking to their firmware through registers on
+//! the gamepak bus: the Play-Yan media player and the Campho Advance videophone.
+//!
+//! None of their hardware is emulated yet. This is the scaffolding for it: the firmware dump is
+//! mapped as a ROM, and the accesses to the register windows of the device are logged with the
+//! `CART` target, each halfword reading back what was last written to it. Writes anywhere else in
+//! the ROM space, which a plain gamepak ignores, are logged too. Enough for a dump to boot, and to
+//! find out what it expects from the device.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::logging;
+
+/// A range of the gamepak bus where the device has registers
+#[derive(Debug, PartialEq)]
+pub struct RegisterWindow {
+    pub name: &'static str,
+    pub start: u32,
+    /// Exclusive
+    pub end: u32,
+    /// Reads return the last value written, otherwise they see the ROM and only writes are
+    /// logged
+    pub read_back: bool,
+}
+
+impl RegisterWindow {
+    const fn new(name: &'static str, start: u32, end: u32, read_back: bool) -> RegisterWindow {
+        RegisterWindow {
+            name,
+            start,
+            end,
+            read_back,
+        }
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+const PLAY_YAN_WINDOWS: [RegisterWindow; 2] = [
+    RegisterWindow::new("command", 0x0900_0000, 0x0900_1000, true),
+    RegisterWindow::new("control", 0x0b00_0000, 0x0b00_0100, true),
+];
+
+/// The camera and the phone line are commanded through writes over the start of the ROM
+const CAMPHO_WINDOWS: [RegisterWindow; 1] = [RegisterWindow::new(
+    "command",
+    0x0800_0000,
+    0x0801_0000,
+    false,
+)];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PeripheralKind {
+    PlayYan,
+    CamphoAdvance,
+}
+
+impl PeripheralKind {
+    pub fn windows(&self) -> &'static [RegisterWindow] {
+        match self {
+            PeripheralKind::PlayYan => &PLAY_YAN_WINDOWS,
+            PeripheralKind::CamphoAdvance => &CAMPHO_WINDOWS,
+        }
+    }
+}
+
+/// Parses "play-yan" or "campho"
+impl TryFrom<&str> for PeripheralKind {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, String> {
+        match s {
+            "play-yan" => Ok(PeripheralKind::PlayYan),
+            "campho" => Ok(PeripheralKind::CamphoAdvance),
+            _ => Err(format!("{} is not a valid peripheral", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Peripheral {
+    kind: PeripheralKind,
+    /// Last value written to each halfword of the windows
+    registers: BTreeMap<u32, u16>,
+}
+
+impl Peripheral {
+    pub fn new(kind: PeripheralKind) -> Peripheral {
+        Peripheral {
+            kind,
+            registers: BTreeMap::new(),
+        }
+    }
+
+    pub fn kind(&self) -> PeripheralKind {
+        self.kind
+    }
+
+    pub fn window(&self, addr: u32) -> Option<&'static RegisterWindow> {
+        self.kind
+            .windows()
+            .iter()
+            .find(|window| window.contains(addr))
+    }
+
+    /// The value of the register at `addr`, `None` outside the windows that read back
+    pub fn read(&self, addr: u32) -> Option<u16> {
+        let window = self.window(addr).filter(|window| window.read_back)?;
+        let value = self.registers.get(&(addr & !1)).copied().unwrap_or(0);
+        trace!(target: logging::CART,
+            "[{:?}] read {} {:#010x}={:#06x}", self.kind, window.name, addr, value
+        );
+        Some(value)
+    }
+
+    /// Takes any write to the gamepak bus, false when it is outside the windows
+    pub fn write(&mut self, addr: u32, value: u16) -> bool {
+        match self.window(addr) {
+            Some(window) => {
+                debug!(target: logging::CART,
+                    "[{:?}] write {} {:#010x}={:#06x}", self.kind, window.name, addr, value
+                );
+                self.registers.insert(addr & !1, value);
+                true
+            }
+            None => {
+                debug!(target: logging::CART,
+                    "[{:?}] unmapped write {:#010x}={:#06x}", self.kind, addr, value
+                );
+                false
+            }
+        }
+    }
+}
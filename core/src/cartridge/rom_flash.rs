@@ -0,0 +1,196 @@
+//! Flash memory mapped in place of the ROM, as found on DACS development carts and on bootleg
+//! flash carts. Commands are written through the gamepak region with the AMD/Fujitsu command set
+//! in 16-bit mode, so the unlock sequence is `0xAA` at offset `0xAAA` then `0x55` at `0x554`.
+//!
+//! The flash contents are the ROM itself, programmed data is kept in savestates but is not written
+//! back to the ROM file.
+use num::FromPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::logging;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum RomFlashWriteSequence {
+    Initial,
+    Magic,
+    Command,
+    Program,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+enum RomFlashMode {
+    ReadArray,
+    AutoSelect,
+    Erase,
+}
+
+#[derive(Debug, Primitive)]
+enum RomFlashCommand {
+    AutoSelect = 0x90,
+    Reset = 0xf0,
+    Erase = 0x80,
+    EraseEntireChip = 0x10,
+    EraseSector = 0x30,
+    Program = 0xa0,
+}
+
+const UNLOCK1_OFFSET: usize = 0xaaa;
+const UNLOCK2_OFFSET: usize = 0x554;
+
+const MANUFACTURER_ID: u16 = 0x0004; // Fujitsu
+const DEVICE_ID: u16 = 0x22fd; // MBM29LV640, 8MB
+
+pub const ROM_FLASH_SIZE: usize = 8 * 1024 * 1024;
+pub const ROM_FLASH_SECTOR_SIZE: usize = 0x10000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RomFlash {
+    wrseq: RomFlashWriteSequence,
+    mode: RomFlashMode,
+}
+
+impl Default for RomFlash {
+    fn default() -> RomFlash {
+        RomFlash::new()
+    }
+}
+
+impl RomFlash {
+    pub fn new() -> RomFlash {
+        RomFlash {
+            wrseq: RomFlashWriteSequence::Initial,
+            mode: RomFlashMode::ReadArray,
+        }
+    }
+
+    /// Returns the value to read at `offset` when the flash isn't in read-array mode
+    pub fn read(&self, offset: usize) -> Option<u16> {
+        match self.mode {
+            RomFlashMode::AutoSelect => match offset & 0xff {
+                0 => Some(MANUFACTURER_ID),
+                2 => Some(DEVICE_ID),
+                _ => Some(0),
+            },
+            _ => None,
+        }
+    }
+
+    fn command(&mut self, memory: &mut [u8], offset: usize, value: u16) {
+        use RomFlashCommand::*;
+        self.wrseq = RomFlashWriteSequence::Initial;
+        match RomFlashCommand::from_u16(value & 0xff) {
+            Some(AutoSelect) => self.mode = RomFlashMode::AutoSelect,
+            Some(Reset) => self.mode = RomFlashMode::ReadArray,
+            Some(Erase) => self.mode = RomFlashMode::Erase,
+            Some(EraseEntireChip) if self.mode == RomFlashMode::Erase => {
+                for byte in memory.iter_mut() {
+                    *byte = 0xff;
+                }
+                self.mode = RomFlashMode::ReadArray;
+            }
+            Some(EraseSector) if self.mode == RomFlashMode::Erase => {
+                let start = offset & !(ROM_FLASH_SECTOR_SIZE - 1);
+                let end = (start + ROM_FLASH_SECTOR_SIZE).min(memory.len());
+                if start < end {
+                    for byte in memory[start..end].iter_mut() {
+                        *byte = 0xff;
+                    }
+                }
+                self.mode = RomFlashMode::ReadArray;
+            }
+            Some(Program) => self.wrseq = RomFlashWriteSequence::Program,
+            command => {
+                warn!(target: logging::CART,
+                    "[ROM FLASH] invalid command {:?} ({:#x}) at offset {:#x}",
+                    command, value, offset
+                );
+                self.mode = RomFlashMode::ReadArray;
+            }
+        }
+    }
+
+    /// Handles a 16-bit write to the ROM region, `offset` is relative to the start of the ROM
+    pub fn write(&mut self, memory: &mut [u8], offset: usize, value: u16) {
+        trace!(target: logging::CART, "[ROM FLASH] write {:#x}={:#x}", offset, value);
+        // Reset is accepted at any time without the unlock sequence
+        if value & 0xff == RomFlashCommand::Reset as u16
+            && self.wrseq != RomFlashWriteSequence::Program
+        {
+            self.mode = RomFlashMode::ReadArray;
+            self.wrseq = RomFlashWriteSequence::Initial;
+            return;
+        }
+        match self.wrseq {
+            RomFlashWriteSequence::Initial => {
+                if offset & 0xfff == UNLOCK1_OFFSET && value & 0xff == 0xaa {
+                    self.wrseq = RomFlashWriteSequence::Magic;
+                }
+            }
+            RomFlashWriteSequence::Magic => {
+                self.wrseq = if offset & 0xfff == UNLOCK2_OFFSET && value & 0xff == 0x55 {
+                    RomFlashWriteSequence::Command
+                } else {
+                    RomFlashWriteSequence::Initial
+                };
+            }
+            RomFlashWriteSequence::Command => self.command(memory, offset, value),
+            RomFlashWriteSequence::Program => {
+                let offset = offset & !1;
+                if offset + 1 < memory.len() {
+                    // Programming can only clear bits
+                    memory[offset] &= value as u8;
+                    memory[offset + 1] &= (value >> 8) as u8;
+                }
+                self.wrseq = RomFlashWriteSequence::Initial;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unlock(flash: &mut RomFlash, memory: &mut [u8]) {
+        flash.write(memory, UNLOCK1_OFFSET, 0xaa);
+        flash.write(memory, UNLOCK2_OFFSET, 0x55);
+    }
+
+    #[test]
+    fn test_program_and_erase() {
+        let mut memory = vec![0xff; 2 * ROM_FLASH_SECTOR_SIZE];
+        let mut flash = RomFlash::new();
+
+        unlock(&mut flash, &mut memory);
+        flash.write(&mut memory, UNLOCK1_OFFSET, 0xa0);
+        flash.write(&mut memory, 0x10, 0x1234);
+        assert_eq!(&memory[0x10..0x12], &[0x34, 0x12]);
+
+        unlock(&mut flash, &mut memory);
+        flash.write(&mut memory, UNLOCK1_OFFSET, 0x80);
+        unlock(&mut flash, &mut memory);
+        flash.write(&mut memory, ROM_FLASH_SECTOR_SIZE, 0x30);
+        assert_eq!(&memory[0x10..0x12], &[0x34, 0x12]);
+
+        unlock(&mut flash, &mut memory);
+        flash.write(&mut memory, UNLOCK1_OFFSET, 0x80);
+        unlock(&mut flash, &mut memory);
+        flash.write(&mut memory, 0, 0x30);
+        assert_eq!(&memory[0x10..0x12], &[0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_auto_select() {
+        let mut memory = vec![0xff; ROM_FLASH_SECTOR_SIZE];
+        let mut flash = RomFlash::new();
+        assert_eq!(flash.read(0), None);
+
+        unlock(&mut flash, &mut memory);
+        flash.write(&mut memory, UNLOCK1_OFFSET, 0x90);
+        assert_eq!(flash.read(0), Some(MANUFACTURER_ID));
+        assert_eq!(flash.read(2), Some(DEVICE_ID));
+
+        flash.write(&mut memory, 0, 0xf0);
+        assert_eq!(flash.read(0), None);
+    }
+}
@@ -1,12 +1,14 @@
 use bit::BitIndex;
 use bit_reverse::LookupReverse;
+#[cfg(feature = "std")]
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use num::FromPrimitive;
 
-use std::cmp;
+use core::cmp;
 
+use super::super::logging;
 use super::gpio::{GpioDevice, GpioDirection, GpioState};
 
 fn num2bcd(mut num: u8) -> u8 {
@@ -24,6 +26,56 @@ fn num2bcd(mut num: u8) -> u8 {
     bcd
 }
 
+/// The date and time the RTC reports
+struct LocalTime {
+    year: i32,
+    month: u32,
+    day: u32,
+    /// 1 for monday to 7 for sunday
+    weekday: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl LocalTime {
+    #[cfg(feature = "std")]
+    fn now() -> LocalTime {
+        let local: DateTime<Local> = Local::now();
+        LocalTime {
+            year: local.year(),
+            month: local.month(),
+            day: local.day(),
+            weekday: local.weekday().number_from_monday(),
+            hour: local.hour(),
+            minute: local.minute(),
+            second: local.second(),
+        }
+    }
+
+    /// Without `std` there is no clock to read, the time is the one the chip resets to
+    #[cfg(not(feature = "std"))]
+    fn now() -> LocalTime {
+        LocalTime {
+            year: 2000,
+            month: 1,
+            day: 1,
+            weekday: 6,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+
+    fn hour(&self, mode_24h: bool) -> u32 {
+        if mode_24h {
+            self.hour
+        } else {
+            (self.hour + 11) % 12
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 enum Port {
     #[doc("Serial Clock")]
@@ -234,38 +286,28 @@ impl Rtc {
         match r {
             RegisterKind::Status => self.internal_buffer[0] = self.status.read(),
             RegisterKind::DateTime => {
-                let local: DateTime<Local> = Local::now();
-                let year = local.year();
+                let local = LocalTime::now();
+                let year = local.year;
                 assert!(year >= 2000 && year <= 2099); // Wonder if I will live to see this one fail
 
-                let hour = if self.status.mode_24h() {
-                    local.hour()
-                } else {
-                    let (_, hour12) = local.hour12();
-                    hour12 - 1
-                };
+                let hour = local.hour(self.status.mode_24h());
 
                 self.internal_buffer[0] = num2bcd((year % 100) as u8);
-                self.internal_buffer[1] = num2bcd(local.month() as u8);
-                self.internal_buffer[2] = num2bcd(local.day() as u8);
-                self.internal_buffer[3] = num2bcd(local.weekday().number_from_monday() as u8);
+                self.internal_buffer[1] = num2bcd(local.month as u8);
+                self.internal_buffer[2] = num2bcd(local.day as u8);
+                self.internal_buffer[3] = num2bcd(local.weekday as u8);
                 self.internal_buffer[4] = num2bcd(hour as u8);
-                self.internal_buffer[5] = num2bcd(local.minute() as u8);
-                self.internal_buffer[6] = num2bcd(local.second() as u8);
+                self.internal_buffer[5] = num2bcd(local.minute as u8);
+                self.internal_buffer[6] = num2bcd(local.second as u8);
             }
             RegisterKind::Time => {
-                let local: DateTime<Local> = Local::now();
-                let hour = if self.status.mode_24h() {
-                    local.hour()
-                } else {
-                    let (_, hour12) = local.hour12();
-                    hour12 - 1
-                };
+                let local = LocalTime::now();
+                let hour = local.hour(self.status.mode_24h());
                 self.internal_buffer[0] = num2bcd(hour as u8);
-                self.internal_buffer[1] = num2bcd(local.minute() as u8);
-                self.internal_buffer[2] = num2bcd(local.second() as u8);
+                self.internal_buffer[1] = num2bcd(local.minute as u8);
+                self.internal_buffer[2] = num2bcd(local.second as u8);
             }
-            _ => warn!("RTC: read {:?} not implemented", r),
+            _ => warn!(target: logging::CART, "RTC: read {:?} not implemented", r),
         }
     }
 
@@ -274,7 +316,7 @@ impl Rtc {
         match r {
             Status => self.status.write(self.internal_buffer[0]),
             ForceReset => self.force_reset(),
-            _ => warn!("RTC: write {:?} not implemented", r),
+            _ => warn!(target: logging::CART, "RTC: write {:?} not implemented", r),
         }
     }
 }
@@ -297,13 +339,13 @@ impl GpioDevice for Rtc {
         }
 
         if self.cs.high() && old_cs.low() {
-            trace!("RTC: CS went from low to high!");
+            trace!(target: logging::CART, "RTC: CS went from low to high!");
         }
 
         use RtcState::*;
 
         if self.cs.low() && self.serial_transfer_in_progress() {
-            debug!(
+            debug!(target: logging::CART,
                 "RTC: CS set low from state {:?}, resetting state",
                 self.state
             );
@@ -345,19 +387,26 @@ impl GpioDevice for Rtc {
 
                 let lsb_first = command.bit_range(0..4) == 0b0110;
                 if !lsb_first && command.bit_range(4..8) != 0b0110 {
-                    panic!("RTC bad command format");
+                    warn!(target: logging::CART, "RTC bad command format {:#010b}", command);
+                    return;
                 }
 
                 if !lsb_first {
                     command = command.swap_bits();
                 }
 
-                let reg = RegisterKind::from_u8(command.bit_range(4..7)).expect("RTC bad register");
+                let reg = match RegisterKind::from_u8(command.bit_range(4..7)) {
+                    Some(reg) => reg,
+                    None => {
+                        warn!(target: logging::CART, "RTC bad register in command {:#010b}", command);
+                        return;
+                    }
+                };
                 let byte_count = reg.param_count();
 
                 let is_read_operation = command.bit(7);
 
-                debug!(
+                debug!(target: logging::CART,
                     "RTC: got command: {} {:?} args len: {}",
                     if is_read_operation { "READ" } else { "WRITE" },
                     reg,
@@ -409,7 +458,7 @@ impl GpioDevice for Rtc {
                     return;
                 };
 
-                trace!("RTC TX BIT {}", bit);
+                trace!(target: logging::CART, "RTC TX BIT {}", bit);
                 assert_eq!(gpio_state[Port::Sio.index()], GpioDirection::In);
                 self.sio.set(bit as u16);
 
@@ -496,8 +545,8 @@ mod registers {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cell::Cell;
-    use std::rc::Rc;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
 
     fn transmit(rtc: &mut Rtc, gpio_state: &GpioState, bit: u8) {
         rtc.write(&gpio_state, 0b0100_u16 | (u16::from(bit) << 1));
@@ -641,9 +690,9 @@ mod tests {
         assert_eq!(rtc.state, RtcState::Idle);
 
         println!("{:x?}", bytes);
-        let local: DateTime<Local> = Local::now();
-        assert_eq!(bytes[0], num2bcd((local.year() % 100) as u8));
-        assert_eq!(bytes[1], num2bcd(local.month() as u8));
-        assert_eq!(bytes[2], num2bcd(local.day() as u8));
+        let local = LocalTime::now();
+        assert_eq!(bytes[0], num2bcd((local.year % 100) as u8));
+        assert_eq!(bytes[1], num2bcd(local.month as u8));
+        assert_eq!(bytes[2], num2bcd(local.day as u8));
     }
 }
@@ -0,0 +1,127 @@
+//! Where the save file of a game goes, when `GamepakBuilder::save_path` doesn't say.
+//!
+//! By default the save goes next to the ROM with the `.sav` extension, which doesn't work for
+//! read-only ROM directories or sandboxed platforms, hence a saves directory can be used
+//! instead, shared or with a subdirectory per game.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::header::CartridgeHeader;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum SaveLocation {
+    /// Next to the ROM file, ROMs loaded from a buffer get no save file
+    #[default]
+    RomDirectory,
+    /// All the saves in one directory
+    Directory(PathBuf),
+    /// A subdirectory of the given directory for each game, named after the ROM
+    PerRomDirectory(PathBuf),
+}
+
+/// How the save file is named, the extension is always `.sav`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SaveNaming {
+    /// The file name of the ROM, or the game code without one
+    #[default]
+    RomName,
+    /// The game code of the header, e.g. `AXVE.sav`. Different dumps and hacks of a game share
+    /// their save.
+    GameCode,
+}
+
+/// The game code, or the title for homebrew without one, usable as a file name
+fn header_name(header: &CartridgeHeader) -> String {
+    let name: String = [&header.game_code, &header.game_title]
+        .iter()
+        .map(|s| {
+            s.chars()
+                .filter(|c| c.is_ascii_alphanumeric() || *c == ' ' || *c == '_')
+                .collect::<String>()
+        })
+        .find(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| "game".to_string());
+    name.trim().to_string()
+}
+
+impl SaveLocation {
+    /// The save file path, `None` when the game gets no save file.
+    /// The directories are created when they don't exist.
+    pub fn resolve(
+        &self,
+        naming: SaveNaming,
+        rom_path: Option<&Path>,
+        header: &CartridgeHeader,
+    ) -> io::Result<Option<PathBuf>> {
+        let rom_name = rom_path
+            .and_then(|path| path.file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| header_name(header));
+        let file_name = match naming {
+            SaveNaming::RomName => rom_name.clone(),
+            SaveNaming::GameCode => header_name(header),
+        } + ".sav";
+
+        let dir = match self {
+            SaveLocation::RomDirectory => {
+                return Ok(rom_path.map(|path| path.with_file_name(file_name)));
+            }
+            SaveLocation::Directory(dir) => dir.clone(),
+            SaveLocation::PerRomDirectory(dir) => dir.join(rom_name),
+        };
+        fs::create_dir_all(&dir)?;
+        Ok(Some(dir.join(file_name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_paths() {
+        let mut rom = vec![0; 0x200];
+        rom[0xa0..0xac].copy_from_slice(b"POKEMON RUBY");
+        rom[0xac..0xb0].copy_from_slice(b"AXVE");
+        super::super::header::fix_checksum(&mut rom);
+        let header = super::super::header::parse(&rom).unwrap();
+        let rom_path = Path::new("roms/Pokemon - Ruby Version (v1.1).gba");
+        let dir = std::env::temp_dir().join(format!("rba-saves-{}", std::process::id()));
+
+        let resolve = |location: SaveLocation, naming, rom_path| {
+            location.resolve(naming, rom_path, &header).unwrap()
+        };
+        assert_eq!(
+            resolve(
+                SaveLocation::RomDirectory,
+                SaveNaming::RomName,
+                Some(rom_path)
+            ),
+            Some(PathBuf::from("roms/Pokemon - Ruby Version (v1.1).sav"))
+        );
+        assert_eq!(
+            resolve(SaveLocation::RomDirectory, SaveNaming::RomName, None),
+            None
+        );
+        assert_eq!(
+            resolve(
+                SaveLocation::Directory(dir.clone()),
+                SaveNaming::GameCode,
+                Some(rom_path)
+            ),
+            Some(dir.join("AXVE.sav"))
+        );
+        assert_eq!(
+            resolve(
+                SaveLocation::PerRomDirectory(dir.clone()),
+                SaveNaming::RomName,
+                None
+            ),
+            Some(dir.join("AXVE").join("AXVE.sav"))
+        );
+        assert!(dir.join("AXVE").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,128 @@
+//! Symbol files of the GBA toolchains, for ROMs that don't come as an ELF.
+//!
+//! Two formats are understood:
+//! - `.sym`, one `address name` per line as written by no$gba, or `address type name` as
+//!   written by `nm`. no$gba directives (`.arm`, `.thumb`, `.dbl:0004`...) are skipped.
+//! - `.map`, the link map of GNU ld. Only the symbol lines are kept, not the sections.
+use alloc::string::ToString;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "std")]
+use super::super::util::read_bin_file;
+#[cfg(feature = "std")]
+use super::super::{CoreError, CoreResult};
+use super::SymbolTable;
+
+fn parse_addr(s: &str) -> Option<u32> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+}
+
+/// Parses a no$gba or `nm` symbol file
+pub fn parse_sym(text: &str) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for line in text.lines() {
+        let line = line.split(';').next().unwrap_or_default();
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let (addr, name) = match tokens.as_slice() {
+            [addr, name] => (addr, name),
+            [addr, kind, name] if kind.len() == 1 => (addr, name),
+            _ => continue,
+        };
+        if let (Some(addr), true) = (parse_addr(addr), is_identifier(name)) {
+            symbols.insert(name.to_string(), addr);
+        }
+    }
+    symbols
+}
+
+/// Parses a GNU ld map file
+pub fn parse_map(text: &str) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for line in text.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        // `0x08000000 name` or `0x08000000 name = .`, section lines start with their name
+        let (addr, name) = match tokens.as_slice() {
+            [addr, name] | [addr, name, "=", ..] if addr.starts_with("0x") => (addr, name),
+            _ => continue,
+        };
+        if let (Some(addr), true) = (parse_addr(addr), is_identifier(name)) {
+            symbols.insert(name.to_string(), addr);
+        }
+    }
+    symbols
+}
+
+/// Loads a `.sym` or `.map` file
+#[cfg(feature = "std")]
+pub fn load_symbol_file(path: &Path) -> CoreResult<SymbolTable> {
+    let bytes = read_bin_file(path)?;
+    let text = String::from_utf8_lossy(&bytes);
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("map") => Ok(parse_map(&text)),
+        Some("sym") => Ok(parse_sym(&text)),
+        _ => Err(CoreError::CartridgeLoadError(format!(
+            "unknown symbol file format {}",
+            path.display()
+        ))),
+    }
+}
+
+/// The symbol file next to the ROM, like no$gba loads them
+#[cfg(feature = "std")]
+pub fn find_symbol_file(rom_path: &Path) -> Option<PathBuf> {
+    ["sym", "map"]
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sym() {
+        let nocash = "; no$gba symbols\n\
+                      08000000 .arm\n\
+                      08000000 _start\n\
+                      080000c0 .dbl:0004\n\
+                      03000000 irq_handler ; in IWRAM\n";
+        let symbols = parse_sym(nocash);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols["_start"], 0x0800_0000);
+        assert_eq!(symbols["irq_handler"], 0x0300_0000);
+
+        let nm = "080001f4 T main\n\
+                  03000010 b frame_count\n\
+                  \x20        U memcpy\n";
+        let symbols = parse_sym(nm);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols["main"], 0x0800_01f4);
+        assert_eq!(symbols["frame_count"], 0x0300_0010);
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map = ".text           0x08000000      0x2d4\n \
+                   .text          0x08000000       0xc0 crt0.o\n                \
+                   0x08000000                _start\n \
+                   .text.main     0x080000c0      0x214 main.o\n                \
+                   0x080000c0                main\n                \
+                   0x03000000                __iwram_start = ORIGIN (iwram)\n                \
+                   0x08000400                PROVIDE (__end = .)\n";
+        let symbols = parse_map(map);
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols["_start"], 0x0800_0000);
+        assert_eq!(symbols["main"], 0x0800_00c0);
+        assert_eq!(symbols["__iwram_start"], 0x0300_0000);
+    }
+}
@@ -0,0 +1,445 @@
+//! Importing the cheat lists of other emulators.
+//!
+//! The cheats of RetroArch (`.cht`), mGBA (`.cheats`) and VBA-M (`.clt`) are GameShark,
+//! CodeBreaker or VBA codes. The ones that write a constant become freeze cheats, the others
+//! (conditionals, ROM patches, hooks, Pro Action Replay v3 and encrypted CodeBreaker codes)
+//! are skipped and reported:
+//! ```ignore
+//! let report = gba.import_cheats(Path::new("Pokemon - Emerald Version (USA, Europe).cht"))?;
+//! for skipped in &report.skipped {
+//!     println!("skipped {}", skipped);
+//! }
+//! ```
+//! A cheat made of several codes becomes as many cheats, with the same name.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+use super::bus::Addr;
+use super::cheats::{Cheat, CheatKind};
+#[cfg(feature = "std")]
+use super::ResultExt;
+use super::{CoreError, CoreResult};
+
+/// The TEA keys of the GameShark Advance (v1 and v2) encryption
+const GSA_V1_SEEDS: [u32; 4] = [0x09f4_fbbd, 0x9681_884a, 0x3520_27e9, 0xf3de_e5a7];
+const TEA_DELTA: u32 = 0x9e37_79b9;
+
+/// The size of a VBA-M cheat, with and without the raw address
+const VBAM_ENTRY_SIZE: usize = 84;
+const VBAM_ENTRY_SIZE_V0: usize = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheatFormat {
+    /// RetroArch's `.cht`
+    RetroArch,
+    /// mGBA's `.cheats`
+    Mgba,
+    /// VBA-M's `.clt` cheat list
+    VbaM,
+}
+
+impl CheatFormat {
+    /// Guesses the format from the extension of a cheat file
+    #[cfg(feature = "std")]
+    pub fn from_path(path: &Path) -> Option<CheatFormat> {
+        match path.extension()?.to_str()? {
+            "cht" => Some(CheatFormat::RetroArch),
+            "cheats" => Some(CheatFormat::Mgba),
+            "clt" => Some(CheatFormat::VbaM),
+            _ => None,
+        }
+    }
+}
+
+/// What was imported from a cheat list
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImportReport {
+    pub cheats: Vec<Cheat>,
+    /// The codes that couldn't be imported, and why
+    pub skipped: Vec<SkippedCode>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkippedCode {
+    pub name: String,
+    pub code: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for SkippedCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.name, self.code, self.reason)
+    }
+}
+
+/// How the codes of a cheat are to be read
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CodeType {
+    /// Told apart by their length
+    Auto,
+    GameShark {
+        encrypted: bool,
+    },
+    ActionReplay,
+}
+
+impl ImportReport {
+    fn add(&mut self, name: &str, code: &str, code_type: CodeType, enabled: bool) {
+        match decode(code, code_type) {
+            Ok((addr, width, value)) => {
+                let mut cheat = Cheat::new(name, addr, width, CheatKind::Freeze(value));
+                cheat.enabled = enabled;
+                match cheat.validate() {
+                    Ok(()) => self.cheats.push(cheat),
+                    Err(_) => self.skip(name, code, "writes out of the bus"),
+                }
+            }
+            Err(reason) => self.skip(name, code, reason),
+        }
+    }
+
+    fn skip(&mut self, name: &str, code: &str, reason: &'static str) {
+        self.skipped.push(SkippedCode {
+            name: name.to_string(),
+            code: code.to_string(),
+            reason,
+        });
+    }
+}
+
+fn gsa_decrypt(mut op1: u32, mut op2: u32, seeds: &[u32; 4]) -> (u32, u32) {
+    let mut sum = TEA_DELTA.wrapping_mul(32);
+    for _ in 0..32 {
+        op2 = op2.wrapping_sub(
+            (op1 << 4).wrapping_add(seeds[2])
+                ^ op1.wrapping_add(sum)
+                ^ (op1 >> 5).wrapping_add(seeds[3]),
+        );
+        op1 = op1.wrapping_sub(
+            (op2 << 4).wrapping_add(seeds[0])
+                ^ op2.wrapping_add(sum)
+                ^ (op2 >> 5).wrapping_add(seeds[1]),
+        );
+        sum = sum.wrapping_sub(TEA_DELTA);
+    }
+    (op1, op2)
+}
+
+fn parse_hex(digits: &str) -> Result<u32, &'static str> {
+    u32::from_str_radix(digits, 16).map_err(|_| "not a code")
+}
+
+/// Decodes a code writing a constant into (address, width, value)
+fn decode(code: &str, code_type: CodeType) -> Result<(Addr, u8, u32), &'static str> {
+    let digits: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect();
+    // the lengths below are in bytes, and slicing in the middle of a character panics
+    if !digits.chars().all(|c| c.is_ascii_hexdigit() || c == ':') {
+        return Err("not a code");
+    }
+    // VBA's own codes, AAAAAAAA:VV with the value as wide as it is written
+    if let Some(i) = digits.find(':') {
+        let width = match digits.len() - i - 1 {
+            2 => 1,
+            4 => 2,
+            8 => 4,
+            _ => return Err("not a code"),
+        };
+        return Ok((
+            parse_hex(&digits[..i])?,
+            width,
+            parse_hex(&digits[i + 1..])?,
+        ));
+    }
+    match (digits.len(), code_type) {
+        (16, CodeType::ActionReplay) => Err("Pro Action Replay v3 codes aren't supported"),
+        (16, CodeType::Auto) | (16, CodeType::GameShark { .. }) => {
+            let (mut op1, mut op2) = (parse_hex(&digits[..8])?, parse_hex(&digits[8..])?);
+            if code_type != (CodeType::GameShark { encrypted: false }) {
+                let decrypted = gsa_decrypt(op1, op2, &GSA_V1_SEEDS);
+                op1 = decrypted.0;
+                op2 = decrypted.1;
+            }
+            let addr = op1 & 0x0fff_ffff;
+            match op1 >> 28 {
+                0 => Ok((addr, 1, op2 & 0xff)),
+                1 => Ok((addr, 2, op2 & 0xffff)),
+                2 => Ok((addr, 4, op2)),
+                _ => Err("only the GameShark codes writing a constant are supported"),
+            }
+        }
+        (12, _) => {
+            let (op1, op2) = (parse_hex(&digits[..8])?, parse_hex(&digits[8..])?);
+            let addr = op1 & 0x0fff_ffff;
+            match op1 >> 28 {
+                3 => Ok((addr, 1, op2 & 0xff)),
+                8 => Ok((addr, 2, op2)),
+                9 => Err("encrypted CodeBreaker codes aren't supported"),
+                _ => Err("only the CodeBreaker codes writing a constant are supported"),
+            }
+        }
+        _ => Err("not a code"),
+    }
+}
+
+/// Imports a cheat list, failing only when the list itself is malformed
+pub fn import(data: &[u8], format: CheatFormat) -> CoreResult<ImportReport> {
+    match format {
+        CheatFormat::RetroArch => import_retroarch(&text(data)?),
+        CheatFormat::Mgba => Ok(import_mgba(&text(data)?)),
+        CheatFormat::VbaM => import_vbam(data),
+    }
+}
+
+/// Imports a cheat list file, in the format its extension says
+#[cfg(feature = "std")]
+pub fn import_file(path: &Path) -> CoreResult<ImportReport> {
+    let format = CheatFormat::from_path(path).ok_or_else(|| {
+        CoreError::CheatError(format!("{}: unknown cheat list format", path.display()))
+    })?;
+    let data = fs::read(path)?;
+    import(&data, format).with_context(|| format!("importing {}", path.display()))
+}
+
+fn text(data: &[u8]) -> CoreResult<String> {
+    String::from_utf8(data.to_vec())
+        .map_err(|_| CoreError::CheatError(String::from("the cheat list isn't text")))
+}
+
+/// `cheatN_desc`, `cheatN_code` (codes joined by `+`) and `cheatN_enable` keys
+fn import_retroarch(text: &str) -> CoreResult<ImportReport> {
+    let mut values = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let i = line.find('=').ok_or_else(|| {
+            CoreError::CheatError(format!("expected key = value, found {:?}", line))
+        })?;
+        let value = line[i + 1..].trim().trim_matches('"');
+        values.insert(line[..i].trim().to_string(), value.to_string());
+    }
+    let count: usize = values
+        .get("cheats")
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| CoreError::CheatError(String::from("missing the cheats count")))?;
+    let mut report = ImportReport::default();
+    for n in 0..count {
+        let key = |field: &str| values.get(&format!("cheat{}_{}", n, field));
+        let name = key("desc")
+            .cloned()
+            .unwrap_or_else(|| format!("cheat {}", n));
+        let enabled = key("enable").is_some_and(|enable| enable == "true");
+        if let Some(codes) = key("code") {
+            for code in codes.split('+').filter(|code| !code.is_empty()) {
+                report.add(&name, code, CodeType::Auto, enabled);
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Sets of codes starting with `# name`, with `!` directives giving the code type or
+/// disabling the set
+fn import_mgba(text: &str) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut name = String::from("cheat");
+    let mut code_type = CodeType::Auto;
+    let mut enabled = true;
+    for line in text.lines().map(str::trim) {
+        if let Some(title) = line.strip_prefix('#') {
+            name = title.trim().to_string();
+            code_type = CodeType::Auto;
+            enabled = true;
+        } else if let Some(directive) = line.strip_prefix('!') {
+            match directive {
+                "disabled" => enabled = false,
+                "reset" => code_type = CodeType::Auto,
+                "GSAv1" => code_type = CodeType::GameShark { encrypted: true },
+                "GSAv1 raw" => code_type = CodeType::GameShark { encrypted: false },
+                "PARv3" | "PARv3 raw" => code_type = CodeType::ActionReplay,
+                _ => {}
+            }
+        } else if !line.is_empty() {
+            report.add(&name, line, code_type, enabled);
+        }
+    }
+    report
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// A version, a type (1 when the entries have the raw address), a count and the entries
+fn import_vbam(data: &[u8]) -> CoreResult<ImportReport> {
+    let error = |msg: &str| CoreError::CheatError(format!("bad VBA-M cheat list: {}", msg));
+    let i32_at = |i: usize| {
+        data.get(i..i + 4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| error("truncated header"))
+    };
+    if i32_at(0)? != 1 {
+        return Err(error("unsupported version"));
+    }
+    let (entry_size, code_offset) = match i32_at(4)? {
+        0 => (VBAM_ENTRY_SIZE_V0, 28),
+        1 => (VBAM_ENTRY_SIZE, 32),
+        _ => return Err(error("unsupported type")),
+    };
+    let count = i32_at(8)?.max(0) as usize;
+    let entries = &data[12..];
+    if entries.len() < count * entry_size {
+        return Err(error("truncated entries"));
+    }
+    let mut report = ImportReport::default();
+    for entry in entries.chunks(entry_size).take(count) {
+        let enabled = entry[12] != 0;
+        let code = c_string(&entry[code_offset..code_offset + 20]);
+        let name = c_string(&entry[code_offset + 20..code_offset + 52]);
+        report.add(&name, &code, CodeType::Auto, enabled);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gsa_encrypt(mut op1: u32, mut op2: u32, seeds: &[u32; 4]) -> (u32, u32) {
+        let mut sum = 0u32;
+        for _ in 0..32 {
+            sum = sum.wrapping_add(TEA_DELTA);
+            op1 = op1.wrapping_add(
+                (op2 << 4).wrapping_add(seeds[0])
+                    ^ op2.wrapping_add(sum)
+                    ^ (op2 >> 5).wrapping_add(seeds[1]),
+            );
+            op2 = op2.wrapping_add(
+                (op1 << 4).wrapping_add(seeds[2])
+                    ^ op1.wrapping_add(sum)
+                    ^ (op1 >> 5).wrapping_add(seeds[3]),
+            );
+        }
+        (op1, op2)
+    }
+
+    fn summary(report: &ImportReport) -> Vec<(&str, Addr, u8, CheatKind, bool)> {
+        report
+            .cheats
+            .iter()
+            .map(|c| (c.name.as_str(), c.addr, c.width, c.kind, c.enabled))
+            .collect()
+    }
+
+    #[test]
+    fn test_decode() {
+        let (op1, op2) = gsa_encrypt(0x1202_4284, 0x0000_03e7, &GSA_V1_SEEDS);
+        assert_eq!(gsa_decrypt(op1, op2, &GSA_V1_SEEDS), (0x1202_4284, 0x03e7));
+        let encrypted = format!("{:08X} {:08X}", op1, op2);
+        assert_eq!(
+            decode(&encrypted, CodeType::Auto),
+            Ok((0x0202_4284, 2, 0x03e7))
+        );
+        let raw = CodeType::GameShark { encrypted: false };
+        assert_eq!(decode("02024284 00000009", raw), Ok((0x0202_4284, 1, 9)));
+        assert_eq!(
+            decode("32024284 0009", CodeType::Auto),
+            Ok((0x0202_4284, 1, 9))
+        );
+        assert_eq!(
+            decode("02024284:03E7", CodeType::Auto),
+            Ok((0x0202_4284, 2, 0x3e7))
+        );
+        assert!(decode("D2024284 0009", CodeType::Auto).is_err());
+        assert!(decode("02024284 00000009", CodeType::ActionReplay).is_err());
+        assert!(decode("hello", CodeType::Auto).is_err());
+        assert_eq!(decode("0202428é0000009", CodeType::Auto), Err("not a code"));
+        assert_eq!(decode("+2024284 0009", CodeType::Auto), Err("not a code"));
+    }
+
+    #[test]
+    fn test_import_retroarch() {
+        let text = "cheats = 2\n\n\
+                    cheat0_desc = \"Infinite Health\"\n\
+                    cheat0_code = \"32024284 0009+82024286 03E7\"\n\
+                    cheat0_enable = true\n\n\
+                    cheat1_desc = \"Walk through walls\"\n\
+                    cheat1_code = \"D2024284 0009\"\n\
+                    cheat1_enable = false\n";
+        let report = import(text.as_bytes(), CheatFormat::RetroArch).unwrap();
+        assert_eq!(
+            summary(&report),
+            [
+                (
+                    "Infinite Health",
+                    0x0202_4284,
+                    1,
+                    CheatKind::Freeze(9),
+                    true
+                ),
+                (
+                    "Infinite Health",
+                    0x0202_4286,
+                    2,
+                    CheatKind::Freeze(0x3e7),
+                    true
+                ),
+            ]
+        );
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].name, "Walk through walls");
+        assert!(import(b"cheat0_code = 1", CheatFormat::RetroArch).is_err());
+    }
+
+    #[test]
+    fn test_import_mgba() {
+        let text = "# Max money\n\
+                    !GSAv1 raw\n\
+                    22024284 0001869F\n\
+                    # Lives\n\
+                    !disabled\n\
+                    02024290:09\n\
+                    # Moon jump\n\
+                    !PARv3\n\
+                    12345678 9ABCDEF0\n";
+        let report = import(text.as_bytes(), CheatFormat::Mgba).unwrap();
+        assert_eq!(
+            summary(&report),
+            [
+                ("Max money", 0x0202_4284, 4, CheatKind::Freeze(99999), true),
+                ("Lives", 0x0202_4290, 1, CheatKind::Freeze(9), false),
+            ]
+        );
+        assert_eq!(report.skipped[0].name, "Moon jump");
+    }
+
+    #[test]
+    fn test_import_vbam() {
+        let mut data = Vec::new();
+        for value in &[1i32, 1, 1] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut entry = [0u8; VBAM_ENTRY_SIZE];
+        entry[12] = 1;
+        entry[32..45].copy_from_slice(b"02024284:03E7");
+        entry[52..57].copy_from_slice(b"Coins");
+        data.extend_from_slice(&entry);
+
+        let report = import(&data, CheatFormat::VbaM).unwrap();
+        assert_eq!(
+            summary(&report),
+            [("Coins", 0x0202_4284, 2, CheatKind::Freeze(0x3e7), true)]
+        );
+        assert!(import(&data[..40], CheatFormat::VbaM).is_err());
+    }
+}
@@ -0,0 +1,497 @@
+//! Cheats that hold game variables in the RAM, and the memory search that finds them.
+//!
+//! A `RamSearch` keeps the addresses whose value behaves as told, search after search, until
+//! the variable is found and can be turned into a cheat:
+//! ```ignore
+//! let mut search = RamSearch::new(&mut gba, 1);
+//! // ... lose a life ...
+//! search.filter(&mut gba, SearchFilter::Decreased);
+//! search.filter(&mut gba, SearchFilter::Equal(2));
+//! if let Some(lives) = search.single() {
+//!     gba.cheats_mut().add(lives.cheat("Infinite lives", CheatKind::Freeze(9)));
+//! }
+//! gba.save_cheats(Path::new("BPEE.cheats.toml"))?;
+//! ```
+//! The enabled cheats are applied at the start of every frame. A cheat file holds the cheats
+//! of one game:
+//! ```toml
+//! game_code = "BPEE"
+//!
+//! [[cheat]]
+//! name = "Infinite lives"
+//! address = 0x02024284
+//! size = 1
+//! freeze = 9
+//!
+//! # Keeps the value in a range, the game can change it within
+//! [[cheat]]
+//! name = "Money"
+//! address = 0x03001000
+//! size = 2
+//! min = 100
+//! max = 999
+//! enabled = false
+//! ```
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use toml;
+
+use super::bus::Addr;
+use super::memory::{Memory, MAX_ADDR};
+use super::ram_diff::RamSnapshot;
+use super::sysbus::consts::{EWRAM_ADDR, INTERNAL_RAM_SIZE, IWRAM_ADDR, WORK_RAM_SIZE};
+use super::{CoreError, CoreResult, GameBoyAdvance};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheatKind {
+    /// Writes the value every frame
+    Freeze(u32),
+    /// Brings the value back in `min..=max` every frame
+    Clamp { min: u32, max: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    pub name: String,
+    pub addr: Addr,
+    /// 1, 2 or 4 bytes
+    pub width: u8,
+    pub kind: CheatKind,
+    pub enabled: bool,
+}
+
+fn width_mask(width: u8) -> u32 {
+    match width {
+        4 => 0xffff_ffff,
+        _ => (1 << (width as u32 * 8)) - 1,
+    }
+}
+
+impl Cheat {
+    /// An enabled cheat
+    pub fn new(name: &str, addr: Addr, width: u8, kind: CheatKind) -> Cheat {
+        Cheat {
+            name: name.to_string(),
+            addr,
+            width,
+            kind,
+            enabled: true,
+        }
+    }
+
+    /// Checks that the cheat can be applied
+    pub fn validate(&self) -> CoreResult<()> {
+        let error = |msg: &str| Err(CoreError::CheatError(format!("{}: {}", self.name, msg)));
+        match self.width {
+            1 | 2 | 4 => {}
+            _ => return error("the size should be 1, 2 or 4 bytes"),
+        }
+        if !self.addr.is_multiple_of(self.width as u32) || self.addr > MAX_ADDR {
+            return error("the address is misaligned or out of the bus");
+        }
+        let mask = width_mask(self.width);
+        match self.kind {
+            CheatKind::Freeze(value) if value & !mask != 0 => error("the value is too large"),
+            CheatKind::Clamp { min, max } if max & !mask != 0 || min > max => {
+                error("the range is empty or too large")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&self, mem: &mut Memory) -> CoreResult<()> {
+        let value = match self.kind {
+            CheatKind::Freeze(value) => value,
+            CheatKind::Clamp { min, max } => {
+                let value = match self.width {
+                    1 => mem.read::<u8>(self.addr)? as u32,
+                    2 => mem.read::<u16>(self.addr)? as u32,
+                    _ => mem.read::<u32>(self.addr)?,
+                };
+                if (min..=max).contains(&value) {
+                    return Ok(());
+                }
+                value.max(min).min(max)
+            }
+        };
+        match self.width {
+            1 => mem.write::<u8>(self.addr, value as u8),
+            2 => mem.write::<u16>(self.addr, value as u16),
+            _ => mem.write::<u32>(self.addr, value),
+        }
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: 0x{:08x} u{} ",
+            if self.enabled { 'x' } else { ' ' },
+            self.name,
+            self.addr,
+            self.width * 8
+        )?;
+        match self.kind {
+            CheatKind::Freeze(value) => write!(f, "= {}", value),
+            CheatKind::Clamp { min, max } => write!(f, "in {}..={}", min, max),
+        }
+    }
+}
+
+/// The format of a cheat in a cheat file, cheat files are read and written with `std` only
+#[cfg(feature = "std")]
+#[derive(Serialize, Deserialize)]
+struct CheatEntry {
+    name: String,
+    address: u32,
+    size: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    freeze: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max: Option<u32>,
+    #[serde(default = "enabled_default")]
+    enabled: bool,
+}
+
+#[cfg(feature = "std")]
+fn enabled_default() -> bool {
+    true
+}
+
+#[cfg(feature = "std")]
+#[derive(Serialize, Deserialize)]
+struct CheatFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    game_code: Option<String>,
+    #[serde(default, rename = "cheat")]
+    cheats: Vec<CheatEntry>,
+}
+
+/// The cheats of a game, returned by `GameBoyAdvance::cheats`
+#[derive(Debug, Default, Clone)]
+pub struct CheatEngine {
+    /// The game the cheats were made for, if the cheat file says
+    pub game_code: Option<String>,
+    cheats: Vec<Cheat>,
+}
+
+impl CheatEngine {
+    pub fn new() -> CheatEngine {
+        CheatEngine::default()
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// Adds a cheat, returning its index
+    pub fn add(&mut self, cheat: Cheat) -> CoreResult<usize> {
+        cheat.validate()?;
+        self.cheats.push(cheat);
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        if index < self.cheats.len() {
+            Some(self.cheats.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns false when there is no such cheat
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub(crate) fn apply(&self, mem: &mut Memory) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            if let Err(e) = cheat.apply(mem) {
+                warn!("cheat {} not applied: {}", cheat.name, e);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn from_toml(text: &str) -> CoreResult<CheatEngine> {
+        let file: CheatFile =
+            toml::from_str(text).map_err(|e| CoreError::CheatError(e.to_string()))?;
+        let mut engine = CheatEngine {
+            game_code: file.game_code,
+            cheats: Vec::new(),
+        };
+        for entry in file.cheats {
+            let kind = match (entry.freeze, entry.min, entry.max) {
+                (Some(value), None, None) => CheatKind::Freeze(value),
+                (None, Some(min), Some(max)) => CheatKind::Clamp { min, max },
+                _ => {
+                    return Err(CoreError::CheatError(format!(
+                        "{}: expected either freeze or min and max",
+                        entry.name
+                    )))
+                }
+            };
+            let mut cheat = Cheat::new(&entry.name, entry.address, entry.size, kind);
+            cheat.enabled = entry.enabled;
+            engine.add(cheat)?;
+        }
+        Ok(engine)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_toml(&self) -> String {
+        let file = CheatFile {
+            game_code: self.game_code.clone(),
+            cheats: self
+                .cheats
+                .iter()
+                .map(|cheat| {
+                    let (freeze, min, max) = match cheat.kind {
+                        CheatKind::Freeze(value) => (Some(value), None, None),
+                        CheatKind::Clamp { min, max } => (None, Some(min), Some(max)),
+                    };
+                    CheatEntry {
+                        name: cheat.name.clone(),
+                        address: cheat.addr,
+                        size: cheat.width,
+                        freeze,
+                        min,
+                        max,
+                        enabled: cheat.enabled,
+                    }
+                })
+                .collect(),
+        };
+        toml::to_string_pretty(&file).expect("failed to serialize the cheats")
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(path: &Path) -> CoreResult<CheatEngine> {
+        let text = fs::read_to_string(path)?;
+        CheatEngine::from_toml(&text)
+            .map_err(|e| CoreError::CheatError(format!("{}: {}", path.display(), e)))
+    }
+
+    #[cfg(feature = "std")]
+    pub fn save(&self, path: &Path) -> CoreResult<()> {
+        fs::write(path, self.to_toml())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchFilter {
+    Equal(u32),
+    NotEqual(u32),
+    /// Compared with the value at the previous search
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl SearchFilter {
+    fn keeps(self, old: u32, new: u32) -> bool {
+        match self {
+            SearchFilter::Equal(value) => new == value,
+            SearchFilter::NotEqual(value) => new != value,
+            SearchFilter::Changed => new != old,
+            SearchFilter::Unchanged => new == old,
+            SearchFilter::Increased => new > old,
+            SearchFilter::Decreased => new < old,
+        }
+    }
+}
+
+/// An address still matching the search
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchResult {
+    pub addr: Addr,
+    pub width: u8,
+    /// The value at the last search
+    pub value: u32,
+}
+
+impl SearchResult {
+    pub fn cheat(&self, name: &str, kind: CheatKind) -> Cheat {
+        Cheat::new(name, self.addr, self.width, kind)
+    }
+}
+
+/// A search for the address of a game variable in the work RAMs
+#[derive(Debug, Clone)]
+pub struct RamSearch {
+    width: u8,
+    results: Vec<SearchResult>,
+}
+
+impl RamSearch {
+    /// Starts a search for a `width` bytes variable, every aligned address is a candidate
+    pub fn new(gba: &mut GameBoyAdvance, width: u8) -> CoreResult<RamSearch> {
+        match width {
+            1 | 2 | 4 => {}
+            _ => {
+                return Err(CoreError::CheatError(String::from(
+                    "searches are for 1, 2 or 4 bytes values",
+                )))
+            }
+        }
+        let snapshot = RamSnapshot::capture(gba, "search");
+        let regions = [
+            (EWRAM_ADDR, WORK_RAM_SIZE as u32),
+            (IWRAM_ADDR, INTERNAL_RAM_SIZE as u32),
+        ];
+        let results = regions
+            .iter()
+            .flat_map(|(base, size)| (*base..base + size).step_by(width as usize))
+            .map(|addr| SearchResult {
+                addr,
+                width,
+                value: snapshot.read(addr, width).unwrap(),
+            })
+            .collect();
+        Ok(RamSearch { width, results })
+    }
+
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    /// Keeps the candidates that match `filter`, returning how many are left
+    pub fn filter(&mut self, gba: &mut GameBoyAdvance, filter: SearchFilter) -> usize {
+        let snapshot = RamSnapshot::capture(gba, "search");
+        let results = core::mem::take(&mut self.results);
+        self.results = results
+            .into_iter()
+            .filter_map(|result| {
+                let value = snapshot.read(result.addr, result.width).unwrap();
+                if filter.keeps(result.value, value) {
+                    Some(SearchResult { value, ..result })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        self.results.len()
+    }
+
+    pub fn results(&self) -> &[SearchResult] {
+        &self.results
+    }
+
+    /// The result once the search is down to one address
+    pub fn single(&self) -> Option<&SearchResult> {
+        match self.results.as_slice() {
+            [result] => Some(result),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gba::test_util::make_mock_gba;
+
+    #[test]
+    fn test_search_and_freeze() {
+        let mut gba = make_mock_gba(&[]);
+        gba.mem().write::<u16>(0x0300_0100, 3).unwrap();
+        gba.mem().write::<u16>(0x0200_0200, 3).unwrap();
+        let mut search = RamSearch::new(&mut gba, 2).unwrap();
+        assert_eq!(
+            search.results().len(),
+            (WORK_RAM_SIZE + INTERNAL_RAM_SIZE) / 2
+        );
+
+        gba.mem().write::<u16>(0x0300_0100, 2).unwrap();
+        assert_eq!(search.filter(&mut gba, SearchFilter::Decreased), 1);
+        let lives = *search.single().unwrap();
+        assert_eq!((lives.addr, lives.value), (0x0300_0100, 2));
+
+        let cheats = gba.cheats_mut();
+        cheats
+            .add(lives.cheat("Infinite lives", CheatKind::Freeze(9)))
+            .unwrap();
+        cheats
+            .add(Cheat::new(
+                "Money",
+                0x0200_0200,
+                2,
+                CheatKind::Clamp { min: 100, max: 999 },
+            ))
+            .unwrap();
+        gba.frame();
+        assert_eq!(gba.mem().read::<u16>(0x0300_0100).unwrap(), 9);
+        assert_eq!(gba.mem().read::<u16>(0x0200_0200).unwrap(), 100);
+
+        // the game can change a clamped value within the range
+        gba.mem().write::<u16>(0x0200_0200, 500).unwrap();
+        gba.frame();
+        assert_eq!(gba.mem().read::<u16>(0x0200_0200).unwrap(), 500);
+
+        assert!(gba.cheats_mut().set_enabled(0, false));
+        gba.mem().write::<u16>(0x0300_0100, 1).unwrap();
+        gba.frame();
+        assert_eq!(gba.mem().read::<u16>(0x0300_0100).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_cheat_file_round_trip() {
+        let mut engine = CheatEngine::new();
+        engine.game_code = Some(String::from("BPEE"));
+        engine
+            .add(Cheat::new("Lives", 0x0202_4284, 1, CheatKind::Freeze(9)))
+            .unwrap();
+        let mut money = Cheat::new(
+            "Money",
+            0x0300_1000,
+            2,
+            CheatKind::Clamp { min: 100, max: 999 },
+        );
+        money.enabled = false;
+        engine.add(money).unwrap();
+
+        let loaded = CheatEngine::from_toml(&engine.to_toml()).unwrap();
+        assert_eq!(loaded.game_code.as_deref(), Some("BPEE"));
+        assert_eq!(loaded.cheats(), engine.cheats());
+        assert_eq!(
+            loaded.cheats()[1].to_string(),
+            "[ ] Money: 0x03001000 u16 in 100..=999"
+        );
+
+        assert!(engine
+            .add(Cheat::new("Big", 0x0200_0000, 1, CheatKind::Freeze(0x100)))
+            .is_err());
+        assert!(engine
+            .add(Cheat::new("Odd", 0x0200_0001, 2, CheatKind::Freeze(0)))
+            .is_err());
+        let both = "[[cheat]]\nname = \"x\"\naddress = 0x02000000\nsize = 1\nfreeze = 1\nmin = 0\n";
+        assert!(CheatEngine::from_toml(both).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Emulator settings shared by the frontends.
+//!
+//! The settings are kept in a TOML file, where every section and key is optional:
+//! ```toml
+//! [paths]
+//! bios = "gba_bios.bin"
+//! save_dir = "saves"
+//!
+//! [emulation]
+//! skip_bios = true
+//!
+//! [audio]
+//! interpolation = "cubic"
+//!
+//! # Overrides for a game code, in the same format as the whole file
+//! [games.BPEE.video]
+//! color_correction = true
+//! ```
+//! Settings coming from several places (the file, then the command line for instance) are
+//! layered with `Config::merge_toml`. Options with a fixed set of values are kept as the same
+//! strings the frontends accept on their command line, and checked when merging.
+//!
+//! `ConfigStore` holds the settings of a running frontend and tells its listeners which
+//! sections changed.
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use super::bios_db::BiosCheck;
+use super::cartridge::{BackupRestore, BackupType, PeripheralKind};
+use super::input::{DpadFilter, DEFAULT_ANALOG_DEADZONE};
+use super::rom_watch::ReloadMode;
+use super::sio::DummyLink;
+use super::sound::Interpolation;
+use super::{CoreError, CoreResult, ResultExt};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PathsConfig {
+    pub bios: PathBuf,
+    /// Directory for the save files, next to the ROM when not set
+    pub save_dir: Option<PathBuf>,
+    /// Directory for the savestate slots, a `states` directory next to the ROM when not set
+    pub state_dir: Option<PathBuf>,
+    /// Directory of game profiles, see `profile.rs`
+    pub profiles: Option<PathBuf>,
+    /// No-Intro DAT file to verify ROMs against
+    pub rom_database: Option<PathBuf>,
+}
+
+impl Default for PathsConfig {
+    fn default() -> PathsConfig {
+        PathsConfig {
+            bios: PathBuf::from("gba_bios.bin"),
+            save_dir: None,
+            state_dir: None,
+            profiles: None,
+            rom_database: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct EmulationConfig {
+    pub skip_bios: bool,
+    /// See `BiosCheck`, "off", "warn" or "error"
+    pub bios_check: String,
+    /// See `BackupType`, "autodetect" or a save type
+    pub save_type: String,
+    pub rtc: bool,
+    pub rom_flash: bool,
+    /// See `PeripheralKind`, "play-yan" or "campho"
+    pub peripheral: Option<String>,
+    /// See `BackupRestore`, "keep-on-disk" or "from-state"
+    pub state_save: String,
+    /// Load the savestates made with another ROM, or a patched one, instead of refusing them
+    pub force_state_load: bool,
+    /// Reload the save file when another program changes it
+    pub watch_save: bool,
+    /// Reload the ROM when it is rebuilt, see `rom_watch`
+    pub watch_rom: bool,
+    /// See `ReloadMode`, "reset" or "keep-state"
+    pub rom_reload: String,
+    /// See `DummyLink`, "disconnected", "slave" or "echo"
+    pub link_cable: String,
+    /// Frontend specific, see `UartBridge::open`
+    pub uart_bridge: Option<String>,
+}
+
+impl Default for EmulationConfig {
+    fn default() -> EmulationConfig {
+        EmulationConfig {
+            skip_bios: false,
+            bios_check: "warn".to_string(),
+            save_type: "autodetect".to_string(),
+            rtc: false,
+            rom_flash: false,
+            peripheral: None,
+            state_save: "keep-on-disk".to_string(),
+            force_state_load: false,
+            watch_save: false,
+            watch_rom: false,
+            rom_reload: "reset".to_string(),
+            link_cable: "disconnected".to_string(),
+            uart_bridge: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct VideoConfig {
+    /// Frontend specific shader names or files
+    pub shaders: Vec<String>,
+    /// Frontend specific, how the picture fills the window
+    pub scale: String,
+    pub color_correction: bool,
+    pub show_fps: bool,
+}
+
+impl Default for VideoConfig {
+    fn default() -> VideoConfig {
+        VideoConfig {
+            shaders: Vec::new(),
+            scale: "fit".to_string(),
+            color_correction: false,
+            show_fps: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// See `Interpolation`, "none", "linear", "cosine" or "cubic"
+    pub interpolation: String,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            interpolation: "none".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct InputConfig {
+    /// Frontend specific key bindings file
+    pub keymap: Option<PathBuf>,
+    pub analog_deadzone: i16,
+    /// See `DpadFilter`, "hardware" or "block"
+    pub dpad_filter: String,
+}
+
+impl Default for InputConfig {
+    fn default() -> InputConfig {
+        InputConfig {
+            keymap: None,
+            analog_deadzone: DEFAULT_ANALOG_DEADZONE,
+            dpad_filter: "hardware".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub paths: PathsConfig,
+    pub emulation: EmulationConfig,
+    pub video: VideoConfig,
+    pub audio: AudioConfig,
+    pub input: InputConfig,
+    /// Overrides by game code, tables in the format of the whole config
+    pub games: BTreeMap<String, toml::Value>,
+}
+
+/// Merges `overlay` into `base`, tables are merged key by key and anything else is replaced
+fn merge_values(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+impl Config {
+    /// The defaults, overridden by the settings of `text`
+    pub fn from_toml(text: &str) -> CoreResult<Config> {
+        let value = toml::from_str(text).map_err(|e| CoreError::ConfigError(e.to_string()))?;
+        let mut config = Config::default();
+        config.merge_toml(value)?;
+        Ok(config)
+    }
+
+    /// The defaults, overridden by the settings of the file at `path`
+    pub fn load(path: &Path) -> CoreResult<Config> {
+        let text = fs::read_to_string(path)?;
+        Config::from_toml(&text).with_context(|| format!("loading {}", path.display()))
+    }
+
+    pub fn to_toml(&self) -> String {
+        // every field is a table or a value that TOML can represent
+        toml::to_string_pretty(self).expect("failed to serialize the config")
+    }
+
+    pub fn save(&self, path: &Path) -> CoreResult<()> {
+        fs::write(path, self.to_toml())?;
+        Ok(())
+    }
+
+    /// Overrides the settings found in `value`, a table in the config file format. The
+    /// config is left as is when the result is invalid.
+    pub fn merge_toml(&mut self, value: toml::Value) -> CoreResult<()> {
+        let mut merged =
+            toml::Value::try_from(&*self).map_err(|e| CoreError::ConfigError(e.to_string()))?;
+        merge_values(&mut merged, value);
+        let config: Config = merged
+            .try_into()
+            .map_err(|e| CoreError::ConfigError(e.to_string()))?;
+        config.validate()?;
+        *self = config;
+        Ok(())
+    }
+
+    /// The settings with the overrides of `game_code` applied
+    pub fn for_game(&self, game_code: &str) -> CoreResult<Config> {
+        let mut config = self.clone();
+        if let Some(overrides) = self.games.get(game_code) {
+            config
+                .merge_toml(overrides.clone())
+                .with_context(|| format!("applying games.{}", game_code))?;
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> CoreResult<()> {
+        BackupType::try_from(self.emulation.save_type.as_str()).map_err(CoreError::ConfigError)?;
+        BackupRestore::try_from(self.emulation.state_save.as_str())
+            .map_err(CoreError::ConfigError)?;
+        Interpolation::try_from(self.audio.interpolation.as_str())
+            .map_err(CoreError::ConfigError)?;
+        ReloadMode::try_from(self.emulation.rom_reload.as_str()).map_err(CoreError::ConfigError)?;
+        BiosCheck::try_from(self.emulation.bios_check.as_str()).map_err(CoreError::ConfigError)?;
+        DummyLink::try_from(self.emulation.link_cable.as_str()).map_err(CoreError::ConfigError)?;
+        DpadFilter::try_from(self.input.dpad_filter.as_str()).map_err(CoreError::ConfigError)?;
+        if let Some(peripheral) = &self.emulation.peripheral {
+            PeripheralKind::try_from(peripheral.as_str()).map_err(CoreError::ConfigError)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_type(&self) -> BackupType {
+        BackupType::try_from(self.emulation.save_type.as_str()).unwrap_or(BackupType::AutoDetect)
+    }
+
+    pub fn backup_restore(&self) -> BackupRestore {
+        BackupRestore::try_from(self.emulation.state_save.as_str()).unwrap_or_default()
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        Interpolation::try_from(self.audio.interpolation.as_str()).unwrap_or_default()
+    }
+
+    pub fn bios_check(&self) -> BiosCheck {
+        BiosCheck::try_from(self.emulation.bios_check.as_str()).unwrap_or_default()
+    }
+
+    pub fn link_cable(&self) -> DummyLink {
+        DummyLink::try_from(self.emulation.link_cable.as_str()).unwrap_or_default()
+    }
+
+    pub fn dpad_filter(&self) -> DpadFilter {
+        DpadFilter::try_from(self.input.dpad_filter.as_str()).unwrap_or_default()
+    }
+
+    pub fn peripheral(&self) -> Option<PeripheralKind> {
+        let peripheral = self.emulation.peripheral.as_ref()?;
+        PeripheralKind::try_from(peripheral.as_str()).ok()
+    }
+
+    pub fn rom_reload(&self) -> ReloadMode {
+        ReloadMode::try_from(self.emulation.rom_reload.as_str()).unwrap_or(ReloadMode::Reset)
+    }
+
+    /// Names of the sections that differ between the two configs
+    pub fn changed_sections(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.paths != other.paths {
+            changed.push("paths");
+        }
+        if self.emulation != other.emulation {
+            changed.push("emulation");
+        }
+        if self.video != other.video {
+            changed.push("video");
+        }
+        if self.audio != other.audio {
+            changed.push("audio");
+        }
+        if self.input != other.input {
+            changed.push("input");
+        }
+        if self.games != other.games {
+            changed.push("games");
+        }
+        changed
+    }
+}
+
+/// Called with the new config and the names of the sections that changed
+pub type ConfigListener = Box<dyn FnMut(&Config, &[&'static str])>;
+
+/// The settings of a running frontend, with listeners to apply them as they change
+#[derive(Default)]
+pub struct ConfigStore {
+    config: Config,
+    /// Where `save` writes the config
+    path: Option<PathBuf>,
+    listeners: Vec<ConfigListener>,
+}
+
+impl ConfigStore {
+    pub fn new(config: Config) -> ConfigStore {
+        ConfigStore {
+            config,
+            path: None,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Loads the config at `path`, the defaults when there is no file yet
+    pub fn load(path: &Path) -> CoreResult<ConfigStore> {
+        let config = if path.is_file() {
+            Config::load(path)?
+        } else {
+            Config::default()
+        };
+        Ok(ConfigStore {
+            config,
+            path: Some(path.to_path_buf()),
+            listeners: Vec::new(),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn subscribe(&mut self, listener: ConfigListener) {
+        self.listeners.push(listener);
+    }
+
+    /// Changes the config, the listeners are called when anything changed. Fails without
+    /// changing anything when the result is invalid.
+    pub fn update<F: FnOnce(&mut Config)>(&mut self, f: F) -> CoreResult<()> {
+        let mut config = self.config.clone();
+        f(&mut config);
+        config.validate()?;
+        self.replace(config);
+        Ok(())
+    }
+
+    /// Same as `Config::merge_toml`, calling the listeners
+    pub fn merge_toml(&mut self, value: toml::Value) -> CoreResult<()> {
+        let mut config = self.config.clone();
+        config.merge_toml(value)?;
+        self.replace(config);
+        Ok(())
+    }
+
+    fn replace(&mut self, config: Config) {
+        let changed = self.config.changed_sections(&config);
+        self.config = config;
+        if !changed.is_empty() {
+            for listener in &mut self.listeners {
+                listener(&self.config, &changed);
+            }
+        }
+    }
+
+    /// Writes the config back to the file it was loaded from, if any
+    pub fn save(&self) -> CoreResult<()> {
+        match &self.path {
+            Some(path) => self.config.save(path),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_missing_settings_keep_their_default() {
+        let config = Config::from_toml("[audio]\ninterpolation = \"cubic\"").unwrap();
+        assert_eq!(config.interpolation(), Interpolation::Cubic);
+        assert_eq!(config.paths, PathsConfig::default());
+        assert_eq!(config.emulation, EmulationConfig::default());
+    }
+
+    #[test]
+    fn test_merge_overrides_only_given_keys() {
+        let mut config = Config::from_toml("[emulation]\nskip_bios = true\nrtc = true").unwrap();
+        config
+            .merge_toml(toml::from_str("[emulation]\nrtc = false").unwrap())
+            .unwrap();
+        assert!(config.emulation.skip_bios);
+        assert!(!config.emulation.rtc);
+    }
+
+    #[test]
+    fn test_invalid_merge_keeps_the_config() {
+        let mut config = Config::default();
+        let invalid = toml::from_str("[emulation]\nsave_type = \"floppy\"").unwrap();
+        assert!(config.merge_toml(invalid).is_err());
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_game_overrides() {
+        let text = r#"
+            [video]
+            show_fps = true
+
+            [games.BPEE.video]
+            color_correction = true
+        "#;
+        let config = Config::from_toml(text).unwrap();
+        let game = config.for_game("BPEE").unwrap();
+        assert!(game.video.color_correction);
+        assert!(game.video.show_fps);
+        assert!(!config.for_game("AXVE").unwrap().video.color_correction);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let mut config = Config::default();
+        config.paths.save_dir = Some(PathBuf::from("saves"));
+        config.video.shaders = vec!["crt".to_string()];
+        assert_eq!(Config::from_toml(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_store_notifies_changed_sections() {
+        let mut store = ConfigStore::new(Config::default());
+        let notified = Rc::new(RefCell::new(Vec::new()));
+        let sink = notified.clone();
+        store.subscribe(Box::new(move |_, changed| {
+            sink.borrow_mut().push(changed.to_vec())
+        }));
+
+        store.update(|config| config.video.show_fps = true).unwrap();
+        store.update(|config| config.video.show_fps = true).unwrap();
+        assert!(store
+            .update(|config| config.audio.interpolation = "loud".to_string())
+            .is_err());
+        assert_eq!(*notified.borrow(), vec![vec!["video"]]);
+    }
+}
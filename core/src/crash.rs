@@ -0,0 +1,84 @@
+//! Crash reports, produced when the cpu hits an unrecoverable error.
+//!
+//! Emulation of the cpu stops and the report is handed to the frontend's crash handler (see
+//! `GameBoyAdvance::set_crash_handler`) so it can be shown to the user or attached to a bug report.
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use super::arm7tdmi::{Core, CpuFault, REG_SP};
+use super::bus::{Addr, DebugRead};
+use super::iodev::consts::IO_BASE;
+use super::sysbus::SysBus;
+
+/// Bytes dumped from the top of the stack
+const STACK_DUMP_SIZE: u32 = 0x100;
+/// Size of the IO register dump, which covers every register but the internal memory control
+const IO_DUMP_SIZE: u32 = 0x400;
+
+#[cfg(not(feature = "send"))]
+pub type CrashHandler = Box<dyn FnMut(&CrashReport)>;
+#[cfg(feature = "send")]
+pub type CrashHandler = Box<dyn FnMut(&CrashReport) + Send>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExecutedInstruction {
+    pub pc: Addr,
+    pub opcode: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    pub reason: String,
+    pub pc: Addr,
+    pub opcode: u32,
+    pub game_title: String,
+    pub game_code: String,
+    /// r0-r14 of the current mode, followed by pc
+    pub registers: Vec<u32>,
+    pub cpsr: u32,
+    pub mode: String,
+    pub state: String,
+    /// Oldest first, the last one being the faulting instruction
+    pub last_instructions: Vec<ExecutedInstruction>,
+    /// Halfwords from 0x04000000, as read by the debugger
+    pub io_registers: Vec<u16>,
+    /// Bytes from the stack pointer up
+    pub stack: Vec<u8>,
+}
+
+impl CrashReport {
+    pub fn new(cpu: &Core, sysbus: &SysBus, fault: CpuFault) -> CrashReport {
+        let mut registers = cpu.gpr.to_vec();
+        registers.push(cpu.pc);
+
+        let sp = cpu.gpr[REG_SP];
+        CrashReport {
+            reason: fault.reason,
+            pc: fault.pc,
+            opcode: fault.opcode,
+            game_title: sysbus.cartridge.header.game_title.clone(),
+            game_code: sysbus.cartridge.header.game_code.clone(),
+            registers,
+            cpsr: cpu.cpsr.get(),
+            mode: format!("{:?}", cpu.cpsr.mode()),
+            state: format!("{:?}", cpu.cpsr.state()),
+            last_instructions: cpu
+                .execution_history()
+                .into_iter()
+                .map(|(pc, opcode)| ExecutedInstruction { pc, opcode })
+                .collect(),
+            io_registers: (0..IO_DUMP_SIZE)
+                .step_by(2)
+                .map(|offset| sysbus.debug_read_16(IO_BASE + offset))
+                .collect(),
+            stack: sysbus.debug_get_bytes(sp..sp.saturating_add(STACK_DUMP_SIZE)),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("crash reports are always serializable")
+    }
+}
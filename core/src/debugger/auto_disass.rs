@@ -0,0 +1,218 @@
+//! Disassembly that follows the code between ARM and Thumb.
+//!
+//! The cpu state of an address is a guess: the state the cpu is in at the current pc, and
+//! the targets of the branches seen so far. `bx` switches state when the register it jumps
+//! through was loaded from a literal or computed from pc a few instructions before, which is
+//! how compilers emit interworking veneers, and `bx pc` in Thumb always switches to ARM. The
+//! guesses are kept across `disass` commands.
+use std::collections::HashMap;
+
+use crate::arm7tdmi::arm::{ArmFormat, ArmInstruction};
+use crate::arm7tdmi::thumb::{ThumbFormat, ThumbInstruction};
+use crate::arm7tdmi::{CpuState, InstructionDecoder};
+use crate::bus::{Addr, DebugRead};
+use crate::cartridge::SymbolTable;
+
+/// The cpu state the code at an address is believed to run in
+#[derive(Debug, Default)]
+pub struct CodeStates {
+    states: HashMap<Addr, CpuState>,
+}
+
+impl CodeStates {
+    pub fn insert(&mut self, addr: Addr, state: CpuState) {
+        self.states.insert(addr, state);
+    }
+
+    pub fn get(&self, addr: Addr) -> Option<CpuState> {
+        self.states.get(&addr).copied()
+    }
+}
+
+/// What the walk knows about the registers, to resolve `bx` targets
+#[derive(Default)]
+struct Walker {
+    registers: [Option<u32>; 16],
+    /// Upper half of a Thumb `bl` target, set by the first of its two instructions
+    bl_high: Option<Addr>,
+}
+
+impl Walker {
+    /// Branch target and the state it runs in, of the ARM instruction `insn`
+    fn arm<B: DebugRead>(&mut self, bus: &B, insn: &ArmInstruction) -> Option<(Addr, CpuState)> {
+        let raw = insn.raw;
+        let pc = insn.pc.wrapping_add(8);
+        let rd = ((raw >> 12) & 0xf) as usize;
+        match insn.fmt {
+            ArmFormat::BranchLink => {
+                self.registers = Default::default();
+                let target = pc.wrapping_add(insn.branch_offset() as Addr);
+                Some((target, CpuState::ARM))
+            }
+            ArmFormat::BranchExchange => {
+                let target = self.registers[(raw & 0xf) as usize]?;
+                self.registers = Default::default();
+                Some(interworking_target(target))
+            }
+            _ => {
+                if raw & 0x0fff_0000 == 0x028f_0000 {
+                    // add rd, pc, #imm
+                    let imm = (raw & 0xff).rotate_right(((raw >> 8) & 0xf) * 2);
+                    self.registers[rd] = Some(pc.wrapping_add(imm));
+                } else if raw & 0x0f7f_0000 == 0x051f_0000 {
+                    // ldr rd, [pc, #imm]
+                    let offset = raw & 0xfff;
+                    let literal = if raw & (1 << 23) != 0 {
+                        pc.wrapping_add(offset)
+                    } else {
+                        pc.wrapping_sub(offset)
+                    };
+                    self.registers[rd] = Some(bus.debug_read_32(literal));
+                }
+                None
+            }
+        }
+    }
+
+    /// Branch target and the state it runs in, of the Thumb instruction `insn`
+    fn thumb<B: DebugRead>(
+        &mut self,
+        bus: &B,
+        insn: &ThumbInstruction,
+    ) -> Option<(Addr, CpuState)> {
+        let raw = insn.raw;
+        let pc = insn.pc.wrapping_add(4);
+        match insn.fmt {
+            ThumbFormat::HiRegOpOrBranchExchange if raw & 0xff80 == 0x4700 => {
+                let rs = ((raw >> 3) & 0xf) as usize;
+                let target = if rs == 15 {
+                    Some(pc & !3)
+                } else {
+                    self.registers[rs]
+                };
+                self.registers = Default::default();
+                target.map(interworking_target)
+            }
+            ThumbFormat::LdrPc => {
+                let rd = ((raw >> 8) & 0x7) as usize;
+                let literal = (pc & !2).wrapping_add(insn.word8() as Addr);
+                self.registers[rd] = Some(bus.debug_read_32(literal));
+                None
+            }
+            ThumbFormat::BranchLongWithLink => {
+                let offset11 = insn.offset11();
+                if raw & (1 << 11) == 0 {
+                    self.bl_high = Some(pc.wrapping_add(((offset11 << 21) >> 9) as Addr));
+                    None
+                } else {
+                    self.registers = Default::default();
+                    let high = self.bl_high.take()?;
+                    Some((high.wrapping_add((offset11 << 1) as Addr), CpuState::THUMB))
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Bit 0 of a `bx` target selects Thumb
+fn interworking_target(target: u32) -> (Addr, CpuState) {
+    if target & 1 != 0 {
+        (target & !1, CpuState::THUMB)
+    } else {
+        (target & !3, CpuState::ARM)
+    }
+}
+
+/// Disassembles `count` instructions from `addr`, in `state` unless `states` knows better.
+/// Symbols label the lines they point to and the branches to them.
+pub fn disassemble<B: DebugRead>(
+    bus: &B,
+    mut addr: Addr,
+    count: usize,
+    mut state: CpuState,
+    states: &mut CodeStates,
+    symbols: Option<&SymbolTable>,
+) -> Vec<String> {
+    let names: HashMap<Addr, &str> = symbols
+        .iter()
+        .flat_map(|symbols| symbols.iter())
+        .map(|(name, addr)| (*addr, name.as_str()))
+        .collect();
+    let mut walker = Walker::default();
+    let mut lines = Vec::new();
+
+    for _ in 0..count {
+        if let Some(known) = states.get(addr) {
+            state = known;
+        }
+        if let Some(name) = names.get(&addr) {
+            lines.push(format!("{}:", name));
+        }
+        let (mut line, branch, size) = match state {
+            CpuState::ARM => {
+                let insn = ArmInstruction::decode(bus.debug_read_32(addr), addr);
+                let branch = walker.arm(bus, &insn);
+                (
+                    format!("{:8x}:\t{:08x} \t{}", addr, insn.raw, insn),
+                    branch,
+                    4,
+                )
+            }
+            CpuState::THUMB => {
+                let insn = ThumbInstruction::decode(bus.debug_read_16(addr), addr);
+                let branch = walker.thumb(bus, &insn);
+                (
+                    format!("{:8x}:\t{:04x}     \t{}", addr, insn.raw, insn),
+                    branch,
+                    2,
+                )
+            }
+        };
+        if let Some((target, target_state)) = branch {
+            states.insert(target, target_state);
+            if let Some(name) = names.get(&target) {
+                line.push_str(&format!("\t; {}", name));
+            }
+        }
+        lines.push(line);
+        addr = addr.wrapping_add(size);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::make_debugger;
+    use super::*;
+    use crate::bus::Bus;
+
+    #[test]
+    fn test_follows_interworking_branches() {
+        let mut debugger = make_debugger(&[0; 0x200]);
+        let bus = &mut debugger.gba.sysbus;
+        let code: Addr = 0x0200_0000;
+        // add r0, pc, #1; bx r0
+        bus.write_32(code, 0xe28f_0001);
+        bus.write_32(code + 4, 0xe12f_ff10);
+        // movs r1, #1; nop; bx pc; nop
+        bus.write_16(code + 8, 0x2101);
+        bus.write_16(code + 10, 0x46c0);
+        bus.write_16(code + 12, 0x4778);
+        bus.write_16(code + 14, 0x46c0);
+        // bl target (ARM)
+        bus.write_32(code + 16, 0xeb00_0000);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("target".to_string(), code + 24);
+        let mut states = CodeStates::default();
+        let lines = disassemble(&**bus, code, 7, CpuState::ARM, &mut states, Some(&symbols));
+
+        assert_eq!(states.get(code + 8), Some(CpuState::THUMB));
+        assert_eq!(states.get(code + 16), Some(CpuState::ARM));
+        assert!(lines[2].contains("mov"));
+        assert!(lines[4].contains("bx\tpc"));
+        assert!(lines[6].ends_with("; target"));
+    }
+}
@@ -0,0 +1,189 @@
+//! Catchpoints, breaking on exceptions and interrupt requests instead of addresses.
+//!
+//! ```text
+//! catch swi          software interrupts, the BIOS calls
+//! catch irq          the cpu entering the interrupt handler
+//! catch undefined    undefined instructions, and the others that crash the emulated cpu
+//! catch vblank       an interrupt source setting its bit in IF, whether IE enables it or not
+//! uncatch [what]     removes one, or all of them
+//! ```
+//! The GBA has no memory protection, so there is no abort to catch.
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::arm7tdmi::Exception;
+use crate::bus::Addr;
+use crate::interrupt::Interrupt;
+
+use super::Debugger;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Catchpoint {
+    Swi,
+    Irq,
+    Undefined,
+    /// An interrupt source requesting an interrupt
+    Source(Interrupt),
+}
+
+impl TryFrom<&str> for Catchpoint {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, String> {
+        match s {
+            "swi" => Ok(Catchpoint::Swi),
+            "irq" => Ok(Catchpoint::Irq),
+            "undefined" | "undef" => Ok(Catchpoint::Undefined),
+            _ => Interrupt::ALL
+                .iter()
+                .find(|source| source.name() == s)
+                .map(|source| Catchpoint::Source(*source))
+                .ok_or_else(|| {
+                    let sources: Vec<&str> = Interrupt::ALL.iter().map(|i| i.name()).collect();
+                    format!(
+                        "{} can't be caught, try swi, irq, undefined or {}",
+                        s,
+                        sources.join(", ")
+                    )
+                }),
+        }
+    }
+}
+
+impl fmt::Display for Catchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Catchpoint::Swi => write!(f, "swi"),
+            Catchpoint::Irq => write!(f, "irq"),
+            Catchpoint::Undefined => write!(f, "undefined"),
+            Catchpoint::Source(source) => write!(f, "{}", source.name()),
+        }
+    }
+}
+
+/// The cpu and the interrupt controller when a catchpoint stopped the emulation
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatchContext {
+    pub catchpoint: Catchpoint,
+    /// The instruction that raised the exception, or the one the irq interrupted
+    pub pc: Addr,
+    /// Where the exception returns to, `None` for an interrupt request
+    pub return_addr: Option<Addr>,
+    /// Before the exception
+    pub cpsr: u32,
+    /// The BIOS function of a swi
+    pub swi: Option<u32>,
+    pub interrupt_enable: u16,
+    pub interrupt_flags: u16,
+    pub interrupt_master_enable: bool,
+}
+
+impl fmt::Display for CatchContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "caught {} @{:x}", self.catchpoint, self.pc)?;
+        if let Some(swi) = self.swi {
+            write!(f, " (function {:#04x})", swi)?;
+        }
+        if let Some(return_addr) = self.return_addr {
+            write!(f, ", returns to {:x}", return_addr)?;
+        }
+        write!(
+            f,
+            ", cpsr={:#010x} IME={} IE={:#06x} IF={:#06x}",
+            self.cpsr, self.interrupt_master_enable, self.interrupt_enable, self.interrupt_flags
+        )
+    }
+}
+
+/// What an instruction is checked against after it ran
+pub(super) struct StepOrigin {
+    pc: Addr,
+    cpsr: u32,
+    interrupt_flags: u16,
+    crashed: bool,
+}
+
+#[derive(Default)]
+pub struct Catchpoints(Vec<Catchpoint>);
+
+impl Catchpoints {
+    /// False if it was already there
+    pub fn add(&mut self, catchpoint: Catchpoint) -> bool {
+        if self.0.contains(&catchpoint) {
+            return false;
+        }
+        self.0.push(catchpoint);
+        true
+    }
+
+    /// False if it wasn't there
+    pub fn remove(&mut self, catchpoint: Catchpoint) -> bool {
+        let len = self.0.len();
+        self.0.retain(|c| *c != catchpoint);
+        self.0.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Catchpoint> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Debugger {
+    /// To be called before stepping, `None` when there is nothing to catch
+    pub(super) fn catch_origin(&mut self) -> Option<StepOrigin> {
+        if self.catchpoints.is_empty() {
+            return None;
+        }
+        self.gba.cpu.last_exception = None;
+        Some(StepOrigin {
+            pc: self.gba.cpu.get_next_pc(),
+            cpsr: self.gba.cpu.cpsr.get(),
+            interrupt_flags: self.gba.sysbus.io.intc.interrupt_flags.get().value(),
+            crashed: self.gba.crash_report().is_some(),
+        })
+    }
+
+    /// The first catchpoint the step from `origin` hit
+    pub(super) fn check_catchpoints(&self, origin: Option<StepOrigin>) -> Option<CatchContext> {
+        let origin = origin?;
+        let intc = &self.gba.sysbus.io.intc;
+        let interrupt_flags = intc.interrupt_flags.get().value();
+        let requested = interrupt_flags & !origin.interrupt_flags;
+        let exception = self.gba.cpu.last_exception;
+        let crashed = !origin.crashed && self.gba.crash_report().is_some();
+        let caught = self
+            .catchpoints
+            .iter()
+            .find(|catchpoint| match catchpoint {
+                Catchpoint::Swi => matches!(exception, Some((Exception::SoftwareInterrupt, _))),
+                Catchpoint::Irq => matches!(exception, Some((Exception::Irq, _))),
+                Catchpoint::Undefined => crashed,
+                Catchpoint::Source(source) => requested & 1 << *source as u16 != 0,
+            })?;
+        let return_addr = match caught {
+            Catchpoint::Swi | Catchpoint::Irq => exception.map(|(_, lr)| lr),
+            _ => None,
+        };
+        Some(CatchContext {
+            catchpoint: *caught,
+            pc: origin.pc,
+            return_addr,
+            cpsr: origin.cpsr,
+            swi: match caught {
+                Catchpoint::Swi => self.gba.cpu.last_swi.map(|(_, number)| number),
+                _ => None,
+            },
+            interrupt_enable: intc.interrupt_enable.0,
+            interrupt_flags,
+            interrupt_master_enable: intc.interrupt_master_enable,
+        })
+    }
+}
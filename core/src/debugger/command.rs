@@ -1,16 +1,27 @@
+use std::convert::TryFrom;
 use std::path::Path;
 use std::time;
 
 use crate::arm7tdmi::arm::ArmInstruction;
 use crate::arm7tdmi::thumb::ThumbInstruction;
-use crate::arm7tdmi::CpuState;
-use crate::bus::{Addr, Bus, DebugRead};
+use crate::arm7tdmi::CpuMode;
+use crate::bus::{Addr, DebugRead};
+use crate::cartridge::load_symbol_file;
+use crate::cheats::{CheatKind, RamSearch, SearchFilter};
 use crate::disass::Disassembler;
+use crate::logging::{self, Subsystem};
+use crate::ram_diff::RamSnapshot;
+use crate::sysbus::consts::IWRAM_ADDR;
 use crate::util::{read_bin_file, write_bin_file};
 
 // use super::palette_view::create_palette_view;
 // use super::tile_view::create_tile_view;
-use super::{parser::Value, Debugger, DebuggerError, DebuggerResult};
+use super::auto_disass;
+use super::reverse::StepKind;
+use super::stack::{stack_top, Symbolizer};
+use super::{
+    parser::Value, Catchpoint, Debugger, DebuggerError, DebuggerResult, StepBack, StopReason,
+};
 
 use ansi_term::Colour;
 
@@ -19,10 +30,23 @@ use fuzzy_matcher::FuzzyMatcher;
 
 use hexdump;
 
+/// Changes `diff` prints before cutting the list short
+const MAX_DIFF_LINES: usize = 64;
+/// Search results `search` prints before cutting the list short
+const MAX_SEARCH_LINES: usize = 16;
+/// Log records `log` prints by default
+const DEFAULT_LOG_LINES: usize = 20;
+/// Words `stack` looks through by default
+const DEFAULT_STACK_WORDS: u32 = 64;
+/// Blocks `heap` prints of every heap before cutting the list short
+const MAX_HEAP_LINES: usize = 32;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum DisassMode {
     ModeArm,
     ModeThumb,
+    /// Follows the code between ARM and Thumb, see `auto_disass`
+    ModeAuto,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -48,10 +72,14 @@ pub enum Command {
     GpuInfo,
     GpioInfo,
     Step(usize),
-    Continue,
+    Continue(Option<Addr>),
     Frame(usize),
+    Watchdog(Option<u64>),
     HexDump(Addr, u32),
     MemWrite(MemWriteCommandSize, Addr, u32),
+    MemFill(Addr, u32, u8),
+    MemCopy(Addr, Addr, u32),
+    Undo,
     Disass(DisassMode, Addr, u32),
     AddBreakpoint(Addr),
     DelBreakpoint(Addr),
@@ -59,17 +87,76 @@ pub enum Command {
     // TileView(u32),
     ClearBreakpoints,
     ListBreakpoints,
+    AddCatchpoint(Catchpoint),
+    /// Removes a catchpoint, or all of them when `None`
+    DelCatchpoint(Option<Catchpoint>),
+    ListCatchpoints,
     Reset,
     Quit,
     TraceToggle(TraceFlags),
     SaveState(String),
     LoadState(String),
+    Screenshot(String, usize),
+    #[cfg(feature = "lua")]
+    Script(String),
+    Source(String),
     ListSymbols(Option<String>),
+    LoadSymbols(String),
+    AddSymbol(String, Addr),
+    RemoveSymbol(String),
+    ListWatches,
+    RemoveWatch(usize),
+    ListSnapshots,
+    Snapshot(String),
+    /// Diffs a snapshot with another one, or with the RAM when `None`
+    Diff(String, Option<String>),
+    /// Prints the last log records
+    ShowLog(usize),
+    ListLogLevels,
+    SetLogLevel(Subsystem, log::LevelFilter),
+    /// Starts a memory search for a variable of that many bytes
+    StartSearch(u8),
+    FilterSearch(SearchFilter),
+    /// Makes a cheat of the address the search is down to
+    AddCheat(String, CheatKind),
+    ListCheats,
+    RemoveCheat(usize),
+    SaveCheats(String),
+    LoadCheats(String),
+    /// Adds the cheats of another emulator's cheat list
+    ImportCheats(String),
+    StepBack(usize),
+    /// Shows the stacks, and the frames in that many words of the current one
+    Stack(u32),
+    Heap,
+}
+
+impl Command {
+    /// Whether it changes the machine other than by running it, see `reverse`
+    fn edits_machine(&self) -> bool {
+        use Command::*;
+        matches!(
+            self,
+            MemWrite(..)
+                | MemFill(..)
+                | MemCopy(..)
+                | Undo
+                | Reset
+                | LoadState(_)
+                | AddCheat(..)
+                | RemoveCheat(_)
+                | LoadCheats(_)
+                | ImportCheats(_)
+        )
+    }
 }
 
 impl Debugger {
     pub fn run_command(&mut self, command: Command) {
         use Command::*;
+        if command.edits_machine() {
+            self.history.seal();
+        }
         #[allow(unreachable_patterns)]
         match command {
             Info => {
@@ -82,34 +169,34 @@ impl Debugger {
             GpioInfo => println!("GPIO: {:#?}", self.gba.sysbus.cartridge.get_gpio()),
             Step(count) => {
                 for _ in 0..count {
-                    self.gba.cpu.step(&mut self.gba.sysbus);
-                    while self.gba.cpu.last_executed.is_none() {
-                        self.gba.cpu.step(&mut self.gba.sysbus);
-                    }
-                    if let Some(last_executed) = &self.gba.cpu.last_executed {
-                        print!(
-                            "{}\t{}",
-                            Colour::Black
-                                .bold()
-                                .italic()
-                                .on(Colour::White)
-                                .paint(format!("Executed at @0x{:08x}:", last_executed.get_pc(),)),
-                            last_executed
-                        );
-                        println!(
-                            "{}",
-                            Colour::Purple.dimmed().italic().paint(format!(
-                                "\t\t/// Next instruction at @0x{:08x}",
-                                self.gba.cpu.get_next_pc()
-                            ))
-                        );
+                    self.record_step(StepKind::Cpu);
+                    let step = self.gba.cpu.step_instruction(&mut self.gba.sysbus);
+                    print!(
+                        "{}\t{}",
+                        Colour::Black
+                            .bold()
+                            .italic()
+                            .on(Colour::White)
+                            .paint(format!("Executed at @0x{:08x}:", step.pc)),
+                        step.instruction
+                    );
+                    println!(
+                        "{}",
+                        Colour::Purple.dimmed().italic().paint(format!(
+                            "\t\t/// {} cycles, next instruction at @0x{:08x}",
+                            step.cycles,
+                            self.gba.cpu.get_next_pc()
+                        ))
+                    );
+                    if let Some((exception, _)) = step.exception {
+                        println!("{:?} exception", exception);
                     }
+                    self.update_watches();
                 }
                 println!("{}\n", self.gba.cpu);
             }
-            Continue => 'running: loop {
-                self.gba.key_poll();
-                if let Some(breakpoint) = self.gba.step_debugger() {
+            Continue(until) => match self.run_until(until) {
+                StopReason::Breakpoint(breakpoint) => {
                     let mut bp_sym = None;
                     if let Some(symbols) = self.gba.sysbus.cartridge.get_symbols() {
                         for s in symbols.keys() {
@@ -123,25 +210,124 @@ impl Debugger {
                     } else {
                         println!("Breakpoint reached! @{:x}", breakpoint);
                     }
-                    break 'running;
                 }
+                StopReason::Caught(context) => println!("{}", context),
+                StopReason::Reached(addr) => println!("Reached @{:x}", addr),
+                StopReason::Timeout { pc, instructions } => println!(
+                    "{} after {} instructions, stopped @{:x}",
+                    Colour::Yellow.paint("Watchdog timeout"),
+                    instructions,
+                    pc
+                ),
             },
             Frame(count) => {
                 let start = time::Instant::now();
                 for _ in 0..count {
-                    self.gba.frame();
+                    self.record_frame();
+                    self.frame();
+                    self.update_watches();
                 }
                 let end = time::Instant::now();
                 println!("that took {:?} seconds", end - start);
             }
+            StepBack(count) => {
+                match self.step_back(count as u64) {
+                    Some(StepBack::Done) => {}
+                    Some(StepBack::FrameStart(count)) => println!(
+                        "back {} instructions, then to the start of the frame",
+                        count
+                    ),
+                    Some(StepBack::HistoryStart(count)) => println!(
+                        "{} after {} instructions",
+                        Colour::Yellow.paint("Reached the start of the history"),
+                        count
+                    ),
+                    None => println!("no history to step back through"),
+                }
+                self.update_watches();
+                println!("{}\n", self.gba.cpu);
+            }
+            Stack(words) => {
+                let mode = self.gba.cpu.cpsr.mode();
+                for stack_mode in CpuMode::BANKS.iter() {
+                    let (sp, lr) = self.gba.cpu.get_banked_sp_lr(*stack_mode);
+                    print!(
+                        "{} {} sp={:08x} lr={:08x}",
+                        if stack_mode.bank_index() == mode.bank_index() {
+                            "*"
+                        } else {
+                            " "
+                        },
+                        stack_mode,
+                        sp,
+                        lr
+                    );
+                    match stack_top(*stack_mode).checked_sub(sp) {
+                        Some(used) if sp >= IWRAM_ADDR => println!(" ({} bytes used)", used),
+                        _ => println!(),
+                    }
+                }
+                let symbolizer = Symbolizer::new(self.gba.sysbus.cartridge.get_symbols().as_ref());
+                let (_, lr) = self.gba.cpu.get_banked_sp_lr(mode);
+                for (name, addr) in [("pc", self.gba.cpu.get_next_pc()), ("lr", lr)].iter() {
+                    println!(
+                        "{}         {:08x} {}",
+                        name,
+                        addr,
+                        symbolizer.name(*addr).unwrap_or_default()
+                    );
+                }
+                for frame in self.stack_frames(words) {
+                    println!("{}", frame);
+                }
+            }
+            Heap => {
+                let heaps = self.heaps();
+                if heaps.is_empty() {
+                    println!("no heap found");
+                }
+                for heap in heaps {
+                    println!("{}", heap);
+                    for block in heap.blocks.iter().take(MAX_HEAP_LINES) {
+                        println!("  {}", block);
+                    }
+                    if heap.blocks.len() > MAX_HEAP_LINES {
+                        println!("  ... {} more", heap.blocks.len() - MAX_HEAP_LINES);
+                    }
+                }
+            }
+            Watchdog(budget) => {
+                self.watchdog = budget;
+                match budget {
+                    Some(budget) => println!("[*] watchdog set to {} instructions", budget),
+                    None => println!("[*] watchdog off"),
+                }
+            }
             HexDump(addr, nbytes) => {
                 let bytes = self.gba.sysbus.debug_get_bytes(addr..addr + nbytes);
                 hexdump::hexdump(&bytes);
             }
-            MemWrite(size, addr, val) => match size {
-                MemWriteCommandSize::Byte => self.gba.sysbus.write_8(addr, val as u8),
-                MemWriteCommandSize::Half => self.gba.sysbus.write_16(addr, val as u16),
-                MemWriteCommandSize::Word => self.gba.sysbus.write_32(addr, val as u32),
+            MemWrite(size, addr, val) => {
+                let bytes = val.to_le_bytes();
+                let len = match size {
+                    MemWriteCommandSize::Byte => 1,
+                    MemWriteCommandSize::Half => 2,
+                    MemWriteCommandSize::Word => 4,
+                };
+                self.edit_memory(addr, &bytes[..len]);
+            }
+            MemFill(addr, n, val) => self.edit_memory(addr, &vec![val; n as usize]),
+            MemCopy(src, dst, n) => {
+                let bytes = self.gba.sysbus.debug_get_bytes(src..src + n);
+                self.edit_memory(dst, &bytes);
+            }
+            Undo => match self.undo_edit() {
+                Some(edit) => println!(
+                    "restored {} bytes @0x{:08x}",
+                    edit.old_bytes.len(),
+                    edit.addr
+                ),
+                None => println!("nothing to undo"),
             },
             Disass(mode, addr, n) => {
                 let bytes = self.gba.sysbus.debug_get_bytes(addr..addr + n);
@@ -158,6 +344,21 @@ impl Debugger {
                             println!("{}", line)
                         }
                     }
+                    DisassMode::ModeAuto => {
+                        let state = self.gba.cpu.get_cpu_state();
+                        self.code_states.insert(self.gba.cpu.get_next_pc(), state);
+                        let lines = auto_disass::disassemble(
+                            &*self.gba.sysbus,
+                            addr,
+                            n as usize,
+                            state,
+                            &mut self.code_states,
+                            self.gba.sysbus.cartridge.get_symbols().as_ref(),
+                        );
+                        for line in lines {
+                            println!("{}", line)
+                        }
+                    }
                 };
             }
             Quit => {
@@ -176,6 +377,25 @@ impl Debugger {
                     println!("[{}] 0x{:08x}", i, b)
                 }
             }
+            AddCatchpoint(catchpoint) => {
+                if self.catchpoints.add(catchpoint) {
+                    println!("Catching {}", catchpoint);
+                } else {
+                    println!("Already catching {}.", catchpoint);
+                }
+            }
+            DelCatchpoint(Some(catchpoint)) => {
+                if !self.catchpoints.remove(catchpoint) {
+                    println!("Not catching {}.", catchpoint);
+                }
+            }
+            DelCatchpoint(None) => self.catchpoints.clear(),
+            ListCatchpoints => {
+                println!("catchpoint list:");
+                for catchpoint in self.catchpoints.iter() {
+                    println!("{}", catchpoint)
+                }
+            }
             // PaletteView => create_palette_view(&self.gba.sysbus.palette_ram.mem),
             // TileView(bg) => create_tile_view(bg, &self.gba),
             Reset => {
@@ -221,10 +441,36 @@ impl Debugger {
             LoadState(load_path) => {
                 let save = read_bin_file(&Path::new(&load_path))
                     .expect("failed to read save state from file");
-                self.gba
-                    .restore_state(&save)
-                    .expect("failed to deserialize");
+                if let Err(e) = self.gba.restore_state(&save) {
+                    println!("failed to load {}: {}", load_path, e);
+                }
+            }
+            Screenshot(path, scale) => {
+                let mut path = Path::new(&path).to_path_buf();
+                if path.extension().is_none() {
+                    path.set_extension("png");
+                }
+                match self.gba.screenshot().save_png(&path, scale) {
+                    Ok(_) => println!("screenshot saved to {}", path.display()),
+                    Err(e) => println!("failed to save screenshot: {}", e),
+                }
+            }
+            #[cfg(feature = "lua")]
+            Script(path) => {
+                let mut path = Path::new(&path).to_path_buf();
+                if path.extension().is_none() {
+                    path.set_extension("lua");
+                }
+                match self.load_script(&path) {
+                    Ok(_) => println!("loaded script {}", path.display()),
+                    Err(e) => println!("failed to load script: {}", e),
+                }
             }
+            Source(path) => match self.run_script(Path::new(&path)) {
+                Err(DebuggerError::ParsingError(msg)) => println!("Parsing error: {}", msg),
+                Err(e) => println!("failed to run {}: {:?}", path, e),
+                Ok(_) => {}
+            },
             ListSymbols(Some(pattern)) => {
                 if let Some(symbols) = self.gba.sysbus.cartridge.get_symbols() {
                     let matcher = SkimMatcherV2::default();
@@ -247,6 +493,137 @@ impl Debugger {
                     println!("symbols not loaded!");
                 }
             }
+            LoadSymbols(path) => match load_symbol_file(Path::new(&path)) {
+                Ok(table) => {
+                    println!("loaded {} symbols from {}", table.len(), path);
+                    self.gba.sysbus.cartridge.merge_symbols(table);
+                }
+                Err(e) => println!("failed to load symbols: {}", e),
+            },
+            AddSymbol(name, addr) => {
+                if let Some(old) = self.gba.sysbus.cartridge.add_symbol(&name, addr) {
+                    println!("{} moved from 0x{:08x}", name, old);
+                }
+            }
+            RemoveSymbol(name) => {
+                if self.gba.sysbus.cartridge.remove_symbol(&name).is_none() {
+                    println!("no symbol {}", name);
+                }
+            }
+            ListWatches => {
+                for watch in self.watches() {
+                    match watch.value {
+                        Some(value) => println!("#{} {} = {:#x}", watch.id, watch.expr, value),
+                        None => println!("#{} {} = ?", watch.id, watch.expr),
+                    }
+                }
+            }
+            RemoveWatch(id) => {
+                if !self.remove_watch(id) {
+                    println!("no watch #{}", id);
+                }
+            }
+            ListSnapshots => {
+                for snapshot in &self.snapshots {
+                    println!("{}", snapshot.label);
+                }
+            }
+            Snapshot(label) => {
+                self.take_snapshot(&label);
+                println!("[*] snapshot {} taken", label);
+            }
+            Diff(old, new) => {
+                let current;
+                let new = match &new {
+                    Some(label) => self.snapshot(label),
+                    None => {
+                        current = RamSnapshot::capture(&mut self.gba, "ram");
+                        Some(&current)
+                    }
+                };
+                match (self.snapshot(&old), new) {
+                    (Some(old), Some(new)) => {
+                        let changes = old.diff(new);
+                        for change in changes.iter().take(MAX_DIFF_LINES) {
+                            println!("{}", change);
+                        }
+                        if changes.len() > MAX_DIFF_LINES {
+                            println!("... {} more", changes.len() - MAX_DIFF_LINES);
+                        }
+                        println!(
+                            "{} changes from {} to {}",
+                            changes.len(),
+                            old.label,
+                            new.label
+                        );
+                    }
+                    _ => println!("no such snapshot, see snap"),
+                }
+            }
+            ShowLog(count) => {
+                for record in logging::recent(count) {
+                    println!("{}", record);
+                }
+            }
+            ListLogLevels => {
+                for subsystem in Subsystem::ALL.iter() {
+                    println!("{:<5} {}", subsystem, logging::level(*subsystem));
+                }
+                println!("other {}", logging::default_level());
+            }
+            SetLogLevel(subsystem, level) => logging::set_level(subsystem, level),
+            StartSearch(width) => match RamSearch::new(&mut self.gba, width) {
+                Ok(search) => {
+                    println!("{} candidates", search.results().len());
+                    self.search = Some(search);
+                }
+                Err(e) => println!("{}", e),
+            },
+            FilterSearch(filter) => match &mut self.search {
+                Some(search) => {
+                    let count = search.filter(&mut self.gba, filter);
+                    for result in search.results().iter().take(MAX_SEARCH_LINES) {
+                        println!("0x{:08x} = {:#x}", result.addr, result.value);
+                    }
+                    println!("{} candidates", count);
+                }
+                None => println!("no search, see search new"),
+            },
+            AddCheat(name, kind) => match self.search.as_ref().and_then(RamSearch::single) {
+                Some(result) => match self.gba.cheats_mut().add(result.cheat(&name, kind)) {
+                    Ok(index) => println!("[*] cheat #{} added", index),
+                    Err(e) => println!("{}", e),
+                },
+                None => println!("the search isn't down to one address"),
+            },
+            ListCheats => {
+                for (index, cheat) in self.gba.cheats().cheats().iter().enumerate() {
+                    println!("#{} {}", index, cheat);
+                }
+            }
+            RemoveCheat(index) => {
+                if self.gba.cheats_mut().remove(index).is_none() {
+                    println!("no cheat #{}", index);
+                }
+            }
+            SaveCheats(path) => {
+                if let Err(e) = self.gba.save_cheats(Path::new(&path)) {
+                    println!("{}", e);
+                }
+            }
+            LoadCheats(path) => match self.gba.load_cheats(Path::new(&path)) {
+                Ok(()) => println!("{} cheats loaded", self.gba.cheats().cheats().len()),
+                Err(e) => println!("{}", e),
+            },
+            ImportCheats(path) => match self.gba.import_cheats(Path::new(&path)) {
+                Ok(report) => {
+                    for skipped in &report.skipped {
+                        println!("skipped {}", skipped);
+                    }
+                    println!("{} cheats imported", report.cheats.len());
+                }
+                Err(e) => println!("{}", e),
+            },
             _ => println!("Not Implemented",),
         }
     }
@@ -303,7 +680,44 @@ impl Debugger {
                 };
                 Ok(Command::Step(count as usize))
             }
-            "c" | "continue" => Ok(Command::Continue),
+            "sb" | "step-back" => {
+                let count = match args.len() {
+                    0 => 1,
+                    1 => self.val_number(&args[0])?,
+                    _ => {
+                        return Err(DebuggerError::InvalidCommandFormat(
+                            "step-back [count]".to_string(),
+                        ))
+                    }
+                };
+                Ok(Command::StepBack(count as usize))
+            }
+            "stack" | "bt" => match args.get(0) {
+                None => Ok(Command::Stack(DEFAULT_STACK_WORDS)),
+                Some(Value::Num(words)) if args.len() == 1 => Ok(Command::Stack(*words)),
+                _ => Err(DebuggerError::InvalidCommandFormat(
+                    "stack [words]".to_string(),
+                )),
+            },
+            "heap" => Ok(Command::Heap),
+            "c" | "continue" => match args.len() {
+                0 => Ok(Command::Continue(None)),
+                1 => Ok(Command::Continue(Some(self.val_address(&args[0])?))),
+                _ => Err(DebuggerError::InvalidCommandFormat(
+                    "continue [until-addr]".to_string(),
+                )),
+            },
+            "watchdog" => match args.get(0) {
+                Some(Value::Num(budget)) if args.len() == 1 => {
+                    Ok(Command::Watchdog(Some(*budget as u64)))
+                }
+                Some(Value::Identifier(off)) if args.len() == 1 && off == "off" => {
+                    Ok(Command::Watchdog(None))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(
+                    "watchdog <instructions|off>".to_string(),
+                )),
+            },
             "f" | "frame" => {
                 let count = match args.len() {
                     0 => 1,
@@ -344,7 +758,7 @@ impl Debugger {
                 };
                 Ok(Command::HexDump(addr, n))
             }
-            "mwb" => {
+            "mwb" | "poke8" => {
                 let (addr, val) = match args.len() {
                     2 => {
                         let addr = self.val_address(&args[0])?;
@@ -364,7 +778,7 @@ impl Debugger {
                     val as u32,
                 ))
             }
-            "mwh" => {
+            "mwh" | "poke16" => {
                 let (addr, val) = match args.len() {
                     2 => {
                         let addr = self.val_address(&args[0])?;
@@ -374,7 +788,7 @@ impl Debugger {
                     }
                     _ => {
                         return Err(DebuggerError::InvalidCommandFormat(
-                            "mwb [addr] [n]".to_string(),
+                            "mwh [addr] [n]".to_string(),
                         ))
                     }
                 };
@@ -384,7 +798,7 @@ impl Debugger {
                     val as u32,
                 ))
             }
-            "mww" => {
+            "mww" | "poke32" => {
                 let (addr, val) = match args.len() {
                     2 => {
                         let addr = self.val_address(&args[0])?;
@@ -394,24 +808,41 @@ impl Debugger {
                     }
                     _ => {
                         return Err(DebuggerError::InvalidCommandFormat(
-                            "mwb [addr] [n]".to_string(),
+                            "mww [addr] [n]".to_string(),
                         ))
                     }
                 };
                 Ok(Command::MemWrite(
-                    MemWriteCommandSize::Half,
+                    MemWriteCommandSize::Word,
                     addr,
                     val as u32,
                 ))
             }
+            "fill" => match args.len() {
+                3 => Ok(Command::MemFill(
+                    self.val_address(&args[0])?,
+                    self.val_number(&args[1])?,
+                    self.val_number(&args[2])? as u8,
+                )),
+                _ => Err(DebuggerError::InvalidCommandFormat(
+                    "fill <addr> <n> <byte>".to_string(),
+                )),
+            },
+            "copy" => match args.len() {
+                3 => Ok(Command::MemCopy(
+                    self.val_address(&args[0])?,
+                    self.val_address(&args[1])?,
+                    self.val_number(&args[2])?,
+                )),
+                _ => Err(DebuggerError::InvalidCommandFormat(
+                    "copy <src> <dst> <n>".to_string(),
+                )),
+            },
+            "undo" => Ok(Command::Undo),
             "d" | "disass" => {
                 let (addr, n) = self.get_disassembler_args(args)?;
 
-                let m = match self.gba.cpu.get_cpu_state() {
-                    CpuState::ARM => DisassMode::ModeArm,
-                    CpuState::THUMB => DisassMode::ModeThumb,
-                };
-                Ok(Command::Disass(m, addr, n))
+                Ok(Command::Disass(DisassMode::ModeAuto, addr, n))
             }
             "da" | "disass-arm" => {
                 let (addr, n) = self.get_disassembler_args(args)?;
@@ -452,6 +883,26 @@ impl Debugger {
             //     Ok(Command::TileView(bg))
             // }
             "bl" => Ok(Command::ListBreakpoints),
+            "catch" | "uncatch" => {
+                let catchpoint = match args.get(0) {
+                    Some(Value::Identifier(name)) if args.len() == 1 => Some(
+                        Catchpoint::try_from(name.as_str())
+                            .map_err(DebuggerError::InvalidArgument)?,
+                    ),
+                    None => None,
+                    _ => {
+                        return Err(DebuggerError::InvalidCommandFormat(format!(
+                            "{} [swi|irq|undefined|<interrupt source>]",
+                            command
+                        )))
+                    }
+                };
+                Ok(match (command.as_ref(), catchpoint) {
+                    ("catch", Some(catchpoint)) => Command::AddCatchpoint(catchpoint),
+                    ("catch", None) => Command::ListCatchpoints,
+                    (_, catchpoint) => Command::DelCatchpoint(catchpoint),
+                })
+            }
             "q" | "quit" => Ok(Command::Quit),
             "r" | "reset" => Ok(Command::Reset),
             "trace" => {
@@ -493,6 +944,38 @@ impl Debugger {
                     }
                 }
             }
+            "screenshot" | "shot" => {
+                let usage =
+                    DebuggerError::InvalidCommandFormat(String::from("screenshot <path> [scale]"));
+                let scale = match args.get(1) {
+                    None => 1,
+                    Some(Value::Num(scale)) => *scale as usize,
+                    Some(_) => return Err(usage),
+                };
+                match args.get(0) {
+                    Some(Value::Identifier(path)) if args.len() <= 2 => {
+                        Ok(Command::Screenshot(path.to_string(), scale))
+                    }
+                    _ => Err(usage),
+                }
+            }
+            #[cfg(feature = "lua")]
+            "script" | "lua" => match args.get(0) {
+                Some(Value::Identifier(path)) if args.len() == 1 => {
+                    Ok(Command::Script(path.to_string()))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "script <path>",
+                ))),
+            },
+            "source" => match args.get(0) {
+                Some(Value::Identifier(path)) if args.len() == 1 => {
+                    Ok(Command::Source(path.to_string()))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "source <path>",
+                ))),
+            },
             "list-symbols" | "list-syms" | "symbols" | "syms" => match args.len() {
                 0 => Ok(Command::ListSymbols(None)),
                 1 => {
@@ -509,6 +992,148 @@ impl Debugger {
                     command
                 ))),
             },
+            "watch" => Err(DebuggerError::InvalidCommandFormat(String::from(
+                "watch <expr>, e.g. watch *(u16*)(sp + 4) & 0xff",
+            ))),
+            "watches" => Ok(Command::ListWatches),
+            "unwatch" => match args.get(0) {
+                Some(Value::Num(id)) if args.len() == 1 => Ok(Command::RemoveWatch(*id as usize)),
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "unwatch <id>",
+                ))),
+            },
+            "snap" | "snapshot" => match args.get(0) {
+                None => Ok(Command::ListSnapshots),
+                Some(Value::Identifier(label)) if args.len() == 1 => {
+                    Ok(Command::Snapshot(label.to_string()))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "snap [label]",
+                ))),
+            },
+            "diff" => match (args.get(0), args.get(1)) {
+                (Some(Value::Identifier(old)), None) => Ok(Command::Diff(old.to_string(), None)),
+                (Some(Value::Identifier(old)), Some(Value::Identifier(new))) if args.len() == 2 => {
+                    Ok(Command::Diff(old.to_string(), Some(new.to_string())))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "diff <snapshot> [snapshot], diffs with the RAM by default",
+                ))),
+            },
+            "log" => match args.get(0) {
+                None => Ok(Command::ShowLog(DEFAULT_LOG_LINES)),
+                Some(Value::Num(count)) if args.len() == 1 => Ok(Command::ShowLog(*count as usize)),
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "log [count]",
+                ))),
+            },
+            "log-level" | "loglevel" => {
+                let usage = || {
+                    DebuggerError::InvalidCommandFormat(String::from(
+                        "log-level [cpu|bus|dma|gpu|apu|cart <off|error|warn|info|debug|trace>]",
+                    ))
+                };
+                match (args.get(0), args.get(1)) {
+                    (None, _) => Ok(Command::ListLogLevels),
+                    (Some(Value::Identifier(subsystem)), Some(Value::Identifier(level)))
+                        if args.len() == 2 =>
+                    {
+                        let subsystem = subsystem.parse().map_err(|_| usage())?;
+                        let level = level.parse().map_err(|_| usage())?;
+                        Ok(Command::SetLogLevel(subsystem, level))
+                    }
+                    _ => Err(usage()),
+                }
+            }
+            "load-symbols" | "load-syms" => match args.get(0) {
+                Some(Value::Identifier(path)) if args.len() == 1 => {
+                    Ok(Command::LoadSymbols(path.to_string()))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "load-symbols <path.sym|path.map>",
+                ))),
+            },
+            "add-symbol" | "add-sym" => match args.get(0) {
+                Some(Value::Identifier(name)) if args.len() == 2 => {
+                    let addr = self.val_address(&args[1])?;
+                    Ok(Command::AddSymbol(name.to_string(), addr))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "add-symbol <name> <addr>",
+                ))),
+            },
+            "remove-symbol" | "rm-sym" => match args.get(0) {
+                Some(Value::Identifier(name)) if args.len() == 1 => {
+                    Ok(Command::RemoveSymbol(name.to_string()))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "remove-symbol <name>",
+                ))),
+            },
+            "search" => {
+                let filter = |filter| Ok(Command::FilterSearch(filter));
+                match (args.get(0), args.get(1)) {
+                    (Some(Value::Identifier(op)), Some(Value::Num(n))) if args.len() == 2 => {
+                        match (op.as_str(), *n) {
+                            ("new", 8) | ("new", 16) | ("new", 32) => {
+                                Ok(Command::StartSearch((n / 8) as u8))
+                            }
+                            ("eq", value) => filter(SearchFilter::Equal(value)),
+                            ("ne", value) => filter(SearchFilter::NotEqual(value)),
+                            _ => Err(DebuggerError::InvalidArgument(op.to_string())),
+                        }
+                    }
+                    (Some(Value::Identifier(op)), None) => match op.as_str() {
+                        "changed" => filter(SearchFilter::Changed),
+                        "unchanged" => filter(SearchFilter::Unchanged),
+                        "inc" => filter(SearchFilter::Increased),
+                        "dec" => filter(SearchFilter::Decreased),
+                        _ => Err(DebuggerError::InvalidArgument(op.to_string())),
+                    },
+                    _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                        "search new <8|16|32> | eq|ne <value> | changed|unchanged|inc|dec",
+                    ))),
+                }
+            }
+            "cheat" => match (args.get(0), args.get(1), args.get(2)) {
+                (Some(Value::Identifier(name)), Some(Value::Num(value)), None) => Ok(
+                    Command::AddCheat(name.to_string(), CheatKind::Freeze(*value)),
+                ),
+                (Some(Value::Identifier(name)), Some(Value::Num(min)), Some(Value::Num(max)))
+                    if args.len() == 3 =>
+                {
+                    Ok(Command::AddCheat(
+                        name.to_string(),
+                        CheatKind::Clamp {
+                            min: *min,
+                            max: *max,
+                        },
+                    ))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "cheat <name> <value> to freeze, cheat <name> <min> <max> to clamp",
+                ))),
+            },
+            "cheats" => Ok(Command::ListCheats),
+            "uncheat" => match args.get(0) {
+                Some(Value::Num(index)) if args.len() == 1 => {
+                    Ok(Command::RemoveCheat(*index as usize))
+                }
+                _ => Err(DebuggerError::InvalidCommandFormat(String::from(
+                    "uncheat <index>",
+                ))),
+            },
+            "save-cheats" | "load-cheats" | "import-cheats" => match args.get(0) {
+                Some(Value::Identifier(path)) if args.len() == 1 => match command.as_ref() {
+                    "save-cheats" => Ok(Command::SaveCheats(path.to_string())),
+                    "load-cheats" => Ok(Command::LoadCheats(path.to_string())),
+                    _ => Ok(Command::ImportCheats(path.to_string())),
+                },
+                _ => Err(DebuggerError::InvalidCommandFormat(format!(
+                    "{} <path>",
+                    command
+                ))),
+            },
             _ => Err(DebuggerError::InvalidCommand(command)),
         }
     }
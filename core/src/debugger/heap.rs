@@ -0,0 +1,329 @@
+//! Heaps in the work RAMs, `heap`.
+//!
+//! Two allocators are recognized by the headers of their blocks:
+//! - the malloc of newlib, which the devkitARM games link: chunks laid end to end, starting
+//!   with the size of the previous chunk when it is free and their own size, bit 0 set when the
+//!   previous chunk is in use. Two free chunks are never next to each other, the last one is
+//!   the free space left.
+//! - the allocator of the Pokémon games, `gflib/malloc.c` in their decompilations: a circular
+//!   list of 16-byte headers tagged with 0xa3a3, the size of the block and the previous and next
+//!   headers.
+//!
+//! Other allocators show no heap, and a table that happens to look like a chain of chunks can
+//! show a heap that isn't one.
+use std::fmt;
+
+use crate::bus::Addr;
+use crate::sysbus::consts::{EWRAM_ADDR, IWRAM_ADDR};
+
+use super::Debugger;
+
+/// Chunks in a row it takes to believe in a newlib heap
+const MIN_NEWLIB_CHUNKS: usize = 4;
+const GFLIB_MAGIC: u16 = 0xa3a3;
+const GFLIB_HEADER_SIZE: u32 = 16;
+/// Headers followed before giving up on a gflib list that doesn't loop back
+const MAX_GFLIB_BLOCKS: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Allocator {
+    Newlib,
+    Gflib,
+}
+
+impl Allocator {
+    pub fn header_size(&self) -> u32 {
+        match self {
+            Allocator::Newlib => 8,
+            Allocator::Gflib => GFLIB_HEADER_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Allocator::Newlib => write!(f, "newlib"),
+            Allocator::Gflib => write!(f, "gflib"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeapBlock {
+    /// What the allocator returns for it, right after its header
+    pub addr: Addr,
+    /// The header included
+    pub size: u32,
+    pub used: bool,
+}
+
+impl fmt::Display for HeapBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:08x} {:>6} bytes {}",
+            self.addr,
+            self.size,
+            if self.used { "used" } else { "free" }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heap {
+    pub allocator: Allocator,
+    /// In address order
+    pub blocks: Vec<HeapBlock>,
+}
+
+impl Heap {
+    pub fn start(&self) -> Addr {
+        self.blocks[0].addr - self.allocator.header_size()
+    }
+
+    pub fn end(&self) -> Addr {
+        let last = &self.blocks[self.blocks.len() - 1];
+        last.addr - self.allocator.header_size() + last.size
+    }
+
+    /// The blocks used or free, and their bytes
+    fn count(&self, used: bool) -> (usize, u32) {
+        self.blocks
+            .iter()
+            .filter(|block| block.used == used)
+            .fold((0, 0), |(count, bytes), block| {
+                (count + 1, bytes + block.size)
+            })
+    }
+
+    pub fn largest_free(&self) -> u32 {
+        self.blocks
+            .iter()
+            .filter(|block| !block.used)
+            .map(|block| block.size)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Heap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (used, used_bytes) = self.count(true);
+        let (free, free_bytes) = self.count(false);
+        write!(
+            f,
+            "{} heap {:08x}-{:08x}: {} bytes in {} used blocks, {} bytes in {} free blocks, \
+             largest free {}",
+            self.allocator,
+            self.start(),
+            self.end(),
+            used_bytes,
+            used,
+            free_bytes,
+            free,
+            self.largest_free()
+        )
+    }
+}
+
+fn read_16(memory: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([memory[offset], memory[offset + 1]])
+}
+
+fn read_32(memory: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        memory[offset],
+        memory[offset + 1],
+        memory[offset + 2],
+        memory[offset + 3],
+    ])
+}
+
+/// The chunks chained from `start`, as long as they are consistent
+fn newlib_chunks(memory: &[u8], start: usize) -> Vec<(usize, u32, bool)> {
+    let mut chunks: Vec<(usize, u32, bool)> = Vec::new();
+    let mut at = start;
+    while at + 8 <= memory.len() {
+        let word = read_32(memory, at + 4);
+        let size = word & !7;
+        let previous_used = word & 1 != 0;
+        // bit 1 marks the chunks mmap()ed, which a GBA doesn't do
+        if word & 2 != 0 || size < 16 || at + size as usize > memory.len() {
+            break;
+        }
+        let consistent = match chunks.last() {
+            None => previous_used,
+            Some(_) if previous_used => true,
+            // a free chunk repeats its size at the start of the next one, and is not next to
+            // another free chunk
+            Some((_, previous_size, previous_previous_used)) => {
+                read_32(memory, at) == *previous_size && *previous_previous_used
+            }
+        };
+        if !consistent {
+            break;
+        }
+        chunks.push((at, size, previous_used));
+        at += size as usize;
+    }
+    chunks
+}
+
+fn scan_newlib(memory: &[u8], start: usize, base: Addr) -> Option<Heap> {
+    let chunks = newlib_chunks(memory, start);
+    if chunks.len() < MIN_NEWLIB_CHUNKS {
+        return None;
+    }
+    let blocks = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, (at, size, _))| HeapBlock {
+            addr: base + *at as u32 + 8,
+            size: *size,
+            // the last one is the top, free
+            used: chunks.get(i + 1).map_or(false, |(_, _, used)| *used),
+        })
+        .collect();
+    Some(Heap {
+        allocator: Allocator::Newlib,
+        blocks,
+    })
+}
+
+fn scan_gflib(memory: &[u8], start: usize, base: Addr) -> Option<Heap> {
+    // the block has to fit in the memory too
+    let is_header = |at: usize| {
+        at + GFLIB_HEADER_SIZE as usize <= memory.len()
+            && read_16(memory, at + 2) == GFLIB_MAGIC
+            && read_16(memory, at) <= 1
+            && at + GFLIB_HEADER_SIZE as usize + read_32(memory, at + 4) as usize <= memory.len()
+    };
+    let mut blocks = Vec::new();
+    let mut at = start;
+    loop {
+        if !is_header(at) || blocks.len() == MAX_GFLIB_BLOCKS {
+            return None;
+        }
+        blocks.push(HeapBlock {
+            addr: base + at as u32 + GFLIB_HEADER_SIZE,
+            size: GFLIB_HEADER_SIZE + read_32(memory, at + 4),
+            used: read_16(memory, at) != 0,
+        });
+        let next = read_32(memory, at + 12).wrapping_sub(base) as usize;
+        if next == start {
+            break;
+        }
+        at = next;
+    }
+    blocks.sort_by_key(|block| block.addr);
+    Some(Heap {
+        allocator: Allocator::Gflib,
+        blocks,
+    })
+}
+
+/// The heaps in `memory`, which starts at `base`
+pub fn scan_heaps(memory: &[u8], base: Addr) -> Vec<Heap> {
+    let mut heaps = Vec::new();
+    let mut at = 0;
+    while at + 8 <= memory.len() {
+        let heap = scan_gflib(memory, at, base).or_else(|| match at % 8 {
+            0 => scan_newlib(memory, at, base),
+            _ => None,
+        });
+        match heap {
+            Some(heap) => {
+                at = ((heap.end() - base) as usize).max(at + 4);
+                heaps.push(heap);
+            }
+            None => at += 4,
+        }
+    }
+    heaps
+}
+
+impl Debugger {
+    /// The heaps in EWRAM then IWRAM
+    pub fn heaps(&self) -> Vec<Heap> {
+        let mut heaps = scan_heaps(self.gba.sysbus.ewram(), EWRAM_ADDR);
+        heaps.extend(scan_heaps(self.gba.sysbus.iwram(), IWRAM_ADDR));
+        heaps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_32(memory: &mut [u8], offset: usize, value: u32) {
+        memory[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn test_newlib_heap() {
+        let mut memory = vec![0; 0x200];
+        // used 0x20, free 0x30, used 0x10, top 0x80, from 0x40
+        put_32(&mut memory, 0x44, 0x21);
+        put_32(&mut memory, 0x64, 0x31);
+        put_32(&mut memory, 0x90, 0x30);
+        put_32(&mut memory, 0x94, 0x10);
+        put_32(&mut memory, 0xa4, 0x81);
+        let heaps = scan_heaps(&memory, 0x0200_0000);
+        assert_eq!(heaps.len(), 1);
+        let blocks: Vec<_> = heaps[0]
+            .blocks
+            .iter()
+            .map(|block| (block.addr, block.size, block.used))
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0x0200_0048, 0x20, true),
+                (0x0200_0068, 0x30, false),
+                (0x0200_0098, 0x10, true),
+                (0x0200_00a8, 0x80, false),
+            ]
+        );
+        assert_eq!(heaps[0].largest_free(), 0x80);
+        assert_eq!(
+            heaps[0].to_string(),
+            "newlib heap 02000040-02000120: 48 bytes in 2 used blocks, 176 bytes in 2 free \
+             blocks, largest free 128"
+        );
+
+        // two free chunks in a row don't happen
+        put_32(&mut memory, 0xa4, 0x80);
+        put_32(&mut memory, 0xa0, 0x10);
+        assert!(scan_heaps(&memory, 0x0200_0000).is_empty());
+    }
+
+    #[test]
+    fn test_gflib_heap() {
+        let mut memory = vec![0; 0x200];
+        let header = |memory: &mut [u8], at: usize, used: u32, size: u32, prev, next| {
+            put_32(memory, at, used | (GFLIB_MAGIC as u32) << 16);
+            put_32(memory, at + 4, size);
+            put_32(memory, at + 8, 0x0300_0000 + prev as u32);
+            put_32(memory, at + 12, 0x0300_0000 + next as u32);
+        };
+        header(&mut memory, 0x100, 1, 0x20, 0x170, 0x130);
+        header(&mut memory, 0x130, 0, 0x30, 0x100, 0x170);
+        header(&mut memory, 0x170, 1, 0x10, 0x130, 0x100);
+        let heaps = scan_heaps(&memory, 0x0300_0000);
+        assert_eq!(heaps.len(), 1);
+        assert_eq!(heaps[0].allocator, Allocator::Gflib);
+        assert_eq!(
+            heaps[0].blocks[1],
+            HeapBlock {
+                addr: 0x0300_0140,
+                size: 0x40,
+                used: false,
+            }
+        );
+
+        // a list that doesn't loop back is no heap
+        put_32(&mut memory, 0x17c, 0x0300_0000);
+        assert!(scan_heaps(&memory, 0x0300_0000).is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+//! Memory editing with an undo journal.
+//!
+//! Every poke, fill or copy keeps the bytes it overwrote, `undo` writes them back in reverse
+//! order. Writes go through `DebugWrite`, so the ROM and BIOS can be patched too.
+use std::collections::VecDeque;
+
+use crate::bus::{Addr, DebugRead, DebugWrite};
+
+use super::Debugger;
+
+/// Number of edits `undo` can go back
+const JOURNAL_LEN: usize = 100;
+
+/// The bytes an edit overwrote
+#[derive(Debug, Clone)]
+pub struct MemoryEdit {
+    pub addr: Addr,
+    pub old_bytes: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct UndoJournal {
+    edits: VecDeque<MemoryEdit>,
+}
+
+impl UndoJournal {
+    fn push(&mut self, edit: MemoryEdit) {
+        if self.edits.len() == JOURNAL_LEN {
+            self.edits.pop_front();
+        }
+        self.edits.push_back(edit);
+    }
+
+    fn pop(&mut self) -> Option<MemoryEdit> {
+        self.edits.pop_back()
+    }
+}
+
+impl Debugger {
+    /// Writes with the widest accesses the alignment allows, IO registers don't behave the
+    /// same when written byte by byte
+    fn write_bytes(&mut self, addr: Addr, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            let addr = addr + i as Addr;
+            let left = bytes.len() - i;
+            if addr % 4 == 0 && left >= 4 {
+                let mut word = [0; 4];
+                word.copy_from_slice(&bytes[i..i + 4]);
+                self.gba
+                    .sysbus
+                    .debug_write_32(addr, u32::from_le_bytes(word));
+                i += 4;
+            } else if addr % 2 == 0 && left >= 2 {
+                let half = u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+                self.gba.sysbus.debug_write_16(addr, half);
+                i += 2;
+            } else {
+                self.gba.sysbus.debug_write_8(addr, bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    /// Writes `bytes` at `addr` and records what was there for `undo_edit`
+    pub fn edit_memory(&mut self, addr: Addr, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let old_bytes = self
+            .gba
+            .sysbus
+            .debug_get_bytes(addr..addr + bytes.len() as u32);
+        self.undo_journal.push(MemoryEdit { addr, old_bytes });
+        self.write_bytes(addr, bytes);
+    }
+
+    /// Reverts the last edit, returns it
+    pub fn undo_edit(&mut self) -> Option<MemoryEdit> {
+        let edit = self.undo_journal.pop()?;
+        self.write_bytes(edit.addr, &edit.old_bytes);
+        Some(edit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::make_debugger;
+    use crate::bus::Bus;
+
+    #[test]
+    fn test_edits_are_undone_in_reverse_order() {
+        let mut debugger = make_debugger(&[0; 0x200]);
+        debugger.gba.sysbus.write_32(0x0200_0000, 0x1122_3344);
+
+        debugger.edit_memory(0x0200_0001, &[0xaa, 0xbb]);
+        debugger.edit_memory(0x0200_0000, &[0xcc; 8]);
+        // the ROM can be patched too
+        debugger.edit_memory(0x0800_0010, &[0x42]);
+        assert_eq!(debugger.gba.sysbus.read_32(0x0200_0000), 0xcccc_cccc);
+        assert_eq!(debugger.gba.sysbus.read_8(0x0800_0010), 0x42);
+
+        assert_eq!(debugger.undo_edit().unwrap().addr, 0x0800_0010);
+        assert_eq!(debugger.gba.sysbus.read_8(0x0800_0010), 0);
+        debugger.undo_edit();
+        assert_eq!(debugger.gba.sysbus.read_32(0x0200_0000), 0x11bb_aa44);
+        debugger.undo_edit();
+        assert_eq!(debugger.gba.sysbus.read_32(0x0200_0000), 0x1122_3344);
+        assert!(debugger.undo_edit().is_none());
+    }
+}
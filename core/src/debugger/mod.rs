@@ -1,11 +1,18 @@
-use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::fs;
+use std::path::Path;
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 use colored::*;
 
+use super::cheats::RamSearch;
+use super::gpu::DISPLAY_HEIGHT;
+use super::ram_diff::RamSnapshot;
+#[cfg(feature = "lua")]
+use super::scripting::ScriptHost;
+#[cfg(feature = "lua")]
+use super::CoreResult;
 use super::GameBoyAdvance;
 use super::{Addr, Bus};
 
@@ -15,6 +22,30 @@ use parser::{parse_expr, DerefType, Expr, Value};
 mod command;
 use command::Command;
 
+mod auto_disass;
+use auto_disass::CodeStates;
+
+mod catch;
+use catch::Catchpoints;
+pub use catch::{CatchContext, Catchpoint};
+
+mod heap;
+pub use heap::{Allocator, Heap, HeapBlock};
+
+mod memedit;
+use memedit::UndoJournal;
+
+mod reverse;
+pub use reverse::StepBack;
+use reverse::{History, StepKind};
+
+mod stack;
+pub use stack::StackFrame;
+
+mod watch;
+use watch::Watches;
+pub use watch::{Watch, WatchChange, WatchHook};
+
 mod palette_view;
 mod tile_view;
 
@@ -35,10 +66,43 @@ impl From<::std::io::Error> for DebuggerError {
 
 type DebuggerResult<T> = Result<T, DebuggerError>;
 
+/// Instructions `continue` runs before giving the prompt back, about 6 seconds of emulated time
+const DEFAULT_WATCHDOG: u64 = 100_000_000;
+
+/// Why `run_until` gave control back
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Breakpoint(Addr),
+    /// Hit a catchpoint, see `catch`
+    Caught(CatchContext),
+    /// Reached the address `run_until` was given
+    Reached(Addr),
+    /// Ran out of the watchdog budget while at `pc`
+    Timeout {
+        pc: Addr,
+        instructions: u64,
+    },
+}
+
 pub struct Debugger {
     pub gba: GameBoyAdvance,
     running: bool,
     pub previous_command: Option<Command>,
+    /// Instruction budget of the execution commands, `None` lets them run forever
+    pub watchdog: Option<u64>,
+    undo_journal: UndoJournal,
+    /// Whether addresses hold ARM or Thumb code, learned by `disass`
+    code_states: CodeStates,
+    watches: Watches,
+    catchpoints: Catchpoints,
+    /// Checkpoints for `step_back`
+    history: History,
+    /// RAM snapshots taken by `snap`, for `diff`
+    snapshots: Vec<RamSnapshot>,
+    /// The memory search narrowed by `search`, for `cheat`
+    search: Option<RamSearch>,
+    #[cfg(feature = "lua")]
+    script_host: Option<ScriptHost>,
 }
 
 impl Debugger {
@@ -47,6 +111,70 @@ impl Debugger {
             gba: gba,
             running: false,
             previous_command: None,
+            watchdog: Some(DEFAULT_WATCHDOG),
+            undo_journal: UndoJournal::default(),
+            code_states: CodeStates::default(),
+            watches: Watches::default(),
+            catchpoints: Catchpoints::default(),
+            history: History::default(),
+            snapshots: Vec::new(),
+            search: None,
+            #[cfg(feature = "lua")]
+            script_host: None,
+        }
+    }
+
+    #[cfg(feature = "lua")]
+    pub fn load_script(&mut self, path: &Path) -> CoreResult<()> {
+        if self.script_host.is_none() {
+            self.script_host = Some(ScriptHost::new()?);
+        }
+        let host = self.script_host.as_mut().unwrap();
+        host.load_file(path, &mut self.gba)
+    }
+
+    /// Runs a frame, going through the loaded script if any
+    fn frame(&mut self) {
+        #[cfg(feature = "lua")]
+        {
+            if let Some(host) = &mut self.script_host {
+                if let Err(e) = host.frame(&mut self.gba) {
+                    println!("script error: {}", e);
+                    self.script_host = None;
+                }
+                return;
+            }
+        }
+        self.gba.frame();
+    }
+
+    /// Runs until a breakpoint, a catchpoint, `until` or the end of the watchdog budget
+    pub fn run_until(&mut self, until: Option<Addr>) -> StopReason {
+        let mut instructions = 0;
+        loop {
+            self.record_step(StepKind::Machine);
+            self.gba.key_poll();
+            let vcount = self.gba.sysbus.io.gpu.vcount;
+            let origin = self.catch_origin();
+            if let Some(breakpoint) = self.gba.step_debugger() {
+                return StopReason::Breakpoint(breakpoint);
+            }
+            if let Some(context) = self.check_catchpoints(origin) {
+                return StopReason::Caught(context);
+            }
+            if vcount != self.gba.sysbus.io.gpu.vcount
+                && self.gba.sysbus.io.gpu.vcount == DISPLAY_HEIGHT
+            {
+                self.update_watches();
+            }
+            instructions += 1;
+            let pc = self.gba.cpu.get_next_pc();
+            if until == Some(pc) {
+                return StopReason::Reached(pc);
+            }
+            if self.watchdog.map_or(false, |budget| instructions >= budget) {
+                return StopReason::Timeout { pc, instructions };
+            }
         }
     }
 
@@ -61,6 +189,17 @@ impl Debugger {
         None
     }
 
+    /// Snapshots the RAM under `label`, replacing the snapshot that had the same label
+    pub fn take_snapshot(&mut self, label: &str) {
+        let snapshot = RamSnapshot::capture(&mut self.gba, label);
+        self.snapshots.retain(|s| s.label != label);
+        self.snapshots.push(snapshot);
+    }
+
+    pub fn snapshot(&self, label: &str) -> Option<&RamSnapshot> {
+        self.snapshots.iter().find(|s| s.label == label)
+    }
+
     pub fn delete_breakpoint(&mut self, addr: u32) {
         self.gba.cpu.breakpoints.retain(|&a| a != addr);
     }
@@ -128,6 +267,7 @@ impl Debugger {
 
     fn eval_assignment(&mut self, lvalue: Value, rvalue: Value) -> DebuggerResult<()> {
         let lvalue = self.val_reg(&lvalue)?;
+        self.history.seal();
         let rvalue = match rvalue {
             Value::Deref(addr_value, deref_type) => {
                 let addr = self.val_address(&addr_value)?;
@@ -167,6 +307,13 @@ impl Debugger {
                 }
                 _ => (),
             },
+            Expr::Watch(expr) => match self.insert_watch(expr) {
+                Ok(id) => println!("watch #{}", id),
+                Err(DebuggerError::InvalidArgument(m)) => {
+                    println!("{}: {}", "watch error".red(), m)
+                }
+                Err(e) => println!("{} {:?}", "failed to add watch".red(), e),
+            },
             Expr::Empty => println!("Got empty expr"),
         }
     }
@@ -175,18 +322,57 @@ impl Debugger {
         self.running = false;
     }
 
+    /// Runs the debugger commands of a script file, see `run_commands`
+    pub fn run_script(&mut self, path: &Path) -> DebuggerResult<()> {
+        let text = fs::read_to_string(path)?;
+        self.run_commands(&path.display().to_string(), &text)
+    }
+
+    /// Runs debugger commands, one per line, as if they were typed at the prompt. Blank lines
+    /// and lines starting with `#` are skipped. Stops at `quit`, or at the first line that
+    /// doesn't parse, `source` names the commands in the error.
+    pub fn run_commands(&mut self, source: &str, text: &str) -> DebuggerResult<()> {
+        self.running = true;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            println!(
+                "{} {}",
+                format!("{}:{}>", source, number + 1).dimmed(),
+                line
+            );
+            match parse_expr(line) {
+                Ok(expr) => self.eval_expr(expr),
+                Err(DebuggerError::ParsingError(msg)) => {
+                    return Err(DebuggerError::ParsingError(format!(
+                        "{}:{}: {}",
+                        source,
+                        number + 1,
+                        msg
+                    )))
+                }
+                Err(e) => return Err(e),
+            }
+            if !self.running {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     pub fn repl(&mut self, script_file: Option<&str>) -> DebuggerResult<()> {
         println!("Welcome to rustboyadvance-NG debugger 😎!\n");
         self.running = true;
         let mut rl = Editor::<()>::new();
         let _ = rl.load_history(".rustboyadvance_history");
         if let Some(path) = script_file {
-            println!("Executing script file");
-            let file = File::open(path)?;
-            let reader = BufReader::new(file);
-            for line in reader.lines() {
-                let expr = parse_expr(&line.unwrap())?;
-                self.eval_expr(expr);
+            println!("Executing script file {}", path);
+            match self.run_script(Path::new(path)) {
+                Err(DebuggerError::ParsingError(msg)) => println!("Parsing error: {}", msg),
+                Err(e) => println!("{} {:?}", "failed to run the script".red(), e),
+                Ok(_) => {}
             }
         }
         while self.running {
@@ -227,3 +413,158 @@ impl Debugger {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::test_util::make_mock_gba;
+
+    pub(super) fn make_debugger(rom: &[u8]) -> Debugger {
+        Debugger::new(make_mock_gba(rom))
+    }
+
+    #[test]
+    fn test_commands_stop_at_quit() {
+        let mut debugger = make_debugger(&vec![0; 0x200]);
+        let script = "
+            # reproduces a crash
+            b 0x08000010
+
+            break 0x08000020
+            quit
+            b 0x08000030
+        ";
+        debugger.run_commands("repro", script).unwrap();
+        assert_eq!(debugger.gba.cpu.breakpoints, vec![0x0800_0010, 0x0800_0020]);
+
+        let err = debugger.run_commands("repro", "b 0x08000040\nwatch +");
+        assert!(
+            matches!(err, Err(DebuggerError::ParsingError(msg)) if msg.starts_with("repro:2:"))
+        );
+        assert_eq!(debugger.gba.cpu.breakpoints.len(), 3);
+    }
+
+    #[test]
+    fn test_watchdog_stops_runaway_continue() {
+        let mut rom = vec![0; 0x200];
+        // mov r0, #1; b .
+        rom[0..4].copy_from_slice(&0xe3a0_0001u32.to_le_bytes());
+        rom[4..8].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let mut debugger = make_debugger(&rom);
+
+        assert_eq!(
+            debugger.run_until(Some(0x0800_0004)),
+            StopReason::Reached(0x0800_0004)
+        );
+        debugger.watchdog = Some(1000);
+        assert_eq!(
+            debugger.run_until(Some(0x0800_0008)),
+            StopReason::Timeout {
+                pc: 0x0800_0004,
+                instructions: 1000
+            }
+        );
+    }
+
+    #[test]
+    fn test_catchpoints() {
+        use crate::interrupt::Interrupt;
+
+        let mut rom = vec![0; 0x200];
+        // swi 0x05; b .
+        rom[0..4].copy_from_slice(&0xef05_0000u32.to_le_bytes());
+        rom[4..8].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let mut debugger = make_debugger(&rom);
+        debugger.run_commands("test", "catch swi").unwrap();
+        assert!(debugger.run_commands("test", "catch nmi").is_err());
+        match debugger.run_until(None) {
+            StopReason::Caught(context) => {
+                assert_eq!(context.catchpoint, Catchpoint::Swi);
+                assert_eq!(context.pc, 0x0800_0000);
+                assert_eq!(context.return_addr, Some(0x0800_0004));
+                assert_eq!(context.swi, Some(5));
+            }
+            reason => panic!("stopped for {:?}", reason),
+        }
+
+        let mut debugger = make_debugger(&rom[4..]);
+        debugger.gba.sysbus.write_16(0x0400_0004, 1 << 3); // DISPSTAT, vblank irq
+        debugger
+            .run_commands("test", "catch swi\ncatch vblank\nuncatch swi")
+            .unwrap();
+        assert_eq!(
+            debugger.catchpoints.iter().collect::<Vec<_>>(),
+            [&Catchpoint::Source(Interrupt::LCD_VBlank)]
+        );
+        match debugger.run_until(None) {
+            StopReason::Caught(context) => {
+                assert_eq!(
+                    context.catchpoint,
+                    Catchpoint::Source(Interrupt::LCD_VBlank)
+                );
+                assert_eq!(context.pc, 0x0800_0000);
+                assert_eq!(context.return_addr, None);
+                assert_eq!(context.interrupt_flags & 1, 1);
+            }
+            reason => panic!("stopped for {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut rom = vec![0; 0x200];
+        // mov r0, #1; add r0, r0, #1 (x4); b .
+        rom[0..4].copy_from_slice(&0xe3a0_0001u32.to_le_bytes());
+        for i in 1..5 {
+            rom[i * 4..i * 4 + 4].copy_from_slice(&0xe280_0001u32.to_le_bytes());
+        }
+        rom[20..24].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let mut debugger = make_debugger(&rom);
+        assert_eq!(debugger.step_back(1), None);
+
+        debugger.run_commands("test", "step 3").unwrap();
+        assert_eq!(debugger.gba.cpu.get_reg(0), 3);
+        assert_eq!(debugger.step_back(1), Some(StepBack::Done));
+        assert_eq!(debugger.gba.cpu.get_reg(0), 2);
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0008);
+
+        // replays what `continue` ran the way it ran it
+        debugger.run_until(Some(0x0800_0014));
+        assert_eq!(debugger.gba.cpu.get_reg(0), 5);
+        assert_eq!(debugger.step_back(3), Some(StepBack::Done));
+        assert_eq!(debugger.gba.cpu.get_reg(0), 2);
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0008);
+
+        debugger.run_commands("test", "frame").unwrap();
+        assert_eq!(debugger.step_back(1), Some(StepBack::FrameStart(0)));
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0008);
+
+        // going back past an edit undoes it
+        debugger.run_commands("test", "r0 = 0x10\nstep").unwrap();
+        assert_eq!(debugger.gba.cpu.get_reg(0), 0x11);
+        assert_eq!(debugger.step_back(2), Some(StepBack::Done));
+        assert_eq!(debugger.gba.cpu.get_reg(0), 1);
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0004);
+        assert_eq!(debugger.step_back(10), Some(StepBack::HistoryStart(1)));
+        assert_eq!(debugger.gba.cpu.get_next_pc(), 0x0800_0000);
+    }
+
+    #[test]
+    fn test_snapshots_are_diffed() {
+        let mut debugger = make_debugger(&vec![0; 0x200]);
+        debugger.run_commands("test", "snap before").unwrap();
+        debugger.gba.sysbus.write_16(0x0300_0010, 0x0102);
+        debugger
+            .run_commands("test", "snap after\nsnap before")
+            .unwrap();
+        assert_eq!(debugger.snapshots.len(), 2);
+
+        // taking "before" again replaced it
+        let after = debugger.snapshot("after").unwrap().clone();
+        assert!(debugger.snapshot("before").unwrap().diff(&after).is_empty());
+        debugger.gba.sysbus.write_8(0x0200_0000, 7);
+        let changes = after.diff(&RamSnapshot::capture(&mut debugger.gba, "ram"));
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].addr, changes[0].new), (0x0200_0000, 7));
+    }
+}
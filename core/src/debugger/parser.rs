@@ -2,12 +2,14 @@ use nom;
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_while1, take_while_m_n};
 use nom::character::complete::{char, digit1, multispace0, multispace1};
-use nom::combinator::{cut, map, map_res, opt};
+use nom::combinator::{all_consuming, cut, map, map_res, opt};
 use nom::error::{context, convert_error, ParseError, VerboseError};
-use nom::multi::separated_list;
-use nom::sequence::{delimited, preceded, separated_pair, terminated, tuple};
+use nom::multi::{fold_many0, separated_list};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
 
+use std::fmt;
+
 use super::{DebuggerError, DebuggerResult};
 
 #[derive(Debug, PartialEq, Clone)]
@@ -25,15 +27,68 @@ pub enum Value {
     Deref(Box<Value>, DerefType),
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchOp {
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+/// The arithmetic of `watch` expressions, identifiers are symbols or registers
+#[derive(Debug, PartialEq, Clone)]
+pub enum WatchExpr {
+    Num(u32),
+    Identifier(String),
+    Deref(Box<WatchExpr>, DerefType),
+    BinOp(WatchOp, Box<WatchExpr>, Box<WatchExpr>),
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Expr {
     /// (command-name arg0 arg1 ...)
     Command(Value, Vec<Value>),
     /// constant = value
     Assignment(Value, Value),
+    /// watch expression
+    Watch(WatchExpr),
     Empty,
 }
 
+impl fmt::Display for WatchExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchExpr::Num(n) => write!(f, "{:#x}", n),
+            WatchExpr::Identifier(name) => write!(f, "{}", name),
+            WatchExpr::Deref(addr, deref_type) => {
+                let deref_type = match deref_type {
+                    DerefType::Word => "u32*",
+                    DerefType::HalfWord => "u16*",
+                    DerefType::Byte => "u8*",
+                };
+                write!(f, "*({}){}", deref_type, addr)
+            }
+            WatchExpr::BinOp(op, lhs, rhs) => {
+                let op = match op {
+                    WatchOp::Add => "+",
+                    WatchOp::Sub => "-",
+                    WatchOp::Mul => "*",
+                    WatchOp::And => "&",
+                    WatchOp::Or => "|",
+                    WatchOp::Xor => "^",
+                    WatchOp::Shl => "<<",
+                    WatchOp::Shr => ">>",
+                };
+                write!(f, "({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+}
+
 fn parse_u32_hex<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, u32, E> {
     let (i, _) = context("hex", tag("0x"))(i)?;
     map_res(take_while_m_n(1, 8, |c: char| c.is_digit(16)), |s| {
@@ -59,9 +114,10 @@ fn parse_boolean<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Val
     )(i)
 }
 
+/// Names and file paths
 fn parse_identifier<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Value, E> {
     map(
-        take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-'),
+        take_while1(|c: char| c.is_alphanumeric() || "_-./\\".contains(c)),
         |s: &str| Value::Identifier(String::from(s)),
     )(i)
 }
@@ -131,12 +187,110 @@ fn parse_assignment<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&str, Exp
     )(i)
 }
 
+fn parse_watch_atom<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, WatchExpr, E> {
+    preceded(
+        multispace0,
+        alt((
+            map(
+                preceded(
+                    char('*'),
+                    pair(
+                        map(opt(parse_deref_type), |t| t.unwrap_or(DerefType::Word)),
+                        parse_watch_atom,
+                    ),
+                ),
+                |(t, addr)| WatchExpr::Deref(Box::new(addr), t),
+            ),
+            delimited(
+                char('('),
+                parse_watch_operand,
+                preceded(multispace0, char(')')),
+            ),
+            map(alt((parse_u32_hex, parse_u32)), WatchExpr::Num),
+            map(
+                take_while1(|c: char| c.is_alphanumeric() || c == '_'),
+                |s: &str| WatchExpr::Identifier(String::from(s)),
+            ),
+        )),
+    )(i)
+}
+
+/// Parses the left associative operators `ops` between operands parsed by `operand`
+fn parse_binops<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+    ops: &'static [(&'static str, WatchOp)],
+    operand: fn(&'a str) -> IResult<&'a str, WatchExpr, E>,
+) -> IResult<&'a str, WatchExpr, E> {
+    let (i, first) = operand(i)?;
+    fold_many0(
+        pair(
+            preceded(multispace0, |i: &'a str| {
+                for (token, op) in ops {
+                    if let Ok((i, _)) = tag::<_, _, E>(*token)(i) {
+                        return Ok((i, *op));
+                    }
+                }
+                Err(nom::Err::Error(E::from_error_kind(
+                    i,
+                    nom::error::ErrorKind::Tag,
+                )))
+            }),
+            operand,
+        ),
+        first,
+        |lhs, (op, rhs)| WatchExpr::BinOp(op, Box::new(lhs), Box::new(rhs)),
+    )(i)
+}
+
+fn parse_watch_product<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, WatchExpr, E> {
+    parse_binops(i, &[("*", WatchOp::Mul)], parse_watch_atom)
+}
+
+fn parse_watch_sum<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, WatchExpr, E> {
+    parse_binops(
+        i,
+        &[("+", WatchOp::Add), ("-", WatchOp::Sub)],
+        parse_watch_product,
+    )
+}
+
+fn parse_watch_shift<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, WatchExpr, E> {
+    parse_binops(
+        i,
+        &[("<<", WatchOp::Shl), (">>", WatchOp::Shr)],
+        parse_watch_sum,
+    )
+}
+
+/// Bitwise operators bind the loosest, then shifts, then `+ -` and then `*`
+fn parse_watch_operand<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, WatchExpr, E> {
+    parse_binops(
+        i,
+        &[("&", WatchOp::And), ("|", WatchOp::Or), ("^", WatchOp::Xor)],
+        parse_watch_shift,
+    )
+}
+
+fn parse_watch<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&str, Expr, E> {
+    context(
+        "watch",
+        map(
+            preceded(
+                terminated(tag("watch"), multispace1),
+                cut(all_consuming(terminated(parse_watch_operand, multispace0))),
+            ),
+            Expr::Watch,
+        ),
+    )(i)
+}
+
 fn _parse_expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&str, Expr, E> {
     context(
         "expression",
         preceded(
             multispace0,
             alt((
+                parse_watch,
                 parse_assignment,
                 parse_command,
                 map(multispace0, |_| Expr::Empty),
@@ -155,6 +309,21 @@ pub fn parse_expr(i: &str) -> DebuggerResult<Expr> {
     }
 }
 
+/// Parses a watch expression, without the `watch` command
+pub fn parse_watch_expr(i: &str) -> DebuggerResult<WatchExpr> {
+    match all_consuming(terminated(
+        parse_watch_operand::<VerboseError<&str>>,
+        multispace0,
+    ))(i)
+    {
+        Ok((_, expr)) => Ok(expr),
+        Err(nom::Err::Failure(e)) | Err(nom::Err::Error(e)) => {
+            Err(DebuggerError::ParsingError(convert_error(i, e)))
+        }
+        _ => panic!("unhandled parser error"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,6 +353,13 @@ mod tests {
                 ]
             ))
         );
+        assert_eq!(
+            parse_expr("source scripts/repro.txt"),
+            Ok(Expr::Command(
+                Value::Identifier("source".to_string()),
+                vec![Value::Identifier("scripts/repro.txt".to_string())]
+            ))
+        );
     }
 
     #[test]
@@ -211,6 +387,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_watch_expr() {
+        let deref = WatchExpr::Deref(
+            Box::new(WatchExpr::Identifier("player_x".to_string())),
+            DerefType::HalfWord,
+        );
+        assert_eq!(
+            parse_expr("watch *(u16*)player_x + r0 * 2 & 0xff"),
+            Ok(Expr::Watch(WatchExpr::BinOp(
+                WatchOp::And,
+                Box::new(WatchExpr::BinOp(
+                    WatchOp::Add,
+                    Box::new(deref),
+                    Box::new(WatchExpr::BinOp(
+                        WatchOp::Mul,
+                        Box::new(WatchExpr::Identifier("r0".to_string())),
+                        Box::new(WatchExpr::Num(2))
+                    ))
+                )),
+                Box::new(WatchExpr::Num(0xff))
+            )))
+        );
+        if let Ok(Expr::Watch(expr)) = parse_expr("watch *(sp + 4)  ") {
+            assert_eq!(expr.to_string(), "*(u32*)(sp + 0x4)");
+        } else {
+            panic!("expected a watch expression");
+        }
+        assert!(parse_expr("watch r0 +").is_err());
+        // not a watch expression
+        assert_eq!(
+            parse_expr("watchdog off"),
+            Ok(Expr::Command(
+                Value::Identifier("watchdog".to_string()),
+                vec![Value::Identifier("off".to_string())]
+            ))
+        );
+    }
+
     #[test]
     fn test_parse_deref() {
         assert_eq!(
@@ -0,0 +1,183 @@
+//! Stepping backwards, `step-back [count]`.
+//!
+//! The execution commands leave a savestate behind every `CHECKPOINT_INTERVAL` instructions, and
+//! before every frame `frame` runs, keeping `HISTORY_BYTES` of them like the rewind buffer of
+//! the frontends. Going back loads the last checkpoint before the instruction to return to, and
+//! runs the instructions in between again the way they were run the first time. `frame` doesn't
+//! count the instructions of a frame, so going back into one lands at its start.
+//!
+//! Editing the machine from the prompt (poking memory, assigning a register, loading a state)
+//! starts a new checkpoint, going back past it undoes the edit. The keys are not recorded, the
+//! replay sees those held now.
+use std::collections::VecDeque;
+
+use super::Debugger;
+
+/// Instructions between two checkpoints, about a third of a frame
+const CHECKPOINT_INTERVAL: u64 = 100_000;
+/// Savestates carry the whole cartridge, a big ROM leaves room for fewer checkpoints
+const HISTORY_BYTES: usize = 256 * 1024 * 1024;
+
+/// How an instruction was run, to run it again the same way
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum StepKind {
+    /// By `step`, the cpu alone
+    Cpu,
+    /// By `continue`, the cpu then the devices, see `GameBoyAdvance::step_debugger`
+    Machine,
+}
+
+/// Where `step_back` stopped
+#[derive(Debug, PartialEq)]
+pub enum StepBack {
+    /// Went back the instructions asked
+    Done,
+    /// At the start of a frame run by `frame`, after going back that many instructions
+    FrameStart(u64),
+    /// At the oldest checkpoint, after going back that many instructions
+    HistoryStart(u64),
+}
+
+struct Checkpoint {
+    state: Vec<u8>,
+    /// The instructions run since, in runs of the same kind. `None` once a frame ran.
+    steps: Option<Vec<(StepKind, u64)>>,
+}
+
+impl Checkpoint {
+    fn instructions(&self) -> Option<u64> {
+        self.steps
+            .as_ref()
+            .map(|steps| steps.iter().map(|(_, count)| count).sum())
+    }
+}
+
+#[derive(Default)]
+pub(super) struct History {
+    checkpoints: VecDeque<Checkpoint>,
+    total_bytes: usize,
+    /// The machine was edited since the last checkpoint, the next step takes a new one
+    sealed: bool,
+}
+
+impl History {
+    fn push(&mut self, state: Vec<u8>, steps: Option<Vec<(StepKind, u64)>>) {
+        self.total_bytes += state.len();
+        self.checkpoints.push_back(Checkpoint { state, steps });
+        self.sealed = false;
+        while self.total_bytes > HISTORY_BYTES && self.checkpoints.len() > 1 {
+            let oldest = self.checkpoints.pop_front().unwrap();
+            self.total_bytes -= oldest.state.len();
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some(checkpoint) = self.checkpoints.pop_back() {
+            self.total_bytes -= checkpoint.state.len();
+        }
+    }
+
+    /// To be called when the machine is changed other than by running it
+    pub(super) fn seal(&mut self) {
+        self.sealed = true;
+    }
+}
+
+/// The first `count` instructions of `steps`
+fn truncate_steps(steps: &[(StepKind, u64)], mut count: u64) -> Vec<(StepKind, u64)> {
+    let mut truncated = Vec::new();
+    for (kind, run) in steps {
+        if count == 0 {
+            break;
+        }
+        truncated.push((*kind, count.min(*run)));
+        count -= count.min(*run);
+    }
+    truncated
+}
+
+impl Debugger {
+    /// To be called before running an instruction
+    pub(super) fn record_step(&mut self, kind: StepKind) {
+        let due = match self.history.checkpoints.back() {
+            Some(checkpoint) => match checkpoint.instructions() {
+                Some(count) => self.history.sealed || count >= CHECKPOINT_INTERVAL,
+                None => true,
+            },
+            None => true,
+        };
+        if due {
+            let state = self.gba.save_state().expect("failed to serialize");
+            self.history.push(state, Some(Vec::new()));
+        }
+        let steps = self
+            .history
+            .checkpoints
+            .back_mut()
+            .and_then(|checkpoint| checkpoint.steps.as_mut())
+            .unwrap();
+        match steps.last_mut() {
+            Some((last, run)) if *last == kind => *run += 1,
+            _ => steps.push((kind, 1)),
+        }
+    }
+
+    /// To be called before running a frame
+    pub(super) fn record_frame(&mut self) {
+        let state = self.gba.save_state().expect("failed to serialize");
+        self.history.push(state, None);
+    }
+
+    /// Loads the last checkpoint and runs `steps` again, the breakpoints set meanwhile are kept
+    fn replay(&mut self, steps: Vec<(StepKind, u64)>) {
+        let checkpoint = self.history.checkpoints.back_mut().unwrap();
+        let breakpoints = self.gba.cpu.breakpoints.clone();
+        self.gba
+            .restore_state(&checkpoint.state)
+            .expect("failed to restore a checkpoint");
+        self.gba.cpu.breakpoints = breakpoints;
+        for (kind, run) in &steps {
+            for _ in 0..*run {
+                match kind {
+                    StepKind::Cpu => {
+                        self.gba.cpu.step_instruction(&mut self.gba.sysbus);
+                    }
+                    StepKind::Machine => {
+                        self.gba.key_poll();
+                        self.gba.step_debugger();
+                    }
+                }
+            }
+        }
+        checkpoint.steps = Some(steps);
+        self.history.sealed = false;
+    }
+
+    /// Goes back `count` instructions, or as far as the history goes. `None` when there is no
+    /// history.
+    pub fn step_back(&mut self, count: u64) -> Option<StepBack> {
+        let mut left = count;
+        loop {
+            let checkpoint = self.history.checkpoints.back()?;
+            let steps = match &checkpoint.steps {
+                Some(steps) => steps,
+                None => {
+                    self.replay(Vec::new());
+                    return Some(StepBack::FrameStart(count - left));
+                }
+            };
+            let run = checkpoint.instructions().unwrap();
+            if run >= left {
+                let steps = truncate_steps(steps, run - left);
+                self.replay(steps);
+                return Some(StepBack::Done);
+            }
+            left -= run;
+            if self.history.checkpoints.len() == 1 {
+                self.replay(Vec::new());
+                return Some(StepBack::HistoryStart(count - left));
+            }
+            self.history.pop();
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The stacks of the cpu, `stack [words]`.
+//!
+//! The games don't keep frame pointers, so the frames are guessed: the words on the stack that
+//! point right after a call, a Thumb `bl`, an ARM `bl` or a `mov lr, pc`, are taken for the
+//! return addresses pushed by the functions running. A stale return address left below sp by a
+//! function that returned is skipped, one left inside a frame isn't, and a leaf function that
+//! doesn't push lr only shows in lr.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::arm7tdmi::CpuMode;
+use crate::bus::{Addr, DebugRead};
+use crate::cartridge::SymbolTable;
+use crate::sysbus::consts::{EWRAM_ADDR, INTERNAL_RAM_SIZE, IWRAM_ADDR, WORK_RAM_SIZE};
+
+use super::Debugger;
+
+/// Symbols further than this before an address don't name it
+const MAX_SYMBOL_OFFSET: u32 = 0x1_0000;
+
+/// Where the BIOS puts the stack of `mode`, the stacks grow down from there
+pub fn stack_top(mode: CpuMode) -> Addr {
+    match mode {
+        CpuMode::Irq => 0x0300_7fa0,
+        CpuMode::Supervisor => 0x0300_7fe0,
+        _ => 0x0300_7f00,
+    }
+}
+
+/// Names addresses after the closest symbol before them
+pub struct Symbolizer<'a> {
+    symbols: BTreeMap<Addr, &'a str>,
+}
+
+impl<'a> Symbolizer<'a> {
+    pub fn new(symbols: Option<&'a SymbolTable>) -> Symbolizer<'a> {
+        Symbolizer {
+            symbols: symbols
+                .into_iter()
+                .flat_map(|symbols| symbols.iter())
+                .map(|(name, addr)| (*addr & !1, name.as_str()))
+                .collect(),
+        }
+    }
+
+    /// `name+0x1c`, `None` when no symbol is close enough. The Thumb bit is ignored.
+    pub fn name(&self, addr: Addr) -> Option<String> {
+        let addr = addr & !1;
+        let (start, name) = self.symbols.range(..=addr).next_back()?;
+        match addr - start {
+            0 => Some(name.to_string()),
+            offset if offset < MAX_SYMBOL_OFFSET => Some(format!("{}+{:#x}", name, offset)),
+            _ => None,
+        }
+    }
+}
+
+/// A word on the stack that looks like a return address
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    /// Where the word is
+    pub slot: Addr,
+    /// With bit 0 set when it returns to Thumb code
+    pub return_addr: Addr,
+    pub symbol: Option<String>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:08x}] {:08x}", self.slot, self.return_addr)?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " {}", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+/// The BIOS, the work RAMs and the ROM
+fn is_code(addr: Addr) -> bool {
+    addr < 0x4000
+        || (EWRAM_ADDR..EWRAM_ADDR + WORK_RAM_SIZE as u32).contains(&addr)
+        || (IWRAM_ADDR..IWRAM_ADDR + INTERNAL_RAM_SIZE as u32).contains(&addr)
+        || (0x0800_0000..0x0e00_0000).contains(&addr)
+}
+
+/// Whether `addr` is right after a call, bit 0 set for Thumb code
+pub fn follows_call<B: DebugRead>(bus: &B, addr: Addr) -> bool {
+    if addr & 1 != 0 {
+        let addr = addr & !1;
+        match addr.checked_sub(4) {
+            Some(call) if is_code(call) && is_code(addr) => {
+                bus.debug_read_16(call) & 0xf800 == 0xf000
+                    && bus.debug_read_16(call + 2) & 0xf800 == 0xf800
+            }
+            _ => false,
+        }
+    } else {
+        match addr.checked_sub(8) {
+            Some(before) if addr & 3 == 0 && is_code(before) && is_code(addr) => {
+                let bl = bus.debug_read_32(addr - 4) & 0x0f00_0000 == 0x0b00_0000;
+                let mov_lr_pc = bus.debug_read_32(before) & 0x0fff_ffff == 0x01a0_e00f;
+                bl || mov_lr_pc
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Debugger {
+    /// The return addresses in the `words` words above sp, stopping at the top of the stack
+    pub fn stack_frames(&self, words: u32) -> Vec<StackFrame> {
+        let mode = self.gba.cpu.cpsr.mode();
+        let (sp, _) = self.gba.cpu.get_banked_sp_lr(mode);
+        let mut end = sp.saturating_add(words.saturating_mul(4));
+        if sp < stack_top(mode) {
+            end = end.min(stack_top(mode));
+        }
+        let symbolizer = Symbolizer::new(self.gba.sysbus.cartridge.get_symbols().as_ref());
+        (sp & !3..end)
+            .step_by(4)
+            .filter_map(|slot| {
+                let word = self.gba.sysbus.debug_read_32(slot);
+                if follows_call(&*self.gba.sysbus, word) {
+                    Some(StackFrame {
+                        slot,
+                        return_addr: word,
+                        symbol: symbolizer.name(word),
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::make_debugger;
+    use super::*;
+    use crate::bus::Bus;
+
+    #[test]
+    fn test_stack_frames() {
+        let mut rom = vec![0; 0x200];
+        // a Thumb bl at 0x10, an ARM bl at 0x20
+        rom[0x10..0x14].copy_from_slice(&[0x00, 0xf0, 0x76, 0xf8]);
+        rom[0x20..0x24].copy_from_slice(&0xeb00_0036u32.to_le_bytes());
+        let mut debugger = make_debugger(&rom);
+        debugger
+            .gba
+            .sysbus
+            .cartridge
+            .add_symbol("main", 0x0800_0001);
+        let sp = debugger.gba.cpu.get_reg(13) - 0x10;
+        debugger.gba.cpu.set_reg(13, sp);
+        debugger.gba.sysbus.write_32(sp, 0x0800_0015);
+        debugger.gba.sysbus.write_32(sp + 4, 0x0800_0014);
+        debugger.gba.sysbus.write_32(sp + 8, 0x0800_0024);
+        debugger.gba.sysbus.write_32(sp + 12, 0x0600_0024);
+
+        let frames = debugger.stack_frames(16);
+        assert_eq!(
+            frames,
+            [
+                StackFrame {
+                    slot: sp,
+                    return_addr: 0x0800_0015,
+                    symbol: Some("main+0x14".to_string()),
+                },
+                StackFrame {
+                    slot: sp + 8,
+                    return_addr: 0x0800_0024,
+                    symbol: Some("main+0x24".to_string()),
+                },
+            ]
+        );
+    }
+}
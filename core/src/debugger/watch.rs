@@ -0,0 +1,174 @@
+//! Watch expressions.
+//!
+//! Watches are evaluated at the start of every VBlank, and after every step while the emulation
+//! is paused. The watches whose value changed go to the watch hook, so a frontend can show live
+//! variables, or are printed by the REPL when there is no hook.
+use crate::bus::DebugRead;
+
+use super::parser::{parse_watch_expr, DerefType, Value, WatchExpr, WatchOp};
+use super::{Debugger, DebuggerResult};
+
+pub struct Watch {
+    pub id: usize,
+    pub expr: WatchExpr,
+    /// The value of the last evaluation, `None` when it failed, e.g. the symbol was removed
+    pub value: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchChange {
+    pub id: usize,
+    pub expr: String,
+    pub old: Option<u32>,
+    pub new: u32,
+}
+
+pub type WatchHook = Box<dyn FnMut(&WatchChange)>;
+
+#[derive(Default)]
+pub struct Watches {
+    watches: Vec<Watch>,
+    next_id: usize,
+    hook: Option<WatchHook>,
+}
+
+impl Debugger {
+    fn eval_watch(&self, expr: &WatchExpr) -> DebuggerResult<u32> {
+        match expr {
+            WatchExpr::Num(n) => Ok(*n),
+            WatchExpr::Identifier(name) => self.val_address(&Value::Identifier(name.clone())),
+            WatchExpr::Deref(addr, deref_type) => {
+                let addr = self.eval_watch(addr)?;
+                let bus = &self.gba.sysbus;
+                Ok(match deref_type {
+                    DerefType::Word => bus.debug_read_32(addr),
+                    DerefType::HalfWord => bus.debug_read_16(addr) as u32,
+                    DerefType::Byte => bus.debug_read_8(addr) as u32,
+                })
+            }
+            WatchExpr::BinOp(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.eval_watch(lhs)?, self.eval_watch(rhs)?);
+                Ok(match op {
+                    WatchOp::Add => lhs.wrapping_add(rhs),
+                    WatchOp::Sub => lhs.wrapping_sub(rhs),
+                    WatchOp::Mul => lhs.wrapping_mul(rhs),
+                    WatchOp::And => lhs & rhs,
+                    WatchOp::Or => lhs | rhs,
+                    WatchOp::Xor => lhs ^ rhs,
+                    WatchOp::Shl => lhs.checked_shl(rhs).unwrap_or(0),
+                    WatchOp::Shr => lhs.checked_shr(rhs).unwrap_or(0),
+                })
+            }
+        }
+    }
+
+    /// Adds a watch of `expr`, which has to evaluate right away. Returns the id of the watch.
+    pub(super) fn insert_watch(&mut self, expr: WatchExpr) -> DebuggerResult<usize> {
+        let value = self.eval_watch(&expr)?;
+        let id = self.watches.next_id;
+        self.watches.next_id += 1;
+        self.watches.watches.push(Watch {
+            id,
+            expr,
+            value: Some(value),
+        });
+        Ok(id)
+    }
+
+    /// Parses and adds a watch, e.g. `*(u16*)player_x + 8`
+    pub fn add_watch(&mut self, expr: &str) -> DebuggerResult<usize> {
+        let expr = parse_watch_expr(expr)?;
+        self.insert_watch(expr)
+    }
+
+    pub fn remove_watch(&mut self, id: usize) -> bool {
+        let len = self.watches.watches.len();
+        self.watches.watches.retain(|watch| watch.id != id);
+        self.watches.watches.len() != len
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.watches.watches
+    }
+
+    pub fn set_watch_hook(&mut self, hook: Option<WatchHook>) {
+        self.watches.hook = hook;
+    }
+
+    /// Evaluates every watch, and reports the ones that changed
+    pub fn update_watches(&mut self) -> Vec<WatchChange> {
+        let values: Vec<Option<u32>> = self
+            .watches
+            .watches
+            .iter()
+            .map(|watch| self.eval_watch(&watch.expr).ok())
+            .collect();
+        let mut changes = Vec::new();
+        for (watch, value) in self.watches.watches.iter_mut().zip(values) {
+            if let Some(new) = value {
+                if watch.value != Some(new) {
+                    changes.push(WatchChange {
+                        id: watch.id,
+                        expr: watch.expr.to_string(),
+                        old: watch.value,
+                        new,
+                    });
+                }
+            }
+            watch.value = value;
+        }
+
+        for change in &changes {
+            match &mut self.watches.hook {
+                Some(hook) => hook(change),
+                None => match change.old {
+                    Some(old) => println!(
+                        "watch #{} {}: {:#x} -> {:#x}",
+                        change.id, change.expr, old, change.new
+                    ),
+                    None => println!("watch #{} {}: {:#x}", change.id, change.expr, change.new),
+                },
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::make_debugger;
+    use super::WatchChange;
+    use crate::bus::Bus;
+
+    #[test]
+    fn test_watches_report_changes() {
+        let mut debugger = make_debugger(&[0; 0x200]);
+        debugger
+            .gba
+            .sysbus
+            .cartridge
+            .add_symbol("counter", 0x0300_0010);
+        debugger.gba.cpu.set_reg(0, 0);
+        let counter = debugger.add_watch("*(u16*)counter").unwrap();
+        let sum = debugger.add_watch("(r0 + *(u8*)0x03000010) << 1").unwrap();
+        assert!(debugger.add_watch("*nowhere").is_err());
+        debugger.set_watch_hook(Some(Box::new(|_: &WatchChange| {})));
+
+        assert!(debugger.update_watches().is_empty());
+        debugger.gba.sysbus.write_16(0x0300_0010, 0x0102);
+        debugger.gba.cpu.set_reg(0, 1);
+        let changes = debugger.update_watches();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].id, counter);
+        assert_eq!(changes[0].expr, "*(u16*)counter");
+        assert_eq!((changes[0].old, changes[0].new), (Some(0), 0x0102));
+        assert_eq!((changes[1].old, changes[1].new), (Some(0), 6));
+
+        assert!(debugger.remove_watch(counter));
+        assert!(!debugger.remove_watch(counter));
+        debugger.gba.sysbus.write_8(0x0300_0010, 0);
+        let changes = debugger.update_watches();
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].id, changes[0].new), (sum, 2));
+    }
+}
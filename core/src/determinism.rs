@@ -0,0 +1,159 @@
+//! Savestate determinism verification.
+//!
+//! Netplay rollbacks and input movies rely on a loaded state running exactly like the machine
+//! it was saved from. `verify_determinism` checks it on the running game: at every checkpoint
+//! it saves a state and runs some frames, then loads the state and runs the frames again with
+//! the same KEYINPUT values, comparing the hashes of every subsystem after each frame. A
+//! difference means some state is missing from savestates, or the emulation depends on the host.
+use std::fmt;
+use std::io;
+
+use serde::Serialize;
+
+use super::cartridge::BackupRestore;
+use super::{CoreResult, GameBoyAdvance};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// FNV-1a over the serialized state, unlike `DefaultHasher` it is the same on every build
+struct StateHasher(u64);
+
+impl io::Write for StateHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SubsystemHash {
+    pub name: &'static str,
+    pub hash: u64,
+}
+
+/// Hash of the serialized `state`, the same on every platform and build
+pub(crate) fn hash_state<T: Serialize + ?Sized>(state: &T) -> u64 {
+    let mut hasher = StateHasher(FNV_OFFSET_BASIS);
+    // the emulator state has no unsized sequences, and writing to the hasher can't fail
+    bincode::serialize_into(&mut hasher, state).expect("failed to serialize the state");
+    hasher.0
+}
+
+impl SubsystemHash {
+    pub(crate) fn of<T: Serialize + ?Sized>(name: &'static str, state: &T) -> SubsystemHash {
+        SubsystemHash {
+            name,
+            hash: hash_state(state),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeterminismCheck {
+    pub checkpoints: usize,
+    /// Frames run from every checkpoint
+    pub frames: usize,
+}
+
+impl Default for DeterminismCheck {
+    fn default() -> DeterminismCheck {
+        DeterminismCheck {
+            checkpoints: 10,
+            frames: 60,
+        }
+    }
+}
+
+/// Where the run from a loaded state first differed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Counted from 0
+    pub checkpoint: usize,
+    /// Frames run from the checkpoint, including the divergent one
+    pub frame: usize,
+    /// The subsystems that differ, the scheduler and the cpu first as they drive the others
+    pub subsystems: Vec<&'static str>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "nondeterminism at checkpoint {}, frame {}: {} differ",
+            self.checkpoint,
+            self.frame,
+            self.subsystems.join(", ")
+        )
+    }
+}
+
+/// Runs `check.checkpoints` checkpoints of `check.frames` frames twice, see the module
+/// documentation. The game ends up after the last checkpoint, or at the divergent frame.
+/// Every frame runs twice, so the frontend and the hooks see them twice.
+pub fn verify_determinism(
+    gba: &mut GameBoyAdvance,
+    check: DeterminismCheck,
+) -> CoreResult<Option<Divergence>> {
+    // notifications are drawn into the frame buffer, which is part of the state
+    let osd_enabled = gba.osd_mut().enabled;
+    gba.osd_mut().enabled = false;
+    let keyinput_override = gba.keyinput_override();
+    // the save of the first run would replace the save of the state when reloading it
+    let backup_restore = gba.backup_restore();
+    gba.set_backup_restore(BackupRestore::FromState);
+
+    let mut result = Ok(None);
+    for checkpoint in 0..check.checkpoints {
+        gba.set_keyinput_override(keyinput_override);
+        result = run_checkpoint(gba, checkpoint, check.frames);
+        if !matches!(result, Ok(None)) {
+            break;
+        }
+    }
+
+    gba.osd_mut().enabled = osd_enabled;
+    gba.set_keyinput_override(keyinput_override);
+    gba.set_backup_restore(backup_restore);
+    result
+}
+
+fn run_checkpoint(
+    gba: &mut GameBoyAdvance,
+    checkpoint: usize,
+    frames: usize,
+) -> CoreResult<Option<Divergence>> {
+    let state = gba.save_state()?;
+    let mut expected = Vec::with_capacity(frames);
+    for _ in 0..frames {
+        gba.frame();
+        expected.push((gba.sysbus.io.keyinput, gba.subsystem_hashes()));
+    }
+
+    gba.restore_state(&state)?;
+    for (frame, (keyinput, expected_hashes)) in expected.into_iter().enumerate() {
+        gba.set_keyinput_override(Some(keyinput));
+        gba.frame();
+        let hashes = gba.subsystem_hashes();
+        let subsystems: Vec<&'static str> = hashes
+            .iter()
+            .zip(&expected_hashes)
+            .filter(|(hash, expected)| hash != expected)
+            .map(|(hash, _)| hash.name)
+            .collect();
+        if !subsystems.is_empty() {
+            return Ok(Some(Divergence {
+                checkpoint,
+                frame: frame + 1,
+                subsystems,
+            }));
+        }
+    }
+    Ok(None)
+}
@@ -1,5 +1,6 @@
-use std::fmt;
-use std::marker::PhantomData;
+use alloc::string::String;
+use core::fmt;
+use core::marker::PhantomData;
 
 use super::arm7tdmi::InstructionDecoder;
 use super::Addr;
@@ -24,7 +25,7 @@ where
             base: base as Addr,
             pos: 0,
             bytes: bytes,
-            word_size: std::mem::size_of::<D::IntType>(),
+            word_size: core::mem::size_of::<D::IntType>(),
             instruction_decoder: PhantomData,
         }
     }
@@ -33,7 +34,7 @@ where
 impl<'a, D> Iterator for Disassembler<'a, D>
 where
     D: InstructionDecoder + fmt::Display,
-    <D as InstructionDecoder>::IntType: std::fmt::LowerHex,
+    <D as InstructionDecoder>::IntType: core::fmt::LowerHex,
 {
     type Item = (Addr, String);
 
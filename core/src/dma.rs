@@ -1,8 +1,11 @@
 use super::cartridge::BackupMedia;
 use super::interrupt::{self, Interrupt, InterruptConnect, SharedInterruptFlags};
 use super::iodev::consts::{REG_FIFO_A, REG_FIFO_B};
+use super::logging;
 use super::sysbus::SysBus;
-use super::Bus;
+use super::{Bus, LoadContext, ResetAfterLoad};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
@@ -25,6 +28,8 @@ pub struct DmaChannel {
     fifo_mode: bool,
     irq: Interrupt,
     interrupt_flags: SharedInterruptFlags,
+    #[serde(skip)]
+    last_transfer: Option<DmaTransfer>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -52,6 +57,7 @@ impl DmaChannel {
             fifo_mode: false,
             internal: Default::default(),
             interrupt_flags,
+            last_transfer: None,
         }
     }
 
@@ -59,6 +65,32 @@ impl DmaChannel {
         self.running
     }
 
+    /// Summary of the transfer about to be performed
+    fn next_transfer(&self) -> DmaTransfer {
+        let word_size = if self.fifo_mode || self.ctrl.is_32bit() {
+            4
+        } else {
+            2
+        };
+        let count = if self.fifo_mode {
+            4
+        } else {
+            match self.internal.count {
+                0 if self.id == 3 => 0x1_0000,
+                0 => 0x0_4000,
+                count => count,
+            }
+        };
+        DmaTransfer {
+            src: self.internal.src_addr,
+            dst: self.internal.dst_addr,
+            count,
+            word_size,
+            timing: self.ctrl.timing(),
+            fifo: self.fifo_mode,
+        }
+    }
+
     pub fn write_src_low(&mut self, low: u16) {
         let src = self.src;
         self.src = (src & 0xffff0000) | (low as u32);
@@ -90,7 +122,7 @@ impl DmaChannel {
         let timing = ctrl.timing();
         let mut start_immediately = false;
         if ctrl.is_enabled() && !self.ctrl.is_enabled() {
-            trace!(
+            trace!(target: logging::DMA,
                 "DMA{} enabled! timing={} src={:#x} dst={:#x} cnt={}",
                 self.id,
                 timing,
@@ -122,13 +154,12 @@ impl DmaChannel {
             /* Increment */ 0 => self.internal.src_addr += word_size,
             /* Decrement */ 1 => self.internal.src_addr -= word_size,
             /* Fixed */ 2 => {}
-            _ => panic!("forbidden DMA source address adjustment"),
+            /* Prohibited, behaves as increment */ _ => self.internal.src_addr += word_size,
         }
         match self.ctrl.dst_adj() {
             /* Increment[+Reload] */ 0 | 3 => self.internal.dst_addr += word_size,
             /* Decrement */ 1 => self.internal.dst_addr -= word_size,
-            /* Fixed */ 2 => {}
-            _ => panic!("forbidden DMA dest address adjustment"),
+            /* Fixed */ _ => {}
         }
     }
 
@@ -189,11 +220,67 @@ impl DmaChannel {
     }
 }
 
+/// A single run of a DMA channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DmaTransfer {
+    pub src: u32,
+    pub dst: u32,
+    /// Number of units transferred
+    pub count: u32,
+    /// Size of a unit in bytes
+    pub word_size: u32,
+    pub timing: u16,
+    /// Refilling a sound FIFO
+    pub fifo: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DmaEvent {
+    Started {
+        channel: usize,
+        transfer: DmaTransfer,
+    },
+    /// `enabled` is false unless the channel repeats
+    Stopped {
+        channel: usize,
+        transfer: DmaTransfer,
+        enabled: bool,
+    },
+}
+
+/// Called with every DMA event, see `GameBoyAdvance::set_dma_hook`
+#[cfg(not(feature = "send"))]
+pub type DmaHook = Box<dyn FnMut(&DmaEvent)>;
+#[cfg(feature = "send")]
+pub type DmaHook = Box<dyn FnMut(&DmaEvent) + Send>;
+
+/// State of a channel, for debuggers
+#[derive(Debug, Clone)]
+pub struct DmaChannelInfo {
+    pub id: usize,
+    /// The registers as written by the game
+    pub src: u32,
+    pub dst: u32,
+    pub word_count: u32,
+    pub ctrl: DmaChannelCtrl,
+    /// The addresses the next unit is transferred from and to
+    pub current_src: u32,
+    pub current_dst: u32,
+    /// Enabled and waiting for its start timing
+    pub active: bool,
+    /// Triggered, the transfer runs before the cpu resumes
+    pub pending: bool,
+    pub last_transfer: Option<DmaTransfer>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DmaController {
     pub channels: [DmaChannel; 4],
     pending_set: u8,
     cycles: usize,
+    /// Events not yet passed to the hook, `None` when nobody listens
+    #[serde(skip)]
+    events: Option<Vec<DmaEvent>>,
 }
 
 impl InterruptConnect for DmaController {
@@ -204,6 +291,12 @@ impl InterruptConnect for DmaController {
     }
 }
 
+impl ResetAfterLoad for DmaController {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.connect_irq(ctx.interrupt_flags.clone());
+    }
+}
+
 impl DmaController {
     pub fn new(interrupt_flags: SharedInterruptFlags) -> DmaController {
         DmaController {
@@ -215,6 +308,7 @@ impl DmaController {
             ],
             pending_set: 0,
             cycles: 0,
+            events: None,
         }
     }
 
@@ -222,10 +316,57 @@ impl DmaController {
         self.pending_set != 0
     }
 
+    pub fn inspect(&self) -> [DmaChannelInfo; 4] {
+        let info = |id: usize| {
+            let channel = &self.channels[id];
+            DmaChannelInfo {
+                id,
+                src: channel.src,
+                dst: channel.dst,
+                word_count: channel.wc,
+                ctrl: channel.ctrl.clone(),
+                current_src: channel.internal.src_addr,
+                current_dst: channel.internal.dst_addr,
+                active: channel.running,
+                pending: self.pending_set & (1 << id) != 0,
+                last_transfer: channel.last_transfer,
+            }
+        };
+        [info(0), info(1), info(2), info(3)]
+    }
+
+    /// Starts or stops collecting the events returned by `take_events`
+    pub fn record_events(&mut self, enable: bool) {
+        self.events = if enable { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_events(&mut self) -> Vec<DmaEvent> {
+        self.events
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
     pub fn perform_work(&mut self, sb: &mut SysBus) {
         for id in 0..4 {
             if self.pending_set & (1 << id) != 0 {
-                self.channels[id].xfer(sb);
+                let channel = &mut self.channels[id];
+                let transfer = channel.next_transfer();
+                if let Some(events) = &mut self.events {
+                    events.push(DmaEvent::Started {
+                        channel: id,
+                        transfer,
+                    });
+                }
+                channel.xfer(sb);
+                channel.last_transfer = Some(transfer);
+                if let Some(events) = &mut self.events {
+                    events.push(DmaEvent::Stopped {
+                        channel: id,
+                        transfer,
+                        enabled: channel.ctrl.is_enabled(),
+                    });
+                }
             }
         }
         self.pending_set = 0;
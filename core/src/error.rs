@@ -0,0 +1,170 @@
+//! The error type shared by the whole core.
+//!
+//! Anything that depends on user input (ROM files, archives, profiles, savestates) reports a
+//! `CoreError` instead of panicking, and `ResultExt::context` records what was being done when
+//! the error happened, e.g.
+//! `loading roms/game.gba: can't load cartridge: incomplete cartridge header`.
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::io;
+
+#[cfg(feature = "debugger")]
+use super::debugger;
+
+#[derive(Debug)]
+pub enum CoreError {
+    #[cfg(feature = "std")]
+    IO(io::Error),
+    /// The ROM, or the archive it came in, is malformed
+    CartridgeLoadError(String),
+    ProfileError(String),
+    ConfigError(String),
+    /// An unknown or corrupt BIOS, refused by `BiosCheck::Error`
+    BiosError(String),
+    #[cfg(feature = "savestates")]
+    SavestateError(String),
+    /// A memory access of `GameBoyAdvance::mem` out of the bus or misaligned
+    MemoryError(String),
+    /// A cheat or a cheat file that can't be used
+    #[cfg(feature = "cheats")]
+    CheatError(String),
+    #[cfg(feature = "rom_db")]
+    RomDatabaseError(String),
+    #[cfg(feature = "debugger")]
+    DebuggerError(debugger::DebuggerError),
+    #[cfg(feature = "lua")]
+    ScriptError(String),
+    /// Another error, along with what was being done when it happened
+    Context {
+        context: String,
+        source: Box<CoreError>,
+    },
+}
+
+pub type CoreResult<T> = Result<T, CoreError>;
+
+/// The names the error type had before `CoreError`
+pub type GBAError = CoreError;
+pub type GBAResult<T> = CoreResult<T>;
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            CoreError::IO(err) => write!(f, "{}", err),
+            CoreError::CartridgeLoadError(msg) => write!(f, "can't load cartridge: {}", msg),
+            CoreError::ProfileError(msg) => write!(f, "invalid game profile: {}", msg),
+            CoreError::ConfigError(msg) => write!(f, "invalid config: {}", msg),
+            CoreError::BiosError(msg) => write!(f, "bad bios: {}", msg),
+            #[cfg(feature = "savestates")]
+            CoreError::SavestateError(msg) => write!(f, "invalid savestate: {}", msg),
+            CoreError::MemoryError(msg) => write!(f, "invalid memory access: {}", msg),
+            #[cfg(feature = "cheats")]
+            CoreError::CheatError(msg) => write!(f, "invalid cheat: {}", msg),
+            #[cfg(feature = "rom_db")]
+            CoreError::RomDatabaseError(msg) => write!(f, "invalid ROM database: {}", msg),
+            #[cfg(feature = "debugger")]
+            CoreError::DebuggerError(err) => write!(f, "debugger error: {:?}", err),
+            #[cfg(feature = "lua")]
+            CoreError::ScriptError(msg) => write!(f, "script error: {}", msg),
+            CoreError::Context { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for CoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CoreError::IO(err) => Some(err),
+            CoreError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Adds context to the errors of the core, in the spirit of `anyhow::Context`
+pub trait ResultExt<T> {
+    fn context<C: Into<String>>(self, context: C) -> CoreResult<T>;
+
+    /// Same as `context`, for contexts that are costly to build
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> CoreResult<T>;
+}
+
+impl<T, E: Into<CoreError>> ResultExt<T> for Result<T, E> {
+    fn context<C: Into<String>>(self, context: C) -> CoreResult<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: Into<String>, F: FnOnce() -> C>(self, f: F) -> CoreResult<T> {
+        self.map_err(|err| CoreError::Context {
+            context: f().into(),
+            source: Box::new(err.into()),
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for CoreError {
+    fn from(err: io::Error) -> CoreError {
+        CoreError::IO(err)
+    }
+}
+
+#[cfg(feature = "savestates")]
+impl From<bincode::Error> for CoreError {
+    fn from(err: bincode::Error) -> CoreError {
+        CoreError::SavestateError(err.to_string())
+    }
+}
+
+#[cfg(feature = "debugger")]
+impl From<debugger::DebuggerError> for CoreError {
+    fn from(err: debugger::DebuggerError) -> CoreError {
+        CoreError::DebuggerError(err)
+    }
+}
+
+#[cfg(feature = "lua")]
+impl From<mlua::Error> for CoreError {
+    fn from(err: mlua::Error) -> CoreError {
+        CoreError::ScriptError(err.to_string())
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<png::EncodingError> for CoreError {
+    fn from(err: png::EncodingError) -> CoreError {
+        CoreError::IO(io::Error::other(err))
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<zip::result::ZipError> for CoreError {
+    fn from(err: zip::result::ZipError) -> CoreError {
+        CoreError::CartridgeLoadError(format!("zip error: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_is_displayed() {
+        let result: CoreResult<()> = Err(CoreError::CartridgeLoadError(
+            "incomplete cartridge header".to_string(),
+        ));
+        let err = result.context("loading game.gba").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "loading game.gba: can't load cartridge: incomplete cartridge header"
+        );
+        #[cfg(feature = "std")]
+        assert!(err.source().is_some());
+    }
+}
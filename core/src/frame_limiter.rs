@@ -0,0 +1,180 @@
+//! Paces a frontend's emulation loop against the host clock.
+//!
+//! The limiter keeps an absolute deadline that is advanced by one frame period on every frame,
+//! so sleep overshoot on one frame is paid back on the next ones instead of accumulating.
+//! ```ignore
+//! let mut limiter = FrameLimiter::new(PacingMode::Native);
+//! loop {
+//!     gba.frame();
+//!     limiter.wait();
+//! }
+//! ```
+use std::time::Duration;
+
+use super::gba::CPU_CLOCK_HZ;
+use super::gpu::CYCLES_FULL_REFRESH;
+use super::util::{now, Instant};
+
+/// Refresh rate of the GBA LCD, about 59.7275 Hz
+pub const NATIVE_FRAME_RATE: f64 = CPU_CLOCK_HZ as f64 / CYCLES_FULL_REFRESH as f64;
+
+/// When the host falls behind by more than this many frames, the limiter stops trying to catch up
+const MAX_LAG_FRAMES: u32 = 4;
+/// `thread::sleep` tends to overshoot by up to a millisecond, the end of the wait is spent spinning
+#[cfg(not(target_arch = "wasm32"))]
+const SPIN_THRESHOLD: Duration = Duration::from_millis(1);
+/// Maximum relative change of the frame period when slaved to audio
+const MAX_AUDIO_SKEW: f64 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacingMode {
+    /// Paced by the host clock at the configured frame rate
+    Native,
+    /// Paced by the frontend blocking on vsync, the limiter never waits
+    VSync,
+    /// Paced by the host clock, with the period nudged to keep the audio queue at its target level.
+    /// The frontend reports the queue level with `update_audio_level`
+    Audio,
+    /// Runs as fast as possible
+    Unlimited,
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameLimiter {
+    mode: PacingMode,
+    frame_time: Duration,
+    /// When the current frame should end, `None` until the first frame completes
+    deadline: Option<Instant>,
+    audio_skew: f64,
+}
+
+impl FrameLimiter {
+    pub fn new(mode: PacingMode) -> FrameLimiter {
+        FrameLimiter::with_frame_rate(mode, NATIVE_FRAME_RATE)
+    }
+
+    pub fn with_frame_rate(mode: PacingMode, frame_rate: f64) -> FrameLimiter {
+        FrameLimiter {
+            mode,
+            frame_time: Duration::from_secs_f64(1.0 / frame_rate),
+            deadline: None,
+            audio_skew: 0.0,
+        }
+    }
+
+    pub fn mode(&self) -> PacingMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: PacingMode) {
+        if mode != self.mode {
+            self.mode = mode;
+            self.reset();
+        }
+    }
+
+    /// The period frames are currently paced at
+    pub fn frame_time(&self) -> Duration {
+        match self.mode {
+            PacingMode::Audio => self.frame_time.mul_f64(1.0 + self.audio_skew),
+            _ => self.frame_time,
+        }
+    }
+
+    /// Forgets the timing history, to be called after the emulation was paused
+    pub fn reset(&mut self) {
+        self.deadline = None;
+        self.audio_skew = 0.0;
+    }
+
+    /// Reports how many samples the audio device has queued, and how many it should ideally have.
+    /// Only used in `PacingMode::Audio`, frames are slowed down when the queue is above target
+    /// and sped up when it is below.
+    pub fn update_audio_level(&mut self, queued: usize, target: usize) {
+        if target == 0 {
+            return;
+        }
+        let error = (queued as f64 - target as f64) / target as f64;
+        self.audio_skew = (error * MAX_AUDIO_SKEW).clamp(-MAX_AUDIO_SKEW, MAX_AUDIO_SKEW);
+    }
+
+    /// Marks the end of a frame and returns how long to wait before starting the next one.
+    /// Frontends that can't block (e.g. in a browser) schedule the next frame with this delay.
+    pub fn next_delay(&mut self) -> Duration {
+        if let PacingMode::VSync | PacingMode::Unlimited = self.mode {
+            self.deadline = None;
+            return Duration::from_secs(0);
+        }
+        let now = now();
+        let frame_time = self.frame_time();
+        let mut deadline = match self.deadline {
+            Some(deadline) => deadline + frame_time,
+            None => now,
+        };
+        if now > deadline + frame_time * MAX_LAG_FRAMES {
+            deadline = now;
+        }
+        self.deadline = Some(deadline);
+        if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    /// Marks the end of a frame and blocks until the next one should start
+    pub fn wait(&mut self) {
+        let delay = self.next_delay();
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let end = now() + delay;
+            if delay > SPIN_THRESHOLD {
+                std::thread::sleep(delay - SPIN_THRESHOLD);
+            }
+            while now() < end {
+                std::thread::yield_now();
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native_frame_rate() {
+        assert!((NATIVE_FRAME_RATE - 59.7275).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_deadline_advances_and_resyncs() {
+        let mut limiter = FrameLimiter::new(PacingMode::Native);
+        assert_eq!(limiter.next_delay(), Duration::from_secs(0));
+        let delay = limiter.next_delay();
+        assert!(delay <= limiter.frame_time());
+        assert!(delay > limiter.frame_time() / 2);
+
+        // way behind schedule, the limiter doesn't try to run the missed frames back to back
+        limiter.deadline = Some(now() - limiter.frame_time() * (MAX_LAG_FRAMES + 1));
+        assert_eq!(limiter.next_delay(), Duration::from_secs(0));
+        assert!(limiter.next_delay() > limiter.frame_time() / 2);
+
+        limiter.set_mode(PacingMode::Unlimited);
+        assert_eq!(limiter.next_delay(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_audio_skew() {
+        let mut limiter = FrameLimiter::new(PacingMode::Audio);
+        let frame_time = limiter.frame_time();
+        limiter.update_audio_level(4096, 2048);
+        assert!(limiter.frame_time() > frame_time);
+        limiter.update_audio_level(0, 2048);
+        assert!(limiter.frame_time() < frame_time);
+        limiter.set_mode(PacingMode::Native);
+        assert_eq!(limiter.frame_time(), frame_time);
+    }
+}
@@ -1,81 +1,210 @@
 /// Struct containing everything
-use std::cell::{Cell, RefCell};
-use std::rc::Rc;
-
+use alloc::boxed::Box;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(all(feature = "std", feature = "cheats"))]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::time;
+
+#[cfg(feature = "savestates")]
 use bincode;
+#[cfg(feature = "savestates")]
 use serde::{Deserialize, Serialize};
 
 use super::arm7tdmi;
-use super::cartridge::Cartridge;
-use super::dma::DmaController;
+use super::bios;
+use super::bios_db::BiosCheck;
+use super::bus::Addr;
+#[cfg(feature = "savestates")]
+use super::cartridge::RomIdentity;
+use super::cartridge::{BackupRestore, Cartridge};
+#[cfg(all(feature = "std", feature = "cheats"))]
+use super::cheat_import::{self, ImportReport};
+#[cfg(feature = "cheats")]
+use super::cheats::CheatEngine;
+#[cfg(feature = "std")]
+use super::config::Config;
+use super::crash::{CrashHandler, CrashReport};
+#[cfg(feature = "savestates")]
+use super::determinism::{hash_state, SubsystemHash};
+use super::dma::{DmaController, DmaEvent, DmaHook};
 use super::gpu::*;
+use super::init::InitOptions;
+use super::input::{InputController, InputLatency, LatencyProbe};
 use super::interrupt::*;
 use super::iodev::*;
-use super::sound::SoundController;
+use super::keypad::Keys;
+use super::logging;
+use super::memory::Memory;
+use super::profile::GameProfile;
+use super::screenshot::Screenshot;
+use super::sio::{DummyLink, SerialDevice};
+use super::sound::{FifoHook, Interpolation, SoundController};
+use super::swi_trace::{HleOutcome, SwiCall, SwiMismatch, SwiTraceMode, SwiTracer};
 use super::sysbus::SysBus;
+use super::time_control::{HostFrame, TimeControl};
+use super::timeline::{Timeline, TimelineEvent, TimelineEventKind};
 use super::timer::Timers;
-
-use super::{AudioInterface, InputInterface, VideoInterface};
+#[cfg(feature = "trace")]
+use super::trace::{TraceSink, TraceWriter};
+#[cfg(feature = "std")]
+use super::util;
+use super::util::RomBuffer;
+#[cfg(feature = "savestates")]
+use super::CoreError;
+
+use super::{
+    lock, AudioSink, CoreResult, Frontend, InputSource, LoadContext, ResetAfterLoad, Shared,
+    VideoSink,
+};
+
+/// Address, r0-r3, mode of the caller and HLE outcome of a swi about to execute
+type SwiStart = (Addr, [u32; 4], arm7tdmi::CpuMode, Option<HleOutcome>);
 
 pub struct GameBoyAdvance {
     pub sysbus: Box<SysBus>,
     pub cpu: arm7tdmi::Core,
 
-    pub video_device: Rc<RefCell<dyn VideoInterface>>,
-    pub audio_device: Rc<RefCell<dyn AudioInterface>>,
-    pub input_device: Rc<RefCell<dyn InputInterface>>,
+    pub video_device: Shared<dyn VideoSink>,
+    pub audio_device: Shared<dyn AudioSink>,
+    pub input_device: Shared<dyn InputSource>,
 
     pub cycles_to_next_event: usize,
 
     overshoot_cycles: usize,
     interrupt_flags: SharedInterruptFlags,
+
+    /// Address of a busy-wait loop, the cpu is skipped to the next event while inside it
+    idle_loop: Option<u32>,
+    /// Replaces the input device state when set
+    keyinput_override: Option<u16>,
+    /// Key events, turbo and queued input applied on top of the input device
+    pub input: InputController,
+    latency_probe: LatencyProbe,
+
+    time_control: TimeControl,
+
+    stats: EmulationStats,
+    #[cfg(feature = "std")]
+    fps_counter: util::FpsCounter,
+
+    crash_report: Option<CrashReport>,
+    crash_handler: Option<CrashHandler>,
+    dma_hook: Option<DmaHook>,
+    audio_fifo_hook: Option<FifoHook>,
+    scanline_hook: Option<ScanlineHook>,
+    /// Shows the FIFO glitch counters on the OSD
+    show_fifo_stats: bool,
+    /// Reloads the save file when another program changes it
+    #[cfg(feature = "std")]
+    watch_backup: bool,
+    /// Whether loading a state overwrites the save file
+    backup_restore: BackupRestore,
+    /// Loads the states made with another ROM, see `set_force_state_load`
+    #[cfg(feature = "savestates")]
+    force_state_load: bool,
+    /// Hardware events of the frames, see `timeline`
+    timeline: Option<Timeline>,
+    /// BIOS calls, see `swi_trace`
+    swi_trace: Option<SwiTracer>,
+    /// The other end of the link cable
+    serial_device: Box<dyn SerialDevice>,
+    #[cfg(feature = "cheats")]
+    cheats: CheatEngine,
+    #[cfg(feature = "trace")]
+    tracer: Option<TraceWriter<Box<dyn TraceSink>>>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct SaveState {
-    sysbus: Box<SysBus>,
-    interrupt_flags: u16,
-    cpu: arm7tdmi::Core,
+/// Where emulated cycles were spent
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CycleBreakdown {
+    /// Cycles spent executing instructions
+    pub cpu: u64,
+    /// Cycles skipped while the CPU was halted or stopped
+    pub halted: u64,
+    /// Cycles skipped inside the game's idle loop, see `GameProfile`
+    pub idle_loop: u64,
+}
+
+/// Counters since the emulator was created, returned by `GameBoyAdvance::stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct EmulationStats {
+    pub cycles: u64,
+    pub frames: u64,
+    pub emulated_seconds: f64,
+    /// Emulated frames during the last host second, always 0 without `std`
+    pub fps: u32,
+    pub breakdown: CycleBreakdown,
+    pub input_latency: InputLatency,
 }
 
-/// Checks if the bios provided is the real one
-fn check_real_bios(bios: &[u8]) -> bool {
-    use sha2::{Digest, Sha256};
+/// Clock rate of the CPU and of everything it shares the bus with
+pub const CPU_CLOCK_HZ: usize = 16 * 1024 * 1024;
 
-    let mut hasher = Sha256::new();
-    hasher.input(bios);
-    let digest = hasher.result();
+/// Pass to `set_speed_multiplier` to run as fast as possible
+pub const SPEED_UNCAPPED: f32 = f32::INFINITY;
 
-    let expected_hash = hex!("fd2547724b505f487e6dcb29ec2ecff3af35a841a77ab2e85fd87350abd36570");
+/// The host refresh rate `run_host_frame` is paced for
+#[cfg(feature = "std")]
+const HOST_FRAME_TIME: time::Duration = time::Duration::from_micros(1_000_000 / 60);
+/// The frames `run_host_frame` runs when uncapped, there is no clock to pace it without `std`
+#[cfg(not(feature = "std"))]
+const UNCAPPED_FRAMES: usize = 8;
 
-    digest.as_slice() == &expected_hash[..]
+#[cfg(feature = "savestates")]
+#[derive(Serialize, Deserialize)]
+struct SaveState {
+    /// First, so that `state_rom` reads it without the rest
+    rom: RomIdentity,
+    sysbus: Box<SysBus>,
+    interrupt_flags: u16,
+    cpu: arm7tdmi::Core,
+    cycles_to_next_event: usize,
+    overshoot_cycles: usize,
 }
 
 impl GameBoyAdvance {
-    pub fn new(
-        bios_rom: Box<[u8]>,
+    /// Passing an empty `bios_rom` selects the HLE BIOS, which starts right at the cartridge.
+    /// The bios can be any `RomBuffer` source, so a shared buffer is not copied.
+    /// A BIOS that isn't a known dump is only warned about, see `with_bios_check`.
+    pub fn new<B: Into<RomBuffer>>(
+        bios_rom: B,
         gamepak: Cartridge,
-        video_device: Rc<RefCell<dyn VideoInterface>>,
-        audio_device: Rc<RefCell<dyn AudioInterface>>,
-        input_device: Rc<RefCell<dyn InputInterface>>,
+        frontend: Frontend,
     ) -> GameBoyAdvance {
-        // Warn the user if the bios is not the real one
-        match check_real_bios(&bios_rom) {
-            true => info!("Verified bios rom"),
-            false => warn!("This is not the real bios rom, some games may not be compatible"),
+        GameBoyAdvance::with_bios_check(bios_rom, gamepak, frontend, BiosCheck::Warn)
+            .expect("the bios check only warns")
+    }
+
+    /// Same as `new`, with `bios_check` deciding what to do with an unknown or corrupt BIOS
+    pub fn with_bios_check<B: Into<RomBuffer>>(
+        bios_rom: B,
+        gamepak: Cartridge,
+        frontend: Frontend,
+        bios_check: BiosCheck,
+    ) -> CoreResult<GameBoyAdvance> {
+        let bios_rom = bios_rom.into();
+        let hle_bios = bios_rom.is_empty();
+        let (bios_rom, bios_check) = if hle_bios {
+            info!("No bios rom provided, using HLE bios");
+            (bios::hle_bios_image().into(), BiosCheck::Off)
+        } else {
+            (bios_rom, bios_check)
         };
 
-        let interrupt_flags = Rc::new(Cell::new(IrqBitmask(0)));
+        let interrupt_flags = SharedInterruptFlags::new(IrqBitmask(0));
 
         let intc = InterruptController::new(interrupt_flags.clone());
         let gpu = Box::new(Gpu::new(interrupt_flags.clone()));
         let dmac = DmaController::new(interrupt_flags.clone());
         let timers = Timers::new(interrupt_flags.clone());
         let sound_controller = Box::new(SoundController::new(
-            audio_device.borrow().get_sample_rate() as f32,
+            lock(&frontend.audio).get_sample_rate() as f32,
         ));
         let io = IoDevices::new(intc, gpu, dmac, timers, sound_controller);
-        let sysbus = Box::new(SysBus::new(io, bios_rom, gamepak));
+        let sysbus = Box::new(SysBus::new(io, bios_rom, gamepak, bios_check)?);
 
         let cpu = arm7tdmi::Core::new();
 
@@ -83,75 +212,311 @@ impl GameBoyAdvance {
             cpu: cpu,
             sysbus: sysbus,
 
-            video_device: video_device,
-            audio_device: audio_device,
-            input_device: input_device,
+            video_device: frontend.video,
+            audio_device: frontend.audio,
+            input_device: frontend.input,
 
             cycles_to_next_event: 1,
             overshoot_cycles: 0,
             interrupt_flags: interrupt_flags,
+            idle_loop: None,
+            keyinput_override: None,
+            input: InputController::default(),
+            latency_probe: LatencyProbe::default(),
+            time_control: TimeControl::default(),
+            stats: EmulationStats::default(),
+            #[cfg(feature = "std")]
+            fps_counter: util::FpsCounter::default(),
+            crash_report: None,
+            crash_handler: None,
+            dma_hook: None,
+            audio_fifo_hook: None,
+            scanline_hook: None,
+            show_fifo_stats: false,
+            #[cfg(feature = "std")]
+            watch_backup: false,
+            backup_restore: BackupRestore::default(),
+            #[cfg(feature = "savestates")]
+            force_state_load: false,
+            timeline: None,
+            swi_trace: None,
+            serial_device: Box::new(DummyLink::default()),
+            #[cfg(feature = "cheats")]
+            cheats: CheatEngine::default(),
+            #[cfg(feature = "trace")]
+            tracer: None,
         };
 
         gba.sysbus.created();
 
-        gba
+        if hle_bios {
+            gba.sysbus.set_hle_bios(true);
+            gba.skip_bios();
+        }
+
+        gba.idle_loop = gba.sysbus.cartridge.quirks.idle_loop;
+        if let Some(profile) = gba.sysbus.cartridge.profile.clone() {
+            gba.apply_profile(&profile);
+        }
+
+        Ok(gba)
     }
 
+    #[cfg(feature = "savestates")]
     pub fn from_saved_state(
         savestate: &[u8],
-        video_device: Rc<RefCell<dyn VideoInterface>>,
-        audio_device: Rc<RefCell<dyn AudioInterface>>,
-        input_device: Rc<RefCell<dyn InputInterface>>,
+        frontend: Frontend,
     ) -> bincode::Result<GameBoyAdvance> {
+        let mut gba = GameBoyAdvance::decode_state(savestate, frontend)?;
+        gba.reconnect_backup();
+        Ok(gba)
+    }
+
+    /// The machine of `savestate` to look at, its backup memory is the one of the state, left
+    /// apart from the save file. See `state_diff`.
+    #[cfg(feature = "savestates")]
+    pub fn inspect_state(savestate: &[u8]) -> bincode::Result<GameBoyAdvance> {
+        GameBoyAdvance::decode_state(savestate, Frontend::default())
+    }
+
+    #[cfg(feature = "savestates")]
+    fn decode_state(savestate: &[u8], frontend: Frontend) -> bincode::Result<GameBoyAdvance> {
         let decoded: Box<SaveState> = bincode::deserialize_from(savestate)?;
 
         let arm7tdmi = decoded.cpu;
-        let mut sysbus = decoded.sysbus;
-        let interrupts = Rc::new(Cell::new(IrqBitmask(decoded.interrupt_flags)));
-
-        sysbus.io.connect_irq(interrupts.clone());
+        let sysbus = decoded.sysbus;
+        let interrupts = SharedInterruptFlags::new(IrqBitmask(decoded.interrupt_flags));
 
-        Ok(GameBoyAdvance {
+        let mut gba = GameBoyAdvance {
             cpu: arm7tdmi,
             sysbus: sysbus,
 
             interrupt_flags: interrupts,
 
-            video_device: video_device,
-            audio_device: audio_device,
-            input_device: input_device,
-
-            cycles_to_next_event: 1,
+            video_device: frontend.video,
+            audio_device: frontend.audio,
+            input_device: frontend.input,
+
+            cycles_to_next_event: decoded.cycles_to_next_event,
+
+            overshoot_cycles: decoded.overshoot_cycles,
+            idle_loop: None,
+            keyinput_override: None,
+            input: InputController::default(),
+            latency_probe: LatencyProbe::default(),
+            time_control: TimeControl::default(),
+            stats: EmulationStats::default(),
+            #[cfg(feature = "std")]
+            fps_counter: util::FpsCounter::default(),
+            crash_report: None,
+            crash_handler: None,
+            dma_hook: None,
+            audio_fifo_hook: None,
+            scanline_hook: None,
+            show_fifo_stats: false,
+            #[cfg(feature = "std")]
+            watch_backup: false,
+            backup_restore: BackupRestore::default(),
+            force_state_load: false,
+            timeline: None,
+            swi_trace: None,
+            serial_device: Box::new(DummyLink::default()),
+            #[cfg(feature = "cheats")]
+            cheats: CheatEngine::default(),
+            #[cfg(feature = "trace")]
+            tracer: None,
+        };
+        gba.reset_after_load();
 
-            overshoot_cycles: 0,
-        })
+        Ok(gba)
     }
 
+    #[cfg(feature = "savestates")]
     pub fn save_state(&self) -> bincode::Result<Vec<u8>> {
         let s = SaveState {
+            rom: self.sysbus.cartridge.identity(),
             cpu: self.cpu.clone(),
             sysbus: self.sysbus.clone(),
             interrupt_flags: self.interrupt_flags.get().value(),
+            cycles_to_next_event: self.cycles_to_next_event,
+            overshoot_cycles: self.overshoot_cycles,
         };
 
         bincode::serialize(&s)
     }
 
-    pub fn restore_state(&mut self, bytes: &[u8]) -> bincode::Result<()> {
+    /// The ROM `savestate` was made with
+    #[cfg(feature = "savestates")]
+    pub fn state_rom(savestate: &[u8]) -> CoreResult<RomIdentity> {
+        Ok(bincode::deserialize_from(savestate)?)
+    }
+
+    /// Refuses a state made with another ROM, or another version of the game: the game would
+    /// find its RAM laid out differently and crash sooner or later. Such a state is only
+    /// loaded with a warning after `set_force_state_load`.
+    #[cfg(feature = "savestates")]
+    pub fn restore_state(&mut self, bytes: &[u8]) -> CoreResult<()> {
         let decoded: Box<SaveState> = bincode::deserialize_from(bytes)?;
+        if let Some(reason) = decoded.rom.mismatch(&self.sysbus.cartridge.identity()) {
+            if !self.force_state_load {
+                return Err(CoreError::SavestateError(reason));
+            }
+            warn!("loading the state anyway: {}", reason);
+        }
+
+        // These are settings rather than state, keep them across the restore
+        let profile = self.sysbus.cartridge.profile.take();
+        let quirks = core::mem::take(&mut self.sysbus.cartridge.quirks);
+        let color_correction = self.sysbus.io.gpu.color_correction;
+        let interpolation = self.sysbus.io.sound.interpolation;
+        let overlay = core::mem::take(&mut self.sysbus.io.gpu.overlay);
+        let osd = core::mem::take(&mut self.sysbus.io.gpu.osd);
+        let raw_frame = self.sysbus.io.gpu.raw_frame.take();
+        let scaled_frame = self.sysbus.io.gpu.scaled_frame.take();
 
         self.cpu = decoded.cpu;
         self.sysbus = decoded.sysbus;
-        self.interrupt_flags = Rc::new(Cell::new(IrqBitmask(decoded.interrupt_flags)));
+        self.sysbus.cartridge.profile = profile;
+        self.sysbus.cartridge.quirks = quirks;
+        self.sysbus.io.gpu.color_correction = color_correction;
+        self.sysbus.io.sound.interpolation = interpolation;
+        self.sysbus.io.gpu.overlay = overlay;
+        self.sysbus.io.gpu.osd = osd;
+        self.sysbus.io.gpu.raw_frame = raw_frame;
+        self.sysbus.io.gpu.scaled_frame = scaled_frame;
+        self.interrupt_flags = SharedInterruptFlags::new(IrqBitmask(decoded.interrupt_flags));
+        self.cycles_to_next_event = decoded.cycles_to_next_event;
+        self.overshoot_cycles = decoded.overshoot_cycles;
+        self.crash_report = None;
+
+        self.reset_after_load();
+        self.reconnect_backup();
 
-        // Redistribute shared pointer for interrupts
-        self.sysbus.io.connect_irq(self.interrupt_flags.clone());
+        Ok(())
+    }
 
-        self.cycles_to_next_event = 1;
+    /// Hashes of the emulated state by subsystem, the ROM and BIOS left out.
+    /// See `determinism::verify_determinism`.
+    #[cfg(feature = "savestates")]
+    pub fn subsystem_hashes(&self) -> Vec<SubsystemHash> {
+        let mut hashes = vec![
+            SubsystemHash::of(
+                "scheduler",
+                &(self.cycles_to_next_event, self.overshoot_cycles),
+            ),
+            SubsystemHash::of("cpu", &self.cpu),
+            SubsystemHash::of(
+                "interrupts",
+                &(self.interrupt_flags.get().value(), &self.sysbus.io.intc),
+            ),
+        ];
+        self.sysbus.subsystem_hashes(&mut hashes);
+        hashes
+    }
 
-        self.sysbus.created();
+    /// A 64-bit hash of the emulated state, the same on every platform and build of the
+    /// same version. Two machines with the same hash run the same from there, which is
+    /// cheaper to check than comparing savestates. The ROM and BIOS are not part of it.
+    #[cfg(feature = "savestates")]
+    pub fn state_hash(&self) -> u64 {
+        let hashes: Vec<u64> = self
+            .subsystem_hashes()
+            .iter()
+            .map(|subsystem| subsystem.hash)
+            .collect();
+        hash_state(&hashes)
+    }
 
-        Ok(())
+    /// A 64-bit hash of the last frame, as presented: the OSD, overlay and color correction
+    /// are part of it
+    #[cfg(feature = "savestates")]
+    pub fn frame_hash(&self) -> u64 {
+        hash_state(self.get_frame_buffer())
+    }
+
+    /// Reconnects a freshly deserialized sysbus to this instance and the host
+    fn reset_after_load(&mut self) {
+        let ctx = LoadContext {
+            interrupt_flags: self.interrupt_flags.clone(),
+            audio_sample_rate: self.output_sample_rate(),
+        };
+        self.sysbus.reset_after_load(&ctx);
+        self.sysbus
+            .io
+            .dmac
+            .record_events(self.dma_hook.is_some() || self.timeline.is_some());
+        self.sysbus
+            .io
+            .timers
+            .record_overflow_events(self.timeline.is_some());
+        self.sysbus
+            .io
+            .sound
+            .record_fifo_events(self.audio_fifo_hook.is_some());
+        self.sysbus
+            .io
+            .gpu
+            .record_lines(self.scanline_hook.is_some());
+    }
+
+    /// Opens the save file again after loading a state, see `BackupRestore`
+    fn reconnect_backup(&mut self) {
+        if self.sysbus.cartridge.reconnect_backup(self.backup_restore) {
+            match self.backup_restore {
+                BackupRestore::FromState => {
+                    self.notify("Save file replaced with the save of the state")
+                }
+                BackupRestore::KeepOnDisk => {
+                    warn!(target: logging::CART, "the save of the state differs from the save file, keeping the file");
+                    self.notify("Kept the save file, the state has another save")
+                }
+            }
+        }
+    }
+
+    /// Applies the emulation settings of a game profile
+    pub fn apply_profile(&mut self, profile: &GameProfile) {
+        if let Some(color_correction) = profile.color_correction {
+            self.sysbus.io.gpu.color_correction = color_correction;
+        }
+        if profile.idle_loop.is_some() {
+            self.idle_loop = profile.idle_loop;
+        }
+    }
+
+    /// Applies the settings that can change while the game runs. The ones used to build the
+    /// cartridge, and skipping the BIOS, are up to the frontend.
+    #[cfg(feature = "std")]
+    pub fn apply_config(&mut self, config: &Config) {
+        self.set_audio_interpolation(config.interpolation());
+        self.set_backup_watch(config.emulation.watch_save);
+        self.set_backup_restore(config.backup_restore());
+        #[cfg(feature = "savestates")]
+        self.set_force_state_load(config.emulation.force_state_load);
+        self.set_serial_device(Box::new(config.link_cable()));
+        self.sysbus.io.gpu.color_correction = config.video.color_correction;
+        self.input.analog_deadzone = config.input.analog_deadzone;
+        self.input.dpad_filter = config.dpad_filter();
+        // the game profile is more specific
+        if let Some(profile) = self.sysbus.cartridge.profile.clone() {
+            self.apply_profile(&profile);
+        }
+    }
+
+    /// Plugs `device` into the link port, the games see a transfer in progress complete with it
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial_device = device;
+    }
+
+    pub fn set_idle_loop(&mut self, addr: Option<u32>) {
+        self.idle_loop = addr;
+    }
+
+    fn in_idle_loop(&self, io: &IoDevices) -> bool {
+        match self.idle_loop {
+            Some(addr) => (addr & !1) == self.cpu.get_next_pc() && !io.intc.irq_pending(),
+            None => false,
+        }
     }
 
     pub fn get_game_title(&self) -> String {
@@ -162,15 +527,263 @@ impl GameBoyAdvance {
         self.sysbus.cartridge.header.game_code.clone()
     }
 
+    /// Takes a copy of the last displayed frame
+    pub fn screenshot(&self) -> Screenshot {
+        Screenshot::new(
+            self.get_frame_buffer(),
+            self.get_game_code(),
+            self.get_game_title(),
+            self.sysbus.io.gpu.frame_count,
+        )
+    }
+
+    /// Keeps the frame in 15-bit colors along with the layer of every pixel, see `raw_frame`
+    pub fn set_raw_frame_enabled(&mut self, enable: bool) {
+        self.sysbus.io.gpu.set_raw_frame_enabled(enable);
+    }
+
+    /// The last drawn frame before the conversion to RGB, row by row. The pixels keep their
+    /// color before the color special effects and the layer they come from, for palette aware
+    /// shaders or to extract datasets.
+    pub fn raw_frame(&self) -> Option<&[RawPixel]> {
+        self.sysbus.io.gpu.raw_frame.as_deref()
+    }
+
+    /// Draws every frame a second time, `scale` times larger, with the affine backgrounds and
+    /// objects sampled between the pixels. Up to `MAX_RENDER_SCALE`, 1 turns it off.
+    pub fn set_render_scale(&mut self, scale: usize) {
+        self.sysbus.io.gpu.set_render_scale(scale);
+    }
+
+    /// The last drawn frame at the render scale, `None` if the scale is 1
+    pub fn scaled_frame_buffer(&self) -> Option<&[u32]> {
+        self.sysbus
+            .io
+            .gpu
+            .scaled_frame
+            .as_ref()
+            .map(|frame| frame.buffer.as_slice())
+    }
+
+    /// Whether the last displayed frame is the same as the one before it
+    pub fn frame_unchanged(&self) -> bool {
+        self.sysbus.io.gpu.frame_unchanged()
+    }
+
+    /// Shows a message on the on-screen display
+    pub fn notify(&mut self, message: &str) {
+        info!("{}", message);
+        self.sysbus.io.gpu.osd.notify(message);
+    }
+
+    pub fn osd_mut(&mut self) -> &mut Osd {
+        &mut self.sysbus.io.gpu.osd
+    }
+
+    /// Reads and writes the memory, without side effects unless switched to `AccessMode::Bus`
+    pub fn mem(&mut self) -> Memory<'_> {
+        Memory::new(&mut self.sysbus)
+    }
+
+    /// The cheats applied at the start of every frame, see `cheats`
+    #[cfg(feature = "cheats")]
+    pub fn cheats(&self) -> &CheatEngine {
+        &self.cheats
+    }
+
+    #[cfg(feature = "cheats")]
+    pub fn cheats_mut(&mut self) -> &mut CheatEngine {
+        &mut self.cheats
+    }
+
+    /// Replaces the cheats with those of a cheat file
+    #[cfg(all(feature = "std", feature = "cheats"))]
+    pub fn load_cheats(&mut self, path: &Path) -> CoreResult<()> {
+        let cheats = CheatEngine::load(path)?;
+        if let Some(game_code) = &cheats.game_code {
+            if *game_code != self.get_game_code() {
+                warn!(
+                    "{} holds the cheats of {}, not {}",
+                    path.display(),
+                    game_code,
+                    self.get_game_code()
+                );
+            }
+        }
+        self.cheats = cheats;
+        Ok(())
+    }
+
+    /// Adds the cheats of another emulator's cheat list, see `cheat_import`
+    #[cfg(all(feature = "std", feature = "cheats"))]
+    pub fn import_cheats(&mut self, path: &Path) -> CoreResult<ImportReport> {
+        let report = cheat_import::import_file(path)?;
+        for cheat in &report.cheats {
+            self.cheats.add(cheat.clone())?;
+        }
+        Ok(report)
+    }
+
+    /// Writes the cheats to a cheat file, for this game
+    #[cfg(all(feature = "std", feature = "cheats"))]
+    pub fn save_cheats(&mut self, path: &Path) -> CoreResult<()> {
+        self.cheats.game_code = Some(self.get_game_code());
+        self.cheats.save(path)
+    }
+
+    /// Forces the KEYINPUT value seen by the game, `None` goes back to polling the input device
+    pub fn set_keyinput_override(&mut self, keyinput: Option<u16>) {
+        self.keyinput_override = keyinput;
+    }
+
+    pub fn keyinput_override(&self) -> Option<u16> {
+        self.keyinput_override
+    }
+
+    /// Presses exactly `keys` during frame number `frame`, see `InputController`
+    pub fn queue_input_at_frame(&mut self, frame: usize, keys: &[Keys]) {
+        self.input.queue_input_at_frame(frame, keys);
+    }
+
+    /// Tells that the frontend just changed the keys its `InputSource` returns, for the input
+    /// latency stats to start from there. Otherwise they start when the change is polled.
+    pub fn input_changed(&mut self) {
+        self.latency_probe.key_set();
+    }
+
     #[inline]
     pub fn key_poll(&mut self) {
-        self.sysbus.io.keyinput = self.input_device.borrow_mut().poll();
+        let polled = lock(&self.input_device).poll();
+        let keyinput = self.input.process(self.sysbus.io.gpu.frame_count, polled);
+        let keyinput = self.keyinput_override.unwrap_or(keyinput);
+        self.latency_probe
+            .polled(keyinput != self.sysbus.io.keyinput);
+        self.sysbus.io.keyinput = keyinput;
+        self.sysbus.io.keyinput_read.set(false);
+        self.sysbus.io.update_keypad_irq();
+    }
+
+    /// The game put the console to sleep in Stop mode, turning off the cpu, the screen and the
+    /// sound until a key press (or the link port, or the gamepak) wakes it up. `frame` only
+    /// polls the keys meanwhile, so the frontend may as well wait for input events.
+    pub fn is_sleeping(&self) -> bool {
+        self.sysbus.io.haltcnt == HaltState::Stop && self.crash_report.is_none()
+    }
+
+    /// Sets the emulation speed used by `run_host_frame`, 1.0 being real time.
+    /// `SPEED_UNCAPPED` (or any non positive value) runs as fast as possible.
+    pub fn set_speed_multiplier(&mut self, speed: f32) {
+        self.time_control.set_speed(speed);
+    }
+
+    pub fn speed_multiplier(&self) -> f32 {
+        self.time_control.speed()
+    }
+
+    /// Pause, slow motion, turbo and frame advance, applied by `run_host_frame`
+    pub fn time_control(&self) -> &TimeControl {
+        &self.time_control
+    }
+
+    pub fn time_control_mut(&mut self) -> &mut TimeControl {
+        &mut self.time_control
+    }
+
+    /// Smoothing of the DirectSound channels, none by default like the hardware
+    pub fn set_audio_interpolation(&mut self, interpolation: Interpolation) {
+        self.sysbus.io.sound.interpolation = interpolation;
+    }
+
+    /// Slow motion stretches the audio so the audio device doesn't starve
+    fn output_sample_rate(&self) -> f32 {
+        let sample_rate = lock(&self.audio_device).get_sample_rate() as f32;
+        sample_rate * self.time_control.audio_stretch()
+    }
+
+    /// Emulates the frames that fit in one host frame (1/60 second) at the current speed, see
+    /// `TimeControl`.
+    ///
+    /// Only the first of those frames is presented, its audio included. The rest run with
+    /// rendering and audio output turned off, so fast-forward keeps a normal audio pitch and
+    /// doesn't flood the audio device. When uncapped, frames are run until a host frame worth
+    /// of time has passed, which keeps the frontend presenting at 60Hz. Without `std` there is
+    /// no clock to tell, `UNCAPPED_FRAMES` are run instead.
+    /// Returns the number of emulated frames, 0 while paused.
+    pub fn run_host_frame(&mut self) -> usize {
+        if self.time_control.take_speed_change() {
+            let sample_rate = self.output_sample_rate();
+            self.sysbus.io.sound.set_output_sample_rate(sample_rate);
+        }
+        let frames = match self.time_control.next_host_frame() {
+            HostFrame::Frames(frames) => {
+                for i in 0..frames {
+                    self.set_frame_skipping(i != 0);
+                    self.frame();
+                }
+                frames
+            }
+            HostFrame::Uncapped => {
+                #[cfg(feature = "std")]
+                let start = util::now();
+                #[cfg(feature = "std")]
+                let host_frame_left = |_| start.elapsed() < HOST_FRAME_TIME;
+                #[cfg(not(feature = "std"))]
+                let host_frame_left = |frames| frames < UNCAPPED_FRAMES;
+                self.frame();
+                let mut frames = 1;
+                self.set_frame_skipping(true);
+                while host_frame_left(frames) && !self.is_sleeping() {
+                    self.frame();
+                    frames += 1;
+                }
+                frames
+            }
+        };
+        self.set_frame_skipping(false);
+        frames
+    }
+
+    pub(crate) fn set_frame_skipping(&mut self, skip: bool) {
+        self.sysbus.io.gpu.skip_frames = skip;
+        self.sysbus.io.sound.mute_output = skip;
+    }
+
+    /// Returns the emulated time and where it was spent.
+    /// The counters are not part of savestates, restoring a state keeps them running.
+    pub fn stats(&self) -> EmulationStats {
+        let cycles =
+            self.stats.breakdown.cpu + self.stats.breakdown.halted + self.stats.breakdown.idle_loop;
+        EmulationStats {
+            cycles,
+            emulated_seconds: cycles as f64 / CPU_CLOCK_HZ as f64,
+            ..self.stats
+        }
     }
 
     pub fn frame(&mut self) {
         self.key_poll();
+        if self.is_sleeping() {
+            if !self.sysbus.io.intc.stop_released() {
+                return;
+            }
+            self.sysbus.io.haltcnt = HaltState::Running;
+        }
+        #[cfg(feature = "cheats")]
+        {
+            if !self.cheats.is_empty() {
+                self.cheats.apply(&mut Memory::new(&mut self.sysbus));
+            }
+        }
+        self.stats.frames += 1;
+        #[cfg(feature = "std")]
+        {
+            if let Some(fps) = self.fps_counter.tick() {
+                self.stats.fps = fps;
+            }
+        }
 
         let mut remaining_cycles = CYCLES_FULL_REFRESH - self.overshoot_cycles;
+        self.overshoot_cycles = 0;
 
         while remaining_cycles > 0 {
             let cycles = self.step();
@@ -178,11 +791,24 @@ impl GameBoyAdvance {
                 remaining_cycles -= cycles;
             } else {
                 self.overshoot_cycles = cycles - remaining_cycles;
-                return;
+                break;
             }
         }
 
-        self.overshoot_cycles = 0;
+        self.latency_probe.frame_done(
+            self.sysbus.io.keyinput_read.get(),
+            &mut self.stats.input_latency,
+        );
+        self.report_fifo_events();
+        #[cfg(feature = "std")]
+        {
+            if self.watch_backup {
+                self.poll_backup_file();
+            }
+        }
+        if let Some(timeline) = &mut self.timeline {
+            timeline.end_frame();
+        }
     }
 
     pub fn add_breakpoint(&mut self, addr: u32) -> Option<usize> {
@@ -206,19 +832,374 @@ impl GameBoyAdvance {
         None
     }
 
+    /// Sets the contents of the memories and registers left undefined at power-on.
+    /// Should be called right after creating the emulator (and after `skip_bios`).
+    pub fn apply_init_options(&mut self, options: &InitOptions) {
+        self.sysbus.apply_init_options(options);
+        self.cpu.gpr[0..13].copy_from_slice(&options.registers);
+    }
+
+    /// Starts from the cartridge with the hardware in the state the BIOS boot sequence leaves it,
+    /// whether or not a real BIOS was provided
     pub fn skip_bios(&mut self) {
         self.cpu.skip_bios();
-        self.sysbus.io.gpu.skip_bios();
+        self.sysbus.skip_bios();
+        self.sysbus.io.skip_bios();
+        self.cpu.reload_pipeline32(&mut self.sysbus);
     }
 
     pub fn step_cpu(&mut self, io: &mut IoDevices) -> usize {
         if io.intc.irq_pending() {
+            if let Some(timeline) = &mut self.timeline {
+                if !self.cpu.cpsr.irq_disabled() {
+                    let serviced =
+                        io.intc.interrupt_flags.get().value() & io.intc.interrupt_enable.0;
+                    timeline.push(TimelineEventKind::IrqServiced(serviced));
+                }
+            }
+            if let Some(swi_trace) = &mut self.swi_trace {
+                if !self.cpu.cpsr.irq_disabled() {
+                    swi_trace.interrupted();
+                }
+            }
             self.cpu.irq(&mut self.sysbus);
             io.haltcnt = HaltState::Running;
         }
+        #[cfg(feature = "trace")]
+        let traced = self.trace_begin();
+        let swi = self.swi_trace_begin();
         let previous_cycles = self.cpu.cycles;
         self.cpu.step(&mut self.sysbus);
-        self.cpu.cycles - previous_cycles
+        let cycles = self.cpu.cycles - previous_cycles;
+        #[cfg(feature = "trace")]
+        self.trace_end(traced);
+        self.swi_trace_end(swi);
+        if let Some(timeline) = &mut self.timeline {
+            timeline.advance(cycles);
+        }
+        self.stats.breakdown.cpu += cycles as u64;
+        if let Some(fault) = self.cpu.fault.take() {
+            self.crash(io, fault);
+        }
+        cycles
+    }
+
+    /// Stops the cpu, the rest of the hardware keeps running so the frontend stays responsive
+    fn crash(&mut self, io: &mut IoDevices, fault: arm7tdmi::CpuFault) {
+        let report = CrashReport::new(&self.cpu, &self.sysbus, fault);
+        error!(
+            "cpu crashed: {} (opcode {:#x} at {:#010x})",
+            report.reason, report.opcode, report.pc
+        );
+        io.haltcnt = HaltState::Stop;
+        match &mut self.crash_handler {
+            Some(handler) => handler(&report),
+            #[cfg(feature = "std")]
+            None => error!("{}", report.to_json()),
+            #[cfg(not(feature = "std"))]
+            None => error!("{:?}", report),
+        }
+        self.crash_report = Some(report);
+    }
+
+    /// Writes every executed instruction and its memory accesses to `out`, see `trace`
+    #[cfg(feature = "trace")]
+    pub fn start_trace<W: TraceSink + 'static>(&mut self, out: W) -> io::Result<()> {
+        let out: Box<dyn TraceSink> = Box::new(out);
+        self.tracer = Some(TraceWriter::new(out)?);
+        Ok(())
+    }
+
+    /// Stops the trace and flushes it
+    #[cfg(feature = "trace")]
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        *self.sysbus.trace_log.get_mut() = None;
+        match self.tracer.take() {
+            Some(mut tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    /// Starts recording the accesses of the next instruction, returns what the trace entry
+    /// needs from before it executes: (cycle, pc, opcode, thumb)
+    #[cfg(feature = "trace")]
+    fn trace_begin(&mut self) -> Option<(u64, u32, u32, bool)> {
+        self.tracer.as_ref()?;
+        match self.sysbus.trace_log.get_mut() {
+            Some(log) => log.clear(),
+            log => *log = Some(Vec::new()),
+        }
+        Some((
+            self.cpu.cycles as u64,
+            self.cpu.get_next_pc(),
+            self.cpu.get_next_opcode(),
+            self.cpu.get_cpu_state() == arm7tdmi::CpuState::THUMB,
+        ))
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace_end(&mut self, traced: Option<(u64, u32, u32, bool)>) {
+        let (cycle, pc, opcode, thumb) = match traced {
+            Some(traced) => traced,
+            None => return,
+        };
+        let accesses = self.sysbus.trace_log.get_mut().as_deref().unwrap_or(&[]);
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.write_entry(cycle, pc, opcode, thumb, accesses) {
+                error!("failed to write the trace, tracing stopped: {}", e);
+                self.tracer = None;
+                *self.sysbus.trace_log.get_mut() = None;
+            }
+        }
+    }
+
+    /// Logs the BIOS calls and their results, and in `SwiTraceMode::CrossCheck` compares the
+    /// real BIOS with the HLE, see `swi_trace`
+    pub fn set_swi_trace(&mut self, mode: Option<SwiTraceMode>) {
+        self.swi_trace = mode.map(SwiTracer::new);
+        self.cpu.last_swi = None;
+    }
+
+    /// The differences found between the HLE and the BIOS, empty unless cross-checking
+    pub fn swi_mismatches(&self) -> &[SwiMismatch] {
+        match &self.swi_trace {
+            Some(swi_trace) => swi_trace.mismatches(),
+            None => &[],
+        }
+    }
+
+    /// The state before a swi: its address, r0-r3, the mode of the caller and what the HLE
+    /// makes of it when cross-checking
+    fn swi_trace_begin(&mut self) -> Option<SwiStart> {
+        let mode = self.swi_trace.as_ref()?.mode();
+        let opcode = self.cpu.get_next_opcode();
+        let is_swi = match self.cpu.get_cpu_state() {
+            arm7tdmi::CpuState::ARM => opcode & 0x0f00_0000 == 0x0f00_0000,
+            arm7tdmi::CpuState::THUMB => opcode & 0xff00 == 0xdf00,
+        };
+        if !is_swi {
+            return None;
+        }
+        let mut args = [0; 4];
+        args.copy_from_slice(&self.cpu.gpr[..4]);
+        let hle = if mode == SwiTraceMode::CrossCheck && !self.sysbus.is_hle_bios() {
+            // the comment field of the swi, as the BIOS decodes it
+            let number = match self.cpu.get_cpu_state() {
+                arm7tdmi::CpuState::ARM => (opcode >> 16) & 0xff,
+                arm7tdmi::CpuState::THUMB => opcode & 0xff,
+            };
+            if bios::hle_implements(number) {
+                HleOutcome::run(&self.cpu, &self.sysbus, number)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        Some((self.cpu.get_next_pc(), args, self.cpu.cpsr.mode(), hle))
+    }
+
+    fn swi_trace_end(&mut self, swi: Option<SwiStart>) {
+        let swi_trace = match &mut self.swi_trace {
+            Some(swi_trace) => swi_trace,
+            None => return,
+        };
+        if let (Some((addr, args, mode, hle)), Some((return_addr, number))) =
+            (swi, self.cpu.last_swi.take())
+        {
+            swi_trace.call(SwiCall { number, addr, args }, return_addr, mode, hle);
+        }
+        swi_trace.check_return(&self.cpu, &self.sysbus);
+    }
+
+    /// Called with the crash report when the cpu hits an unrecoverable error
+    pub fn set_crash_handler(&mut self, handler: CrashHandler) {
+        self.crash_handler = Some(handler);
+    }
+
+    /// The report of the crash that stopped the cpu, if any
+    pub fn crash_report(&self) -> Option<&CrashReport> {
+        self.crash_report.as_ref()
+    }
+
+    /// Called on every start and end of a DMA transfer, `None` removes the hook
+    pub fn set_dma_hook(&mut self, hook: Option<DmaHook>) {
+        self.sysbus
+            .io
+            .dmac
+            .record_events(hook.is_some() || self.timeline.is_some());
+        self.dma_hook = hook;
+    }
+
+    /// Called at the end of each frame with the DirectSound FIFO underruns and overruns that
+    /// happened during the frame, `None` removes the hook
+    pub fn set_audio_fifo_hook(&mut self, hook: Option<FifoHook>) {
+        self.sysbus.io.sound.record_fifo_events(hook.is_some());
+        self.audio_fifo_hook = hook;
+    }
+
+    /// Called with every line right after the GPU draws it, for beam racing or per line
+    /// effects. Lines aren't drawn while skipping frames. `None` removes the hook
+    pub fn set_scanline_hook(&mut self, hook: Option<ScanlineHook>) {
+        self.sysbus.io.gpu.record_lines(hook.is_some());
+        self.scanline_hook = hook;
+    }
+
+    fn report_scanlines(&mut self) {
+        if let Some(hook) = &mut self.scanline_hook {
+            for (line, colors) in self.sysbus.io.gpu.take_drawn_lines() {
+                hook(line, &colors);
+            }
+        }
+    }
+
+    /// Shows the DirectSound FIFO underrun and overrun counts on the OSD
+    pub fn show_fifo_stats(&mut self, show: bool) {
+        self.show_fifo_stats = show;
+        if !show {
+            self.sysbus.io.gpu.osd.set_status("fifo", None);
+        }
+    }
+
+    /// Checks the save file once per frame, and reloads it when another program (a save
+    /// editor for instance) changed it. The game keeps running with the new save, what it
+    /// saved in the meantime is lost.
+    #[cfg(feature = "std")]
+    pub fn set_backup_watch(&mut self, enable: bool) {
+        self.watch_backup = enable;
+    }
+
+    /// Whether loading a savestate overwrites the save file with the save of the state, or
+    /// keeps the save file. Either way the OSD tells when they differed.
+    pub fn set_backup_restore(&mut self, restore: BackupRestore) {
+        self.backup_restore = restore;
+    }
+
+    /// Loads the states made with another ROM or a patched one, with a warning rather than
+    /// refusing them, see `restore_state`
+    #[cfg(feature = "savestates")]
+    pub fn set_force_state_load(&mut self, force: bool) {
+        self.force_state_load = force;
+    }
+
+    pub fn backup_restore(&self) -> BackupRestore {
+        self.backup_restore
+    }
+
+    /// Reads the save file again, see `set_backup_watch` to do it automatically
+    #[cfg(feature = "std")]
+    pub fn reload_backup(&mut self) -> io::Result<()> {
+        self.sysbus.cartridge.reload_backup()
+    }
+
+    #[cfg(feature = "std")]
+    fn poll_backup_file(&mut self) {
+        let cartridge = &mut self.sysbus.cartridge;
+        if cartridge.backup_busy() || !cartridge.backup_changed_on_disk() {
+            return;
+        }
+        match cartridge.reload_backup() {
+            Ok(()) => self.notify("Save file reloaded"),
+            Err(err) => error!(target: logging::CART, "can't reload the save file: {}", err),
+        }
+    }
+
+    fn report_fifo_events(&mut self) {
+        if let Some(hook) = &mut self.audio_fifo_hook {
+            for event in self.sysbus.io.sound.take_fifo_events() {
+                hook(&event);
+            }
+        }
+        if self.show_fifo_stats {
+            let [a, b] = self.sysbus.io.sound.fifo_stats();
+            let text = format!(
+                "A {}u {}o B {}u {}o",
+                a.underruns, a.overruns, b.underruns, b.overruns
+            );
+            self.sysbus.io.gpu.osd.set_status("fifo", Some(&text));
+        }
+    }
+
+    fn perform_dma(&mut self) {
+        let io = unsafe {
+            let ptr = &mut *self.sysbus as *mut SysBus;
+            &mut (*ptr).io as &mut IoDevices
+        };
+        io.dmac.perform_work(&mut self.sysbus);
+        let events = io.dmac.take_events();
+        if let Some(timeline) = &mut self.timeline {
+            for event in &events {
+                timeline.push(match *event {
+                    DmaEvent::Started { channel, transfer } => TimelineEventKind::DmaStarted {
+                        channel,
+                        fifo: transfer.fifo,
+                    },
+                    DmaEvent::Stopped { channel, .. } => TimelineEventKind::DmaStopped { channel },
+                });
+            }
+            timeline.check_irqs(self.interrupt_flags.get().value());
+        }
+        if let Some(hook) = &mut self.dma_hook {
+            for event in &events {
+                hook(event);
+            }
+        }
+    }
+
+    /// Records the hardware events of every frame, see `timeline`
+    pub fn set_timeline_enabled(&mut self, enable: bool) {
+        self.timeline = if enable {
+            Some(Timeline::new(self.interrupt_flags.get().value()))
+        } else {
+            None
+        };
+        self.sysbus
+            .io
+            .dmac
+            .record_events(enable || self.dma_hook.is_some());
+        self.sysbus.io.timers.record_overflow_events(enable);
+    }
+
+    /// The hardware events of the last frame, empty when the timeline is disabled
+    pub fn last_frame_events(&self) -> &[TimelineEvent] {
+        match &self.timeline {
+            Some(timeline) => timeline.last_frame(),
+            None => &[],
+        }
+    }
+
+    /// Records what the devices did during the last step, after they were updated
+    fn update_timeline(&mut self, gpu_state: GpuState) {
+        let timeline = match &mut self.timeline {
+            Some(timeline) => timeline,
+            None => return,
+        };
+        let io = &mut self.sysbus.io;
+        for overflow in io.timers.take_overflow_events() {
+            timeline.push(TimelineEventKind::TimerOverflow {
+                timer: overflow.timer,
+                count: overflow.count,
+            });
+        }
+        if io.gpu.state != gpu_state {
+            match io.gpu.state {
+                GpuState::HBlank | GpuState::VBlankHBlank => {
+                    timeline.push(TimelineEventKind::HBlank {
+                        line: io.gpu.vcount,
+                    })
+                }
+                GpuState::VBlankHDraw if gpu_state == GpuState::HBlank => {
+                    timeline.push(TimelineEventKind::VBlank)
+                }
+                _ => {}
+            }
+        }
+        timeline.check_irqs(self.interrupt_flags.get().value());
     }
 
     pub fn step(&mut self) -> usize {
@@ -229,19 +1210,31 @@ impl GameBoyAdvance {
         };
 
         let mut cycles_left = self.cycles_to_next_event;
-        let mut cycles_to_next_event = std::usize::MAX;
+        let mut cycles_to_next_event = usize::MAX;
         let mut cycles = 0;
 
         while cycles_left > 0 {
+            if io.haltcnt == HaltState::Halt && io.intc.halt_released() {
+                io.haltcnt = HaltState::Running;
+            }
             let _cycles = if !io.dmac.is_active() {
-                if HaltState::Running == io.haltcnt {
+                if HaltState::Running == io.haltcnt && !self.in_idle_loop(io) {
                     self.step_cpu(io)
                 } else {
-                    cycles = cycles_left;
+                    // skip right to the next event
+                    if HaltState::Running == io.haltcnt {
+                        self.stats.breakdown.idle_loop += cycles_left as u64;
+                    } else {
+                        self.stats.breakdown.halted += cycles_left as u64;
+                    }
+                    if let Some(timeline) = &mut self.timeline {
+                        timeline.advance(cycles_left);
+                    }
+                    cycles += cycles_left;
                     break;
                 }
             } else {
-                io.dmac.perform_work(&mut self.sysbus);
+                self.perform_dma();
                 return cycles;
             };
 
@@ -253,7 +1246,9 @@ impl GameBoyAdvance {
         }
 
         // update gpu & sound
+        let gpu_state = io.gpu.state;
         io.timers.update(cycles, &mut self.sysbus);
+        io.sio.update(cycles, self.serial_device.as_mut());
         io.gpu.update(
             cycles,
             &mut cycles_to_next_event,
@@ -263,6 +1258,11 @@ impl GameBoyAdvance {
         io.sound
             .update(cycles, &mut cycles_to_next_event, &self.audio_device);
         self.cycles_to_next_event = cycles_to_next_event;
+        self.report_scanlines();
+        self.update_timeline(gpu_state);
+        if self.sysbus.take_hard_reset_request() {
+            self.hard_reset();
+        }
 
         cycles
     }
@@ -279,7 +1279,7 @@ impl GameBoyAdvance {
 
         // clear any pending DMAs
         while io.dmac.is_active() {
-            io.dmac.perform_work(&mut self.sysbus);
+            self.perform_dma();
         }
 
         let cycles = self.step_cpu(io);
@@ -287,7 +1287,9 @@ impl GameBoyAdvance {
 
         let mut _ignored = 0;
         // update gpu & sound
+        let gpu_state = io.gpu.state;
         io.timers.update(cycles, &mut self.sysbus);
+        io.sio.update(cycles, self.serial_device.as_mut());
         io.gpu.update(
             cycles,
             &mut _ignored,
@@ -295,59 +1297,900 @@ impl GameBoyAdvance {
             &self.video_device,
         );
         io.sound.update(cycles, &mut _ignored, &self.audio_device);
+        self.report_scanlines();
+        self.update_timeline(gpu_state);
+        if self.sysbus.take_hard_reset_request() {
+            self.hard_reset();
+        }
 
         breakpoint
     }
 
     /// Query the emulator for the recently drawn framebuffer.
-    /// for use with implementations where the VideoInterface is not a viable option.
+    /// for use with implementations where the VideoSink is not a viable option.
     pub fn get_frame_buffer(&self) -> &[u32] {
         self.sysbus.io.gpu.get_frame_buffer()
     }
 
-    /// Reset the emulator
+    /// Resets the cpu alone, through the reset vector of the BIOS, see `hard_reset` for the
+    /// whole console
     pub fn soft_reset(&mut self) {
         self.cpu.reset(&mut self.sysbus);
     }
+
+    /// Power-cycles the console: the cpu, the work RAMs and the IO devices start over, while
+    /// the ROM and BIOS stay loaded, the save memory and RTC keep their contents and the
+    /// settings and hooks of the emulator are kept. With the HLE BIOS the game starts right
+    /// away, otherwise the BIOS boots it again.
+    pub fn hard_reset(&mut self) {
+        let interrupt_flags = SharedInterruptFlags::new(IrqBitmask(0));
+        let intc = InterruptController::new(interrupt_flags.clone());
+        let mut gpu = Box::new(Gpu::new(interrupt_flags.clone()));
+        let old_gpu = &mut self.sysbus.io.gpu;
+        gpu.color_correction = old_gpu.color_correction;
+        gpu.overlay = core::mem::take(&mut old_gpu.overlay);
+        gpu.osd = core::mem::take(&mut old_gpu.osd);
+        gpu.raw_frame = old_gpu.raw_frame.take();
+        gpu.scaled_frame = old_gpu.scaled_frame.take();
+        let dmac = DmaController::new(interrupt_flags.clone());
+        let timers = Timers::new(interrupt_flags.clone());
+        let mut sound_controller = Box::new(SoundController::new(self.output_sample_rate()));
+        sound_controller.interpolation = self.sysbus.io.sound.interpolation;
+        let io = IoDevices::new(intc, gpu, dmac, timers, sound_controller);
+
+        self.sysbus.hard_reset(io);
+        self.cpu = arm7tdmi::Core::new();
+        self.interrupt_flags = interrupt_flags;
+        self.cycles_to_next_event = 1;
+        self.overshoot_cycles = 0;
+        self.crash_report = None;
+        self.reset_after_load();
+        self.reconnect_backup();
+        if self.sysbus.is_hle_bios() {
+            self.skip_bios();
+        }
+    }
+
+    /// Replaces the ROM with the one of `gamepak` while the game runs, keeping the CPU, RAM,
+    /// IO and save state, see `rom_watch`. The settings of the new game take effect.
+    pub fn swap_rom(&mut self, gamepak: Cartridge) {
+        self.sysbus.cartridge.replace_rom(gamepak);
+        self.idle_loop = self.sysbus.cartridge.quirks.idle_loop;
+        if let Some(profile) = self.sysbus.cartridge.profile.clone() {
+            self.apply_profile(&profile);
+        }
+        self.cpu.refill_pipeline(&mut self.sysbus);
+    }
+
+    /// Pulls the cartridge out of the running console, raising the gamepak interrupt that a few
+    /// games catch for an easter egg. The gamepak bus reads open bus until `swap_rom` puts a
+    /// cartridge back.
+    pub fn remove_cartridge(&mut self) {
+        if self.sysbus.cartridge.is_removed() {
+            return;
+        }
+        self.sysbus.cartridge.remove();
+        signal_irq(&self.interrupt_flags, Interrupt::GamePak);
+    }
+
+    pub fn is_cartridge_removed(&self) -> bool {
+        self.sysbus.cartridge.is_removed()
+    }
+}
+
+/// Fixtures for the tests of the whole crate
+#[cfg(test)]
+pub(crate) mod test_util {
+    use super::super::cartridge::{header, Cartridge, GamepakBuilder};
+    use super::{Frontend, GameBoyAdvance};
+
+    /// `code` at the start of a ROM of at least 0x200 bytes, with a valid header checksum
+    pub(crate) fn make_test_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = code.to_vec();
+        if rom.len() < 0x200 {
+            rom.resize(0x200, 0);
+        }
+        header::fix_checksum(&mut rom);
+        rom
+    }
+
+    /// Same, from ARM opcodes
+    pub(crate) fn make_arm_rom(code: &[u32]) -> Vec<u8> {
+        let bytes: Vec<u8> = code
+            .iter()
+            .flat_map(|word| word.to_le_bytes().to_vec())
+            .collect();
+        make_test_rom(&bytes)
+    }
+
+    /// A cartridge with SRAM and no save file
+    pub(crate) fn make_test_cartridge(code: &[u8]) -> Cartridge {
+        GamepakBuilder::new()
+            .buffer(&make_test_rom(code))
+            .with_sram()
+            .without_backup_to_file()
+            .build()
+            .unwrap()
+    }
+
+    pub(crate) fn make_mock_gba(code: &[u8]) -> GameBoyAdvance {
+        make_mock_gba_with_bios(vec![0; 0x4000].into_boxed_slice(), code)
+    }
+
+    /// Starts from the cartridge, the BIOS skipped
+    pub(crate) fn make_mock_gba_with_bios(bios: Box<[u8]>, code: &[u8]) -> GameBoyAdvance {
+        let mut gba = GameBoyAdvance::new(bios, make_test_cartridge(code), Frontend::default());
+        gba.skip_bios();
+
+        gba
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::test_util::*;
     use super::*;
-    use std::cell::RefCell;
-    use std::rc::Rc;
 
     use super::super::bus::Bus;
-    use super::super::cartridge::GamepakBuilder;
 
-    struct DummyInterface {}
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_restore_state_reconnects_interrupts() {
+        let gba = make_mock_gba(&[0; 0x200]);
+        let state = gba.save_state().unwrap();
+        let restored = GameBoyAdvance::from_saved_state(&state, Frontend::default()).unwrap();
+
+        assert!(restored
+            .interrupt_flags
+            .ptr_eq(&restored.sysbus.io.intc.interrupt_flags));
+    }
 
-    impl DummyInterface {
-        fn new() -> DummyInterface {
-            DummyInterface {}
-        }
+    #[test]
+    fn test_ram_slices() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        gba.sysbus.write_16(0x0200_0010, 0x1234);
+        gba.sysbus.write_8(0x0300_7fff, 0x56);
+        gba.sysbus.write_16(0x0601_7ffe, 0x789a);
+        assert_eq!(gba.sysbus.ewram()[0x10..0x12], [0x34, 0x12]);
+        assert_eq!(gba.sysbus.iwram()[0x7fff], 0x56);
+        assert_eq!(gba.sysbus.vram()[0x1_7ffe..0x1_8000], [0x9a, 0x78]);
+
+        gba.sysbus.ewram_mut()[0x3_ffff] = 1;
+        gba.sysbus.iwram_mut()[0] = 2;
+        gba.sysbus.vram_mut()[0] = 3;
+        assert_eq!(gba.sysbus.read_8(0x0203_ffff), 1);
+        assert_eq!(gba.sysbus.read_8(0x0300_0000), 2);
+        assert_eq!(gba.sysbus.read_8(0x0600_0000), 3);
+    }
+
+    #[test]
+    fn test_remove_cartridge() {
+        let mut gba = make_mock_gba(&[0x42; 0x200]);
+        let gamepak_irq = 1 << Interrupt::GamePak as u16;
+        assert_eq!(gba.sysbus.read_8(0x0800_0000), 0x42);
+
+        gba.remove_cartridge();
+        assert!(gba.is_cartridge_removed());
+        assert_eq!(gba.sysbus.read_16(0x0800_0000), 0);
+        assert_eq!(gba.sysbus.read_16(0x0900_0010), 0x0008);
+        assert_eq!(gba.interrupt_flags.get().value() & gamepak_irq, gamepak_irq);
+
+        // it's only pulled out once
+        gba.interrupt_flags.set(IrqBitmask(0));
+        gba.remove_cartridge();
+        assert_eq!(gba.interrupt_flags.get().value(), 0);
     }
 
-    impl VideoInterface for DummyInterface {}
-    impl AudioInterface for DummyInterface {}
-    impl InputInterface for DummyInterface {}
+    #[test]
+    fn test_stop_mode_sleeps_until_keypad_irq() {
+        use super::super::input::keys_to_keyinput;
+        use super::super::keypad::KEYCNT_IRQ;
+
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes()); // b .
+        let mut gba = make_mock_gba(&rom);
+        let keypad_irq = 1 << Interrupt::Keypad as u16;
+        gba.sysbus.write_16(0x0400_0200, keypad_irq | 1); // IE, keypad and vblank
+        let keycnt = KEYCNT_IRQ | 1 << Keys::ButtonA as u16;
+        gba.sysbus.write_16(0x0400_0132, keycnt); // KEYCNT
+        gba.sysbus.write_8(0x0400_0301, 0x80); // HALTCNT
+        assert!(gba.is_sleeping());
+
+        let frame_count = gba.sysbus.io.gpu.frame_count;
+        gba.interrupt_flags.set(IrqBitmask(1));
+        gba.frame();
+        assert!(gba.is_sleeping());
+        assert_eq!(gba.sysbus.io.gpu.frame_count, frame_count);
+
+        gba.set_keyinput_override(Some(keys_to_keyinput(&[Keys::ButtonB])));
+        gba.frame();
+        assert!(gba.is_sleeping());
+
+        gba.set_keyinput_override(Some(keys_to_keyinput(&[Keys::ButtonA])));
+        gba.frame();
+        assert!(!gba.is_sleeping());
+        assert_eq!(gba.interrupt_flags.get().value() & keypad_irq, keypad_irq);
+        assert!(gba.sysbus.io.gpu.frame_count > frame_count);
+    }
 
-    fn make_mock_gba(rom: &[u8]) -> GameBoyAdvance {
-        let bios = vec![0; 0x4000].into_boxed_slice();
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_restore_state_backup_modes() {
+        use super::super::cartridge::GamepakBuilder;
+        use super::super::sysbus::consts::SRAM_LO;
+
+        let path = std::env::temp_dir().join(format!("rba-restore-{}.sav", std::process::id()));
+        let _ = std::fs::remove_file(&path);
         let cartridge = GamepakBuilder::new()
-            .buffer(rom)
+            .buffer(&make_test_rom(&[]))
             .with_sram()
-            .without_backup_to_file()
+            .save_path(&path)
             .build()
             .unwrap();
-        let dummy = Rc::new(RefCell::new(DummyInterface::new()));
-        let mut gba =
-            GameBoyAdvance::new(bios, cartridge, dummy.clone(), dummy.clone(), dummy.clone());
-        gba.skip_bios();
+        let bios = vec![0; 0x4000].into_boxed_slice();
+        let mut gba = GameBoyAdvance::new(bios, cartridge, Frontend::default());
+
+        gba.sysbus.write_8(SRAM_LO, 1);
+        let state = gba.save_state().unwrap();
+        // the game saves again after the state was taken
+        gba.sysbus.write_8(SRAM_LO, 2);
+
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.sysbus.read_8(SRAM_LO), 2);
+        assert_eq!(std::fs::read(&path).unwrap()[0], 2);
+
+        gba.set_backup_restore(BackupRestore::FromState);
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.sysbus.read_8(SRAM_LO), 1);
+        assert_eq!(std::fs::read(&path).unwrap()[0], 1);
+        // the file is written again
+        gba.sysbus.write_8(SRAM_LO, 3);
+        assert_eq!(std::fs::read(&path).unwrap()[0], 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_in_memory_backup_is_part_of_the_state() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        gba.sysbus.write_8(0x0E00_0010, 0x42);
+        let state = gba.save_state().unwrap();
+        gba.sysbus.write_8(0x0E00_0010, 0);
+
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.sysbus.read_8(0x0E00_0010), 0x42);
+    }
+
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_state_of_another_rom_is_refused() {
+        let rom = |code: &[u8], fill: u8| {
+            let mut rom = vec![fill; 0x200];
+            rom[0xac..0xb0].copy_from_slice(code);
+            rom
+        };
+        let original = make_mock_gba(&rom(b"BPEE", 0));
+        let state = original.save_state().unwrap();
+        assert_eq!(
+            GameBoyAdvance::state_rom(&state).unwrap(),
+            original.sysbus.cartridge.identity()
+        );
+
+        let mut other_game = make_mock_gba(&rom(b"AXVE", 0));
+        let err = other_game.restore_state(&state).unwrap_err();
+        assert!(err.to_string().contains("the state is of game BPEE"));
+
+        let mut patched = make_mock_gba(&rom(b"BPEE", 1));
+        patched.sysbus.write_8(0x0300_0000, 0x42);
+        let err = patched.restore_state(&state).unwrap_err();
+        assert!(err.to_string().contains("another version of BPEE"));
+        assert_eq!(patched.sysbus.read_8(0x0300_0000), 0x42);
 
+        patched.set_force_state_load(true);
+        patched.restore_state(&state).unwrap();
+        assert_eq!(patched.sysbus.read_8(0x0300_0000), 0);
+    }
+
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_loaded_states_are_deterministic() {
+        use super::super::determinism::{verify_determinism, DeterminismCheck};
+
+        // mov r0, #0x03000000; loop: ldr r1, [r0]; add r1, r1, #1; str r1, [r0]; b loop
+        let code: [u32; 5] = [
+            0xe3a0_0403,
+            0xe590_1000,
+            0xe281_1001,
+            0xe580_1000,
+            0xeaff_fffb,
+        ];
+        let mut gba = make_mock_gba(&make_arm_rom(&code));
+
+        let hashes = gba.subsystem_hashes();
+        gba.sysbus.write_32(0x0300_0100, 1);
+        let changed: Vec<&str> = gba
+            .subsystem_hashes()
+            .iter()
+            .zip(&hashes)
+            .filter(|(new, old)| new != old)
+            .map(|(new, _)| new.name)
+            .collect();
+        assert_eq!(changed, ["iwram"]);
+
+        let check = DeterminismCheck {
+            checkpoints: 3,
+            frames: 4,
+        };
+        assert_eq!(verify_determinism(&mut gba, check).unwrap(), None);
+        assert_ne!(gba.sysbus.read_32(0x0300_0000), 0);
+        assert_eq!(gba.keyinput_override(), None);
+    }
+
+    #[test]
+    fn test_swap_rom_keeps_state() {
+        fn counter_rom(increment: u32) -> Vec<u8> {
+            // mov r0, #0x03000000; loop: ldr r1, [r0]; add r1, r1, #increment; str r1, [r0]; b loop
+            let code: [u32; 5] = [
+                0xe3a0_0403,
+                0xe590_1000,
+                0xe281_1000 | increment,
+                0xe580_1000,
+                0xeaff_fffb,
+            ];
+            make_arm_rom(&code)
+        }
+
+        let mut gba = make_mock_gba(&counter_rom(1));
+        gba.sysbus.write_8(0x0E00_0000, 0x42);
+        gba.frame();
+        let count = gba.sysbus.read_32(0x0300_0000);
+        assert_ne!(count, 0);
+
+        gba.swap_rom(make_test_cartridge(&counter_rom(0)));
+        // no instruction of the old ROM is left in the pipeline
+        let count = gba.sysbus.read_32(0x0300_0000);
+        gba.frame();
+        assert_eq!(gba.sysbus.read_32(0x0300_0000), count);
+        assert_eq!(gba.sysbus.read_8(0x0E00_0000), 0x42);
+    }
+
+    #[cfg(feature = "send")]
+    #[test]
+    fn test_instances_run_on_threads() {
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut gba = make_mock_gba(&[0; 0x200]);
+                std::thread::spawn(move || {
+                    gba.frame();
+                    gba.stats().frames
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_input_latency_waits_for_keyinput_read() {
+        use super::super::input::keys_to_keyinput;
+
+        // mov r0, #0x04000000; add r0, r0, #0x130; loop: ldrh r1, [r0]; b loop
+        let code: [u32; 4] = [0xe3a0_0404, 0xe280_0e13, 0xe1d0_10b0, 0xeaff_fffd];
+        let mut reading = make_mock_gba(&make_arm_rom(&code));
+        let mut ignoring = make_mock_gba(&[0; 0x200]);
+
+        for gba in [&mut reading, &mut ignoring].iter_mut() {
+            gba.frame();
+            gba.input_changed();
+            gba.set_keyinput_override(Some(keys_to_keyinput(&[Keys::ButtonA])));
+            gba.frame();
+            gba.frame();
+        }
+
+        let latency = reading.stats().input_latency;
+        assert_eq!(latency.samples, 1);
+        assert_eq!(latency.last_frames, 1);
+        assert_eq!(latency.average, latency.last);
+        assert_eq!(ignoring.stats().input_latency.samples, 0);
+    }
+
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_state_and_frame_hashes() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        let other = make_mock_gba(&[0; 0x200]);
+        assert_eq!(gba.state_hash(), other.state_hash());
+        assert_eq!(gba.frame_hash(), other.frame_hash());
+
+        let state = gba.save_state().unwrap();
+        let hash = gba.state_hash();
+        gba.sysbus.io.gpu.palette_ram.mem[0] = 0xff;
+        gba.frame();
+        assert_ne!(gba.state_hash(), hash);
+        assert_ne!(gba.frame_hash(), other.frame_hash());
+
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.state_hash(), hash);
+    }
+
+    #[test]
+    fn test_stats_count_emulated_time() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        assert_eq!(gba.stats(), EmulationStats::default());
+
+        gba.frame();
+        gba.frame();
+        let stats = gba.stats();
+        assert_eq!(stats.frames, 2);
+        assert!(stats.cycles >= 2 * CYCLES_FULL_REFRESH as u64);
+        assert_eq!(
+            stats.cycles,
+            stats.breakdown.cpu + stats.breakdown.halted + stats.breakdown.idle_loop
+        );
+        assert!((stats.emulated_seconds - 2.0 / 59.7275).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_time_control() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        gba.time_control_mut().set_paused(true);
+        assert_eq!(gba.run_host_frame(), 0);
+        gba.time_control_mut().step_frame();
+        assert_eq!(gba.run_host_frame(), 1);
+        assert_eq!(gba.run_host_frame(), 0);
+        assert_eq!(gba.stats().frames, 1);
+
+        gba.time_control_mut().set_paused(false);
+        gba.time_control_mut().set_slow_motion(0.25);
+        let frames: usize = (0..8).map(|_| gba.run_host_frame()).sum();
+        assert_eq!(frames, 2);
+        let sample_rate = lock(&gba.audio_device).get_sample_rate() as f32;
+        assert_eq!(gba.sysbus.io.sound.output_sample_rate(), sample_rate * 4.0);
+
+        gba.time_control_mut().set_turbo(true);
+        gba.time_control_mut().set_turbo_speed(3.0);
+        assert_eq!(gba.run_host_frame(), 3);
+        assert_eq!(gba.sysbus.io.sound.output_sample_rate(), sample_rate);
+        assert!(!gba.sysbus.io.gpu.skip_frames);
+    }
+
+    #[test]
+    fn test_dma_inspect_and_hook() {
+        use super::super::dma::DmaEvent;
+        use super::super::shared;
+
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        let events = shared(Vec::new());
+        let events_clone = events.clone();
+        gba.set_dma_hook(Some(Box::new(move |event: &DmaEvent| {
+            lock(&events_clone).push(*event)
+        })));
+
+        gba.sysbus.write_32(0x0200_0000, 0x1234_5678);
+        gba.sysbus.write_32(0x0400_00d4, 0x0200_0000);
+        gba.sysbus.write_32(0x0400_00d8, 0x0200_0100);
+        gba.sysbus.write_16(0x0400_00dc, 2);
+        // enabled, 16 bit, immediate
+        gba.sysbus.write_16(0x0400_00de, 0x8000);
+        assert!(gba.sysbus.io.dmac.inspect()[3].pending);
+
+        gba.step();
+        assert_eq!(gba.sysbus.read_32(0x0200_0100), 0x1234_5678);
+
+        let info = &gba.sysbus.io.dmac.inspect()[3];
+        assert!(!info.pending && !info.active);
+        let transfer = info.last_transfer.unwrap();
+        assert_eq!((transfer.src, transfer.dst), (0x0200_0000, 0x0200_0100));
+        assert_eq!((transfer.count, transfer.word_size), (2, 2));
+        assert_eq!(
+            *lock(&events),
+            vec![
+                DmaEvent::Started {
+                    channel: 3,
+                    transfer
+                },
+                DmaEvent::Stopped {
+                    channel: 3,
+                    transfer,
+                    enabled: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame_timeline() {
+        use super::super::timeline::TimelineEventKind::*;
+
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        gba.set_timeline_enabled(true);
+        assert!(gba.last_frame_events().is_empty());
+        // VBlank IRQ, timer 0 overflowing every 256 cycles and an immediate DMA
+        gba.sysbus.write_16(0x0400_0004, 0x8);
+        gba.sysbus.write_16(0x0400_0100, 0xff00);
+        gba.sysbus.write_16(0x0400_0102, 0x80);
+        gba.sysbus.write_32(0x0400_00d4, 0x0200_0000);
+        gba.sysbus.write_32(0x0400_00d8, 0x0200_0100);
+        gba.sysbus.write_16(0x0400_00dc, 2);
+        gba.sysbus.write_16(0x0400_00de, 0x8000);
+        gba.frame();
+
+        let events = gba.last_frame_events();
+        assert!(events.windows(2).all(|w| w[0].cycle <= w[1].cycle));
+        assert_eq!(
+            events[0].kind,
+            DmaStarted {
+                channel: 3,
+                fifo: false
+            }
+        );
+        assert_eq!(events[1].kind, DmaStopped { channel: 3 });
+        let count = |f: &dyn Fn(&TimelineEventKind) -> bool| {
+            events.iter().filter(|event| f(&event.kind)).count()
+        };
+        assert_eq!(count(&|kind| matches!(kind, HBlank { .. })), 228);
+        assert_eq!(count(&|kind| *kind == VBlank), 1);
+        let overflows: usize = events
+            .iter()
+            .map(|event| match event.kind {
+                TimerOverflow { timer: 0, count } => count,
+                _ => 0,
+            })
+            .sum();
+        assert!(overflows >= CYCLES_FULL_REFRESH / 256 - 1);
+        let vblank = events.iter().find(|e| e.kind == VBlank).unwrap();
+        let irq = events
+            .iter()
+            .find(|e| e.kind == IrqRaised(Interrupt::LCD_VBlank))
+            .unwrap();
+        assert!(irq.cycle >= vblank.cycle);
+        assert_eq!(count(&|kind| matches!(kind, IrqServiced(_))), 0);
+
+        gba.set_timeline_enabled(false);
+        assert!(gba.last_frame_events().is_empty());
+    }
+
+    #[test]
+    fn test_timers_inspect() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        // timer 0 overflows every 256 cycles, timer 1 counts two of its overflows
+        gba.sysbus.write_16(0x0400_0100, 0xff00);
+        gba.sysbus.write_16(0x0400_0104, 0xfffe);
+        gba.sysbus.write_16(0x0400_0106, 0x84);
+        gba.sysbus.write_16(0x0400_0102, 0xc0);
+        gba.frame();
+
+        let [timer0, timer1, _, _] = gba.sysbus.io.timers.inspect();
+        assert_eq!(timer0.reload, 0xff00);
+        assert_eq!((timer0.prescaler, timer0.cascade_from), (1, None));
+        assert_eq!(timer1.cascade_from, Some(0));
+        assert!(timer0.overflows >= CYCLES_FULL_REFRESH as u64 / 256 - 1);
+        assert_eq!(timer1.overflows, timer0.overflows / 2);
+
+        let history: Vec<_> = gba.sysbus.io.timers.overflow_history().collect();
+        assert_eq!(history.len(), 64);
+        assert!(history.windows(2).all(|w| w[0].cycle <= w[1].cycle));
+        assert!(history.iter().any(|overflow| overflow.timer == 1));
+        assert!(history
+            .iter()
+            .all(|overflow| overflow.irq == (overflow.timer == 0)));
+    }
+
+    #[test]
+    fn test_scanline_hook() {
+        use super::super::shared;
+
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        let lines = shared(Vec::new());
+        let lines_clone = lines.clone();
+        gba.set_scanline_hook(Some(Box::new(
+            move |line: usize, colors: &[u16; DISPLAY_WIDTH]| {
+                lock(&lines_clone).push((line, colors[0]))
+            },
+        )));
+
+        // forced blank, every line is white
+        gba.sysbus.write_16(0x0400_0000, 0x80);
+        gba.frame();
+        let lines = lock(&lines);
+        assert_eq!(lines.len(), DISPLAY_HEIGHT);
+        for (i, (line, color)) in lines.iter().enumerate() {
+            assert_eq!(*line, (i + 1) % DISPLAY_HEIGHT);
+            assert_eq!(*color, 0x7fff);
+        }
+    }
+
+    #[test]
+    fn test_init_options() {
+        use super::super::init::MemoryFill;
+
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        let mut options = InitOptions::with_fill(MemoryFill::Ones);
+        options.registers[3] = 0x1234;
+        gba.apply_init_options(&options);
+        assert_eq!(gba.sysbus.read_32(0x0200_0000), 0xffff_ffff);
+        assert_eq!(gba.sysbus.read_32(0x0300_7000), 0xffff_ffff);
+        assert_eq!(gba.sysbus.read_16(0x0600_0000), 0xffff);
+        assert_eq!(gba.cpu.gpr[3], 0x1234);
+
+        let mut other = make_mock_gba(&[0; 0x200]);
+        gba.apply_init_options(&InitOptions::with_fill(MemoryFill::Random(7)));
+        other.apply_init_options(&InitOptions::with_fill(MemoryFill::Random(7)));
+        assert_eq!(
+            gba.sysbus.read_32(0x0200_0100),
+            other.sysbus.read_32(0x0200_0100)
+        );
+        assert_ne!(
+            gba.sysbus.read_32(0x0200_0100),
+            gba.sysbus.read_32(0x0300_0100)
+        );
+    }
+
+    #[test]
+    fn test_undefined_instruction_crash_report() {
+        use super::super::crash::ExecutedInstruction;
+        use super::super::shared;
+
+        let mut rom = vec![0; 0x200];
+        // mov r0, #0x12; undefined instruction
+        rom[0..4].copy_from_slice(&0xe3a0_0012u32.to_le_bytes());
+        rom[4..8].copy_from_slice(&0xe7f0_00f0u32.to_le_bytes());
+        let mut gba = make_mock_gba(&rom);
+
+        let handled = shared(false);
+        let handled_clone = handled.clone();
+        gba.set_crash_handler(Box::new(move |_| *lock(&handled_clone) = true));
+        gba.frame();
+
+        assert!(*lock(&handled));
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Stop);
+        let report = gba.crash_report().unwrap();
+        assert_eq!(report.pc, 0x0800_0004);
+        assert_eq!(report.opcode, 0xe7f0_00f0);
+        assert_eq!(report.registers[0], 0x12);
+        let last = &report.last_instructions[report.last_instructions.len() - 2..];
+        assert_eq!(
+            last,
+            &[
+                ExecutedInstruction {
+                    pc: 0x0800_0000,
+                    opcode: 0xe3a0_0012
+                },
+                ExecutedInstruction {
+                    pc: 0x0800_0004,
+                    opcode: 0xe7f0_00f0
+                },
+            ]
+        );
+        assert_eq!(report.stack.len(), 0x100);
+        #[cfg(feature = "std")]
+        assert!(report.to_json().contains("\"last_instructions\""));
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        use super::super::arm7tdmi::{DecodedInstruction, Exception};
+
+        // mov r0, #0x03000000; str r0, [r0]; moveq r1, #1; swi 0
+        let code: [u32; 4] = [0xe3a0_0403, 0xe580_0000, 0x03a0_1001, 0xef00_0000];
+        let mut gba = make_mock_gba(&make_arm_rom(&code));
+        let mut steps = Vec::new();
+        for _ in 0..code.len() {
+            steps.push(gba.cpu.step_instruction(&mut gba.sysbus));
+        }
+
+        assert_eq!((steps[0].pc, steps[0].opcode), (0x0800_0000, code[0]));
+        assert!(matches!(steps[0].instruction, DecodedInstruction::Arm(_)));
+        assert!(steps[0].executed);
+        assert!(steps.iter().all(|step| step.cycles > 0));
+        #[cfg(feature = "trace")]
+        assert_eq!(
+            steps[1].accesses,
+            [super::super::trace::MemAccess {
+                write: true,
+                width: 4,
+                addr: 0x0300_0000,
+                value: 0x0300_0000,
+            }]
+        );
+        assert!(!steps[2].executed);
+        assert_eq!(steps[2].exception, None);
+        assert_eq!(
+            steps[3].exception,
+            Some((Exception::SoftwareInterrupt, 0x0800_0010))
+        );
+        assert_eq!(gba.cpu.get_next_pc(), 0x08);
+    }
+
+    #[test]
+    fn test_skip_bios_protects_bios() {
+        let gba = make_mock_gba(&[0; 0x200]);
+        assert_eq!(gba.sysbus.read_32(0x0000_0100), 0xe129_f000);
+        assert_eq!(gba.sysbus.read_16(0x0000_0102), 0xe129);
+        assert!(gba.sysbus.io.post_boot_flag);
+    }
+
+    #[test]
+    fn test_write_only_io_reads_open_bus() {
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xe3a0_0001u32.to_le_bytes());
+        let mut gba = make_mock_gba(&rom);
+
+        gba.sysbus.on_cpu_fetch(0x0800_0000, false);
+        assert_eq!(gba.sysbus.read_32(0x0400_0010), 0xe3a0_0001); // BG0HOFS
+        assert_eq!(gba.sysbus.read_16(0x0400_00b8), 0); // DMA0CNT_L reads as zero
+
+        gba.sysbus.on_cpu_fetch(0x0800_0002, true);
+        assert_eq!(gba.sysbus.read_16(0x0400_00b2), 0xe3a0); // DMA0SAD_H
+    }
+
+    #[test]
+    fn test_io_write_masks() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+
+        let dispstat = gba.sysbus.read_16(0x0400_0004);
+        gba.sysbus.write_16(0x0400_0004, 0xffff);
+        assert_eq!(gba.sysbus.read_16(0x0400_0004), 0xff38 | (dispstat & 7));
+
+        gba.sysbus.write_16(0x0400_0200, 0xffff); // IE
+        assert_eq!(gba.sysbus.read_16(0x0400_0200), 0x3fff);
+        gba.sysbus.write_16(0x0400_0208, 0x0002); // IME
+        assert_eq!(gba.sysbus.read_16(0x0400_0208), 0);
+    }
+
+    fn intr_wait_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x200];
+        // swi 0x04 (IntrWait)
+        rom[0..4].copy_from_slice(&0xef04_0000u32.to_le_bytes());
+        // b .
+        rom[4..8].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        rom
+    }
+
+    #[test]
+    fn test_hle_intr_wait_halts_until_flag() {
+        let mut gba = make_mock_gba_with_bios(Box::new([]), &intr_wait_rom());
+        gba.cpu.gpr[0] = 0;
+        gba.cpu.gpr[1] = 1;
+        gba.frame();
+
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Halt);
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0000);
+        assert!(gba.sysbus.io.intc.interrupt_master_enable);
+    }
+
+    #[test]
+    fn test_hle_intr_wait_consumes_flag() {
+        let mut gba = make_mock_gba_with_bios(Box::new([]), &intr_wait_rom());
+        gba.cpu.gpr[0] = 0;
+        gba.cpu.gpr[1] = 1;
+        gba.sysbus.write_16(bios::INTR_CHECK_FLAGS_ADDR, 0b11);
+        gba.frame();
+
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Running);
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0004);
+        assert_eq!(gba.sysbus.read_16(bios::INTR_CHECK_FLAGS_ADDR), 0b10);
+    }
+
+    /// `b .` then `opcode` at 0x08000004, where the cpu is about to execute
+    fn make_swi_gba(opcode: u32) -> GameBoyAdvance {
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        rom[4..8].copy_from_slice(&opcode.to_le_bytes());
+        let mut gba = make_mock_gba_with_bios(Box::new([]), &rom);
+        gba.cpu.pc = 0x0800_0004;
+        gba.cpu.reload_pipeline32(&mut gba.sysbus);
         gba
     }
 
+    #[test]
+    fn test_hle_soft_reset() {
+        // swi 0x00
+        let mut gba = make_swi_gba(0xef00_0000);
+        gba.cpu.gpr[0] = 5;
+        gba.cpu.gpr[13] = 0x0300_7e00;
+        gba.sysbus.write_32(0x0300_7f80, 0x1234);
+        gba.sysbus.write_32(0x0200_0000, 0x1234);
+        gba.frame();
+
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0000);
+        assert_eq!(gba.cpu.gpr[0], 0);
+        assert_eq!(gba.cpu.gpr[13], 0x0300_7f00);
+        assert_eq!(gba.sysbus.read_32(0x0300_7f80), 0);
+        // the game's memory is left alone
+        assert_eq!(gba.sysbus.read_32(0x0200_0000), 0x1234);
+    }
+
+    #[test]
+    fn test_hard_reset() {
+        use super::super::sysbus::consts::SRAM_LO;
+
+        // swi 0x26 (HardReset)
+        let mut gba = make_swi_gba(0xef26_0000);
+        gba.sysbus.write_32(0x0200_0000, 0x1234);
+        gba.sysbus.write_16(0x0400_0200, 0x0001); // IE
+        gba.sysbus.write_8(SRAM_LO, 0x42);
+        gba.frame();
+
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0000);
+        assert_eq!(gba.sysbus.read_32(0x0200_0000), 0);
+        assert_eq!(gba.sysbus.read_16(0x0400_0200), 0);
+        assert_eq!(gba.sysbus.read_8(SRAM_LO), 0x42);
+        assert!(gba
+            .interrupt_flags
+            .ptr_eq(&gba.sysbus.io.intc.interrupt_flags));
+
+        gba.sysbus.write_32(0x0200_0000, 0x1234);
+        gba.hard_reset();
+        assert_eq!(gba.sysbus.read_32(0x0200_0000), 0);
+    }
+
+    #[test]
+    fn test_swi_cross_check() {
+        use super::super::swi_trace::SwiTraceMode;
+
+        // a BIOS that returns from every swi without doing anything
+        let mut bios = vec![0; 0x4000];
+        bios[0x08..0x0c].copy_from_slice(&0xe1b0_f00eu32.to_le_bytes()); // movs pc, lr
+        let mut gba = make_mock_gba_with_bios(bios.into_boxed_slice(), &intr_wait_rom());
+        gba.set_swi_trace(Some(SwiTraceMode::CrossCheck));
+        gba.cpu.gpr[0] = 0;
+        gba.cpu.gpr[1] = 1;
+        gba.sysbus.write_16(bios::INTR_CHECK_FLAGS_ADDR, 0b11);
+        gba.frame();
+
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0004);
+        let mismatches: Vec<_> = gba
+            .swi_mismatches()
+            .iter()
+            .map(|m| (m.what, m.hle, m.bios))
+            .collect();
+        assert_eq!(mismatches, [("BIOS_IF", 0b10, 0b11), ("IME", 1, 0)]);
+        assert_eq!(gba.swi_mismatches()[0].call.number, 4);
+        assert_eq!(gba.swi_mismatches()[0].call.addr, 0x0800_0000);
+    }
+
+    #[cfg(feature = "builtin-bios")]
+    #[test]
+    fn test_builtin_bios_boots_to_cartridge() {
+        use super::super::arm7tdmi::CpuMode;
+
+        // b .
+        let cartridge = make_test_cartridge(&0xeaff_fffeu32.to_le_bytes());
+        let mut gba =
+            GameBoyAdvance::new(bios::builtin_bios_image(), cartridge, Frontend::default());
+        gba.frame();
+
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0000);
+        assert_eq!(gba.cpu.cpsr.mode(), CpuMode::System);
+        assert_eq!(gba.cpu.get_reg(13), 0x0300_7f00);
+    }
+
+    #[cfg(feature = "builtin-bios")]
+    #[test]
+    fn test_builtin_bios_intr_wait() {
+        let mut gba = make_mock_gba_with_bios(bios::builtin_bios_image(), &intr_wait_rom());
+        gba.cpu.gpr[0] = 0;
+        gba.cpu.gpr[1] = 1;
+        gba.sysbus.write_16(bios::INTR_CHECK_FLAGS_ADDR, 0b11);
+        gba.frame();
+
+        assert_eq!(gba.cpu.get_next_pc(), 0x0800_0004);
+        assert_eq!(gba.sysbus.read_16(bios::INTR_CHECK_FLAGS_ADDR), 0b10);
+        assert!(gba.sysbus.io.intc.interrupt_master_enable);
+
+        // no interrupt is enabled, so it never comes back from halt
+        let mut gba = make_mock_gba_with_bios(bios::builtin_bios_image(), &intr_wait_rom());
+        gba.cpu.gpr[0] = 0;
+        gba.cpu.gpr[1] = 1;
+        gba.frame();
+
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Halt);
+        assert!(gba.cpu.get_next_pc() < 0x0000_4000);
+    }
+
     #[test]
     fn test_arm7tdmi_arm_eggvance() {
         let mut gba = make_mock_gba(include_bytes!("../../external/gba-suite/arm/arm.gba"));
@@ -0,0 +1,237 @@
+//! Builds a `GameBoyAdvance` with the devices and hooks it is plugged into.
+//!
+//! Everything that lies outside the emulated hardware is injected, each behind the trait or
+//! hook the core already uses for it: the video, audio and input devices of the `Frontend`, the
+//! `SerialDevice` at the other end of the link cable, the `TraceSink` of the instruction trace,
+//! the crash handler, and the hooks that observe the DMA controller, the DirectSound FIFOs and
+//! the lines drawn by the GPU. A test can script the input and play the other end of the link
+//! cable, and an embedder can record what the hooks see, without going through the setters one
+//! by one after the machine is created:
+//! ```ignore
+//! let gba = GbaBuilder::new(cartridge)
+//!     .bios(bios)
+//!     .input(shared(ScriptedInput::new(inputs)))
+//!     .serial_device(Box::new(LoopbackLink::default()))
+//!     .dma_hook(Box::new(|event| println!("{:?}", event)))
+//!     .skip_bios()
+//!     .build()?;
+//! ```
+//! Only what lies outside the hardware is injected. The cpu, the bus, the DMA controller and the
+//! GPU are concrete types without a trait to stand in for them, so no mock can replace them: the
+//! hooks observe them, they don't intercept them.
+use super::bios_db::BiosCheck;
+use super::cartridge::Cartridge;
+#[cfg(feature = "std")]
+use super::config::Config;
+use super::crash::CrashHandler;
+use super::dma::DmaHook;
+use super::gpu::ScanlineHook;
+use super::init::InitOptions;
+use super::sio::SerialDevice;
+use super::sound::FifoHook;
+#[cfg(feature = "trace")]
+use super::trace::TraceSink;
+use super::util::RomBuffer;
+use super::{AudioSink, CoreResult, Frontend, GameBoyAdvance, InputSource, Shared, VideoSink};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+pub struct GbaBuilder {
+    cartridge: Cartridge,
+    bios: RomBuffer,
+    bios_check: BiosCheck,
+    frontend: Frontend,
+    #[cfg(feature = "std")]
+    config: Option<Config>,
+    serial_device: Option<Box<dyn SerialDevice>>,
+    skip_bios: bool,
+    init_options: Option<InitOptions>,
+    crash_handler: Option<CrashHandler>,
+    dma_hook: Option<DmaHook>,
+    audio_fifo_hook: Option<FifoHook>,
+    scanline_hook: Option<ScanlineHook>,
+    #[cfg(feature = "trace")]
+    trace_sink: Option<Box<dyn TraceSink>>,
+}
+
+impl GbaBuilder {
+    /// A machine with `cartridge` inserted, the HLE BIOS and no devices, see `Frontend::new`
+    pub fn new(cartridge: Cartridge) -> GbaBuilder {
+        GbaBuilder {
+            cartridge,
+            bios: Vec::new().into(),
+            bios_check: BiosCheck::Warn,
+            frontend: Frontend::new(),
+            #[cfg(feature = "std")]
+            config: None,
+            serial_device: None,
+            skip_bios: false,
+            init_options: None,
+            crash_handler: None,
+            dma_hook: None,
+            audio_fifo_hook: None,
+            scanline_hook: None,
+            #[cfg(feature = "trace")]
+            trace_sink: None,
+        }
+    }
+
+    /// An empty `bios` selects the HLE BIOS, like `GameBoyAdvance::new`
+    pub fn bios<B: Into<RomBuffer>>(mut self, bios: B) -> Self {
+        self.bios = bios.into();
+        self
+    }
+
+    /// What to do with an unknown or corrupt BIOS, only warn by default
+    pub fn bios_check(mut self, bios_check: BiosCheck) -> Self {
+        self.bios_check = bios_check;
+        self
+    }
+
+    /// Replaces the three devices at once
+    pub fn frontend(mut self, frontend: Frontend) -> Self {
+        self.frontend = frontend;
+        self
+    }
+
+    pub fn video(mut self, video: Shared<dyn VideoSink>) -> Self {
+        self.frontend = self.frontend.video(video);
+        self
+    }
+
+    pub fn audio(mut self, audio: Shared<dyn AudioSink>) -> Self {
+        self.frontend = self.frontend.audio(audio);
+        self
+    }
+
+    pub fn input(mut self, input: Shared<dyn InputSource>) -> Self {
+        self.frontend = self.frontend.input(input);
+        self
+    }
+
+    /// Applied with `GameBoyAdvance::apply_config`. The link cable it sets up gives way to
+    /// `serial_device`.
+    #[cfg(feature = "std")]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// The other end of the link cable, nothing is plugged by default
+    pub fn serial_device(mut self, device: Box<dyn SerialDevice>) -> Self {
+        self.serial_device = Some(device);
+        self
+    }
+
+    /// Starts from the cartridge rather than from the boot sequence of the BIOS
+    pub fn skip_bios(mut self) -> Self {
+        self.skip_bios = true;
+        self
+    }
+
+    /// Applied once the BIOS is skipped, see `GameBoyAdvance::apply_init_options`
+    pub fn init_options(mut self, options: InitOptions) -> Self {
+        self.init_options = Some(options);
+        self
+    }
+
+    pub fn crash_handler(mut self, handler: CrashHandler) -> Self {
+        self.crash_handler = Some(handler);
+        self
+    }
+
+    pub fn dma_hook(mut self, hook: DmaHook) -> Self {
+        self.dma_hook = Some(hook);
+        self
+    }
+
+    pub fn audio_fifo_hook(mut self, hook: FifoHook) -> Self {
+        self.audio_fifo_hook = Some(hook);
+        self
+    }
+
+    pub fn scanline_hook(mut self, hook: ScanlineHook) -> Self {
+        self.scanline_hook = Some(hook);
+        self
+    }
+
+    /// Traces every instruction from the first one, see `GameBoyAdvance::start_trace`
+    #[cfg(feature = "trace")]
+    pub fn trace<W: TraceSink + 'static>(mut self, out: W) -> Self {
+        self.trace_sink = Some(Box::new(out));
+        self
+    }
+
+    /// Fails on a BIOS refused by the `bios_check`, or a trace that can't be started
+    pub fn build(self) -> CoreResult<GameBoyAdvance> {
+        let mut gba = GameBoyAdvance::with_bios_check(
+            self.bios,
+            self.cartridge,
+            self.frontend,
+            self.bios_check,
+        )?;
+        #[cfg(feature = "std")]
+        {
+            if let Some(config) = &self.config {
+                gba.apply_config(config);
+            }
+        }
+        if let Some(device) = self.serial_device {
+            gba.set_serial_device(device);
+        }
+        if self.skip_bios {
+            gba.skip_bios();
+        }
+        if let Some(options) = &self.init_options {
+            gba.apply_init_options(options);
+        }
+        if let Some(handler) = self.crash_handler {
+            gba.set_crash_handler(handler);
+        }
+        gba.set_dma_hook(self.dma_hook);
+        gba.set_audio_fifo_hook(self.audio_fifo_hook);
+        gba.set_scanline_hook(self.scanline_hook);
+        #[cfg(feature = "trace")]
+        {
+            if let Some(out) = self.trace_sink {
+                gba.start_trace(out)?;
+            }
+        }
+        Ok(gba)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::test_util::make_test_cartridge;
+    use crate::gpu::consts::DISPLAY_HEIGHT;
+    use crate::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+    use crate::{lock, shared};
+
+    struct HeldKey(Keys);
+
+    impl InputSource for HeldKey {
+        fn poll(&mut self) -> u16 {
+            KEYINPUT_ALL_RELEASED & !(1 << self.0 as u16)
+        }
+    }
+
+    #[test]
+    fn test_builder_injects_devices_and_hooks() {
+        let cartridge = make_test_cartridge(&[]);
+        let input = shared(HeldKey(Keys::ButtonA));
+        let lines = shared(0);
+        let drawn = lines.clone();
+        let mut gba = GbaBuilder::new(cartridge)
+            .bios(vec![0; 0x4000])
+            .input(input.clone())
+            .scanline_hook(Box::new(move |_, _| *lock(&drawn) += 1))
+            .skip_bios()
+            .build()
+            .unwrap();
+        gba.frame();
+        assert_eq!(gba.sysbus.io.keyinput, lock(&input).poll());
+        assert_eq!(*lock(&lines), DISPLAY_HEIGHT);
+    }
+}
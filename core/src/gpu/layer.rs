@@ -25,6 +25,40 @@ impl RenderLayerKind {
     }
 }
 
+/// A pixel of the frame before it's converted to RGB, see `GameBoyAdvance::raw_frame`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawPixel {
+    /// The color that is displayed
+    pub color: Rgb15,
+    /// Color of the topmost layer, before the color special effects
+    pub source: Rgb15,
+    /// The topmost layer
+    pub layer: RenderLayerKind,
+    /// Alpha blended, or brightness changed
+    pub blended: bool,
+}
+
+impl Default for RawPixel {
+    fn default() -> RawPixel {
+        RawPixel {
+            color: Rgb15::BLACK,
+            source: Rgb15::BLACK,
+            layer: RenderLayerKind::Backdrop,
+            blended: false,
+        }
+    }
+}
+
+impl RawPixel {
+    pub(super) fn backdrop(color: Rgb15) -> RawPixel {
+        RawPixel {
+            color,
+            source: color,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct RenderLayer {
     pub kind: RenderLayerKind,
@@ -65,6 +99,11 @@ impl RenderLayer {
     pub(super) fn is_object(&self) -> bool {
         self.kind == RenderLayerKind::Objects
     }
+
+    /// Objects are above backgrounds of the same priority, and everything is above the backdrop
+    pub(super) fn is_above(&self, other: &RenderLayer) -> bool {
+        (self.priority, self.priority_by_type) < (other.priority, other.priority_by_type)
+    }
 }
 
 #[cfg(test)]
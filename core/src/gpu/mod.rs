@@ -1,15 +1,16 @@
-use std::cell::RefCell;
-use std::fmt;
-use std::rc::Rc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
 
 use serde::{Deserialize, Serialize};
 
 use super::bus::*;
 use super::dma::{DmaNotifer, TIMING_HBLANK, TIMING_VBLANK};
 use super::interrupt::{self, Interrupt, InterruptConnect, SharedInterruptFlags};
+use super::logging;
 pub use super::sysbus::consts::*;
 use super::util::BoxedMemory;
-use super::VideoInterface;
+use super::{lock, LoadContext, ResetAfterLoad, Shared, VideoSink};
 
 use crate::bitfield::Bit;
 use crate::num::FromPrimitive;
@@ -17,14 +18,23 @@ use crate::num::FromPrimitive;
 mod render;
 
 use render::Point;
+use tile_cache::TileCache;
 
 mod layer;
 mod mosaic;
+pub mod osd;
+pub mod overlay;
 mod rgb15;
+mod scale;
 mod sfx;
+mod tile_cache;
 mod window;
 
+pub use layer::{RawPixel, RenderLayerKind};
+pub use osd::Osd;
+pub use overlay::Overlay;
 pub use rgb15::Rgb15;
+pub use scale::{ScaledFrame, MAX_RENDER_SCALE};
 pub use window::*;
 
 pub mod regs;
@@ -108,14 +118,14 @@ impl fmt::Debug for Scanline {
     }
 }
 
-impl std::ops::Index<usize> for Scanline {
+impl core::ops::Index<usize> for Scanline {
     type Output = Rgb15;
     fn index(&self, index: usize) -> &Self::Output {
         &self.inner[index]
     }
 }
 
-impl std::ops::IndexMut<usize> for Scanline {
+impl core::ops::IndexMut<usize> for Scanline {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
         &mut self.inner[index]
     }
@@ -172,8 +182,17 @@ impl Default for ObjBufferEntry {
     }
 }
 
-type VideoDeviceRcRefCell = Rc<RefCell<dyn VideoInterface>>;
+type SharedVideoDevice = Shared<dyn VideoSink>;
 
+/// Called with every drawn line and its 15-bit colors, see `GameBoyAdvance::set_scanline_hook`
+#[cfg(not(feature = "send"))]
+pub type ScanlineHook = Box<dyn FnMut(usize, &[u16; DISPLAY_WIDTH])>;
+#[cfg(feature = "send")]
+pub type ScanlineHook = Box<dyn FnMut(usize, &[u16; DISPLAY_WIDTH]) + Send>;
+
+/// Savestates can be taken at any cycle, so everything the rest of the frame depends on is
+/// serialized, down to the line buffers and the affine reference points latched at vblank.
+/// `#[serde(skip)]` is only for frontend settings and caches rebuilt after loading.
 #[derive(Serialize, Deserialize, Clone, DebugStub)]
 pub struct Gpu {
     pub state: GpuState,
@@ -211,6 +230,60 @@ pub struct Gpu {
 
     #[debug_stub = "Frame Buffer"]
     pub(super) frame_buffer: Vec<u32>,
+
+    #[serde(skip)]
+    #[debug_stub = "Tile Cache"]
+    tile_cache: TileCache,
+
+    /// The frame in 15-bit colors along with the layers the pixels come from, for frontends
+    /// that process the picture themselves. `None` unless enabled with `set_raw_frame_enabled`.
+    #[serde(skip)]
+    #[debug_stub = "Raw Frame"]
+    pub raw_frame: Option<Vec<RawPixel>>,
+
+    /// The frame drawn again at a higher resolution, see `set_render_scale`
+    #[serde(skip)]
+    #[debug_stub = "Scaled Frame"]
+    pub scaled_frame: Option<ScaledFrame>,
+    /// Where the affine layers are sampled within the pixel, in 1/256 pixels
+    #[serde(skip)]
+    subpixel: Point,
+
+    /// Something that changes the picture was written since the last presented frame
+    #[serde(skip)]
+    frame_dirty: bool,
+    /// Written while the frame was drawn, so the next frame differs from this one too
+    #[serde(skip)]
+    next_frame_dirty: bool,
+    #[serde(skip)]
+    frame_unchanged: bool,
+    #[serde(skip)]
+    presented_color_correction: bool,
+
+    /// The lines drawn since the last `take_drawn_lines`, `None` unless enabled with
+    /// `record_lines`
+    #[serde(skip)]
+    #[debug_stub = "Drawn Lines"]
+    drawn_lines: Option<Vec<(usize, Box<[u16; DISPLAY_WIDTH]>)>>,
+
+    /// Number of frames completed so far
+    pub frame_count: usize,
+
+    /// Output colors as they look on the GBA's LCD, a frontend setting that isn't saved
+    #[serde(skip)]
+    pub color_correction: bool,
+
+    /// Drawn on top of every frame
+    #[serde(skip)]
+    pub overlay: Overlay,
+
+    /// Emulator notifications, drawn above the overlay
+    #[serde(skip)]
+    pub osd: Osd,
+
+    /// Don't render or present frames, used to skip frames when running faster than real time
+    #[serde(skip)]
+    pub skip_frames: bool,
 }
 
 impl InterruptConnect for Gpu {
@@ -219,6 +292,13 @@ impl InterruptConnect for Gpu {
     }
 }
 
+impl ResetAfterLoad for Gpu {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.connect_irq(ctx.interrupt_flags.clone());
+        self.frame_dirty = true;
+    }
+}
+
 impl Gpu {
     pub fn new(interrupt_flags: SharedInterruptFlags) -> Gpu {
         Gpu {
@@ -254,6 +334,21 @@ impl Gpu {
             obj_buffer: vec![Default::default(); DISPLAY_WIDTH * DISPLAY_HEIGHT],
 
             frame_buffer: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT],
+            tile_cache: TileCache::default(),
+            raw_frame: None,
+            scaled_frame: None,
+            subpixel: (0, 0),
+            frame_dirty: true,
+            next_frame_dirty: false,
+            frame_unchanged: false,
+            presented_color_correction: false,
+            drawn_lines: None,
+
+            frame_count: 0,
+            color_correction: false,
+            overlay: Overlay::default(),
+            osd: Osd::default(),
+            skip_frames: false,
         }
     }
 
@@ -262,7 +357,7 @@ impl Gpu {
         let old_mode = self.dispcnt.mode();
         let new_mode = new_dispcnt.mode();
         if old_mode != new_mode {
-            debug!("[GPU] Display mode changed! {} -> {}", old_mode, new_mode);
+            debug!(target: logging::GPU, "[GPU] Display mode changed! {} -> {}", old_mode, new_mode);
             self.vram_obj_tiles_start = if new_dispcnt.mode() >= 3 {
                 VRAM_OBJ_TILES_START_BITMAP
             } else {
@@ -273,6 +368,8 @@ impl Gpu {
     }
 
     pub fn skip_bios(&mut self) {
+        // The BIOS leaves the screen blanked
+        self.dispcnt = DisplayControl(0x80);
         for i in 0..2 {
             self.bg_aff[i].pa = 0x100;
             self.bg_aff[i].pb = 0;
@@ -338,7 +435,101 @@ impl Gpu {
         )
     }
 
+    /// Called for every write that can change the picture
+    pub fn mark_dirty(&mut self) {
+        self.frame_dirty = true;
+        if self.vcount < DISPLAY_HEIGHT {
+            self.next_frame_dirty = true;
+        }
+    }
+
+    /// The last presented frame is the same as the one before it, frontends can skip uploading
+    /// it and encoders can repeat the previous frame
+    pub fn frame_unchanged(&self) -> bool {
+        self.frame_unchanged
+    }
+
+    fn end_frame(&mut self, video_device: &SharedVideoDevice) {
+        // a skipped frame isn't presented, the next one is compared to the last presented frame
+        if !self.skip_frames {
+            self.frame_unchanged = !self.frame_dirty
+                && self.overlay.is_empty()
+                && self.osd.is_empty()
+                && self.presented_color_correction == self.color_correction;
+            self.frame_dirty = false;
+            self.presented_color_correction = self.color_correction;
+            self.present_frame(video_device);
+        }
+        self.frame_dirty |= self.next_frame_dirty;
+        self.next_frame_dirty = false;
+    }
+
+    /// Applies the post processing and hands the frame to the video device
+    fn present_frame(&mut self, video_device: &SharedVideoDevice) {
+        if self.color_correction {
+            self.correct_frame_colors();
+        }
+        if !self.overlay.is_empty() {
+            self.overlay.render(&mut self.frame_buffer);
+        }
+        if !self.osd.is_empty() {
+            self.osd.render(&mut self.frame_buffer);
+        }
+        if self.frame_unchanged {
+            lock(video_device).render_unchanged(&self.frame_buffer);
+        } else {
+            lock(video_device).render(&self.frame_buffer);
+        }
+    }
+
+    pub fn set_raw_frame_enabled(&mut self, enable: bool) {
+        if enable != self.raw_frame.is_some() {
+            self.raw_frame = if enable {
+                Some(vec![RawPixel::default(); DISPLAY_WIDTH * DISPLAY_HEIGHT])
+            } else {
+                None
+            };
+        }
+    }
+
+    /// For the lines that aren't composed from the layers
+    fn fill_raw_line(&mut self, color: Rgb15) {
+        let line = self.vcount * DISPLAY_WIDTH;
+        if let Some(raw_frame) = &mut self.raw_frame {
+            for pixel in raw_frame[line..line + DISPLAY_WIDTH].iter_mut() {
+                *pixel = RawPixel::backdrop(color);
+            }
+        }
+    }
+
     pub fn render_scanline(&mut self) {
+        self.render_native_line();
+        if self.scaled_frame.is_some() {
+            self.render_scaled_line();
+        }
+        if let Some(drawn_lines) = &mut self.drawn_lines {
+            let line = &self.frame_buffer[self.vcount * DISPLAY_WIDTH..];
+            let mut colors = Box::new([0; DISPLAY_WIDTH]);
+            for (color, pixel) in colors.iter_mut().zip(line) {
+                *color = Rgb15::from_rgb24(*pixel).0;
+            }
+            drawn_lines.push((self.vcount, colors));
+        }
+    }
+
+    /// Starts or stops collecting the lines returned by `take_drawn_lines`
+    pub fn record_lines(&mut self, enable: bool) {
+        self.drawn_lines = if enable { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_drawn_lines(&mut self) -> Vec<(usize, Box<[u16; DISPLAY_WIDTH]>)> {
+        self.drawn_lines
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn render_native_line(&mut self) {
         if self.dispcnt.force_blank() {
             for x in self.frame_buffer[self.vcount * DISPLAY_WIDTH..]
                 .iter_mut()
@@ -346,6 +537,7 @@ impl Gpu {
             {
                 *x = 0xf8f8f8;
             }
+            self.fill_raw_line(Rgb15::WHITE);
             return;
         }
 
@@ -394,11 +586,28 @@ impl Gpu {
                 self.render_mode5(2);
                 self.finalize_scanline(2, 2);
             }
-            _ => panic!("{:?} not supported", self.dispcnt.mode()),
+            _ => {
+                // prohibited modes only show the backdrop
+                let backdrop = Rgb15(self.palette_ram.read_16(0));
+                let line = self.vcount * DISPLAY_WIDTH;
+                for pixel in self.frame_buffer[line..line + DISPLAY_WIDTH].iter_mut() {
+                    *pixel = backdrop.to_rgb24();
+                }
+                self.fill_raw_line(backdrop);
+            }
         }
         // self.mosaic_sfx();
     }
 
+    fn write_palette_16(&mut self, ofs: u32, value: u16) {
+        self.mark_dirty();
+        // only the background palette is cached
+        if ofs < 0x200 {
+            self.tile_cache.invalidate_palette();
+        }
+        self.palette_ram.write_16(ofs, value);
+    }
+
     /// Clears the gpu obj buffer
     pub fn obj_buffer_reset(&mut self) {
         for x in self.obj_buffer.iter_mut() {
@@ -406,6 +615,17 @@ impl Gpu {
         }
     }
 
+    fn correct_frame_colors(&mut self) {
+        for pixel in self.frame_buffer.iter_mut() {
+            *pixel = Rgb15::from_rgb24(*pixel).to_rgb24_lcd();
+        }
+        if let Some(frame) = &mut self.scaled_frame {
+            for pixel in frame.buffer.iter_mut() {
+                *pixel = Rgb15::from_rgb24(*pixel).to_rgb24_lcd();
+            }
+        }
+    }
+
     pub fn get_frame_buffer(&self) -> &[u32] {
         &self.frame_buffer
     }
@@ -414,7 +634,7 @@ impl Gpu {
         &mut self,
         completed: GpuState,
         dma_notifier: &mut D,
-        video_device: &VideoDeviceRcRefCell,
+        video_device: &SharedVideoDevice,
     ) where
         D: DmaNotifer,
     {
@@ -449,7 +669,9 @@ impl Gpu {
                 if self.vcount < DISPLAY_HEIGHT {
                     self.state = HDraw;
                     self.dispstat.set_hblank_flag(false);
-                    self.render_scanline();
+                    if !self.skip_frames {
+                        self.render_scanline();
+                    }
                     // update BG2/3 reference points on the end of a scanline
                     for i in 0..2 {
                         self.bg_aff[i].internal_x += self.bg_aff[i].pb as i16 as i32;
@@ -470,7 +692,8 @@ impl Gpu {
                     };
 
                     dma_notifier.notify(TIMING_VBLANK);
-                    video_device.borrow_mut().render(&self.frame_buffer);
+                    self.frame_count += 1;
+                    self.end_frame(video_device);
                     self.obj_buffer_reset();
                     self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = VBlankHDraw;
@@ -495,7 +718,9 @@ impl Gpu {
                     update_vcount!(0);
                     self.dispstat.set_vblank_flag(false);
                     self.dispstat.set_hblank_flag(false);
-                    self.render_scanline();
+                    if !self.skip_frames {
+                        self.render_scanline();
+                    }
                     self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = HDraw;
                 }
@@ -508,7 +733,7 @@ impl Gpu {
         mut cycles: usize,
         cycles_to_next_event: &mut usize,
         dma_notifier: &mut D,
-        video_device: &VideoDeviceRcRefCell,
+        video_device: &SharedVideoDevice,
     ) where
         D: DmaNotifer,
     {
@@ -549,15 +774,20 @@ impl Bus for Gpu {
     fn write_16(&mut self, addr: Addr, value: u16) {
         let page = (addr >> 24) as usize;
         match page {
-            PAGE_PALRAM => self.palette_ram.write_16(addr & 0x3fe, value),
+            PAGE_PALRAM => self.write_palette_16(addr & 0x3fe, value),
             PAGE_VRAM => {
                 let mut ofs = addr & ((VIDEO_RAM_SIZE as u32) - 1);
                 if ofs > 0x18000 {
                     ofs -= 0x8000;
                 }
+                self.tile_cache.invalidate_vram(ofs);
+                self.mark_dirty();
                 self.vram.write_16(ofs, value)
             }
-            PAGE_OAM => self.oam.write_16(addr & 0x3fe, value),
+            PAGE_OAM => {
+                self.mark_dirty();
+                self.oam.write_16(addr & 0x3fe, value)
+            }
             _ => unreachable!(),
         }
     }
@@ -569,13 +799,15 @@ impl Bus for Gpu {
 
         let page = (addr >> 24) as usize;
         match page {
-            PAGE_PALRAM => self.palette_ram.write_16(addr & 0x3fe, expand_value(value)),
+            PAGE_PALRAM => self.write_palette_16(addr & 0x3fe, expand_value(value)),
             PAGE_VRAM => {
                 let mut ofs = addr & ((VIDEO_RAM_SIZE as u32) - 1);
                 if ofs > 0x18000 {
                     ofs -= 0x8000;
                 }
                 if ofs < self.vram_obj_tiles_start {
+                    self.tile_cache.invalidate_vram(ofs);
+                    self.mark_dirty();
                     self.vram.write_16(ofs & !1, expand_value(value));
                 }
             }
@@ -600,8 +832,7 @@ impl DebugRead for Gpu {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cell::Cell;
-    use std::rc::Rc;
+    use crate::shared;
 
     struct NopDmaNotifer;
     impl DmaNotifer for NopDmaNotifer {
@@ -609,11 +840,11 @@ mod tests {
     }
 
     #[derive(Default)]
-    struct TestVideoInterface {
+    struct TestVideoSink {
         frame_counter: usize,
     }
 
-    impl VideoInterface for TestVideoInterface {
+    impl VideoSink for TestVideoSink {
         fn render(&mut self, _buffer: &[u32]) {
             self.frame_counter += 1;
         }
@@ -621,9 +852,9 @@ mod tests {
 
     #[test]
     fn test_gpu_state_machine() {
-        let mut gpu = Gpu::new(Rc::new(Cell::new(Default::default())));
-        let video = Rc::new(RefCell::new(TestVideoInterface::default()));
-        let video_clone: VideoDeviceRcRefCell = video.clone();
+        let mut gpu = Gpu::new(Default::default());
+        let video = shared(TestVideoSink::default());
+        let video_clone: SharedVideoDevice = video.clone();
         let mut dma_notifier = NopDmaNotifer;
         let mut cycles_to_next_event = CYCLES_FULL_REFRESH;
 
@@ -646,7 +877,7 @@ mod tests {
 
         for line in 0..160 {
             println!("line = {}", line);
-            assert_eq!(video.borrow().frame_counter, 0);
+            assert_eq!(lock(&video).frame_counter, 0);
             assert_eq!(gpu.vcount, line);
             assert_eq!(gpu.state, GpuState::HDraw);
             assert_eq!(gpu.dispstat.get_hblank_flag(), false);
@@ -663,7 +894,7 @@ mod tests {
             assert_eq!(gpu.interrupt_flags.get().LCD_VCounterMatch(), false);
         }
 
-        assert_eq!(video.borrow().frame_counter, 1);
+        assert_eq!(lock(&video).frame_counter, 1);
 
         for line in 0..68 {
             println!("line = {}", 160 + line);
@@ -681,7 +912,7 @@ mod tests {
             update!(CYCLES_HBLANK);
         }
 
-        assert_eq!(video.borrow().frame_counter, 1);
+        assert_eq!(lock(&video).frame_counter, 1);
         assert_eq!(total_cycles, CYCLES_FULL_REFRESH);
 
         assert_eq!(gpu.interrupt_flags.get().LCD_VCounterMatch(), true);
@@ -691,4 +922,126 @@ mod tests {
         assert_eq!(gpu.dispstat.get_vcount_flag(), true);
         assert_eq!(gpu.dispstat.get_hblank_flag(), false);
     }
+
+    #[cfg(feature = "savestates")]
+    #[test]
+    fn test_state_saved_mid_scanline() {
+        let mut gpu = Gpu::new(Default::default());
+        let video: SharedVideoDevice = shared(TestVideoSink::default());
+        let mut dma_notifier = NopDmaNotifer;
+        let mut cycles_to_next_event = CYCLES_FULL_REFRESH;
+
+        for ofs in 0..VIDEO_RAM_SIZE as u32 {
+            gpu.vram.write_8(ofs, (ofs * 7 + ofs / 0x800) as u8);
+        }
+        for ofs in 0..0x200 {
+            gpu.palette_ram.write_8(ofs, (ofs * 13) as u8);
+        }
+        // mode 1 with BG0-2 and WIN0, BG2 rotated so its reference point moves every line
+        gpu.write_dispcnt(0x2701);
+        gpu.backgrounds[2].bgcnt.0 = 0x2000;
+        gpu.bg_aff[0].pa = 0xf0;
+        gpu.bg_aff[0].pb = 0x40;
+        gpu.bg_aff[0].pc = -0x40;
+        gpu.bg_aff[0].pd = 0xf0;
+        gpu.win0.left = 16;
+        gpu.win0.right = 200;
+        gpu.win0.top = 40;
+        gpu.win0.bottom = 120;
+        gpu.win0.flags = WindowFlags::from(0b101);
+        gpu.winout_flags = WindowFlags::from(0b110);
+        gpu.dispstat.set_vcount_setting(100);
+        gpu.dispstat.set_vcount_irq_enable(true);
+
+        // halfway through line 50
+        for _ in 0..50 {
+            gpu.update(
+                CYCLES_HDRAW,
+                &mut cycles_to_next_event,
+                &mut dma_notifier,
+                &video,
+            );
+            gpu.update(
+                CYCLES_HBLANK,
+                &mut cycles_to_next_event,
+                &mut dma_notifier,
+                &video,
+            );
+        }
+        gpu.update(
+            CYCLES_HDRAW / 2,
+            &mut cycles_to_next_event,
+            &mut dma_notifier,
+            &video,
+        );
+
+        let state = bincode::serialize(&gpu).unwrap();
+        let mut loaded: Gpu = bincode::deserialize(&state).unwrap();
+        loaded.reset_after_load(&LoadContext {
+            interrupt_flags: SharedInterruptFlags::new(gpu.interrupt_flags.get()),
+            audio_sample_rate: 44100.0,
+        });
+
+        let rest_of_frame = CYCLES_FULL_REFRESH - 50 * (CYCLES_HDRAW + CYCLES_HBLANK);
+        for gpu in [&mut gpu, &mut loaded].iter_mut() {
+            gpu.update(
+                rest_of_frame - CYCLES_HDRAW / 2,
+                &mut cycles_to_next_event,
+                &mut dma_notifier,
+                &video,
+            );
+        }
+
+        assert_eq!(loaded.frame_buffer, gpu.frame_buffer);
+        assert_eq!(loaded.vcount, 0);
+        assert_eq!(loaded.state, gpu.state);
+        assert_eq!(loaded.dispstat.0, gpu.dispstat.0);
+        assert_eq!(loaded.bg_aff[0].internal_x, gpu.bg_aff[0].internal_x);
+        assert_eq!(loaded.bg_aff[0].internal_y, gpu.bg_aff[0].internal_y);
+        assert_eq!(
+            loaded.interrupt_flags.get().value(),
+            gpu.interrupt_flags.get().value()
+        );
+        assert!(loaded.interrupt_flags.get().LCD_VCounterMatch());
+    }
+
+    #[test]
+    fn test_unchanged_frames() {
+        #[derive(Default)]
+        struct FrameLog(Vec<bool>);
+        impl VideoSink for FrameLog {
+            fn render(&mut self, _buffer: &[u32]) {
+                self.0.push(true);
+            }
+            fn render_unchanged(&mut self, _buffer: &[u32]) {
+                self.0.push(false);
+            }
+        }
+
+        let mut gpu = Gpu::new(Default::default());
+        let log = shared(FrameLog::default());
+        let video: SharedVideoDevice = log.clone();
+
+        gpu.end_frame(&video);
+        gpu.end_frame(&video);
+        assert!(gpu.frame_unchanged());
+
+        // written in vblank, only the next frame changes
+        gpu.vcount = DISPLAY_HEIGHT + 10;
+        gpu.write_16(PALRAM_ADDR, 0x1234);
+        gpu.end_frame(&video);
+        gpu.end_frame(&video);
+
+        // written while drawing, this frame and the next one change
+        gpu.vcount = 80;
+        gpu.write_16(OAM_ADDR, 0x1234);
+        gpu.end_frame(&video);
+        gpu.end_frame(&video);
+        gpu.end_frame(&video);
+
+        assert_eq!(
+            lock(&log).0,
+            vec![true, false, true, false, true, true, false]
+        );
+    }
 }
@@ -0,0 +1,124 @@
+//! On-screen display for emulator notifications.
+//!
+//! Unlike the `Overlay`, which is meant for scripts and tools, the OSD has a fixed layout:
+//! short lived notifications ("State saved", "Screenshot taken") stack up in the bottom left
+//! corner, and status indicators (fast-forward, FPS, recording) stay in the top right corner
+//! until they are cleared. Frontends only report events, the core takes care of drawing them.
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use super::overlay::{draw_text, shade_rect, text_size, LINE_HEIGHT};
+use super::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+
+/// Frames a notification stays on screen, about 2 seconds
+pub const NOTIFICATION_FRAMES: usize = 120;
+/// Notifications fade out during their last frames
+const FADE_FRAMES: usize = 30;
+const MAX_NOTIFICATIONS: usize = 4;
+const MARGIN: i32 = 2;
+const TEXT_COLOR: u32 = 0xffffff;
+const STATUS_COLOR: u32 = 0xffff40;
+
+#[derive(Debug, Clone)]
+pub struct Osd {
+    pub enabled: bool,
+    /// Messages along with the number of frames they are still shown for
+    notifications: VecDeque<(String, usize)>,
+    /// Named indicators, drawn right to left in insertion order
+    status: Vec<(String, String)>,
+}
+
+impl Default for Osd {
+    fn default() -> Osd {
+        Osd {
+            enabled: true,
+            notifications: VecDeque::new(),
+            status: Vec::new(),
+        }
+    }
+}
+
+fn fade(color: u32, frames_left: usize) -> u32 {
+    if frames_left >= FADE_FRAMES {
+        return color;
+    }
+    let scale = |c: u32| (c & 0xff) * frames_left as u32 / FADE_FRAMES as u32;
+    scale(color >> 16) << 16 | scale(color >> 8) << 8 | scale(color)
+}
+
+impl Osd {
+    /// Shows a message for `NOTIFICATION_FRAMES` frames, the oldest message is dropped when
+    /// there are too many
+    pub fn notify(&mut self, message: &str) {
+        if self.notifications.len() == MAX_NOTIFICATIONS {
+            self.notifications.pop_front();
+        }
+        self.notifications
+            .push_back((message.to_string(), NOTIFICATION_FRAMES));
+    }
+
+    /// Sets the status indicator `name`, `None` removes it
+    pub fn set_status(&mut self, name: &str, text: Option<&str>) {
+        let index = self.status.iter().position(|(n, _)| n == name);
+        match (index, text) {
+            (Some(index), Some(text)) => self.status[index].1 = text.to_string(),
+            (Some(index), None) => {
+                self.status.remove(index);
+            }
+            (None, Some(text)) => self.status.push((name.to_string(), text.to_string())),
+            (None, None) => {}
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.notifications.clear();
+        self.status.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.notifications.is_empty() && self.status.is_empty()
+    }
+
+    /// Draws into the frame buffer and ages notifications by a frame
+    pub fn render(&mut self, frame_buffer: &mut [u32]) {
+        if self.enabled {
+            let mut y = DISPLAY_HEIGHT as i32 - MARGIN;
+            for (message, frames_left) in self.notifications.iter().rev() {
+                let (w, h) = text_size(message);
+                y -= h as i32;
+                shade_rect(
+                    frame_buffer,
+                    0,
+                    y - 1,
+                    w as u32 + 2 * MARGIN as u32,
+                    h as u32,
+                );
+                draw_text(
+                    frame_buffer,
+                    MARGIN,
+                    y,
+                    message,
+                    fade(TEXT_COLOR, *frames_left),
+                );
+            }
+
+            let mut x = DISPLAY_WIDTH as i32 - MARGIN;
+            for (_, text) in &self.status {
+                let (w, _) = text_size(text);
+                x -= w as i32;
+                shade_rect(frame_buffer, x - 1, 0, w as u32 + 1, LINE_HEIGHT as u32);
+                draw_text(frame_buffer, x, MARGIN - 1, text, STATUS_COLOR);
+                x -= MARGIN;
+            }
+        }
+
+        for (_, frames_left) in self.notifications.iter_mut() {
+            *frames_left = frames_left.saturating_sub(1);
+        }
+        while let Some((_, 0)) = self.notifications.front() {
+            self.notifications.pop_front();
+        }
+    }
+}
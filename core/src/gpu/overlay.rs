@@ -0,0 +1,172 @@
+//! Text and rectangles drawn on top of the output frame
+//!
+//! Items are drawn into the frame buffer right before it is handed to the video device,
+//! each one stays on screen for a given number of frames.
+
+use super::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+/// Horizontal distance between characters
+pub const CHAR_ADVANCE: usize = GLYPH_WIDTH + 1;
+/// Vertical distance between lines
+pub const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+
+/// 3x5 glyphs for ASCII 0x20..0x7f, each row is 3 bits starting from the top row at bit 14.
+/// Lowercase letters are drawn as uppercase.
+#[rustfmt::skip]
+const FONT: [u16; 95] = [
+    0x0000, 0x2482, 0x5a00, 0x5f7d, 0x3c9e, 0x52a5, 0x2aab, 0x2400, //  !"#$%&'
+    0x1491, 0x4494, 0x0aa8, 0x05d0, 0x0014, 0x01c0, 0x0002, 0x12a4, // ()*+,-./
+    0x7b6f, 0x2c97, 0x73e7, 0x72cf, 0x5bc9, 0x79cf, 0x79ef, 0x7252, // 01234567
+    0x7bef, 0x7bcf, 0x0410, 0x0414, 0x1511, 0x0e38, 0x4454, 0x72c2, // 89:;<=>?
+    0x2be3, 0x2bed, 0x6bae, 0x3923, 0x6b6e, 0x79a7, 0x79a4, 0x396b, // @ABCDEFG
+    0x5bed, 0x7497, 0x126a, 0x5bad, 0x4927, 0x5fed, 0x6b6d, 0x2b6a, // HIJKLMNO
+    0x6ba4, 0x2b73, 0x6bad, 0x388e, 0x7492, 0x5b6f, 0x5b6a, 0x5bfd, // PQRSTUVW
+    0x5aad, 0x5a92, 0x72a7, 0x6926, 0x4889, 0x324b, 0x2a00, 0x0007, // XYZ[\]^_
+    0x4400, 0x2bed, 0x6bae, 0x3923, 0x6b6e, 0x79a7, 0x79a4, 0x396b, // `abcdefg
+    0x5bed, 0x7497, 0x126a, 0x5bad, 0x4927, 0x5fed, 0x6b6d, 0x2b6a, // hijklmno
+    0x6ba4, 0x2b73, 0x6bad, 0x388e, 0x7492, 0x5b6f, 0x5b6a, 0x5bfd, // pqrstuvw
+    0x5aad, 0x5a92, 0x72a7, 0x3593, 0x2492, 0x64d6, 0x0780, // xyz{|}~
+];
+
+fn glyph(c: char) -> u16 {
+    match c as u32 {
+        0x20..=0x7e => FONT[(c as u32 - 0x20) as usize],
+        _ => FONT[('?' as u32 - 0x20) as usize],
+    }
+}
+
+#[inline]
+fn put_pixel(frame_buffer: &mut [u32], x: i32, y: i32, color: u32) {
+    if x >= 0 && y >= 0 && (x as usize) < DISPLAY_WIDTH && (y as usize) < DISPLAY_HEIGHT {
+        frame_buffer[y as usize * DISPLAY_WIDTH + x as usize] = color;
+    }
+}
+
+/// Draws text at (x, y), '\n' starts a new line. Pixels outside the screen are clipped.
+pub fn draw_text(frame_buffer: &mut [u32], x: i32, y: i32, text: &str, color: u32) {
+    let mut pen_x = x;
+    let mut pen_y = y;
+    for c in text.chars() {
+        if c == '\n' {
+            pen_x = x;
+            pen_y += LINE_HEIGHT as i32;
+            continue;
+        }
+        let bits = glyph(c);
+        for row in 0..GLYPH_HEIGHT {
+            for col in 0..GLYPH_WIDTH {
+                let bit = 14 - (row * GLYPH_WIDTH + col);
+                if bits & (1 << bit) != 0 {
+                    put_pixel(frame_buffer, pen_x + col as i32, pen_y + row as i32, color);
+                }
+            }
+        }
+        pen_x += CHAR_ADVANCE as i32;
+    }
+}
+
+pub fn fill_rect(frame_buffer: &mut [u32], x: i32, y: i32, w: u32, h: u32, color: u32) {
+    for py in y..y + h as i32 {
+        for px in x..x + w as i32 {
+            put_pixel(frame_buffer, px, py, color);
+        }
+    }
+}
+
+/// Halves the brightness of the area, used as a see-through background for text
+pub fn shade_rect(frame_buffer: &mut [u32], x: i32, y: i32, w: u32, h: u32) {
+    let x0 = x.max(0) as usize;
+    let y0 = y.max(0) as usize;
+    let x1 = ((x + w as i32).max(0) as usize).min(DISPLAY_WIDTH);
+    let y1 = ((y + h as i32).max(0) as usize).min(DISPLAY_HEIGHT);
+    for py in y0..y1 {
+        for pixel in &mut frame_buffer[py * DISPLAY_WIDTH + x0..py * DISPLAY_WIDTH + x1.max(x0)] {
+            *pixel = (*pixel >> 1) & 0x7f7f7f;
+        }
+    }
+}
+
+/// Size in pixels of the area `text` takes when drawn
+pub fn text_size(text: &str) -> (usize, usize) {
+    let lines = text.split('\n');
+    let mut width = 0;
+    let mut height = 0;
+    for line in lines {
+        width = width.max(line.chars().count() * CHAR_ADVANCE);
+        height += LINE_HEIGHT;
+    }
+    (width, height)
+}
+
+#[derive(Debug, Clone)]
+enum OverlayItem {
+    Text {
+        x: i32,
+        y: i32,
+        text: String,
+        color: u32,
+    },
+    Rect {
+        x: i32,
+        y: i32,
+        w: u32,
+        h: u32,
+        color: u32,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Overlay {
+    /// Items along with the number of frames they are still shown for
+    items: Vec<(OverlayItem, usize)>,
+}
+
+impl Overlay {
+    /// Adds text with a dark shadow so it stays readable on any background
+    pub fn text(&mut self, x: i32, y: i32, text: &str, color: u32, frames: usize) {
+        let item = OverlayItem::Text {
+            x,
+            y,
+            text: text.to_string(),
+            color,
+        };
+        self.items.push((item, frames));
+    }
+
+    pub fn rect(&mut self, x: i32, y: i32, w: u32, h: u32, color: u32, frames: usize) {
+        let item = OverlayItem::Rect { x, y, w, h, color };
+        self.items.push((item, frames));
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Draws all items into the frame buffer, and drops the ones that expired
+    pub fn render(&mut self, frame_buffer: &mut [u32]) {
+        for (item, _) in &self.items {
+            match item {
+                OverlayItem::Text { x, y, text, color } => {
+                    draw_text(frame_buffer, x + 1, y + 1, text, 0);
+                    draw_text(frame_buffer, *x, *y, text, *color);
+                }
+                OverlayItem::Rect { x, y, w, h, color } => {
+                    fill_rect(frame_buffer, *x, *y, *w, *h, *color);
+                }
+            }
+        }
+        for (_, frames) in self.items.iter_mut() {
+            *frames = frames.saturating_sub(1);
+        }
+        self.items.retain(|(_, frames)| *frames > 0);
+    }
+}
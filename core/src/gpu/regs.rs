@@ -136,7 +136,7 @@ bitflags! {
 
 impl From<u16> for BlendFlags {
     fn from(v: u16) -> BlendFlags {
-        BlendFlags::from_bits(v as u32).unwrap()
+        BlendFlags::from_bits_truncate(v as u32)
     }
 }
 
@@ -194,7 +194,7 @@ bitflags! {
 
 impl From<u16> for WindowFlags {
     fn from(v: u16) -> WindowFlags {
-        WindowFlags::from_bits(v as u32).unwrap()
+        WindowFlags::from_bits_truncate(v as u32)
     }
 }
 
@@ -45,6 +45,14 @@ pub(super) static MODE5_VIEWPORT: ViewPort = ViewPort {
 pub(super) mod utils {
     use super::Point;
 
+    /// Where a point `subpixel` away from the screen pixel lands in the texture, both in 1/256
+    /// pixels
+    #[inline]
+    pub fn transform_subpixel(subpixel: Point, pa: i32, pb: i32, pc: i32, pd: i32) -> Point {
+        let (x, y) = subpixel;
+        ((pa * x + pb * y) >> 8, (pc * x + pd * y) >> 8)
+    }
+
     #[inline]
     pub fn transform_bg_point(ref_point: Point, screen_x: i32, pa: i32, pc: i32) -> Point {
         let (ref_x, ref_y) = ref_point;
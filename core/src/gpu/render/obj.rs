@@ -1,5 +1,6 @@
 use super::super::regs::*;
 use super::super::*;
+use super::utils;
 
 const OVRAM: u32 = 0x0601_0000;
 const PALRAM_OFS_FG: u32 = 0x200;
@@ -120,6 +121,13 @@ impl Gpu {
         };
 
         let affine_matrix = self.get_affine_matrix(attrs.affine_index());
+        let (offset_x, offset_y) = utils::transform_subpixel(
+            self.subpixel,
+            affine_matrix.pa,
+            affine_matrix.pb,
+            affine_matrix.pc,
+            affine_matrix.pd,
+        );
 
         let half_width = bbox_w / 2;
         let half_height = bbox_h / 2;
@@ -136,17 +144,14 @@ impl Gpu {
                     if screen_x >= screen_width {
                         break;
                     }
-                    if self
-                        .obj_buffer_get(screen_x as usize, screen_y as usize)
-                        .priority
-                        <= attrs.2.priority()
-                        && !attrs.is_obj_window()
-                    {
+                    if self.obj_pixel_taken(screen_x as usize, screen_y as usize, &attrs) {
                         continue;
                     }
 
-                    let transformed_x = (affine_matrix.pa * ix + affine_matrix.pb * iy) >> 8;
-                    let transformed_y = (affine_matrix.pc * ix + affine_matrix.pd * iy) >> 8;
+                    let transformed_x =
+                        (affine_matrix.pa * ix + affine_matrix.pb * iy + offset_x) >> 8;
+                    let transformed_y =
+                        (affine_matrix.pc * ix + affine_matrix.pd * iy + offset_y) >> 8;
                     let texture_x = transformed_x + obj_w / 2;
                     let texture_y = transformed_y + obj_h / 2;
                     if texture_x >= 0 && texture_x < obj_w && texture_y >= 0 && texture_y < obj_h {
@@ -228,12 +233,7 @@ impl Gpu {
                     if screen_x >= screen_width {
                         break;
                     }
-                    if self
-                        .obj_buffer_get(screen_x as usize, screen_y as usize)
-                        .priority
-                        <= attrs.2.priority()
-                        && !attrs.is_obj_window()
-                    {
+                    if self.obj_pixel_taken(screen_x as usize, screen_y as usize, &attrs) {
                         continue;
                     }
                     let mut sprite_y = screen_y - ref_y;
@@ -275,6 +275,13 @@ impl Gpu {
         }
     }
 
+    /// Objects are drawn in OAM order and the first opaque pixel wins, whatever the priorities.
+    /// An object with a lower OAM index but a lower priority than a background therefore hides
+    /// the objects under it along with itself, which games use to mask sprites.
+    fn obj_pixel_taken(&self, x: usize, y: usize, attrs: &ObjAttrs) -> bool {
+        !attrs.is_obj_window() && !self.obj_buffer_get(x, y).color.is_transparent()
+    }
+
     fn write_obj_pixel(&mut self, x: usize, y: usize, pixel_color: Rgb15, attrs: &ObjAttrs) {
         let mut current_obj = self.obj_buffer_get_mut(x, y);
         let obj_mode = attrs.0.objmode();
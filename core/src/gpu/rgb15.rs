@@ -1,5 +1,6 @@
 //! Helper type to deal with the GBA's 15bit color
 
+use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
 bitfield! {
@@ -12,6 +13,10 @@ bitfield! {
     pub b, set_b: 14, 10;
 }
 
+lazy_static! {
+    static ref LCD_COLORS: Vec<u32> = (0..0x8000).map(|c| Rgb15(c).compute_lcd_color()).collect();
+}
+
 impl Rgb15 {
     pub const BLACK: Rgb15 = Rgb15(0);
     pub const WHITE: Rgb15 = Rgb15(0x7fff);
@@ -21,6 +26,36 @@ impl Rgb15 {
         ((self.r() as u32) << 19) | ((self.g() as u32) << 11) | ((self.b() as u32) << 3)
     }
 
+    /// Like `to_rgb24` but approximates how the color looks on the GBA's LCD,
+    /// which is darker and less saturated than a modern display
+    pub fn to_rgb24_lcd(&self) -> u32 {
+        LCD_COLORS[(self.0 & 0x7fff) as usize]
+    }
+
+    fn compute_lcd_color(&self) -> u32 {
+        const LCD_GAMMA: f32 = 4.0;
+        const OUT_GAMMA: f32 = 2.2;
+
+        let lr = (self.r() as f32 / 31.0).powf(LCD_GAMMA);
+        let lg = (self.g() as f32 / 31.0).powf(LCD_GAMMA);
+        let lb = (self.b() as f32 / 31.0).powf(LCD_GAMMA);
+
+        let mix = |r: f32, g: f32, b: f32| -> u32 {
+            let value = ((r * lr + g * lg + b * lb) / 255.0).powf(1.0 / OUT_GAMMA);
+            (value * 255.0 * 255.0 / 280.0).min(255.0) as u32
+        };
+
+        (mix(255.0, 50.0, 0.0) << 16) | (mix(10.0, 230.0, 30.0) << 8) | mix(50.0, 10.0, 220.0)
+    }
+
+    pub fn from_rgb24(rgb: u32) -> Rgb15 {
+        Rgb15::from_rgb(
+            ((rgb >> 19) & 0x1f) as u16,
+            ((rgb >> 11) & 0x1f) as u16,
+            ((rgb >> 3) & 0x1f) as u16,
+        )
+    }
+
     pub fn from_rgb(r: u16, g: u16, b: u16) -> Rgb15 {
         let mut c = Rgb15(0);
         c.set_r(r);
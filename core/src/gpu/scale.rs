@@ -0,0 +1,141 @@
+//! Render scale for the affine layers.
+//!
+//! With a render scale of N, every scanline is composed again N x N times, each time with the
+//! affine backgrounds and affine objects sampled at a different fraction of a pixel. Rotated and
+//! scaled layers come out smoother ("Mode 7" floors for instance), while the tiled layers and
+//! the regular objects are the same pixels as in the normal frame, only larger.
+//!
+//! The scaled frame is kept next to the normal frame buffer, which is still what the video
+//! device gets, and has no overlay or OSD.
+use super::consts::*;
+use super::Gpu;
+use alloc::vec::Vec;
+
+pub const MAX_RENDER_SCALE: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct ScaledFrame {
+    pub scale: usize,
+    pub buffer: Vec<u32>,
+}
+
+impl ScaledFrame {
+    fn new(scale: usize) -> ScaledFrame {
+        ScaledFrame {
+            scale,
+            buffer: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT * scale * scale],
+        }
+    }
+}
+
+impl Gpu {
+    /// A scale of 1 turns the scaled frame off
+    pub fn set_render_scale(&mut self, scale: usize) {
+        assert!((1..=MAX_RENDER_SCALE).contains(&scale));
+        self.scaled_frame = if scale > 1 {
+            Some(ScaledFrame::new(scale))
+        } else {
+            None
+        };
+    }
+
+    pub fn render_scale(&self) -> usize {
+        self.scaled_frame.as_ref().map_or(1, |frame| frame.scale)
+    }
+
+    /// Draws the current line to the scaled frame, after it was drawn to the frame buffer
+    pub(super) fn render_scaled_line(&mut self) {
+        let scale = self.render_scale();
+        let (affine_bgs, bg_start, bg_end): (&[usize], usize, usize) = match self.dispcnt.mode() {
+            0 => (&[], 0, 3),
+            1 => (&[2], 0, 2),
+            2 => (&[2, 3], 2, 3),
+            3..=5 => (&[], 2, 2),
+            _ => (&[], 0, 0),
+        };
+        let resample = !self.dispcnt.force_blank()
+            && self.dispcnt.mode() <= 5
+            && (!affine_bgs.is_empty() || self.dispcnt.enable_obj());
+
+        let y = self.vcount;
+        let width = DISPLAY_WIDTH * scale;
+        let mut line = [0; DISPLAY_WIDTH];
+        if !resample {
+            line.copy_from_slice(&self.frame_buffer[y * DISPLAY_WIDTH..(y + 1) * DISPLAY_WIDTH]);
+        }
+
+        // the raw frame keeps the pixels of the normal frame
+        let raw_frame = self.raw_frame.take();
+        for sub_y in 0..scale {
+            for sub_x in 0..scale {
+                if resample {
+                    self.subpixel = ((256 * sub_x / scale) as i32, (256 * sub_y / scale) as i32);
+                    for bg in affine_bgs {
+                        if self.dispcnt.enable_bg(*bg) {
+                            self.render_aff_bg(*bg);
+                        }
+                    }
+                    if self.dispcnt.enable_obj() {
+                        for x in 0..DISPLAY_WIDTH {
+                            *self.obj_buffer_get_mut(x, y) = Default::default();
+                        }
+                        self.render_objs();
+                    }
+                    self.compose_scanline(bg_start, bg_end, &mut line);
+                }
+                let frame = self.scaled_frame.as_mut().unwrap();
+                let row = (y * scale + sub_y) * width;
+                for (x, pixel) in line.iter().enumerate() {
+                    frame.buffer[row + x * scale + sub_x] = *pixel;
+                }
+            }
+        }
+        self.subpixel = (0, 0);
+        self.raw_frame = raw_frame;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::Rgb15;
+    use crate::sysbus::consts::*;
+    use crate::Bus;
+
+    #[test]
+    fn test_affine_layers_are_sampled_between_pixels() {
+        let mut gpu = Gpu::new(Default::default());
+        // mode 2, BG2 only, the map is at 0x800 and every entry is tile 0
+        gpu.write_dispcnt(0x0402);
+        gpu.backgrounds[2].bgcnt.0 = 1 << 8;
+        // tile 0 alternates between colors 1 and 2
+        for ofs in (0..0x40).step_by(2) {
+            gpu.write_16(VRAM_ADDR + ofs, 0x0201);
+        }
+        gpu.write_16(PALRAM_ADDR + 2, 0x001f);
+        gpu.write_16(PALRAM_ADDR + 4, 0x03e0);
+        // zoomed out twice horizontally
+        gpu.bg_aff[0].pa = 0x200;
+        gpu.bg_aff[0].pd = 0x100;
+        let red = Rgb15(0x001f).to_rgb24();
+        let green = Rgb15(0x03e0).to_rgb24();
+
+        gpu.set_render_scale(2);
+        gpu.render_scanline();
+        assert_eq!(gpu.frame_buffer[0], red);
+        assert_eq!(gpu.frame_buffer[1], red);
+
+        let frame = gpu.scaled_frame.as_ref().unwrap();
+        assert_eq!(frame.buffer.len(), 4 * DISPLAY_WIDTH * DISPLAY_HEIGHT);
+        let width = 2 * DISPLAY_WIDTH;
+        for row in 0..2 {
+            assert_eq!(
+                frame.buffer[row * width..row * width + 4],
+                [red, green, red, green]
+            );
+        }
+
+        gpu.set_render_scale(1);
+        assert!(gpu.scaled_frame.is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use std::cmp;
+use core::cmp;
 
 use arrayvec::ArrayVec;
 use num::FromPrimitive;
@@ -76,14 +76,19 @@ impl Gpu {
         let y = self.vcount;
         let output = unsafe {
             let ptr = self.frame_buffer[y * DISPLAY_WIDTH..].as_mut_ptr();
-            std::slice::from_raw_parts_mut(ptr, DISPLAY_WIDTH)
+            core::slice::from_raw_parts_mut(ptr, DISPLAY_WIDTH)
         };
+        self.compose_scanline(bg_start, bg_end, output);
+    }
+
+    pub(super) fn compose_scanline(&mut self, bg_start: usize, bg_end: usize, output: &mut [u32]) {
+        let y = self.vcount;
         if !self.dispcnt.is_using_windows() {
             let win = WindowInfo::new(WindowType::WinNone, WindowFlags::all());
             let backgrounds = self.active_backgrounds_sorted(bg_start, bg_end, win.flags);
             for x in 0..DISPLAY_WIDTH {
                 let pixel = self.compose_pixel(x, y, &win, &backgrounds);
-                output[x] = pixel.to_rgb24();
+                self.put_pixel(output, x, pixel);
             }
         } else {
             let mut occupied = [false; DISPLAY_WIDTH];
@@ -93,7 +98,7 @@ impl Gpu {
                 let backgrounds = self.active_backgrounds_sorted(bg_start, bg_end, win.flags);
                 for x in self.win0.left()..self.win0.right() {
                     let pixel = self.compose_pixel(x, y, &win, &backgrounds);
-                    output[x] = pixel.to_rgb24();
+                    self.put_pixel(output, x, pixel);
                     occupied[x] = true;
                     occupied_count += 1;
                 }
@@ -107,7 +112,7 @@ impl Gpu {
                 for x in self.win1.left()..self.win1.right() {
                     if !occupied[x] {
                         let pixel = self.compose_pixel(x, y, &win, &backgrounds);
-                        output[x] = pixel.to_rgb24();
+                        self.put_pixel(output, x, pixel);
                         occupied[x] = true;
                         occupied_count += 1;
                     }
@@ -131,13 +136,13 @@ impl Gpu {
                     if obj_entry.window {
                         // WinObj
                         let pixel = self.compose_pixel(x, y, &win_obj, &win_obj_backgrounds);
-                        output[x] = pixel.to_rgb24();
+                        self.put_pixel(output, x, pixel);
                         occupied[x] = true;
                         occupied_count += 1;
                     } else {
                         // WinOut
                         let pixel = self.compose_pixel(x, y, &win_out, &win_out_backgrounds);
-                        output[x] = pixel.to_rgb24();
+                        self.put_pixel(output, x, pixel);
                         occupied[x] = true;
                         occupied_count += 1;
                     }
@@ -148,7 +153,7 @@ impl Gpu {
                         continue;
                     }
                     let pixel = self.compose_pixel(x, y, &win_out, &win_out_backgrounds);
-                    output[x] = pixel.to_rgb24();
+                    self.put_pixel(output, x, pixel);
                     occupied[x] = true;
                     occupied_count += 1;
                 }
@@ -156,91 +161,271 @@ impl Gpu {
         }
     }
 
-    fn compose_pixel(&self, x: usize, y: usize, win: &WindowInfo, backgrounds: &[usize]) -> Rgb15 {
-        let backdrop_color = Rgb15(self.palette_ram.read_16(0));
+    /// The two topmost visible layers at `x`, the first and second targets of the blending unit.
+    /// The backdrop is behind everything, so there is always a first target.
+    fn visible_layers(
+        &self,
+        x: usize,
+        y: usize,
+        win: &WindowInfo,
+        backgrounds: &[usize],
+    ) -> ArrayVec<[RenderLayer; 2]> {
+        let mut layers = ArrayVec::<[_; 2]>::new();
 
-        let mut layers = ArrayVec::<[_; 7]>::new();
-        unsafe {
-            layers.push_unchecked(RenderLayer::backdrop(backdrop_color));
-        }
+        let obj_entry = self.obj_buffer_get(x, y);
+        let mut obj_layer = if self.dispcnt.enable_obj()
+            && win.flags.obj_enabled()
+            && !obj_entry.color.is_transparent()
+        {
+            Some(RenderLayer::objects(obj_entry.color, obj_entry.priority))
+        } else {
+            None
+        };
 
         for bg in backgrounds.iter() {
             let bg_pixel = self.backgrounds[*bg].line[x];
-            if !bg_pixel.is_transparent() {
-                unsafe {
-                    layers.push_unchecked(RenderLayer::background(
-                        *bg,
-                        bg_pixel,
-                        self.backgrounds[*bg].bgcnt.priority(),
-                    ));
-                }
+            if bg_pixel.is_transparent() {
+                continue;
+            }
+            let bg_layer =
+                RenderLayer::background(*bg, bg_pixel, self.backgrounds[*bg].bgcnt.priority());
+            if obj_layer
+                .as_ref()
+                .is_some_and(|obj| obj.is_above(&bg_layer))
+            {
+                layers.push(obj_layer.take().unwrap());
             }
+            if layers.is_full() {
+                return layers;
+            }
+            layers.push(bg_layer);
+            if layers.is_full() {
+                return layers;
+            }
+        }
+        if let Some(obj) = obj_layer {
+            layers.push(obj);
+        }
+        if !layers.is_full() {
+            layers.push(RenderLayer::backdrop(Rgb15(self.palette_ram.read_16(0))));
         }
+        layers
+    }
 
-        let obj_entry = self.obj_buffer_get(x, y);
-        if self.dispcnt.enable_obj() && win.flags.obj_enabled() && !obj_entry.color.is_transparent()
-        {
-            unsafe {
-                layers.push_unchecked(RenderLayer::objects(obj_entry.color, obj_entry.priority))
-            }
+    fn put_pixel(&mut self, output: &mut [u32], x: usize, pixel: RawPixel) {
+        output[x] = pixel.color.to_rgb24();
+        if let Some(raw_frame) = &mut self.raw_frame {
+            raw_frame[self.vcount * DISPLAY_WIDTH + x] = pixel;
         }
+    }
 
-        // now, sort the layers
-        layers.sort_by_key(|k| (k.priority, k.priority_by_type));
+    fn compose_pixel(
+        &self,
+        x: usize,
+        y: usize,
+        win: &WindowInfo,
+        backgrounds: &[usize],
+    ) -> RawPixel {
+        let layers = self.visible_layers(x, y, win, backgrounds);
+        let top = &layers[0];
+        let effect = self.color_effect(win, top, layers.get(1), self.obj_buffer_get(x, y).alpha);
+        RawPixel {
+            color: effect.unwrap_or(top.pixel),
+            source: top.pixel,
+            layer: top.kind,
+            blended: effect.is_some(),
+        }
+    }
 
-        let top_pixel = layers[0].pixel; // self.layer_to_pixel(x, y, &layers[0]);
-        let mut result = top_pixel;
-        'blend: loop {
-            /* loop hack so we can leave this block early */
-            let obj_sfx = obj_entry.alpha && layers[0].is_object();
-            if win.flags.sfx_enabled() || obj_sfx {
-                let top_layer_flags = self.bldcnt.top();
-                let bot_layer_flags = self.bldcnt.bottom();
+    /// The color of `top` after the color special effects, `None` when there is no effect
+    fn color_effect(
+        &self,
+        win: &WindowInfo,
+        top: &RenderLayer,
+        under: Option<&RenderLayer>,
+        obj_alpha: bool,
+    ) -> Option<Rgb15> {
+        let bottom = under.filter(|layer| self.bldcnt.bottom().contains_render_layer(layer));
+        let alpha_blend = |bottom: &RenderLayer| {
+            top.pixel
+                .blend_with(bottom.pixel, self.bldalpha.eva(), self.bldalpha.evb())
+        };
 
-                if !(top_layer_flags.contains_render_layer(&layers[0]) || obj_sfx) {
-                    break 'blend;
-                }
+        // semi-transparent objects are always first target and alpha blended, but only when the
+        // pixel under them is a second target
+        let obj_sfx = top.is_object() && obj_alpha;
+        if let Some(bottom) = bottom.filter(|_| obj_sfx) {
+            return Some(alpha_blend(bottom));
+        }
 
-                // if this is object alpha blending, ensure that the bottom layer contains a color to blend with
-                let blend_mode = if obj_sfx
-                    && layers.len() > 1
-                    && bot_layer_flags.contains_render_layer(&layers[1])
-                {
-                    BldMode::BldAlpha
-                } else {
-                    self.bldcnt.mode()
-                };
-
-                match blend_mode {
-                    BldMode::BldAlpha => {
-                        let bot_pixel = if layers.len() > 1 {
-                            if !(bot_layer_flags.contains_render_layer(&layers[1])) {
-                                break 'blend;
-                            }
-                            layers[1].pixel //self.layer_to_pixel(x, y, &layers[1])
-                        } else {
-                            backdrop_color
-                        };
-
-                        let eva = self.bldalpha.eva();
-                        let evb = self.bldalpha.evb();
-                        result = top_pixel.blend_with(bot_pixel, eva, evb);
-                    }
-                    BldMode::BldWhite => {
-                        let evy = self.bldy;
-                        result = top_pixel.blend_with(Rgb15::WHITE, 16 - evy, evy);
-                    }
-                    BldMode::BldBlack => {
-                        let evy = self.bldy;
-                        result = top_pixel.blend_with(Rgb15::BLACK, 16 - evy, evy);
-                    }
-                    BldMode::BldNone => {
-                        result = top_pixel;
-                    }
-                }
-            }
-            break 'blend;
+        if !win.flags.sfx_enabled() || !self.bldcnt.top().contains_render_layer(top) {
+            return None;
+        }
+        match self.bldcnt.mode() {
+            BldMode::BldAlpha => bottom.map(alpha_blend),
+            BldMode::BldWhite => Some(top.pixel.blend_with(
+                Rgb15::WHITE,
+                16 - self.bldy,
+                self.bldy,
+            )),
+            BldMode::BldBlack => Some(top.pixel.blend_with(
+                Rgb15::BLACK,
+                16 - self.bldy,
+                self.bldy,
+            )),
+            BldMode::BldNone => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Rgb15 = Rgb15(0x001f);
+    const GREEN: Rgb15 = Rgb15(0x03e0);
+    const BLUE: Rgb15 = Rgb15(0x7c00);
+
+    fn make_gpu() -> Gpu {
+        let mut gpu = Gpu::new(Default::default());
+        // mode 0, everything enabled
+        gpu.write_dispcnt(0x1f00);
+        // hide all the objects
+        for obj in 0..128 {
+            gpu.oam.write_16(obj * 8, 0x200);
+        }
+        gpu
+    }
+
+    fn set_bg(gpu: &mut Gpu, bg: usize, priority: u16, color: Rgb15) {
+        gpu.backgrounds[bg].bgcnt.0 = priority;
+        for x in 0..DISPLAY_WIDTH {
+            gpu.backgrounds[bg].line[x] = color;
         }
-        result
+    }
+
+    /// An 8x8 object on the first line, made of tile `obj` filled with color `obj` of the first
+    /// palette
+    fn set_obj(gpu: &mut Gpu, obj: u32, x: u16, priority: u16, color: Rgb15) {
+        let tile = 0x1_0000 + 0x20 * obj;
+        for offset in 0..0x20 {
+            gpu.vram.write_8(tile + offset, (obj as u8) * 0x11);
+        }
+        gpu.palette_ram.write_16(0x200 + 2 * obj, color.0);
+        gpu.oam.write_16(obj * 8, 0);
+        gpu.oam.write_16(obj * 8 + 2, x);
+        gpu.oam.write_16(obj * 8 + 4, obj as u16 | priority << 10);
+    }
+
+    fn render(gpu: &mut Gpu) -> &[u32] {
+        gpu.obj_buffer_reset();
+        gpu.render_objs();
+        gpu.finalize_scanline(0, 3);
+        &gpu.frame_buffer[..DISPLAY_WIDTH]
+    }
+
+    #[test]
+    fn test_background_priority_ties() {
+        let mut gpu = make_gpu();
+        set_bg(&mut gpu, 2, 1, RED);
+        set_bg(&mut gpu, 1, 1, GREEN);
+        set_bg(&mut gpu, 3, 0, BLUE);
+        gpu.backgrounds[3].line[0] = Rgb15::TRANSPARENT;
+        let line = render(&mut gpu);
+        // the lower background number wins
+        assert_eq!(line[0], GREEN.to_rgb24());
+        assert_eq!(line[1], BLUE.to_rgb24());
+    }
+
+    #[test]
+    fn test_object_wins_priority_tie() {
+        let mut gpu = make_gpu();
+        set_bg(&mut gpu, 0, 2, RED);
+        set_obj(&mut gpu, 1, 0, 2, GREEN);
+        let line = render(&mut gpu);
+        assert_eq!(line[0], GREEN.to_rgb24());
+        assert_eq!(line[8], RED.to_rgb24());
+    }
+
+    #[test]
+    fn test_object_behind_background_masks_objects() {
+        let mut gpu = make_gpu();
+        set_bg(&mut gpu, 0, 1, RED);
+        gpu.backgrounds[0].line[10] = Rgb15::TRANSPARENT;
+        // object 1 is behind the background and covers half of object 2, in front of it
+        set_obj(&mut gpu, 1, 4, 3, GREEN);
+        set_obj(&mut gpu, 2, 0, 0, BLUE);
+        let line = render(&mut gpu);
+        assert_eq!(line[0], BLUE.to_rgb24());
+        // the background shows through object 2
+        assert_eq!(line[4], RED.to_rgb24());
+        assert_eq!(line[10], GREEN.to_rgb24());
+    }
+
+    #[test]
+    fn test_blend_only_with_the_pixel_right_under() {
+        let mut gpu = make_gpu();
+        set_bg(&mut gpu, 0, 0, RED);
+        set_bg(&mut gpu, 2, 1, GREEN);
+        set_bg(&mut gpu, 1, 2, BLUE);
+        gpu.backgrounds[2].line[0] = Rgb15::TRANSPARENT;
+        // BG0 alpha blended on BG1, 50% each
+        gpu.bldcnt.0 = 0x0241;
+        gpu.bldalpha.0 = 0x0808;
+        let line = render(&mut gpu);
+        assert_eq!(line[0], Rgb15::from_rgb(15, 0, 15).to_rgb24());
+        // BG2 is in between and isn't a second target
+        assert_eq!(line[1], RED.to_rgb24());
+    }
+
+    #[test]
+    fn test_semi_transparent_object() {
+        let mut gpu = make_gpu();
+        set_bg(&mut gpu, 1, 1, BLUE);
+        gpu.backgrounds[1].line[1] = Rgb15::TRANSPARENT;
+        set_obj(&mut gpu, 1, 0, 0, GREEN);
+        gpu.oam.write_16(8, 0x400);
+        // brighten BG0 only, BG1 is a second target
+        gpu.bldcnt.0 = 0x0281;
+        gpu.bldalpha.0 = 0x0808;
+        gpu.bldy = 16;
+        let line = render(&mut gpu);
+        // alpha blended whatever the mode
+        assert_eq!(line[0], Rgb15::from_rgb(0, 15, 15).to_rgb24());
+        // no second target under it, and objects aren't a first target
+        assert_eq!(line[1], GREEN.to_rgb24());
+        assert_eq!(line[8], BLUE.to_rgb24());
+    }
+
+    #[test]
+    fn test_raw_frame() {
+        let mut gpu = make_gpu();
+        gpu.set_raw_frame_enabled(true);
+        set_bg(&mut gpu, 0, 0, RED);
+        set_bg(&mut gpu, 1, 1, BLUE);
+        gpu.backgrounds[0].line[1] = Rgb15::TRANSPARENT;
+        gpu.bldcnt.0 = 0x0241;
+        gpu.bldalpha.0 = 0x0808;
+        render(&mut gpu);
+
+        let raw_frame = gpu.raw_frame.as_ref().unwrap();
+        assert_eq!(
+            raw_frame[0],
+            RawPixel {
+                color: Rgb15::from_rgb(15, 0, 15),
+                source: RED,
+                layer: RenderLayerKind::Background0,
+                blended: true,
+            }
+        );
+        assert_eq!(
+            raw_frame[1],
+            RawPixel {
+                color: BLUE,
+                source: BLUE,
+                layer: RenderLayerKind::Background1,
+                blended: false,
+            }
+        );
     }
 }
@@ -0,0 +1,156 @@
+//! Cache of decoded background tiles.
+//!
+//! Text backgrounds decode every tile they cross on every scanline, so a static scene decodes
+//! the same tiles again and again. Decoded tiles are kept until the VRAM holding them or the
+//! background palette is written.
+use super::consts::*;
+use super::{Gpu, PixelFormat, Rgb15};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Key of the 256 colors tiles, 16 colors tiles are keyed by their palette bank
+const KEY_BPP8: u8 = 0x10;
+
+type DecodedTile = [Rgb15; 64];
+
+#[derive(Clone)]
+pub struct TileCache {
+    /// Decoded tiles by their VRAM address / 32, a 256 colors tile is kept in the first of the
+    /// two slots it spans
+    slots: Vec<Vec<(u8, Box<DecodedTile>)>>,
+    /// The background palette was written, the whole cache is dropped before the next lookup
+    palette_dirty: bool,
+}
+
+impl Default for TileCache {
+    fn default() -> TileCache {
+        TileCache {
+            slots: vec![Vec::new(); VIDEO_RAM_SIZE / TILE_SIZE as usize],
+            palette_dirty: false,
+        }
+    }
+}
+
+impl TileCache {
+    fn key(palette_bank: u32, format: PixelFormat) -> u8 {
+        match format {
+            PixelFormat::BPP4 => palette_bank as u8,
+            PixelFormat::BPP8 => KEY_BPP8,
+        }
+    }
+
+    /// Drops the tiles that contain the VRAM offset `ofs`
+    pub fn invalidate_vram(&mut self, ofs: u32) {
+        let slot = (ofs / TILE_SIZE) as usize;
+        self.slots[slot].clear();
+        if slot & 1 != 0 {
+            self.slots[slot - 1].retain(|(key, _)| *key != KEY_BPP8);
+        }
+    }
+
+    /// Palette writes are frequent during fades, so they are only flagged here
+    pub fn invalidate_palette(&mut self) {
+        self.palette_dirty = true;
+    }
+
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            slot.clear();
+        }
+        self.palette_dirty = false;
+    }
+
+    fn get(&self, tile_addr: u32, palette_bank: u32, format: PixelFormat) -> Option<&DecodedTile> {
+        let key = TileCache::key(palette_bank, format);
+        self.slots[(tile_addr / TILE_SIZE) as usize]
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, tile)| tile.as_ref())
+    }
+
+    fn insert(
+        &mut self,
+        tile_addr: u32,
+        palette_bank: u32,
+        format: PixelFormat,
+        tile: DecodedTile,
+    ) {
+        let key = TileCache::key(palette_bank, format);
+        self.slots[(tile_addr / TILE_SIZE) as usize].push((key, Box::new(tile)));
+    }
+}
+
+impl Gpu {
+    fn decode_tile(&self, tile_addr: u32, palette_bank: u32, format: PixelFormat) -> DecodedTile {
+        let mut tile = [Rgb15::TRANSPARENT; 64];
+        for y in 0..8 {
+            for x in 0..8 {
+                let index = self.read_pixel_index(tile_addr, x, y, format);
+                tile[(8 * y + x) as usize] = self.get_palette_color(index as u32, palette_bank, 0);
+            }
+        }
+        tile
+    }
+
+    /// Row `y` of a background tile, decoded to colors
+    pub(super) fn tile_row(
+        &mut self,
+        tile_addr: u32,
+        y: u32,
+        palette_bank: u32,
+        format: PixelFormat,
+    ) -> [Rgb15; 8] {
+        if self.tile_cache.palette_dirty {
+            self.tile_cache.clear();
+        }
+        if self
+            .tile_cache
+            .get(tile_addr, palette_bank, format)
+            .is_none()
+        {
+            let tile = self.decode_tile(tile_addr, palette_bank, format);
+            self.tile_cache
+                .insert(tile_addr, palette_bank, format, tile);
+        }
+        let tile = self
+            .tile_cache
+            .get(tile_addr, palette_bank, format)
+            .unwrap();
+        let mut row = [Rgb15::TRANSPARENT; 8];
+        row.copy_from_slice(&tile[8 * y as usize..8 * y as usize + 8]);
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysbus::consts::*;
+    use crate::Bus;
+
+    #[test]
+    fn test_tiles_are_decoded_again_after_writes() {
+        let mut gpu = Gpu::new(Default::default());
+        // 16 colors, tiles at 0, map at 0xf800 so every entry is tile 0 with palette bank 0
+        gpu.backgrounds[0].bgcnt.0 = 31 << 8;
+        for ofs in (0..0x20).step_by(2) {
+            gpu.write_16(VRAM_ADDR + ofs, 0x1111);
+        }
+        gpu.write_16(PALRAM_ADDR + 2, 0x001f);
+        gpu.write_16(PALRAM_ADDR + 4, 0x03e0);
+
+        gpu.render_reg_bg(0);
+        assert_eq!(gpu.backgrounds[0].line[0], Rgb15(0x001f));
+        assert!(gpu.tile_cache.get(0, 0, PixelFormat::BPP4).is_some());
+
+        gpu.write_16(PALRAM_ADDR + 2, 0x7c00);
+        gpu.render_reg_bg(0);
+        assert_eq!(gpu.backgrounds[0].line[8], Rgb15(0x7c00));
+
+        // the first 4 pixels of the first row
+        gpu.write_16(VRAM_ADDR, 0x2222);
+        gpu.render_reg_bg(0);
+        assert_eq!(gpu.backgrounds[0].line[0], Rgb15(0x03e0));
+        assert_eq!(gpu.backgrounds[0].line[4], Rgb15(0x7c00));
+    }
+}
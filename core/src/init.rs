@@ -0,0 +1,123 @@
+//! Power-on state of the memories and registers that the hardware leaves undefined.
+//!
+//! Real hardware starts with garbage in RAM, and a few games (accidentally) depend on it.
+//! The default is all zeros, the other patterns help reproducing those games' behavior, and a
+//! seeded pattern keeps runs reproducible, which movie recording and TAS tools rely on.
+use alloc::string::String;
+use core::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MemoryFill {
+    #[default]
+    Zero,
+    Ones,
+    /// Pseudo random bytes, the same seed always gives the same contents
+    Random(u64),
+}
+
+impl MemoryFill {
+    pub fn fill(&self, memory: &mut [u8]) {
+        match *self {
+            MemoryFill::Zero => memory.iter_mut().for_each(|byte| *byte = 0),
+            MemoryFill::Ones => memory.iter_mut().for_each(|byte| *byte = 0xff),
+            MemoryFill::Random(seed) => {
+                let mut rng = XorShift64::new(seed);
+                for chunk in memory.chunks_mut(8) {
+                    let bytes = rng.next().to_le_bytes();
+                    chunk.copy_from_slice(&bytes[..chunk.len()]);
+                }
+            }
+        }
+    }
+}
+
+/// Parses "zero", "ff", "random" or "random:<seed>"
+impl TryFrom<&str> for MemoryFill {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "zero" => Ok(MemoryFill::Zero),
+            "ff" => Ok(MemoryFill::Ones),
+            "random" => Ok(MemoryFill::Random(0)),
+            _ => match s.strip_prefix("random:").map(str::parse) {
+                Some(Ok(seed)) => Ok(MemoryFill::Random(seed)),
+                _ => Err(format!("{} is not a valid memory fill pattern", s)),
+            },
+        }
+    }
+}
+
+/// Applied with `GameBoyAdvance::apply_init_options`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InitOptions {
+    pub ewram: MemoryFill,
+    pub iwram: MemoryFill,
+    pub vram: MemoryFill,
+    /// r0-r12 when starting from the cartridge, a BIOS boot overwrites them
+    pub registers: [u32; 13],
+}
+
+impl InitOptions {
+    /// Uses the same pattern for every memory. Random patterns get a different seed for each
+    /// memory so they don't end up with the same contents.
+    pub fn with_fill(fill: MemoryFill) -> InitOptions {
+        let fill_for = |index: u64| match fill {
+            MemoryFill::Random(seed) => MemoryFill::Random(seed.wrapping_add(index)),
+            fill => fill,
+        };
+        InitOptions {
+            ewram: fill_for(0),
+            iwram: fill_for(1),
+            vram: fill_for(2),
+            ..Default::default()
+        }
+    }
+}
+
+/// xorshift64*, small and identical on every platform
+struct XorShift64(u64);
+
+impl XorShift64 {
+    fn new(seed: u64) -> XorShift64 {
+        // the state must never be zero
+        XorShift64(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_fill_is_reproducible() {
+        let mut a = vec![0; 0x101];
+        let mut b = vec![0; 0x101];
+        MemoryFill::Random(42).fill(&mut a);
+        MemoryFill::Random(42).fill(&mut b);
+        assert_eq!(a, b);
+        assert!(a.iter().any(|byte| *byte != 0));
+
+        MemoryFill::Random(43).fill(&mut b);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_parse_memory_fill() {
+        assert_eq!(MemoryFill::try_from("ff"), Ok(MemoryFill::Ones));
+        assert_eq!(
+            MemoryFill::try_from("random:1234"),
+            Ok(MemoryFill::Random(1234))
+        );
+        assert!(MemoryFill::try_from("random:abc").is_err());
+    }
+}
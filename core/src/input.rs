@@ -0,0 +1,418 @@
+//! Input processing between the frontend's `InputSource` and the KEYINPUT register.
+//!
+//! Besides the snapshot polled from the frontend, keys can be driven by:
+//! * Key events timestamped with the frame they happen at, a pressed key stays pressed until its
+//!   release event
+//! * Input queued for a specific frame, which replaces everything else for that frame. This is
+//!   what scripted runs and input movies use
+//! * An analog stick, mapped to the d-pad past a deadzone
+//!
+//! Gamepads following the "standard" layout of the W3C Gamepad API can be mapped to keys with
+//! `standard_gamepad_to_keyinput`.
+//!
+//! Keys with turbo enabled are pressed for `duty` frames out of every `period` frames while held.
+//!
+//! Opposite d-pad directions pressed together, which a keyboard or an input movie can do but not
+//! the d-pad, go through a `DpadFilter`.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::time::Duration;
+
+use bit::BitIndex;
+
+#[cfg(not(feature = "std"))]
+use super::gba::CPU_CLOCK_HZ;
+#[cfg(not(feature = "std"))]
+use super::gpu::consts::CYCLES_FULL_REFRESH;
+use super::keypad::{Keys, KEYINPUT_ALL_RELEASED, NUM_KEYS};
+#[cfg(feature = "std")]
+use super::util::{now, Instant};
+
+pub const DEFAULT_ANALOG_DEADZONE: i16 = 0x2000;
+
+/// Button indices of the standard gamepad layout and the keys they press. Like the GBA, the
+/// right face button is A and the bottom one is B, both shoulder buttons and triggers are L/R.
+pub const STANDARD_GAMEPAD_MAPPING: [(usize, Keys); 12] = [
+    (0, Keys::ButtonB),
+    (1, Keys::ButtonA),
+    (4, Keys::ButtonL),
+    (5, Keys::ButtonR),
+    (6, Keys::ButtonL),
+    (7, Keys::ButtonR),
+    (8, Keys::Select),
+    (9, Keys::Start),
+    (12, Keys::Up),
+    (13, Keys::Down),
+    (14, Keys::Left),
+    (15, Keys::Right),
+];
+
+/// What the game sees of left+right or up+down, some games glitch or crash on them
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DpadFilter {
+    /// Both directions pressed, as the hardware reads them
+    #[default]
+    Hardware,
+    /// Neither direction pressed
+    Block,
+}
+
+/// Parses "hardware" or "block"
+impl TryFrom<&str> for DpadFilter {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, String> {
+        match s {
+            "hardware" => Ok(DpadFilter::Hardware),
+            "block" => Ok(DpadFilter::Block),
+            _ => Err(format!("{} is not a valid d-pad filter", s)),
+        }
+    }
+}
+
+impl DpadFilter {
+    /// Filters `keyinput`, in KEYINPUT format
+    pub fn apply(self, keyinput: u16) -> u16 {
+        let mut keyinput = keyinput;
+        if self == DpadFilter::Block {
+            let opposites = [(Keys::Left, Keys::Right), (Keys::Up, Keys::Down)];
+            for (a, b) in opposites.iter() {
+                if !keyinput.bit(*a as usize) && !keyinput.bit(*b as usize) {
+                    keyinput.set_bit(*a as usize, true);
+                    keyinput.set_bit(*b as usize, true);
+                }
+            }
+        }
+        keyinput
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub frame: usize,
+    pub key: Keys,
+    pub pressed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Turbo {
+    /// Length of a turbo cycle in frames
+    pub period: usize,
+    /// Number of frames the key is pressed at the start of every cycle
+    pub duty: usize,
+}
+
+impl Turbo {
+    pub fn new(period: usize, duty: usize) -> Turbo {
+        let period = period.max(1);
+        Turbo {
+            period,
+            duty: duty.min(period),
+        }
+    }
+
+    fn is_pressed(&self, frame: usize) -> bool {
+        frame % self.period < self.duty
+    }
+}
+
+impl Default for Turbo {
+    /// Pressed every other frame
+    fn default() -> Turbo {
+        Turbo::new(2, 1)
+    }
+}
+
+/// Maps an analog stick position to d-pad bits, in KEYINPUT format (0 is pressed)
+pub fn analog_to_dpad(x: i16, y: i16, deadzone: i16) -> u16 {
+    let mut keyinput = KEYINPUT_ALL_RELEASED;
+    keyinput.set_bit(Keys::Left as usize, x > -deadzone);
+    keyinput.set_bit(Keys::Right as usize, x < deadzone);
+    keyinput.set_bit(Keys::Up as usize, y > -deadzone);
+    keyinput.set_bit(Keys::Down as usize, y < deadzone);
+    keyinput
+}
+
+/// Converts a list of pressed keys to KEYINPUT format
+pub fn keys_to_keyinput(keys: &[Keys]) -> u16 {
+    let mut keyinput = KEYINPUT_ALL_RELEASED;
+    for key in keys {
+        keyinput.set_bit(*key as usize, false);
+    }
+    keyinput
+}
+
+/// Converts the buttons of a standard layout gamepad, indexed like the Gamepad API `buttons`
+/// array, to KEYINPUT format. Missing buttons are released.
+pub fn standard_gamepad_to_keyinput(pressed: &[bool]) -> u16 {
+    let mut keyinput = KEYINPUT_ALL_RELEASED;
+    for (button, key) in STANDARD_GAMEPAD_MAPPING.iter() {
+        if pressed.get(*button).copied().unwrap_or(false) {
+            keyinput.set_bit(*key as usize, false);
+        }
+    }
+    keyinput
+}
+
+#[derive(Debug, Clone)]
+pub struct InputController {
+    /// Keys held through key events, in KEYINPUT format
+    held: u16,
+    events: BTreeMap<usize, Vec<KeyEvent>>,
+    queued: BTreeMap<usize, u16>,
+    turbo: [Option<Turbo>; NUM_KEYS],
+    analog: (i16, i16),
+    pub analog_deadzone: i16,
+    pub dpad_filter: DpadFilter,
+}
+
+impl Default for InputController {
+    fn default() -> InputController {
+        InputController {
+            held: KEYINPUT_ALL_RELEASED,
+            events: BTreeMap::new(),
+            queued: BTreeMap::new(),
+            turbo: [None; NUM_KEYS],
+            analog: (0, 0),
+            analog_deadzone: DEFAULT_ANALOG_DEADZONE,
+            dpad_filter: DpadFilter::default(),
+        }
+    }
+}
+
+impl InputController {
+    pub fn new() -> InputController {
+        InputController::default()
+    }
+
+    /// Schedules a key event, events for past frames are applied on the next poll
+    pub fn push_event(&mut self, event: KeyEvent) {
+        self.events.entry(event.frame).or_default().push(event);
+    }
+
+    /// Forces the pressed keys for a single frame, ignoring every other input source
+    pub fn queue_input_at_frame(&mut self, frame: usize, keys: &[Keys]) {
+        self.queued.insert(frame, keys_to_keyinput(keys));
+    }
+
+    pub fn set_turbo(&mut self, key: Keys, turbo: Option<Turbo>) {
+        self.turbo[key as usize] = turbo;
+    }
+
+    pub fn set_analog(&mut self, x: i16, y: i16) {
+        self.analog = (x, y);
+    }
+
+    /// Drops pending events and queued input, and releases held keys
+    pub fn clear(&mut self) {
+        self.held = KEYINPUT_ALL_RELEASED;
+        self.events.clear();
+        self.queued.clear();
+        self.analog = (0, 0);
+    }
+
+    /// Combines all the input sources into the KEYINPUT value for `frame`
+    pub fn process(&mut self, frame: usize, polled: u16) -> u16 {
+        let pending: Vec<usize> = self.events.range(..=frame).map(|(f, _)| *f).collect();
+        for f in pending {
+            for event in self.events.remove(&f).unwrap() {
+                self.held.set_bit(event.key as usize, !event.pressed);
+            }
+        }
+
+        // Queued frames from the past can't be played anymore
+        self.queued = self.queued.split_off(&frame);
+        if let Some(keyinput) = self.queued.remove(&frame) {
+            return self.dpad_filter.apply(keyinput);
+        }
+
+        let (x, y) = self.analog;
+        let mut keyinput = polled & self.held & analog_to_dpad(x, y, self.analog_deadzone);
+        for (i, turbo) in self.turbo.iter().enumerate() {
+            if let Some(turbo) = turbo {
+                if !keyinput.bit(i) && !turbo.is_pressed(frame) {
+                    keyinput.set_bit(i, true);
+                }
+            }
+        }
+        self.dpad_filter.apply(keyinput)
+    }
+}
+
+/// Time from a key change to the end of the first frame that read KEYINPUT after it, which is
+/// when the game can first react to it. Without `std` there is no clock, the frames run are
+/// counted as a frame of emulated time each.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct InputLatency {
+    /// Of the last change
+    pub last: Duration,
+    /// Frames run from the change to the one that read it, included
+    pub last_frames: u32,
+    pub average: Duration,
+    /// Number of changes measured
+    pub samples: u32,
+}
+
+/// A key change the game didn't read yet
+#[derive(Debug, Clone, Copy)]
+struct PendingChange {
+    #[cfg(feature = "std")]
+    since: Instant,
+    frames: u32,
+}
+
+#[cfg(not(feature = "std"))]
+const FRAME_TIME: Duration =
+    Duration::from_nanos(CYCLES_FULL_REFRESH as u64 * 1_000_000_000 / CPU_CLOCK_HZ as u64);
+
+impl PendingChange {
+    #[cfg(feature = "std")]
+    fn elapsed(&self) -> Duration {
+        self.since.elapsed()
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn elapsed(&self) -> Duration {
+        FRAME_TIME * self.frames
+    }
+}
+
+/// Measures `InputLatency`
+#[derive(Debug, Default)]
+pub(crate) struct LatencyProbe {
+    #[cfg(feature = "std")]
+    key_set: Option<Instant>,
+    pending: Option<PendingChange>,
+}
+
+impl LatencyProbe {
+    /// The frontend changed its input, the next poll will see it
+    pub(crate) fn key_set(&mut self) {
+        #[cfg(feature = "std")]
+        self.key_set.get_or_insert_with(now);
+    }
+
+    /// A new KEYINPUT value was latched for the frame
+    pub(crate) fn polled(&mut self, changed: bool) {
+        #[cfg(feature = "std")]
+        let key_set = self.key_set.take();
+        if changed && self.pending.is_none() {
+            self.pending = Some(PendingChange {
+                #[cfg(feature = "std")]
+                since: key_set.unwrap_or_else(now),
+                frames: 0,
+            });
+        }
+    }
+
+    /// The frame is over, `keyinput_read` if the game read KEYINPUT during it
+    pub(crate) fn frame_done(&mut self, keyinput_read: bool, latency: &mut InputLatency) {
+        if let Some(pending) = &mut self.pending {
+            pending.frames += 1;
+            if keyinput_read {
+                let last = pending.elapsed();
+                let total = latency.average * latency.samples + last;
+                latency.samples += 1;
+                latency.average = total / latency.samples;
+                latency.last = last;
+                latency.last_frames = pending.frames;
+                self.pending = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_events() {
+        let mut input = InputController::new();
+        input.push_event(KeyEvent {
+            frame: 2,
+            key: Keys::ButtonA,
+            pressed: true,
+        });
+        input.push_event(KeyEvent {
+            frame: 4,
+            key: Keys::ButtonA,
+            pressed: false,
+        });
+        let pressed: Vec<bool> = (0..6)
+            .map(|frame| !input.process(frame, KEYINPUT_ALL_RELEASED).bit(0))
+            .collect();
+        assert_eq!(pressed, vec![false, false, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_queued_input_replaces_polled() {
+        let mut input = InputController::new();
+        input.queue_input_at_frame(1, &[Keys::Start]);
+        let polled = keys_to_keyinput(&[Keys::ButtonB]);
+        assert_eq!(input.process(0, polled), polled);
+        assert_eq!(input.process(1, polled), keys_to_keyinput(&[Keys::Start]));
+        assert_eq!(input.process(2, polled), polled);
+    }
+
+    #[test]
+    fn test_turbo_duty_cycle() {
+        let mut input = InputController::new();
+        input.set_turbo(Keys::ButtonB, Some(Turbo::new(4, 3)));
+        let polled = keys_to_keyinput(&[Keys::ButtonB]);
+        let pressed: Vec<bool> = (0..8)
+            .map(|frame| !input.process(frame, polled).bit(Keys::ButtonB as usize))
+            .collect();
+        assert_eq!(
+            pressed,
+            vec![true, true, true, false, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_dpad_filter() {
+        let mut input = InputController::new();
+        let polled = keys_to_keyinput(&[Keys::Left, Keys::Right, Keys::Up, Keys::ButtonA]);
+        assert_eq!(input.process(0, polled), polled);
+
+        input.dpad_filter = DpadFilter::Block;
+        let filtered = keys_to_keyinput(&[Keys::Up, Keys::ButtonA]);
+        assert_eq!(input.process(1, polled), filtered);
+        // input movies too
+        input.queue_input_at_frame(2, &[Keys::Up, Keys::Down]);
+        assert_eq!(input.process(2, polled), KEYINPUT_ALL_RELEASED);
+    }
+
+    #[test]
+    fn test_analog_to_dpad() {
+        let deadzone = DEFAULT_ANALOG_DEADZONE;
+        assert_eq!(analog_to_dpad(0, 0, deadzone), KEYINPUT_ALL_RELEASED);
+        assert_eq!(
+            analog_to_dpad(core::i16::MAX, core::i16::MIN, deadzone),
+            keys_to_keyinput(&[Keys::Right, Keys::Up])
+        );
+    }
+
+    #[test]
+    fn test_standard_gamepad_mapping() {
+        let mut pressed = [false; 17];
+        assert_eq!(
+            standard_gamepad_to_keyinput(&pressed),
+            KEYINPUT_ALL_RELEASED
+        );
+        pressed[1] = true;
+        pressed[7] = true;
+        pressed[14] = true;
+        assert_eq!(
+            standard_gamepad_to_keyinput(&pressed),
+            keys_to_keyinput(&[Keys::ButtonA, Keys::ButtonR, Keys::Left])
+        );
+        // gamepads with fewer buttons
+        assert_eq!(
+            standard_gamepad_to_keyinput(&[true]),
+            keys_to_keyinput(&[Keys::ButtonB])
+        );
+    }
+}
@@ -1,10 +1,12 @@
-use std::cell::Cell;
-use std::rc::Rc;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-pub trait InterruptConnect {
+use super::{LoadContext, ResetAfterLoad};
 
+pub trait InterruptConnect {
     // Connect a SharedInterruptFlags to this interrupt source
     fn connect_irq(&mut self, interrupt_flags: SharedInterruptFlags);
 }
@@ -28,6 +30,45 @@ pub enum Interrupt {
     GamePak = 13,
 }
 
+impl Interrupt {
+    pub const ALL: [Interrupt; 14] = [
+        Interrupt::LCD_VBlank,
+        Interrupt::LCD_HBlank,
+        Interrupt::LCD_VCounterMatch,
+        Interrupt::Timer0_Overflow,
+        Interrupt::Timer1_Overflow,
+        Interrupt::Timer2_Overflow,
+        Interrupt::Timer3_Overflow,
+        Interrupt::SerialCommunication,
+        Interrupt::DMA0,
+        Interrupt::DMA1,
+        Interrupt::DMA2,
+        Interrupt::DMA3,
+        Interrupt::Keypad,
+        Interrupt::GamePak,
+    ];
+
+    /// The short name of the source, as the debugger and the IO trace show it
+    pub fn name(self) -> &'static str {
+        match self {
+            Interrupt::LCD_VBlank => "vblank",
+            Interrupt::LCD_HBlank => "hblank",
+            Interrupt::LCD_VCounterMatch => "vcount",
+            Interrupt::Timer0_Overflow => "timer0",
+            Interrupt::Timer1_Overflow => "timer1",
+            Interrupt::Timer2_Overflow => "timer2",
+            Interrupt::Timer3_Overflow => "timer3",
+            Interrupt::SerialCommunication => "serial",
+            Interrupt::DMA0 => "dma0",
+            Interrupt::DMA1 => "dma1",
+            Interrupt::DMA2 => "dma2",
+            Interrupt::DMA3 => "dma3",
+            Interrupt::Keypad => "keypad",
+            Interrupt::GamePak => "gamepak",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct InterruptController {
     pub interrupt_master_enable: bool,
@@ -50,6 +91,20 @@ impl InterruptController {
             & ((self.interrupt_flags.get().value() & self.interrupt_enable.0) != 0)
     }
 
+    /// Halt ends as soon as an enabled interrupt is requested, even when IME is off
+    #[inline]
+    pub fn halt_released(&self) -> bool {
+        (self.interrupt_flags.get().value() & self.interrupt_enable.0) != 0
+    }
+
+    /// Only the keypad, the link port and the gamepak can wake up from Stop mode
+    pub fn stop_released(&self) -> bool {
+        let wake_up = 1 << Interrupt::Keypad as u16
+            | 1 << Interrupt::SerialCommunication as u16
+            | 1 << Interrupt::GamePak as u16;
+        (self.interrupt_flags.get().value() & self.interrupt_enable.0 & wake_up) != 0
+    }
+
     #[inline]
     pub fn clear(&mut self, value: u16) {
         let _if = self.interrupt_flags.get();
@@ -64,6 +119,12 @@ impl InterruptConnect for InterruptController {
     }
 }
 
+impl ResetAfterLoad for InterruptController {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.connect_irq(ctx.interrupt_flags.clone());
+    }
+}
+
 #[inline]
 pub fn signal_irq(interrupt_flags: &SharedInterruptFlags, i: Interrupt) {
     let _if = interrupt_flags.get();
@@ -112,4 +173,47 @@ bitfield! {
     pub GamePak, set_GamePak: 13;
 }
 
-pub type SharedInterruptFlags = Rc<Cell<IrqBitmask>>;
+/// The IF register, shared by the interrupt controller and every interrupt source.
+/// It is atomic only so that the emulator is `Send`, a single thread accesses it at a time.
+/// Serialized as the value, like the `Rc<Cell<IrqBitmask>>` it replaced.
+#[derive(Clone, Default)]
+pub struct SharedInterruptFlags(Arc<AtomicU16>);
+
+impl SharedInterruptFlags {
+    pub fn new(flags: IrqBitmask) -> SharedInterruptFlags {
+        SharedInterruptFlags(Arc::new(AtomicU16::new(flags.0)))
+    }
+
+    #[inline]
+    pub fn get(&self) -> IrqBitmask {
+        IrqBitmask(self.0.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn set(&self, flags: IrqBitmask) {
+        self.0.store(flags.0, Ordering::Relaxed)
+    }
+
+    /// Whether both are the same register
+    pub fn ptr_eq(&self, other: &SharedInterruptFlags) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SharedInterruptFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+impl Serialize for SharedInterruptFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.get().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SharedInterruptFlags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IrqBitmask::deserialize(deserializer).map(SharedInterruptFlags::new)
+    }
+}
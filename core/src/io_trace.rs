@@ -0,0 +1,220 @@
+//! Readable writes to the IO registers.
+//!
+//! Every write to the IO registers is logged under the `io` target at the trace level, with the
+//! name of the register, and the fields of the registers that are made of flags:
+//! ```text
+//! REG_DISPCNT := mode3 | bg2 | obj (0x1403)
+//! REG_IE := vblank | timer0 (0x0009)
+//! REG_BG2HOFS := 0x0010
+//! REG_DISPCNT[hi] := 0x14
+//! ```
+//! `logging::configure("io=trace")` turns it on. The sound FIFOs are left out, the DMA feeds them
+//! thousands of times a second.
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+
+use num::FromPrimitive;
+
+use super::bus::Addr;
+use super::interrupt::Interrupt;
+use super::iodev::consts::*;
+use super::iodev::io_reg_string;
+use super::keypad::{Keys, NUM_KEYS};
+use super::logging;
+
+/// A write to the IO registers, its `Display` decodes it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IoWrite {
+    pub addr: Addr,
+    pub value: u32,
+    /// 1, 2 or 4 bytes
+    pub width: u8,
+}
+
+/// Logs the write to `addr`, unless it is to a sound FIFO
+#[inline]
+pub fn log_write(addr: Addr, value: u32, width: u8) {
+    if (REG_FIFO_A..REG_FIFO_B + 4).contains(&(addr & !3)) {
+        return;
+    }
+    trace!(target: logging::IO, "{}", IoWrite { addr, value, width });
+}
+
+/// Adds the names of the flags set in `value`, `names` pairs a bit with its name
+fn write_flags(fields: &mut Vec<String>, value: u16, names: &[(u16, &str)]) {
+    for (bit, name) in names {
+        if value & 1 << bit != 0 {
+            fields.push(name.to_string());
+        }
+    }
+}
+
+/// The fields of the registers worth decoding, `None` for the others
+fn decode(reg: Addr, value: u16) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let bits = |shift: u16, width: u16| (value >> shift) & ((1 << width) - 1);
+    match reg {
+        REG_DISPCNT => {
+            fields.push(format!("mode{}", bits(0, 3)));
+            write_flags(
+                &mut fields,
+                value,
+                &[
+                    (3, "cgb"),
+                    (4, "frame1"),
+                    (5, "hblank_free"),
+                    (6, "obj_1d"),
+                    (7, "forced_blank"),
+                    (8, "bg0"),
+                    (9, "bg1"),
+                    (10, "bg2"),
+                    (11, "bg3"),
+                    (12, "obj"),
+                    (13, "win0"),
+                    (14, "win1"),
+                    (15, "objwin"),
+                ],
+            );
+        }
+        REG_DISPSTAT => {
+            write_flags(
+                &mut fields,
+                value,
+                &[(3, "vblank_irq"), (4, "hblank_irq"), (5, "vcount_irq")],
+            );
+            fields.push(format!("vcount={}", bits(8, 8)));
+        }
+        REG_BG0CNT | REG_BG1CNT | REG_BG2CNT | REG_BG3CNT => {
+            fields.push(format!("priority{}", bits(0, 2)));
+            fields.push(format!("char{}", bits(2, 2)));
+            write_flags(&mut fields, value, &[(6, "mosaic"), (7, "8bpp")]);
+            fields.push(format!("screen{}", bits(8, 5)));
+            write_flags(&mut fields, value, &[(13, "wrap")]);
+            fields.push(format!("size{}", bits(14, 2)));
+        }
+        REG_IE | REG_IF => {
+            for source in Interrupt::ALL.iter() {
+                if value & 1 << *source as u16 != 0 {
+                    fields.push(source.name().to_string());
+                }
+            }
+        }
+        REG_IME => fields.push(if value & 1 != 0 { "on" } else { "off" }.to_string()),
+        REG_DMA0CNT_H | REG_DMA1CNT_H | REG_DMA2CNT_H | REG_DMA3CNT_H => {
+            const DST: [&str; 4] = ["dst_inc", "dst_dec", "dst_fixed", "dst_reload"];
+            const SRC: [&str; 4] = ["src_inc", "src_dec", "src_fixed", "src_prohibited"];
+            const TIMING: [&str; 4] = ["now", "vblank", "hblank", "special"];
+            fields.push(DST[bits(5, 2) as usize].to_string());
+            fields.push(SRC[bits(7, 2) as usize].to_string());
+            write_flags(&mut fields, value, &[(9, "repeat")]);
+            fields.push(
+                if value & 1 << 10 != 0 {
+                    "32bit"
+                } else {
+                    "16bit"
+                }
+                .to_string(),
+            );
+            write_flags(&mut fields, value, &[(11, "drq")]);
+            fields.push(TIMING[bits(12, 2) as usize].to_string());
+            write_flags(&mut fields, value, &[(14, "irq"), (15, "enable")]);
+        }
+        REG_TM0CNT_H | REG_TM1CNT_H | REG_TM2CNT_H | REG_TM3CNT_H => {
+            const PRESCALER: [u16; 4] = [1, 64, 256, 1024];
+            fields.push(format!("div{}", PRESCALER[bits(0, 2) as usize]));
+            write_flags(
+                &mut fields,
+                value,
+                &[(2, "cascade"), (6, "irq"), (7, "enable")],
+            );
+        }
+        REG_KEYCNT => {
+            for key in 0..NUM_KEYS as u16 {
+                if value & 1 << key != 0 {
+                    fields.push(format!("{:?}", Keys::from_u16(key).unwrap()));
+                }
+            }
+            write_flags(&mut fields, value, &[(14, "irq"), (15, "and")]);
+        }
+        REG_HALTCNT => fields.push(if value & 0x80 != 0 { "stop" } else { "halt" }.to_string()),
+        _ => return None,
+    }
+    Some(fields)
+}
+
+/// `reg := fields (value)` for a halfword register
+fn write_register(f: &mut fmt::Formatter<'_>, reg: Addr, value: u16) -> fmt::Result {
+    let name = io_reg_string(reg);
+    match decode(reg, value) {
+        Some(fields) if fields.is_empty() => write!(f, "{} := none ({:#06x})", name, value),
+        Some(fields) => write!(f, "{} := {} ({:#06x})", name, fields.join(" | "), value),
+        None => write!(f, "{} := {:#06x}", name, value),
+    }
+}
+
+impl fmt::Display for IoWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.width {
+            1 if self.addr == REG_HALTCNT => write_register(f, self.addr, self.value as u16),
+            1 => {
+                let half = if self.addr & 1 != 0 { "hi" } else { "lo" };
+                write!(
+                    f,
+                    "{}[{}] := {:#04x}",
+                    io_reg_string(self.addr & !1),
+                    half,
+                    self.value
+                )
+            }
+            2 => write_register(f, self.addr, self.value as u16),
+            // the DMA addresses are a single register
+            _ if io_reg_string(self.addr + 2) == "UNKNOWN" => {
+                write!(f, "{} := {:#010x}", io_reg_string(self.addr), self.value)
+            }
+            _ => {
+                write_register(f, self.addr, self.value as u16)?;
+                write!(f, ", ")?;
+                write_register(f, self.addr + 2, (self.value >> 16) as u16)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn show(addr: Addr, value: u32, width: u8) -> String {
+        IoWrite { addr, value, width }.to_string()
+    }
+
+    #[test]
+    fn test_decoded_writes() {
+        assert_eq!(
+            show(REG_DISPCNT, 0x1403, 2),
+            "REG_DISPCNT := mode3 | bg2 | obj (0x1403)"
+        );
+        assert_eq!(
+            show(REG_IE, 0x2009, 2),
+            "REG_IE := vblank | timer0 | gamepak (0x2009)"
+        );
+        assert_eq!(show(REG_IF, 0, 2), "REG_IF := none (0x0000)");
+        assert_eq!(show(REG_BG2HOFS, 0x10, 2), "REG_BG2HOFS := 0x0010");
+        assert_eq!(show(REG_DISPCNT + 1, 0x14, 1), "REG_DISPCNT[hi] := 0x14");
+        assert_eq!(show(REG_HALTCNT, 0x80, 1), "REG_HALTCNT := stop (0x0080)");
+        assert_eq!(
+            show(REG_TM0CNT_L, 0x00c1_fe00, 4),
+            "REG_TM0CNT_L := 0xfe00, REG_TM0CNT_H := div64 | irq | enable (0x00c1)"
+        );
+        assert_eq!(
+            show(REG_DMA3SAD, 0x0800_1000, 4),
+            "REG_DMA3SAD := 0x08001000"
+        );
+        assert_eq!(
+            show(REG_KEYCNT, 0x4009, 2),
+            "REG_KEYCNT := ButtonA | Start | irq (0x4009)"
+        );
+    }
+}
@@ -1,14 +1,22 @@
-use std::cmp;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::cmp;
 
 use super::bus::*;
 use super::dma::DmaController;
 use super::gpu::regs::WindowFlags;
 use super::gpu::*;
-use super::interrupt::{InterruptConnect, InterruptController, SharedInterruptFlags};
+use super::interrupt::{
+    signal_irq, Interrupt, InterruptConnect, InterruptController, SharedInterruptFlags,
+};
 use super::keypad;
+use super::logging;
+use super::sio::{SerialController, SioMode};
 use super::sound::SoundController;
 use super::sysbus::SysBusPtr;
 use super::timer::Timers;
+use super::{LoadContext, ResetAfterLoad};
 
 use serde::{Deserialize, Serialize};
 
@@ -28,10 +36,15 @@ pub struct IoDevices {
     pub sound: Box<SoundController>,
     pub timers: Timers,
     pub dmac: DmaController,
+    pub sio: SerialController,
     pub keyinput: u16,
+    pub keycnt: u16,
     pub post_boot_flag: bool,
     pub waitcnt: WaitControl, // TODO also implement 4000800
     pub haltcnt: HaltState,
+    /// KEYINPUT was read since the last key poll, for the input latency stats
+    #[serde(skip)]
+    pub keyinput_read: Cell<bool>,
 
     // HACK
     // my ownership design sucks
@@ -48,21 +61,49 @@ impl IoDevices {
         timers: Timers,
         sound_controller: Box<SoundController>,
     ) -> IoDevices {
+        let sio = SerialController::new(intc.interrupt_flags.clone());
         IoDevices {
             intc,
             gpu,
             timers,
             dmac,
+            sio,
             sound: sound_controller,
             post_boot_flag: false,
             haltcnt: HaltState::Running,
             keyinput: keypad::KEYINPUT_ALL_RELEASED,
+            keycnt: 0,
             waitcnt: WaitControl(0),
+            keyinput_read: Cell::new(false),
 
             sysbus_ptr: Default::default(),
         }
     }
 
+    pub fn skip_bios(&mut self) {
+        self.post_boot_flag = true;
+        self.haltcnt = HaltState::Running;
+        self.gpu.skip_bios();
+    }
+
+    /// Requests the keypad interrupt when KEYCNT asks for it and its keys are pressed: any of
+    /// them, or all of them with bit 15 set
+    pub fn update_keypad_irq(&self) {
+        if self.keycnt & keypad::KEYCNT_IRQ == 0 {
+            return;
+        }
+        let selected = self.keycnt & keypad::KEYINPUT_ALL_RELEASED;
+        let pressed = !self.keyinput & selected;
+        let condition = if self.keycnt & keypad::KEYCNT_AND != 0 {
+            selected != 0 && pressed == selected
+        } else {
+            pressed != 0
+        };
+        if condition {
+            signal_irq(&self.intc.interrupt_flags, Interrupt::Keypad);
+        }
+    }
+
     pub fn set_sysbus_ptr(&mut self, ptr: SysBusPtr) {
         self.sysbus_ptr = ptr;
     }
@@ -74,9 +115,139 @@ impl InterruptConnect for IoDevices {
         self.gpu.connect_irq(interrupt_flags.clone());
         self.dmac.connect_irq(interrupt_flags.clone());
         self.timers.connect_irq(interrupt_flags.clone());
+        self.sio.connect_irq(interrupt_flags.clone());
+    }
+}
+
+/// The sysbus pointer is set by `SysBus::reset_after_load`
+impl ResetAfterLoad for IoDevices {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.intc.reset_after_load(ctx);
+        self.gpu.reset_after_load(ctx);
+        self.sound.reset_after_load(ctx);
+        self.timers.reset_after_load(ctx);
+        self.dmac.reset_after_load(ctx);
+        self.sio.reset_after_load(ctx);
+    }
+}
+
+/// Size of the IO register map, higher offsets are unused except for the mirrored
+/// memory control register
+const IO_MAP_SIZE: usize = 0x400;
+const REG_MEMCNT_OFFSET: Addr = 0x800;
+
+/// Write-only and unused halfwords, as `[start, end)` offsets from the IO base.
+/// Reading them returns open bus, unlike unused bits of readable registers which read as zero.
+const IO_OPEN_BUS_RANGES: &[(usize, usize)] = &[
+    (0x010, 0x048), // BG offsets, BG2/3 rotation and scaling, WIN0H-WIN1V
+    (0x04c, 0x050), // MOSAIC
+    (0x054, 0x060), // BLDY
+    (0x0a0, 0x0b0), // FIFO_A, FIFO_B
+    (0x0b0, 0x0b8), // DMA0SAD, DMA0DAD
+    (0x0bc, 0x0c4), // DMA1SAD, DMA1DAD
+    (0x0c8, 0x0d0), // DMA2SAD, DMA2DAD
+    (0x0d4, 0x0dc), // DMA3SAD, DMA3DAD
+    (0x0e0, 0x100),
+    (0x110, 0x120),
+    (0x12c, 0x130),
+    (0x138, 0x140),
+    (0x142, 0x150),
+    (0x15a, 0x200),
+    (0x20c, 0x300),
+    (0x304, IO_MAP_SIZE),
+];
+
+lazy_static! {
+    static ref IO_READABLE: Vec<bool> = {
+        let mut readable = vec![true; IO_MAP_SIZE / 2];
+        for (start, end) in IO_OPEN_BUS_RANGES {
+            for halfword in &mut readable[start / 2..end / 2] {
+                *halfword = false;
+            }
+        }
+        readable
+    };
+}
+
+/// Whether reading the IO register at `io_addr` (an offset from the IO base) returns its value,
+/// rather than open bus
+#[inline]
+pub fn is_io_readable(io_addr: Addr) -> bool {
+    match IO_READABLE.get((io_addr / 2) as usize) {
+        Some(readable) => *readable,
+        None => io_addr & !1 == REG_MEMCNT_OFFSET,
     }
 }
 
+/// Writable bits of the registers that have read-only or unused bits.
+/// On writes, read-only bits keep their current value and unused bits stay zero.
+/// Registers missing from this table are fully writable.
+const IO_WRITE_MASK_TABLE: &[(Addr, u16)] = &[
+    (REG_DISPSTAT, 0xff38), // VBlank, HBlank and VCounter flags are read-only
+    (REG_VCOUNT, 0x0000),
+    (REG_BG0CNT, 0xdfff), // no wraparound bit on text backgrounds
+    (REG_BG1CNT, 0xdfff),
+    (REG_BG0HOFS, 0x01ff),
+    (REG_BG0VOFS, 0x01ff),
+    (REG_BG1HOFS, 0x01ff),
+    (REG_BG1VOFS, 0x01ff),
+    (REG_BG2HOFS, 0x01ff),
+    (REG_BG2VOFS, 0x01ff),
+    (REG_BG3HOFS, 0x01ff),
+    (REG_BG3VOFS, 0x01ff),
+    (REG_BG2X_H, 0x0fff),
+    (REG_BG2Y_H, 0x0fff),
+    (REG_BG3X_H, 0x0fff),
+    (REG_BG3Y_H, 0x0fff),
+    (REG_WININ, 0x3f3f),
+    (REG_WINOUT, 0x3f3f),
+    (REG_BLDCNT, 0x3fff),
+    (REG_BLDALPHA, 0x1f1f),
+    (REG_BLDY, 0x001f),
+    (REG_DMA0SAD + 2, 0x07ff),
+    (REG_DMA0DAD + 2, 0x07ff),
+    (REG_DMA0CNT_L, 0x3fff),
+    (REG_DMA0CNT_H, 0xf7e0),
+    (REG_DMA1SAD + 2, 0x0fff),
+    (REG_DMA1DAD + 2, 0x07ff),
+    (REG_DMA1CNT_L, 0x3fff),
+    (REG_DMA1CNT_H, 0xf7e0),
+    (REG_DMA2SAD + 2, 0x0fff),
+    (REG_DMA2DAD + 2, 0x07ff),
+    (REG_DMA2CNT_L, 0x3fff),
+    (REG_DMA2CNT_H, 0xf7e0),
+    (REG_DMA3SAD + 2, 0x0fff),
+    (REG_DMA3DAD + 2, 0x0fff),
+    (REG_DMA3CNT_H, 0xffe0),
+    (REG_TM0CNT_H, 0x00c7),
+    (REG_TM1CNT_H, 0x00c7),
+    (REG_TM2CNT_H, 0x00c7),
+    (REG_TM3CNT_H, 0x00c7),
+    (REG_KEYINPUT, 0x0000),
+    (REG_KEYCNT, 0xc3ff),
+    (REG_IE, 0x3fff),
+    (REG_WAITCNT, 0x5fff), // bit 15 is the read-only gamepak type flag
+    (REG_IME, 0x0001),
+];
+
+lazy_static! {
+    static ref IO_WRITE_MASKS: Vec<u16> = {
+        let mut masks = vec![0xffff; IO_MAP_SIZE / 2];
+        for (reg, mask) in IO_WRITE_MASK_TABLE {
+            masks[((reg - IO_BASE) / 2) as usize] = *mask;
+        }
+        masks
+    };
+}
+
+#[inline]
+fn io_write_mask(io_addr: Addr) -> u16 {
+    IO_WRITE_MASKS
+        .get((io_addr / 2) as usize)
+        .copied()
+        .unwrap_or(0xffff)
+}
+
 impl Bus for IoDevices {
     fn read_16(&self, addr: Addr) -> u16 {
         let io = self;
@@ -110,6 +281,7 @@ impl Bus for IoDevices {
             REG_IF => io.intc.interrupt_flags.get().value() as u16,
 
             REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_read(io_addr),
+            REG_SIOMULTI0..=REG_SIODATA8 | REG_RCNT => io.sio.handle_read(io_addr),
 
             SOUND_BASE..=SOUND_END => io.sound.handle_read(io_addr),
             REG_DMA0CNT_H => io.dmac.channels[0].ctrl.0,
@@ -121,10 +293,14 @@ impl Bus for IoDevices {
 
             REG_POSTFLG => io.post_boot_flag as u16,
             REG_HALTCNT => 0,
-            REG_KEYINPUT => io.keyinput as u16,
+            REG_KEYCNT => io.keycnt,
+            REG_KEYINPUT => {
+                io.keyinput_read.set(true);
+                io.keyinput
+            }
 
             _ => {
-                trace!(
+                trace!(target: logging::BUS,
                     "Unimplemented read from {:x} {}",
                     io_addr,
                     io_reg_string(io_addr)
@@ -150,6 +326,17 @@ impl Bus for IoDevices {
         }
         let io_addr = addr + IO_BASE;
 
+        let mask = io_write_mask(addr);
+        let value = if mask != 0xffff {
+            (io.read_16(addr) & !mask) | (value & mask)
+        } else {
+            value
+        };
+
+        if (REG_DISPCNT..=REG_BLDY).contains(&io_addr) && io_addr != REG_DISPSTAT {
+            io.gpu.mark_dirty();
+        }
+
         macro_rules! write_reference_point {
             (low bg $coord:ident $internal:ident) => {{
                 let i = ((io_addr - REG_BG2X_L) / 0x10) as usize;
@@ -162,8 +349,7 @@ impl Bus for IoDevices {
             (high bg $coord:ident $internal:ident) => {{
                 let i = ((io_addr - REG_BG2X_L) / 0x10) as usize;
                 let t = io.gpu.bg_aff[i].$coord;
-                let new_value =
-                    (t & 0xffff) | ((sign_extend_i32((value & 0xfff) as i32, 12)) << 16);
+                let new_value = (t & 0xffff) | ((sign_extend_i32(value as i32, 12)) << 16);
                 io.gpu.bg_aff[i].$coord = new_value;
                 io.gpu.bg_aff[i].$internal = new_value;
             }};
@@ -171,19 +357,19 @@ impl Bus for IoDevices {
 
         match io_addr {
             REG_DISPCNT => io.gpu.write_dispcnt(value),
-            REG_DISPSTAT => io.gpu.dispstat.0 = value | (io.gpu.dispstat.0 & 7),
+            REG_DISPSTAT => io.gpu.dispstat.0 = value,
             REG_BG0CNT => io.gpu.backgrounds[0].bgcnt.0 = value,
             REG_BG1CNT => io.gpu.backgrounds[1].bgcnt.0 = value,
             REG_BG2CNT => io.gpu.backgrounds[2].bgcnt.0 = value,
             REG_BG3CNT => io.gpu.backgrounds[3].bgcnt.0 = value,
-            REG_BG0HOFS => io.gpu.backgrounds[0].bghofs = value & 0x1ff,
-            REG_BG0VOFS => io.gpu.backgrounds[0].bgvofs = value & 0x1ff,
-            REG_BG1HOFS => io.gpu.backgrounds[1].bghofs = value & 0x1ff,
-            REG_BG1VOFS => io.gpu.backgrounds[1].bgvofs = value & 0x1ff,
-            REG_BG2HOFS => io.gpu.backgrounds[2].bghofs = value & 0x1ff,
-            REG_BG2VOFS => io.gpu.backgrounds[2].bgvofs = value & 0x1ff,
-            REG_BG3HOFS => io.gpu.backgrounds[3].bghofs = value & 0x1ff,
-            REG_BG3VOFS => io.gpu.backgrounds[3].bgvofs = value & 0x1ff,
+            REG_BG0HOFS => io.gpu.backgrounds[0].bghofs = value,
+            REG_BG0VOFS => io.gpu.backgrounds[0].bgvofs = value,
+            REG_BG1HOFS => io.gpu.backgrounds[1].bghofs = value,
+            REG_BG1VOFS => io.gpu.backgrounds[1].bgvofs = value,
+            REG_BG2HOFS => io.gpu.backgrounds[2].bghofs = value,
+            REG_BG2VOFS => io.gpu.backgrounds[2].bgvofs = value,
+            REG_BG3HOFS => io.gpu.backgrounds[3].bghofs = value,
+            REG_BG3VOFS => io.gpu.backgrounds[3].bgvofs = value,
             REG_BG2X_L | REG_BG3X_L => write_reference_point!(low bg x internal_x),
             REG_BG2Y_L | REG_BG3Y_L => write_reference_point!(low bg y internal_y),
             REG_BG2X_H | REG_BG3X_H => write_reference_point!(high bg x internal_x),
@@ -233,13 +419,14 @@ impl Bus for IoDevices {
             REG_MOSAIC => io.gpu.mosaic.0 = value,
             REG_BLDCNT => io.gpu.bldcnt.0 = value,
             REG_BLDALPHA => io.gpu.bldalpha.0 = value,
-            REG_BLDY => io.gpu.bldy = cmp::min(value & 0b11111, 16),
+            REG_BLDY => io.gpu.bldy = cmp::min(value, 16),
 
             REG_IME => io.intc.interrupt_master_enable = value != 0,
             REG_IE => io.intc.interrupt_enable.0 = value,
             REG_IF => io.intc.clear(value),
 
             REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_write(io_addr, value),
+            REG_SIOMULTI0..=REG_SIODATA8 | REG_RCNT => io.sio.handle_write(io_addr, value),
 
             SOUND_BASE..=SOUND_END => {
                 io.sound.handle_write(io_addr, value);
@@ -253,21 +440,17 @@ impl Bus for IoDevices {
 
             REG_WAITCNT => {
                 io.waitcnt.0 = value;
-                (*io.sysbus_ptr).on_waitcnt_written(io.waitcnt);
+                (*io.sysbus_ptr).on_waitcnt_written(&mut io.waitcnt);
             }
 
             REG_POSTFLG => io.post_boot_flag = value != 0,
-            REG_HALTCNT => {
-                if value & 0x80 != 0 {
-                    io.haltcnt = HaltState::Stop;
-                    panic!("Can't handle HaltCtrl == Stop yet");
-                } else {
-                    io.haltcnt = HaltState::Halt;
-                }
+            REG_KEYCNT => {
+                io.keycnt = value;
+                io.update_keypad_irq();
             }
 
             _ => {
-                trace!(
+                trace!(target: logging::BUS,
                     "Unimplemented write to {:x} {}",
                     io_addr,
                     io_reg_string(io_addr)
@@ -286,6 +469,22 @@ impl Bus for IoDevices {
             0x0400_00A4 | 0x0400_00A5 | 0x0400_00A6 | 0x0400_00A7 => {
                 self.sound.write_fifo(1, value as i8)
             }
+            // the PSG registers and the wave RAM
+            io_addr if (SOUND_BASE..REG_FIFO_A).contains(&io_addr) => {
+                self.sound.handle_write_8(io_addr, value)
+            }
+            // a byte register of its own, the halfword write would go to POSTFLG
+            REG_HALTCNT => {
+                self.haltcnt = if value & 0x80 != 0 {
+                    HaltState::Stop
+                } else {
+                    HaltState::Halt
+                };
+            }
+            // without reading SIODATA8, which would take a received byte
+            REG_SIODATA8 if self.sio.mode() == SioMode::Uart => {
+                self.sio.handle_write(REG_SIODATA8, value as u16)
+            }
             _ => {
                 let t = self.read_16(addr & !1);
                 let t = if addr & 1 != 0 {
@@ -301,7 +500,13 @@ impl Bus for IoDevices {
 
 impl DebugRead for IoDevices {
     fn debug_read_8(&self, addr: Addr) -> u8 {
-        self.read_8(addr)
+        // the game didn't read it
+        let keyinput_read = self.keyinput_read.get();
+        let uart = self.sio.uart_read_state();
+        let value = self.read_8(addr);
+        self.keyinput_read.set(keyinput_read);
+        self.sio.set_uart_read_state(uart);
+        value
     }
 }
 
@@ -414,14 +619,14 @@ pub mod consts {
     pub const REG_TM2CNT_H: Addr = 0x0400_010A;     //  2    R/W    Timer 2 Control
     pub const REG_TM3CNT_L: Addr = 0x0400_010C;     //  2    R/W    Timer 3 Counter/Reload
     pub const REG_TM3CNT_H: Addr = 0x0400_010E;     //  2    R/W    Timer 3 Control
-    // pub const REG_SIODATA32: Addr = 0x0400_0120;    //  4    R/W    SIO Data (Normal-32bit Mode; shared with below)
-    // pub const REG_SIOMULTI0: Addr = 0x0400_0120;    //  2    R/W    SIO Data 0 (Parent)    (Multi-Player Mode)
-    // pub const REG_SIOMULTI1: Addr = 0x0400_0122;    //  2    R/W    SIO Data 1 (1st Child) (Multi-Player Mode)
-    // pub const REG_SIOMULTI2: Addr = 0x0400_0124;    //  2    R/W    SIO Data 2 (2nd Child) (Multi-Player Mode)
-    // pub const REG_SIOMULTI3: Addr = 0x0400_0126;    //  2    R/W    SIO Data 3 (3rd Child) (Multi-Player Mode)
-    // pub const REG_SIOCNT: Addr = 0x0400_0128;       //  2    R/W    SIO Control Register
-    // pub const REG_SIOMLT_SEND: Addr = 0x0400_012A;  //  2    R/W    SIO Data (Local of MultiPlayer; shared below)
-    // pub const REG_SIODATA8: Addr = 0x0400_012A;     //  2    R/W    SIO Data (Normal-8bit and UART Mode)
+    pub const REG_SIODATA32: Addr = 0x0400_0120;    //  4    R/W    SIO Data (Normal-32bit Mode; shared with below)
+    pub const REG_SIOMULTI0: Addr = 0x0400_0120;    //  2    R/W    SIO Data 0 (Parent)    (Multi-Player Mode)
+    pub const REG_SIOMULTI1: Addr = 0x0400_0122;    //  2    R/W    SIO Data 1 (1st Child) (Multi-Player Mode)
+    pub const REG_SIOMULTI2: Addr = 0x0400_0124;    //  2    R/W    SIO Data 2 (2nd Child) (Multi-Player Mode)
+    pub const REG_SIOMULTI3: Addr = 0x0400_0126;    //  2    R/W    SIO Data 3 (3rd Child) (Multi-Player Mode)
+    pub const REG_SIOCNT: Addr = 0x0400_0128;       //  2    R/W    SIO Control Register
+    pub const REG_SIOMLT_SEND: Addr = 0x0400_012A;  //  2    R/W    SIO Data (Local of MultiPlayer; shared below)
+    pub const REG_SIODATA8: Addr = 0x0400_012A;     //  2    R/W    SIO Data (Normal-8bit and UART Mode)
     pub const REG_KEYINPUT: Addr = 0x0400_0130;     //  2    R      Key Status
     pub const REG_KEYCNT: Addr = 0x0400_0132;       //  2    R/W    Key Interrupt Control
     pub const REG_RCNT: Addr = 0x0400_0134;         //  2    R/W    SIO Mode Select/General Purpose Data
@@ -523,13 +728,13 @@ pub fn io_reg_string(addr: u32) -> &'static str {
         REG_TM3CNT_L => "REG_TM3CNT_L",
         REG_TM3CNT_H => "REG_TM3CNT_H",
         // REG_SIODATA32 => "REG_SIODATA32",
-        // REG_SIOMULTI0 => "REG_SIOMULTI0",
-        // REG_SIOMULTI1 => "REG_SIOMULTI1",
-        // REG_SIOMULTI2 => "REG_SIOMULTI2",
-        // REG_SIOMULTI3 => "REG_SIOMULTI3",
-        // REG_SIOCNT => "REG_SIOCNT",
+        REG_SIOMULTI0 => "REG_SIOMULTI0",
+        REG_SIOMULTI1 => "REG_SIOMULTI1",
+        REG_SIOMULTI2 => "REG_SIOMULTI2",
+        REG_SIOMULTI3 => "REG_SIOMULTI3",
+        REG_SIOCNT => "REG_SIOCNT",
         // REG_SIOMLT_SEND => "REG_SIOMLT_SEND",
-        // REG_SIODATA8 => "REG_SIODATA8",
+        REG_SIODATA8 => "REG_SIODATA8",
         REG_KEYINPUT => "REG_KEYINPUT",
         REG_KEYCNT => "REG_KEYCNT",
         REG_RCNT => "REG_RCNT",
@@ -1,4 +1,7 @@
-#[derive(Debug, Primitive, PartialEq)]
+use alloc::string::String;
+use core::convert::TryFrom;
+
+#[derive(Debug, Primitive, PartialEq, Eq, Hash, Clone, Copy)]
 #[repr(u8)]
 pub enum Keys {
     ButtonA = 0,
@@ -13,8 +16,32 @@ pub enum Keys {
     ButtonL = 9,
 }
 
+impl TryFrom<&str> for Keys {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        use Keys::*;
+        match s.to_lowercase().as_ref() {
+            "a" => Ok(ButtonA),
+            "b" => Ok(ButtonB),
+            "select" => Ok(Select),
+            "start" => Ok(Start),
+            "right" => Ok(Right),
+            "left" => Ok(Left),
+            "up" => Ok(Up),
+            "down" => Ok(Down),
+            "r" => Ok(ButtonR),
+            "l" => Ok(ButtonL),
+            _ => Err(format!("{} is not a valid key name", s)),
+        }
+    }
+}
+
 pub const NUM_KEYS: usize = 10;
 pub const KEYINPUT_ALL_RELEASED: u16 = 0b1111111111;
+/// KEYCNT bits, the low 10 select the keys like in KEYINPUT
+pub const KEYCNT_IRQ: u16 = 1 << 14;
+pub const KEYCNT_AND: u16 = 1 << 15;
 
 #[derive(Debug, Primitive, PartialEq)]
 #[repr(u8)]
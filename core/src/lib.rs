@@ -1,3 +1,14 @@
+//! Without the `std` feature the core is `no_std` and only needs an allocator, everything that
+//! talks to the operating system (files, clocks, threads) is left to the frontend.
+//! The tests always link `std`.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[macro_use]
+extern crate alloc;
+// Some derives, like `DebugStub`, expand to `::std` paths
+#[cfg(not(any(feature = "std", test)))]
+extern crate core as std;
+
 #[macro_use]
 extern crate serde;
 
@@ -26,32 +37,82 @@ extern crate log;
 #[macro_use]
 extern crate hex_literal;
 
+#[cfg(feature = "std")]
 use zip;
 
-use std::error::Error;
-use std::fmt;
+#[cfg(not(feature = "send"))]
+use alloc::rc::Rc;
+#[cfg(not(feature = "send"))]
+use core::cell::{RefCell, RefMut};
+#[cfg(feature = "send")]
+use std::sync::{Arc, Mutex, MutexGuard};
 
 #[macro_use]
 pub mod util;
 pub mod arm7tdmi;
+#[cfg(feature = "savestates")]
+pub mod batch;
+pub mod bios;
+pub mod bios_db;
 pub mod cartridge;
+#[cfg(feature = "cheats")]
+pub mod cheat_import;
+#[cfg(feature = "cheats")]
+pub mod cheats;
+#[cfg(feature = "std")]
+pub mod config;
+pub mod crash;
+#[cfg(feature = "savestates")]
+pub mod determinism;
+#[cfg(feature = "disassembler")]
 pub mod disass;
+pub mod error;
+pub use error::{CoreError, CoreResult, GBAError, GBAResult, ResultExt};
 pub mod gpu;
 pub mod sound;
 pub mod sysbus;
 pub use sysbus::SysBus;
 pub mod interrupt;
+pub mod io_trace;
 pub mod iodev;
 pub use interrupt::Interrupt;
 pub use interrupt::SharedInterruptFlags;
 pub mod gba;
 pub use gba::GameBoyAdvance;
+pub mod gba_builder;
+pub use gba_builder::GbaBuilder;
 pub mod bus;
 pub mod dma;
+#[cfg(feature = "std")]
+pub mod frame_limiter;
+pub mod init;
+pub mod input;
 pub mod keypad;
+pub mod link;
+pub mod logging;
+pub mod memory;
+#[cfg(all(feature = "std", feature = "savestates"))]
+pub mod netplay;
+pub mod profile;
+pub mod ram_diff;
+#[cfg(feature = "std")]
+pub mod rom_watch;
+#[cfg(all(feature = "std", feature = "savestates"))]
+pub mod save_slots;
+pub mod screenshot;
+pub mod sio;
+#[cfg(feature = "savestates")]
+pub mod state_diff;
+pub mod swi_trace;
+pub mod time_control;
+pub mod timeline;
 pub mod timer;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod uart_bridge;
 pub use bus::*;
-pub(crate) mod overrides;
+pub mod quirks;
 
 #[cfg(feature = "gdb")]
 pub mod gdb;
@@ -59,14 +120,65 @@ pub mod gdb;
 #[cfg(feature = "debugger")]
 pub mod debugger;
 
-pub trait VideoInterface {
+#[cfg(feature = "lua")]
+pub mod scripting;
+
+#[cfg(feature = "rom_db")]
+pub mod rom_db;
+
+/// A device shared by the frontend and the core. With the `send` feature it is an
+/// `Arc<Mutex<T>>`, so that `GameBoyAdvance` can move to another thread.
+#[cfg(not(feature = "send"))]
+pub type Shared<T> = Rc<RefCell<T>>;
+#[cfg(feature = "send")]
+pub type Shared<T> = Arc<Mutex<T>>;
+
+pub fn shared<T>(device: T) -> Shared<T> {
+    #[cfg(not(feature = "send"))]
+    return Rc::new(RefCell::new(device));
+    #[cfg(feature = "send")]
+    return Arc::new(Mutex::new(device));
+}
+
+/// Borrows a shared device for as long as the returned guard lives
+#[cfg(not(feature = "send"))]
+pub fn lock<T: ?Sized>(device: &Shared<T>) -> RefMut<'_, T> {
+    device.borrow_mut()
+}
+
+/// Borrows a shared device for as long as the returned guard lives
+#[cfg(feature = "send")]
+pub fn lock<T: ?Sized>(device: &Shared<T>) -> MutexGuard<'_, T> {
+    device.lock().unwrap()
+}
+
+/// `Send` with the `send` feature, the devices implement it so that they can move along with
+/// the emulator
+#[cfg(not(feature = "send"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "send"))]
+impl<T: ?Sized> MaybeSend for T {}
+#[cfg(feature = "send")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "send")]
+impl<T: ?Sized + Send> MaybeSend for T {}
+
+/// Receives the frames drawn by the core
+pub trait VideoSink: MaybeSend {
     #[allow(unused_variables)]
     fn render(&mut self, buffer: &[u32]) {}
+
+    /// Called instead of `render` when the frame is the same as the previous one, so the upload of
+    /// the frame can be skipped
+    fn render_unchanged(&mut self, buffer: &[u32]) {
+        self.render(buffer)
+    }
 }
 
 pub type StereoSample<T> = (T, T);
 
-pub trait AudioInterface {
+/// Receives the audio samples produced by the core
+pub trait AudioSink: MaybeSend {
     fn get_sample_rate(&self) -> i32 {
         44100
     }
@@ -78,61 +190,110 @@ pub trait AudioInterface {
     fn push_sample(&mut self, samples: StereoSample<i16>) {}
 }
 
-pub trait InputInterface {
+/// Provides the KEYINPUT state, polled once per frame
+pub trait InputSource: MaybeSend {
     fn poll(&mut self) -> u16 {
         keypad::KEYINPUT_ALL_RELEASED
     }
 }
 
-#[derive(Debug)]
-pub enum GBAError {
-    IO(::std::io::Error),
-    CartridgeLoadError(String),
-    #[cfg(feature = "debugger")]
-    DebuggerError(debugger::DebuggerError),
+/// A device that discards video and audio and never presses any key
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullDevice;
+
+impl VideoSink for NullDevice {}
+impl AudioSink for NullDevice {}
+impl InputSource for NullDevice {}
+
+/// The set of devices a frontend hands to the core.
+///
+/// Every device defaults to a `NullDevice`, so a frontend only sets the ones it cares about:
+/// ```ignore
+/// let frontend = Frontend::new().video(video).input(input);
+/// let gba = GameBoyAdvance::new(bios, gamepak, frontend);
+/// ```
+#[derive(Clone)]
+pub struct Frontend {
+    pub video: Shared<dyn VideoSink>,
+    pub audio: Shared<dyn AudioSink>,
+    pub input: Shared<dyn InputSource>,
 }
 
-impl fmt::Display for GBAError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "error: {:?}", self)
+impl Default for Frontend {
+    fn default() -> Frontend {
+        Frontend::new()
     }
 }
 
-impl Error for GBAError {
-    fn description(&self) -> &str {
-        "emulator error"
+impl Frontend {
+    pub fn new() -> Frontend {
+        let null = shared(NullDevice);
+        Frontend {
+            video: null.clone(),
+            audio: null.clone(),
+            input: null,
+        }
     }
-}
 
-pub type GBAResult<T> = Result<T, GBAError>;
+    /// Uses a single object implementing all three traits as every device
+    pub fn from_device<T>(device: Shared<T>) -> Frontend
+    where
+        T: VideoSink + AudioSink + InputSource + 'static,
+    {
+        Frontend {
+            video: device.clone(),
+            audio: device.clone(),
+            input: device,
+        }
+    }
 
-impl From<::std::io::Error> for GBAError {
-    fn from(err: ::std::io::Error) -> GBAError {
-        GBAError::IO(err)
+    pub fn video(mut self, video: Shared<dyn VideoSink>) -> Self {
+        self.video = video;
+        self
     }
-}
 
-#[cfg(feature = "debugger")]
-impl From<debugger::DebuggerError> for GBAError {
-    fn from(err: debugger::DebuggerError) -> GBAError {
-        GBAError::DebuggerError(err)
+    pub fn audio(mut self, audio: Shared<dyn AudioSink>) -> Self {
+        self.audio = audio;
+        self
     }
-}
 
-impl From<zip::result::ZipError> for GBAError {
-    fn from(_err: zip::result::ZipError) -> GBAError {
-        GBAError::IO(::std::io::Error::from(::std::io::ErrorKind::InvalidInput))
+    pub fn input(mut self, input: Shared<dyn InputSource>) -> Self {
+        self.input = input;
+        self
     }
 }
 
+/// What the devices need from the host to become usable after being deserialized
+pub struct LoadContext {
+    pub interrupt_flags: interrupt::SharedInterruptFlags,
+    /// Sample rate of the current audio device, which may differ from the one the state was saved with
+    pub audio_sample_rate: f32,
+}
+
+/// Implemented by everything that is part of a savestate but holds state that can't be serialized:
+/// shared pointers, backpointers and host resources, usually hidden with `#[serde(skip)]`.
+///
+/// `GameBoyAdvance` calls this once on the whole `SysBus` tree after a state is loaded, so
+/// there is no separate initialization step to forget.
+pub trait ResetAfterLoad {
+    fn reset_after_load(&mut self, ctx: &LoadContext);
+}
+
 pub mod prelude {
     pub use super::arm7tdmi;
     pub use super::cartridge::{Cartridge, GamepakBuilder};
     #[cfg(feature = "debugger")]
     pub use super::debugger::Debugger;
+    #[cfg(feature = "std")]
+    pub use super::frame_limiter::{FrameLimiter, PacingMode};
     pub use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+    pub use super::init::{InitOptions, MemoryFill};
+    pub use super::memory::AccessMode;
+    pub use super::screenshot::Screenshot;
+    pub use super::util::RomBuffer;
+    #[cfg(feature = "std")]
     pub use super::util::{read_bin_file, write_bin_file};
     pub use super::Bus;
-    pub use super::{AudioInterface, InputInterface, StereoSample, VideoInterface};
-    pub use super::{GBAError, GBAResult, GameBoyAdvance};
+    pub use super::{AudioSink, Frontend, InputSource, NullDevice, StereoSample, VideoSink};
+    pub use super::{CoreError, CoreResult, GBAError, GBAResult, GameBoyAdvance, GbaBuilder};
 }
@@ -0,0 +1,190 @@
+//! A link cable between emulated consoles.
+//!
+//! `LinkCable::new` makes a cable with a port for each console, plugged in with
+//! `GameBoyAdvance::set_serial_device`. The consoles are meant to run one after the other a
+//! frame at a time, as `netplay::LinkedConsoles` does, so that they exchange the same data
+//! whenever they run the same frames:
+//! ```text
+//! normal        a master clocks the transfer of a console waiting as a slave and they swap
+//!               their data, with no slave waiting the master receives all ones
+//! multiplayer   each console runs the transfer as the parent, what the others sent in their
+//!               last transfer is the data of its children
+//! ```
+//! A slave is clocked by the next transfer of a master, which may only come in a later frame:
+//! the transfers take longer than on hardware, which the games wait for anyway.
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use super::sio::SerialDevice;
+use super::{lock, shared, Shared};
+
+/// What is on the cable, saved along with the consoles to go back to a frame
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkState {
+    /// The data of the normal transfer each console waits to be clocked for
+    waiting: Vec<Option<u32>>,
+    /// The data a master clocked in for the waiting transfer of each console
+    clocked_in: Vec<Option<u32>>,
+    /// SIOMLT_SEND of the last multiplayer transfer of each console
+    multiplayer: Vec<u16>,
+}
+
+pub struct LinkCable {
+    state: Shared<LinkState>,
+}
+
+impl LinkCable {
+    /// A cable for 2 to 4 consoles
+    pub fn new(consoles: usize) -> LinkCable {
+        assert!((2..=4).contains(&consoles));
+        LinkCable {
+            state: shared(LinkState {
+                waiting: vec![None; consoles],
+                clocked_in: vec![None; consoles],
+                multiplayer: vec![0xffff; consoles],
+            }),
+        }
+    }
+
+    /// The port of the `console`th console
+    pub fn port(&self, console: usize) -> LinkPort {
+        assert!(console < lock(&self.state).waiting.len());
+        LinkPort {
+            state: self.state.clone(),
+            console,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        lock(&self.state).clone()
+    }
+
+    pub fn set_state(&self, state: LinkState) {
+        *lock(&self.state) = state;
+    }
+}
+
+/// The end of a `LinkCable` plugged into a console
+pub struct LinkPort {
+    state: Shared<LinkState>,
+    console: usize,
+}
+
+impl SerialDevice for LinkPort {
+    /// The other consoles are always plugged in
+    fn ready(&self) -> bool {
+        true
+    }
+
+    fn transfer_normal(&mut self, data: u32, bits: u32, master: bool) -> Option<u32> {
+        let mask = if bits >= 32 { !0 } else { (1 << bits) - 1 };
+        let console = self.console;
+        let mut state = lock(&self.state);
+        if !master {
+            return match state.clocked_in[console].take() {
+                Some(received) => {
+                    state.waiting[console] = None;
+                    Some(received & mask)
+                }
+                None => {
+                    state.waiting[console] = Some(data);
+                    None
+                }
+            };
+        }
+        let slave = (0..state.waiting.len())
+            .find(|&other| other != console && state.waiting[other].is_some());
+        match slave {
+            Some(slave) => {
+                let received = state.waiting[slave].take().unwrap();
+                state.clocked_in[slave] = Some(data);
+                Some(received & mask)
+            }
+            None => Some(mask),
+        }
+    }
+
+    fn transfer_multiplayer(&mut self, data: u16) -> Option<[u16; 3]> {
+        let console = self.console;
+        let mut state = lock(&self.state);
+        state.multiplayer[console] = data;
+        let mut children = [0xffff; 3];
+        let others = (0..state.multiplayer.len()).filter(|&other| other != console);
+        for (child, other) in children.iter_mut().zip(others) {
+            *child = state.multiplayer[other];
+        }
+        Some(children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::interrupt::{IrqBitmask, SharedInterruptFlags};
+    use crate::iodev::consts::*;
+    use crate::sio::SerialController;
+
+    const SIOCNT_START: u16 = 1 << 7;
+    const SIOCNT_INTERNAL_CLOCK: u16 = 1 << 0;
+    const SIOCNT_MULTIPLAYER: u16 = 2 << 12;
+
+    fn make_sio() -> SerialController {
+        SerialController::new(SharedInterruptFlags::new(IrqBitmask(0)))
+    }
+
+    #[test]
+    fn test_normal_transfer_between_consoles() {
+        let cable = LinkCable::new(2);
+        let (mut master_port, mut slave_port) = (cable.port(0), cable.port(1));
+        let (mut master, mut slave) = (make_sio(), make_sio());
+
+        // nobody listens yet
+        master.handle_write(REG_SIODATA8, 0x34);
+        master.handle_write(REG_SIOCNT, SIOCNT_INTERNAL_CLOCK | SIOCNT_START);
+        master.update(10_000, &mut master_port);
+        assert!(!master.is_busy());
+        assert_eq!(master.handle_read(REG_SIODATA8), 0xff);
+
+        slave.handle_write(REG_SIODATA8, 0x12);
+        slave.handle_write(REG_SIOCNT, SIOCNT_START);
+        slave.update(10_000, &mut slave_port);
+        assert!(slave.is_busy());
+
+        let waiting = cable.state();
+        master.handle_write(REG_SIODATA8, 0x34);
+        master.handle_write(REG_SIOCNT, SIOCNT_INTERNAL_CLOCK | SIOCNT_START);
+        master.update(10_000, &mut master_port);
+        assert_eq!(master.handle_read(REG_SIODATA8), 0x12);
+        slave.update(1, &mut slave_port);
+        assert!(!slave.is_busy());
+        assert_eq!(slave.handle_read(REG_SIODATA8), 0x34);
+
+        // going back to a saved state puts the slave back to waiting
+        cable.set_state(waiting);
+        slave.handle_write(REG_SIOCNT, SIOCNT_START);
+        slave.update(10_000, &mut slave_port);
+        assert!(slave.is_busy());
+    }
+
+    #[test]
+    fn test_multiplayer_transfer_between_consoles() {
+        let cable = LinkCable::new(3);
+        let mut ports: Vec<LinkPort> = (0..3).map(|console| cable.port(console)).collect();
+        let mut consoles: Vec<SerialController> = (0..3).map(|_| make_sio()).collect();
+
+        for (console, (sio, port)) in consoles.iter_mut().zip(ports.iter_mut()).enumerate() {
+            sio.handle_write(REG_SIODATA8, 0x100 + console as u16);
+            sio.handle_write(REG_SIOCNT, SIOCNT_MULTIPLAYER | SIOCNT_START);
+            sio.update(200_000, port);
+            assert!(!sio.is_busy());
+        }
+        // the last one sees what the others sent, and nobody in the 4th place
+        let last = &consoles[2];
+        let received: Vec<u16> = (0..4)
+            .map(|i| last.handle_read(REG_SIOMULTI0 + 2 * i))
+            .collect();
+        assert_eq!(received, [0x102, 0x100, 0x101, 0xffff]);
+    }
+}
@@ -0,0 +1,356 @@
+//! Per-subsystem logging.
+//!
+//! The core logs the cpu, bus, DMA, GPU, sound, cartridge and IO register messages under their
+//! own targets (`rba::cpu`, `rba::bus`, ...), so that tracing one subsystem doesn't drown the
+//! output. A frontend wraps its logger with `install`, which filters the records with levels
+//! that can be changed at runtime, and with `std` keeps the last ones in a ring buffer for the
+//! debugger to show:
+//! ```ignore
+//! logging::install(Box::new(my_logger))?;
+//! logging::configure("warn,dma=trace,cart=info")?;
+//! logging::set_level(Subsystem::Cart, LevelFilter::Off);
+//! for record in logging::recent(20) {
+//!     println!("{}", record);
+//! }
+//! ```
+//! The wrapped logger sees the records that passed the filter, so it should let them all
+//! through itself.
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+
+#[cfg(feature = "std")]
+use log::Level;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use super::{CoreError, CoreResult};
+
+pub const CPU: &str = "rba::cpu";
+pub const BUS: &str = "rba::bus";
+pub const DMA: &str = "rba::dma";
+pub const GPU: &str = "rba::gpu";
+pub const APU: &str = "rba::apu";
+pub const CART: &str = "rba::cart";
+/// The writes to the IO registers, see `io_trace`
+pub const IO: &str = "rba::io";
+
+/// The records kept by the ring buffer unless `set_ring_capacity` says otherwise
+#[cfg(feature = "std")]
+pub const DEFAULT_RING_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Cpu,
+    Bus,
+    Dma,
+    Gpu,
+    Apu,
+    Cart,
+    Io,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 7] = [
+        Subsystem::Cpu,
+        Subsystem::Bus,
+        Subsystem::Dma,
+        Subsystem::Gpu,
+        Subsystem::Apu,
+        Subsystem::Cart,
+        Subsystem::Io,
+    ];
+
+    pub fn target(self) -> &'static str {
+        match self {
+            Subsystem::Cpu => CPU,
+            Subsystem::Bus => BUS,
+            Subsystem::Dma => DMA,
+            Subsystem::Gpu => GPU,
+            Subsystem::Apu => APU,
+            Subsystem::Cart => CART,
+            Subsystem::Io => IO,
+        }
+    }
+
+    /// The target without the `rba::` prefix
+    pub fn name(self) -> &'static str {
+        &self.target()[5..]
+    }
+
+    pub fn from_target(target: &str) -> Option<Subsystem> {
+        Subsystem::ALL
+            .iter()
+            .copied()
+            .find(|subsystem| subsystem.target() == target)
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = CoreError;
+
+    /// Either the name or the target, `dma` or `rba::dma`
+    fn from_str(s: &str) -> CoreResult<Subsystem> {
+        Subsystem::ALL
+            .iter()
+            .copied()
+            .find(|subsystem| subsystem.name() == s || subsystem.target() == s)
+            .ok_or_else(|| CoreError::ConfigError(format!("unknown log subsystem {:?}", s)))
+    }
+}
+
+/// A record kept by the ring buffer
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:<5} {}] {}", self.level, self.target, self.message)
+    }
+}
+
+/// The levels of the subsystems, in the order of `Subsystem::ALL`, then of the other targets
+static LEVELS: [AtomicUsize; 8] = [
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+    AtomicUsize::new(LevelFilter::Info as usize),
+];
+const DEFAULT_INDEX: usize = 7;
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The ring buffer needs a lock, without `std` the records only go to the wrapped logger
+#[cfg(feature = "std")]
+struct Ring {
+    capacity: usize,
+    records: VecDeque<LogRecord>,
+}
+
+#[cfg(feature = "std")]
+lazy_static! {
+    static ref RING: Mutex<Ring> = Mutex::new(Ring {
+        capacity: DEFAULT_RING_CAPACITY,
+        records: VecDeque::new(),
+    });
+}
+
+fn level_filter(value: usize) -> LevelFilter {
+    match value {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+fn index(subsystem: Subsystem) -> usize {
+    Subsystem::ALL.iter().position(|s| *s == subsystem).unwrap()
+}
+
+fn target_level(target: &str) -> LevelFilter {
+    let i = Subsystem::from_target(target).map_or(DEFAULT_INDEX, index);
+    level_filter(LEVELS[i].load(Ordering::Relaxed))
+}
+
+/// Lets the `log` macros through up to the most verbose level in use
+fn update_max_level() {
+    if INSTALLED.load(Ordering::Relaxed) {
+        let max = LEVELS
+            .iter()
+            .map(|level| level.load(Ordering::Relaxed))
+            .max()
+            .unwrap();
+        log::set_max_level(level_filter(max));
+    }
+}
+
+struct SubsystemLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for SubsystemLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= target_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        #[cfg(feature = "std")]
+        push_record(LogRecord {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(feature = "std")]
+fn push_record(record: LogRecord) {
+    let mut ring = RING.lock().unwrap();
+    if ring.capacity == 0 {
+        return;
+    }
+    while ring.records.len() >= ring.capacity {
+        ring.records.pop_front();
+    }
+    ring.records.push_back(record);
+}
+
+/// Installs `inner` as the global logger, behind the per-subsystem levels. Fails if a logger
+/// was already installed.
+pub fn install(inner: Box<dyn Log>) -> Result<(), SetLoggerError> {
+    let logger: &'static SubsystemLogger = Box::leak(Box::new(SubsystemLogger { inner }));
+    log::set_logger(logger)?;
+    INSTALLED.store(true, Ordering::Relaxed);
+    update_max_level();
+    Ok(())
+}
+
+pub fn level(subsystem: Subsystem) -> LevelFilter {
+    level_filter(LEVELS[index(subsystem)].load(Ordering::Relaxed))
+}
+
+pub fn set_level(subsystem: Subsystem, level: LevelFilter) {
+    LEVELS[index(subsystem)].store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// The level of the targets that aren't a subsystem
+pub fn default_level() -> LevelFilter {
+    level_filter(LEVELS[DEFAULT_INDEX].load(Ordering::Relaxed))
+}
+
+pub fn set_default_level(level: LevelFilter) {
+    LEVELS[DEFAULT_INDEX].store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Sets all the levels from a spec like `warn,dma=trace,cart=off`: a bare level applies to
+/// every target, and `subsystem=level` overrides it for one subsystem
+pub fn configure(spec: &str) -> CoreResult<()> {
+    let parse_level = |s: &str| {
+        LevelFilter::from_str(s)
+            .map_err(|_| CoreError::ConfigError(format!("unknown log level {:?}", s)))
+    };
+    let mut default = default_level();
+    let mut overrides = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match item.find('=') {
+            Some(i) => overrides.push((
+                Subsystem::from_str(&item[..i])?,
+                parse_level(&item[i + 1..])?,
+            )),
+            None => default = parse_level(item)?,
+        }
+    }
+    for level in &LEVELS {
+        level.store(default as usize, Ordering::Relaxed);
+    }
+    for (subsystem, level) in overrides {
+        LEVELS[index(subsystem)].store(level as usize, Ordering::Relaxed);
+    }
+    update_max_level();
+    Ok(())
+}
+
+/// The last `count` records, oldest first
+#[cfg(feature = "std")]
+pub fn recent(count: usize) -> Vec<LogRecord> {
+    let ring = RING.lock().unwrap();
+    let skip = ring.records.len().saturating_sub(count);
+    ring.records.iter().skip(skip).cloned().collect()
+}
+
+#[cfg(feature = "std")]
+pub fn clear_recent() {
+    RING.lock().unwrap().records.clear();
+}
+
+/// How many records the ring buffer keeps, 0 disables it
+#[cfg(feature = "std")]
+pub fn set_ring_capacity(capacity: usize) {
+    let mut ring = RING.lock().unwrap();
+    ring.capacity = capacity;
+    while ring.records.len() > capacity {
+        ring.records.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsystem_names() {
+        assert_eq!(Subsystem::from_str("dma").unwrap(), Subsystem::Dma);
+        assert_eq!(Subsystem::from_str("rba::cart").unwrap(), Subsystem::Cart);
+        assert_eq!(Subsystem::from_str("io").unwrap(), Subsystem::Io);
+        assert!(Subsystem::from_str("ppu").is_err());
+        assert_eq!(Subsystem::from_target(GPU), Some(Subsystem::Gpu));
+        assert_eq!(Subsystem::Apu.to_string(), "apu");
+    }
+
+    // the levels and the ring buffer are global, so they are tested together
+    #[test]
+    fn test_levels_and_ring() {
+        configure("warn,dma=trace,cart=off").unwrap();
+        assert_eq!(level(Subsystem::Dma), LevelFilter::Trace);
+        assert_eq!(level(Subsystem::Cart), LevelFilter::Off);
+        assert_eq!(level(Subsystem::Cpu), LevelFilter::Warn);
+        assert_eq!(default_level(), LevelFilter::Warn);
+        assert_eq!(target_level(DMA), LevelFilter::Trace);
+        assert_eq!(target_level("rustboyadvance_core::gba"), LevelFilter::Warn);
+        assert!(configure("dma=loud").is_err());
+        assert!(configure("ppu=info").is_err());
+
+        #[cfg(feature = "std")]
+        {
+            set_ring_capacity(2);
+            for i in 0..3 {
+                push_record(LogRecord {
+                    level: Level::Info,
+                    target: DMA.to_string(),
+                    message: format!("message {}", i),
+                });
+            }
+            let records = recent(10);
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[1].to_string(), "[INFO  rba::dma] message 2");
+            assert_eq!(recent(1), records[1..]);
+            clear_recent();
+            assert!(recent(10).is_empty());
+            set_ring_capacity(DEFAULT_RING_CAPACITY);
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Reading and writing the emulated memory from outside the emulation.
+//!
+//! Tools (scripts, dumpers, cheat engines) go through `GameBoyAdvance::mem` instead of poking
+//! the `SysBus` directly, and choose how the access behaves with `AccessMode`:
+//! ```ignore
+//! let hp = gba.mem().read::<u16>(0x0202_4284)?;
+//! gba.mem().write::<u16>(0x0202_4284, 999)?;
+//! let mut oam = [0u16; 512];
+//! gba.mem().read_into(0x0700_0000, &mut oam)?;
+//! gba.mem().mode(AccessMode::Bus).write::<u16>(0x0400_0000, 0x0403)?;
+//! ```
+use super::bus::{Addr, Bus, DebugRead, DebugWrite};
+use super::sysbus::SysBus;
+use super::{CoreError, CoreResult};
+use alloc::vec::Vec;
+
+/// The last address of the 28 bit address bus
+pub const MAX_ADDR: Addr = 0x0fff_ffff;
+
+/// How reads and writes reach the memory
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AccessMode {
+    /// Reads have no side effects, and writes reach the BIOS and the ROM too
+    #[default]
+    Debug,
+    /// Accesses behave like the cpu's: the BIOS is read protected, IO reads and writes have
+    /// their side effects, and unaligned addresses are rejected
+    Bus,
+}
+
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+/// The sizes the memory can be accessed with: `u8`, `u16` and `u32`
+pub trait MemoryValue: sealed::Sealed + Copy {
+    const SIZE: u32;
+
+    #[doc(hidden)]
+    fn read(sysbus: &SysBus, mode: AccessMode, addr: Addr) -> Self;
+    #[doc(hidden)]
+    fn write(self, sysbus: &mut SysBus, mode: AccessMode, addr: Addr);
+}
+
+impl MemoryValue for u8 {
+    const SIZE: u32 = 1;
+
+    fn read(sysbus: &SysBus, mode: AccessMode, addr: Addr) -> u8 {
+        match mode {
+            AccessMode::Debug => sysbus.debug_read_8(addr),
+            AccessMode::Bus => sysbus.read_8(addr),
+        }
+    }
+
+    fn write(self, sysbus: &mut SysBus, mode: AccessMode, addr: Addr) {
+        match mode {
+            AccessMode::Debug => sysbus.debug_write_8(addr, self),
+            AccessMode::Bus => sysbus.write_8(addr, self),
+        }
+    }
+}
+
+impl MemoryValue for u16 {
+    const SIZE: u32 = 2;
+
+    fn read(sysbus: &SysBus, mode: AccessMode, addr: Addr) -> u16 {
+        match mode {
+            AccessMode::Debug => sysbus.debug_read_16(addr),
+            AccessMode::Bus => sysbus.read_16(addr),
+        }
+    }
+
+    fn write(self, sysbus: &mut SysBus, mode: AccessMode, addr: Addr) {
+        match mode {
+            AccessMode::Debug => sysbus.debug_write_16(addr, self),
+            AccessMode::Bus => sysbus.write_16(addr, self),
+        }
+    }
+}
+
+impl MemoryValue for u32 {
+    const SIZE: u32 = 4;
+
+    fn read(sysbus: &SysBus, mode: AccessMode, addr: Addr) -> u32 {
+        match mode {
+            AccessMode::Debug => sysbus.debug_read_32(addr),
+            AccessMode::Bus => sysbus.read_32(addr),
+        }
+    }
+
+    fn write(self, sysbus: &mut SysBus, mode: AccessMode, addr: Addr) {
+        match mode {
+            AccessMode::Debug => sysbus.debug_write_32(addr, self),
+            AccessMode::Bus => sysbus.write_32(addr, self),
+        }
+    }
+}
+
+/// Access to the memory of a `GameBoyAdvance`, returned by `GameBoyAdvance::mem`
+pub struct Memory<'a> {
+    sysbus: &'a mut SysBus,
+    mode: AccessMode,
+}
+
+impl<'a> Memory<'a> {
+    pub(crate) fn new(sysbus: &'a mut SysBus) -> Memory<'a> {
+        Memory {
+            sysbus,
+            mode: AccessMode::default(),
+        }
+    }
+
+    /// Changes how the following accesses behave, `AccessMode::Debug` by default
+    pub fn mode(mut self, mode: AccessMode) -> Memory<'a> {
+        self.mode = mode;
+        self
+    }
+
+    pub fn access_mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    pub fn read<T: MemoryValue>(&self, addr: Addr) -> CoreResult<T> {
+        self.check::<T>(addr, 1)?;
+        Ok(T::read(self.sysbus, self.mode, addr))
+    }
+
+    pub fn write<T: MemoryValue>(&mut self, addr: Addr, value: T) -> CoreResult<()> {
+        self.check::<T>(addr, 1)?;
+        value.write(self.sysbus, self.mode, addr);
+        Ok(())
+    }
+
+    /// Fills `values` with consecutive values starting at `addr`
+    pub fn read_into<T: MemoryValue>(&self, addr: Addr, values: &mut [T]) -> CoreResult<()> {
+        self.check::<T>(addr, values.len())?;
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = T::read(self.sysbus, self.mode, addr + i as u32 * T::SIZE);
+        }
+        Ok(())
+    }
+
+    /// Writes `values` one after another starting at `addr`
+    pub fn write_slice<T: MemoryValue>(&mut self, addr: Addr, values: &[T]) -> CoreResult<()> {
+        self.check::<T>(addr, values.len())?;
+        for (i, value) in values.iter().enumerate() {
+            value.write(self.sysbus, self.mode, addr + i as u32 * T::SIZE);
+        }
+        Ok(())
+    }
+
+    /// `size` bytes starting at `addr`
+    pub fn read_bytes(&self, addr: Addr, size: u32) -> CoreResult<Vec<u8>> {
+        let mut bytes = vec![0; size as usize];
+        self.read_into(addr, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Checks that `count` values of `T` starting at `addr` are on the bus
+    fn check<T: MemoryValue>(&self, addr: Addr, count: usize) -> CoreResult<()> {
+        if self.mode == AccessMode::Bus && !addr.is_multiple_of(T::SIZE) {
+            return Err(CoreError::MemoryError(format!(
+                "{:#010x} is not aligned to {} bytes",
+                addr,
+                T::SIZE
+            )));
+        }
+        let size = (count as u64) * T::SIZE as u64;
+        if addr as u64 + size > MAX_ADDR as u64 + 1 {
+            return Err(CoreError::MemoryError(format!(
+                "{:#x} bytes at {:#010x} go past the end of the address space",
+                size, addr
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::gba::test_util::make_mock_gba_with_bios;
+    use super::super::GameBoyAdvance;
+    use super::*;
+
+    fn make_gba() -> GameBoyAdvance {
+        make_mock_gba_with_bios(vec![0x5a; 0x4000].into_boxed_slice(), &[])
+    }
+
+    #[test]
+    fn test_read_write_sizes() {
+        let mut gba = make_gba();
+        let mut mem = gba.mem();
+        mem.write::<u32>(0x0300_0000, 0x1122_3344).unwrap();
+        assert_eq!(mem.read::<u8>(0x0300_0000).unwrap(), 0x44);
+        assert_eq!(mem.read::<u16>(0x0300_0002).unwrap(), 0x1122);
+
+        mem.write_slice::<u16>(0x0200_0000, &[1, 2, 3]).unwrap();
+        let mut values = [0u16; 3];
+        mem.read_into(0x0200_0000, &mut values).unwrap();
+        assert_eq!(values, [1, 2, 3]);
+        assert_eq!(mem.read_bytes(0x0200_0000, 4).unwrap(), vec![1, 0, 2, 0]);
+    }
+
+    #[test]
+    fn test_debug_and_bus_access() {
+        let mut gba = make_gba();
+        // the BIOS is only readable while the cpu runs it
+        assert_eq!(gba.mem().read::<u8>(0).unwrap(), 0x5a);
+        assert_ne!(
+            gba.mem().mode(AccessMode::Bus).read::<u32>(0).unwrap(),
+            0x5a5a_5a5a
+        );
+
+        gba.mem().write::<u8>(0x0800_0010, 0x42).unwrap();
+        assert_eq!(gba.mem().read::<u8>(0x0800_0010).unwrap(), 0x42);
+        gba.mem()
+            .mode(AccessMode::Bus)
+            .write::<u8>(0x0800_0011, 0x42)
+            .unwrap();
+        assert_eq!(gba.mem().read::<u8>(0x0800_0011).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_invalid_accesses() {
+        let mut gba = make_gba();
+        assert!(gba.mem().read::<u16>(0x0300_0001).is_ok());
+        assert!(gba
+            .mem()
+            .mode(AccessMode::Bus)
+            .read::<u16>(0x0300_0001)
+            .is_err());
+        assert!(gba.mem().read::<u32>(0x0fff_fffc).is_ok());
+        assert!(gba.mem().read::<u32>(0x1000_0000).is_err());
+        assert!(gba.mem().read_bytes(0x0fff_ff00, 0x200).is_err());
+        assert!(gba.mem().read_bytes(0xffff_ff00, 0x200).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+//! Rollback netplay.
+//!
+//! Every peer runs the whole game. The local input of frame `n` is applied at frame
+//! `n + input_delay` and sent to the other peers right away, so with a delay covering the
+//! latency the remote inputs are there in time. When they are not, the frame runs with the
+//! last known input of the late player, and once its real input arrives the game is rolled
+//! back to the state before the first mispredicted frame and simulated again up to the present.
+//! This relies on the emulation being deterministic: the same state and inputs give the same
+//! frames on every peer.
+//!
+//! The game is anything implementing `RollbackTarget`. A `GameBoyAdvance` is one, with the
+//! inputs of all the players merged into its keypad, which suits games played by taking turns
+//! on a single console. Link cable versus games run as `LinkedConsoles`: one console per
+//! player, each getting the input of its player, linked through their serial ports by a
+//! `LinkCable`.
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use serde::{Deserialize, Serialize};
+
+use super::keypad::KEYINPUT_ALL_RELEASED;
+use super::link::{LinkCable, LinkState};
+use super::{CoreError, CoreResult, GameBoyAdvance};
+
+/// The input of a player for a frame, KEYINPUT style (a cleared bit is a pressed key)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputMessage {
+    pub player: usize,
+    pub frame: u64,
+    pub input: u16,
+}
+
+/// Carries the input messages between the peers.
+/// Messages must arrive, and in the order a player sent them.
+pub trait Transport {
+    fn send(&mut self, message: &InputMessage) -> io::Result<()>;
+    /// The messages received since the last call, without blocking
+    fn receive(&mut self) -> io::Result<Vec<InputMessage>>;
+}
+
+/// A transport between two sessions of the same process, see `ChannelTransport::pair`
+pub struct ChannelTransport {
+    sender: Sender<InputMessage>,
+    receiver: Receiver<InputMessage>,
+}
+
+impl ChannelTransport {
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        let (sender_a, receiver_b) = channel();
+        let (sender_b, receiver_a) = channel();
+        (
+            ChannelTransport {
+                sender: sender_a,
+                receiver: receiver_a,
+            },
+            ChannelTransport {
+                sender: sender_b,
+                receiver: receiver_b,
+            },
+        )
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the other peer is gone")
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, message: &InputMessage) -> io::Result<()> {
+        self.sender.send(*message).map_err(|_| disconnected())
+    }
+
+    fn receive(&mut self) -> io::Result<Vec<InputMessage>> {
+        let mut messages = Vec::new();
+        loop {
+            match self.receiver.try_recv() {
+                Ok(message) => messages.push(message),
+                Err(TryRecvError::Empty) => return Ok(messages),
+                Err(TryRecvError::Disconnected) => return Err(disconnected()),
+            }
+        }
+    }
+}
+
+/// The game a session runs
+pub trait RollbackTarget {
+    fn save_snapshot(&self) -> CoreResult<Vec<u8>>;
+    fn load_snapshot(&mut self, snapshot: &[u8]) -> CoreResult<()>;
+    /// Runs a frame with the input of every player. `resimulating` is set for the frames
+    /// replayed after a rollback, their video and audio are not presented.
+    fn run_frame(&mut self, inputs: &[u16], resimulating: bool);
+}
+
+impl RollbackTarget for GameBoyAdvance {
+    fn save_snapshot(&self) -> CoreResult<Vec<u8>> {
+        Ok(self.save_state()?)
+    }
+
+    fn load_snapshot(&mut self, snapshot: &[u8]) -> CoreResult<()> {
+        self.restore_state(snapshot)
+    }
+
+    fn run_frame(&mut self, inputs: &[u16], resimulating: bool) {
+        // A key is pressed when any player presses it
+        let keyinput = inputs
+            .iter()
+            .fold(KEYINPUT_ALL_RELEASED, |keyinput, input| keyinput & input);
+        self.set_keyinput_override(Some(keyinput));
+        self.set_frame_skipping(resimulating);
+        self.frame();
+        self.set_frame_skipping(false);
+        self.set_keyinput_override(None);
+    }
+}
+
+/// A console per player, linked together, see `link`
+pub struct LinkedConsoles {
+    consoles: Vec<GameBoyAdvance>,
+    cable: LinkCable,
+}
+
+impl LinkedConsoles {
+    /// Plugs the consoles into a cable, in the order of the players
+    pub fn new(mut consoles: Vec<GameBoyAdvance>) -> LinkedConsoles {
+        let cable = LinkCable::new(consoles.len());
+        for (player, console) in consoles.iter_mut().enumerate() {
+            console.set_serial_device(Box::new(cable.port(player)));
+        }
+        LinkedConsoles { consoles, cable }
+    }
+
+    pub fn consoles(&self) -> &[GameBoyAdvance] {
+        &self.consoles
+    }
+
+    /// The console of `player`, for the local player to see and hear
+    pub fn console_mut(&mut self, player: usize) -> &mut GameBoyAdvance {
+        &mut self.consoles[player]
+    }
+}
+
+impl RollbackTarget for LinkedConsoles {
+    fn save_snapshot(&self) -> CoreResult<Vec<u8>> {
+        let states = self
+            .consoles
+            .iter()
+            .map(|console| console.save_state())
+            .collect::<bincode::Result<Vec<Vec<u8>>>>()?;
+        Ok(bincode::serialize(&(states, self.cable.state()))?)
+    }
+
+    fn load_snapshot(&mut self, snapshot: &[u8]) -> CoreResult<()> {
+        let (states, cable): (Vec<Vec<u8>>, LinkState) = bincode::deserialize(snapshot)?;
+        if states.len() != self.consoles.len() {
+            return Err(CoreError::SavestateError(format!(
+                "snapshot of {} consoles, {} are linked",
+                states.len(),
+                self.consoles.len()
+            )));
+        }
+        for (console, state) in self.consoles.iter_mut().zip(states.iter()) {
+            console.restore_state(state)?;
+        }
+        self.cable.set_state(cable);
+        Ok(())
+    }
+
+    fn run_frame(&mut self, inputs: &[u16], resimulating: bool) {
+        for (console, input) in self.consoles.iter_mut().zip(inputs) {
+            console.run_frame(&[*input], resimulating);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionConfig {
+    pub players: usize,
+    /// Frames between a local input and the frame it applies to
+    pub input_delay: u64,
+    /// How far the session runs ahead of the last frame with the input of every player,
+    /// it waits for the late players past that
+    pub max_rollback: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            players: 2,
+            input_delay: 2,
+            max_rollback: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameResult {
+    /// The frame ran, after simulating `resimulated` frames again because of late inputs
+    Advanced { resimulated: u64 },
+    /// Too far ahead of a remote player, the frame didn't run and the local input wasn't taken
+    WaitingForRemote,
+}
+
+pub struct RollbackSession<T: Transport> {
+    transport: T,
+    config: SessionConfig,
+    local_player: usize,
+    /// The next frame to run
+    frame: u64,
+    /// The frame after the last input received from each player
+    next_input: Vec<u64>,
+    /// The inputs received for the frames not confirmed yet
+    inputs: BTreeMap<u64, Vec<Option<u16>>>,
+    /// The inputs the frames not confirmed yet ran with
+    used_inputs: BTreeMap<u64, Vec<u16>>,
+    /// The state at the start of the frames not confirmed yet
+    snapshots: BTreeMap<u64, Vec<u8>>,
+    /// The last input received from each player, the prediction of its next ones
+    last_input: Vec<u16>,
+    rollbacks: u64,
+}
+
+impl<T: Transport> RollbackSession<T> {
+    pub fn new(transport: T, local_player: usize, config: SessionConfig) -> RollbackSession<T> {
+        assert!(local_player < config.players);
+        RollbackSession {
+            transport,
+            config,
+            local_player,
+            frame: 0,
+            // the frames before the input delay run without input
+            next_input: vec![config.input_delay; config.players],
+            inputs: BTreeMap::new(),
+            used_inputs: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            last_input: vec![KEYINPUT_ALL_RELEASED; config.players],
+            rollbacks: 0,
+        }
+    }
+
+    /// The next frame to run
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The frames before this one ran with the input of every player. The inputs of the frames
+    /// after it are kept, even when they are all there, until the frames run.
+    pub fn confirmed_frame(&self) -> u64 {
+        self.next_input
+            .iter()
+            .copied()
+            .min()
+            .unwrap_or(self.frame)
+            .min(self.frame)
+    }
+
+    /// Number of times a late input made the session roll back
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    fn record_input(&mut self, player: usize, frame: u64, input: u16) {
+        let players = self.config.players;
+        self.inputs
+            .entry(frame)
+            .or_insert_with(|| vec![None; players])[player] = Some(input);
+        self.next_input[player] = frame + 1;
+        self.last_input[player] = input;
+    }
+
+    /// The inputs of `frame`, the missing ones being predicted
+    fn frame_inputs(&self, frame: u64) -> Vec<u16> {
+        if frame < self.config.input_delay {
+            return vec![KEYINPUT_ALL_RELEASED; self.config.players];
+        }
+        (0..self.config.players)
+            .map(|player| {
+                self.inputs
+                    .get(&frame)
+                    .and_then(|inputs| inputs[player])
+                    .unwrap_or(self.last_input[player])
+            })
+            .collect()
+    }
+
+    /// Takes the remote inputs, returns the first frame that ran with a wrong prediction
+    fn receive_inputs(&mut self) -> io::Result<Option<u64>> {
+        let mut mispredicted: Option<u64> = None;
+        for message in self.transport.receive()? {
+            if message.player >= self.config.players
+                || message.player == self.local_player
+                || message.frame < self.next_input[message.player]
+            {
+                warn!("netplay: ignoring unexpected input {:?}", message);
+                continue;
+            }
+            self.record_input(message.player, message.frame, message.input);
+            let wrong = self
+                .used_inputs
+                .get(&message.frame)
+                .is_some_and(|used| used[message.player] != message.input);
+            if wrong {
+                mispredicted = Some(mispredicted.map_or(message.frame, |f| f.min(message.frame)));
+            }
+        }
+        Ok(mispredicted)
+    }
+
+    /// Goes back to the start of `from` and runs the frames up to the present again
+    fn rollback<G: RollbackTarget>(&mut self, game: &mut G, from: u64) -> CoreResult<u64> {
+        game.load_snapshot(&self.snapshots[&from])?;
+        for frame in from..self.frame {
+            if frame != from {
+                self.snapshots.insert(frame, game.save_snapshot()?);
+            }
+            let inputs = self.frame_inputs(frame);
+            game.run_frame(&inputs, true);
+            self.used_inputs.insert(frame, inputs);
+        }
+        self.rollbacks += 1;
+        Ok(self.frame - from)
+    }
+
+    /// Drops what the confirmed frames no longer need
+    fn prune(&mut self) {
+        let confirmed = self.confirmed_frame();
+        self.inputs = self.inputs.split_off(&confirmed);
+        self.used_inputs = self.used_inputs.split_off(&confirmed);
+        self.snapshots = self.snapshots.split_off(&confirmed);
+    }
+
+    /// Runs the next frame, `local_input` being the KEYINPUT value of the local player
+    pub fn advance_frame<G: RollbackTarget>(
+        &mut self,
+        game: &mut G,
+        local_input: u16,
+    ) -> CoreResult<FrameResult> {
+        let input_frame = self.frame + self.config.input_delay;
+        // when waiting, the input of that frame was already taken
+        if self.next_input[self.local_player] == input_frame {
+            self.record_input(self.local_player, input_frame, local_input);
+            self.transport.send(&InputMessage {
+                player: self.local_player,
+                frame: input_frame,
+                input: local_input,
+            })?;
+        }
+
+        let mut resimulated = 0;
+        if let Some(from) = self.receive_inputs()? {
+            resimulated = self.rollback(game, from)?;
+        }
+
+        if self.frame >= self.confirmed_frame() + self.config.max_rollback {
+            self.prune();
+            return Ok(FrameResult::WaitingForRemote);
+        }
+
+        self.snapshots.insert(self.frame, game.save_snapshot()?);
+        let inputs = self.frame_inputs(self.frame);
+        game.run_frame(&inputs, false);
+        self.used_inputs.insert(self.frame, inputs);
+        self.frame += 1;
+        self.prune();
+
+        Ok(FrameResult::Advanced { resimulated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Records the inputs of every frame, its state is that record
+    #[derive(Default)]
+    struct MockGame {
+        frames: Vec<Vec<u16>>,
+        resimulated: usize,
+    }
+
+    impl RollbackTarget for MockGame {
+        fn save_snapshot(&self) -> CoreResult<Vec<u8>> {
+            Ok(bincode::serialize(&self.frames)?)
+        }
+
+        fn load_snapshot(&mut self, snapshot: &[u8]) -> CoreResult<()> {
+            self.frames = bincode::deserialize(snapshot)?;
+            Ok(())
+        }
+
+        fn run_frame(&mut self, inputs: &[u16], resimulating: bool) {
+            self.frames.push(inputs.to_vec());
+            if resimulating {
+                self.resimulated += 1;
+            }
+        }
+    }
+
+    /// Holds the received messages for `lag` calls to `receive`
+    struct LaggingTransport {
+        inner: ChannelTransport,
+        lag: usize,
+        in_flight: VecDeque<Vec<InputMessage>>,
+    }
+
+    impl Transport for LaggingTransport {
+        fn send(&mut self, message: &InputMessage) -> io::Result<()> {
+            self.inner.send(message)
+        }
+
+        fn receive(&mut self) -> io::Result<Vec<InputMessage>> {
+            self.in_flight.push_back(self.inner.receive()?);
+            if self.in_flight.len() > self.lag {
+                Ok(self.in_flight.pop_front().unwrap())
+            } else {
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn input(player: usize, frame: u64) -> u16 {
+        // changes every few frames so that predictions fail
+        KEYINPUT_ALL_RELEASED & !(1 << ((frame / 3 + player as u64) % 10))
+    }
+
+    #[test]
+    fn test_peers_converge_after_rollbacks() {
+        let (a, b) = ChannelTransport::pair();
+        let b = LaggingTransport {
+            inner: b,
+            lag: 3,
+            in_flight: VecDeque::new(),
+        };
+        let config = SessionConfig::default();
+        let mut session_a = RollbackSession::new(a, 0, config);
+        let mut session_b = RollbackSession::new(b, 1, config);
+        let (mut game_a, mut game_b) = (MockGame::default(), MockGame::default());
+
+        for _ in 0..60 {
+            let frame = session_a.frame();
+            session_a
+                .advance_frame(&mut game_a, input(0, frame))
+                .unwrap();
+            let frame = session_b.frame();
+            session_b
+                .advance_frame(&mut game_b, input(1, frame))
+                .unwrap();
+        }
+
+        assert_eq!(session_a.frame(), 60);
+        assert_eq!(session_b.frame(), 60);
+        assert!(session_b.rollbacks() > 0);
+        assert!(game_b.resimulated > 0);
+        assert_eq!(session_a.rollbacks(), 0);
+
+        // the frames both peers have every input of are the same, with the right inputs
+        let confirmed = session_a.confirmed_frame().min(session_b.confirmed_frame()) as usize;
+        assert!(confirmed > 50);
+        assert_eq!(game_a.frames[..confirmed], game_b.frames[..confirmed]);
+        for (frame, inputs) in game_a.frames[..confirmed].iter().enumerate() {
+            let frame = frame as u64;
+            if frame < config.input_delay {
+                assert_eq!(inputs, &[KEYINPUT_ALL_RELEASED; 2]);
+            } else {
+                let sent = frame - config.input_delay;
+                assert_eq!(inputs, &[input(0, sent), input(1, sent)]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_waits_for_remote_input() {
+        let (a, _b) = ChannelTransport::pair();
+        let config = SessionConfig {
+            players: 2,
+            input_delay: 1,
+            max_rollback: 4,
+        };
+        let mut session = RollbackSession::new(a, 0, config);
+        let mut game = MockGame::default();
+
+        let results: Vec<FrameResult> = (0..8)
+            .map(|_| {
+                session
+                    .advance_frame(&mut game, KEYINPUT_ALL_RELEASED)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(session.frame(), 5);
+        assert_eq!(results[4], FrameResult::Advanced { resimulated: 0 });
+        assert_eq!(results[5], FrameResult::WaitingForRemote);
+        assert_eq!(game.frames.len(), 5);
+    }
+
+    #[test]
+    fn test_linked_consoles_get_their_player_input() {
+        use crate::gba::test_util::{make_arm_rom, make_mock_gba};
+
+        // mov r0, #0x04000000; add r0, r0, #0x130; mov r1, #0x03000000
+        // loop: ldrh r2, [r0]; str r2, [r1]; b loop
+        let code: [u32; 6] = [
+            0xe3a0_0301,
+            0xe280_0e13,
+            0xe3a0_1403,
+            0xe1d0_20b0,
+            0xe581_2000,
+            0xeaff_fffc,
+        ];
+        let consoles = (0..2)
+            .map(|_| make_mock_gba(&make_arm_rom(&code)))
+            .collect();
+        let mut linked = LinkedConsoles::new(consoles);
+        fn keyinput(linked: &mut LinkedConsoles, player: usize) -> u16 {
+            let console = linked.console_mut(player);
+            console.mem().read::<u16>(0x0300_0000).unwrap()
+        }
+
+        let snapshot = linked.save_snapshot().unwrap();
+        linked.run_frame(&[input(0, 0), input(1, 0)], false);
+        assert_eq!(keyinput(&mut linked, 0), input(0, 0));
+        assert_eq!(keyinput(&mut linked, 1), input(1, 0));
+
+        // going back and running the frame again with other inputs
+        linked.load_snapshot(&snapshot).unwrap();
+        linked.run_frame(&[input(1, 0), input(0, 0)], true);
+        assert_eq!(keyinput(&mut linked, 0), input(1, 0));
+        assert_eq!(keyinput(&mut linked, 1), input(0, 0));
+    }
+}
@@ -0,0 +1,106 @@
+//! Per-game configuration profiles.
+//!
+//! A profile directory holds one TOML file per game, for example `BPEE.toml`:
+//! ```toml
+//! # Defaults to the file name when neither game_code nor game_title are given
+//! game_code = "BPEE"
+//! save_type = "flash128k"
+//! rtc = true
+//! color_correction = true
+//! # Address of a busy-wait loop, the cpu is skipped to the next hardware event while there
+//! idle_loop = 0x080008a6
+//!
+//! # Frontend specific control mappings, passed through as is
+//! [controls]
+//! ```
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use toml;
+
+use super::cartridge::header::CartridgeHeader;
+use super::cartridge::BackupType;
+#[cfg(feature = "std")]
+use super::{CoreError, CoreResult};
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct GameProfile {
+    pub game_code: Option<String>,
+    pub game_title: Option<String>,
+    pub save_type: Option<String>,
+    pub rtc: Option<bool>,
+    pub color_correction: Option<bool>,
+    pub idle_loop: Option<u32>,
+    #[cfg(feature = "std")]
+    pub controls: Option<toml::Value>,
+}
+
+impl GameProfile {
+    pub fn save_type(&self) -> Option<BackupType> {
+        self.save_type
+            .as_ref()
+            .and_then(|s| BackupType::try_from(s.as_str()).ok())
+    }
+
+    fn matches_code(&self, header: &CartridgeHeader) -> bool {
+        self.game_code.as_ref() == Some(&header.game_code)
+    }
+
+    fn matches_title(&self, header: &CartridgeHeader) -> bool {
+        self.game_title.as_ref() == Some(&header.game_title)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GameProfiles {
+    profiles: Vec<GameProfile>,
+}
+
+impl GameProfiles {
+    /// Loads all the `*.toml` files found in `dir`
+    #[cfg(feature = "std")]
+    pub fn load_dir(dir: &Path) -> CoreResult<GameProfiles> {
+        let mut profiles = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+                continue;
+            }
+            let text = fs::read_to_string(&path)?;
+            let mut profile: GameProfile = toml::from_str(&text)
+                .map_err(|e| CoreError::ProfileError(format!("{}: {}", path.display(), e)))?;
+            if let Some(save_type) = &profile.save_type {
+                BackupType::try_from(save_type.as_str())
+                    .map_err(|e| CoreError::ProfileError(format!("{}: {}", path.display(), e)))?;
+            }
+            if profile.game_code.is_none() && profile.game_title.is_none() {
+                profile.game_code = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(|stem| stem.to_string());
+            }
+            debug!("Loaded game profile {:?}", path);
+            profiles.push(profile);
+        }
+        Ok(GameProfiles { profiles })
+    }
+
+    pub fn push(&mut self, profile: GameProfile) {
+        self.profiles.push(profile);
+    }
+
+    /// Finds the profile for a game, matching by game code first and then by title
+    pub fn find(&self, header: &CartridgeHeader) -> Option<&GameProfile> {
+        self.profiles
+            .iter()
+            .find(|p| p.matches_code(header))
+            .or_else(|| self.profiles.iter().find(|p| p.matches_title(header)))
+    }
+}
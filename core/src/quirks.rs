@@ -0,0 +1,122 @@
+//! Per-title emulation quirks.
+//!
+//! Every game specific fix lives in `quirks.yaml`, keyed by game code:
+//! ```yaml
+//! - code: BPEE
+//!   name: Pokemon - Emerald Version (USA, Europe)
+//!   save_type: flash128k
+//!   rtc: true
+//!   eeprom_size: 8192       # bytes, 512 or 8192
+//!   flash_id: 0x1362        # chip id reported in flash ID mode
+//!   disable_prefetch: true  # keeps the prefetch enable bit of WAITCNT cleared
+//!   idle_loop: 0x080008a6   # see `GameBoyAdvance::set_idle_loop`
+//! ```
+//! The cartridge builder looks the quirks up and keeps them in `Cartridge::quirks`, where the
+//! rest of the core reads them. The table is parsed with `std` only, without it no game has
+//! quirks.
+#[cfg(feature = "std")]
+use core::convert::TryFrom;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+#[cfg(feature = "std")]
+use yaml_rust::{Yaml, YamlLoader};
+
+use super::cartridge::{BackupType, EepromType};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GameQuirks {
+    pub force_rtc: bool,
+    pub save_type: Option<BackupType>,
+    pub eeprom_size: Option<EepromType>,
+    /// Chip id of the flash, for games that check for a specific manufacturer
+    pub flash_id: Option<u16>,
+    pub disable_prefetch: bool,
+    pub idle_loop: Option<u32>,
+}
+
+#[cfg(feature = "std")]
+fn parse_quirks(game: &Yaml) -> Result<GameQuirks, String> {
+    let save_type = match game["save_type"].as_str() {
+        Some(save_type) => Some(BackupType::try_from(save_type)?),
+        None => None,
+    };
+    let eeprom_size = match game["eeprom_size"].as_i64() {
+        Some(512) => Some(EepromType::Eeprom512),
+        Some(8192) => Some(EepromType::Eeprom8k),
+        Some(size) => return Err(format!("{} is not a valid eeprom size", size)),
+        None => None,
+    };
+    if eeprom_size.is_some() && save_type.is_some() && save_type != Some(BackupType::Eeprom) {
+        return Err(format!("eeprom_size given for save type {:?}", save_type));
+    }
+    Ok(GameQuirks {
+        force_rtc: game["rtc"].as_bool().unwrap_or(false),
+        save_type,
+        eeprom_size,
+        flash_id: game["flash_id"].as_i64().map(|id| id as u16),
+        disable_prefetch: game["disable_prefetch"].as_bool().unwrap_or(false),
+        idle_loop: game["idle_loop"].as_i64().map(|addr| addr as u32),
+    })
+}
+
+#[cfg(feature = "std")]
+lazy_static! {
+    static ref GAME_QUIRKS: HashMap<String, GameQuirks> = {
+        let mut m = HashMap::new();
+
+        let docs = YamlLoader::load_from_str(include_str!("../quirks.yaml"))
+            .expect("failed to load quirks file");
+
+        let doc = &docs[0];
+        let games = doc.as_vec().unwrap();
+
+        for game in games {
+            let game_code = String::from(game["code"].as_str().unwrap());
+            let quirks = parse_quirks(game).unwrap_or_else(|e| panic!("{}: {}", game_code, e));
+            m.insert(game_code, quirks);
+        }
+
+        m
+    };
+}
+
+#[cfg(feature = "std")]
+pub fn get_game_quirks(game_code: &str) -> Option<&'static GameQuirks> {
+    GAME_QUIRKS.get(game_code)
+}
+
+#[cfg(not(feature = "std"))]
+pub fn get_game_quirks(_game_code: &str) -> Option<&'static GameQuirks> {
+    None
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quirks_table_is_valid() {
+        // parses the whole table
+        let emerald = get_game_quirks("BPEE").unwrap();
+        assert!(emerald.force_rtc);
+        assert_eq!(emerald.save_type, Some(BackupType::Flash1M));
+        assert_eq!(get_game_quirks("XXXX"), None);
+    }
+
+    #[test]
+    fn test_parse_quirks() {
+        let docs = YamlLoader::load_from_str(
+            "code: TEST\nsave_type: eeprom\neeprom_size: 8192\nflash_id: 0x1362\nidle_loop: 0x080008a6\ndisable_prefetch: true",
+        )
+        .unwrap();
+        let quirks = parse_quirks(&docs[0]).unwrap();
+        assert!(matches!(quirks.eeprom_size, Some(EepromType::Eeprom8k)));
+        assert_eq!(quirks.flash_id, Some(0x1362));
+        assert_eq!(quirks.idle_loop, Some(0x0800_08a6));
+        assert!(quirks.disable_prefetch);
+
+        let docs = YamlLoader::load_from_str("code: TEST\neeprom_size: 1000").unwrap();
+        assert!(parse_quirks(&docs[0]).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Snapshots of the work RAM and their differences.
+//!
+//! The usual way to find a game variable is to snapshot the RAM, change the variable in the
+//! game (lose a life, pick up a coin), snapshot again and look at what changed:
+//! ```ignore
+//! let before = RamSnapshot::capture(&mut gba, "before");
+//! // ... play ...
+//! let after = RamSnapshot::capture(&mut gba, "after");
+//! for change in before.diff(&after) {
+//!     println!("{}", change);
+//! }
+//! ```
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::bus::Addr;
+use super::sysbus::consts::{EWRAM_ADDR, IWRAM_ADDR};
+use super::GameBoyAdvance;
+
+/// A copy of EWRAM and IWRAM
+#[derive(Debug, Clone)]
+pub struct RamSnapshot {
+    pub label: String,
+    pub ewram: Vec<u8>,
+    pub iwram: Vec<u8>,
+}
+
+/// A changed value, as wide as the bytes that changed around it suggest
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RamChange {
+    pub addr: Addr,
+    /// 1, 2 or 4 bytes
+    pub width: u8,
+    pub old: u32,
+    pub new: u32,
+}
+
+impl fmt::Display for RamChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.width as usize * 2;
+        write!(
+            f,
+            "0x{:08x} u{:<2} 0x{:0digits$x} -> 0x{:0digits$x} ({} -> {})",
+            self.addr,
+            self.width * 8,
+            self.old,
+            self.new,
+            self.old,
+            self.new,
+            digits = digits
+        )
+    }
+}
+
+impl RamSnapshot {
+    pub fn capture(gba: &GameBoyAdvance, label: &str) -> RamSnapshot {
+        RamSnapshot {
+            label: label.to_string(),
+            ewram: gba.sysbus.ewram().to_vec(),
+            iwram: gba.sysbus.iwram().to_vec(),
+        }
+    }
+
+    /// The values that differ in `other`, by address. `self` holds the old values.
+    pub fn diff(&self, other: &RamSnapshot) -> Vec<RamChange> {
+        let mut changes = diff_region(EWRAM_ADDR, &self.ewram, &other.ewram);
+        changes.extend(diff_region(IWRAM_ADDR, &self.iwram, &other.iwram));
+        changes
+    }
+
+    /// The `width` bytes value at `addr`, `None` outside of the work RAMs
+    pub fn read(&self, addr: Addr, width: u8) -> Option<u32> {
+        let (base, ram) = if addr >= IWRAM_ADDR {
+            (IWRAM_ADDR, &self.iwram)
+        } else {
+            (EWRAM_ADDR, &self.ewram)
+        };
+        let offset = addr.checked_sub(base)? as usize;
+        ram.get(offset..offset + width as usize).map(read_le)
+    }
+}
+
+fn read_le(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+/// Guesses the widths by aligned words: a word with changes in both halves is a u32, a
+/// halfword with both bytes changed is a u16, any other changed byte is a u8
+fn diff_region(base: Addr, old: &[u8], new: &[u8]) -> Vec<RamChange> {
+    let mut changes = Vec::new();
+    let mut push = |offset: usize, width: usize| {
+        changes.push(RamChange {
+            addr: base + offset as u32,
+            width: width as u8,
+            old: read_le(&old[offset..offset + width]),
+            new: read_le(&new[offset..offset + width]),
+        })
+    };
+    for (word, (old_word, new_word)) in old.chunks(4).zip(new.chunks(4)).enumerate() {
+        if old_word == new_word {
+            continue;
+        }
+        let changed: Vec<bool> = old_word
+            .iter()
+            .zip(new_word)
+            .map(|(old, new)| old != new)
+            .collect();
+        let offset = word * 4;
+        let low = changed[..2].iter().any(|c| *c);
+        let high = changed[2..].iter().any(|c| *c);
+        if low && high {
+            push(offset, 4);
+            continue;
+        }
+        for half in 0..2 {
+            match (changed[half * 2], changed[half * 2 + 1]) {
+                (true, true) => push(offset + half * 2, 2),
+                (true, false) => push(offset + half * 2, 1),
+                (false, true) => push(offset + half * 2 + 1, 1),
+                (false, false) => {}
+            }
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sysbus::consts::{INTERNAL_RAM_SIZE, WORK_RAM_SIZE};
+
+    fn snapshot(ewram: &[(usize, u8)]) -> RamSnapshot {
+        let mut snapshot = RamSnapshot {
+            label: String::new(),
+            ewram: vec![0; WORK_RAM_SIZE],
+            iwram: vec![0; INTERNAL_RAM_SIZE],
+        };
+        for (offset, value) in ewram {
+            snapshot.ewram[*offset] = *value;
+        }
+        snapshot
+    }
+
+    #[test]
+    fn test_diff_guesses_widths() {
+        let old = snapshot(&[(0x10, 0xff), (0x20, 5), (0x30, 3)]);
+        let new = snapshot(&[
+            (0x10, 0x00),
+            (0x11, 0x01),
+            (0x21, 1),
+            (0x30, 4),
+            (0x40, 1),
+            (0x43, 2),
+        ]);
+        let changes = old.diff(&new);
+        let summary: Vec<(Addr, u8, u32, u32)> = changes
+            .iter()
+            .map(|c| (c.addr, c.width, c.old, c.new))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x0200_0010, 2, 0x00ff, 0x0100),
+                (0x0200_0020, 2, 0x0005, 0x0100),
+                (0x0200_0030, 1, 3, 4),
+                (0x0200_0040, 4, 0, 0x0200_0001),
+            ]
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            "0x02000010 u16 0x00ff -> 0x0100 (255 -> 256)"
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+}
@@ -0,0 +1,357 @@
+//! ROM verification against No-Intro DAT files.
+//!
+//! No-Intro DATs are not redistributed with the emulator, the user loads one with
+//! `RomDatabase::load`. The Logiqx XML format is parsed, where each game looks like:
+//! ```xml
+//! <game name="Pokemon - Emerald Version (USA, Europe)">
+//!     <rom name="..." size="16777216" crc="1f1c08fb" sha1="..." serial="BPEE"/>
+//! </game>
+//! ```
+//! A match gives the game's real serial, which is used instead of the header's game code to look
+//! up the per-title quirks (save type, RTC), see `GamepakBuilder::rom_database`.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use super::{CoreError, CoreResult};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RomHashes {
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+impl RomHashes {
+    pub fn compute(bytes: &[u8]) -> RomHashes {
+        let mut hasher = Sha1::new();
+        hasher.input(bytes);
+        let mut sha1 = [0; 20];
+        sha1.copy_from_slice(hasher.result().as_slice());
+        RomHashes {
+            crc32: crc32fast::hash(bytes),
+            sha1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatEntry {
+    /// Name of the game in the database
+    pub name: String,
+    pub size: usize,
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    /// 4 letters game code
+    pub serial: Option<String>,
+    /// Known bad dumps are listed with the "baddump" status
+    pub bad_dump: bool,
+}
+
+impl DatEntry {
+    fn matches(&self, size: usize, hashes: &RomHashes) -> bool {
+        match self.sha1 {
+            Some(sha1) => sha1 == hashes.sha1,
+            None => self.size == size && self.crc32 == hashes.crc32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpStatus {
+    Good(DatEntry),
+    /// A known bad dump, or a ROM with the serial of a game but none of its hashes
+    BadDump(DatEntry),
+    /// The start of the ROM is a good dump, followed by `extra_bytes` of junk
+    Overdump {
+        entry: DatEntry,
+        extra_bytes: usize,
+    },
+    Unknown,
+}
+
+impl DumpStatus {
+    pub fn entry(&self) -> Option<&DatEntry> {
+        match self {
+            DumpStatus::Good(entry) | DumpStatus::BadDump(entry) => Some(entry),
+            DumpStatus::Overdump { entry, .. } => Some(entry),
+            DumpStatus::Unknown => None,
+        }
+    }
+}
+
+impl fmt::Display for DumpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DumpStatus::Good(entry) => write!(f, "good dump of {}", entry.name),
+            DumpStatus::BadDump(entry) => write!(f, "bad dump of {}", entry.name),
+            DumpStatus::Overdump { entry, extra_bytes } => write!(
+                f,
+                "overdump of {} ({} extra bytes)",
+                entry.name, extra_bytes
+            ),
+            DumpStatus::Unknown => write!(f, "unknown ROM"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RomDatabase {
+    entries: Vec<DatEntry>,
+    by_crc32: HashMap<u32, Vec<usize>>,
+    by_serial: HashMap<String, Vec<usize>>,
+}
+
+impl RomDatabase {
+    pub fn load(path: &Path) -> CoreResult<RomDatabase> {
+        let text = fs::read_to_string(path)?;
+        RomDatabase::from_dat(&text)
+            .map_err(|e| CoreError::RomDatabaseError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses a DAT in the Logiqx XML format
+    pub fn from_dat(text: &str) -> CoreResult<RomDatabase> {
+        let mut db = RomDatabase::default();
+        let mut game_name = None;
+        let mut rest = text;
+        while let Some(start) = rest.find('<') {
+            if rest[start..].starts_with("<!--") {
+                let end = rest[start..].find("-->").ok_or_else(|| {
+                    CoreError::RomDatabaseError("unterminated comment".to_string())
+                })?;
+                rest = &rest[start + end + 3..];
+                continue;
+            }
+            let end = rest[start..]
+                .find('>')
+                .ok_or_else(|| CoreError::RomDatabaseError("unterminated tag".to_string()))?;
+            let tag = rest[start + 1..start + end].trim_end_matches('/');
+            rest = &rest[start + end + 1..];
+            // the XML declaration and the DOCTYPE
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+
+            let (tag_name, attributes) = match tag.find(char::is_whitespace) {
+                Some(i) => (&tag[..i], parse_attributes(&tag[i..])?),
+                None => (tag, HashMap::new()),
+            };
+            match tag_name {
+                "game" | "machine" => game_name = attributes.get("name").cloned(),
+                "/game" | "/machine" => game_name = None,
+                "rom" => {
+                    let entry = parse_rom(game_name.as_ref(), &attributes)?;
+                    db.push(entry);
+                }
+                _ => {}
+            }
+        }
+        Ok(db)
+    }
+
+    pub fn push(&mut self, entry: DatEntry) {
+        let index = self.entries.len();
+        self.by_crc32.entry(entry.crc32).or_default().push(index);
+        if let Some(serial) = &entry.serial {
+            self.by_serial
+                .entry(serial.clone())
+                .or_default()
+                .push(index);
+        }
+        self.entries.push(entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn entries_at<'a>(
+        &'a self,
+        indices: Option<&'a Vec<usize>>,
+    ) -> impl Iterator<Item = &'a DatEntry> {
+        indices
+            .into_iter()
+            .flatten()
+            .map(move |i| &self.entries[*i])
+    }
+
+    /// Matches the ROM by its hashes, then by `game_code` to tell bad dumps from unknown ROMs
+    pub fn verify(&self, rom: &[u8], game_code: &str) -> DumpStatus {
+        let hashes = RomHashes::compute(rom);
+        if let Some(entry) = self
+            .entries_at(self.by_crc32.get(&hashes.crc32))
+            .find(|entry| entry.matches(rom.len(), &hashes))
+        {
+            return if entry.bad_dump {
+                DumpStatus::BadDump(entry.clone())
+            } else {
+                DumpStatus::Good(entry.clone())
+            };
+        }
+
+        for entry in self.entries_at(self.by_serial.get(game_code)) {
+            if entry.size < rom.len()
+                && entry.matches(entry.size, &RomHashes::compute(&rom[..entry.size]))
+            {
+                return DumpStatus::Overdump {
+                    entry: entry.clone(),
+                    extra_bytes: rom.len() - entry.size,
+                };
+            }
+        }
+        match self.entries_at(self.by_serial.get(game_code)).next() {
+            Some(entry) => DumpStatus::BadDump(entry.clone()),
+            None => DumpStatus::Unknown,
+        }
+    }
+}
+
+fn parse_rom(
+    game_name: Option<&String>,
+    attributes: &HashMap<String, String>,
+) -> CoreResult<DatEntry> {
+    let invalid = |what: &str| CoreError::RomDatabaseError(format!("invalid rom {}", what));
+    let name = game_name
+        .or_else(|| attributes.get("name"))
+        .cloned()
+        .unwrap_or_default();
+    let size = attributes
+        .get("size")
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| invalid("size"))?;
+    let crc32 = attributes
+        .get("crc")
+        .and_then(|crc| u32::from_str_radix(crc, 16).ok())
+        .ok_or_else(|| invalid("crc"))?;
+    let sha1 = match attributes.get("sha1") {
+        Some(sha1) => Some(parse_sha1(sha1).ok_or_else(|| invalid("sha1"))?),
+        None => None,
+    };
+    // Some DATs use the full product code, e.g. AGB-BPEE-USA
+    let serial = attributes.get("serial").map(|serial| {
+        serial
+            .split('-')
+            .find(|part| part.len() == 4)
+            .unwrap_or(serial)
+            .to_string()
+    });
+    Ok(DatEntry {
+        name,
+        size,
+        crc32,
+        sha1,
+        serial,
+        bad_dump: attributes.get("status").map(String::as_str) == Some("baddump"),
+    })
+}
+
+fn parse_sha1(s: &str) -> Option<[u8; 20]> {
+    if s.len() != 40 {
+        return None;
+    }
+    let mut sha1 = [0; 20];
+    for (i, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(sha1)
+}
+
+fn parse_attributes(s: &str) -> CoreResult<HashMap<String, String>> {
+    let mut attributes = HashMap::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let eq = rest
+            .find('=')
+            .ok_or_else(|| CoreError::RomDatabaseError(format!("bad attribute in {}", s)))?;
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = value
+            .chars()
+            .next()
+            .filter(|c| *c == '"' || *c == '\'')
+            .ok_or_else(|| CoreError::RomDatabaseError(format!("unquoted attribute {}", key)))?;
+        let end = value[1..].find(quote).ok_or_else(|| {
+            CoreError::RomDatabaseError(format!("unterminated attribute {}", key))
+        })?;
+        attributes.insert(key.to_string(), unescape(&value[1..end + 1]));
+        rest = value[end + 2..].trim_start();
+    }
+    Ok(attributes)
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_dat(name: &str, rom: &[u8], serial: &str, status: &str) -> String {
+        let hashes = RomHashes::compute(rom);
+        let sha1: String = hashes.sha1.iter().map(|b| format!("{:02X}", b)).collect();
+        format!(
+            r#"<?xml version="1.0"?>
+<!DOCTYPE datafile PUBLIC "-//Logiqx//DTD ROM Management Datafile//EN" "http://www.logiqx.com/Dats/datafile.dtd">
+<datafile>
+    <!-- <rom name="commented out" size="0" crc="0"/> -->
+    <game name="{}">
+        <description>test</description>
+        <rom name="test.gba" size="{}" crc="{:08X}" sha1="{}" serial="{}" {}/>
+    </game>
+</datafile>"#,
+            name,
+            rom.len(),
+            hashes.crc32,
+            sha1,
+            serial,
+            status
+        )
+    }
+
+    #[test]
+    fn test_verify_dump_status() {
+        let rom: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+        let db =
+            RomDatabase::from_dat(&make_dat("Test &amp; Co", &rom, "AGB-ATST-USA", "")).unwrap();
+        assert_eq!(db.len(), 1);
+
+        match db.verify(&rom, "ATST") {
+            DumpStatus::Good(entry) => {
+                assert_eq!(entry.name, "Test & Co");
+                assert_eq!(entry.serial.as_deref(), Some("ATST"));
+            }
+            status => panic!("unexpected {:?}", status),
+        }
+
+        let mut overdump = rom.clone();
+        overdump.resize(0x800, 0xff);
+        match db.verify(&overdump, "ATST") {
+            DumpStatus::Overdump { extra_bytes, .. } => assert_eq!(extra_bytes, 0x400),
+            status => panic!("unexpected {:?}", status),
+        }
+
+        let mut bad = rom.clone();
+        bad[0x10] ^= 1;
+        assert!(matches!(db.verify(&bad, "ATST"), DumpStatus::BadDump(_)));
+        assert_eq!(db.verify(&bad, "AXXX"), DumpStatus::Unknown);
+    }
+
+    #[test]
+    fn test_known_bad_dump() {
+        let rom = vec![0x42; 0x100];
+        let db =
+            RomDatabase::from_dat(&make_dat("Bad", &rom, "ABAD", "status=\"baddump\"")).unwrap();
+        assert!(matches!(db.verify(&rom, "ABAD"), DumpStatus::BadDump(_)));
+    }
+}
@@ -0,0 +1,143 @@
+//! Reloading the ROM while it is being developed.
+//!
+//! A `RomWatcher` tells when the ROM (or ELF) file was rebuilt, the frontend then builds a new
+//! gamepak from it and either starts it over, or hands it to `GameBoyAdvance::swap_rom` to
+//! keep the running state.
+//! ```ignore
+//! let mut watcher = RomWatcher::new(&rom_path);
+//! loop {
+//!     if watcher.poll() {
+//!         gba.swap_rom(GamepakBuilder::new().file(&rom_path).build()?);
+//!     }
+//!     gba.frame();
+//! }
+//! ```
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::util::{now, Instant};
+
+/// What happens to the running game when the ROM is reloaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReloadMode {
+    /// The new ROM starts from scratch, like a power cycle. Only the save is kept.
+    Reset,
+    /// The CPU, RAM and IO state are kept and the code continues in the new ROM, which works
+    /// as long as the code around the program counter didn't move
+    KeepState,
+}
+
+impl TryFrom<&str> for ReloadMode {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "reset" => Ok(ReloadMode::Reset),
+            "keep-state" => Ok(ReloadMode::KeepState),
+            _ => Err(format!("{} is not a valid reload mode", s)),
+        }
+    }
+}
+
+/// The modification time and size of a file, what tells that it was written
+type FileStamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Watches a ROM file for changes by polling its modification time and size
+#[derive(Debug)]
+pub struct RomWatcher {
+    path: PathBuf,
+    loaded: Option<FileStamp>,
+    /// A change seen on the last check, reported once the file stopped changing
+    pending: Option<FileStamp>,
+    last_check: Instant,
+    /// Time between two checks of the file
+    pub interval: Duration,
+}
+
+impl RomWatcher {
+    /// Watches `path`, the file as it is now is the loaded one
+    pub fn new(path: &Path) -> RomWatcher {
+        RomWatcher {
+            path: path.to_path_buf(),
+            loaded: stamp(path),
+            pending: None,
+            last_check: now(),
+            interval: Duration::from_millis(500),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Checks the file if `interval` passed since the last check, and returns true once it
+    /// changed. A linker writes the file in several steps, so the change is only reported
+    /// when the file was the same on two checks in a row. A missing file is never reported.
+    pub fn poll(&mut self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+        self.last_check = now();
+
+        let current = match stamp(&self.path) {
+            Some(current) => current,
+            None => {
+                self.pending = None;
+                return false;
+            }
+        };
+        if Some(current) == self.loaded {
+            self.pending = None;
+            false
+        } else if Some(current) == self.pending {
+            self.loaded = Some(current);
+            self.pending = None;
+            true
+        } else {
+            self.pending = Some(current);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_mode_from_str() {
+        assert_eq!(ReloadMode::try_from("reset"), Ok(ReloadMode::Reset));
+        assert_eq!(
+            ReloadMode::try_from("keep-state"),
+            Ok(ReloadMode::KeepState)
+        );
+        assert!(ReloadMode::try_from("keep").is_err());
+    }
+
+    #[test]
+    fn test_change_reported_once_stable() {
+        let path = std::env::temp_dir().join(format!("rba-rom-watch-{}.gba", std::process::id()));
+        fs::write(&path, [0u8; 16]).unwrap();
+
+        let mut watcher = RomWatcher::new(&path);
+        watcher.interval = Duration::from_secs(0);
+        assert!(!watcher.poll());
+
+        // the size changes, so the modification time resolution doesn't matter
+        fs::write(&path, [0u8; 32]).unwrap();
+        assert!(!watcher.poll());
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.poll());
+        assert!(!watcher.poll());
+    }
+}
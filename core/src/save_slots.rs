@@ -0,0 +1,240 @@
+//! Savestate slots, behaving the same in every frontend.
+//!
+//! The states of a game are kept in a directory of their own:
+//! ```text
+//! states/Pokemon Emerald/
+//!     slot0.state ... slot9.state
+//!     autosave.state      written when the frontend exits
+//!     undo-load.state     the emulator before the last load
+//!     undo-save.state     the slot the last save overwrote
+//! ```
+//! Nothing is lost to a mistaken hotkey: loading a slot backs up the running game first, and
+//! saving over a slot keeps its previous state, until the next load or save.
+//! ```ignore
+//! let mut slots = SaveSlotManager::new(Path::new("states"), "Pokemon Emerald")?;
+//! slots.save(&gba, slots.selected())?;
+//! if let Some(newest) = slots.newest() {
+//!     slots.load(&mut gba, newest)?;
+//! }
+//! slots.undo_load(&mut gba)?;
+//! ```
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::{CoreError, CoreResult, GameBoyAdvance, ResultExt};
+
+pub const SLOT_COUNT: usize = 10;
+
+const AUTOSAVE: &str = "autosave.state";
+const UNDO_LOAD: &str = "undo-load.state";
+const UNDO_SAVE: &str = "undo-save.state";
+
+/// Writes through a temporary file, so that a crash doesn't leave half a state behind
+fn write_state(path: &Path, state: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, state)?;
+    fs::rename(&tmp, path)
+}
+
+pub struct SaveSlotManager {
+    dir: PathBuf,
+    selected: usize,
+    newest: Option<usize>,
+    /// The slot `undo-save.state` belongs to, and the newest slot before it was saved
+    overwritten: Option<(usize, Option<usize>)>,
+}
+
+impl SaveSlotManager {
+    /// Manages the slots of `game` in `states_dir`, creating its directory
+    pub fn new(states_dir: &Path, game: &str) -> CoreResult<SaveSlotManager> {
+        let dir = states_dir.join(game);
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let mut manager = SaveSlotManager {
+            dir,
+            selected: 0,
+            newest: None,
+            overwritten: None,
+        };
+        manager.newest = (0..SLOT_COUNT)
+            .filter_map(|slot| manager.modified(slot).map(|time| (time, slot)))
+            .max()
+            .map(|(_, slot)| slot);
+        Ok(manager)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn slot_path(&self, slot: usize) -> PathBuf {
+        self.dir.join(format!("slot{}.state", slot))
+    }
+
+    /// When the slot was saved, `None` for an empty slot
+    pub fn modified(&self, slot: usize) -> Option<SystemTime> {
+        fs::metadata(self.slot_path(slot))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    pub fn is_empty(&self, slot: usize) -> bool {
+        !self.slot_path(slot).is_file()
+    }
+
+    /// The slot saved last
+    pub fn newest(&self) -> Option<usize> {
+        self.newest
+    }
+
+    /// The slot the save and load hotkeys without a slot number go to
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn select(&mut self, slot: usize) {
+        self.selected = slot % SLOT_COUNT;
+    }
+
+    pub fn select_next(&mut self) -> usize {
+        self.select(self.selected + 1);
+        self.selected
+    }
+
+    pub fn select_previous(&mut self) -> usize {
+        self.select(self.selected + SLOT_COUNT - 1);
+        self.selected
+    }
+
+    fn check_slot(slot: usize) -> CoreResult<()> {
+        if slot < SLOT_COUNT {
+            Ok(())
+        } else {
+            Err(CoreError::SavestateError(format!(
+                "there is no slot {}, the last one is {}",
+                slot,
+                SLOT_COUNT - 1
+            )))
+        }
+    }
+
+    /// Saves to `slot`, keeping the state it held for `undo_save`
+    pub fn save(&mut self, gba: &GameBoyAdvance, slot: usize) -> CoreResult<()> {
+        SaveSlotManager::check_slot(slot)?;
+        let state = gba.save_state()?;
+        let path = self.slot_path(slot);
+        if path.is_file() {
+            fs::copy(&path, self.dir.join(UNDO_SAVE))?;
+            self.overwritten = Some((slot, self.newest));
+        }
+        write_state(&path, &state).with_context(|| format!("saving {}", path.display()))?;
+        self.newest = Some(slot);
+        Ok(())
+    }
+
+    /// Loads `slot`, keeping the running game for `undo_load`. False for an empty slot.
+    pub fn load(&mut self, gba: &mut GameBoyAdvance, slot: usize) -> CoreResult<bool> {
+        SaveSlotManager::check_slot(slot)?;
+        self.load_file(gba, &self.slot_path(slot))
+    }
+
+    fn load_file(&self, gba: &mut GameBoyAdvance, path: &Path) -> CoreResult<bool> {
+        if !path.is_file() {
+            return Ok(false);
+        }
+        let state = fs::read(path)?;
+        let running = gba.save_state()?;
+        gba.restore_state(&state)
+            .with_context(|| format!("loading {}", path.display()))?;
+        write_state(&self.dir.join(UNDO_LOAD), &running)?;
+        Ok(true)
+    }
+
+    /// Goes back to the game as it was before the last load. False when there is nothing to
+    /// undo.
+    pub fn undo_load(&mut self, gba: &mut GameBoyAdvance) -> CoreResult<bool> {
+        let path = self.dir.join(UNDO_LOAD);
+        if !path.is_file() {
+            return Ok(false);
+        }
+        gba.restore_state(&fs::read(&path)?)
+            .with_context(|| format!("loading {}", path.display()))?;
+        fs::remove_file(&path)?;
+        Ok(true)
+    }
+
+    /// Puts back the state the last save overwrote, returning its slot
+    pub fn undo_save(&mut self) -> CoreResult<Option<usize>> {
+        let (slot, newest) = match self.overwritten.take() {
+            Some(overwritten) => overwritten,
+            None => return Ok(None),
+        };
+        fs::rename(self.dir.join(UNDO_SAVE), self.slot_path(slot))?;
+        self.newest = newest;
+        Ok(Some(slot))
+    }
+
+    /// Saves to the autosave slot, for when the frontend exits
+    pub fn autosave(&self, gba: &GameBoyAdvance) -> CoreResult<()> {
+        let path = self.dir.join(AUTOSAVE);
+        write_state(&path, &gba.save_state()?).with_context(|| format!("saving {}", path.display()))
+    }
+
+    /// Loads the autosave slot, keeping the running game for `undo_load`. False when there is
+    /// no autosave.
+    pub fn load_autosave(&mut self, gba: &mut GameBoyAdvance) -> CoreResult<bool> {
+        self.load_file(gba, &self.dir.join(AUTOSAVE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gba::test_util::make_mock_gba;
+
+    #[test]
+    fn test_slots_and_undo() {
+        let dir = std::env::temp_dir().join(format!("rba-slots-{}", std::process::id()));
+        let mut slots = SaveSlotManager::new(&dir, "game").unwrap();
+        let mut gba = make_mock_gba(&[]);
+        assert_eq!(slots.newest(), None);
+        assert!(!slots.load(&mut gba, 1).unwrap());
+        assert!(slots.save(&gba, SLOT_COUNT).is_err());
+
+        gba.mem().write::<u8>(0x0200_0000, 1).unwrap();
+        slots.save(&gba, 1).unwrap();
+        gba.mem().write::<u8>(0x0200_0000, 2).unwrap();
+        slots.save(&gba, 1).unwrap();
+        assert_eq!(slots.newest(), Some(1));
+        assert!(!slots.is_empty(1));
+
+        // a mistaken save is undone
+        assert_eq!(slots.undo_save().unwrap(), Some(1));
+        assert_eq!(slots.undo_save().unwrap(), None);
+        gba.mem().write::<u8>(0x0200_0000, 3).unwrap();
+        assert!(slots.load(&mut gba, 1).unwrap());
+        assert_eq!(gba.mem().read::<u8>(0x0200_0000).unwrap(), 1);
+
+        // and so is a mistaken load
+        assert!(slots.undo_load(&mut gba).unwrap());
+        assert_eq!(gba.mem().read::<u8>(0x0200_0000).unwrap(), 3);
+        assert!(!slots.undo_load(&mut gba).unwrap());
+
+        slots.autosave(&gba).unwrap();
+        let mut slots = SaveSlotManager::new(&dir, "game").unwrap();
+        assert_eq!(slots.newest(), Some(1));
+        assert!(slots.load_autosave(&mut make_mock_gba(&[])).unwrap());
+        assert_eq!(slots.select_previous(), SLOT_COUNT - 1);
+        assert_eq!(slots.select_next(), 0);
+
+        // the slot saved before the undone save is the newest again
+        slots.save(&gba, 2).unwrap();
+        slots.save(&gba, 1).unwrap();
+        assert_eq!(slots.newest(), Some(1));
+        assert_eq!(slots.undo_save().unwrap(), Some(1));
+        assert_eq!(slots.newest(), Some(2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
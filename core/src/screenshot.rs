@@ -0,0 +1,117 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{BufWriter, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use png;
+
+use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+#[cfg(feature = "std")]
+use super::CoreResult;
+
+/// A copy of the displayed frame along with some information about when it was taken
+#[derive(Debug, Clone)]
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    /// Pixels in the frame buffer format (0x00RRGGBB)
+    pub pixels: Vec<u32>,
+    pub game_code: String,
+    pub game_title: String,
+    /// Number of frames emulated when the screenshot was taken
+    pub frame: usize,
+}
+
+impl Screenshot {
+    pub fn new(
+        frame_buffer: &[u32],
+        game_code: String,
+        game_title: String,
+        frame: usize,
+    ) -> Screenshot {
+        Screenshot {
+            width: DISPLAY_WIDTH,
+            height: DISPLAY_HEIGHT,
+            pixels: frame_buffer.to_vec(),
+            game_code,
+            game_title,
+            frame,
+        }
+    }
+
+    /// Nearest neighbor upscale by an integer factor
+    pub fn scaled(&self, factor: usize) -> Screenshot {
+        let factor = factor.max(1);
+        let width = self.width * factor;
+        let height = self.height * factor;
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = &self.pixels[(y / factor) * self.width..][..self.width];
+            for x in 0..width {
+                pixels.push(row[x / factor]);
+            }
+        }
+        Screenshot {
+            width,
+            height,
+            pixels,
+            game_code: self.game_code.clone(),
+            game_title: self.game_title.clone(),
+            frame: self.frame,
+        }
+    }
+
+    /// Packed 24bit RGB bytes
+    pub fn to_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.pixels.len() * 3);
+        for pixel in &self.pixels {
+            rgb.push((pixel >> 16) as u8);
+            rgb.push((pixel >> 8) as u8);
+            rgb.push(*pixel as u8);
+        }
+        rgb
+    }
+
+    /// Encodes as PNG, game code, title and frame number are stored in tEXt chunks
+    #[cfg(feature = "std")]
+    pub fn write_png<W: Write>(&self, w: W) -> CoreResult<()> {
+        let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+
+        let frame = self.frame.to_string();
+        let text = [
+            ("Software", "RustBoyAdvance"),
+            ("GameCode", self.game_code.as_str()),
+            ("GameTitle", self.game_title.as_str()),
+            ("Frame", frame.as_str()),
+        ];
+        for (keyword, value) in text.iter() {
+            let mut chunk = Vec::with_capacity(keyword.len() + 1 + value.len());
+            chunk.extend_from_slice(keyword.as_bytes());
+            chunk.push(0);
+            chunk.extend_from_slice(value.as_bytes());
+            writer.write_chunk(*b"tEXt", &chunk)?;
+        }
+
+        writer.write_image_data(&self.to_rgb())?;
+        Ok(())
+    }
+
+    /// Writes a PNG file, scaled by `scale` (1 for native resolution)
+    #[cfg(feature = "std")]
+    pub fn save_png(&self, path: &Path, scale: usize) -> CoreResult<()> {
+        let file = BufWriter::new(File::create(path)?);
+        if scale > 1 {
+            self.scaled(scale).write_png(file)
+        } else {
+            self.write_png(file)
+        }
+    }
+}
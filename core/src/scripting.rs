@@ -0,0 +1,264 @@
+//! Lua scripting host.
+//!
+//! Scripts register callbacks with `emu.on_frame(function() ... end)`, these run right before
+//! every emulated frame. The following APIs are available both from the callbacks and from the
+//! script's top level:
+//!
+//! * `emu.framecount()`, `emu.game_code()`, `emu.game_title()`
+//! * `memory.read_u8/u16/u32(addr)`, `memory.write_u8/u16/u32(addr, value)`,
+//!   `memory.read_range(addr, size)` (as a string of bytes), reads have no side effects and
+//!   accesses outside of the address space raise an error
+//! * `joypad.get()` returns a table of pressed keys, `joypad.set({a = true, start = true})`
+//!   forces the keys for the upcoming frame
+//! * `savestate.save()` returns the state as a string, `savestate.load(state)` restores it
+//! * `gui.text(x, y, text [, color [, frames]])`, `gui.rect(x, y, w, h [, color [, frames]])`
+//!   draw on top of the output, colors are 0xRRGGBB and items last a single frame by default
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use bit::BitIndex;
+use mlua::{Function, Lua, Table};
+
+use super::keypad::{Keys, KEYINPUT_ALL_RELEASED, NUM_KEYS};
+use super::memory::AccessMode;
+use super::{CoreError, CoreResult, GameBoyAdvance};
+
+const PRELUDE: &str = r#"
+emu = {}
+local frame_callbacks = {}
+
+function emu.on_frame(callback)
+    table.insert(frame_callbacks, callback)
+end
+
+function emu.__run_frame_callbacks()
+    for _, callback in ipairs(frame_callbacks) do
+        callback()
+    end
+end
+"#;
+
+const KEY_NAMES: [&str; NUM_KEYS] = [
+    "a", "b", "select", "start", "right", "left", "up", "down", "r", "l",
+];
+
+const DEFAULT_COLOR: u32 = 0xffffff;
+
+/// x, y, text, color, frames
+type TextArgs = (i32, i32, String, Option<u32>, Option<usize>);
+/// x, y, w, h, color, frames
+type RectArgs = (i32, i32, u32, u32, Option<u32>, Option<usize>);
+
+pub struct ScriptHost {
+    lua: Lua,
+}
+
+impl ScriptHost {
+    pub fn new() -> CoreResult<ScriptHost> {
+        let lua = Lua::new();
+        lua.load(PRELUDE).set_name("prelude")?.exec()?;
+        Ok(ScriptHost { lua })
+    }
+
+    /// Runs a script file, usually registering frame callbacks
+    pub fn load_file(&mut self, path: &Path, gba: &mut GameBoyAdvance) -> CoreResult<()> {
+        let source = fs::read_to_string(path)?;
+        let name = path.to_string_lossy();
+        self.with_api(gba, |lua| lua.load(&source).set_name(&*name)?.exec())
+    }
+
+    /// Runs a string of Lua code
+    pub fn exec(&mut self, source: &str, gba: &mut GameBoyAdvance) -> CoreResult<()> {
+        self.with_api(gba, |lua| lua.load(source).exec())
+    }
+
+    /// Runs the frame callbacks and then emulates a frame
+    pub fn frame(&mut self, gba: &mut GameBoyAdvance) -> CoreResult<()> {
+        self.with_api(gba, |lua| {
+            let emu: Table = lua.globals().get("emu")?;
+            let run: Function = emu.get("__run_frame_callbacks")?;
+            run.call(())
+        })?;
+        gba.frame();
+        // joypad.set only lasts for a single frame
+        gba.set_keyinput_override(None);
+        Ok(())
+    }
+
+    /// Exposes the emulator to Lua for the duration of `f`
+    fn with_api<F>(&self, gba: &mut GameBoyAdvance, f: F) -> CoreResult<()>
+    where
+        F: FnOnce(&Lua) -> mlua::Result<()>,
+    {
+        let gba = RefCell::new(gba);
+        let lua = &self.lua;
+        lua.scope(|scope| {
+            let globals = lua.globals();
+
+            let emu: Table = globals.get("emu")?;
+            emu.set(
+                "framecount",
+                scope.create_function(|_, ()| Ok(gba.borrow().sysbus.io.gpu.frame_count))?,
+            )?;
+            emu.set(
+                "game_code",
+                scope.create_function(|_, ()| Ok(gba.borrow().get_game_code()))?,
+            )?;
+            emu.set(
+                "game_title",
+                scope.create_function(|_, ()| Ok(gba.borrow().get_game_title()))?,
+            )?;
+
+            let memory = lua.create_table()?;
+            memory.set(
+                "read_u8",
+                scope.create_function(|_, addr: u32| {
+                    gba.borrow_mut()
+                        .mem()
+                        .read::<u8>(addr)
+                        .map_err(mlua::Error::external)
+                })?,
+            )?;
+            memory.set(
+                "read_u16",
+                scope.create_function(|_, addr: u32| {
+                    gba.borrow_mut()
+                        .mem()
+                        .read::<u16>(addr)
+                        .map_err(mlua::Error::external)
+                })?,
+            )?;
+            memory.set(
+                "read_u32",
+                scope.create_function(|_, addr: u32| {
+                    gba.borrow_mut()
+                        .mem()
+                        .read::<u32>(addr)
+                        .map_err(mlua::Error::external)
+                })?,
+            )?;
+            memory.set(
+                "read_range",
+                scope.create_function(|lua, (addr, size): (u32, u32)| {
+                    if addr.checked_add(size).is_none() {
+                        return Err(mlua::Error::external(CoreError::MemoryError(format!(
+                            "{:#x} bytes at {:#010x} go past the end of the address space",
+                            size, addr
+                        ))));
+                    }
+                    let bytes = gba
+                        .borrow_mut()
+                        .mem()
+                        .read_bytes(addr, size)
+                        .map_err(mlua::Error::external)?;
+                    lua.create_string(&bytes)
+                })?,
+            )?;
+            memory.set(
+                "write_u8",
+                scope.create_function(|_, (addr, value): (u32, u8)| {
+                    let mut gba = gba.borrow_mut();
+                    let mut mem = gba.mem().mode(AccessMode::Bus);
+                    mem.write(addr, value).map_err(mlua::Error::external)
+                })?,
+            )?;
+            memory.set(
+                "write_u16",
+                scope.create_function(|_, (addr, value): (u32, u16)| {
+                    let mut gba = gba.borrow_mut();
+                    let mut mem = gba.mem().mode(AccessMode::Bus);
+                    mem.write(addr, value).map_err(mlua::Error::external)
+                })?,
+            )?;
+            memory.set(
+                "write_u32",
+                scope.create_function(|_, (addr, value): (u32, u32)| {
+                    let mut gba = gba.borrow_mut();
+                    let mut mem = gba.mem().mode(AccessMode::Bus);
+                    mem.write(addr, value).map_err(mlua::Error::external)
+                })?,
+            )?;
+            globals.set("memory", memory)?;
+
+            let joypad = lua.create_table()?;
+            joypad.set(
+                "get",
+                scope.create_function(|lua, ()| {
+                    let keyinput = gba.borrow().sysbus.io.keyinput;
+                    let keys = lua.create_table()?;
+                    for (i, name) in KEY_NAMES.iter().enumerate() {
+                        keys.set(*name, !keyinput.bit(i))?;
+                    }
+                    Ok(keys)
+                })?,
+            )?;
+            joypad.set(
+                "set",
+                scope.create_function(|_, keys: Table| {
+                    let mut keyinput = KEYINPUT_ALL_RELEASED;
+                    for pair in keys.pairs::<String, bool>() {
+                        let (name, pressed) = pair?;
+                        let key = Keys::try_from(name.as_str()).map_err(mlua::Error::external)?;
+                        keyinput.set_bit(key as usize, !pressed);
+                    }
+                    gba.borrow_mut().set_keyinput_override(Some(keyinput));
+                    Ok(())
+                })?,
+            )?;
+            globals.set("joypad", joypad)?;
+
+            let savestate = lua.create_table()?;
+            savestate.set(
+                "save",
+                scope.create_function(|lua, ()| {
+                    let state = gba.borrow().save_state().map_err(mlua::Error::external)?;
+                    lua.create_string(&state)
+                })?,
+            )?;
+            savestate.set(
+                "load",
+                scope.create_function(|_, state: mlua::String| {
+                    gba.borrow_mut()
+                        .restore_state(state.as_bytes())
+                        .map_err(mlua::Error::external)
+                })?,
+            )?;
+            globals.set("savestate", savestate)?;
+
+            let gui = lua.create_table()?;
+            gui.set(
+                "text",
+                scope.create_function(|_, (x, y, text, color, frames): TextArgs| {
+                    gba.borrow_mut().sysbus.io.gpu.overlay.text(
+                        x,
+                        y,
+                        &text,
+                        color.unwrap_or(DEFAULT_COLOR),
+                        frames.unwrap_or(1),
+                    );
+                    Ok(())
+                })?,
+            )?;
+            gui.set(
+                "rect",
+                scope.create_function(|_, (x, y, w, h, color, frames): RectArgs| {
+                    gba.borrow_mut().sysbus.io.gpu.overlay.rect(
+                        x,
+                        y,
+                        w,
+                        h,
+                        color.unwrap_or(DEFAULT_COLOR),
+                        frames.unwrap_or(1),
+                    );
+                    Ok(())
+                })?,
+            )?;
+            globals.set("gui", gui)?;
+
+            f(lua)
+        })?;
+        Ok(())
+    }
+}
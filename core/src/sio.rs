@@ -0,0 +1,724 @@
+//! The serial port, where the link cable plugs in.
+//!
+//! The other end of the cable is a `SerialDevice`, see `GameBoyAdvance::set_serial_device`.
+//! Without a partner, a few games wait forever in their link checks: a transfer clocked by the
+//! other console never completes when there is none. `DummyLink` stands in for that console:
+//! ```text
+//! disconnected    nothing plugged in, as on real hardware
+//! slave           another console, always ready, answering 0 to everything
+//! echo            a cable plugged back into the port, every byte sent is received
+//! ```
+//! The transfers complete after the time the hardware takes, and raise the serial interrupt
+//! when it is enabled.
+//!
+//! In UART mode the bytes go out one at a time at the baud rate the game set, with its data
+//! length and parity, through FIFOs of 4 bytes when it enables them. `UartBridge` connects the
+//! UART to a TCP client or a serial device of the host, for the homebrew that print to a debug
+//! console.
+//!
+//! In general purpose mode, RCNT drives the 4 lines of the port directly, as for some unusual
+//! peripherals and homebrew linking more than 4 consoles: the device sees the levels the GBA
+//! puts on its outputs, and sets the other lines.
+use alloc::string::String;
+use core::cell::Cell;
+use core::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use super::gba::CPU_CLOCK_HZ;
+use super::interrupt::{self, Interrupt, InterruptConnect, SharedInterruptFlags};
+use super::iodev::consts::*;
+use super::logging;
+use super::{LoadContext, MaybeSend, ResetAfterLoad};
+
+/// SIOCNT bits shared by the normal and multiplayer modes
+const SIOCNT_START: u16 = 1 << 7;
+const SIOCNT_IRQ: u16 = 1 << 14;
+/// Normal mode: the GBA drives the clock
+const SIOCNT_INTERNAL_CLOCK: u16 = 1 << 0;
+const SIOCNT_2MHZ: u16 = 1 << 1;
+/// Normal mode: SI, the SO line of the other end, high when nothing is plugged in
+const SIOCNT_SI: u16 = 1 << 2;
+/// Multiplayer mode: SI, ID and error bits, all read-only
+const SIOCNT_MULTI_STATUS: u16 = 0x007c;
+/// Multiplayer mode: SD, all the consoles are ready
+const SIOCNT_SD: u16 = 1 << 3;
+
+/// UART mode: send only while the other end is ready
+const UART_CTS: u16 = 1 << 2;
+const UART_SEND_FULL: u16 = 1 << 4;
+const UART_RECEIVE_EMPTY: u16 = 1 << 5;
+const UART_ERROR: u16 = 1 << 6;
+const UART_8BIT: u16 = 1 << 7;
+const UART_FIFO: u16 = 1 << 8;
+const UART_PARITY: u16 = 1 << 9;
+const UART_SEND_ENABLE: u16 = 1 << 10;
+const UART_RECEIVE_ENABLE: u16 = 1 << 11;
+const UART_STATUS: u16 = UART_SEND_FULL | UART_RECEIVE_EMPTY | UART_ERROR;
+
+const UART_FIFO_LEN: usize = 4;
+
+const RCNT_WRITE_MASK: u16 = 0xc1ff;
+/// General purpose mode: interrupt when SI goes from high to low
+const RCNT_SI_IRQ: u16 = 1 << 8;
+
+/// General purpose mode: the lines in the data (bits 0-3) and direction (bits 4-7) bits of RCNT
+pub const GPIO_SC: u8 = 1 << 0;
+pub const GPIO_SD: u8 = 1 << 1;
+pub const GPIO_SI: u8 = 1 << 2;
+pub const GPIO_SO: u8 = 1 << 3;
+const GPIO_LINES: u8 = 0xf;
+
+/// Start, 16 data and stop bits, from each of the 4 consoles a multiplayer transfer can have
+const MULTIPLAYER_TRANSFER_BITS: usize = 18 * 4;
+/// Of the multiplayer and UART modes
+const BAUD_RATES: [usize; 4] = [9600, 38400, 57600, 115_200];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SioMode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    /// The general purpose mode of RCNT
+    Gpio,
+    JoyBus,
+}
+
+/// The other end of the link cable
+pub trait SerialDevice: MaybeSend {
+    /// Whether the other end signals it is ready: SI low in normal mode, SD high in
+    /// multiplayer mode
+    fn ready(&self) -> bool;
+
+    /// Exchanges the low `bits` (8 or 32) of `data` in normal mode, returning what the other end
+    /// sent. When the GBA isn't the `master`, the transfer waits for the other end to drive the
+    /// clock, `None` while it doesn't.
+    fn transfer_normal(&mut self, data: u32, bits: u32, master: bool) -> Option<u32>;
+
+    /// Sends `data` as the parent of a multiplayer transfer, returning what the 3 children sent,
+    /// `None` when no child answers
+    fn transfer_multiplayer(&mut self, data: u16) -> Option<[u16; 3]>;
+
+    /// Sends a byte in UART mode
+    #[allow(unused_variables)]
+    fn uart_send(&mut self, byte: u8) {}
+
+    /// The next byte the other end sent in UART mode, polled once per byte time
+    fn uart_receive(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// In general purpose mode, sees the GBA put `levels` on the lines of `outputs` (`GPIO_SC`,
+    /// `GPIO_SD`, `GPIO_SI` and `GPIO_SO`), and returns the levels of the others, called on
+    /// every update. The lines left floating are pulled high.
+    #[allow(unused_variables)]
+    fn gpio(&mut self, levels: u8, outputs: u8) -> u8 {
+        GPIO_LINES
+    }
+}
+
+/// A stand-in for another console, for the games that won't go on without one
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DummyLink {
+    /// Nothing plugged in, the input lines are pulled high
+    #[default]
+    Disconnected,
+    /// Another console, always ready and answering 0
+    Slave,
+    /// A cable looped back, what is sent is received
+    Echo,
+}
+
+/// Parses "disconnected", "slave" or "echo"
+impl TryFrom<&str> for DummyLink {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, String> {
+        match s {
+            "disconnected" => Ok(DummyLink::Disconnected),
+            "slave" => Ok(DummyLink::Slave),
+            "echo" => Ok(DummyLink::Echo),
+            _ => Err(format!("{} is not a valid link cable", s)),
+        }
+    }
+}
+
+impl SerialDevice for DummyLink {
+    fn ready(&self) -> bool {
+        *self != DummyLink::Disconnected
+    }
+
+    fn transfer_normal(&mut self, data: u32, bits: u32, master: bool) -> Option<u32> {
+        let mask = if bits >= 32 { !0 } else { (1 << bits) - 1 };
+        match self {
+            DummyLink::Disconnected if master => Some(mask),
+            DummyLink::Disconnected => None,
+            DummyLink::Slave => Some(0),
+            DummyLink::Echo => Some(data & mask),
+        }
+    }
+
+    fn transfer_multiplayer(&mut self, data: u16) -> Option<[u16; 3]> {
+        match self {
+            DummyLink::Disconnected => None,
+            DummyLink::Slave => Some([0, 0xffff, 0xffff]),
+            DummyLink::Echo => Some([data; 3]),
+        }
+    }
+
+    fn gpio(&mut self, levels: u8, outputs: u8) -> u8 {
+        match self {
+            // SO is wired to SI
+            DummyLink::Echo if outputs & GPIO_SO != 0 && levels & GPIO_SO == 0 => {
+                GPIO_LINES & !GPIO_SI
+            }
+            _ => GPIO_LINES,
+        }
+    }
+}
+
+/// A UART FIFO, holding a single byte when the FIFOs are disabled
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct UartFifo {
+    bytes: [u8; UART_FIFO_LEN],
+    len: usize,
+}
+
+impl UartFifo {
+    /// False when the FIFO is full
+    fn push(&mut self, byte: u8, capacity: usize) -> bool {
+        if self.len >= capacity {
+            return false;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[0];
+        self.bytes.copy_within(1.., 0);
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// What reading the UART registers changes, see `SerialController::uart_read_state`
+#[derive(Clone, Copy)]
+pub(crate) struct UartReadState(UartFifo, bool);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SerialController {
+    pub siocnt: u16,
+    pub rcnt: u16,
+    /// SIODATA32, or SIOMULTI0-3 in multiplayer mode
+    data: [u16; 4],
+    /// SIODATA8, or SIOMLT_SEND in multiplayer mode
+    send: u16,
+    /// Cycles until the transfer in progress completes, it may then still wait for the clock
+    /// of the other end
+    transfer_cycles: Option<usize>,
+    /// Of the device, as of the last update
+    ready: bool,
+    uart_send: UartFifo,
+    /// Popped by reading SIODATA8
+    uart_receive: Cell<UartFifo>,
+    /// A byte was received with the receive FIFO full, cleared by reading SIOCNT
+    uart_error: Cell<bool>,
+    /// Towards the next byte time
+    uart_cycles: usize,
+    /// Levels of the lines the device drives in general purpose mode
+    gpio_inputs: u8,
+    interrupt_flags: SharedInterruptFlags,
+}
+
+impl InterruptConnect for SerialController {
+    fn connect_irq(&mut self, interrupt_flags: SharedInterruptFlags) {
+        self.interrupt_flags = interrupt_flags;
+    }
+}
+
+impl ResetAfterLoad for SerialController {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.connect_irq(ctx.interrupt_flags.clone());
+    }
+}
+
+impl SerialController {
+    pub fn new(interrupt_flags: SharedInterruptFlags) -> SerialController {
+        SerialController {
+            siocnt: 0,
+            rcnt: 0,
+            data: [0; 4],
+            send: 0,
+            transfer_cycles: None,
+            ready: false,
+            uart_send: UartFifo::default(),
+            uart_receive: Cell::new(UartFifo::default()),
+            uart_error: Cell::new(false),
+            uart_cycles: 0,
+            gpio_inputs: GPIO_LINES,
+            interrupt_flags,
+        }
+    }
+
+    pub fn mode(&self) -> SioMode {
+        match (self.rcnt >> 14, (self.siocnt >> 12) & 3) {
+            (2, _) => SioMode::Gpio,
+            (3, _) => SioMode::JoyBus,
+            (_, 0) => SioMode::Normal8,
+            (_, 1) => SioMode::Normal32,
+            (_, 2) => SioMode::Multiplayer,
+            _ => SioMode::Uart,
+        }
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.transfer_cycles.is_some()
+    }
+
+    pub fn handle_read(&self, io_addr: u32) -> u16 {
+        match io_addr {
+            REG_SIOMULTI0..=REG_SIOMULTI3 => self.data[((io_addr - REG_SIOMULTI0) / 2) as usize],
+            REG_SIOCNT => match self.mode() {
+                SioMode::Normal8 | SioMode::Normal32 if self.ready => self.siocnt & !SIOCNT_SI,
+                SioMode::Normal8 | SioMode::Normal32 => self.siocnt | SIOCNT_SI,
+                // always the parent, with no error
+                SioMode::Multiplayer if self.ready => {
+                    (self.siocnt & !SIOCNT_MULTI_STATUS) | SIOCNT_SD
+                }
+                SioMode::Multiplayer => self.siocnt & !SIOCNT_MULTI_STATUS,
+                SioMode::Uart => self.read_uart_status(),
+                _ => self.siocnt,
+            },
+            REG_SIODATA8 if self.mode() == SioMode::Uart => {
+                let mut fifo = self.uart_receive.get();
+                let byte = fifo.pop().unwrap_or(0);
+                self.uart_receive.set(fifo);
+                byte as u16
+            }
+            REG_SIODATA8 => self.send,
+            REG_RCNT if self.mode() == SioMode::Gpio => {
+                (self.rcnt & !(GPIO_LINES as u16)) | self.gpio_levels() as u16
+            }
+            REG_RCNT => self.rcnt,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn handle_write(&mut self, io_addr: u32, value: u16) {
+        match io_addr {
+            REG_SIOMULTI0..=REG_SIOMULTI3 => {
+                self.data[((io_addr - REG_SIOMULTI0) / 2) as usize] = value
+            }
+            REG_SIOCNT => self.write_siocnt(value),
+            REG_SIODATA8 if self.mode() == SioMode::Uart => {
+                let capacity = self.uart_fifo_capacity();
+                if !self.uart_send.push(value as u8, capacity) {
+                    debug!(target: logging::BUS, "UART send FIFO overflow");
+                }
+            }
+            REG_SIODATA8 => self.send = value,
+            REG_RCNT => self.rcnt = value & RCNT_WRITE_MASK,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_siocnt(&mut self, value: u16) {
+        let starting = value & SIOCNT_START != 0 && !self.is_busy();
+        self.siocnt = value;
+        if self.mode() == SioMode::Uart {
+            // bit 7 is the data length
+            self.siocnt &= !UART_STATUS;
+            self.transfer_cycles = None;
+        } else if value & SIOCNT_START == 0 {
+            // stops a transfer still waiting for the clock of the other end
+            self.transfer_cycles = None;
+        } else if starting {
+            self.transfer_cycles = self.transfer_time();
+            if self.transfer_cycles.is_none() {
+                debug!(target: logging::BUS, "{:?} transfers are not emulated", self.mode());
+            }
+        }
+    }
+
+    /// Cycles a transfer takes in the current mode, `None` if it is not emulated
+    fn transfer_time(&self) -> Option<usize> {
+        let bit_cycles =
+            if self.siocnt & SIOCNT_INTERNAL_CLOCK != 0 && self.siocnt & SIOCNT_2MHZ != 0 {
+                CPU_CLOCK_HZ / (2 * 1024 * 1024)
+            } else {
+                // the other end clocks at 256KHz too
+                CPU_CLOCK_HZ / (256 * 1024)
+            };
+        match self.mode() {
+            SioMode::Normal8 => Some(8 * bit_cycles),
+            SioMode::Normal32 => Some(32 * bit_cycles),
+            SioMode::Multiplayer => {
+                let baud_rate = BAUD_RATES[(self.siocnt & 3) as usize];
+                Some(MULTIPLAYER_TRANSFER_BITS * CPU_CLOCK_HZ / baud_rate)
+            }
+            _ => None,
+        }
+    }
+
+    /// Advances the transfer in progress by `cycles`, exchanging the data with `device` once it
+    /// is over
+    pub fn update(&mut self, cycles: usize, device: &mut dyn SerialDevice) {
+        self.ready = device.ready();
+        match self.mode() {
+            SioMode::Uart => return self.update_uart(cycles, device),
+            SioMode::Gpio => return self.update_gpio(device),
+            _ => {}
+        }
+        let cycles_left = match self.transfer_cycles {
+            Some(cycles_left) => cycles_left,
+            None => return,
+        };
+        if cycles_left > cycles {
+            self.transfer_cycles = Some(cycles_left - cycles);
+            return;
+        }
+        self.transfer_cycles = Some(0);
+
+        let master = self.siocnt & SIOCNT_INTERNAL_CLOCK != 0;
+        match self.mode() {
+            SioMode::Normal8 => match device.transfer_normal(self.send as u32 & 0xff, 8, master) {
+                Some(received) => self.send = (self.send & 0xff00) | (received as u16 & 0xff),
+                None => return,
+            },
+            SioMode::Normal32 => {
+                let data = (self.data[1] as u32) << 16 | self.data[0] as u32;
+                match device.transfer_normal(data, 32, master) {
+                    Some(received) => {
+                        self.data[0] = received as u16;
+                        self.data[1] = (received >> 16) as u16;
+                    }
+                    None => return,
+                }
+            }
+            SioMode::Multiplayer => {
+                let children = device
+                    .transfer_multiplayer(self.send)
+                    .unwrap_or([0xffff; 3]);
+                self.data = [self.send, children[0], children[1], children[2]];
+            }
+            // the mode changed during the transfer
+            _ => {}
+        }
+
+        self.transfer_cycles = None;
+        self.siocnt &= !SIOCNT_START;
+        if self.siocnt & SIOCNT_IRQ != 0 {
+            interrupt::signal_irq(&self.interrupt_flags, Interrupt::SerialCommunication);
+        }
+    }
+
+    /// The lines of the port in general purpose mode, driven by the GBA or by the device
+    pub fn gpio_levels(&self) -> u8 {
+        let outputs = (self.rcnt >> 4) as u8 & GPIO_LINES;
+        (self.rcnt as u8 & outputs) | (self.gpio_inputs & !outputs & GPIO_LINES)
+    }
+
+    fn update_gpio(&mut self, device: &mut dyn SerialDevice) {
+        let outputs = (self.rcnt >> 4) as u8 & GPIO_LINES;
+        let before = self.gpio_levels();
+        self.gpio_inputs = device.gpio(self.rcnt as u8 & outputs, outputs) & GPIO_LINES;
+        let falling = before & !self.gpio_levels();
+        if falling & GPIO_SI != 0 && self.rcnt & RCNT_SI_IRQ != 0 {
+            interrupt::signal_irq(&self.interrupt_flags, Interrupt::SerialCommunication);
+        }
+    }
+
+    fn uart_fifo_capacity(&self) -> usize {
+        if self.siocnt & UART_FIFO != 0 {
+            UART_FIFO_LEN
+        } else {
+            1
+        }
+    }
+
+    /// SIOCNT, reading it clears the error flag
+    fn read_uart_status(&self) -> u16 {
+        let mut status = self.siocnt;
+        if self.uart_send.len >= self.uart_fifo_capacity() {
+            status |= UART_SEND_FULL;
+        }
+        if self.uart_receive.get().len == 0 {
+            status |= UART_RECEIVE_EMPTY;
+        }
+        if self.uart_error.replace(false) {
+            status |= UART_ERROR;
+        }
+        status
+    }
+
+    /// Saved by debuggers around their reads, which must not consume the received bytes
+    pub(crate) fn uart_read_state(&self) -> UartReadState {
+        UartReadState(self.uart_receive.get(), self.uart_error.get())
+    }
+
+    pub(crate) fn set_uart_read_state(&self, state: UartReadState) {
+        self.uart_receive.set(state.0);
+        self.uart_error.set(state.1);
+    }
+
+    /// Cycles a byte takes on the line, with its start, parity and stop bits. The parity itself
+    /// (even or odd) is left to the devices, a byte never arrives corrupted.
+    fn uart_byte_cycles(&self) -> usize {
+        let data_bits = if self.siocnt & UART_8BIT != 0 { 8 } else { 7 };
+        let parity_bits = if self.siocnt & UART_PARITY != 0 { 1 } else { 0 };
+        let baud_rate = BAUD_RATES[(self.siocnt & 3) as usize];
+        (1 + data_bits + parity_bits + 1) * CPU_CLOCK_HZ / baud_rate
+    }
+
+    fn update_uart(&mut self, cycles: usize, device: &mut dyn SerialDevice) {
+        let byte_cycles = self.uart_byte_cycles();
+        self.uart_cycles += cycles;
+        while self.uart_cycles >= byte_cycles {
+            self.uart_cycles -= byte_cycles;
+            self.uart_transfer_byte(device);
+        }
+    }
+
+    /// Sends and receives a byte, whichever there is to send or receive
+    fn uart_transfer_byte(&mut self, device: &mut dyn SerialDevice) {
+        let mask = if self.siocnt & UART_8BIT != 0 {
+            0xff
+        } else {
+            0x7f
+        };
+        let mut irq = false;
+        let clear_to_send = self.siocnt & UART_CTS == 0 || device.ready();
+        if self.siocnt & UART_SEND_ENABLE != 0 && clear_to_send {
+            if let Some(byte) = self.uart_send.pop() {
+                device.uart_send(byte & mask);
+                irq |= self.uart_send.len == 0;
+            }
+        }
+        if self.siocnt & UART_RECEIVE_ENABLE != 0 {
+            if let Some(byte) = device.uart_receive() {
+                let mut fifo = self.uart_receive.get();
+                if !fifo.push(byte & mask, self.uart_fifo_capacity()) {
+                    // the byte is lost
+                    self.uart_error.set(true);
+                }
+                self.uart_receive.set(fifo);
+                irq = true;
+            }
+        }
+        if irq && self.siocnt & SIOCNT_IRQ != 0 {
+            interrupt::signal_irq(&self.interrupt_flags, Interrupt::SerialCommunication);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::interrupt::IrqBitmask;
+
+    fn start(sio: &mut SerialController, siocnt: u16) {
+        sio.handle_write(REG_SIOCNT, siocnt | SIOCNT_START);
+        assert!(sio.is_busy());
+    }
+
+    #[test]
+    fn test_normal_transfers() {
+        let flags = SharedInterruptFlags::new(IrqBitmask(0));
+        let mut sio = SerialController::new(flags.clone());
+        let mut link = DummyLink::Disconnected;
+
+        // 8 bits at 256KHz from the internal clock, nobody answers
+        sio.handle_write(REG_SIODATA8, 0x42);
+        start(&mut sio, SIOCNT_INTERNAL_CLOCK | SIOCNT_IRQ);
+        sio.update(8 * 64 - 1, &mut link);
+        assert!(sio.is_busy());
+        sio.update(1, &mut link);
+        assert!(!sio.is_busy());
+        assert_eq!(sio.handle_read(REG_SIODATA8), 0xff);
+        assert_eq!(sio.handle_read(REG_SIOCNT) & SIOCNT_START, 0);
+        assert_eq!(
+            flags.get().value(),
+            1 << Interrupt::SerialCommunication as u16
+        );
+
+        // as a slave, the transfer waits for a clock that never comes...
+        start(&mut sio, 0x1000);
+        sio.update(10_000, &mut link);
+        assert!(sio.is_busy());
+        assert_ne!(sio.handle_read(REG_SIOCNT) & SIOCNT_SI, 0);
+
+        // ...unless a partner is plugged in
+        let mut link = DummyLink::Echo;
+        sio.handle_write(REG_SIOMULTI0, 0x5678);
+        sio.handle_write(REG_SIOMULTI1, 0x1234);
+        sio.update(1, &mut link);
+        assert!(!sio.is_busy());
+        assert_eq!(sio.handle_read(REG_SIOMULTI1), 0x1234);
+        assert_eq!(sio.handle_read(REG_SIOCNT) & SIOCNT_SI, 0);
+    }
+
+    #[test]
+    fn test_multiplayer_transfer() {
+        let mut sio = SerialController::new(SharedInterruptFlags::new(IrqBitmask(0)));
+        let mut link = DummyLink::Slave;
+        // 115200 bauds
+        sio.handle_write(REG_SIOCNT, 0x2003);
+        sio.update(0, &mut link);
+        assert_ne!(sio.handle_read(REG_SIOCNT) & SIOCNT_SD, 0);
+
+        sio.handle_write(REG_SIOMLT_SEND, 0xabcd);
+        start(&mut sio, 0x2003);
+        sio.update(1_000_000, &mut link);
+        assert!(!sio.is_busy());
+        let data: Vec<u16> = (0..4)
+            .map(|i| sio.handle_read(REG_SIOMULTI0 + 2 * i))
+            .collect();
+        assert_eq!(data, vec![0xabcd, 0, 0xffff, 0xffff]);
+    }
+
+    #[derive(Default)]
+    struct Terminal {
+        sent: Vec<u8>,
+        to_receive: Vec<u8>,
+    }
+
+    impl SerialDevice for Terminal {
+        fn ready(&self) -> bool {
+            true
+        }
+
+        fn transfer_normal(&mut self, _data: u32, _bits: u32, _master: bool) -> Option<u32> {
+            None
+        }
+
+        fn transfer_multiplayer(&mut self, _data: u16) -> Option<[u16; 3]> {
+            None
+        }
+
+        fn uart_send(&mut self, byte: u8) {
+            self.sent.push(byte);
+        }
+
+        fn uart_receive(&mut self) -> Option<u8> {
+            if self.to_receive.is_empty() {
+                None
+            } else {
+                Some(self.to_receive.remove(0))
+            }
+        }
+    }
+
+    #[test]
+    fn test_uart() {
+        let flags = SharedInterruptFlags::new(IrqBitmask(0));
+        let mut sio = SerialController::new(flags.clone());
+        let mut terminal = Terminal::default();
+        terminal.to_receive = b"ok!!!".to_vec();
+        // 115200 bauds, 8 bits, FIFOs, sending and receiving, no parity
+        let siocnt = 0x3003 | UART_8BIT | UART_FIFO | UART_SEND_ENABLE | UART_RECEIVE_ENABLE;
+        sio.handle_write(REG_SIOCNT, siocnt | SIOCNT_IRQ);
+        for byte in b"hello" {
+            sio.handle_write(REG_SIODATA8, *byte as u16);
+        }
+        assert_ne!(sio.handle_read(REG_SIOCNT) & UART_SEND_FULL, 0);
+
+        let byte_cycles = 10 * CPU_CLOCK_HZ / 115_200;
+        sio.update(byte_cycles - 1, &mut terminal);
+        assert!(terminal.sent.is_empty());
+        assert_eq!(flags.get().value(), 0);
+        sio.update(1, &mut terminal);
+        assert_eq!(terminal.sent, b"h");
+        assert_ne!(flags.get().value(), 0);
+
+        // the fifth byte didn't fit in the FIFOs
+        sio.update(4 * byte_cycles, &mut terminal);
+        assert_eq!(terminal.sent, b"hell");
+        let status = sio.handle_read(REG_SIOCNT);
+        assert_eq!(status & (UART_SEND_FULL | UART_RECEIVE_EMPTY), 0);
+        assert_ne!(status & UART_ERROR, 0);
+        assert_eq!(sio.handle_read(REG_SIOCNT) & UART_ERROR, 0);
+
+        let state = sio.uart_read_state();
+        assert_eq!(sio.handle_read(REG_SIODATA8), b'o' as u16);
+        sio.set_uart_read_state(state);
+        let received: Vec<u8> = (0..4)
+            .map(|_| sio.handle_read(REG_SIODATA8) as u8)
+            .collect();
+        assert_eq!(received, b"ok!!");
+        assert_ne!(sio.handle_read(REG_SIOCNT) & UART_RECEIVE_EMPTY, 0);
+    }
+
+    /// Answers SD with the inverse of SC, and pulls SI low on request
+    #[derive(Default)]
+    struct Wires {
+        si_low: bool,
+    }
+
+    impl SerialDevice for Wires {
+        fn ready(&self) -> bool {
+            false
+        }
+
+        fn transfer_normal(&mut self, _data: u32, _bits: u32, _master: bool) -> Option<u32> {
+            None
+        }
+
+        fn transfer_multiplayer(&mut self, _data: u16) -> Option<[u16; 3]> {
+            None
+        }
+
+        fn gpio(&mut self, levels: u8, outputs: u8) -> u8 {
+            assert_eq!(outputs, GPIO_SC | GPIO_SO);
+            let mut inputs = GPIO_LINES;
+            if levels & GPIO_SC != 0 {
+                inputs &= !GPIO_SD;
+            }
+            if self.si_low {
+                inputs &= !GPIO_SI;
+            }
+            inputs
+        }
+    }
+
+    #[test]
+    fn test_gpio() {
+        let flags = SharedInterruptFlags::new(IrqBitmask(0));
+        let mut sio = SerialController::new(flags.clone());
+        let mut wires = Wires::default();
+        // SC and SO are outputs, SC high and SO low
+        sio.handle_write(REG_RCNT, 0x8000 | RCNT_SI_IRQ | 0x90 | 0x1);
+        assert_eq!(sio.mode(), SioMode::Gpio);
+        sio.update(1, &mut wires);
+        assert_eq!(sio.handle_read(REG_RCNT) & 0xf, 0x5);
+        assert_eq!(flags.get().value(), 0);
+
+        // SC low, SO high
+        sio.handle_write(REG_RCNT, 0x8000 | RCNT_SI_IRQ | 0x90 | 0x8);
+        sio.update(1, &mut wires);
+        assert_eq!(sio.handle_read(REG_RCNT) & 0xf, 0xe);
+
+        wires.si_low = true;
+        sio.update(1, &mut wires);
+        assert_eq!(sio.handle_read(REG_RCNT) & 0xf, 0xa);
+        assert_eq!(
+            flags.get().value(),
+            1 << Interrupt::SerialCommunication as u16
+        );
+
+        // looped back, SI follows SO
+        let mut link = DummyLink::Echo;
+        sio.handle_write(REG_RCNT, 0x8000 | 0x80);
+        sio.update(1, &mut link);
+        assert_eq!(sio.handle_read(REG_RCNT) & GPIO_SI as u16, 0);
+        sio.handle_write(REG_RCNT, 0x8000 | 0x80 | 0x8);
+        sio.update(1, &mut link);
+        assert_ne!(sio.handle_read(REG_RCNT) & GPIO_SI as u16, 0);
+    }
+}
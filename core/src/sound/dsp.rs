@@ -1,8 +1,12 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
 use crate::StereoSample;
 
 use serde::{Deserialize, Serialize};
 
-const PI: f32 = std::f32::consts::PI;
+const PI: f32 = core::f32::consts::PI;
 
 pub trait Resampler {
     fn feed(&mut self, s: StereoSample<f32>, output: &mut Vec<StereoSample<f32>>);
@@ -13,7 +17,7 @@ pub struct CosineResampler {
     last_in_sample: StereoSample<f32>,
     phase: f32,
     pub in_freq: f32,
-    out_freq: f32,
+    pub out_freq: f32,
 }
 
 fn cosine_interpolation(y1: f32, y2: f32, phase: f32) -> f32 {
@@ -21,6 +25,51 @@ fn cosine_interpolation(y1: f32, y2: f32, phase: f32) -> f32 {
     y2 * (1.0 - mu2) + y1 * mu2
 }
 
+/// How DirectSound samples are interpolated between two timer ticks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Interpolation {
+    /// Each sample is held until the next tick, like the hardware does
+    #[default]
+    None,
+    Linear,
+    Cosine,
+    /// Catmull-Rom spline, which needs a sample ahead and so lags one more sample behind
+    Cubic,
+}
+
+impl TryFrom<&str> for Interpolation {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "none" => Ok(Interpolation::None),
+            "linear" => Ok(Interpolation::Linear),
+            "cosine" => Ok(Interpolation::Cosine),
+            "cubic" => Ok(Interpolation::Cubic),
+            _ => Err(format!("{} is not a valid interpolation", s)),
+        }
+    }
+}
+
+impl Interpolation {
+    /// `history` holds the last 4 samples, the newest last. `phase` is the time since the newest
+    /// sample, as a fraction of the sample period.
+    pub fn interpolate(&self, history: &[f32; 4], phase: f32) -> f32 {
+        let [y0, y1, y2, y3] = *history;
+        match self {
+            Interpolation::None => y3,
+            Interpolation::Linear => y2 + (y3 - y2) * phase,
+            Interpolation::Cosine => cosine_interpolation(y3, y2, phase),
+            Interpolation::Cubic => {
+                let a = -0.5 * y0 + 1.5 * y1 - 1.5 * y2 + 0.5 * y3;
+                let b = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+                let c = -0.5 * y0 + 0.5 * y2;
+                ((a * phase + b) * phase + c) * phase + y1
+            }
+        }
+    }
+}
+
 impl Resampler for CosineResampler {
     fn feed(&mut self, s: StereoSample<f32>, output: &mut Vec<StereoSample<f32>>) {
         while self.phase < 1.0 {
@@ -44,3 +93,21 @@ impl CosineResampler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolation_endpoints() {
+        let history = [1.0, 2.0, 4.0, 8.0];
+        for interpolation in &[Interpolation::Linear, Interpolation::Cosine] {
+            assert!((interpolation.interpolate(&history, 0.0) - 4.0).abs() < 1e-4);
+            assert!((interpolation.interpolate(&history, 1.0) - 8.0).abs() < 1e-4);
+        }
+        assert!((Interpolation::Cubic.interpolate(&history, 0.0) - 2.0).abs() < 1e-4);
+        assert!((Interpolation::Cubic.interpolate(&history, 1.0) - 4.0).abs() < 1e-4);
+        assert_eq!(Interpolation::None.interpolate(&history, 0.5), 8.0);
+        assert_eq!(Interpolation::try_from("cubic"), Ok(Interpolation::Cubic));
+    }
+}
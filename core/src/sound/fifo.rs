@@ -21,13 +21,15 @@ impl SoundFifo {
         }
     }
 
-    pub fn write(&mut self, value: i8) {
+    /// Returns false when the fifo is full and the value is dropped
+    pub fn write(&mut self, value: i8) -> bool {
         if self.count >= SOUND_FIFO_CAPACITY {
-            return;
+            return false;
         }
         self.data[self.wr_pos] = value;
         self.wr_pos = (self.wr_pos + 1) % SOUND_FIFO_CAPACITY;
         self.count += 1;
+        true
     }
 
     pub fn read(&mut self) -> i8 {
@@ -40,6 +42,10 @@ impl SoundFifo {
         value
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
     pub fn count(&self) -> usize {
         self.count
     }
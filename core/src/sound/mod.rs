@@ -1,27 +1,38 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp;
 
 use bit::BitIndex;
 use serde::{Deserialize, Serialize};
 
 use super::dma::DmaController;
 use super::iodev::consts::*;
+use super::logging;
 
-use crate::{AudioInterface, StereoSample};
+use crate::{lock, AudioSink, LoadContext, ResetAfterLoad, Shared, StereoSample};
 
 mod fifo;
 use fifo::SoundFifo;
 
 mod dsp;
+pub use dsp::Interpolation;
 use dsp::{CosineResampler, Resampler};
 
-const DMG_RATIOS: [f32; 4] = [0.25, 0.5, 1.0, 0.0];
+mod psg;
+use psg::Psg;
+
+/// Volume of the PSG channels in quarters, the last setting is prohibited
+const PSG_VOLUMES: [i32; 4] = [1, 2, 4, 0];
 const DMA_TIMERS: [usize; 2] = [0, 1];
-const DUTY_RATIOS: [f32; 4] = [0.125, 0.25, 0.5, 0.75];
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct DmaSoundChannel {
-    value: i8,
+    /// The last samples read from the FIFO, the newest last
+    history: [i8; 4],
+    /// Cycles since the last sample was read
+    elapsed: usize,
+    /// Cycles between the last two samples
+    period: usize,
     volume_shift: i16,
     enable_right: bool,
     enable_left: bool,
@@ -37,13 +48,33 @@ impl DmaSoundChannel {
             _ => unreachable!(),
         }
     }
+
+    fn push_sample(&mut self, value: i8) {
+        self.history.rotate_left(1);
+        self.history[3] = value;
+        self.period = core::cmp::max(self.elapsed, 1);
+        self.elapsed = 0;
+    }
+
+    fn sample(&self, interpolation: Interpolation) -> f32 {
+        let history = [
+            self.history[0] as f32,
+            self.history[1] as f32,
+            self.history[2] as f32,
+            self.history[3] as f32,
+        ];
+        let phase = (self.elapsed as f32 / self.period as f32).min(1.0);
+        interpolation.interpolate(&history, phase)
+    }
 }
 
 impl Default for DmaSoundChannel {
     fn default() -> DmaSoundChannel {
         DmaSoundChannel {
             volume_shift: 0,
-            value: 0,
+            history: [0; 4],
+            elapsed: 0,
+            period: 1,
             enable_right: false,
             enable_left: false,
             timer_select: 0,
@@ -58,7 +89,33 @@ const REG_FIFO_A_H: u32 = REG_FIFO_A + 2;
 const REG_FIFO_B_L: u32 = REG_FIFO_B;
 const REG_FIFO_B_H: u32 = REG_FIFO_B + 2;
 
-type AudioDeviceRcRefCell = Rc<RefCell<dyn AudioInterface>>;
+/// Last byte of the PSG channel registers
+const PSG_END: u32 = REG_SOUND4CNT_H + 3;
+const WAVE_RAM_END: u32 = REG_WAVE_RAM + 0xf;
+
+type SharedAudioDevice = Shared<dyn AudioSink>;
+
+/// Glitches of a DirectSound FIFO since the counters were reset
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FifoStats {
+    /// Samples played from an empty FIFO, the DMA didn't keep up with the timer
+    pub underruns: u64,
+    /// Bytes dropped because the FIFO was full
+    pub overruns: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FifoEvent {
+    /// FIFO A is 0, FIFO B is 1
+    Underrun(usize),
+    Overrun(usize),
+}
+
+/// Called with the FIFO glitches of each frame, see `GameBoyAdvance::set_audio_fifo_hook`
+#[cfg(not(feature = "send"))]
+pub type FifoHook = Box<dyn FnMut(&FifoEvent)>;
+#[cfg(feature = "send")]
+pub type FifoHook = Box<dyn FnMut(&FifoEvent) + Send>;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SoundController {
@@ -66,28 +123,14 @@ pub struct SoundController {
 
     mse: bool,
 
-    left_volume: usize,
-    left_sqr1: bool,
-    left_sqr2: bool,
-    left_wave: bool,
-    left_noise: bool,
-
-    right_volume: usize,
-    right_sqr1: bool,
-    right_sqr2: bool,
-    right_wave: bool,
-    right_noise: bool,
-
-    dmg_volume_ratio: f32,
-
-    sqr1_rate: usize,
-    sqr1_timed: bool,
-    sqr1_length: f32,
-    sqr1_duty: f32,
-    sqr1_step_time: usize,
-    sqr1_step_increase: bool,
-    sqr1_initial_vol: usize,
-    sqr1_cur_vol: usize,
+    /// Master volume of the PSG channels, left then right, from 0 to 7
+    psg_master_volume: [usize; 2],
+    /// Which of square 1, square 2, wave and noise play on the left, then on the right
+    psg_enables: [[bool; 4]; 2],
+
+    psg_volume: usize,
+
+    psg: Psg,
 
     sound_bias: u16,
 
@@ -95,67 +138,114 @@ pub struct SoundController {
     cycles_per_sample: usize,
 
     dma_sound: [DmaSoundChannel; 2],
+    /// Smoothing of the DirectSound channels
+    pub interpolation: Interpolation,
 
     resampler: CosineResampler,
     output_buffer: Vec<StereoSample<f32>>,
+
+    /// Samples are produced but not pushed to the audio device, used for skipped frames
+    #[serde(skip)]
+    pub mute_output: bool,
+
+    #[serde(skip)]
+    fifo_stats: [FifoStats; 2],
+    /// FIFO events not yet passed to the hook, `None` when nobody listens
+    #[serde(skip)]
+    fifo_events: Option<Vec<FifoEvent>>,
+}
+
+impl ResetAfterLoad for SoundController {
+    /// The audio device may not run at the rate the state was saved with
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.resampler.out_freq = ctx.audio_sample_rate;
+    }
 }
 
 impl SoundController {
+    /// Changes the rate samples are produced at, a higher rate than the audio device's stretches
+    /// the audio (and lowers its pitch)
+    pub fn set_output_sample_rate(&mut self, sample_rate: f32) {
+        self.resampler.out_freq = sample_rate;
+    }
+
+    pub fn output_sample_rate(&self) -> f32 {
+        self.resampler.out_freq
+    }
+
     pub fn new(audio_device_sample_rate: f32) -> SoundController {
         let resampler = CosineResampler::new(32768_f32, audio_device_sample_rate);
         SoundController {
             cycles: 0,
             mse: false,
-            left_volume: 0,
-            left_sqr1: false,
-            left_sqr2: false,
-            left_wave: false,
-            left_noise: false,
-            right_volume: 0,
-            right_sqr1: false,
-            right_sqr2: false,
-            right_wave: false,
-            right_noise: false,
-            dmg_volume_ratio: 0.0,
-            sqr1_rate: 0,
-            sqr1_timed: false,
-            sqr1_length: 0.0,
-            sqr1_duty: DUTY_RATIOS[0],
-            sqr1_step_time: 0,
-            sqr1_step_increase: false,
-            sqr1_initial_vol: 0,
-            sqr1_cur_vol: 0,
+            psg_master_volume: [0; 2],
+            psg_enables: [[false; 4]; 2],
+            psg_volume: 0,
+            psg: Psg::new(),
             sound_bias: 0x200,
             sample_rate: 32_768f32,
             cycles_per_sample: 512,
             dma_sound: [Default::default(), Default::default()],
+            interpolation: Interpolation::default(),
 
             resampler: resampler,
             output_buffer: Vec::with_capacity(1024),
+            mute_output: false,
+            fifo_stats: Default::default(),
+            fifo_events: None,
+        }
+    }
+
+    pub fn fifo_stats(&self) -> [FifoStats; 2] {
+        self.fifo_stats
+    }
+
+    pub fn reset_fifo_stats(&mut self) {
+        self.fifo_stats = Default::default();
+    }
+
+    /// Starts or stops collecting the events returned by `take_fifo_events`
+    pub fn record_fifo_events(&mut self, enable: bool) {
+        self.fifo_events = if enable { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_fifo_events(&mut self) -> Vec<FifoEvent> {
+        self.fifo_events
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn on_fifo_event(&mut self, event: FifoEvent) {
+        match event {
+            FifoEvent::Underrun(fifo) => self.fifo_stats[fifo].underruns += 1,
+            FifoEvent::Overrun(fifo) => self.fifo_stats[fifo].overruns += 1,
+        }
+        if let Some(events) = &mut self.fifo_events {
+            events.push(event);
         }
     }
 
     pub fn handle_read(&self, io_addr: u32) -> u16 {
         let value = match io_addr {
-            REG_SOUNDCNT_X => cbit(7, self.mse),
+            REG_SOUND1CNT_L..=PSG_END => {
+                let offset = io_addr - REG_SOUND1CNT_L;
+                self.psg.read_8(offset) as u16 | (self.psg.read_8(offset + 1) as u16) << 8
+            }
+            REG_SOUNDCNT_X => cbit(7, self.mse) | self.psg.status(),
             REG_SOUNDCNT_L => {
-                self.left_volume as u16
-                    | (self.right_volume as u16) << 4
-                    | cbit(8, self.left_sqr1)
-                    | cbit(9, self.left_sqr2)
-                    | cbit(10, self.left_wave)
-                    | cbit(11, self.left_noise)
-                    | cbit(12, self.right_sqr1)
-                    | cbit(13, self.right_sqr2)
-                    | cbit(14, self.right_wave)
-                    | cbit(15, self.right_noise)
+                let mut value =
+                    self.psg_master_volume[0] as u16 | (self.psg_master_volume[1] as u16) << 4;
+                for (side, enables) in self.psg_enables.iter().enumerate() {
+                    for (psg_channel, enabled) in enables.iter().enumerate() {
+                        value |= cbit((8 + 4 * side + psg_channel) as u8, *enabled);
+                    }
+                }
+                value
             }
 
             REG_SOUNDCNT_H => {
-                DMG_RATIOS
-                    .iter()
-                    .position(|&f| f == self.dmg_volume_ratio)
-                    .expect("bad dmg_volume_ratio!") as u16
+                self.psg_volume as u16
                     | cbit(2, self.dma_sound[0].volume_shift == 1)
                     | cbit(3, self.dma_sound[1].volume_shift == 1)
                     | cbit(8, self.dma_sound[0].enable_right)
@@ -168,6 +258,12 @@ impl SoundController {
 
             REG_SOUNDBIAS => self.sound_bias,
 
+            REG_WAVE_RAM..=WAVE_RAM_END => {
+                let index = (io_addr - REG_WAVE_RAM) as usize;
+                self.psg.read_wave_ram(index) as u16
+                    | (self.psg.read_wave_ram(index + 1) as u16) << 8
+            }
+
             _ => {
                 // println!(
                 //     "Unimplemented read from {:x} {}",
@@ -186,46 +282,80 @@ impl SoundController {
         value
     }
 
-    pub fn handle_write(&mut self, io_addr: u32, value: u16) {
-        if io_addr == REG_SOUNDCNT_X {
-            if value & bit(7) != 0 {
-                if !self.mse {
-                    info!("MSE enabled!");
-                    self.mse = true;
-                }
-            } else {
-                if self.mse {
-                    info!("MSE disabled!");
-                    self.mse = false;
-                }
+    /// Byte writes to the PSG registers only affect that byte, a write to the low byte of
+    /// SOUND1CNT_H must not reload the envelope for instance
+    pub fn handle_write_8(&mut self, io_addr: u32, value: u8) {
+        match io_addr {
+            REG_SOUND1CNT_L..=PSG_END | REG_WAVE_RAM..=WAVE_RAM_END => {
+                self.write_psg(io_addr, value)
             }
+            _ => {
+                let t = self.handle_read(io_addr & !1);
+                let t = if io_addr & 1 != 0 {
+                    (t & 0xff) | (value as u16) << 8
+                } else {
+                    (t & 0xff00) | (value as u16)
+                };
+                self.handle_write(io_addr & !1, t);
+            }
+        }
+    }
 
-            // other fields of this register are read-only anyway, ignore them.
+    fn write_psg(&mut self, io_addr: u32, value: u8) {
+        match io_addr {
+            REG_WAVE_RAM..=WAVE_RAM_END => self
+                .psg
+                .write_wave_ram((io_addr - REG_WAVE_RAM) as usize, value),
+            // the PSG registers are read-only while the sound circuits are powered off
+            _ if !self.mse => {}
+            _ => self.psg.write_8(io_addr - REG_SOUND1CNT_L, value),
+        }
+    }
+
+    fn set_master_enable(&mut self, enable: bool) {
+        if enable == self.mse {
             return;
         }
+        if enable {
+            info!(target: logging::APU, "MSE enabled!");
+        } else {
+            info!(target: logging::APU, "MSE disabled!");
+            // powering off clears SOUND1CNT_L to SOUNDCNT_L, the wave RAM is kept
+            self.handle_write(REG_SOUNDCNT_L, 0);
+            self.psg.power_off();
+        }
+        self.mse = enable;
+    }
 
-        // TODO - figure out which writes should be disabled when MSE is off
-        // if !self.mse {
-        //     warn!("MSE disabled, refusing to write");
-        //     return;
-        // }
+    pub fn handle_write(&mut self, io_addr: u32, value: u16) {
+        if io_addr == REG_SOUNDCNT_X {
+            // other fields of this register are read-only anyway, ignore them.
+            self.set_master_enable(value & bit(7) != 0);
+            return;
+        }
 
         match io_addr {
+            REG_SOUND1CNT_L..=PSG_END | REG_WAVE_RAM..=WAVE_RAM_END => {
+                self.write_psg(io_addr, value as u8);
+                self.write_psg(io_addr + 1, (value >> 8) as u8);
+            }
+
+            REG_SOUNDCNT_L if !self.mse => {}
+
             REG_SOUNDCNT_L => {
-                self.left_volume = value.bit_range(0..2) as usize;
-                self.right_volume = value.bit_range(4..6) as usize;
-                self.left_sqr1 = value.bit(8);
-                self.left_sqr2 = value.bit(9);
-                self.left_wave = value.bit(10);
-                self.left_noise = value.bit(11);
-                self.right_sqr1 = value.bit(12);
-                self.right_sqr2 = value.bit(13);
-                self.right_wave = value.bit(14);
-                self.right_noise = value.bit(15);
+                self.psg_master_volume = [
+                    value.bit_range(0..3) as usize,
+                    value.bit_range(4..7) as usize,
+                ];
+                for (side, enables) in self.psg_enables.iter_mut().enumerate() {
+                    for (psg_channel, enabled) in enables.iter_mut().enumerate() {
+                        *enabled = value.bit(8 + 4 * side + psg_channel);
+                    }
+                }
             }
 
             REG_SOUNDCNT_H => {
-                self.dmg_volume_ratio = DMG_RATIOS[value.bit_range(0..1) as usize];
+                self.psg_volume = value.bit_range(0..2) as usize;
                 self.dma_sound[0].volume_shift = value.bit(2) as i16;
                 self.dma_sound[1].volume_shift = value.bit(3) as i16;
                 self.dma_sound[0].enable_right = value.bit(8);
@@ -243,30 +373,14 @@ impl SoundController {
                 }
             }
 
-            REG_SOUND1CNT_H => {
-                self.sqr1_length = (64 - value.bit_range(0..5) as usize) as f32 / 256.0;
-                self.sqr1_duty = DUTY_RATIOS[value.bit_range(6..7) as usize];
-                self.sqr1_step_time = value.bit_range(8..10) as usize;
-                self.sqr1_step_increase = value.bit(11);
-                self.sqr1_initial_vol = value.bit_range(12..15) as usize;
-            }
-
-            REG_SOUND1CNT_X => {
-                self.sqr1_rate = value.bit_range(0..10) as usize;
-                self.sqr1_timed = value.bit(14);
-                if value.bit(15) {
-                    self.sqr1_cur_vol = self.sqr1_initial_vol;
-                }
-            }
-
             REG_FIFO_A_L | REG_FIFO_A_H => {
-                self.dma_sound[0].fifo.write((value & 0xff) as i8);
-                self.dma_sound[0].fifo.write(((value >> 8) & 0xff) as i8);
+                self.write_fifo(0, (value & 0xff) as i8);
+                self.write_fifo(0, ((value >> 8) & 0xff) as i8);
             }
 
             REG_FIFO_B_L | REG_FIFO_B_H => {
-                self.dma_sound[1].fifo.write((value & 0xff) as i8);
-                self.dma_sound[1].fifo.write(((value >> 8) & 0xff) as i8);
+                self.write_fifo(1, (value & 0xff) as i8);
+                self.write_fifo(1, ((value >> 8) & 0xff) as i8);
             }
 
             REG_SOUNDBIAS => {
@@ -291,7 +405,9 @@ impl SoundController {
 
     pub fn write_fifo(&mut self, id: usize, val: i8) {
         assert!(id == 0 || id == 1);
-        self.dma_sound[id].fifo.write(val);
+        if !self.dma_sound[id].fifo.write(val) {
+            self.on_fifo_event(FifoEvent::Overrun(id));
+        }
     }
 
     pub fn handle_timer_overflow(
@@ -309,70 +425,103 @@ impl SoundController {
             let dma = &mut self.dma_sound[fifo];
 
             if timer_id == dma.timer_select {
-                dma.value = dma.fifo.read();
+                let underrun = dma.fifo.is_empty() && (dma.enable_left || dma.enable_right);
+                let value = dma.fifo.read();
+                dma.push_sample(value);
                 if dma.fifo.count() <= 16 {
                     dmac.notify_sound_fifo(FIFO_INDEX_TO_REG[fifo]);
                 }
+                if underrun {
+                    self.on_fifo_event(FifoEvent::Underrun(fifo));
+                }
             }
         }
     }
 
+    /// One side of the mixer, in the 10 bits signed range the hardware mixes in before the bias
+    /// is added. It goes past this range in loud sections and `dac_output` clips it.
+    fn mix(&self, channel: usize) -> i32 {
+        let mut sample = 0;
+        for dma in &self.dma_sound {
+            if dma.is_stereo_channel_enabled(channel) {
+                let value = dma.sample(self.interpolation).round() as i32;
+                // 50% or 100%
+                sample += value * (2 << dma.volume_shift);
+            }
+        }
+
+        let psg_sample: i32 = self
+            .psg
+            .output()
+            .iter()
+            .zip(self.psg_enables[channel].iter())
+            .filter(|(_, enabled)| **enabled)
+            .map(|(output, _)| *output as i32)
+            .sum();
+        let volume = 1 + self.psg_master_volume[channel] as i32;
+        sample + ((psg_sample * volume * PSG_VOLUMES[self.psg_volume]) >> 2)
+    }
+
     pub fn update(
         &mut self,
         cycles: usize,
         cycles_to_next_event: &mut usize,
-        audio_device: &AudioDeviceRcRefCell,
+        audio_device: &SharedAudioDevice,
     ) {
-        self.cycles += cycles;
-        while self.cycles >= self.cycles_per_sample {
-            self.cycles -= self.cycles_per_sample;
+        let mut cycles = cycles;
+        while self.cycles + cycles >= self.cycles_per_sample {
+            // the PSG channels are caught up to the time of the sample
+            let elapsed = self.cycles_per_sample - self.cycles;
+            if self.mse {
+                self.psg.run(elapsed);
+            }
+            cycles -= elapsed;
+            self.cycles = 0;
+            for dma in &mut self.dma_sound {
+                dma.elapsed += elapsed;
+            }
 
             // time to push a new sample!
 
-            let mut sample = [0f32; 2];
-
-            for channel in 0..=1 {
-                let mut dma_sample = 0;
-                for dma in &mut self.dma_sound {
-                    if dma.is_stereo_channel_enabled(channel) {
-                        let value = dma.value as i16;
-                        dma_sample += value * (2 << dma.volume_shift);
-                    }
-                }
+            let stereo_sample = (
+                dac_output(self.mix(0), self.sound_bias) as f32,
+                dac_output(self.mix(1), self.sound_bias) as f32,
+            );
+            self.resampler.feed(stereo_sample, &mut self.output_buffer);
 
-                apply_bias(&mut dma_sample, self.sound_bias.bit_range(0..10) as i16);
-                sample[channel] = dma_sample as i32 as f32;
+            if self.mute_output {
+                self.output_buffer.clear();
+                continue;
             }
 
-            let stereo_sample = (sample[0], sample[1]);
-            self.resampler.feed(stereo_sample, &mut self.output_buffer);
-
-            let mut audio = audio_device.borrow_mut();
+            let mut audio = lock(audio_device);
             self.output_buffer.drain(..).for_each(|(left, right)| {
-                audio.push_sample((
-                    (left.round() as i16) * (std::i16::MAX / 512),
-                    (right.round() as i16) * (std::i16::MAX / 512),
-                ));
+                // float to int casts saturate
+                audio.push_sample((left.round() as i16, right.round() as i16));
             });
         }
+        if self.mse {
+            self.psg.run(cycles);
+        }
+        for dma in &mut self.dma_sound {
+            dma.elapsed += cycles;
+        }
+        self.cycles += cycles;
         if self.cycles_per_sample < *cycles_to_next_event {
             *cycles_to_next_event = self.cycles_per_sample;
         }
     }
 }
 
-#[inline(always)]
-fn apply_bias(sample: &mut i16, level: i16) {
-    let mut s = *sample;
-    s += level;
-    // clamp
-    if s > 0x3ff {
-        s = 0x3ff;
-    } else if s < 0 {
-        s = 0;
-    }
-    s -= level;
-    *sample = s;
+/// The DAC: the bias is added to the mixed sample, which is clipped to 10 bits, and the low bits
+/// are dropped according to the resolution. The bias is then taken out again, the way the
+/// capacitor on the output does, and the result is scaled to 16 bits.
+fn dac_output(sample: i32, sound_bias: u16) -> i16 {
+    let level = (sound_bias & 0x3fe) as i32;
+    let dropped_bits = 1 + sound_bias.bit_range(14..16) as u32;
+    let clipped = (sample + level).clamp(0, 0x3ff);
+    let output = ((clipped >> dropped_bits) << dropped_bits) - level;
+    cmp::min(cmp::max(output << 6, i16::MIN as i32), i16::MAX as i32) as i16
 }
 
 // TODO move
@@ -388,3 +537,119 @@ fn cbit(idx: u8, value: bool) -> u16 {
 fn bit(idx: u8) -> u16 {
     1 << idx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_master_enable_gates_psg() {
+        let mut sound = SoundController::new(32768.0);
+        sound.handle_write(REG_SOUND1CNT_H, 0xf080);
+        assert_eq!(sound.handle_read(REG_SOUND1CNT_H), 0);
+
+        sound.handle_write(REG_SOUNDCNT_X, 0x80);
+        sound.handle_write(REG_SOUNDCNT_L, 0xff77);
+        sound.handle_write(REG_SOUND1CNT_H, 0xf080);
+        // trigger with the length counter enabled
+        sound.handle_write_8(REG_SOUND1CNT_X + 1, 0xc0);
+        assert_eq!(sound.handle_read(REG_SOUND1CNT_H), 0xf080);
+        assert_eq!(sound.handle_read(REG_SOUND1CNT_X), 0x4000);
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_X), 0x81);
+        // a byte write to the length leaves the envelope alone
+        sound.handle_write_8(REG_SOUND1CNT_H, 0x3f);
+        assert_eq!(sound.handle_read(REG_SOUND1CNT_H), 0xf000);
+
+        sound.handle_write(REG_WAVE_RAM, 0x1234);
+        sound.handle_write(REG_SOUNDCNT_X, 0);
+        assert_eq!(sound.handle_read(REG_SOUND1CNT_H), 0);
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_L), 0);
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_X), 0);
+        assert_eq!(sound.handle_read(REG_WAVE_RAM), 0x1234);
+    }
+
+    #[test]
+    fn test_fifo_underrun_and_overrun() {
+        let mut dmac = DmaController::new(Default::default());
+        let mut sound = SoundController::new(32768.0);
+        sound.record_fifo_events(true);
+        sound.handle_write(REG_SOUNDCNT_X, 0x80);
+        // FIFO A to the right channel, driven by timer 0
+        sound.handle_write(REG_SOUNDCNT_H, 1 << 8);
+
+        for i in 0..34 {
+            sound.write_fifo(0, i as i8);
+        }
+        assert_eq!(sound.fifo_stats()[0].overruns, 2);
+
+        for _ in 0..33 {
+            sound.handle_timer_overflow(&mut dmac, 0, 1);
+        }
+        assert_eq!(sound.fifo_stats()[0].underruns, 1);
+        // FIFO B isn't playing, reading it empty isn't a glitch
+        assert_eq!(sound.fifo_stats()[1], FifoStats::default());
+        assert_eq!(
+            sound.take_fifo_events(),
+            vec![
+                FifoEvent::Overrun(0),
+                FifoEvent::Overrun(0),
+                FifoEvent::Underrun(0)
+            ]
+        );
+
+        sound.reset_fifo_stats();
+        assert_eq!(sound.fifo_stats()[0], FifoStats::default());
+    }
+
+    #[test]
+    fn test_psg_stereo_routing_and_volume() {
+        let mut sound = SoundController::new(32768.0);
+        sound.handle_write(REG_SOUNDCNT_X, 0x80);
+        // square 1 on the left at master volume 7, wave on the right
+        sound.handle_write(REG_SOUNDCNT_L, 0x41ff);
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_L), 0x4177);
+        sound.handle_write(REG_SOUNDCNT_H, 2);
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_H), 2);
+
+        sound.handle_write(REG_SOUND1CNT_H, 0xf080);
+        sound.handle_write_8(REG_SOUND1CNT_X + 1, 0x80);
+        assert_eq!(sound.mix(0).abs(), 15 * 8);
+        assert_eq!(sound.mix(1), 0);
+
+        // 25%
+        sound.handle_write(REG_SOUNDCNT_H, 0);
+        assert_eq!(sound.mix(0).abs(), 15 * 8 / 4);
+        sound.handle_write(REG_SOUNDCNT_L, 0x0107);
+        assert_eq!(sound.mix(0).abs(), 15 * 8 / 4);
+        sound.handle_write(REG_SOUNDCNT_L, 0x0100);
+        assert_eq!(sound.mix(0).abs(), 15 / 4);
+    }
+
+    #[test]
+    fn test_dac_clips_loud_samples() {
+        assert_eq!(dac_output(0, 0x200), 0);
+        // 9 bits resolution
+        assert_eq!(dac_output(0x1ff, 0x200), 0x1fe << 6);
+        // clipped instead of wrapping around
+        assert_eq!(dac_output(0x300, 0x200), 0x1fe << 6);
+        assert_eq!(dac_output(-0x300, 0x200), core::i16::MIN);
+        // 8 bits resolution
+        assert_eq!(dac_output(7, 0x4200), 4 << 6);
+    }
+
+    #[test]
+    fn test_mix_both_fifos_at_full_volume() {
+        let mut dmac = DmaController::new(Default::default());
+        let mut sound = SoundController::new(32768.0);
+        sound.handle_write(REG_SOUNDCNT_X, 0x80);
+        // both FIFOs at 100% to the left, driven by timer 0
+        sound.handle_write(REG_SOUNDCNT_H, 0x220c);
+        sound.write_fifo(0, 127);
+        sound.write_fifo(1, 127);
+        sound.handle_timer_overflow(&mut dmac, 0, 1);
+
+        assert_eq!(sound.mix(0), 127 * 4 * 2);
+        assert_eq!(sound.mix(1), 0);
+        assert_eq!(dac_output(sound.mix(0), sound.sound_bias), 0x1fe << 6);
+    }
+}
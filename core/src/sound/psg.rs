@@ -0,0 +1,777 @@
+//! The four "PSG" channels inherited from the Game Boy: two square waves (the first with a
+//! frequency sweep), a 4-bit wave table and a noise generator.
+//!
+//! Registers are handled byte by byte with their NRxy names, the GBA maps them at
+//! `REG_SOUND1CNT_L..=REG_SOUND4CNT_H`, see `Psg::write_8`.
+use serde::{Deserialize, Serialize};
+
+/// The frame sequencer runs at 512Hz
+const FRAME_SEQUENCER_PERIOD: usize = 32768;
+
+const SQUARE_LENGTH: u16 = 64;
+const WAVE_LENGTH: u16 = 256;
+const NOISE_LENGTH: u16 = 64;
+
+const DUTY_PATTERNS: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true],
+    [true, false, false, false, false, false, false, true],
+    [true, false, false, false, false, true, true, true],
+    [false, true, true, true, true, true, true, false],
+];
+
+/// Noise timer periods in cycles, for each divisor code of SOUND4CNT_H
+const NOISE_DIVISORS: [usize; 8] = [32, 64, 128, 192, 256, 320, 384, 448];
+
+/// Readable bits of each register byte, unused and write-only bits read as zero
+const READ_MASKS: [u8; 0x20] = [
+    0x7f, 0x00, 0xc0, 0xff, 0x00, 0x40, 0x00, 0x00, // SOUND1
+    0xc0, 0xff, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00, // SOUND2
+    0xe0, 0x00, 0x00, 0xe0, 0x00, 0x40, 0x00, 0x00, // SOUND3
+    0x00, 0xff, 0x00, 0x00, 0xff, 0x40, 0x00, 0x00, // SOUND4
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+
+impl LengthCounter {
+    /// Loads the counter from the length field of NRx1
+    fn load(&mut self, max: u16, length: u16) {
+        self.counter = max - length;
+    }
+
+    /// Returns true when the counter expires and the channel must be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    /// Applies a write to NRx4. When the next frame sequencer step doesn't clock the length
+    /// counters, enabling the counter clocks it once more, and a trigger reloading an expired
+    /// counter loads it with `max - 1`. Returns true when the channel must be disabled.
+    fn write_control(&mut self, value: u8, max: u16, next_step_clocks_length: bool) -> bool {
+        let was_enabled = self.enabled;
+        let trigger = value & 0x80 != 0;
+        self.enabled = value & 0x40 != 0;
+
+        let mut disable = false;
+        if !next_step_clocks_length && !was_enabled && self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+        if trigger && self.counter == 0 {
+            self.counter = max;
+            if self.enabled && !next_step_clocks_length {
+                self.counter -= 1;
+            }
+        }
+        disable
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    volume: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC of the channel is powered off when both the volume and direction bits are clear
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial_volume;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+/// Frequency sweep of the first square channel
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    /// A frequency was calculated in negate mode since the last trigger
+    negate_used: bool,
+}
+
+impl Sweep {
+    /// Returns true when the channel must be disabled: clearing the negate bit after a
+    /// frequency was calculated in negate mode does that
+    fn write(&mut self, value: u8) -> bool {
+        let was_negate = self.negate;
+        self.shift = value & 0x07;
+        self.negate = value & 0x08 != 0;
+        self.period = (value >> 4) & 0x07;
+        was_negate && !self.negate && self.negate_used
+    }
+
+    /// A period of 0 reloads the timer with 8
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Returns true when the channel must be disabled, the overflow check runs right away when
+    /// the shift is not 0
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negate_used = false;
+        self.shift != 0 && self.calculate() > 2047
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+
+    /// Updates the frequency of the channel, returns true when it overflowed and the channel
+    /// must be disabled
+    fn clock(&mut self, frequency: &mut u16) -> bool {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return false;
+        }
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return false;
+        }
+        let new_frequency = self.calculate();
+        if new_frequency > 2047 {
+            return true;
+        }
+        if self.shift == 0 {
+            return false;
+        }
+        self.shadow_frequency = new_frequency;
+        *frequency = new_frequency;
+        // the new frequency isn't used, but it is checked for overflow as well
+        self.calculate() > 2047
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SquareChannel {
+    enabled: bool,
+    duty: u8,
+    length: LengthCounter,
+    envelope: Envelope,
+    frequency: u16,
+    timer: usize,
+    duty_position: u8,
+}
+
+impl SquareChannel {
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = 0;
+        self.envelope.trigger();
+    }
+
+    fn run(&mut self, cycles: usize) {
+        if !self.enabled {
+            return;
+        }
+        let period = (2048 - self.frequency as usize) * 16;
+        self.timer += cycles;
+        while self.timer >= period {
+            self.timer -= period;
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        if DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    /// Plays both banks as a single 64 samples pattern
+    two_banks: bool,
+    /// The bank played first, the CPU accesses the other one
+    bank: usize,
+    length: LengthCounter,
+    volume_code: u8,
+    force_volume: bool,
+    frequency: u16,
+    timer: usize,
+    /// Index of the playing sample, in 4-bit units from the start of the played bank
+    position: usize,
+    ram: [[u8; 16]; 2],
+}
+
+impl WaveChannel {
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = 0;
+        self.position = 0;
+    }
+
+    fn run(&mut self, cycles: usize) {
+        if !self.enabled {
+            return;
+        }
+        let period = (2048 - self.frequency as usize) * 8;
+        let samples = if self.two_banks { 64 } else { 32 };
+        self.timer += cycles;
+        while self.timer >= period {
+            self.timer -= period;
+            self.position = (self.position + 1) % samples;
+        }
+    }
+
+    /// Bank and byte index of the playing sample
+    fn playing_byte(&self) -> (usize, usize) {
+        (
+            (self.bank + self.position / 32) % 2,
+            (self.position % 32) / 2,
+        )
+    }
+
+    /// Where a CPU access to the wave RAM byte at `index` goes. The CPU normally sees the bank
+    /// that isn't selected for playback, so a bank can be refilled while the other one plays.
+    /// When both banks are played the CPU has no bank of its own, and while the channel runs
+    /// its accesses hit the byte being played, like on the CGB the GBA inherits the APU from.
+    fn cpu_byte(&self, index: usize) -> (usize, usize) {
+        if self.enabled && self.two_banks {
+            self.playing_byte()
+        } else {
+            (self.bank ^ 1, index)
+        }
+    }
+
+    fn sample(&self) -> u8 {
+        let (bank, index) = self.playing_byte();
+        let byte = self.ram[bank][index];
+        if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0xf
+        }
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let sample = self.sample() as i16 * 2 - 15;
+        if self.force_volume {
+            return sample * 3 / 4;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => sample,
+            2 => sample / 2,
+            _ => sample / 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    divisor_code: usize,
+    /// Shifts of 14 and 15 stop the channel from clocking
+    shift: usize,
+    /// The feedback also goes to bit 6, which makes the lower 7 bits a 7 stage LFSR
+    width_7bit: bool,
+    lfsr: u16,
+    timer: usize,
+}
+
+impl NoiseChannel {
+    fn write(&mut self, value: u8) {
+        self.divisor_code = (value & 0x07) as usize;
+        self.width_7bit = value & 0x08 != 0;
+        self.shift = (value >> 4) as usize;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.lfsr = 0x7fff;
+        self.timer = 0;
+        self.envelope.trigger();
+    }
+
+    fn run(&mut self, cycles: usize) {
+        if !self.enabled || self.shift >= 14 {
+            return;
+        }
+        let period = NOISE_DIVISORS[self.divisor_code] << self.shift;
+        self.timer += cycles;
+        while self.timer >= period {
+            self.timer -= period;
+            self.clock_lfsr();
+        }
+    }
+
+    /// Periods are 32767 steps, or 127 steps in the 7 stage mode. The mode can be switched while
+    /// the channel plays, the upper bits keep shifting but don't affect the output.
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.width_7bit {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        if self.lfsr & 1 == 0 {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Psg {
+    square1: SquareChannel,
+    sweep: Sweep,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    /// The register bytes as last written, for reading back
+    regs: [u8; 0x20],
+    frame_sequencer_cycles: usize,
+    /// The next step of the frame sequencer
+    frame_sequencer_step: u8,
+}
+
+impl Psg {
+    pub fn new() -> Psg {
+        Default::default()
+    }
+
+    /// Powers the channels off, as done when the master enable of SOUNDCNT_X is cleared.
+    /// Everything but the wave RAM is cleared.
+    pub fn power_off(&mut self) {
+        let ram = self.wave.ram;
+        *self = Default::default();
+        self.wave.ram = ram;
+    }
+
+    /// Bits 0-3 of SOUNDCNT_X
+    pub fn status(&self) -> u16 {
+        (self.square1.enabled as u16)
+            | (self.square2.enabled as u16) << 1
+            | (self.wave.enabled as u16) << 2
+            | (self.noise.enabled as u16) << 3
+    }
+
+    /// Reads the register byte at `offset` from `REG_SOUND1CNT_L`
+    pub fn read_8(&self, offset: u32) -> u8 {
+        self.regs[offset as usize] & READ_MASKS[offset as usize]
+    }
+
+    /// Writes the register byte at `offset` from `REG_SOUND1CNT_L`
+    pub fn write_8(&mut self, offset: u32, value: u8) {
+        self.regs[offset as usize] = value;
+        let next_step_clocks_length = self.frame_sequencer_step.is_multiple_of(2);
+        match offset {
+            // NR10, the guard does the write, which may disable the channel
+            0x00 if self.sweep.write(value) => self.square1.enabled = false,
+            // NR11, NR21
+            0x02 | 0x08 => {
+                let channel = self.square_mut(offset);
+                channel.duty = value >> 6;
+                channel.length.load(SQUARE_LENGTH, (value & 0x3f) as u16);
+            }
+            // NR12, NR22
+            0x03 | 0x09 => {
+                let channel = self.square_mut(offset);
+                channel.envelope.write(value);
+                if !channel.envelope.dac_enabled() {
+                    channel.enabled = false;
+                }
+            }
+            // NR13, NR23
+            0x04 | 0x0c => {
+                let channel = self.square_mut(offset);
+                channel.frequency = (channel.frequency & 0x700) | value as u16;
+            }
+            // NR14, NR24
+            0x05 | 0x0d => {
+                let channel = self.square_mut(offset);
+                channel.frequency = (channel.frequency & 0xff) | ((value as u16 & 7) << 8);
+                if channel
+                    .length
+                    .write_control(value, SQUARE_LENGTH, next_step_clocks_length)
+                {
+                    channel.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    channel.trigger();
+                    if offset == 0x05 && self.sweep.trigger(self.square1.frequency) {
+                        self.square1.enabled = false;
+                    }
+                }
+            }
+            // NR30
+            0x10 => {
+                self.wave.two_banks = value & 0x20 != 0;
+                self.wave.bank = ((value >> 6) & 1) as usize;
+                self.wave.dac_enabled = value & 0x80 != 0;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            }
+            // NR31
+            0x12 => self.wave.length.load(WAVE_LENGTH, value as u16),
+            // NR32
+            0x13 => {
+                self.wave.volume_code = (value >> 5) & 3;
+                self.wave.force_volume = value & 0x80 != 0;
+            }
+            // NR33
+            0x14 => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            // NR34
+            0x15 => {
+                self.wave.frequency = (self.wave.frequency & 0xff) | ((value as u16 & 7) << 8);
+                if self
+                    .wave
+                    .length
+                    .write_control(value, WAVE_LENGTH, next_step_clocks_length)
+                {
+                    self.wave.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.wave.trigger();
+                }
+            }
+            // NR41
+            0x18 => self.noise.length.load(NOISE_LENGTH, (value & 0x3f) as u16),
+            // NR42
+            0x19 => {
+                self.noise.envelope.write(value);
+                if !self.noise.envelope.dac_enabled() {
+                    self.noise.enabled = false;
+                }
+            }
+            // NR43
+            0x1c => self.noise.write(value),
+            // NR44
+            0x1d => {
+                if self
+                    .noise
+                    .length
+                    .write_control(value, NOISE_LENGTH, next_step_clocks_length)
+                {
+                    self.noise.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.noise.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn square_mut(&mut self, offset: u32) -> &mut SquareChannel {
+        if offset < 0x08 {
+            &mut self.square1
+        } else {
+            &mut self.square2
+        }
+    }
+
+    /// Reads the wave RAM byte at `index` as the CPU sees it
+    pub fn read_wave_ram(&self, index: usize) -> u8 {
+        let (bank, index) = self.wave.cpu_byte(index);
+        self.wave.ram[bank][index]
+    }
+
+    pub fn write_wave_ram(&mut self, index: usize, value: u8) {
+        let (bank, index) = self.wave.cpu_byte(index);
+        self.wave.ram[bank][index] = value;
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            if self.square1.length.clock() {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.clock() {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if (step == 2 || step == 6) && self.sweep.clock(&mut self.square1.frequency) {
+            self.square1.enabled = false;
+        }
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    pub fn run(&mut self, cycles: usize) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            let chunk =
+                core::cmp::min(cycles, FRAME_SEQUENCER_PERIOD - self.frame_sequencer_cycles);
+            self.square1.run(chunk);
+            self.square2.run(chunk);
+            self.wave.run(chunk);
+            self.noise.run(chunk);
+            self.frame_sequencer_cycles += chunk;
+            if self.frame_sequencer_cycles == FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_cycles = 0;
+                self.clock_frame_sequencer();
+            }
+            cycles -= chunk;
+        }
+    }
+
+    /// Current output of each channel, between -15 and 15
+    pub fn output(&self) -> [i16; 4] {
+        [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the frame sequencer until its next step is `step`
+    fn run_to_step(psg: &mut Psg, step: u8) {
+        while psg.frame_sequencer_step != step {
+            psg.run(FRAME_SEQUENCER_PERIOD);
+        }
+    }
+
+    #[test]
+    fn test_length_counter_expires() {
+        let mut psg = Psg::new();
+        run_to_step(&mut psg, 0);
+        psg.write_8(0x03, 0xf0);
+        // length 62, 2 clocks left
+        psg.write_8(0x02, 62);
+        psg.write_8(0x05, 0xc0);
+        assert_eq!(psg.status(), 1);
+        psg.run(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.status(), 1);
+        // step 1 doesn't clock the length
+        psg.run(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.status(), 1);
+        psg.run(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.status(), 0);
+    }
+
+    #[test]
+    fn test_length_enable_extra_clock() {
+        let mut psg = Psg::new();
+        run_to_step(&mut psg, 1);
+        psg.write_8(0x03, 0xf0);
+        psg.write_8(0x02, 63);
+        psg.write_8(0x05, 0x80);
+        assert_eq!(psg.status(), 1);
+        // enabling the length counter when the next step doesn't clock it clocks it right away
+        psg.write_8(0x05, 0x40);
+        assert_eq!(psg.status(), 0);
+
+        // triggering with an expired counter loads it with 63
+        psg.write_8(0x05, 0xc0);
+        assert_eq!(psg.square1.length.counter, 63);
+    }
+
+    /// Triggers the first square channel with the sweep register set to `sweep`
+    fn trigger_sweep(psg: &mut Psg, sweep: u8, frequency: u16) {
+        psg.write_8(0x00, sweep);
+        psg.write_8(0x03, 0xf0);
+        psg.write_8(0x04, frequency as u8);
+        psg.write_8(0x05, 0x80 | (frequency >> 8) as u8);
+    }
+
+    #[test]
+    fn test_sweep_overflow_on_trigger() {
+        let mut psg = Psg::new();
+        // 0x700 + (0x700 >> 1) overflows
+        trigger_sweep(&mut psg, 0x71, 0x700);
+        assert_eq!(psg.status(), 0);
+        // no calculation with a shift of 0
+        trigger_sweep(&mut psg, 0x70, 0x700);
+        assert_eq!(psg.status(), 1);
+    }
+
+    #[test]
+    fn test_sweep_negate_then_disable() {
+        let mut psg = Psg::new();
+        // leaving negate mode before any calculation is harmless
+        trigger_sweep(&mut psg, 0x18, 0x400);
+        psg.write_8(0x00, 0x10);
+        assert_eq!(psg.status(), 1);
+
+        trigger_sweep(&mut psg, 0x19, 0x400);
+        run_to_step(&mut psg, 3);
+        assert_eq!(psg.square1.frequency, 0x200);
+        psg.write_8(0x00, 0x11);
+        assert_eq!(psg.status(), 0);
+    }
+
+    #[test]
+    fn test_sweep_period_zero() {
+        let mut psg = Psg::new();
+        run_to_step(&mut psg, 0);
+        trigger_sweep(&mut psg, 0x01, 0x100);
+        assert_eq!(psg.sweep.timer, 8);
+        // the timer runs, but a period of 0 never updates the frequency
+        for _ in 0..64 {
+            psg.run(FRAME_SEQUENCER_PERIOD);
+        }
+        assert_eq!(psg.square1.frequency, 0x100);
+        assert_eq!(psg.status(), 1);
+    }
+
+    #[test]
+    fn test_dac_off_disables_channel() {
+        let mut psg = Psg::new();
+        psg.write_8(0x10, 0x80);
+        psg.write_8(0x15, 0x80);
+        assert_eq!(psg.status(), 4);
+        psg.write_8(0x10, 0x00);
+        assert_eq!(psg.status(), 0);
+        // the trigger bit reads back as zero
+        assert_eq!(psg.read_8(0x15), 0);
+    }
+
+    #[test]
+    fn test_wave_ram_access_while_playing() {
+        let mut psg = Psg::new();
+        // bank 0 plays, the CPU refills bank 1
+        psg.write_8(0x10, 0x80);
+        psg.write_8(0x15, 0x87);
+        psg.write_wave_ram(0, 0x12);
+        assert_eq!(psg.wave.ram[1][0], 0x12);
+        assert_eq!(psg.wave.ram[0][0], 0x00);
+
+        // both banks play, accesses hit the playing byte
+        psg.write_8(0x10, 0xa0);
+        psg.wave.position = 36;
+        psg.write_wave_ram(0, 0x34);
+        assert_eq!(psg.wave.ram[1][2], 0x34);
+        assert_eq!(psg.read_wave_ram(15), 0x34);
+
+        // stopped, back to the bank that isn't selected
+        psg.write_8(0x10, 0x20);
+        assert_eq!(psg.read_wave_ram(0), 0x12);
+    }
+
+    #[test]
+    fn test_noise_lfsr_periods() {
+        fn period(noise: &mut NoiseChannel, mask: u16) -> usize {
+            let start = noise.lfsr & mask;
+            let mut steps = 0;
+            loop {
+                noise.clock_lfsr();
+                steps += 1;
+                if noise.lfsr & mask == start {
+                    return steps;
+                }
+            }
+        }
+
+        let mut noise = NoiseChannel::default();
+        noise.trigger();
+        assert_eq!(period(&mut noise, 0x7fff), 32767);
+
+        noise.write(0x08);
+        assert!(noise.width_7bit);
+        assert_eq!(period(&mut noise, 0x7f), 127);
+        // the first zeroes only reach bit 0 after going through the 7 stages
+        noise.lfsr = 0x7f;
+        let pattern: Vec<u16> = (0..8)
+            .map(|_| {
+                noise.clock_lfsr();
+                noise.lfsr & 1
+            })
+            .collect();
+        assert_eq!(pattern, vec![1, 1, 1, 1, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_power_off_keeps_wave_ram() {
+        let mut psg = Psg::new();
+        psg.write_wave_ram(3, 0x5a);
+        psg.write_8(0x09, 0xf0);
+        psg.write_8(0x0d, 0x80);
+        psg.power_off();
+        assert_eq!(psg.status(), 0);
+        assert_eq!(psg.read_8(0x09), 0);
+        assert_eq!(psg.read_wave_ram(3), 0x5a);
+    }
+}
@@ -0,0 +1,244 @@
+//! What differs between two savestates.
+//!
+//! `StateDiff::new` decodes two savestates and compares the registers of the cpu in every mode,
+//! the scheduler and the IO registers field by field, and the memories by pages of `PAGE_SIZE`
+//! bytes, with a checksum of each page. The subsystems whose hash differs, see
+//! `GameBoyAdvance::subsystem_hashes`, are listed too: they also cover the state of the devices
+//! the registers don't show. Diffing the states of two runs that should be the same tells which
+//! part of the machine a desync started in.
+//!
+//! The states are decoded apart from the save files, so the backup memory compared is the one
+//! of each state.
+use std::fmt;
+
+use super::arm7tdmi::CpuMode;
+use super::bus::{Addr, Bus};
+use super::determinism::hash_state;
+use super::iodev::consts::IO_BASE;
+use super::iodev::io_reg_string;
+use super::sysbus::consts::{EWRAM_ADDR, IWRAM_ADDR};
+use super::{CoreResult, GameBoyAdvance};
+
+/// Bytes of memory compared at a time
+pub const PAGE_SIZE: usize = 0x400;
+/// Bytes of IO registers
+const IO_SIZE: u32 = 0x400;
+
+/// A register or a counter that differs
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    /// Like `cpu.r4`, `cpu.sp_irq` or `io.REG_DISPCNT`
+    pub name: String,
+    pub old: u64,
+    pub new: u64,
+}
+
+impl fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:#x} -> {:#x}", self.name, self.old, self.new)
+    }
+}
+
+/// A page of memory that differs
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageDiff {
+    /// ewram, iwram, vram, palette, oam or backup
+    pub region: &'static str,
+    /// The offset in the save memory for the backup
+    pub addr: Addr,
+    pub len: usize,
+    pub old_checksum: u64,
+    pub new_checksum: u64,
+    /// Bytes of the page that differ
+    pub changed: usize,
+}
+
+impl fmt::Display for PageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:08x}-{:08x}: {} bytes differ (checksum {:016x} -> {:016x})",
+            self.region,
+            self.addr,
+            self.addr + self.len as u32 - 1,
+            self.changed,
+            self.old_checksum,
+            self.new_checksum
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StateDiff {
+    /// Why the states are of different ROMs, `None` when they are of the same one
+    pub rom: Option<String>,
+    /// The names of `GameBoyAdvance::subsystem_hashes` that differ
+    pub subsystems: Vec<&'static str>,
+    pub fields: Vec<FieldDiff>,
+    pub pages: Vec<PageDiff>,
+}
+
+/// The registers and counters compared, by name
+fn fields(gba: &GameBoyAdvance) -> Vec<(String, u64)> {
+    let cpu = &gba.cpu;
+    let mut fields: Vec<(String, u64)> = (0..16)
+        .map(|r| (format!("cpu.r{}", r), cpu.get_reg(r) as u64))
+        .collect();
+    fields.push(("cpu.cpsr".to_string(), cpu.cpsr.get() as u64));
+    for mode in CpuMode::BANKS.iter() {
+        let name = mode.to_string().to_lowercase();
+        let (sp, lr) = cpu.get_banked_sp_lr(*mode);
+        fields.push((format!("cpu.sp_{}", name), sp as u64));
+        fields.push((format!("cpu.lr_{}", name), lr as u64));
+    }
+    fields.push(("cpu.cycles".to_string(), cpu.cycles as u64));
+    fields.push((
+        "scheduler.cycles_to_next_event".to_string(),
+        gba.cycles_to_next_event as u64,
+    ));
+    for offset in (0..IO_SIZE).step_by(2) {
+        let name = io_reg_string(IO_BASE + offset);
+        if name != "UNKNOWN" {
+            let value = gba.sysbus.io.read_16(offset);
+            fields.push((format!("io.{}", name), value as u64));
+        }
+    }
+    fields
+}
+
+/// The memories compared, with their address
+fn regions(gba: &GameBoyAdvance) -> Vec<(&'static str, Addr, Vec<u8>)> {
+    let gpu = &gba.sysbus.io.gpu;
+    vec![
+        ("ewram", EWRAM_ADDR, gba.sysbus.ewram().to_vec()),
+        ("iwram", IWRAM_ADDR, gba.sysbus.iwram().to_vec()),
+        ("palette", 0x0500_0000, gpu.palette_ram.mem.to_vec()),
+        ("vram", 0x0600_0000, gba.sysbus.vram().to_vec()),
+        ("oam", 0x0700_0000, gpu.oam.mem.to_vec()),
+        (
+            "backup",
+            0,
+            gba.sysbus.cartridge.backup_bytes().unwrap_or_default(),
+        ),
+    ]
+}
+
+impl StateDiff {
+    /// Compares the savestates `old` and `new`
+    pub fn new(old: &[u8], new: &[u8]) -> CoreResult<StateDiff> {
+        let old = GameBoyAdvance::inspect_state(old)?;
+        let new = GameBoyAdvance::inspect_state(new)?;
+        Ok(StateDiff::between(&old, &new))
+    }
+
+    /// Compares two machines
+    pub fn between(old: &GameBoyAdvance, new: &GameBoyAdvance) -> StateDiff {
+        let mut diff = StateDiff {
+            rom: old
+                .sysbus
+                .cartridge
+                .identity()
+                .mismatch(&new.sysbus.cartridge.identity()),
+            ..Default::default()
+        };
+        diff.subsystems = old
+            .subsystem_hashes()
+            .iter()
+            .zip(new.subsystem_hashes().iter())
+            .filter(|(old, new)| old != new)
+            .map(|(old, _)| old.name)
+            .collect();
+        diff.fields = fields(old)
+            .into_iter()
+            .zip(fields(new))
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((name, old), (_, new))| FieldDiff { name, old, new })
+            .collect();
+        for ((region, addr, old), (_, _, new)) in regions(old).into_iter().zip(regions(new)) {
+            if old.len() != new.len() {
+                diff.fields.push(FieldDiff {
+                    name: format!("{}.size", region),
+                    old: old.len() as u64,
+                    new: new.len() as u64,
+                });
+            }
+            for (i, (old, new)) in old.chunks(PAGE_SIZE).zip(new.chunks(PAGE_SIZE)).enumerate() {
+                if old != new {
+                    diff.pages.push(PageDiff {
+                        region,
+                        addr: addr + (i * PAGE_SIZE) as u32,
+                        len: old.len().min(new.len()),
+                        old_checksum: hash_state(old),
+                        new_checksum: hash_state(new),
+                        changed: old.iter().zip(new).filter(|(a, b)| a != b).count(),
+                    });
+                }
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rom.is_none()
+            && self.subsystems.is_empty()
+            && self.fields.is_empty()
+            && self.pages.is_empty()
+    }
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "the states are the same");
+        }
+        if let Some(reason) = &self.rom {
+            writeln!(f, "different ROMs: {}", reason)?;
+        }
+        if !self.subsystems.is_empty() {
+            writeln!(f, "subsystems that differ: {}", self.subsystems.join(", "))?;
+        }
+        for field in &self.fields {
+            writeln!(f, "{}", field)?;
+        }
+        for page in &self.pages {
+            writeln!(f, "{}", page)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gba::test_util::make_mock_gba;
+
+    #[test]
+    fn test_state_diff() {
+        let mut gba = make_mock_gba(&[]);
+        let old = gba.save_state().unwrap();
+        assert!(StateDiff::new(&old, &old).unwrap().is_empty());
+
+        gba.cpu.set_reg(3, 0x1234);
+        gba.sysbus.write_16(0x0400_0000, 0x0403);
+        gba.sysbus.write_8(0x0200_0411, 7);
+        let diff = StateDiff::new(&old, &gba.save_state().unwrap()).unwrap();
+        assert_eq!(diff.rom, None);
+        assert_eq!(diff.subsystems, ["cpu", "gpu", "ewram"]);
+        let fields: Vec<&str> = diff
+            .fields
+            .iter()
+            .map(|field| field.name.as_str())
+            .collect();
+        assert_eq!(fields, ["cpu.r3", "io.REG_DISPCNT"]);
+        assert_eq!(diff.fields[0].new, 0x1234);
+        assert_eq!(diff.pages.len(), 1);
+        assert_eq!(
+            (
+                diff.pages[0].region,
+                diff.pages[0].addr,
+                diff.pages[0].changed
+            ),
+            ("ewram", 0x0200_0400, 1)
+        );
+    }
+}
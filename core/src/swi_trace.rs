@@ -0,0 +1,262 @@
+//! Tracing the BIOS calls, and checking the HLE BIOS against a real one.
+//!
+//! With `GameBoyAdvance::set_swi_trace`, every software interrupt is logged with its decoded
+//! arguments, and its results once it returns:
+//! ```text
+//! swi 0x06 Div(num=0x64, denom=0x7) at 0x08000134
+//! swi 0x06 Div returned r0=0xe r1=0x2 r3=0xe
+//! ```
+//! In `SwiTraceMode::CrossCheck`, with a real BIOS loaded, the functions the HLE implements
+//! are also run through the HLE on a copy of the emulator, and the results of both are
+//! compared once the real BIOS returns. The differences are logged and kept in `mismatches`.
+//! Interrupts handled during the call make the side effects on the interrupt flags legitimately
+//! differ, so only the result registers are compared then.
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::arm7tdmi::{Core, CpuAction, CpuMode};
+use super::bios::{self, SwiInfo, INTR_CHECK_FLAGS_ADDR};
+use super::bus::{Addr, DebugRead};
+use super::logging;
+use super::sysbus::SysBus;
+
+/// Calls that never return (SoftReset) are forgotten past this depth
+const MAX_PENDING: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SwiTraceMode {
+    /// Logs the calls and their results
+    Log,
+    /// Also runs the HLE alongside the real BIOS and compares them
+    CrossCheck,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwiCall {
+    pub number: u32,
+    /// Address of the swi instruction
+    pub addr: Addr,
+    /// r0-r3 when the swi was executed
+    pub args: [u32; 4],
+}
+
+impl SwiCall {
+    pub fn info(&self) -> Option<&'static SwiInfo> {
+        bios::swi_info(self.number)
+    }
+
+    fn name(&self) -> &'static str {
+        self.info().map_or("Unknown", |info| info.name)
+    }
+}
+
+impl fmt::Display for SwiCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "swi {:#04x} {}(", self.number, self.name())?;
+        if let Some(info) = self.info() {
+            let args = info
+                .args
+                .iter()
+                .zip(self.args.iter())
+                .filter(|(name, _)| !name.is_empty())
+                .map(|(name, value)| format!("{}={:#x}", name, value));
+            write!(f, "{}", args.collect::<Vec<_>>().join(", "))?;
+        }
+        write!(f, ") at {:#010x}", self.addr)
+    }
+}
+
+/// A value the HLE and the real BIOS disagree on
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwiMismatch {
+    pub call: SwiCall,
+    /// "r0" to "r3", "BIOS_IF" or "IME"
+    pub what: &'static str,
+    pub hle: u32,
+    pub bios: u32,
+}
+
+impl fmt::Display for SwiMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} is {:#x} with the HLE, {:#x} with the bios",
+            self.call, self.what, self.hle, self.bios
+        )
+    }
+}
+
+/// The state the HLE left a call in
+#[derive(Debug, Clone)]
+pub(crate) struct HleOutcome {
+    regs: [u32; 4],
+    intr_check_flags: u32,
+    ime: bool,
+}
+
+impl HleOutcome {
+    /// Runs the HLE of `number` on copies of `cpu` and `sysbus`, about to execute the swi.
+    /// `None` when it doesn't return right away, as for an IntrWait that has to wait.
+    pub(crate) fn run(cpu: &Core, sysbus: &SysBus, number: u32) -> Option<HleOutcome> {
+        let mut cpu = cpu.clone();
+        let mut sysbus = Box::new(sysbus.clone());
+        sysbus.created();
+        let lr = cpu.get_next_pc() + cpu.word_size() as u32;
+        match bios::hle_swi(&mut cpu, &mut sysbus, lr, number) {
+            CpuAction::AdvancePC => Some(HleOutcome::capture(&cpu, &sysbus)),
+            CpuAction::FlushPipeline => None,
+        }
+    }
+
+    fn capture(cpu: &Core, sysbus: &SysBus) -> HleOutcome {
+        let mut regs = [0; 4];
+        regs.copy_from_slice(&cpu.gpr[..4]);
+        HleOutcome {
+            regs,
+            intr_check_flags: sysbus.debug_read_16(INTR_CHECK_FLAGS_ADDR) as u32,
+            ime: sysbus.io.intc.interrupt_master_enable,
+        }
+    }
+}
+
+struct PendingSwi {
+    call: SwiCall,
+    return_addr: Addr,
+    mode: CpuMode,
+    hle: Option<HleOutcome>,
+    interrupted: bool,
+}
+
+pub struct SwiTracer {
+    mode: SwiTraceMode,
+    pending: Vec<PendingSwi>,
+    mismatches: Vec<SwiMismatch>,
+}
+
+impl SwiTracer {
+    pub fn new(mode: SwiTraceMode) -> SwiTracer {
+        SwiTracer {
+            mode,
+            pending: Vec::new(),
+            mismatches: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> SwiTraceMode {
+        self.mode
+    }
+
+    pub fn mismatches(&self) -> &[SwiMismatch] {
+        &self.mismatches
+    }
+
+    /// Records a swi the cpu just executed, that returns to `return_addr` in `mode`
+    pub(crate) fn call(
+        &mut self,
+        call: SwiCall,
+        return_addr: Addr,
+        mode: CpuMode,
+        hle: Option<HleOutcome>,
+    ) {
+        // the HLE executes the swi again while IntrWait waits
+        if let Some(last) = self.pending.last() {
+            if last.call.addr == call.addr && last.return_addr == return_addr {
+                return;
+            }
+        }
+        info!(target: logging::CPU, "{}", call);
+        if self.pending.len() == MAX_PENDING {
+            self.pending.remove(0);
+        }
+        self.pending.push(PendingSwi {
+            call,
+            return_addr,
+            mode,
+            hle,
+            interrupted: false,
+        });
+    }
+
+    /// An interrupt is being serviced, maybe in the middle of the pending calls
+    pub(crate) fn interrupted(&mut self) {
+        for pending in &mut self.pending {
+            pending.interrupted = true;
+        }
+    }
+
+    /// Completes the innermost call if the cpu is back from it
+    pub(crate) fn check_return(&mut self, cpu: &Core, sysbus: &SysBus) {
+        match self.pending.last() {
+            Some(pending)
+                if cpu.get_next_pc() == pending.return_addr && cpu.cpsr.mode() == pending.mode => {}
+            _ => return,
+        }
+        let pending = self.pending.pop().unwrap();
+        let results = pending.call.info().map_or(&[][..], |info| info.results);
+        if !results.is_empty() {
+            let values: Vec<String> = results
+                .iter()
+                .map(|r| format!("r{}={:#x}", r, cpu.gpr[*r]))
+                .collect();
+            info!(
+                target: logging::CPU,
+                "swi {:#04x} {} returned {}",
+                pending.call.number,
+                pending.call.name(),
+                values.join(" ")
+            );
+        }
+        if let Some(hle) = &pending.hle {
+            let bios = HleOutcome::capture(cpu, sysbus);
+            let mut compared = Vec::new();
+            for r in results {
+                compared.push((["r0", "r1", "r2", "r3"][*r], hle.regs[*r], bios.regs[*r]));
+            }
+            if !pending.interrupted {
+                compared.push(("BIOS_IF", hle.intr_check_flags, bios.intr_check_flags));
+                compared.push(("IME", hle.ime as u32, bios.ime as u32));
+            }
+            for (what, hle, bios) in compared {
+                if hle != bios {
+                    let mismatch = SwiMismatch {
+                        call: pending.call.clone(),
+                        what,
+                        hle,
+                        bios,
+                    };
+                    warn!(target: logging::CPU, "{}", mismatch);
+                    self.mismatches.push(mismatch);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_display() {
+        let call = SwiCall {
+            number: 6,
+            addr: 0x0800_0134,
+            args: [100, 7, 0, 0],
+        };
+        assert_eq!(
+            call.to_string(),
+            "swi 0x06 Div(num=0x64, denom=0x7) at 0x08000134"
+        );
+        let call = SwiCall {
+            number: 0x27,
+            addr: 0x0800_0000,
+            args: [1, 2, 0x80, 0],
+        };
+        assert_eq!(
+            call.to_string(),
+            "swi 0x27 CustomHalt(mode=0x80) at 0x08000000"
+        );
+    }
+}
@@ -1,14 +1,26 @@
-use std::fmt;
+#[cfg(feature = "trace")]
+use core::cell::RefCell;
+use core::fmt;
 
+use bitfield::BitRange;
 use serde::{Deserialize, Serialize};
 
+use super::bios_db::{self, BiosCheck};
 use super::bus::*;
 use super::cartridge::Cartridge;
+#[cfg(feature = "savestates")]
+use super::determinism::SubsystemHash;
 use super::dma::DmaNotifer;
-use super::iodev::{IoDevices, WaitControl};
-use super::util::{BoxedMemory, WeakPointer};
+use super::init::InitOptions;
+use super::io_trace;
+use super::iodev::{is_io_readable, IoDevices, WaitControl};
+#[cfg(feature = "trace")]
+use super::trace::MemAccess;
+use super::util::{BoxedMemory, RomBuffer, WeakPointer};
+use super::{CoreResult, LoadContext, ResetAfterLoad};
 
 pub mod consts {
+    pub const BIOS_SIZE: usize = 16 * 1024;
     pub const WORK_RAM_SIZE: usize = 256 * 1024;
     pub const INTERNAL_RAM_SIZE: usize = 32 * 1024;
 
@@ -114,52 +126,51 @@ impl CycleLookupTables {
         self.s_cycles16[PAGE_PALRAM] = 1;
     }
 
+    #[inline]
+    fn set_page_cycles(&mut self, page: usize, n16: usize, s16: usize, n32: usize, s32: usize) {
+        self.n_cycles16[page] = n16;
+        self.s_cycles16[page] = s16;
+        self.n_cycles32[page] = n32;
+        self.s_cycles32[page] = s32;
+    }
+
     pub fn update_gamepak_waitstates(&mut self, waitcnt: WaitControl) {
         static S_GAMEPAK_NSEQ_CYCLES: [usize; 4] = [4, 3, 2, 8];
         static S_GAMEPAK_WS0_SEQ_CYCLES: [usize; 2] = [2, 1];
         static S_GAMEPAK_WS1_SEQ_CYCLES: [usize; 2] = [4, 1];
         static S_GAMEPAK_WS2_SEQ_CYCLES: [usize; 2] = [8, 1];
 
-        let ws0_first_access = waitcnt.ws0_first_access() as usize;
-        let ws1_first_access = waitcnt.ws1_first_access() as usize;
-        let ws2_first_access = waitcnt.ws2_first_access() as usize;
-        let ws0_second_access = waitcnt.ws0_second_access() as usize;
-        let ws1_second_access = waitcnt.ws1_second_access() as usize;
-        let ws2_second_access = waitcnt.ws2_second_access() as usize;
-
-        // update SRAM access
-        let sram_wait_cycles = 1 + S_GAMEPAK_NSEQ_CYCLES[waitcnt.sram_wait_control() as usize];
-        self.n_cycles32[PAGE_SRAM_LO] = sram_wait_cycles;
-        self.n_cycles32[PAGE_SRAM_LO] = sram_wait_cycles;
-        self.n_cycles16[PAGE_SRAM_HI] = sram_wait_cycles;
-        self.n_cycles16[PAGE_SRAM_HI] = sram_wait_cycles;
-        self.s_cycles32[PAGE_SRAM_LO] = sram_wait_cycles;
-        self.s_cycles32[PAGE_SRAM_LO] = sram_wait_cycles;
-        self.s_cycles16[PAGE_SRAM_HI] = sram_wait_cycles;
-        self.s_cycles16[PAGE_SRAM_HI] = sram_wait_cycles;
-
-        // update both pages of each waitstate
-        for i in 0..2 {
-            self.n_cycles16[PAGE_GAMEPAK_WS0 + i] = 1 + S_GAMEPAK_NSEQ_CYCLES[ws0_first_access];
-            self.s_cycles16[PAGE_GAMEPAK_WS0 + i] = 1 + S_GAMEPAK_WS0_SEQ_CYCLES[ws0_second_access];
-
-            self.n_cycles16[PAGE_GAMEPAK_WS1 + i] = 1 + S_GAMEPAK_NSEQ_CYCLES[ws1_first_access];
-            self.s_cycles16[PAGE_GAMEPAK_WS1 + i] = 1 + S_GAMEPAK_WS1_SEQ_CYCLES[ws1_second_access];
-
-            self.n_cycles16[PAGE_GAMEPAK_WS2 + i] = 1 + S_GAMEPAK_NSEQ_CYCLES[ws2_first_access];
-            self.s_cycles16[PAGE_GAMEPAK_WS2 + i] = 1 + S_GAMEPAK_WS2_SEQ_CYCLES[ws2_second_access];
-
-            // ROM 32bit accesses are split into two 16bit accesses 1N+1S
-            self.n_cycles32[PAGE_GAMEPAK_WS0 + i] =
-                self.n_cycles16[PAGE_GAMEPAK_WS0 + i] + self.s_cycles16[PAGE_GAMEPAK_WS0 + i];
-            self.n_cycles32[PAGE_GAMEPAK_WS1 + i] =
-                self.n_cycles16[PAGE_GAMEPAK_WS1 + i] + self.s_cycles16[PAGE_GAMEPAK_WS1 + i];
-            self.n_cycles32[PAGE_GAMEPAK_WS2 + i] =
-                self.n_cycles16[PAGE_GAMEPAK_WS2 + i] + self.s_cycles16[PAGE_GAMEPAK_WS2 + i];
-
-            self.s_cycles32[PAGE_GAMEPAK_WS0 + i] = 2 * self.s_cycles16[PAGE_GAMEPAK_WS0 + i];
-            self.s_cycles32[PAGE_GAMEPAK_WS1 + i] = 2 * self.s_cycles16[PAGE_GAMEPAK_WS1 + i];
-            self.s_cycles32[PAGE_GAMEPAK_WS2 + i] = 2 * self.s_cycles16[PAGE_GAMEPAK_WS2 + i];
+        // (first page, first access wait, second access wait) of each ROM waitstate region
+        let rom_regions = [
+            (
+                PAGE_GAMEPAK_WS0,
+                S_GAMEPAK_NSEQ_CYCLES[waitcnt.ws0_first_access() as usize],
+                S_GAMEPAK_WS0_SEQ_CYCLES[waitcnt.ws0_second_access() as usize],
+            ),
+            (
+                PAGE_GAMEPAK_WS1,
+                S_GAMEPAK_NSEQ_CYCLES[waitcnt.ws1_first_access() as usize],
+                S_GAMEPAK_WS1_SEQ_CYCLES[waitcnt.ws1_second_access() as usize],
+            ),
+            (
+                PAGE_GAMEPAK_WS2,
+                S_GAMEPAK_NSEQ_CYCLES[waitcnt.ws2_first_access() as usize],
+                S_GAMEPAK_WS2_SEQ_CYCLES[waitcnt.ws2_second_access() as usize],
+            ),
+        ];
+        for (first_page, first_wait, second_wait) in rom_regions.iter() {
+            let n16 = 1 + first_wait;
+            let s16 = 1 + second_wait;
+            // the ROM bus is 16bit, 32bit accesses are split into two 16bit accesses 1N+1S
+            for page in [*first_page, first_page + 1].iter() {
+                self.set_page_cycles(*page, n16, s16, n16 + s16, 2 * s16);
+            }
+        }
+
+        // SRAM has an 8bit bus and is always accessed once, whatever the access width
+        let sram_cycles = 1 + S_GAMEPAK_NSEQ_CYCLES[waitcnt.sram_wait_control() as usize];
+        for page in [PAGE_SRAM_LO, PAGE_SRAM_HI].iter() {
+            self.set_page_cycles(*page, sram_cycles, sram_cycles, sram_cycles, sram_cycles);
         }
     }
 }
@@ -168,46 +179,278 @@ impl CycleLookupTables {
 pub struct SysBus {
     pub io: IoDevices,
 
-    bios: BoxedMemory,
+    bios: RomBuffer,
     onboard_work_ram: BoxedMemory,
     internal_work_ram: BoxedMemory,
     pub cartridge: Cartridge,
 
     cycle_luts: CycleLookupTables,
 
+    /// The BIOS can only be read while the cpu executes from it
+    bios_readable: bool,
+    /// Last opcode fetched from the BIOS, which is what protected BIOS reads return
+    bios_value: u32,
+    /// Software interrupts are emulated instead of running the BIOS code, see `bios`
+    hle_bios: bool,
+    /// Address and size of the last opcode fetch, which is what open bus reads return
+    last_fetch: Addr,
+    last_fetch_thumb: bool,
+
     pub trace_access: bool,
+
+    /// Set by the HLE of the BIOS HardReset function, see `take_hard_reset_request`
+    #[serde(skip)]
+    hard_reset_requested: bool,
+
+    /// The accesses of the instruction being traced, `None` when not tracing, see `trace`
+    #[cfg(feature = "trace")]
+    #[serde(skip)]
+    pub(crate) trace_log: RefCell<Option<Vec<MemAccess>>>,
 }
 
+/// The BIOS opcode latched after the boot sequence jumps to the cartridge
+pub const BIOS_VALUE_AFTER_BOOT: u32 = 0xe129_f000;
+
 pub type SysBusPtr = WeakPointer<SysBus>;
 
+impl ResetAfterLoad for SysBus {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.io.reset_after_load(ctx);
+        self.created();
+    }
+}
+
 impl SysBus {
-    pub fn new(io: IoDevices, bios_rom: Box<[u8]>, cartridge: Cartridge) -> SysBus {
+    /// Fails if `bios_check` refuses the BIOS, see `bios_db`
+    pub fn new(
+        io: IoDevices,
+        bios_rom: RomBuffer,
+        cartridge: Cartridge,
+        bios_check: BiosCheck,
+    ) -> CoreResult<SysBus> {
+        bios_db::identify(&bios_rom).check(bios_check)?;
+
         let mut luts = CycleLookupTables::default();
         luts.init();
         luts.update_gamepak_waitstates(io.waitcnt);
 
-        SysBus {
+        Ok(SysBus {
             io,
-            bios: BoxedMemory::new(bios_rom),
+            bios: bios_rom,
             onboard_work_ram: BoxedMemory::new(vec![0; WORK_RAM_SIZE].into_boxed_slice()),
             internal_work_ram: BoxedMemory::new(vec![0; INTERNAL_RAM_SIZE].into_boxed_slice()),
             cartridge: cartridge,
 
             cycle_luts: luts,
 
+            bios_readable: true,
+            bios_value: 0,
+            hle_bios: false,
+            last_fetch: 0,
+            last_fetch_thumb: false,
+
             trace_access: false,
+            hard_reset_requested: false,
+            #[cfg(feature = "trace")]
+            trace_log: RefCell::new(None),
+        })
+    }
+
+    /// Called by the cpu before fetching an instruction from `pc`
+    #[inline]
+    pub fn on_cpu_fetch(&mut self, pc: Addr, thumb: bool) {
+        self.last_fetch = pc;
+        self.last_fetch_thumb = thumb;
+        self.bios_readable = (pc as usize) < BIOS_SIZE;
+        if self.bios_readable {
+            self.bios_value = self.bios.read_32(pc & !3);
+        }
+    }
+
+    /// Reads an opcode, which isn't traced as an access of the executed instruction
+    #[inline(always)]
+    pub fn fetch_32(&self, addr: Addr) -> u32 {
+        #[cfg(feature = "trace")]
+        let log = self.trace_log.replace(None);
+        let value = self.read_32(addr);
+        #[cfg(feature = "trace")]
+        self.trace_log.replace(log);
+        value
+    }
+
+    /// Same as `fetch_32`, in Thumb state
+    #[inline(always)]
+    pub fn fetch_16(&self, addr: Addr) -> u16 {
+        #[cfg(feature = "trace")]
+        let log = self.trace_log.replace(None);
+        let value = self.read_16(addr);
+        #[cfg(feature = "trace")]
+        self.trace_log.replace(log);
+        value
+    }
+
+    #[cfg(feature = "trace")]
+    #[inline(always)]
+    fn trace_access(&self, write: bool, width: u8, addr: Addr, value: u32) {
+        if let Some(log) = self.trace_log.borrow_mut().as_mut() {
+            log.push(MemAccess {
+                write,
+                width,
+                addr,
+                value,
+            });
+        }
+    }
+
+    /// Puts the bus in the state the BIOS leaves it after booting
+    pub fn skip_bios(&mut self) {
+        self.bios_readable = false;
+        self.bios_value = BIOS_VALUE_AFTER_BOOT;
+    }
+
+    pub fn apply_init_options(&mut self, options: &InitOptions) {
+        options.ewram.fill(&mut self.onboard_work_ram.mem);
+        options.iwram.fill(&mut self.internal_work_ram.mem);
+        options.vram.fill(&mut self.io.gpu.vram.mem);
+    }
+
+    /// The whole EWRAM, for tools that scan memory: reading it through the bus a byte at a time
+    /// is slow
+    pub fn ewram(&self) -> &[u8] {
+        &self.onboard_work_ram.mem
+    }
+
+    pub fn iwram(&self) -> &[u8] {
+        &self.internal_work_ram.mem
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.io.gpu.vram.mem
+    }
+
+    /// The writes go around the bus, the access trace doesn't see them
+    pub fn ewram_mut(&mut self) -> &mut [u8] {
+        &mut self.onboard_work_ram.mem
+    }
+
+    pub fn iwram_mut(&mut self) -> &mut [u8] {
+        &mut self.internal_work_ram.mem
+    }
+
+    /// The gpu doesn't see these writes, the picture is redrawn in case they change it
+    pub fn vram_mut(&mut self) -> &mut [u8] {
+        self.io.gpu.mark_dirty();
+        &mut self.io.gpu.vram.mem
+    }
+
+    /// Powers the bus on again with `io`, keeping the BIOS and the cartridge
+    pub fn hard_reset(&mut self, io: IoDevices) {
+        self.io = io;
+        for byte in self.onboard_work_ram.mem.iter_mut() {
+            *byte = 0;
+        }
+        for byte in self.internal_work_ram.mem.iter_mut() {
+            *byte = 0;
+        }
+        self.cartridge.reset();
+        self.cycle_luts.update_gamepak_waitstates(self.io.waitcnt);
+        self.bios_readable = true;
+        self.bios_value = 0;
+        self.last_fetch = 0;
+        self.last_fetch_thumb = false;
+        self.hard_reset_requested = false;
+    }
+
+    pub(crate) fn request_hard_reset(&mut self) {
+        self.hard_reset_requested = true;
+    }
+
+    /// Whether the game called HardReset since the last call, for the emulator to carry it out
+    pub(crate) fn take_hard_reset_request(&mut self) -> bool {
+        core::mem::replace(&mut self.hard_reset_requested, false)
+    }
+
+    pub fn set_hle_bios(&mut self, hle_bios: bool) {
+        self.hle_bios = hle_bios;
+    }
+
+    #[inline]
+    pub fn is_hle_bios(&self) -> bool {
+        self.hle_bios
+    }
+
+    /// Adds the hashes of the devices, the work RAMs and the backup
+    #[cfg(feature = "savestates")]
+    pub(crate) fn subsystem_hashes(&self, hashes: &mut Vec<SubsystemHash>) {
+        let io = &self.io;
+        hashes.push(SubsystemHash::of("dma", &io.dmac));
+        hashes.push(SubsystemHash::of("timers", &io.timers));
+        hashes.push(SubsystemHash::of(
+            "io",
+            &(io.keyinput, io.post_boot_flag, io.waitcnt, io.haltcnt),
+        ));
+        hashes.push(SubsystemHash::of("gpu", &*io.gpu));
+        hashes.push(SubsystemHash::of("sound", &*io.sound));
+        hashes.push(SubsystemHash::of("ewram", &self.onboard_work_ram));
+        hashes.push(SubsystemHash::of("iwram", &self.internal_work_ram));
+        hashes.push(SubsystemHash::of("backup", &self.cartridge.backup));
+        hashes.push(SubsystemHash::of("gpio", self.cartridge.get_gpio()));
+        hashes.push(SubsystemHash::of(
+            "bus",
+            &(
+                self.bios_readable,
+                self.bios_value,
+                self.last_fetch,
+                self.last_fetch_thumb,
+            ),
+        ));
+    }
+
+    /// Value of reads from write-only and unused addresses: the last opcode prefetched by the
+    /// cpu, in thumb state the fetched halfword appears on both halves of the bus
+    fn open_bus(&self) -> u32 {
+        if self.last_fetch & 0xff00_0000 == IOMEM_ADDR {
+            return 0;
+        }
+        if self.last_fetch_thumb {
+            let opcode = self.read_16(self.last_fetch) as u32;
+            opcode | opcode << 16
+        } else {
+            self.read_32(self.last_fetch)
+        }
+    }
+
+    #[inline]
+    fn read_io_16(&self, io_addr: Addr) -> u16 {
+        if is_io_readable(io_addr) {
+            self.io.read_16(io_addr)
+        } else {
+            (self.open_bus() >> ((io_addr & 2) * 8)) as u16
         }
     }
 
-    /// must be called whenever this object is instanciated
+    #[inline]
+    fn read_protected_bios(&self, addr: Addr) -> u32 {
+        if self.bios_readable {
+            self.bios.read_32(addr & !3)
+        } else {
+            self.bios_value
+        }
+    }
+
+    /// must be called whenever this object is instanciated or moved,
+    /// this is done by `reset_after_load` for deserialized instances
     pub fn created(&mut self) {
         let ptr = SysBusPtr::new(self as *mut SysBus);
         // HACK
         self.io.set_sysbus_ptr(ptr.clone());
     }
 
-    pub fn on_waitcnt_written(&mut self, waitcnt: WaitControl) {
-        self.cycle_luts.update_gamepak_waitstates(waitcnt);
+    pub fn on_waitcnt_written(&mut self, waitcnt: &mut WaitControl) {
+        if self.cartridge.quirks.disable_prefetch {
+            waitcnt.set_bit_range(14, 14, 0);
+        }
+        self.cycle_luts.update_gamepak_waitstates(*waitcnt);
     }
 
     #[inline(always)]
@@ -241,8 +484,8 @@ impl SysBus {
 
 impl Bus for SysBus {
     fn read_32(&self, addr: Addr) -> u32 {
-        match addr & 0xff000000 {
-            BIOS_ADDR => self.bios.read_32(addr),
+        let value = match addr & 0xff000000 {
+            BIOS_ADDR => self.read_protected_bios(addr),
             EWRAM_ADDR => self.onboard_work_ram.read_32(addr & 0x3_fffc),
             IWRAM_ADDR => self.internal_work_ram.read_32(addr & 0x7ffc),
             IOMEM_ADDR => {
@@ -251,7 +494,7 @@ impl Bus for SysBus {
                 } else {
                     addr & 0x7fc
                 };
-                self.io.read_32(addr)
+                self.read_io_16(addr) as u32 | (self.read_io_16(addr + 2) as u32) << 16
             }
             PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.read_32(addr),
             GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO => {
@@ -263,12 +506,15 @@ impl Bus for SysBus {
                 // TODO open-bus
                 0
             }
-        }
+        };
+        #[cfg(feature = "trace")]
+        self.trace_access(false, 4, addr, value as u32);
+        value
     }
 
     fn read_16(&self, addr: Addr) -> u16 {
-        match addr & 0xff000000 {
-            BIOS_ADDR => self.bios.read_16(addr),
+        let value = match addr & 0xff000000 {
+            BIOS_ADDR => (self.read_protected_bios(addr) >> ((addr & 2) * 8)) as u16,
             EWRAM_ADDR => self.onboard_work_ram.read_16(addr & 0x3_fffe),
             IWRAM_ADDR => self.internal_work_ram.read_16(addr & 0x7ffe),
             IOMEM_ADDR => {
@@ -277,7 +523,7 @@ impl Bus for SysBus {
                 } else {
                     addr & 0x7fe
                 };
-                self.io.read_16(addr)
+                self.read_io_16(addr)
             }
             PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.read_16(addr),
             GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO => {
@@ -289,12 +535,15 @@ impl Bus for SysBus {
                 // TODO open-bus
                 0
             }
-        }
+        };
+        #[cfg(feature = "trace")]
+        self.trace_access(false, 2, addr, value as u32);
+        value
     }
 
     fn read_8(&self, addr: Addr) -> u8 {
-        match addr & 0xff000000 {
-            BIOS_ADDR => self.bios.read_8(addr),
+        let value = match addr & 0xff000000 {
+            BIOS_ADDR => (self.read_protected_bios(addr) >> ((addr & 3) * 8)) as u8,
             EWRAM_ADDR => self.onboard_work_ram.read_8(addr & 0x3_ffff),
             IWRAM_ADDR => self.internal_work_ram.read_8(addr & 0x7fff),
             IOMEM_ADDR => {
@@ -303,7 +552,7 @@ impl Bus for SysBus {
                 } else {
                     addr & 0x7ff
                 };
-                self.io.read_8(addr)
+                (self.read_io_16(addr & !1) >> ((addr & 1) * 8)) as u8
             }
             PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.read_8(addr),
             GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO => {
@@ -315,10 +564,15 @@ impl Bus for SysBus {
                 // TODO open-bus
                 0
             }
-        }
+        };
+        #[cfg(feature = "trace")]
+        self.trace_access(false, 1, addr, value as u32);
+        value
     }
 
     fn write_32(&mut self, addr: Addr, value: u32) {
+        #[cfg(feature = "trace")]
+        self.trace_access(true, 4, addr, value as u32);
         match addr & 0xff000000 {
             BIOS_ADDR => {}
             EWRAM_ADDR => self.onboard_work_ram.write_32(addr & 0x3_fffc, value),
@@ -329,10 +583,13 @@ impl Bus for SysBus {
                 } else {
                     addr & 0x7fc
                 };
+                io_trace::log_write(IOMEM_ADDR + addr, value, 4);
                 self.io.write_32(addr, value)
             }
             PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.write_32(addr, value),
-            GAMEPAK_WS0_LO => self.cartridge.write_32(addr, value),
+            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO => {
+                self.cartridge.write_32(addr, value)
+            }
             GAMEPAK_WS2_HI => self.cartridge.write_32(addr, value),
             SRAM_LO | SRAM_HI => self.cartridge.write_32(addr, value),
             _ => {
@@ -343,6 +600,8 @@ impl Bus for SysBus {
     }
 
     fn write_16(&mut self, addr: Addr, value: u16) {
+        #[cfg(feature = "trace")]
+        self.trace_access(true, 2, addr, value as u32);
         match addr & 0xff000000 {
             BIOS_ADDR => {}
             EWRAM_ADDR => self.onboard_work_ram.write_16(addr & 0x3_fffe, value),
@@ -353,10 +612,13 @@ impl Bus for SysBus {
                 } else {
                     addr & 0x7fe
                 };
+                io_trace::log_write(IOMEM_ADDR + addr, value as u32, 2);
                 self.io.write_16(addr, value)
             }
             PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.write_16(addr, value),
-            GAMEPAK_WS0_LO => self.cartridge.write_16(addr, value),
+            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO => {
+                self.cartridge.write_16(addr, value)
+            }
             GAMEPAK_WS2_HI => self.cartridge.write_16(addr, value),
             SRAM_LO | SRAM_HI => self.cartridge.write_16(addr, value),
             _ => {
@@ -367,6 +629,8 @@ impl Bus for SysBus {
     }
 
     fn write_8(&mut self, addr: Addr, value: u8) {
+        #[cfg(feature = "trace")]
+        self.trace_access(true, 1, addr, value as u32);
         match addr & 0xff000000 {
             BIOS_ADDR => {}
             EWRAM_ADDR => self.onboard_work_ram.write_8(addr & 0x3_ffff, value),
@@ -377,10 +641,13 @@ impl Bus for SysBus {
                 } else {
                     addr & 0x7ff
                 };
+                io_trace::log_write(IOMEM_ADDR + addr, value as u32, 1);
                 self.io.write_8(addr, value)
             }
             PALRAM_ADDR | VRAM_ADDR | OAM_ADDR => self.io.gpu.write_8(addr, value),
-            GAMEPAK_WS0_LO => self.cartridge.write_8(addr, value),
+            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO => {
+                self.cartridge.write_8(addr, value)
+            }
             GAMEPAK_WS2_HI => self.cartridge.write_8(addr, value),
             SRAM_LO | SRAM_HI => self.cartridge.write_8(addr, value),
             _ => {
@@ -419,8 +686,96 @@ impl DebugRead for SysBus {
     }
 }
 
+impl SysBus {
+    /// BIOS and ROM pokes go byte by byte through the debug path, everything else is written
+    /// like the cpu would
+    fn is_debug_patch(addr: Addr) -> bool {
+        matches!(
+            addr & 0xff000000,
+            BIOS_ADDR
+                | GAMEPAK_WS0_LO
+                | GAMEPAK_WS0_HI
+                | GAMEPAK_WS1_LO
+                | GAMEPAK_WS1_HI
+                | GAMEPAK_WS2_LO
+                | GAMEPAK_WS2_HI
+        )
+    }
+}
+
+impl DebugWrite for SysBus {
+    fn debug_write_32(&mut self, addr: Addr, value: u32) {
+        if SysBus::is_debug_patch(addr) {
+            self.debug_write_16(addr, (value & 0xffff) as u16);
+            self.debug_write_16(addr + 2, (value >> 16) as u16);
+        } else {
+            self.write_32(addr, value);
+        }
+    }
+
+    fn debug_write_16(&mut self, addr: Addr, value: u16) {
+        if SysBus::is_debug_patch(addr) {
+            self.debug_write_8(addr, (value & 0xff) as u8);
+            self.debug_write_8(addr + 1, (value >> 8) as u8);
+        } else {
+            self.write_16(addr, value);
+        }
+    }
+
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        match addr & 0xff000000 {
+            BIOS_ADDR => self.bios.debug_write_8(addr, value),
+            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI | GAMEPAK_WS1_LO | GAMEPAK_WS1_HI | GAMEPAK_WS2_LO
+            | GAMEPAK_WS2_HI => self.cartridge.debug_write_8(addr, value),
+            _ => self.write_8(addr, value),
+        }
+    }
+}
+
 impl DmaNotifer for SysBus {
     fn notify(&mut self, timing: u16) {
         self.io.dmac.notify_from_gpu(timing);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitfield::BitRange;
+
+    fn luts_for(value: u16) -> CycleLookupTables {
+        let mut waitcnt = WaitControl::default();
+        waitcnt.set_bit_range(15, 0, value);
+        let mut luts = CycleLookupTables::default();
+        luts.init();
+        luts.update_gamepak_waitstates(waitcnt);
+        luts
+    }
+
+    #[test]
+    fn test_gamepak_waitstates_default() {
+        let luts = luts_for(0);
+        for page in PAGE_GAMEPAK_WS0..PAGE_GAMEPAK_WS0 + 2 {
+            assert_eq!(luts.n_cycles16[page], 5);
+            assert_eq!(luts.s_cycles16[page], 3);
+            assert_eq!(luts.n_cycles32[page], 8);
+            assert_eq!(luts.s_cycles32[page], 6);
+        }
+        for page in PAGE_GAMEPAK_WS2..PAGE_GAMEPAK_WS2 + 2 {
+            assert_eq!(luts.s_cycles16[page], 9);
+        }
+    }
+
+    #[test]
+    fn test_sram_waitstates_all_widths() {
+        // SRAM 8 waits, WS2 second access 1 wait
+        let luts = luts_for(0b0100_0000_0011);
+        for page in [PAGE_SRAM_LO, PAGE_SRAM_HI].iter() {
+            assert_eq!(luts.n_cycles16[*page], 9);
+            assert_eq!(luts.s_cycles16[*page], 9);
+            assert_eq!(luts.n_cycles32[*page], 9);
+            assert_eq!(luts.s_cycles32[*page], 9);
+        }
+        assert_eq!(luts.s_cycles16[PAGE_GAMEPAK_WS2 + 1], 2);
+    }
+}
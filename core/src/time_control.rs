@@ -0,0 +1,223 @@
+//! Pause, slow motion, turbo and frame advance.
+//!
+//! `GameBoyAdvance::run_host_frame` asks its `TimeControl` how many frames to emulate every host
+//! frame, so the frontends only flip its switches and get the same behaviour:
+//! - paused, no frame runs, except one per `step_frame`;
+//! - in slow motion, a frame runs every few host frames, and the audio is resampled to last as
+//!   long, lower in pitch, rather than leaving the audio device starving;
+//! - in turbo, several frames run per host frame. Only the first one is presented and heard, the
+//!   others skip rendering and audio output, so the pitch stays the same.
+//!
+//! Turbo takes over the speed while held, and going back to it keeps the slow motion set before.
+
+/// The slowest slow motion
+pub const MIN_SLOW_MOTION: f32 = 0.25;
+
+/// What to run for a host frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostFrame {
+    /// That many frames, only the first one presented
+    Frames(usize),
+    /// Frames until a host frame worth of time has passed
+    Uncapped,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeControl {
+    paused: bool,
+    /// Emulated frames per host frame, `None` when uncapped
+    speed: Option<f32>,
+    turbo: bool,
+    /// The speed while in turbo, `None` when uncapped
+    turbo_speed: Option<f32>,
+    /// Frames `step_frame` asked for, run while paused
+    pending_steps: usize,
+    /// Fraction of a frame carried over to the next host frame
+    accumulator: f32,
+    /// The speed changed since the last `take_speed_change`
+    speed_changed: bool,
+}
+
+impl Default for TimeControl {
+    fn default() -> TimeControl {
+        TimeControl {
+            paused: false,
+            speed: Some(1.0),
+            turbo: false,
+            turbo_speed: None,
+            pending_steps: 0,
+            accumulator: 0.0,
+            speed_changed: false,
+        }
+    }
+}
+
+/// `None` for the speeds that can't be kept up with: infinite, or not positive
+fn capped(speed: f32) -> Option<f32> {
+    if speed > 0.0 && speed.is_finite() {
+        Some(speed)
+    } else {
+        None
+    }
+}
+
+impl TimeControl {
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pausing drops the frames `step_frame` asked for and not run yet
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.pending_steps = 0;
+        self.accumulator = 0.0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.set_paused(!self.paused);
+    }
+
+    /// Pauses, then runs a single frame at the next host frame. Frames asked for in a row run
+    /// one per host frame.
+    pub fn step_frame(&mut self) {
+        if !self.paused {
+            self.set_paused(true);
+        }
+        self.pending_steps += 1;
+    }
+
+    /// Sets the speed, 1.0 being real time. Infinite or non positive speeds run as fast as
+    /// possible.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = capped(speed);
+        self.change_speed();
+    }
+
+    /// The speed out of turbo, `f32::INFINITY` when uncapped
+    pub fn speed(&self) -> f32 {
+        self.speed.unwrap_or(f32::INFINITY)
+    }
+
+    /// Slows down to `factor`, from `MIN_SLOW_MOTION` to 1.0 for real time
+    pub fn set_slow_motion(&mut self, factor: f32) {
+        self.set_speed(factor.clamp(MIN_SLOW_MOTION, 1.0));
+    }
+
+    pub fn is_turbo(&self) -> bool {
+        self.turbo
+    }
+
+    pub fn set_turbo(&mut self, turbo: bool) {
+        if turbo != self.turbo {
+            self.turbo = turbo;
+            self.change_speed();
+        }
+    }
+
+    /// The speed of turbo, uncapped by default
+    pub fn set_turbo_speed(&mut self, speed: f32) {
+        self.turbo_speed = capped(speed);
+        if self.turbo {
+            self.change_speed();
+        }
+    }
+
+    /// The speed run at, `None` when uncapped
+    pub fn effective_speed(&self) -> Option<f32> {
+        if self.turbo {
+            self.turbo_speed
+        } else {
+            self.speed
+        }
+    }
+
+    /// How much slower than real time the audio plays, 1.0 unless in slow motion
+    pub fn audio_stretch(&self) -> f32 {
+        match self.effective_speed() {
+            Some(speed) if speed < 1.0 => 1.0 / speed,
+            _ => 1.0,
+        }
+    }
+
+    fn change_speed(&mut self) {
+        self.accumulator = 0.0;
+        self.speed_changed = true;
+    }
+
+    /// Whether the speed changed since the last call
+    pub(crate) fn take_speed_change(&mut self) -> bool {
+        core::mem::replace(&mut self.speed_changed, false)
+    }
+
+    /// What to run for the next host frame
+    pub fn next_host_frame(&mut self) -> HostFrame {
+        if self.paused {
+            if self.pending_steps == 0 {
+                return HostFrame::Frames(0);
+            }
+            self.pending_steps -= 1;
+            return HostFrame::Frames(1);
+        }
+        match self.effective_speed() {
+            Some(speed) => {
+                self.accumulator += speed;
+                let frames = self.accumulator as usize;
+                self.accumulator -= frames as f32;
+                HostFrame::Frames(frames)
+            }
+            None => HostFrame::Uncapped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(time_control: &mut TimeControl, host_frames: usize) -> Vec<HostFrame> {
+        (0..host_frames)
+            .map(|_| time_control.next_host_frame())
+            .collect()
+    }
+
+    #[test]
+    fn test_time_control() {
+        let mut time_control = TimeControl::default();
+        assert_eq!(frames(&mut time_control, 2), [HostFrame::Frames(1); 2]);
+
+        time_control.set_slow_motion(0.1);
+        assert_eq!(time_control.speed(), MIN_SLOW_MOTION);
+        assert_eq!(time_control.audio_stretch(), 4.0);
+        assert!(time_control.take_speed_change());
+        assert!(!time_control.take_speed_change());
+        let slow = frames(&mut time_control, 8);
+        assert_eq!(
+            slow.iter().filter(|f| **f == HostFrame::Frames(1)).count(),
+            2
+        );
+
+        // turbo overrides the slow motion, and gives it back
+        time_control.set_turbo(true);
+        assert_eq!(time_control.next_host_frame(), HostFrame::Uncapped);
+        assert_eq!(time_control.audio_stretch(), 1.0);
+        time_control.set_turbo_speed(3.0);
+        assert_eq!(time_control.next_host_frame(), HostFrame::Frames(3));
+        time_control.set_turbo(false);
+        assert_eq!(time_control.speed(), MIN_SLOW_MOTION);
+
+        time_control.set_slow_motion(1.0);
+        time_control.step_frame();
+        time_control.step_frame();
+        assert!(time_control.is_paused());
+        assert_eq!(
+            frames(&mut time_control, 3),
+            [
+                HostFrame::Frames(1),
+                HostFrame::Frames(1),
+                HostFrame::Frames(0)
+            ]
+        );
+        time_control.toggle_pause();
+        assert_eq!(time_control.next_host_frame(), HostFrame::Frames(1));
+    }
+}
@@ -0,0 +1,134 @@
+//! A timeline of the hardware events of a frame.
+//!
+//! When enabled with `GameBoyAdvance::set_timeline_enabled`, the interrupts, DMA transfers,
+//! timer overflows and blanking periods of every frame are recorded with the cycle they
+//! happened at, and `GameBoyAdvance::last_frame_events` returns those of the last frame, for a
+//! frontend to draw a timing diagram of raster or audio issues. The devices are stamped at the
+//! end of the emulation step they happened in, so their events can be late by up to a
+//! scanline phase (960 cycles), while the cpu side (servicing an IRQ) is exact.
+use alloc::vec::Vec;
+use num::FromPrimitive;
+
+use super::interrupt::Interrupt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimelineEventKind {
+    /// A device requested an interrupt, whether or not it is enabled
+    IrqRaised(Interrupt),
+    /// The cpu entered the IRQ handler, with the IF bits that were both requested and enabled
+    IrqServiced(u16),
+    DmaStarted {
+        channel: usize,
+        fifo: bool,
+    },
+    DmaStopped {
+        channel: usize,
+    },
+    /// The counter can wrap more than once in a step
+    TimerOverflow {
+        timer: usize,
+        count: usize,
+    },
+    HBlank {
+        line: usize,
+    },
+    VBlank,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimelineEvent {
+    /// Cycles since the start of the frame
+    pub cycle: usize,
+    pub kind: TimelineEventKind,
+}
+
+#[derive(Debug, Default)]
+pub struct Timeline {
+    cycle: usize,
+    /// IF at the last check, to tell the newly raised interrupts
+    irq_flags: u16,
+    events: Vec<TimelineEvent>,
+    last_frame: Vec<TimelineEvent>,
+}
+
+impl Timeline {
+    /// `irq_flags` are the interrupts already requested, which aren't recorded as raised
+    pub fn new(irq_flags: u16) -> Timeline {
+        Timeline {
+            irq_flags,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn advance(&mut self, cycles: usize) {
+        self.cycle += cycles;
+    }
+
+    pub(crate) fn push(&mut self, kind: TimelineEventKind) {
+        self.events.push(TimelineEvent {
+            cycle: self.cycle,
+            kind,
+        });
+    }
+
+    /// Records the interrupts raised since the last check
+    pub(crate) fn check_irqs(&mut self, irq_flags: u16) {
+        let raised = irq_flags & !self.irq_flags;
+        self.irq_flags = irq_flags;
+        for bit in 0..16 {
+            if raised & (1 << bit) != 0 {
+                if let Some(irq) = Interrupt::from_usize(bit) {
+                    self.push(TimelineEventKind::IrqRaised(irq));
+                }
+            }
+        }
+    }
+
+    /// Keeps the events of the frame that just ended, and starts the next one
+    pub(crate) fn end_frame(&mut self) {
+        self.last_frame.clear();
+        core::mem::swap(&mut self.events, &mut self.last_frame);
+        self.cycle = 0;
+    }
+
+    pub fn last_frame(&self) -> &[TimelineEvent] {
+        &self.last_frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raised_irqs_are_recorded_once() {
+        let mut timeline = Timeline::default();
+        timeline.advance(10);
+        timeline.check_irqs(0b1001);
+        timeline.advance(5);
+        // acknowledged VBlank, still pending Timer0
+        timeline.check_irqs(0b1000);
+        timeline.check_irqs(0b1001);
+        timeline.end_frame();
+
+        assert_eq!(
+            timeline.last_frame(),
+            [
+                TimelineEvent {
+                    cycle: 10,
+                    kind: TimelineEventKind::IrqRaised(Interrupt::LCD_VBlank)
+                },
+                TimelineEvent {
+                    cycle: 10,
+                    kind: TimelineEventKind::IrqRaised(Interrupt::Timer0_Overflow)
+                },
+                TimelineEvent {
+                    cycle: 15,
+                    kind: TimelineEventKind::IrqRaised(Interrupt::LCD_VBlank)
+                },
+            ]
+        );
+        timeline.end_frame();
+        assert!(timeline.last_frame().is_empty());
+    }
+}
@@ -1,12 +1,20 @@
 use super::interrupt::{self, Interrupt, InterruptConnect, SharedInterruptFlags};
 use super::iodev::consts::*;
+use super::logging;
 use super::sysbus::SysBus;
+use super::{LoadContext, ResetAfterLoad};
+use alloc::vec::Vec;
+
+use alloc::collections::VecDeque;
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
 const SHIFT_LUT: [usize; 4] = [0, 6, 8, 10];
 
+/// Number of overflows kept by `Timers::overflow_history`
+const OVERFLOW_HISTORY_LEN: usize = 64;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Timer {
     // registers
@@ -19,6 +27,9 @@ pub struct Timer {
     timer_id: usize,
     cycles: usize,
     prescalar_shift: usize,
+    /// Overflows since power on, for debuggers
+    #[serde(skip)]
+    overflows: u64,
 }
 
 impl Timer {
@@ -35,6 +46,7 @@ impl Timer {
             initial_data: 0,
             cycles: 0,
             prescalar_shift: 0,
+            overflows: 0,
         }
     }
 
@@ -66,16 +78,55 @@ impl Timer {
         }
 
         self.data += ticks as u16;
+        self.overflows += num_overflows as u64;
 
         num_overflows as usize
     }
 }
 
+/// State of a timer, for debuggers
+#[derive(Debug, Clone)]
+pub struct TimerInfo {
+    pub id: usize,
+    /// The live counter, as read from TMxCNT_L
+    pub counter: u16,
+    /// Loaded into the counter on overflow
+    pub reload: u16,
+    pub ctl: TimerCtl,
+    /// Cycles per tick, 1, 64, 256 or 1024
+    pub prescaler: usize,
+    /// Cycles already counted towards the next tick
+    pub prescaler_phase: usize,
+    /// Overflows since power on
+    pub overflows: u64,
+    /// The timer whose overflows this one counts instead of cycles
+    pub cascade_from: Option<usize>,
+}
+
+/// A timer overflow, see `Timers::overflow_history`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerOverflow {
+    pub timer: usize,
+    /// Cycles since power on, up to the update that overflowed the timer
+    pub cycle: u64,
+    /// The counter can wrap more than once in an update
+    pub count: usize,
+    pub irq: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Timers {
     timers: [Timer; 4],
     running_timers: u8,
     pub trace: bool,
+    /// Cycles since power on, timestamps the overflow history
+    #[serde(skip)]
+    elapsed_cycles: u64,
+    #[serde(skip)]
+    overflow_history: VecDeque<TimerOverflow>,
+    /// Overflows not yet taken by `take_overflow_events`, `None` when nobody listens
+    #[serde(skip)]
+    overflow_events: Option<Vec<TimerOverflow>>,
 }
 
 impl InterruptConnect for Timers {
@@ -86,14 +137,20 @@ impl InterruptConnect for Timers {
     }
 }
 
-impl std::ops::Index<usize> for Timers {
+impl ResetAfterLoad for Timers {
+    fn reset_after_load(&mut self, ctx: &LoadContext) {
+        self.connect_irq(ctx.interrupt_flags.clone());
+    }
+}
+
+impl core::ops::Index<usize> for Timers {
     type Output = Timer;
     fn index(&self, index: usize) -> &Self::Output {
         &self.timers[index]
     }
 }
 
-impl std::ops::IndexMut<usize> for Timers {
+impl core::ops::IndexMut<usize> for Timers {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
         &mut self.timers[index]
     }
@@ -110,6 +167,63 @@ impl Timers {
             ],
             running_timers: 0,
             trace: false,
+            elapsed_cycles: 0,
+            overflow_history: VecDeque::with_capacity(OVERFLOW_HISTORY_LEN),
+            overflow_events: None,
+        }
+    }
+
+    pub fn inspect(&self) -> [TimerInfo; 4] {
+        let info = |id: usize| {
+            let timer = &self.timers[id];
+            TimerInfo {
+                id,
+                counter: timer.data,
+                reload: timer.initial_data,
+                ctl: timer.ctl.clone(),
+                prescaler: 1 << timer.prescalar_shift,
+                prescaler_phase: timer.cycles,
+                overflows: timer.overflows,
+                cascade_from: if id > 0 && timer.ctl.cascade() {
+                    Some(id - 1)
+                } else {
+                    None
+                },
+            }
+        };
+        [info(0), info(1), info(2), info(3)]
+    }
+
+    /// The last overflows, oldest first
+    pub fn overflow_history(&self) -> impl Iterator<Item = &TimerOverflow> {
+        self.overflow_history.iter()
+    }
+
+    /// Starts or stops collecting the overflows returned by `take_overflow_events`
+    pub fn record_overflow_events(&mut self, enable: bool) {
+        self.overflow_events = if enable { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_overflow_events(&mut self) -> Vec<TimerOverflow> {
+        self.overflow_events
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn record_overflow(&mut self, id: usize, count: usize) {
+        if self.overflow_history.len() == OVERFLOW_HISTORY_LEN {
+            self.overflow_history.pop_front();
+        }
+        let overflow = TimerOverflow {
+            timer: id,
+            cycle: self.elapsed_cycles,
+            count,
+            irq: self.timers[id].ctl.irq_enabled(),
+        };
+        self.overflow_history.push_back(overflow);
+        if let Some(events) = &mut self.overflow_events {
+            events.push(overflow);
         }
     }
 
@@ -127,7 +241,7 @@ impl Timers {
             self.running_timers &= !(1 << id);
         }
         if old_enabled != new_enabled {
-            trace!(
+            trace!(target: logging::BUS,
                 "TMR{} {}",
                 id,
                 if new_enabled { "enabled" } else { "disabled" }
@@ -179,6 +293,7 @@ impl Timers {
     }
 
     pub fn update(&mut self, cycles: usize, sb: &mut SysBus) {
+        self.elapsed_cycles += cycles as u64;
         for id in 0..4 {
             if self.running_timers & (1 << id) == 0 {
                 continue;
@@ -193,10 +308,14 @@ impl Timers {
                 timer.cycles = cycles & ((1 << timer.prescalar_shift) - 1);
 
                 if num_overflows > 0 {
+                    self.record_overflow(id, num_overflows);
                     if id != 3 {
                         let next_timer = &mut self.timers[id + 1];
                         if next_timer.ctl.cascade() {
-                            next_timer.update(num_overflows);
+                            let cascaded_overflows = next_timer.update(num_overflows);
+                            if cascaded_overflows > 0 {
+                                self.record_overflow(id + 1, cascaded_overflows);
+                            }
                         }
                     }
                     if id == 0 || id == 1 {
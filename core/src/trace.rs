@@ -0,0 +1,347 @@
+//! Execution traces, for differential debugging against other emulators.
+//!
+//! While tracing, every executed instruction is written to a binary file along with the memory
+//! accesses it made. DMA transfers and opcode fetches are left out. The file starts with
+//! `TRACE_MAGIC` and a little-endian u16 version, followed by one entry per instruction:
+//!
+//! | size | field                                            |
+//! |------|--------------------------------------------------|
+//! | 8    | CPU cycles executed before the instruction       |
+//! | 4    | address of the instruction                       |
+//! | 4    | opcode                                           |
+//! | 1    | flags, bit 0 is set in Thumb state               |
+//! | 4    | number of memory accesses                        |
+//! | 9 *  | accesses: kind, address (4), value (4)           |
+//!
+//! The kind of an access holds log2 of its width in bytes in bits 0-1, and bit 7 is set for
+//! writes. All numbers are little-endian. Printing an entry decodes its writes to the IO
+//! registers, see `io_trace`.
+//! ```ignore
+//! gba.start_trace(BufWriter::new(File::create("game.trace")?))?;
+//! gba.frame();
+//! gba.stop_trace()?;
+//!
+//! for entry in TraceReader::new(BufReader::new(File::open("game.trace")?))? {
+//!     println!("{}", entry?);
+//! }
+//! ```
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use super::bus::Addr;
+use super::io_trace::IoWrite;
+use super::sysbus::consts::IOMEM_ADDR;
+use super::MaybeSend;
+
+pub const TRACE_MAGIC: &[u8; 8] = b"RBATRACE";
+pub const TRACE_VERSION: u16 = 1;
+
+const FLAG_THUMB: u8 = 1;
+const ACCESS_WRITE: u8 = 0x80;
+
+/// A memory access made by an instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemAccess {
+    pub write: bool,
+    /// 1, 2 or 4 bytes
+    pub width: u8,
+    pub addr: Addr,
+    pub value: u32,
+}
+
+/// An executed instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub cycle: u64,
+    pub pc: Addr,
+    pub opcode: u32,
+    pub thumb: bool,
+    pub accesses: Vec<MemAccess>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.thumb {
+            write!(
+                f,
+                "{:>12} {:08x}: {:04x}    ",
+                self.cycle, self.pc, self.opcode
+            )?;
+        } else {
+            write!(f, "{:>12} {:08x}: {:08x}", self.cycle, self.pc, self.opcode)?;
+        }
+        for access in &self.accesses {
+            let digits = access.width as usize * 2;
+            write!(
+                f,
+                " {}{} [{:08x}]={:0digits$x}",
+                if access.write { 'w' } else { 'r' },
+                access.width * 8,
+                access.addr,
+                access.value,
+                digits = digits
+            )?;
+            if access.write && access.addr & 0xff00_0000 == IOMEM_ADDR {
+                let write = IoWrite {
+                    addr: access.addr,
+                    value: access.value,
+                    width: access.width,
+                };
+                write!(f, " ({})", write)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a trace is written to, a `Write` that is `Send` when the emulator has to be
+pub trait TraceSink: Write + MaybeSend {}
+
+impl<T: Write + MaybeSend> TraceSink for T {}
+
+/// Writes the entries of a trace
+pub struct TraceWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Writes the header of the trace to `out`
+    pub fn new(mut out: W) -> io::Result<TraceWriter<W>> {
+        out.write_all(TRACE_MAGIC)?;
+        out.write_all(&TRACE_VERSION.to_le_bytes())?;
+        Ok(TraceWriter { out })
+    }
+
+    pub fn write_entry(
+        &mut self,
+        cycle: u64,
+        pc: Addr,
+        opcode: u32,
+        thumb: bool,
+        accesses: &[MemAccess],
+    ) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(21 + accesses.len() * 9);
+        buf.extend_from_slice(&cycle.to_le_bytes());
+        buf.extend_from_slice(&pc.to_le_bytes());
+        buf.extend_from_slice(&opcode.to_le_bytes());
+        buf.push(if thumb { FLAG_THUMB } else { 0 });
+        buf.extend_from_slice(&(accesses.len() as u32).to_le_bytes());
+        for access in accesses {
+            let mut kind = access.width.trailing_zeros() as u8;
+            if access.write {
+                kind |= ACCESS_WRITE;
+            }
+            buf.push(kind);
+            buf.extend_from_slice(&access.addr.to_le_bytes());
+            buf.extend_from_slice(&access.value.to_le_bytes());
+        }
+        self.out.write_all(&buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Reads the entries of a trace, as an iterator
+pub struct TraceReader<R: Read> {
+    input: R,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Checks the header of the trace in `input`
+    pub fn new(mut input: R) -> io::Result<TraceReader<R>> {
+        let mut header = [0; 10];
+        input.read_exact(&mut header)?;
+        if &header[..8] != TRACE_MAGIC {
+            return Err(invalid_data("not an execution trace"));
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != TRACE_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported trace version {}",
+                version
+            )));
+        }
+        Ok(TraceReader { input })
+    }
+
+    /// The next entry, `None` at the end of the trace
+    pub fn read_entry(&mut self) -> io::Result<Option<TraceEntry>> {
+        let mut head = [0; 21];
+        // an end of file is only clean between two entries
+        let mut read = 0;
+        while read < head.len() {
+            match self.input.read(&mut head[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let u32_at = |bytes: &[u8], i: usize| {
+            u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+        };
+        let mut cycle = [0; 8];
+        cycle.copy_from_slice(&head[..8]);
+        let count = u32_at(&head, 17) as usize;
+
+        let mut accesses = Vec::with_capacity(count.min(256));
+        let mut buf = [0; 9];
+        for _ in 0..count {
+            self.input.read_exact(&mut buf)?;
+            accesses.push(MemAccess {
+                write: buf[0] & ACCESS_WRITE != 0,
+                width: 1 << (buf[0] & 3),
+                addr: u32_at(&buf, 1),
+                value: u32_at(&buf, 5),
+            });
+        }
+        Ok(Some(TraceEntry {
+            cycle: u64::from_le_bytes(cycle),
+            pc: u32_at(&head, 8),
+            opcode: u32_at(&head, 12),
+            thumb: head[16] & FLAG_THUMB != 0,
+            accesses,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEntry>;
+
+    fn next(&mut self) -> Option<io::Result<TraceEntry>> {
+        self.read_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::gba::test_util::{make_arm_rom, make_mock_gba};
+    use crate::{lock, shared, Shared};
+
+    /// Keeps what the trace writes to it, for the test to read back
+    struct SharedBuffer(Shared<Vec<u8>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            lock(&self.0).extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_entries_round_trip() {
+        let store = MemAccess {
+            write: true,
+            width: 2,
+            addr: 0x0300_0000,
+            value: 0x1234,
+        };
+        let mut writer = TraceWriter::new(Vec::new()).unwrap();
+        writer
+            .write_entry(10, 0x0800_0000, 0xe3a0_0301, false, &[])
+            .unwrap();
+        writer
+            .write_entry(12, 0x0800_0004, 0x8008, true, &[store])
+            .unwrap();
+        let bytes = writer.into_inner();
+
+        let entries: Vec<TraceEntry> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].cycle, entries[0].thumb), (10, false));
+        assert_eq!(entries[1].accesses, [store]);
+        assert_eq!(
+            entries[1].to_string(),
+            "          12 08000004: 8008     w16 [03000000]=1234"
+        );
+        let io_store = TraceEntry {
+            accesses: vec![MemAccess {
+                addr: 0x0400_0000,
+                value: 0x0403,
+                ..store
+            }],
+            ..entries[1].clone()
+        };
+        assert_eq!(
+            io_store.to_string(),
+            "          12 08000004: 8008     w16 [04000000]=0403 (REG_DISPCNT := mode3 | bg2 (0x0403))"
+        );
+
+        // a truncated entry is an error, not the end of the trace
+        let mut reader = TraceReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(TraceReader::new(&b"RBATRACX\x01\x00"[..]).is_err());
+    }
+
+    #[test]
+    fn test_instructions_are_traced() {
+        // mov r0, #0x03000000; ldr r1, [r0]; add r1, r1, #1; strh r1, [r0]; b .
+        let code: [u32; 5] = [
+            0xe3a0_0403,
+            0xe590_1000,
+            0xe281_1001,
+            0xe1c0_10b0,
+            0xeaff_fffe,
+        ];
+        let mut gba = make_mock_gba(&make_arm_rom(&code));
+
+        let trace = shared(Vec::new());
+        gba.start_trace(SharedBuffer(trace.clone())).unwrap();
+        for _ in 0..4 {
+            gba.step();
+        }
+        gba.stop_trace().unwrap();
+        let bytes = lock(&trace).clone();
+        // the instructions that were in the pipeline after skipping the BIOS come first
+        let entries: Vec<TraceEntry> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .skip_while(|entry| entry.pc != 0x0800_0000)
+            .take(4)
+            .collect();
+
+        let pcs: Vec<Addr> = entries.iter().map(|e| e.pc).collect();
+        assert_eq!(pcs, [0x0800_0000, 0x0800_0004, 0x0800_0008, 0x0800_000c]);
+        assert_eq!(entries[1].opcode, 0xe590_1000);
+        assert!(entries[0].accesses.is_empty());
+        assert_eq!(
+            entries[1].accesses,
+            [MemAccess {
+                write: false,
+                width: 4,
+                addr: 0x0300_0000,
+                value: 0
+            }]
+        );
+        assert_eq!(
+            entries[3].accesses,
+            [MemAccess {
+                write: true,
+                width: 2,
+                addr: 0x0300_0000,
+                value: 1
+            }]
+        );
+        assert!(entries[0].cycle < entries[3].cycle);
+    }
+}
@@ -0,0 +1,141 @@
+//! Connects the UART of the serial port to the host.
+//!
+//! Homebrew printing to a debug console over the link cable, and some flash cart tools, talk
+//! to the other end in UART mode. A `UartBridge` is that other end, opened from a spec:
+//! ```text
+//! tcp:127.0.0.1:5000      listens there, `nc 127.0.0.1 5000` is then the console
+//! /dev/pts/3              a serial device or a pseudo terminal, e.g. one end of
+//!                         `socat -d -d pty,raw,echo=0 pty,raw,echo=0`
+//! ```
+//! The clones of a bridge share its connection, so a frontend opens it once and plugs a clone
+//! into every game it loads.
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::sio::{DummyLink, SerialDevice};
+use super::{CoreResult, ResultExt};
+
+type Writer = Box<dyn Write + Send>;
+
+#[derive(Clone)]
+pub struct UartBridge {
+    /// Where the bytes sent go, `None` while nothing is connected
+    writer: Arc<Mutex<Option<Writer>>>,
+    received: Arc<Mutex<Receiver<u8>>>,
+}
+
+/// Hands over what `reader` reads until it is closed
+fn forward(mut reader: impl Read, sender: &Sender<u8>) {
+    let mut buffer = [0; 256];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(len) => {
+                for byte in &buffer[..len] {
+                    if sender.send(*byte).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl UartBridge {
+    fn new(received: Receiver<u8>) -> UartBridge {
+        UartBridge {
+            writer: Arc::new(Mutex::new(None)),
+            received: Arc::new(Mutex::new(received)),
+        }
+    }
+
+    /// Opens `spec`, `tcp:<address>` or the path of a serial device
+    pub fn open(spec: &str) -> CoreResult<UartBridge> {
+        match spec.strip_prefix("tcp:") {
+            Some(addr) => UartBridge::listen(addr),
+            None => UartBridge::device(Path::new(spec)),
+        }
+    }
+
+    /// Waits for TCP clients on `addr`, serving one at a time
+    pub fn listen(addr: &str) -> CoreResult<UartBridge> {
+        let listener = TcpListener::bind(addr).with_context(|| format!("listening on {}", addr))?;
+        info!("UART bridge listening on {}", addr);
+        let (sender, receiver) = channel();
+        let bridge = UartBridge::new(receiver);
+        let writer = bridge.writer.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                match stream.try_clone() {
+                    Ok(clone) => *writer.lock().unwrap() = Some(Box::new(clone)),
+                    Err(_) => continue,
+                }
+                info!("UART bridge connected to {:?}", stream.peer_addr());
+                forward(stream, &sender);
+                *writer.lock().unwrap() = None;
+                info!("UART bridge disconnected");
+            }
+        });
+        Ok(bridge)
+    }
+
+    /// Talks to a serial device or a pseudo terminal
+    pub fn device(path: &Path) -> CoreResult<UartBridge> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        let reader = file.try_clone()?;
+        let (sender, receiver) = channel();
+        let bridge = UartBridge::new(receiver);
+        *bridge.writer.lock().unwrap() = Some(Box::new(file));
+        thread::spawn(move || forward(reader, &sender));
+        Ok(bridge)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.writer.lock().unwrap().is_some()
+    }
+}
+
+/// Only speaks UART, the other modes find the port unplugged
+impl SerialDevice for UartBridge {
+    fn ready(&self) -> bool {
+        self.is_connected()
+    }
+
+    fn transfer_normal(&mut self, data: u32, bits: u32, master: bool) -> Option<u32> {
+        DummyLink::Disconnected.transfer_normal(data, bits, master)
+    }
+
+    fn transfer_multiplayer(&mut self, _data: u16) -> Option<[u16; 3]> {
+        None
+    }
+
+    fn uart_send(&mut self, byte: u8) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(stream) = writer.as_mut() {
+            if stream
+                .write_all(&[byte])
+                .and_then(|_| stream.flush())
+                .is_err()
+            {
+                *writer = None;
+            }
+        }
+    }
+
+    fn uart_receive(&mut self) -> Option<u8> {
+        self.received.lock().unwrap().try_recv().ok()
+    }
+}
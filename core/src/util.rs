@@ -1,39 +1,53 @@
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "std")]
 use std::io;
+#[cfg(feature = "std")]
 use std::io::prelude::*;
-use std::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
 use std::path::Path;
-use std::ptr;
+#[cfg(feature = "std")]
 use std::time;
 
-use super::bus::{Addr, Bus, DebugRead};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-#[cfg(not(target_arch = "wasm32"))]
-type Instant = time::Instant;
-#[cfg(not(target_arch = "wasm32"))]
-fn now() -> Instant {
+use super::bus::{Addr, Bus, DebugRead, DebugWrite};
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub(crate) type Instant = time::Instant;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub(crate) fn now() -> Instant {
     time::Instant::now()
 }
 
-#[cfg(target_arch = "wasm32")]
+#[cfg(all(feature = "std", target_arch = "wasm32"))]
 use instant;
-#[cfg(target_arch = "wasm32")]
-type Instant = instant::Instant;
-#[cfg(target_arch = "wasm32")]
-fn now() -> Instant {
+#[cfg(all(feature = "std", target_arch = "wasm32"))]
+pub(crate) type Instant = instant::Instant;
+#[cfg(all(feature = "std", target_arch = "wasm32"))]
+pub(crate) fn now() -> Instant {
     instant::Instant::now()
 }
 
+#[cfg(feature = "std")]
 use crate::GameBoyAdvance;
+use core::fmt;
 #[cfg(feature = "gdb")]
 use gdbstub;
 #[cfg(feature = "gdb")]
 use gdbstub::GdbStub;
-use std::fmt;
 #[cfg(feature = "gdb")]
 use std::net::TcpListener;
+#[cfg(feature = "std")]
 use std::net::ToSocketAddrs;
 
+#[cfg(feature = "std")]
 pub fn spawn_and_run_gdb_server<A: ToSocketAddrs + fmt::Display>(
     #[allow(unused)] target: &mut GameBoyAdvance,
     #[allow(unused)] addr: A,
@@ -67,6 +81,7 @@ pub fn spawn_and_run_gdb_server<A: ToSocketAddrs + fmt::Display>(
     Ok(())
 }
 
+#[cfg(feature = "std")]
 pub fn read_bin_file(filename: &Path) -> io::Result<Vec<u8>> {
     let mut buf = Vec::new();
     let mut file = File::open(filename)?;
@@ -74,6 +89,7 @@ pub fn read_bin_file(filename: &Path) -> io::Result<Vec<u8>> {
     Ok(buf)
 }
 
+#[cfg(feature = "std")]
 pub fn write_bin_file(filename: &Path, data: &Vec<u8>) -> io::Result<()> {
     let mut f = File::create(filename)?;
     f.write_all(data)?;
@@ -81,13 +97,16 @@ pub fn write_bin_file(filename: &Path, data: &Vec<u8>) -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "std")]
 pub struct FpsCounter {
     count: u32,
     timer: Instant,
 }
 
+#[cfg(feature = "std")]
 const SECOND: time::Duration = time::Duration::from_secs(1);
 
+#[cfg(feature = "std")]
 impl Default for FpsCounter {
     fn default() -> FpsCounter {
         FpsCounter {
@@ -97,6 +116,7 @@ impl Default for FpsCounter {
     }
 }
 
+#[cfg(feature = "std")]
 impl FpsCounter {
     pub fn tick(&mut self) -> Option<u32> {
         self.count += 1;
@@ -131,6 +151,7 @@ macro_rules! host_breakpoint {
     };
 }
 
+#[cfg(feature = "std")]
 pub mod audio {
     use ringbuf::{Consumer, Producer, RingBuffer};
 
@@ -165,6 +186,10 @@ pub struct WeakPointer<T: ?Sized> {
     ptr: *mut T,
 }
 
+// The pointers only ever point into the object that holds them, like the `SysBus` pointer of
+// `IoDevices`, so they move to another thread along with what they point to.
+unsafe impl<T: ?Sized + Send> Send for WeakPointer<T> {}
+
 impl<T> WeakPointer<T> {
     pub fn new(ptr: *mut T) -> Self {
         WeakPointer { ptr }
@@ -222,3 +247,127 @@ impl DebugRead for BoxedMemory {
         self.mem[addr as usize]
     }
 }
+
+impl DebugWrite for BoxedMemory {
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        self.mem[addr as usize] = value;
+    }
+}
+
+/// Read-only image (ROM or BIOS) that can be shared with the frontend or borrowed instead of
+/// copied, so large ROMs don't have to be held twice in memory.
+///
+/// Writes go through `to_mut`, which makes a private copy first when the image is not owned.
+/// Savestates always store the contents, and restore them as `Owned`.
+#[derive(Clone)]
+pub enum RomBuffer {
+    Owned(Box<[u8]>),
+    Shared(Arc<[u8]>),
+    Static(&'static [u8]),
+    /// Backed by a memory mapped file or any other host owned buffer
+    Mapped(Arc<dyn AsRef<[u8]> + Send + Sync>),
+}
+
+impl RomBuffer {
+    pub fn to_mut(&mut self) -> &mut [u8] {
+        if !matches!(self, RomBuffer::Owned(_)) {
+            *self = RomBuffer::Owned(self.to_vec().into_boxed_slice());
+        }
+        match self {
+            RomBuffer::Owned(bytes) => bytes,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Deref for RomBuffer {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            RomBuffer::Owned(bytes) => bytes,
+            RomBuffer::Shared(bytes) => bytes,
+            RomBuffer::Static(bytes) => bytes,
+            RomBuffer::Mapped(bytes) => (**bytes).as_ref(),
+        }
+    }
+}
+
+impl From<Box<[u8]>> for RomBuffer {
+    fn from(bytes: Box<[u8]>) -> RomBuffer {
+        RomBuffer::Owned(bytes)
+    }
+}
+
+impl From<Vec<u8>> for RomBuffer {
+    fn from(bytes: Vec<u8>) -> RomBuffer {
+        RomBuffer::Owned(bytes.into_boxed_slice())
+    }
+}
+
+impl From<Arc<[u8]>> for RomBuffer {
+    fn from(bytes: Arc<[u8]>) -> RomBuffer {
+        RomBuffer::Shared(bytes)
+    }
+}
+
+impl From<&'static [u8]> for RomBuffer {
+    fn from(bytes: &'static [u8]) -> RomBuffer {
+        RomBuffer::Static(bytes)
+    }
+}
+
+impl From<Cow<'static, [u8]>> for RomBuffer {
+    fn from(bytes: Cow<'static, [u8]>) -> RomBuffer {
+        match bytes {
+            Cow::Borrowed(bytes) => RomBuffer::Static(bytes),
+            Cow::Owned(bytes) => RomBuffer::from(bytes),
+        }
+    }
+}
+
+impl fmt::Debug for RomBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            RomBuffer::Owned(_) => "Owned",
+            RomBuffer::Shared(_) => "Shared",
+            RomBuffer::Static(_) => "Static",
+            RomBuffer::Mapped(_) => "Mapped",
+        };
+        write!(f, "RomBuffer::{}({} bytes)", kind, self.len())
+    }
+}
+
+impl Serialize for RomBuffer {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.deref().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RomBuffer {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RomBuffer, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(RomBuffer::from)
+    }
+}
+
+impl Bus for RomBuffer {
+    #[inline]
+    fn read_8(&self, addr: Addr) -> u8 {
+        unsafe { *self.get_unchecked(addr as usize) }
+    }
+
+    fn write_8(&mut self, _addr: Addr, _value: u8) {}
+}
+
+impl DebugRead for RomBuffer {
+    fn debug_read_8(&self, addr: Addr) -> u8 {
+        self[addr as usize]
+    }
+}
+
+impl DebugWrite for RomBuffer {
+    fn debug_write_8(&mut self, addr: Addr, value: u8) {
+        self.to_mut()[addr as usize] = value;
+    }
+}
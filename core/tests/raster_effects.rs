@@ -0,0 +1,170 @@
+//! Raster effects driven by HBlank DMA.
+//!
+//! The ROM is a single `b .`, the tests program the hardware between frames like a VBlank
+//! handler would, and check every line as it is drawn through the scanline hook. The frame
+//! hashes catch changes of the whole picture from one frame to the next.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use rustboyadvance_core::cartridge::header;
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::{lock, Shared};
+
+const REG_DISPCNT: u32 = 0x0400_0000;
+const REG_VCOUNT: u32 = 0x0400_0006;
+const REG_BG0CNT: u32 = 0x0400_0008;
+const REG_WIN0H: u32 = 0x0400_0040;
+const REG_WIN0V: u32 = 0x0400_0044;
+const REG_WININ: u32 = 0x0400_0048;
+const REG_WINOUT: u32 = 0x0400_004a;
+const REG_DMA0SAD: u32 = 0x0400_00b0;
+const REG_DMA0DAD: u32 = 0x0400_00b4;
+const REG_DMA0CNT_L: u32 = 0x0400_00b8;
+const REG_DMA0CNT_H: u32 = 0x0400_00ba;
+
+const PALRAM: u32 = 0x0500_0000;
+const VRAM: u32 = 0x0600_0000;
+const TABLE: u32 = 0x0200_0000;
+
+/// Enabled, repeated on every HBlank, fixed destination, 16 bit
+const HBLANK_DMA: u16 = 0x8000 | 0x2000 | 0x0200 | 0x0040;
+
+const BLUE: u16 = 0x7c00;
+const GREEN: u16 = 0x03e0;
+
+type Lines = Shared<Vec<(usize, Vec<u16>)>>;
+
+fn make_gba() -> (GameBoyAdvance, Lines) {
+    let mut rom = vec![0; 0x200];
+    // b .
+    rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+    header::fix_checksum(&mut rom);
+    let cartridge = GamepakBuilder::new()
+        .buffer(&rom)
+        .with_sram()
+        .without_backup_to_file()
+        .build()
+        .unwrap();
+    let bios = vec![0; 0x4000].into_boxed_slice();
+    let mut gba = GameBoyAdvance::new(bios, cartridge, Frontend::default());
+    gba.skip_bios();
+
+    let lines = Lines::default();
+    let lines_clone = lines.clone();
+    gba.set_scanline_hook(Some(Box::new(
+        move |line: usize, colors: &[u16; DISPLAY_WIDTH]| {
+            lock(&lines_clone).push((line, colors.to_vec()))
+        },
+    )));
+    (gba, lines)
+}
+
+fn run_until_line(gba: &mut GameBoyAdvance, line: u16) {
+    while gba.sysbus.read_16(REG_VCOUNT) != line {
+        gba.step();
+    }
+}
+
+/// Writes `table` to EWRAM and has DMA 0 copy one entry to `dst` on every HBlank. The first
+/// entry is for line 0 and is written right away.
+fn start_hdma(gba: &mut GameBoyAdvance, dst: u32, table: &[u16]) {
+    for (i, entry) in table.iter().enumerate() {
+        gba.sysbus.write_16(TABLE + 2 * i as u32, *entry);
+    }
+    gba.sysbus.write_16(dst, table[0]);
+    gba.sysbus.write_16(REG_DMA0CNT_H, 0);
+    gba.sysbus.write_32(REG_DMA0SAD, TABLE + 2);
+    gba.sysbus.write_32(REG_DMA0DAD, dst);
+    gba.sysbus.write_16(REG_DMA0CNT_L, 1);
+    gba.sysbus.write_16(REG_DMA0CNT_H, HBLANK_DMA);
+}
+
+/// Runs from VBlank to the next one, returns the drawn lines
+fn run_frame(gba: &mut GameBoyAdvance, lines: &Lines) -> Vec<(usize, Vec<u16>)> {
+    lock(lines).clear();
+    run_until_line(gba, 0);
+    run_until_line(gba, DISPLAY_HEIGHT as u16);
+    lock(lines).split_off(0)
+}
+
+fn frame_hash(gba: &GameBoyAdvance) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    gba.get_frame_buffer().hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn test_hdma_gradient_sky() {
+    let (mut gba, lines) = make_gba();
+    // mode 0 without any background, only the backdrop is visible
+    gba.sysbus.write_16(REG_DISPCNT, 0);
+    let gradient: Vec<u16> = (0..=DISPLAY_HEIGHT as u16)
+        .map(|y| (y / 5) << 10 | (31 - y / 6))
+        .collect();
+
+    run_until_line(&mut gba, DISPLAY_HEIGHT as u16);
+    run_frame(&mut gba, &lines);
+    let flat = frame_hash(&gba);
+
+    let mut hashes = Vec::new();
+    for _ in 0..2 {
+        start_hdma(&mut gba, PALRAM, &gradient);
+        let drawn = run_frame(&mut gba, &lines);
+        assert_eq!(drawn.len(), DISPLAY_HEIGHT);
+        for (y, (line, colors)) in drawn.iter().enumerate() {
+            assert_eq!(*line, y);
+            assert!(
+                colors.iter().all(|color| *color == gradient[y]),
+                "line {}",
+                y
+            );
+        }
+        hashes.push(frame_hash(&gba));
+    }
+    assert_eq!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], flat);
+}
+
+#[test]
+fn test_hdma_window_animation() {
+    let (mut gba, lines) = make_gba();
+    // BG0 is green, only visible inside WIN0
+    for ofs in (0..0x20).step_by(2) {
+        gba.sysbus.write_16(VRAM + ofs, 0x1111);
+    }
+    gba.sysbus.write_16(PALRAM, BLUE);
+    gba.sysbus.write_16(PALRAM + 2, GREEN);
+    gba.sysbus.write_16(REG_BG0CNT, 0x1f00);
+    gba.sysbus.write_16(REG_WIN0V, DISPLAY_HEIGHT as u16);
+    gba.sysbus.write_16(REG_WININ, 0x01);
+    gba.sysbus.write_16(REG_WINOUT, 0x00);
+    gba.sysbus.write_16(REG_DISPCNT, 0x2100);
+
+    run_until_line(&mut gba, DISPLAY_HEIGHT as u16);
+    let mut hashes = Vec::new();
+    // a triangle that moves right by 8 pixels every frame
+    for frame in 0..3 {
+        let center = 100 + 8 * frame;
+        let edges: Vec<(usize, usize)> = (0..=DISPLAY_HEIGHT)
+            .map(|y| (center - y / 2, center + y / 2))
+            .collect();
+        let table: Vec<u16> = edges
+            .iter()
+            .map(|(left, right)| (*left as u16) << 8 | *right as u16)
+            .collect();
+        start_hdma(&mut gba, REG_WIN0H, &table);
+
+        let drawn = run_frame(&mut gba, &lines);
+        assert_eq!(drawn.len(), DISPLAY_HEIGHT);
+        for (y, (_, colors)) in drawn.iter().enumerate() {
+            let (left, right) = edges[y];
+            for (x, color) in colors.iter().enumerate() {
+                let expected = if x >= left && x < right { GREEN } else { BLUE };
+                assert_eq!(*color, expected, "line {} pixel {}", y, x);
+            }
+        }
+        hashes.push(frame_hash(&gba));
+    }
+    assert_ne!(hashes[0], hashes[1]);
+    assert_ne!(hashes[1], hashes[2]);
+}
@@ -1,5 +1,8 @@
 package com.mrmichel.rustboyadvance;
 
+import android.graphics.Bitmap;
+import android.view.Surface;
+
 /**
  * JNI wrapper to the rust core
  */
@@ -19,7 +22,7 @@ public class EmulatorBindings {
      * Open a new emulator context
      * @param bios bytearray of the GBA bios
      * @param rom bytearray of the rom to run
-     * @param frameBuffer frameBuffer render target
+     * @param frameBuffer unused, the frames are returned by runFrame
      * @param save_name name of the save file TODO remove this
      * @param skipBios skip bios
      * @return the emulator context to use pass to other methods in this class
@@ -30,7 +33,7 @@ public class EmulatorBindings {
     /**
      * Open a new emulator context from a saved state buffer
      * @param savedState
-     * @param frameBuffer
+     * @param frameBuffer unused, the frames are returned by runFrame
      * @return
      * @throws NativeBindingException
      */
@@ -59,6 +62,22 @@ public class EmulatorBindings {
      */
     public static native void runFrame(long ctx, int[] frame_buffer);
 
+    /**
+     * Runs the emulation for a single frame and draws it into a bitmap.
+     * @param ctx
+     * @param bitmap a mutable ARGB_8888 bitmap of 240x160
+     * @throws NativeBindingException
+     */
+    public static native void runFrameToBitmap(long ctx, Bitmap bitmap) throws NativeBindingException;
+
+    /**
+     * Runs the emulation for a single frame and posts it to a surface, which scales it to its view.
+     * @param ctx
+     * @param surface
+     * @throws NativeBindingException
+     */
+    public static native void runFrameToSurface(long ctx, Surface surface) throws NativeBindingException;
+
     /**
      * Collect pending audio samples
      * @param ctx
@@ -66,6 +85,15 @@ public class EmulatorBindings {
      */
     public static native short[] collectAudioSamples(long ctx);
 
+    /**
+     * Collect pending audio samples into a preallocated buffer, suitable for feeding an AudioTrack
+     * without allocating on every call.
+     * @param ctx
+     * @param buffer buffer to fill with interleaved stereo samples
+     * @return number of samples written into buffer
+     */
+    public static native int collectAudioSamplesInto(long ctx, short[] buffer);
+
     /**
      * @param ctx
      * @return The loaded ROM title
@@ -37,10 +37,11 @@ public class AudioThread extends Thread {
     public void run() {
         super.run();
 
+        short[] samples = new short[4096];
         while (!stopping) {
             if (enabled) {
-                short[] samples = emulator.collectAudioSamples();
-                audioTrack.write(samples, 0, samples.length);
+                int count = emulator.collectAudioSamples(samples);
+                audioTrack.write(samples, 0, count);
             }
         }
     }
@@ -48,6 +48,10 @@ public class Emulator {
         return EmulatorBindings.collectAudioSamples(ctx);
     }
 
+    public synchronized int collectAudioSamples(short[] buffer) {
+        return EmulatorBindings.collectAudioSamplesInto(ctx, buffer);
+    }
+
     public synchronized void setKeyState(int keyState) {
         EmulatorBindings.setKeyState(this.ctx, keyState);
     }
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
+
+#[macro_use]
+extern crate clap;
+
+#[macro_use]
+extern crate log;
+
+use bit::BitIndex;
+
+use rustboyadvance_core::keypad;
+use rustboyadvance_core::logging;
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::state_diff::StateDiff;
+
+mod script;
+use script::{Action, Script};
+
+/// Feeds keys from the script, audio & video are dropped
+struct ScriptedInput {
+    keyinput: u16,
+}
+
+impl InputSource for ScriptedInput {
+    fn poll(&mut self) -> u16 {
+        self.keyinput
+    }
+}
+
+fn run_action(
+    gba: &mut GameBoyAdvance,
+    hw: &Rc<RefCell<ScriptedInput>>,
+    action: &Action,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        Action::Press(keys) | Action::Release(keys) => {
+            let released = match action {
+                Action::Release(_) => true,
+                _ => false,
+            };
+            let mut hw = hw.borrow_mut();
+            for key in keys {
+                let key = keypad::Keys::try_from(key.as_str())?;
+                hw.keyinput.set_bit(key as usize, released);
+            }
+        }
+        Action::Screenshot(path) => {
+            gba.screenshot().save_png(Path::new(path), 1)?;
+            info!("screenshot saved to {}", path);
+        }
+        Action::SaveState(path) => {
+            let state = gba.save_state()?;
+            write_bin_file(Path::new(path), &state)?;
+            info!("savestate saved to {}", path);
+        }
+        Action::LoadState(path) => {
+            let state = read_bin_file(Path::new(path))?;
+            gba.restore_state(&state)?;
+            info!("savestate loaded from {}", path);
+        }
+        Action::DumpMemory(dump) => {
+            let bytes = gba.mem().read_bytes(dump.addr, dump.size)?;
+            write_bin_file(Path::new(&dump.path), &bytes)?;
+            info!(
+                "dumped {:#x} bytes from {:#010x} to {}",
+                dump.size, dump.addr, dump.path
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let yaml = load_yaml!("cli.yml");
+    let matches = clap::App::from_yaml(yaml).get_matches();
+
+    // the levels are the core's, per subsystem, so env_logger lets everything through
+    let logger = env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Trace)
+        .build();
+    logging::install(Box::new(logger))?;
+    logging::configure(matches.value_of("log").unwrap())?;
+
+    if let Some(matches) = matches.subcommand_matches("diff-states") {
+        let old = read_bin_file(Path::new(matches.value_of("old").unwrap()))?;
+        let new = read_bin_file(Path::new(matches.value_of("new").unwrap()))?;
+        let diff = StateDiff::new(&old, &new)?;
+        print!("{}", diff);
+        if !diff.is_empty() {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let skip_bios = matches.occurrences_of("skip_bios") != 0;
+    let bios_path = Path::new(matches.value_of("bios").unwrap_or_default());
+    let rom_path = Path::new(matches.value_of("game_rom").unwrap());
+
+    let script = match matches.value_of("script") {
+        Some(path) => Script::load(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+        None => Script::default(),
+    };
+
+    let frames = match matches.value_of("frames") {
+        Some(frames) => frames.parse::<usize>()?,
+        None => script.last_frame(),
+    };
+
+    let bios_bin = match read_bin_file(bios_path) {
+        Ok(bios) => bios,
+        #[cfg(feature = "builtin-bios")]
+        Err(_) if !bios_path.exists() => {
+            info!("{} not found, using the built-in bios", bios_path.display());
+            rustboyadvance_core::bios::builtin_bios_image().to_vec()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let gamepak = GamepakBuilder::new()
+        .file(rom_path)
+        .without_backup_to_file()
+        .build()?;
+
+    let hw = Rc::new(RefCell::new(ScriptedInput {
+        keyinput: keypad::KEYINPUT_ALL_RELEASED,
+    }));
+
+    let mut gba = GameBoyAdvance::new(
+        bios_bin.into_boxed_slice(),
+        gamepak,
+        Frontend::new().input(hw.clone()),
+    );
+
+    if skip_bios {
+        gba.skip_bios();
+    }
+
+    if let Some(fill) = matches.value_of("ram_fill") {
+        let fill = MemoryFill::try_from(fill)?;
+        gba.apply_init_options(&InitOptions::with_fill(fill));
+    }
+
+    info!("running {} for {} frames", rom_path.display(), frames);
+
+    let mut events = script.events.iter().peekable();
+    for frame in 0..frames {
+        while let Some(event) = events.peek() {
+            if event.frame > frame {
+                break;
+            }
+            run_action(&mut gba, &hw, &event.action)?;
+            events.next();
+        }
+        gba.frame();
+    }
+
+    // events scheduled at (or after) the last frame run once emulation is done
+    for event in events {
+        run_action(&mut gba, &hw, &event.action)?;
+    }
+
+    Ok(())
+}
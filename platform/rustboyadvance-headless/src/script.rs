@@ -0,0 +1,72 @@
+//! Script format for headless runs.
+//!
+//! A script is a list of events, each executed right before the emulator runs the given frame.
+//! Example (JSON):
+//! ```json
+//! {
+//!     "frames": 600,
+//!     "events": [
+//!         { "frame": 120, "action": { "press": ["start"] } },
+//!         { "frame": 125, "action": { "release": ["start"] } },
+//!         { "frame": 599, "action": { "screenshot": "out.png" } },
+//!         { "frame": 599, "action": { "dump_memory": { "addr": 33554432, "size": 256, "path": "ewram.bin" } } }
+//!     ]
+//! }
+//! ```
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Script {
+    /// How many frames to run, defaults to one frame past the last event
+    pub frames: Option<usize>,
+    #[serde(default)]
+    pub events: Vec<ScriptEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScriptEvent {
+    pub frame: usize,
+    pub action: Action,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemoryDump {
+    pub addr: u32,
+    pub size: u32,
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Press(Vec<String>),
+    Release(Vec<String>),
+    Screenshot(String),
+    SaveState(String),
+    LoadState(String),
+    DumpMemory(MemoryDump),
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read script {:?}: {}", path, e))?;
+        let mut script: Script = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| e.to_string())?,
+            _ => serde_json::from_str(&text).map_err(|e| e.to_string())?,
+        };
+        // keep events stable-sorted by frame so they fire in file order within a frame
+        script.events.sort_by_key(|e| e.frame);
+        Ok(script)
+    }
+
+    pub fn last_frame(&self) -> usize {
+        match self.frames {
+            Some(frames) => frames,
+            None => self.events.last().map(|e| e.frame + 1).unwrap_or(0),
+        }
+    }
+}
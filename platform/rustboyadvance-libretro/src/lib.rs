@@ -45,9 +45,9 @@ impl HwInterface {
 }
 
 // do nothing here, everything is handled in the libretro_backend::Core impl
-impl VideoInterface for HwInterface {}
+impl VideoSink for HwInterface {}
 
-impl AudioInterface for HwInterface {
+impl AudioSink for HwInterface {
     fn push_sample(&mut self, samples: StereoSample<i16>) {
         let prod = self.audio_ring_buffer.producer();
         prod.push(samples.0).unwrap();
@@ -55,7 +55,7 @@ impl AudioInterface for HwInterface {
     }
 }
 
-impl InputInterface for HwInterface {
+impl InputSource for HwInterface {
     fn poll(&mut self) -> u16 {
         self.key_state
     }
@@ -122,9 +122,7 @@ impl libretro_backend::Core for RustBoyAdvanceCore {
                 let gba = GameBoyAdvance::new(
                     bios.into_boxed_slice(),
                     gamepak,
-                    hwif.clone(),
-                    hwif.clone(),
-                    hwif.clone(),
+                    Frontend::from_device(hwif.clone()),
                 );
 
                 self.hwif = Some(hwif);
@@ -2,7 +2,6 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use std::path::Path;
-use std::time;
 
 #[macro_use]
 extern crate clap;
@@ -19,13 +18,13 @@ struct MiniFb {
     window: minifb::Window,
 }
 
-impl VideoInterface for MiniFb {
+impl VideoSink for MiniFb {
     fn render(&mut self, buffer: &[u32]) {
         self.window.update_with_buffer(buffer).unwrap();
     }
 }
 
-impl InputInterface for MiniFb {
+impl InputSource for MiniFb {
     fn poll(&mut self) -> u16 {
         let mut keyinput = keypad::KEYINPUT_ALL_RELEASED;
         keyinput.set_bit(keypad::Keys::Up as usize, !self.window.is_key_down(Key::Up));
@@ -69,7 +68,7 @@ impl InputInterface for MiniFb {
     }
 }
 
-impl AudioInterface for MiniFb {
+impl AudioSink for MiniFb {
     fn get_sample_rate(&self) -> i32 {
         0
     }
@@ -107,19 +106,19 @@ fn main() {
     let mut gba = GameBoyAdvance::new(
         bios_bin.into_boxed_slice(),
         cart,
-        minifb.clone(),
-        minifb.clone(),
-        minifb.clone(),
+        Frontend::from_device(minifb.clone()),
     );
 
     if skip_bios {
         gba.skip_bios();
     }
 
-    let frame_time = time::Duration::new(0, 1_000_000_000u32 / 60);
+    let mut limiter = FrameLimiter::new(if no_framerate_limit {
+        PacingMode::Unlimited
+    } else {
+        PacingMode::Native
+    });
     loop {
-        let start_time = time::Instant::now();
-
         gba.frame();
 
         if let Some(fps) = fps_counter.tick() {
@@ -128,15 +127,6 @@ fn main() {
             minifb.borrow_mut().window.set_title(&title);
         }
 
-        if !no_framerate_limit {
-            let time_passed = start_time.elapsed();
-            let delay = frame_time.checked_sub(time_passed);
-            match delay {
-                None => {}
-                Some(delay) => {
-                    ::std::thread::sleep(delay);
-                }
-            };
-        }
+        limiter.wait();
     }
 }
@@ -0,0 +1,31 @@
+// A simple CRT look: scanlines and an aperture grille. The GBA never had a CRT, but some like
+// it anyway.
+#if defined(VERTEX)
+in vec2 position;
+in vec2 tex_coord;
+out vec2 uv;
+
+void main() {
+    uv = tex_coord;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+#elif defined(FRAGMENT)
+uniform sampler2D source;
+uniform vec2 source_size;
+in vec2 uv;
+out vec4 color;
+
+const float PI = 3.14159265;
+const float SCANLINE_STRENGTH = 0.35;
+const float MASK_DARK = 0.8;
+const float BRIGHTNESS = 1.15;
+
+void main() {
+    vec2 texel = uv * source_size;
+    vec3 rgb = texture(source, (floor(texel) + 0.5) / source_size).rgb;
+    float scanline = 1.0 - SCANLINE_STRENGTH + SCANLINE_STRENGTH * sin(fract(texel.y) * PI);
+    vec3 mask = vec3(MASK_DARK);
+    mask[int(gl_FragCoord.x) % 3] = 1.0;
+    color = vec4(pow(rgb, vec3(1.1)) * scanline * mask * BRIGHTNESS, 1.0);
+}
+#endif
@@ -0,0 +1,32 @@
+// The pixel grid of the GBA's LCD: a darker line between the pixels, once they are at least
+// 3 screen pixels wide.
+#if defined(VERTEX)
+in vec2 position;
+in vec2 tex_coord;
+out vec2 uv;
+
+void main() {
+    uv = tex_coord;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+#elif defined(FRAGMENT)
+uniform sampler2D source;
+uniform vec2 source_size;
+uniform vec2 output_size;
+in vec2 uv;
+out vec4 color;
+
+const float GRID_STRENGTH = 0.35;
+
+void main() {
+    vec2 texel = uv * source_size;
+    vec2 pixel_size = output_size / source_size;
+    vec3 rgb = texelFetch(source, min(ivec2(texel), ivec2(source_size) - 1), 0).rgb;
+    if (min(pixel_size.x, pixel_size.y) >= 3.0) {
+        // the last screen pixel of every GBA pixel, right and bottom
+        vec2 edge = step(1.0 - 1.0 / pixel_size, fract(texel));
+        rgb *= 1.0 - GRID_STRENGTH * max(edge.x, edge.y);
+    }
+    color = vec4(rgb, 1.0);
+}
+#endif
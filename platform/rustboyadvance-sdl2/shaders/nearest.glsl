@@ -0,0 +1,21 @@
+// Plain pixels, every GBA pixel is drawn as a block of screen pixels.
+#if defined(VERTEX)
+in vec2 position;
+in vec2 tex_coord;
+out vec2 uv;
+
+void main() {
+    uv = tex_coord;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+#elif defined(FRAGMENT)
+uniform sampler2D source;
+uniform vec2 source_size;
+in vec2 uv;
+out vec4 color;
+
+void main() {
+    ivec2 texel = min(ivec2(uv * source_size), ivec2(source_size) - 1);
+    color = vec4(texelFetch(source, texel, 0).rgb, 1.0);
+}
+#endif
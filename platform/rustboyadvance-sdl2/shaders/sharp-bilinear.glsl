@@ -0,0 +1,27 @@
+// Sharp pixels at any scale: the picture is scaled up by a whole factor with nearest neighbour,
+// only the remaining fraction is interpolated, which hides the uneven pixel sizes.
+#if defined(VERTEX)
+in vec2 position;
+in vec2 tex_coord;
+out vec2 uv;
+
+void main() {
+    uv = tex_coord;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+#elif defined(FRAGMENT)
+uniform sampler2D source;
+uniform vec2 source_size;
+uniform vec2 output_size;
+in vec2 uv;
+out vec4 color;
+
+void main() {
+    vec2 texel = uv * source_size;
+    vec2 scale = max(floor(output_size / source_size), vec2(1.0));
+    vec2 region = 0.5 - 0.5 / scale;
+    vec2 center_distance = fract(texel) - 0.5;
+    vec2 f = (center_distance - clamp(center_distance, -region, region)) * scale + 0.5;
+    color = vec4(texture(source, (floor(texel) + f) / source_size).rgb, 1.0);
+}
+#endif
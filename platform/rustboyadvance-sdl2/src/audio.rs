@@ -1,11 +1,14 @@
 use sdl2;
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpec, AudioSpecDesired};
 
-use rustboyadvance_core::{AudioInterface, StereoSample};
+use rustboyadvance_core::{AudioSink, StereoSample};
 
 use ringbuf;
 use ringbuf::{Consumer, Producer, RingBuffer};
 
+#[cfg(feature = "ffmpeg")]
+use super::recorder::{self, SharedRecorder};
+
 struct GbaAudioCallback {
     consumer: Consumer<StereoSample<i16>>,
     spec: AudioSpec,
@@ -15,6 +18,8 @@ pub struct Sdl2AudioPlayer {
     _device: AudioDevice<GbaAudioCallback>,
     producer: Producer<StereoSample<i16>>,
     freq: i32,
+    #[cfg(feature = "ffmpeg")]
+    pub recorder: SharedRecorder,
 }
 
 impl AudioCallback for GbaAudioCallback {
@@ -35,13 +40,15 @@ impl AudioCallback for GbaAudioCallback {
     }
 }
 
-impl AudioInterface for Sdl2AudioPlayer {
+impl AudioSink for Sdl2AudioPlayer {
     fn get_sample_rate(&self) -> i32 {
         self.freq
     }
 
     fn push_sample(&mut self, sample: StereoSample<i16>) {
         #![allow(unused_must_use)]
+        #[cfg(feature = "ffmpeg")]
+        recorder::record_sample(&self.recorder, sample);
         self.producer.push(sample);
     }
 }
@@ -85,5 +92,7 @@ pub fn create_audio_player(sdl: &sdl2::Sdl) -> Sdl2AudioPlayer {
         _device: device,
         freq,
         producer: producer.unwrap(),
+        #[cfg(feature = "ffmpeg")]
+        recorder: Default::default(),
     }
 }
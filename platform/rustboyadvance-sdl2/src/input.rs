@@ -2,46 +2,273 @@ use sdl2::controller::Axis;
 use sdl2::controller::Button;
 use sdl2::keyboard::Scancode;
 
+use serde::Deserialize;
+
 use rustboyadvance_core::keypad as gba_keypad;
-use rustboyadvance_core::InputInterface;
+use rustboyadvance_core::InputSource;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
 
 use bit;
 use bit::BitIndex;
 
+const DEFAULT_KEYBINDINGS: &str = include_str!("../keybindings.toml");
+
+/// Frontend actions that don't map to a GBA key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    FastForward,
+    Rewind,
+    ToggleRecording,
+    Screenshot,
+    NextShader,
+    RecentRoms,
+    SaveState(usize),
+    LoadState(usize),
+    /// Goes back to the game as it was before the last load
+    UndoLoadState,
+    /// Puts back the slot the last save overwrote
+    UndoSaveState,
+    /// Power-cycles the console
+    Reset,
+    Pause,
+    /// Pauses and runs a single frame
+    FrameAdvance,
+    SlowMotion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Key(gba_keypad::Keys),
+    Turbo(gba_keypad::Keys),
+    Hotkey(Hotkey),
+}
+
+/// A set of bindings as they appear in the keybindings file, inputs are kept as names
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct BindingsConfig {
+    keys: HashMap<String, String>,
+    turbo: HashMap<String, String>,
+    fast_forward: Option<String>,
+    rewind: Option<String>,
+    record: Option<String>,
+    screenshot: Option<String>,
+    next_shader: Option<String>,
+    recent_roms: Option<String>,
+    save_state: Vec<String>,
+    load_state: Vec<String>,
+    undo_load_state: Option<String>,
+    undo_save_state: Option<String>,
+    reset: Option<String>,
+    pause: Option<String>,
+    frame_advance: Option<String>,
+    slow_motion: Option<String>,
+}
+
+impl BindingsConfig {
+    fn bindings(&self) -> Result<Vec<(Binding, &str)>, String> {
+        let mut result = Vec::new();
+        for (key, input) in &self.keys {
+            let key = gba_keypad::Keys::try_from(key.as_str())?;
+            result.push((Binding::Key(key), input.as_str()));
+        }
+        for (key, input) in &self.turbo {
+            let key = gba_keypad::Keys::try_from(key.as_str())?;
+            result.push((Binding::Turbo(key), input.as_str()));
+        }
+        if let Some(input) = &self.fast_forward {
+            result.push((Binding::Hotkey(Hotkey::FastForward), input.as_str()));
+        }
+        if let Some(input) = &self.rewind {
+            result.push((Binding::Hotkey(Hotkey::Rewind), input.as_str()));
+        }
+        if let Some(input) = &self.record {
+            result.push((Binding::Hotkey(Hotkey::ToggleRecording), input.as_str()));
+        }
+        if let Some(input) = &self.screenshot {
+            result.push((Binding::Hotkey(Hotkey::Screenshot), input.as_str()));
+        }
+        if let Some(input) = &self.next_shader {
+            result.push((Binding::Hotkey(Hotkey::NextShader), input.as_str()));
+        }
+        if let Some(input) = &self.recent_roms {
+            result.push((Binding::Hotkey(Hotkey::RecentRoms), input.as_str()));
+        }
+        for (slot, input) in self.save_state.iter().enumerate() {
+            result.push((Binding::Hotkey(Hotkey::SaveState(slot)), input.as_str()));
+        }
+        for (slot, input) in self.load_state.iter().enumerate() {
+            result.push((Binding::Hotkey(Hotkey::LoadState(slot)), input.as_str()));
+        }
+        if let Some(input) = &self.undo_load_state {
+            result.push((Binding::Hotkey(Hotkey::UndoLoadState), input.as_str()));
+        }
+        if let Some(input) = &self.undo_save_state {
+            result.push((Binding::Hotkey(Hotkey::UndoSaveState), input.as_str()));
+        }
+        if let Some(input) = &self.reset {
+            result.push((Binding::Hotkey(Hotkey::Reset), input.as_str()));
+        }
+        if let Some(input) = &self.pause {
+            result.push((Binding::Hotkey(Hotkey::Pause), input.as_str()));
+        }
+        if let Some(input) = &self.frame_advance {
+            result.push((Binding::Hotkey(Hotkey::FrameAdvance), input.as_str()));
+        }
+        if let Some(input) = &self.slow_motion {
+            result.push((Binding::Hotkey(Hotkey::SlowMotion), input.as_str()));
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct KeymapConfig {
+    turbo_period: Option<u32>,
+    keyboard: BindingsConfig,
+    controller: BindingsConfig,
+}
+
+/// Maps keyboard scancodes and game controller buttons to bindings.
+///
+/// Each binding has at most one keyboard input and one controller input,
+/// binding an input to an already bound action replaces the previous input.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    keyboard: HashMap<Scancode, Binding>,
+    controller: HashMap<Button, Binding>,
+    turbo_period: u32,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keymap = Keymap {
+            keyboard: HashMap::new(),
+            controller: HashMap::new(),
+            turbo_period: 1,
+        };
+        let config = toml::from_str(DEFAULT_KEYBINDINGS).expect("invalid default keybindings");
+        keymap.apply(config).expect("invalid default keybindings");
+        keymap
+    }
+}
+
+impl Keymap {
+    /// Loads the default keymap, overridden by the bindings found in `path`
+    pub fn load(path: &Path) -> Result<Keymap, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+        let mut keymap = Keymap::default();
+        keymap.apply(config)?;
+        Ok(keymap)
+    }
+
+    /// Overrides bindings from a TOML value in the keybindings file format
+    pub fn apply_toml(&mut self, value: toml::Value) -> Result<(), String> {
+        let config: KeymapConfig = value.try_into().map_err(|e| e.to_string())?;
+        self.apply(config)
+    }
+
+    fn apply(&mut self, config: KeymapConfig) -> Result<(), String> {
+        if let Some(turbo_period) = config.turbo_period {
+            self.turbo_period = turbo_period.max(1);
+        }
+        for (binding, name) in config.keyboard.bindings()? {
+            let scancode = Scancode::from_name(name)
+                .ok_or_else(|| format!("{} is not a valid keyboard scancode", name))?;
+            self.bind_key(scancode, binding);
+        }
+        for (binding, name) in config.controller.bindings()? {
+            let button = Button::from_string(name)
+                .ok_or_else(|| format!("{} is not a valid controller button", name))?;
+            self.bind_button(button, binding);
+        }
+        Ok(())
+    }
+
+    pub fn bind_key(&mut self, scancode: Scancode, binding: Binding) {
+        self.keyboard.retain(|_, b| *b != binding);
+        self.keyboard.insert(scancode, binding);
+    }
+
+    pub fn bind_button(&mut self, button: Button, binding: Binding) {
+        self.controller.retain(|_, b| *b != binding);
+        self.controller.insert(button, binding);
+    }
+}
+
 pub struct Sdl2Input {
+    /// Can be modified at runtime to remap inputs
+    pub keymap: Keymap,
     keyinput: u16,
     axis_keyinput: u16,
+    /// Bitmask of keys with a turbo input held down
+    turbo_keys: u16,
+    frame_counter: u32,
 }
 
-impl InputInterface for Sdl2Input {
+impl InputSource for Sdl2Input {
     fn poll(&mut self) -> u16 {
-        !(!self.keyinput | !self.axis_keyinput)
+        // Called once per frame, turbo keys are pressed for `turbo_period` frames and then released
+        let turbo_phase = (self.frame_counter / self.keymap.turbo_period) % 2 == 0;
+        self.frame_counter = self.frame_counter.wrapping_add(1);
+        let turbo_keyinput = if turbo_phase {
+            !self.turbo_keys
+        } else {
+            gba_keypad::KEYINPUT_ALL_RELEASED
+        };
+        !(!self.keyinput | !self.axis_keyinput | !turbo_keyinput)
     }
 }
 
 impl Sdl2Input {
-    pub fn on_keyboard_key_down(&mut self, scancode: Scancode) {
-        if let Some(key) = scancode_to_keypad(scancode) {
-            self.keyinput.set_bit(key as usize, false);
+    /// Updates the key state, returning the hotkey bound to `binding` if any
+    fn on_binding(&mut self, binding: Binding, pressed: bool) -> Option<Hotkey> {
+        match binding {
+            Binding::Key(key) => {
+                self.keyinput.set_bit(key as usize, !pressed);
+                None
+            }
+            Binding::Turbo(key) => {
+                self.turbo_keys.set_bit(key as usize, pressed);
+                None
+            }
+            Binding::Hotkey(hotkey) => Some(hotkey),
         }
     }
 
-    pub fn on_keyboard_key_up(&mut self, scancode: Scancode) {
-        if let Some(key) = scancode_to_keypad(scancode) {
-            self.keyinput.set_bit(key as usize, true);
-        }
+    pub fn on_keyboard_key_down(&mut self, scancode: Scancode) -> Option<Hotkey> {
+        let binding = *self.keymap.keyboard.get(&scancode)?;
+        self.on_binding(binding, true)
     }
 
-    pub fn on_controller_button_down(&mut self, button: Button) {
-        if let Some(key) = controller_button_to_keypad(button) {
-            self.keyinput.set_bit(key as usize, false);
-        }
+    pub fn on_keyboard_key_up(&mut self, scancode: Scancode) -> Option<Hotkey> {
+        let binding = *self.keymap.keyboard.get(&scancode)?;
+        self.on_binding(binding, false)
     }
 
-    pub fn on_controller_button_up(&mut self, button: Button) {
-        if let Some(key) = controller_button_to_keypad(button) {
-            self.keyinput.set_bit(key as usize, true);
-        }
+    pub fn on_controller_button_down(&mut self, button: Button) -> Option<Hotkey> {
+        let binding = *self.keymap.controller.get(&button)?;
+        self.on_binding(binding, true)
+    }
+
+    pub fn on_controller_button_up(&mut self, button: Button) -> Option<Hotkey> {
+        let binding = *self.keymap.controller.get(&button)?;
+        self.on_binding(binding, false)
+    }
+
+    /// Releases everything, used when the controller that held the keys goes away
+    pub fn release_all(&mut self) {
+        self.keyinput = gba_keypad::KEYINPUT_ALL_RELEASED;
+        self.axis_keyinput = gba_keypad::KEYINPUT_ALL_RELEASED;
+        self.turbo_keys = 0;
     }
 
     pub fn on_axis_motion(&mut self, axis: Axis, val: i16) {
@@ -70,41 +297,12 @@ impl Sdl2Input {
     }
 }
 
-fn scancode_to_keypad(scancode: Scancode) -> Option<gba_keypad::Keys> {
-    match scancode {
-        Scancode::Up => Some(gba_keypad::Keys::Up),
-        Scancode::Down => Some(gba_keypad::Keys::Down),
-        Scancode::Left => Some(gba_keypad::Keys::Left),
-        Scancode::Right => Some(gba_keypad::Keys::Right),
-        Scancode::Z => Some(gba_keypad::Keys::ButtonB),
-        Scancode::X => Some(gba_keypad::Keys::ButtonA),
-        Scancode::Return => Some(gba_keypad::Keys::Start),
-        Scancode::Backspace => Some(gba_keypad::Keys::Select),
-        Scancode::A => Some(gba_keypad::Keys::ButtonL),
-        Scancode::S => Some(gba_keypad::Keys::ButtonR),
-        _ => None,
-    }
-}
-
-fn controller_button_to_keypad(button: Button) -> Option<gba_keypad::Keys> {
-    match button {
-        Button::DPadUp => Some(gba_keypad::Keys::Up),
-        Button::DPadDown => Some(gba_keypad::Keys::Down),
-        Button::DPadLeft => Some(gba_keypad::Keys::Left),
-        Button::DPadRight => Some(gba_keypad::Keys::Right),
-        Button::A => Some(gba_keypad::Keys::ButtonB), // A and B are swapped compared to the SDL layout
-        Button::B => Some(gba_keypad::Keys::ButtonA),
-        Button::Start => Some(gba_keypad::Keys::Start),
-        Button::Back => Some(gba_keypad::Keys::Select),
-        Button::LeftShoulder => Some(gba_keypad::Keys::ButtonL),
-        Button::RightShoulder => Some(gba_keypad::Keys::ButtonR),
-        _ => None,
-    }
-}
-
-pub fn create_input() -> Sdl2Input {
+pub fn create_input(keymap: Keymap) -> Sdl2Input {
     Sdl2Input {
+        keymap,
         keyinput: gba_keypad::KEYINPUT_ALL_RELEASED,
         axis_keyinput: gba_keypad::KEYINPUT_ALL_RELEASED,
+        turbo_keys: 0,
+        frame_counter: 0,
     }
 }
@@ -1,5 +1,5 @@
 use sdl2;
-use sdl2::controller::Button;
+use sdl2::controller::GameController;
 use sdl2::event::{Event, WindowEvent};
 use sdl2::image::{InitFlag, LoadSurface, LoadTexture};
 use sdl2::keyboard::Scancode;
@@ -10,10 +10,8 @@ use sdl2::surface::Surface;
 
 use sdl2::EventPump;
 
-use bytesize;
-use spin_sleep;
-
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use std::fs;
@@ -34,25 +32,223 @@ use flexi_logger::*;
 
 mod audio;
 mod input;
+mod recent;
+#[cfg(feature = "ffmpeg")]
+mod recorder;
+mod rewind;
+mod shader;
 mod video;
 
 use audio::create_audio_player;
-use input::create_input;
-use video::{create_video_interface, SCREEN_HEIGHT, SCREEN_WIDTH};
-
-use rustboyadvance_core::cartridge::BackupType;
+use input::{create_input, Hotkey, Keymap};
+use recent::{RecentMenu, RecentRoms};
+#[cfg(feature = "ffmpeg")]
+use recorder::{Recorder, RecordingFormat, SharedRecorder};
+use rewind::RewindBuffer;
+use shader::Shader;
+use video::{create_video_interface, ScaleMode, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use rustboyadvance_core::cartridge::{Cartridge, SaveLocation};
+use rustboyadvance_core::config::Config;
+use rustboyadvance_core::determinism::{verify_determinism, DeterminismCheck};
 use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::profile::GameProfiles;
+use rustboyadvance_core::rom_db::RomDatabase;
+use rustboyadvance_core::rom_watch::{ReloadMode, RomWatcher};
+use rustboyadvance_core::save_slots::SaveSlotManager;
+#[cfg(feature = "lua")]
+use rustboyadvance_core::scripting::ScriptHost;
+use rustboyadvance_core::uart_bridge::UartBridge;
 use rustboyadvance_core::util::spawn_and_run_gdb_server;
 use rustboyadvance_core::util::FpsCounter;
 
 const LOG_DIR: &str = ".logs";
 const DEFAULT_GDB_SERVER_ADDR: &'static str = "localhost:1337";
+const DEFAULT_CONFIG_FILE: &str = "rustboyadvance.toml";
+const DEFAULT_KEYMAP_FILE: &str = "keybindings.toml";
+const DEFAULT_PROFILES_DIR: &str = "profiles";
+const RECENT_ROMS_FILE: &str = "recent_roms.txt";
+
+const REWIND_BUFFER_BYTES: usize = 256 * 1024 * 1024;
+const REWIND_INTERVAL_FRAMES: usize = 10;
+/// How long to wait for input while the game sleeps, before running the loop again anyway
+const SLEEP_WAIT_MS: u32 = 100;
+/// The speed while the slow motion hotkey is held
+const SLOW_MOTION: f32 = 0.5;
 
 const CANVAS_WIDTH: u32 = SCREEN_WIDTH;
 const CANVAS_HEIGHT: u32 = SCREEN_HEIGHT;
 
-fn get_savestate_path(rom_filename: &Path) -> PathBuf {
-    rom_filename.with_extension("savestate")
+/// The savestate slots of a rom, in `paths.state_dir` or a `states` directory next to the rom
+fn open_save_slots(config: &Config, rom_path: &Path) -> CoreResult<SaveSlotManager> {
+    let states_dir = match &config.paths.state_dir {
+        Some(dir) => dir.clone(),
+        None => rom_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("states"),
+    };
+    let game = rom_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    SaveSlotManager::new(&states_dir, &game)
+}
+
+/// File name for on-screen messages
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// The keymap with the control mappings of the game profile applied on top
+fn get_game_keymap(keymap: &Keymap, gba: &GameBoyAdvance) -> Result<Keymap, String> {
+    let mut game_keymap = keymap.clone();
+    let profile = gba.sysbus.cartridge.profile.as_ref();
+    if let Some(controls) = profile.and_then(|profile| profile.controls.as_ref()) {
+        game_keymap.apply_toml(controls.clone())?;
+    }
+    Ok(game_keymap)
+}
+
+/// The settings given on the command line, in the config file format
+fn command_line_config(matches: &clap::ArgMatches) -> toml::Value {
+    let mut sections = toml::value::Table::new();
+    let mut set = |section: &str, key: &str, value: toml::Value| {
+        sections
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(Default::default()))
+            .as_table_mut()
+            .unwrap()
+            .insert(key.to_string(), value);
+    };
+
+    let values = [
+        ("paths", "bios", "bios"),
+        ("paths", "save_dir", "save_dir"),
+        ("paths", "state_dir", "state_dir"),
+        ("paths", "profiles", "profiles"),
+        ("paths", "rom_database", "dat"),
+        ("emulation", "save_type", "save_type"),
+        ("emulation", "state_save", "state_save"),
+        ("emulation", "rom_reload", "rom_reload"),
+        ("emulation", "bios_check", "bios_check"),
+        ("emulation", "link_cable", "link_cable"),
+        ("emulation", "uart_bridge", "uart"),
+        ("emulation", "peripheral", "peripheral"),
+        ("video", "scale", "scale"),
+        ("audio", "interpolation", "audio_interpolation"),
+        ("input", "keymap", "keymap"),
+        ("input", "dpad_filter", "dpad_filter"),
+    ];
+    for (section, key, arg) in values.iter() {
+        if let Some(value) = matches.value_of(arg) {
+            set(section, key, value.into());
+        }
+    }
+
+    let flags = [
+        ("emulation", "skip_bios", "skip_bios"),
+        ("emulation", "rtc", "rtc"),
+        ("emulation", "rom_flash", "rom_flash"),
+        ("emulation", "watch_save", "watch_save"),
+        ("emulation", "force_state_load", "force_state_load"),
+        ("emulation", "watch_rom", "watch_rom"),
+        ("video", "show_fps", "show_fps"),
+    ];
+    for (section, key, arg) in flags.iter() {
+        if matches.is_present(arg) {
+            set(section, key, true.into());
+        }
+    }
+
+    if let Some(shaders) = matches.values_of("shader") {
+        set("video", "shaders", shaders.collect::<Vec<_>>().into());
+    }
+
+    toml::Value::Table(sections)
+}
+
+fn build_gamepak(
+    rom_path: &Path,
+    config: &Config,
+    profiles: &GameProfiles,
+    rom_database: &RomDatabase,
+) -> Result<Cartridge, Box<dyn std::error::Error>> {
+    let mut builder = GamepakBuilder::new()
+        .save_type(config.save_type())
+        .file(rom_path)
+        .profiles(profiles)
+        .rom_database(rom_database);
+
+    // a mapping would see the file change under it while it is rebuilt
+    if !config.emulation.watch_rom {
+        builder = builder.mmap();
+    }
+
+    if config.emulation.rtc {
+        builder = builder.with_rtc();
+    }
+
+    if config.emulation.rom_flash {
+        builder = builder.with_rom_flash();
+    }
+
+    if let Some(kind) = config.peripheral() {
+        builder = builder.with_peripheral(kind);
+    }
+
+    if let Some(save_dir) = &config.paths.save_dir {
+        builder = builder.save_location(SaveLocation::Directory(save_dir.clone()));
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Builds the cartridge for `rom_path` with the settings of the game, which are returned too
+fn build_game_gamepak(
+    rom_path: &Path,
+    config: &Config,
+    profiles: &GameProfiles,
+    rom_database: &RomDatabase,
+) -> Result<(Cartridge, Config), Box<dyn std::error::Error>> {
+    let mut gamepak = build_gamepak(rom_path, config, profiles, rom_database)?;
+
+    // the game code is only known once the cartridge is built
+    let game_config = config.for_game(&gamepak.header.game_code)?;
+    if game_config.emulation != config.emulation || game_config.paths != config.paths {
+        gamepak = build_gamepak(rom_path, &game_config, profiles, rom_database)?;
+    }
+
+    Ok((gamepak, game_config))
+}
+
+/// Builds the emulator for `rom_path` with the settings of the game, the game keymap is left
+/// to the caller
+fn load_game(
+    rom_path: &Path,
+    bios: &[u8],
+    config: &Config,
+    profiles: &GameProfiles,
+    rom_database: &RomDatabase,
+    frontend: Frontend,
+    uart_bridge: Option<&UartBridge>,
+) -> Result<GameBoyAdvance, Box<dyn std::error::Error>> {
+    let (gamepak, game_config) = build_game_gamepak(rom_path, config, profiles, rom_database)?;
+
+    let mut builder = GbaBuilder::new(gamepak)
+        .bios(bios.to_vec())
+        .bios_check(game_config.bios_check())
+        .frontend(frontend);
+    if let Some(bridge) = uart_bridge {
+        builder = builder.serial_device(Box::new(bridge.clone()));
+    }
+    if game_config.emulation.skip_bios {
+        builder = builder.skip_bios();
+    }
+
+    Ok(builder.config(game_config).build()?)
 }
 
 /// Waits for the user to drag a rom file to window
@@ -98,6 +294,7 @@ fn wait_for_rom(canvas: &mut WindowCanvas, event_pump: &mut EventPump) -> Result
     }
 }
 
+#[cfg(not(feature = "builtin-bios"))]
 fn ask_download_bios() {
     const OPEN_SOURCE_BIOS_URL: &'static str =
         "https://github.com/Nebuleon/ReGBA/raw/master/bios/gba_bios.bin";
@@ -115,22 +312,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .start()
         .unwrap();
 
-    let mut frame_limiter = true;
     let yaml = load_yaml!("cli.yml");
     let matches = clap::App::from_yaml(yaml).get_matches();
 
-    let bios_path = Path::new(matches.value_of("bios").unwrap_or_default());
-    let bios_bin = match read_bin_file(bios_path) {
+    let config_path = Path::new(matches.value_of("config").unwrap_or(DEFAULT_CONFIG_FILE));
+    let mut config = if config_path.is_file() {
+        Config::load(config_path)?
+    } else {
+        Config::default()
+    };
+    config.merge_toml(command_line_config(&matches))?;
+    if matches.is_present("save_config") {
+        config.save(config_path)?;
+        info!("Saved the settings to {}", config_path.display());
+    }
+
+    let bios_bin = match read_bin_file(&config.paths.bios) {
         Ok(bios) => bios,
-        _ => {
+        #[cfg(feature = "builtin-bios")]
+        Err(_) => {
+            info!(
+                "{} not found, using the built-in bios",
+                config.paths.bios.display()
+            );
+            rustboyadvance_core::bios::builtin_bios_image().to_vec()
+        }
+        #[cfg(not(feature = "builtin-bios"))]
+        Err(_) => {
             ask_download_bios();
             std::process::exit(0);
         }
     };
 
-    let skip_bios = matches.occurrences_of("skip_bios") != 0;
+    let show_fps = config.video.show_fps;
 
-    let debug = matches.occurrences_of("debug") != 0;
+    let debug = matches.is_present("debug") || matches.is_present("debug_script");
     let with_gdbserver = matches.occurrences_of("with_gdbserver") != 0;
 
     info!("Initializing SDL2 context");
@@ -158,60 +374,126 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         include_str!("../../../external/SDL_GameControllerDB/gamecontrollerdb.txt");
     controller_subsystem.load_mappings_from_read(&mut Cursor::new(controller_mappings))?;
 
-    let available_controllers = (0..controller_subsystem.num_joysticks()?)
-        .filter(|&id| controller_subsystem.is_game_controller(id))
-        .collect::<Vec<u32>>();
-
-    let mut active_controller = match available_controllers.first() {
-        Some(&id) => {
+    // All connected controllers are kept open, indexed by their instance id
+    let mut controllers: HashMap<i32, GameController> = HashMap::new();
+    for id in 0..controller_subsystem.num_joysticks()? {
+        if controller_subsystem.is_game_controller(id) {
             let controller = controller_subsystem.open(id)?;
             info!("Found game controller: {}", controller.name());
-            Some(controller)
+            controllers.insert(controller.instance_id(), controller);
         }
-        _ => {
-            info!("No game controllers were found");
-            None
+    }
+    if controllers.is_empty() {
+        info!("No game controllers were found");
+    }
+
+    let keymap = match &config.input.keymap {
+        Some(path) => Keymap::load(path)?,
+        None if Path::new(DEFAULT_KEYMAP_FILE).is_file() => {
+            Keymap::load(Path::new(DEFAULT_KEYMAP_FILE))?
+        }
+        None => Keymap::default(),
+    };
+
+    let profiles = match &config.paths.profiles {
+        Some(dir) => GameProfiles::load_dir(dir)?,
+        None if Path::new(DEFAULT_PROFILES_DIR).is_dir() => {
+            GameProfiles::load_dir(Path::new(DEFAULT_PROFILES_DIR))?
         }
+        None => GameProfiles::default(),
+    };
+
+    let rom_database = match &config.paths.rom_database {
+        Some(path) => RomDatabase::load(path)?,
+        None => RomDatabase::default(),
     };
 
     let mut rom_path = match matches.value_of("game_rom") {
-        Some(path) => path.to_string(),
+        Some(path) => PathBuf::from(path),
         _ => {
             info!("[!] Rom file missing, please drag a rom file into the emulator window...");
-            wait_for_rom(&mut canvas, &mut event_pump)?
+            PathBuf::from(wait_for_rom(&mut canvas, &mut event_pump)?)
         }
     };
-
-    let video = Rc::new(RefCell::new(create_video_interface(canvas)));
+    let mut recent_roms = RecentRoms::load(Path::new(RECENT_ROMS_FILE));
+    let mut recent_menu = RecentMenu::default();
+
+    // the last shader given is used from the start
+    let mut shaders = Shader::builtin();
+    for name in &config.video.shaders {
+        let shader = Shader::find(name)?;
+        shaders.retain(|s| s.name != shader.name);
+        shaders.push(shader);
+    }
+    let shader_index = if config.video.shaders.is_empty() {
+        0
+    } else {
+        shaders.len() - 1
+    };
+    let scale_mode = ScaleMode::try_from(config.video.scale.as_str())?;
+    let video = Rc::new(RefCell::new(create_video_interface(
+        canvas,
+        shaders,
+        shader_index,
+        scale_mode,
+    )?));
     let audio = Rc::new(RefCell::new(create_audio_player(&sdl_context)));
-    let input = Rc::new(RefCell::new(create_input()));
-
-    let mut savestate_path = get_savestate_path(&Path::new(&rom_path));
-
-    let mut rom_name = Path::new(&rom_path).file_name().unwrap().to_str().unwrap();
-
-    let mut builder = GamepakBuilder::new()
-        .save_type(BackupType::try_from(
-            matches.value_of("save_type").unwrap(),
-        )?)
-        .file(Path::new(&rom_path));
-
-    if matches.occurrences_of("rtc") != 0 {
-        builder = builder.with_rtc();
+    let input = Rc::new(RefCell::new(create_input(keymap.clone())));
+
+    #[cfg(feature = "ffmpeg")]
+    let recording_format = RecordingFormat::try_from(matches.value_of("record_format").unwrap())?;
+    #[cfg(feature = "ffmpeg")]
+    let recorder = SharedRecorder::default();
+    #[cfg(feature = "ffmpeg")]
+    {
+        video.borrow_mut().recorder = recorder.clone();
+        audio.borrow_mut().recorder = recorder.clone();
     }
 
-    let gamepak = builder.build()?;
+    // opened once, every game loaded is plugged into it
+    let uart_bridge = match &config.emulation.uart_bridge {
+        Some(spec) => Some(UartBridge::open(spec)?),
+        None => None,
+    };
 
-    let mut gba = GameBoyAdvance::new(
-        bios_bin.into_boxed_slice(),
-        gamepak,
-        video.clone(),
-        audio.clone(),
-        input.clone(),
-    );
+    let mut rom_name = display_name(&rom_path);
+    let mut gba = load_game(
+        &rom_path,
+        &bios_bin,
+        &config,
+        &profiles,
+        &rom_database,
+        Frontend::new()
+            .video(video.clone())
+            .audio(audio.clone())
+            .input(input.clone()),
+        uart_bridge.as_ref(),
+    )?;
+    input.borrow_mut().keymap = get_game_keymap(&keymap, &gba)?;
+    recent_roms.add(&rom_path);
+    let mut slots = open_save_slots(&config, &rom_path)?;
+    let mut rom_watcher = if config.emulation.watch_rom {
+        info!("Watching {} for changes", rom_path.display());
+        Some(RomWatcher::new(&rom_path))
+    } else {
+        None
+    };
 
-    if skip_bios {
-        gba.skip_bios();
+    if let Some(frames) = matches.value_of("verify_determinism") {
+        let check = DeterminismCheck {
+            frames: frames.parse()?,
+            ..Default::default()
+        };
+        match verify_determinism(&mut gba, check)? {
+            Some(divergence) => {
+                error!("{}", divergence);
+                process::exit(1);
+            }
+            None => {
+                info!("{} checkpoints replayed identically", check.checkpoints);
+                return Ok(());
+            }
+        }
     }
 
     if debug {
@@ -219,8 +501,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         {
             gba.cpu.set_verbose(true);
             let mut debugger = Debugger::new(gba);
+            #[cfg(feature = "lua")]
+            {
+                if let Some(script) = matches.value_of("lua") {
+                    debugger.load_script(Path::new(script))?;
+                }
+            }
             info!("starting debugger...");
-            debugger.repl(matches.value_of("script_file")).unwrap();
+            debugger.repl(matches.value_of("debug_script")).unwrap();
             info!("ending debugger...");
             return Ok(());
         }
@@ -234,20 +522,73 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         spawn_and_run_gdb_server(&mut gba, DEFAULT_GDB_SERVER_ADDR)?;
     }
 
+    #[cfg(feature = "lua")]
+    let mut script_host = match matches.value_of("lua") {
+        Some(script) => {
+            let mut host = ScriptHost::new()?;
+            host.load_file(Path::new(script), &mut gba)?;
+            Some(host)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "lua"))]
+    {
+        if matches.is_present("lua") {
+            info!("Lua scripts require the 'lua' feature");
+        }
+    }
+
     let mut fps_counter = FpsCounter::default();
-    let frame_time = time::Duration::new(0, 1_000_000_000u32 / 60);
+    let mut rewind_buffer = RewindBuffer::new(REWIND_BUFFER_BYTES, REWIND_INTERVAL_FRAMES);
+    let mut rewinding = false;
+    let mut hotkeys: Vec<(Hotkey, bool)> = Vec::new();
+    let mut limiter = FrameLimiter::new(PacingMode::Native);
     'running: loop {
-        let start_time = time::Instant::now();
-
-        for event in event_pump.poll_iter() {
+        let mut next_rom = None;
+        // the game is in Stop mode, nothing happens until a key is pressed
+        let woken_by = if gba.is_sleeping() {
+            event_pump.wait_event_timeout(SLEEP_WAIT_MS)
+        } else {
+            None
+        };
+        for event in woken_by.into_iter().chain(event_pump.poll_iter()) {
+            if matches!(
+                event,
+                Event::KeyDown { .. }
+                    | Event::KeyUp { .. }
+                    | Event::ControllerButtonDown { .. }
+                    | Event::ControllerButtonUp { .. }
+                    | Event::ControllerAxisMotion { .. }
+            ) {
+                gba.input_changed();
+            }
             match event {
                 Event::KeyDown {
                     scancode: Some(scancode),
                     ..
-                } => match scancode {
-                    Scancode::Space => frame_limiter = false,
-                    k => input.borrow_mut().on_keyboard_key_down(k),
+                } if recent_menu.is_open() => match scancode {
+                    Scancode::Up => recent_menu.select_previous(&recent_roms),
+                    Scancode::Down => recent_menu.select_next(&recent_roms),
+                    Scancode::Return => {
+                        next_rom = recent_menu.close(&recent_roms).map(Path::to_path_buf)
+                    }
+                    Scancode::Escape => {
+                        recent_menu.close(&recent_roms);
+                    }
+                    k => {
+                        if let Some(hotkey) = input.borrow_mut().on_keyboard_key_down(k) {
+                            hotkeys.push((hotkey, true));
+                        }
+                    }
                 },
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    ..
+                } => {
+                    if let Some(hotkey) = input.borrow_mut().on_keyboard_key_down(scancode) {
+                        hotkeys.push((hotkey, true));
+                    }
+                }
                 Event::KeyUp {
                     scancode: Some(scancode),
                     ..
@@ -256,105 +597,338 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     Scancode::F1 => {
                         let mut debugger = Debugger::new(gba);
                         info!("starting debugger...");
-                        debugger.repl(matches.value_of("script_file")).unwrap();
+                        debugger.repl(matches.value_of("debug_script")).unwrap();
                         gba = debugger.gba;
                         info!("ending debugger...")
                     }
                     #[cfg(feature = "gdb")]
                     Scancode::F2 => spawn_and_run_gdb_server(&mut gba, DEFAULT_GDB_SERVER_ADDR)?,
-                    Scancode::F5 => {
-                        info!("Saving state ...");
-                        let save = gba.save_state()?;
-                        write_bin_file(&savestate_path, &save)?;
-                        info!(
-                            "Saved to {:?} ({})",
-                            savestate_path,
-                            bytesize::ByteSize::b(save.len() as u64)
-                        );
-                    }
-                    Scancode::F9 => {
-                        if savestate_path.is_file() {
-                            let save = read_bin_file(&savestate_path)?;
-                            info!("Restoring state from {:?}...", savestate_path);
-                            gba.restore_state(&save)?;
-                            info!("Restored!");
-                        } else {
-                            info!("Savestate not created, please create one by pressing F5");
+                    k => {
+                        if let Some(hotkey) = input.borrow_mut().on_keyboard_key_up(k) {
+                            hotkeys.push((hotkey, false));
                         }
                     }
-                    Scancode::Space => frame_limiter = true,
-                    k => input.borrow_mut().on_keyboard_key_up(k),
-                },
-                Event::ControllerButtonDown { button, .. } => match button {
-                    Button::RightStick => frame_limiter = !frame_limiter,
-                    b => input.borrow_mut().on_controller_button_down(b),
                 },
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(hotkey) = input.borrow_mut().on_controller_button_down(button) {
+                        hotkeys.push((hotkey, true));
+                    }
+                }
                 Event::ControllerButtonUp { button, .. } => {
-                    input.borrow_mut().on_controller_button_up(button);
+                    if let Some(hotkey) = input.borrow_mut().on_controller_button_up(button) {
+                        hotkeys.push((hotkey, false));
+                    }
                 }
                 Event::ControllerAxisMotion { axis, value, .. } => {
                     input.borrow_mut().on_axis_motion(axis, value);
                 }
                 Event::ControllerDeviceRemoved { which, .. } => {
-                    let removed = if let Some(active_controller) = &active_controller {
-                        active_controller.instance_id() == (which as i32)
-                    } else {
-                        false
-                    };
-                    if removed {
-                        let name = active_controller
-                            .and_then(|controller| Some(controller.name()))
-                            .unwrap();
-                        info!("Removing game controller: {}", name);
-                        active_controller = None;
+                    if let Some(controller) = controllers.remove(&(which as i32)) {
+                        info!("Removing game controller: {}", controller.name());
+                        // Don't leave keys held by the removed controller stuck
+                        input.borrow_mut().release_all();
                     }
                 }
                 Event::ControllerDeviceAdded { which, .. } => {
-                    if active_controller.is_none() {
-                        let controller = controller_subsystem.open(which)?;
+                    let controller = controller_subsystem.open(which)?;
+                    if !controllers.contains_key(&controller.instance_id()) {
                         info!("Adding game controller: {}", controller.name());
-                        active_controller = Some(controller);
+                        controllers.insert(controller.instance_id(), controller);
                     }
                 }
                 Event::Quit { .. } => break 'running,
-                Event::DropFile { filename, .. } => {
-                    // load the new rom
-                    rom_path = filename;
-                    savestate_path = get_savestate_path(&Path::new(&rom_path));
-                    rom_name = Path::new(&rom_path).file_name().unwrap().to_str().unwrap();
-                    let gamepak = GamepakBuilder::new().file(Path::new(&rom_path)).build()?;
-                    let bios_bin = read_bin_file(bios_path).unwrap();
-
-                    // create a new emulator - TODO, export to a function
-                    gba = GameBoyAdvance::new(
-                        bios_bin.into_boxed_slice(),
-                        gamepak,
-                        video.clone(),
-                        audio.clone(),
-                        input.clone(),
-                    );
-                    gba.skip_bios();
+                Event::DropFile { filename, .. } => next_rom = Some(PathBuf::from(filename)),
+                _ => {}
+            }
+        }
+
+        for (hotkey, pressed) in hotkeys.drain(..) {
+            match (hotkey, pressed) {
+                (Hotkey::FastForward, pressed) => {
+                    // Fast-forward paces itself, see GameBoyAdvance::run_host_frame
+                    limiter.set_mode(if pressed {
+                        PacingMode::Unlimited
+                    } else {
+                        PacingMode::Native
+                    });
+                    gba.time_control_mut().set_turbo(pressed);
+                    gba.osd_mut()
+                        .set_status("ff", if pressed { Some(">>") } else { None });
+                }
+                (Hotkey::Rewind, pressed) => {
+                    rewinding = pressed;
+                    gba.osd_mut()
+                        .set_status("rewind", if pressed { Some("<<") } else { None });
+                }
+                (Hotkey::ToggleRecording, false) => {
+                    #[cfg(feature = "ffmpeg")]
+                    {
+                        let mut recorder = recorder.borrow_mut();
+                        match recorder.take() {
+                            Some(active) => {
+                                info!("Stopping recording...");
+                                gba.osd_mut().set_status("rec", None);
+                                match active.stop() {
+                                    Ok(output) => {
+                                        gba.notify(&format!("Recorded {}", display_name(&output)))
+                                    }
+                                    Err(e) => {
+                                        error!("failed to finish the recording: {}", e);
+                                        gba.notify("Recording failed");
+                                    }
+                                }
+                            }
+                            None => {
+                                let timestamp = time::SystemTime::now()
+                                    .duration_since(time::UNIX_EPOCH)?
+                                    .as_secs();
+                                let output = rom_path.with_extension(format!(
+                                    "{}.{}",
+                                    timestamp,
+                                    recording_format.extension()
+                                ));
+                                let sample_rate = audio.borrow().get_sample_rate();
+                                info!("Recording to {:?}", output);
+                                match Recorder::start(&output, recording_format, sample_rate) {
+                                    Ok(active) => {
+                                        *recorder = Some(active);
+                                        gba.osd_mut().set_status("rec", Some("REC"));
+                                    }
+                                    Err(e) => {
+                                        error!("failed to start recording {:?}: {}", output, e);
+                                        gba.notify("Can't start recording");
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    #[cfg(not(feature = "ffmpeg"))]
+                    {
+                        info!("Recording requires the 'ffmpeg' feature");
+                    }
+                }
+                (Hotkey::Screenshot, false) => {
+                    let screenshot = gba.screenshot();
+                    let path = rom_path.with_extension(format!("{}.png", screenshot.frame));
+                    match screenshot.save_png(&path, 1) {
+                        Ok(()) => gba.notify(&format!("Saved screenshot {}", display_name(&path))),
+                        Err(e) => {
+                            error!("failed to save {}: {}", path.display(), e);
+                            gba.notify("Can't save the screenshot");
+                        }
+                    }
+                }
+                (Hotkey::NextShader, false) => {
+                    let name = video.borrow_mut().next_shader().to_string();
+                    gba.notify(&format!("Shader: {}", name));
+                }
+                (Hotkey::RecentRoms, false) => recent_menu.open_or_next(&recent_roms),
+                (Hotkey::SaveState(slot), false) => {
+                    info!("Saving state to slot {} ...", slot);
+                    match slots.save(&gba, slot) {
+                        Ok(()) => {
+                            info!("Saved to {:?}", slots.slot_path(slot));
+                            gba.notify(&format!("State saved to slot {}", slot));
+                        }
+                        Err(e) => {
+                            error!("{}", e);
+                            gba.notify(&format!("Can't save to slot {}", slot));
+                        }
+                    }
+                }
+                (Hotkey::LoadState(slot), false) => match slots.load(&mut gba, slot) {
+                    Ok(true) => {
+                        info!("Restored state from {:?}", slots.slot_path(slot));
+                        rewind_buffer.clear();
+                        gba.notify(&format!("State loaded from slot {}", slot));
+                    }
+                    Ok(false) => gba.notify(&format!("Slot {} is empty", slot)),
+                    Err(e) => {
+                        error!("{}", e);
+                        gba.notify(&format!("Can't load slot {}", slot));
+                    }
+                },
+                (Hotkey::UndoLoadState, false) => match slots.undo_load(&mut gba) {
+                    Ok(true) => {
+                        rewind_buffer.clear();
+                        gba.notify("Load undone");
+                    }
+                    Ok(false) => gba.notify("No load to undo"),
+                    Err(e) => {
+                        error!("{}", e);
+                        gba.notify("Can't undo the load");
+                    }
+                },
+                (Hotkey::UndoSaveState, false) => match slots.undo_save() {
+                    Ok(Some(slot)) => gba.notify(&format!("Slot {} restored", slot)),
+                    Ok(None) => gba.notify("No save to undo"),
+                    Err(e) => {
+                        error!("{}", e);
+                        gba.notify("Can't undo the save");
+                    }
+                },
+                (Hotkey::Reset, false) => {
+                    gba.hard_reset();
+                    gba.notify("Reset");
+                }
+                (Hotkey::Pause, false) => gba.time_control_mut().toggle_pause(),
+                (Hotkey::FrameAdvance, true) => gba.time_control_mut().step_frame(),
+                (Hotkey::SlowMotion, pressed) => {
+                    gba.time_control_mut()
+                        .set_slow_motion(if pressed { SLOW_MOTION } else { 1.0 });
+                    gba.osd_mut()
+                        .set_status("slow", if pressed { Some("x0.5") } else { None });
                 }
                 _ => {}
             }
         }
 
-        gba.frame();
+        gba.osd_mut()
+            .set_status("recent", recent_menu.status(&recent_roms).as_deref());
+
+        if let Some(watcher) = &mut rom_watcher {
+            if next_rom.is_none() && watcher.poll() {
+                match config.rom_reload() {
+                    ReloadMode::Reset => next_rom = Some(rom_path.clone()),
+                    ReloadMode::KeepState => {
+                        match build_game_gamepak(&rom_path, &config, &profiles, &rom_database) {
+                            Ok((gamepak, _)) => {
+                                gba.swap_rom(gamepak);
+                                // the states hold the old rom
+                                rewind_buffer.clear();
+                                rewinding = false;
+                                gba.notify(&format!("Reloaded {}", rom_name));
+                            }
+                            Err(e) => {
+                                error!("failed to reload {}: {}", rom_path.display(), e);
+                                gba.notify(&format!("Failed to reload {}", rom_name));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(path) = next_rom {
+            let frontend = Frontend::new()
+                .video(video.clone())
+                .audio(audio.clone())
+                .input(input.clone());
+            match load_game(
+                &path,
+                &bios_bin,
+                &config,
+                &profiles,
+                &rom_database,
+                frontend,
+                uart_bridge.as_ref(),
+            ) {
+                Ok(new_gba) => {
+                    // the save of the old game is written as it goes, dropping it loses nothing
+                    if let Err(e) = slots.autosave(&gba) {
+                        error!("failed to autosave {}: {}", rom_name, e);
+                    }
+                    slots = open_save_slots(&config, &path)?;
+                    gba = new_gba;
+                    rom_path = path;
+                    rom_name = display_name(&rom_path);
+                    info!("Loaded {}", rom_name);
+                    input.borrow_mut().keymap = get_game_keymap(&keymap, &gba)?;
+                    input.borrow_mut().release_all();
+                    recent_roms.add(&rom_path);
+                    if let Some(watcher) = &mut rom_watcher {
+                        if watcher.path() != rom_path {
+                            *watcher = RomWatcher::new(&rom_path);
+                        }
+                    }
+                    rewind_buffer.clear();
+                    rewinding = false;
+                    limiter.set_mode(PacingMode::Native);
+                    #[cfg(feature = "ffmpeg")]
+                    {
+                        // the video is named after the old game
+                        if let Some(active) = recorder.borrow_mut().take() {
+                            info!("Stopping recording...");
+                            if let Err(e) = active.stop() {
+                                error!("failed to finish the recording: {}", e);
+                                gba.notify("Recording failed");
+                            }
+                        }
+                    }
+                    #[cfg(feature = "lua")]
+                    {
+                        // scripts usually read the memory of a specific game, start it afresh
+                        if let Some(script) = matches.value_of("lua") {
+                            let mut host = ScriptHost::new()?;
+                            host.load_file(Path::new(script), &mut gba)?;
+                            script_host = Some(host);
+                        }
+                    }
+                    gba.notify(&format!("Loaded {}", rom_name));
+                }
+                Err(e) => {
+                    error!("failed to load {}: {}", path.display(), e);
+                    gba.notify(&format!("Failed to load {}", display_name(&path)));
+                }
+            }
+        }
+
+        if rewinding {
+            if let Some(snapshot) = rewind_buffer.pop() {
+                if let Err(e) = gba.restore_state(&snapshot) {
+                    error!("failed to rewind: {}", e);
+                    rewinding = false;
+                    rewind_buffer.clear();
+                    gba.osd_mut().set_status("rewind", None);
+                    gba.notify("Can't rewind");
+                }
+            }
+        }
+
+        #[cfg(feature = "lua")]
+        {
+            if let Some(host) = &mut script_host {
+                if let Err(e) = host.frame(&mut gba) {
+                    error!("Script error, unloading: {}", e);
+                    script_host = None;
+                }
+            } else {
+                gba.run_host_frame();
+            }
+        }
+        #[cfg(not(feature = "lua"))]
+        gba.run_host_frame();
+
+        if !rewinding && rewind_buffer.tick() {
+            rewind_buffer.push(gba.save_state()?);
+        }
 
         if let Some(fps) = fps_counter.tick() {
             let title = format!("{} ({} fps)", rom_name, fps);
             video.borrow_mut().set_window_title(&title);
+            if show_fps {
+                let latency = gba.stats().input_latency;
+                let status = if latency.samples > 0 {
+                    format!("{} FPS, input {} ms", fps, latency.average.as_millis())
+                } else {
+                    format!("{} FPS", fps)
+                };
+                gba.osd_mut().set_status("fps", Some(&status));
+            }
         }
 
-        if frame_limiter {
-            let time_passed = start_time.elapsed();
-            let delay = frame_time.checked_sub(time_passed);
-            match delay {
-                None => {}
-                Some(delay) => {
-                    spin_sleep::sleep(delay);
-                }
-            };
+        limiter.wait();
+    }
+
+    slots.autosave(&gba)?;
+
+    #[cfg(feature = "ffmpeg")]
+    {
+        if let Some(active) = recorder.borrow_mut().take() {
+            info!("Stopping recording...");
+            if let Err(e) = active.stop() {
+                error!("failed to finish the recording: {}", e);
+            }
         }
     }
 
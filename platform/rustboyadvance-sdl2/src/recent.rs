@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAX_RECENT_ROMS: usize = 10;
+/// Longer names don't fit on the screen
+const MAX_NAME_CHARS: usize = 32;
+
+/// The roms loaded last, most recent first, kept in a text file with one path per line
+pub struct RecentRoms {
+    file: PathBuf,
+    roms: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    /// Reads the list from `file`, a missing file is an empty list
+    pub fn load(file: &Path) -> RecentRoms {
+        let roms = match fs::read_to_string(file) {
+            Ok(text) => text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(PathBuf::from)
+                .take(MAX_RECENT_ROMS)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        RecentRoms {
+            file: file.to_path_buf(),
+            roms,
+        }
+    }
+
+    pub fn roms(&self) -> &[PathBuf] {
+        &self.roms
+    }
+
+    /// Moves `rom` to the top of the list and writes the list back
+    pub fn add(&mut self, rom: &Path) {
+        let rom = fs::canonicalize(rom).unwrap_or_else(|_| rom.to_path_buf());
+        self.roms.retain(|path| *path != rom);
+        self.roms.insert(0, rom);
+        self.roms.truncate(MAX_RECENT_ROMS);
+
+        let mut text = String::new();
+        for path in &self.roms {
+            text.push_str(&path.to_string_lossy());
+            text.push('\n');
+        }
+        if let Err(e) = fs::write(&self.file, text) {
+            warn!("failed to write {}: {}", self.file.display(), e);
+        }
+    }
+}
+
+/// A selection in the recent roms, shown as an OSD status while open
+#[derive(Debug, Default)]
+pub struct RecentMenu {
+    selected: Option<usize>,
+}
+
+impl RecentMenu {
+    pub fn is_open(&self) -> bool {
+        self.selected.is_some()
+    }
+
+    /// Opens on the rom before the current one, or selects the next rom when already open
+    pub fn open_or_next(&mut self, recent: &RecentRoms) {
+        let len = recent.roms().len();
+        if len == 0 {
+            return;
+        }
+        self.selected = Some(match self.selected {
+            Some(index) => (index + 1) % len,
+            None => 1 % len,
+        });
+    }
+
+    pub fn select_previous(&mut self, recent: &RecentRoms) {
+        let len = recent.roms().len();
+        if let Some(index) = &mut self.selected {
+            *index = (*index + len - 1) % len;
+        }
+    }
+
+    pub fn select_next(&mut self, recent: &RecentRoms) {
+        let len = recent.roms().len();
+        if let Some(index) = &mut self.selected {
+            *index = (*index + 1) % len;
+        }
+    }
+
+    /// Closes the menu, returning the selected rom
+    pub fn close<'a>(&mut self, recent: &'a RecentRoms) -> Option<&'a Path> {
+        let index = self.selected.take()?;
+        recent.roms().get(index).map(PathBuf::as_path)
+    }
+
+    /// The status text for the selection, `None` when closed
+    pub fn status(&self, recent: &RecentRoms) -> Option<String> {
+        let index = self.selected?;
+        let name: String = recent.roms()[index]
+            .file_name()
+            .map(|name| {
+                name.to_string_lossy()
+                    .chars()
+                    .take(MAX_NAME_CHARS)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(format!("{}/{} {}", index + 1, recent.roms().len(), name))
+    }
+}
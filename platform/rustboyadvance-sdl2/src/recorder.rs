@@ -0,0 +1,202 @@
+//! Gameplay recording through an external `ffmpeg` executable.
+//!
+//! Frames are piped into an ffmpeg process as raw video while audio samples are written to a
+//! temporary PCM file, both streams are muxed together when the recording is stopped.
+//!
+//! The video stream is declared at the exact GBA refresh rate, and the audio samples are produced
+//! by the core according to emulated cycles, so both streams stay in sync no matter how fast the
+//! emulator actually ran (fast-forward, frame drops, debugger pauses etc.)
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::rc::Rc;
+
+use rustboyadvance_core::gpu::{CYCLES_FULL_REFRESH, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use rustboyadvance_core::StereoSample;
+
+const GBA_CLOCK_HZ: usize = 16 * 1024 * 1024;
+const SCALE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordingFormat {
+    Mp4,
+    WebM,
+}
+
+impl RecordingFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordingFormat::Mp4 => "mp4",
+            RecordingFormat::WebM => "webm",
+        }
+    }
+
+    fn video_codec(&self) -> &'static [&'static str] {
+        match self {
+            RecordingFormat::Mp4 => &["-c:v", "libx264", "-preset", "veryfast", "-crf", "18"],
+            RecordingFormat::WebM => &["-c:v", "libvpx-vp9", "-lossless", "1"],
+        }
+    }
+
+    fn audio_codec(&self) -> &'static [&'static str] {
+        match self {
+            RecordingFormat::Mp4 => &["-c:a", "aac", "-b:a", "192k"],
+            RecordingFormat::WebM => &["-c:a", "libopus", "-b:a", "128k"],
+        }
+    }
+}
+
+impl std::convert::TryFrom<&str> for RecordingFormat {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "mp4" => Ok(RecordingFormat::Mp4),
+            "webm" => Ok(RecordingFormat::WebM),
+            _ => Err(format!("{} is not a valid recording format", s)),
+        }
+    }
+}
+
+pub struct Recorder {
+    format: RecordingFormat,
+    output: PathBuf,
+    video_path: PathBuf,
+    audio_path: PathBuf,
+    sample_rate: i32,
+    encoder: Child,
+    video: BufWriter<ChildStdin>,
+    audio: BufWriter<File>,
+    frames: usize,
+}
+
+/// Shared between the video and audio devices, `None` while not recording
+pub type SharedRecorder = Rc<RefCell<Option<Recorder>>>;
+
+impl Recorder {
+    pub fn start(output: &Path, format: RecordingFormat, sample_rate: i32) -> io::Result<Recorder> {
+        let video_path = output.with_extension("video.mkv");
+        let audio_path = output.with_extension("audio.raw");
+
+        let framerate = format!("{}/{}", GBA_CLOCK_HZ, CYCLES_FULL_REFRESH);
+        let video_size = format!("{}x{}", DISPLAY_WIDTH, DISPLAY_HEIGHT);
+        let scale = format!(
+            "scale={}:{}:flags=neighbor",
+            DISPLAY_WIDTH * SCALE,
+            DISPLAY_HEIGHT * SCALE
+        );
+        let mut encoder = Command::new("ffmpeg")
+            .args(&["-y", "-loglevel", "error"])
+            .args(&["-f", "rawvideo", "-pixel_format", "bgr0"])
+            .args(&["-video_size", &video_size, "-framerate", &framerate])
+            .args(&["-i", "-", "-vf", &scale, "-pix_fmt", "yuv420p"])
+            .args(format.video_codec())
+            .arg(&video_path)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        let video = BufWriter::new(encoder.stdin.take().unwrap());
+        let audio = BufWriter::new(File::create(&audio_path)?);
+
+        Ok(Recorder {
+            format,
+            output: output.to_path_buf(),
+            video_path,
+            audio_path,
+            sample_rate,
+            encoder,
+            video,
+            audio,
+            frames: 0,
+        })
+    }
+
+    pub fn push_frame(&mut self, buffer: &[u32]) -> io::Result<()> {
+        for pixel in buffer {
+            self.video.write_all(&pixel.to_le_bytes())?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn push_sample(&mut self, sample: StereoSample<i16>) -> io::Result<()> {
+        self.audio.write_all(&sample.0.to_le_bytes())?;
+        self.audio.write_all(&sample.1.to_le_bytes())
+    }
+
+    /// Finishes encoding and muxes the audio in, returns the path of the recording
+    pub fn stop(self) -> io::Result<PathBuf> {
+        let Recorder {
+            format,
+            output,
+            video_path,
+            audio_path,
+            sample_rate,
+            mut encoder,
+            mut video,
+            mut audio,
+            frames,
+        } = self;
+
+        video.flush()?;
+        audio.flush()?;
+        // Closing stdin lets the encoder finish
+        drop(video);
+        drop(audio);
+        let status = encoder.wait()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("ffmpeg failed to encode the video ({})", status),
+            ));
+        }
+
+        let sample_rate = sample_rate.to_string();
+        let status = Command::new("ffmpeg")
+            .args(&["-y", "-loglevel", "error"])
+            .arg("-i")
+            .arg(&video_path)
+            .args(&["-f", "s16le", "-ar", &sample_rate, "-ac", "2", "-i"])
+            .arg(&audio_path)
+            .args(&["-c:v", "copy"])
+            .args(format.audio_codec())
+            .arg(&output)
+            .status()?;
+
+        fs::remove_file(&video_path)?;
+        fs::remove_file(&audio_path)?;
+
+        if status.success() {
+            info!("Recorded {} frames to {:?}", frames, output);
+            Ok(output)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("ffmpeg failed to mux the recording ({})", status),
+            ))
+        }
+    }
+}
+
+/// Feeds a frame to the recorder if one is running, recording is stopped on errors
+pub fn record_frame(recorder: &SharedRecorder, buffer: &[u32]) {
+    let mut recorder = recorder.borrow_mut();
+    if let Some(active) = recorder.as_mut() {
+        if let Err(e) = active.push_frame(buffer) {
+            error!("Recording aborted: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+/// Feeds an audio sample to the recorder if one is running, recording is stopped on errors
+pub fn record_sample(recorder: &SharedRecorder, sample: StereoSample<i16>) {
+    let mut recorder = recorder.borrow_mut();
+    if let Some(active) = recorder.as_mut() {
+        if let Err(e) = active.push_sample(sample) {
+            error!("Recording aborted: {}", e);
+            *recorder = None;
+        }
+    }
+}
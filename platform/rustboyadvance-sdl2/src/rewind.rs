@@ -0,0 +1,58 @@
+use std::collections::VecDeque;
+
+/// Keeps a history of savestates to rewind through.
+///
+/// Savestates carry the whole cartridge, so the history is bounded by a total byte budget
+/// rather than by a number of snapshots, and snapshots are only taken every few frames.
+pub struct RewindBuffer {
+    snapshots: VecDeque<Vec<u8>>,
+    total_bytes: usize,
+    max_bytes: usize,
+    interval: usize,
+    frame_counter: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(max_bytes: usize, interval: usize) -> RewindBuffer {
+        RewindBuffer {
+            snapshots: VecDeque::new(),
+            total_bytes: 0,
+            max_bytes,
+            interval: interval.max(1),
+            frame_counter: 0,
+        }
+    }
+
+    /// Should be called once per emulated frame, returns true when a snapshot is due
+    pub fn tick(&mut self) -> bool {
+        self.frame_counter += 1;
+        if self.frame_counter >= self.interval {
+            self.frame_counter = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        self.total_bytes += snapshot.len();
+        self.snapshots.push_back(snapshot);
+        while self.total_bytes > self.max_bytes && self.snapshots.len() > 1 {
+            let oldest = self.snapshots.pop_front().unwrap();
+            self.total_bytes -= oldest.len();
+        }
+    }
+
+    /// Takes the most recent snapshot
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let snapshot = self.snapshots.pop_back()?;
+        self.total_bytes -= snapshot.len();
+        Some(snapshot)
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.total_bytes = 0;
+        self.frame_counter = 0;
+    }
+}
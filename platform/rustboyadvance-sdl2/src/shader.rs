@@ -0,0 +1,173 @@
+//! GLSL shaders the frames are drawn through.
+//!
+//! A shader is a single `.glsl` file holding both stages, enclosed in `#if defined(VERTEX)` and
+//! `#elif defined(FRAGMENT)` like RetroArch's single pass shaders. Each stage is compiled with
+//! `#version 150` and its name defined. The vertex stage gets the attributes `position`
+//! (clip space) and `tex_coord`, and both stages can use the uniforms:
+//! - `source`: the GBA frame, sampled with bilinear filtering, `texelFetch` gives plain pixels
+//! - `source_size`: the size of the frame, 240x160
+//! - `output_size`: the size of the picture on screen, in pixels
+//!
+//! The built-in shaders in the `shaders` directory are examples.
+use std::ffi::CString;
+use std::fs;
+use std::path::Path;
+use std::ptr;
+
+use gl::types::{GLchar, GLenum, GLint, GLsizei, GLuint};
+
+use super::video::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const POSITION_ATTRIB: GLuint = 0;
+pub const TEX_COORD_ATTRIB: GLuint = 1;
+
+const BUILTIN_SHADERS: &[(&str, &str)] = &[
+    ("nearest", include_str!("../shaders/nearest.glsl")),
+    (
+        "sharp-bilinear",
+        include_str!("../shaders/sharp-bilinear.glsl"),
+    ),
+    ("lcd-grid", include_str!("../shaders/lcd-grid.glsl")),
+    ("crt", include_str!("../shaders/crt.glsl")),
+];
+
+#[derive(Debug, Clone)]
+pub struct Shader {
+    pub name: String,
+    source: String,
+}
+
+impl Shader {
+    /// The built-in shaders, starting with the plain one
+    pub fn builtin() -> Vec<Shader> {
+        BUILTIN_SHADERS
+            .iter()
+            .map(|(name, source)| Shader {
+                name: name.to_string(),
+                source: source.to_string(),
+            })
+            .collect()
+    }
+
+    /// A built-in shader by name, or a `.glsl` file
+    pub fn find(name: &str) -> Result<Shader, String> {
+        if let Some(shader) = Shader::builtin().into_iter().find(|s| s.name == name) {
+            return Ok(shader);
+        }
+        let path = Path::new(name);
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read shader {}: {}", path.display(), e))?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| name.to_string());
+        Ok(Shader { name, source })
+    }
+}
+
+/// Reads the compilation or link log of a shader or program
+unsafe fn info_log(
+    id: GLuint,
+    get_iv: unsafe fn(GLuint, GLenum, *mut GLint),
+    get_log: unsafe fn(GLuint, GLsizei, *mut GLsizei, *mut GLchar),
+) -> String {
+    let mut len = 0;
+    get_iv(id, gl::INFO_LOG_LENGTH, &mut len);
+    let mut log = vec![0u8; len.max(1) as usize];
+    get_log(id, len, ptr::null_mut(), log.as_mut_ptr() as *mut GLchar);
+    String::from_utf8_lossy(&log)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+fn compile_stage(kind: GLenum, stage: &str, source: &str) -> Result<GLuint, String> {
+    let text = CString::new(format!("#version 150\n#define {}\n{}", stage, source))
+        .map_err(|_| "the shader contains a nul byte".to_string())?;
+    unsafe {
+        let id = gl::CreateShader(kind);
+        gl::ShaderSource(id, 1, &text.as_ptr(), ptr::null());
+        gl::CompileShader(id);
+        let mut compiled = 0;
+        gl::GetShaderiv(id, gl::COMPILE_STATUS, &mut compiled);
+        if compiled == 0 {
+            let log = info_log(id, gl::GetShaderiv, gl::GetShaderInfoLog);
+            gl::DeleteShader(id);
+            return Err(format!("{} stage: {}", stage.to_lowercase(), log));
+        }
+        Ok(id)
+    }
+}
+
+fn uniform_location(program: GLuint, name: &str) -> GLint {
+    let name = CString::new(name).unwrap();
+    // -1 when the shader doesn't use it, setting it is then a no-op
+    unsafe { gl::GetUniformLocation(program, name.as_ptr()) }
+}
+
+/// A linked shader, the GL context must be current
+pub struct ShaderProgram {
+    id: GLuint,
+    source: GLint,
+    source_size: GLint,
+    output_size: GLint,
+}
+
+impl ShaderProgram {
+    pub fn new(shader: &Shader) -> Result<ShaderProgram, String> {
+        let vertex = compile_stage(gl::VERTEX_SHADER, "VERTEX", &shader.source)?;
+        let fragment = match compile_stage(gl::FRAGMENT_SHADER, "FRAGMENT", &shader.source) {
+            Ok(fragment) => fragment,
+            Err(e) => {
+                unsafe { gl::DeleteShader(vertex) };
+                return Err(e);
+            }
+        };
+
+        let id = unsafe {
+            let id = gl::CreateProgram();
+            gl::AttachShader(id, vertex);
+            gl::AttachShader(id, fragment);
+            let position = CString::new("position").unwrap();
+            let tex_coord = CString::new("tex_coord").unwrap();
+            gl::BindAttribLocation(id, POSITION_ATTRIB, position.as_ptr());
+            gl::BindAttribLocation(id, TEX_COORD_ATTRIB, tex_coord.as_ptr());
+            gl::LinkProgram(id);
+            // only flagged for deletion until the program goes away
+            gl::DeleteShader(vertex);
+            gl::DeleteShader(fragment);
+
+            let mut linked = 0;
+            gl::GetProgramiv(id, gl::LINK_STATUS, &mut linked);
+            if linked == 0 {
+                let log = info_log(id, gl::GetProgramiv, gl::GetProgramInfoLog);
+                gl::DeleteProgram(id);
+                return Err(format!("link: {}", log));
+            }
+            id
+        };
+
+        Ok(ShaderProgram {
+            id,
+            source: uniform_location(id, "source"),
+            source_size: uniform_location(id, "source_size"),
+            output_size: uniform_location(id, "output_size"),
+        })
+    }
+
+    /// Uses the program to draw a picture of `output_size` pixels
+    pub fn bind(&self, output_size: (i32, i32)) {
+        unsafe {
+            gl::UseProgram(self.id);
+            gl::Uniform1i(self.source, 0);
+            gl::Uniform2f(self.source_size, SCREEN_WIDTH as f32, SCREEN_HEIGHT as f32);
+            gl::Uniform2f(self.output_size, output_size.0 as f32, output_size.1 as f32);
+        }
+    }
+}
+
+impl Drop for ShaderProgram {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteProgram(self.id) };
+    }
+}
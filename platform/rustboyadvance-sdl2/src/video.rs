@@ -1,59 +1,264 @@
-use sdl2::pixels::{Color, PixelFormatEnum};
-use sdl2::rect::Rect;
-use sdl2::render::{Texture, TextureCreator, WindowCanvas};
-use sdl2::video::WindowContext;
+use std::convert::TryFrom;
+use std::mem;
+use std::ptr;
+
+use gl::types::{GLfloat, GLsizeiptr, GLuint};
+use sdl2::render::WindowCanvas;
+use sdl2::video::{GLContext, GLProfile, Window};
 
 use rustboyadvance_core::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
-use rustboyadvance_core::VideoInterface;
+use rustboyadvance_core::VideoSink;
+
+#[cfg(feature = "ffmpeg")]
+use super::recorder::{self, SharedRecorder};
+use super::shader::{Shader, ShaderProgram, POSITION_ATTRIB, TEX_COORD_ATTRIB};
 
 pub const SCREEN_WIDTH: u32 = DISPLAY_WIDTH as u32;
 pub const SCREEN_HEIGHT: u32 = DISPLAY_HEIGHT as u32;
 
-pub struct Sdl2Video<'a> {
-    _tc: TextureCreator<WindowContext>, // only kept alive because of the texture
-    texture: Texture<'a>,               // TODO - what happens if _tc is destroyed first ?
-    canvas: WindowCanvas,
+/// A quad covering the viewport as a triangle strip, position then texture coordinates
+#[rustfmt::skip]
+const QUAD: [GLfloat; 16] = [
+    -1.0, -1.0, 0.0, 1.0,
+     1.0, -1.0, 1.0, 1.0,
+    -1.0,  1.0, 0.0, 0.0,
+     1.0,  1.0, 1.0, 0.0,
+];
+
+/// How the picture fills the window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScaleMode {
+    /// As large as the window allows, keeping the 3:2 aspect ratio
+    Fit,
+    /// The largest whole multiple of the GBA resolution, so that all pixels are the same size
+    Integer,
+    /// The whole window, ignoring the aspect ratio
+    Stretch,
 }
 
-impl<'a> Sdl2Video<'a> {
+impl TryFrom<&str> for ScaleMode {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "fit" => Ok(ScaleMode::Fit),
+            "integer" => Ok(ScaleMode::Integer),
+            "stretch" => Ok(ScaleMode::Stretch),
+            _ => Err(format!("{} is not a valid scale mode", s)),
+        }
+    }
+}
+
+impl ScaleMode {
+    /// The picture within a `width`x`height` window, as x, y, width and height
+    fn viewport(self, width: u32, height: u32) -> (i32, i32, i32, i32) {
+        let scale_x = width as f32 / SCREEN_WIDTH as f32;
+        let scale_y = height as f32 / SCREEN_HEIGHT as f32;
+        let (w, h) = match self {
+            ScaleMode::Stretch => (width, height),
+            ScaleMode::Fit => {
+                let scale = scale_x.min(scale_y);
+                (
+                    (SCREEN_WIDTH as f32 * scale) as u32,
+                    (SCREEN_HEIGHT as f32 * scale) as u32,
+                )
+            }
+            ScaleMode::Integer => {
+                let scale = (scale_x.min(scale_y).floor() as u32).max(1);
+                (SCREEN_WIDTH * scale, SCREEN_HEIGHT * scale)
+            }
+        };
+        (
+            (width as i32 - w as i32) / 2,
+            (height as i32 - h as i32) / 2,
+            w as i32,
+            h as i32,
+        )
+    }
+}
+
+pub struct Sdl2Video {
+    program: ShaderProgram,
+    shaders: Vec<Shader>,
+    shader_index: usize,
+    scale_mode: ScaleMode,
+    texture: GLuint,
+    vertex_array: GLuint,
+    vertex_buffer: GLuint,
+    _gl_context: GLContext,
+    window: Window,
+    #[cfg(feature = "ffmpeg")]
+    pub recorder: SharedRecorder,
+}
+
+impl Sdl2Video {
     pub fn set_window_title(&mut self, title: &str) {
-        self.canvas.window_mut().set_title(&title).unwrap();
+        self.window.set_title(&title).unwrap();
+    }
+
+    /// Switches to the next shader that compiles, returns its name
+    pub fn next_shader(&mut self) -> &str {
+        for _ in 1..self.shaders.len() {
+            self.shader_index = (self.shader_index + 1) % self.shaders.len();
+            let shader = &self.shaders[self.shader_index];
+            match ShaderProgram::new(shader) {
+                Ok(program) => {
+                    self.program = program;
+                    break;
+                }
+                Err(e) => error!("failed to compile shader {}: {}", shader.name, e),
+            }
+        }
+        &self.shaders[self.shader_index].name
+    }
+
+    fn present(&mut self) {
+        let (width, height) = self.window.drawable_size();
+        let (x, y, w, h) = self.scale_mode.viewport(width, height);
+        unsafe {
+            gl::Viewport(0, 0, width as i32, height as i32);
+            gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+
+            gl::Viewport(x, y, w, h);
+            self.program.bind((w, h));
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+            gl::BindVertexArray(self.vertex_array);
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+        }
+        self.window.gl_swap_window();
     }
 }
 
-impl<'a> VideoInterface for Sdl2Video<'a> {
+impl VideoSink for Sdl2Video {
     fn render(&mut self, buffer: &[u32]) {
-        self.texture
-            .update(
-                None,
-                unsafe { std::mem::transmute::<&[u32], &[u8]>(buffer) },
-                (SCREEN_WIDTH as usize) * 4,
-            )
-            .unwrap();
-        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
-        self.canvas.clear();
-        self.canvas
-            .copy(
-                &self.texture,
-                None,
-                Some(Rect::new(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT)),
-            )
-            .unwrap();
-        self.canvas.present();
-    }
-}
-
-pub fn create_video_interface<'a>(canvas: WindowCanvas) -> Sdl2Video<'a> {
-    let mut tc = canvas.texture_creator();
-    let texture = unsafe {
-        let tc_ptr = &mut tc as *mut TextureCreator<WindowContext>;
-        (*tc_ptr)
-            .create_texture_streaming(PixelFormatEnum::BGRA32, SCREEN_WIDTH, SCREEN_HEIGHT)
-            .unwrap()
-    };
-    Sdl2Video {
-        _tc: tc,
-        texture: texture,
-        canvas: canvas,
+        #[cfg(feature = "ffmpeg")]
+        recorder::record_frame(&self.recorder, buffer);
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.texture);
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                0,
+                0,
+                SCREEN_WIDTH as i32,
+                SCREEN_HEIGHT as i32,
+                gl::BGRA,
+                gl::UNSIGNED_BYTE,
+                buffer.as_ptr() as *const _,
+            );
+        }
+        self.present();
+    }
+
+    #[allow(unused_variables)]
+    fn render_unchanged(&mut self, buffer: &[u32]) {
+        #[cfg(feature = "ffmpeg")]
+        recorder::record_frame(&self.recorder, buffer);
+
+        // the texture already holds this frame
+        self.present();
+    }
+}
+
+impl Drop for Sdl2Video {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture);
+            gl::DeleteBuffers(1, &self.vertex_buffer);
+            gl::DeleteVertexArrays(1, &self.vertex_array);
+        }
     }
 }
+
+/// Takes over the window of `canvas` to draw with OpenGL, through `shaders[shader_index]`
+pub fn create_video_interface(
+    canvas: WindowCanvas,
+    shaders: Vec<Shader>,
+    shader_index: usize,
+    scale_mode: ScaleMode,
+) -> Result<Sdl2Video, String> {
+    let window = canvas.into_window();
+    let video_subsystem = window.subsystem().clone();
+    let gl_attr = video_subsystem.gl_attr();
+    gl_attr.set_context_profile(GLProfile::Core);
+    gl_attr.set_context_version(3, 2);
+    let gl_context = window.gl_create_context()?;
+    gl::load_with(|name| video_subsystem.gl_get_proc_address(name) as *const _);
+    // the frame limiter does the pacing
+    let _ = video_subsystem.gl_set_swap_interval(0);
+
+    let program = ShaderProgram::new(&shaders[shader_index]).map_err(|e| {
+        format!(
+            "failed to compile shader {}: {}",
+            shaders[shader_index].name, e
+        )
+    })?;
+
+    let (mut texture, mut vertex_array, mut vertex_buffer) = (0, 0, 0);
+    unsafe {
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA8 as i32,
+            SCREEN_WIDTH as i32,
+            SCREEN_HEIGHT as i32,
+            0,
+            gl::BGRA,
+            gl::UNSIGNED_BYTE,
+            ptr::null(),
+        );
+
+        gl::GenVertexArrays(1, &mut vertex_array);
+        gl::BindVertexArray(vertex_array);
+        gl::GenBuffers(1, &mut vertex_buffer);
+        gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer);
+        gl::BufferData(
+            gl::ARRAY_BUFFER,
+            mem::size_of_val(&QUAD) as GLsizeiptr,
+            QUAD.as_ptr() as *const _,
+            gl::STATIC_DRAW,
+        );
+        let stride = 4 * mem::size_of::<GLfloat>() as i32;
+        gl::EnableVertexAttribArray(POSITION_ATTRIB);
+        gl::VertexAttribPointer(
+            POSITION_ATTRIB,
+            2,
+            gl::FLOAT,
+            gl::FALSE,
+            stride,
+            ptr::null(),
+        );
+        gl::EnableVertexAttribArray(TEX_COORD_ATTRIB);
+        gl::VertexAttribPointer(
+            TEX_COORD_ATTRIB,
+            2,
+            gl::FLOAT,
+            gl::FALSE,
+            stride,
+            (2 * mem::size_of::<GLfloat>()) as *const _,
+        );
+    }
+
+    Ok(Sdl2Video {
+        program,
+        shaders,
+        shader_index,
+        scale_mode,
+        texture,
+        vertex_array,
+        vertex_buffer,
+        _gl_context: gl_context,
+        window,
+        #[cfg(feature = "ffmpeg")]
+        recorder: Default::default(),
+    })
+}
@@ -223,6 +223,58 @@ document.getElementById("maxFps").addEventListener('change', e => {
     }
 })
 
+// Gamepads are polled by the emulator every frame, the first connected one is used
+window.addEventListener("gamepadconnected", e => {
+    console.log("Gamepad connected: " + e.gamepad.id);
+});
+
+var touchControls = document.getElementById("touch-controls");
+
+wasm.touch_layout().forEach(button => {
+    var element = document.createElement("div");
+    element.textContent = button.label();
+    element.style.left = (button.x() * 100) + "%";
+    element.style.top = (button.y() * 100) + "%";
+    element.style.width = (button.width() * 100) + "%";
+    element.style.height = (button.height() * 100) + "%";
+    touchControls.appendChild(element);
+});
+
+const updateTouches = e => {
+    e.preventDefault();
+    if (null == emulator) {
+        return;
+    }
+    var rect = touchControls.getBoundingClientRect();
+    var points = [];
+    for (let touch of e.touches) {
+        points.push((touch.clientX - rect.left) / rect.width);
+        points.push((touch.clientY - rect.top) / rect.height);
+    }
+    emulator.set_touches(new Float32Array(points));
+};
+
+['touchstart', 'touchmove', 'touchend', 'touchcancel'].forEach(eventName => {
+    touchControls.addEventListener(eventName, updateTouches, false);
+});
+
+const showTouchControls = show => {
+    document.getElementById("touchControls").checked = show;
+    touchControls.classList.toggle("hidden", !show);
+    if (!show && null != emulator) {
+        emulator.set_touches(new Float32Array([]));
+    }
+};
+
+// shown by default on touch screens
+var touchControlsSetting = JSON.parse(localStorage.getItem("touchControls"));
+showTouchControls(null != touchControlsSetting ? touchControlsSetting : ('ontouchstart' in window));
+
+document.getElementById("touchControls").addEventListener('change', e => {
+    showTouchControls(e.target.checked);
+    localStorage.setItem("touchControls", JSON.stringify(e.target.checked));
+});
+
 document.addEventListener("keydown", e => {
     if (null != emulator) {
         emulator.key_down(e.key)
@@ -8,13 +8,17 @@ use js_sys::Float32Array;
 
 use web_sys::AudioContext;
 use web_sys::CanvasRenderingContext2d;
+use web_sys::{Gamepad, GamepadButton};
 
+use rustboyadvance_core::input::standard_gamepad_to_keyinput;
 use rustboyadvance_core::keypad as gba_keypad;
 use rustboyadvance_core::prelude::*;
 use rustboyadvance_core::util::audio::AudioRingBuffer;
 
 use bit::BitIndex;
 
+use super::touch::touches_to_keyinput;
+
 #[wasm_bindgen]
 pub struct Emulator {
     gba: GameBoyAdvance,
@@ -24,6 +28,8 @@ pub struct Emulator {
 struct Interface {
     frame: Vec<u8>,
     keyinput: u16,
+    gamepad_keyinput: u16,
+    touch_keyinput: u16,
     sample_rate: i32,
     audio_ctx: AudioContext,
     audio_ring_buffer: AudioRingBuffer,
@@ -40,6 +46,8 @@ impl Interface {
         Ok(Interface {
             frame: vec![0; 240 * 160 * 4],
             keyinput: gba_keypad::KEYINPUT_ALL_RELEASED,
+            gamepad_keyinput: gba_keypad::KEYINPUT_ALL_RELEASED,
+            touch_keyinput: gba_keypad::KEYINPUT_ALL_RELEASED,
             sample_rate: audio_ctx.sample_rate() as i32,
             audio_ctx: audio_ctx,
             audio_ring_buffer: AudioRingBuffer::new(),
@@ -47,7 +55,7 @@ impl Interface {
     }
 }
 
-impl VideoInterface for Interface {
+impl VideoSink for Interface {
     fn render(&mut self, buffer: &[u32]) {
         // TODO optimize
         for i in 0..buffer.len() {
@@ -64,7 +72,7 @@ fn convert_sample(s: i16) -> f32 {
     ((s as f32) / 32767_f32)
 }
 
-impl AudioInterface for Interface {
+impl AudioSink for Interface {
     fn get_sample_rate(&self) -> i32 {
         self.sample_rate
     }
@@ -75,9 +83,9 @@ impl AudioInterface for Interface {
     }
 }
 
-impl InputInterface for Interface {
+impl InputSource for Interface {
     fn poll(&mut self) -> u16 {
-        self.keyinput
+        self.keyinput & self.gamepad_keyinput & self.touch_keyinput
     }
 }
 
@@ -97,9 +105,7 @@ impl Emulator {
         let gba = GameBoyAdvance::new(
             bios.to_vec().into_boxed_slice(),
             gamepak,
-            interface.clone(),
-            interface.clone(),
-            interface.clone(),
+            Frontend::from_device(interface.clone()),
         );
 
         Ok(Emulator { gba, interface })
@@ -110,6 +116,7 @@ impl Emulator {
     }
 
     pub fn run_frame(&mut self, ctx: &CanvasRenderingContext2d) -> Result<(), JsValue> {
+        self.poll_gamepad()?;
         self.gba.frame();
         let mut frame_buffer = &mut self.interface.borrow_mut().frame;
         let data = web_sys::ImageData::new_with_u8_clamped_array_and_sh(
@@ -153,6 +160,58 @@ impl Emulator {
         }
     }
 
+    /// Reads the first connected gamepad, assuming the standard layout. The left stick moves
+    /// the d-pad through the core input.
+    fn poll_gamepad(&mut self) -> Result<(), JsValue> {
+        let window = web_sys::window().ok_or("no window")?;
+        let gamepad = window
+            .navigator()
+            .get_gamepads()?
+            .iter()
+            .filter_map(|gamepad| gamepad.dyn_into::<Gamepad>().ok())
+            .find(Gamepad::connected);
+
+        let (keyinput, stick) = match gamepad {
+            Some(gamepad) => {
+                let pressed: Vec<bool> = gamepad
+                    .buttons()
+                    .iter()
+                    .map(|button| button.unchecked_into::<GamepadButton>().pressed())
+                    .collect();
+                let axes: Vec<f64> = gamepad
+                    .axes()
+                    .iter()
+                    .map(|axis| axis.as_f64().unwrap_or(0.0))
+                    .collect();
+                let axis = |i: usize| {
+                    let value = axes.get(i).copied().unwrap_or(0.0).max(-1.0).min(1.0);
+                    (value * std::i16::MAX as f64) as i16
+                };
+                (standard_gamepad_to_keyinput(&pressed), (axis(0), axis(1)))
+            }
+            None => (gba_keypad::KEYINPUT_ALL_RELEASED, (0, 0)),
+        };
+
+        let changed = {
+            let mut interface = self.interface.borrow_mut();
+            let changed = interface.gamepad_keyinput != keyinput;
+            interface.gamepad_keyinput = keyinput;
+            changed
+        };
+        self.gba.input.set_analog(stick.0, stick.1);
+        if changed {
+            self.gba.input_changed();
+        }
+        Ok(())
+    }
+
+    /// Updates the keys held on the touch controls, `points` holds the x and y of every touch
+    /// in fractions of the overlay size, see `touch_layout`
+    pub fn set_touches(&mut self, points: &[f32]) {
+        self.interface.borrow_mut().touch_keyinput = touches_to_keyinput(points);
+        self.gba.input_changed();
+    }
+
     pub fn test_fps(&mut self) {
         use rustboyadvance_core::util::FpsCounter;
 
@@ -10,6 +10,7 @@ use wasm_bindgen_console_logger::DEFAULT_LOGGER;
 use rustboyadvance_core::cartridge;
 
 pub mod emulator;
+pub mod touch;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
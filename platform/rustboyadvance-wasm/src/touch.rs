@@ -0,0 +1,101 @@
+//! Built-in layout of the on-screen touch controls.
+//!
+//! The page draws the buttons of `touch_layout` over an overlay element, and reports the
+//! touches to `Emulator::set_touches`. Positions and sizes are fractions of the overlay size,
+//! so the layout scales with it.
+use wasm_bindgen::prelude::*;
+
+use js_sys::Array;
+
+use rustboyadvance_core::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+
+use bit::BitIndex;
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    key: Keys,
+    label: &'static str,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+}
+
+impl Region {
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+const fn region(key: Keys, label: &'static str, x: f32, y: f32, width: f32, height: f32) -> Region {
+    Region {
+        key,
+        label,
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+const LAYOUT: [Region; 10] = [
+    region(Keys::ButtonL, "L", 0.02, 0.02, 0.22, 0.14),
+    region(Keys::ButtonR, "R", 0.76, 0.02, 0.22, 0.14),
+    region(Keys::Up, "\u{25b2}", 0.14, 0.22, 0.12, 0.2),
+    region(Keys::Left, "\u{25c0}", 0.02, 0.42, 0.12, 0.2),
+    region(Keys::Right, "\u{25b6}", 0.26, 0.42, 0.12, 0.2),
+    region(Keys::Down, "\u{25bc}", 0.14, 0.62, 0.12, 0.2),
+    region(Keys::ButtonB, "B", 0.62, 0.5, 0.14, 0.22),
+    region(Keys::ButtonA, "A", 0.8, 0.34, 0.14, 0.22),
+    region(Keys::Select, "SELECT", 0.3, 0.86, 0.18, 0.1),
+    region(Keys::Start, "START", 0.52, 0.86, 0.18, 0.1),
+];
+
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct TouchButton {
+    region: Region,
+}
+
+#[wasm_bindgen]
+impl TouchButton {
+    pub fn label(&self) -> String {
+        self.region.label.to_string()
+    }
+
+    pub fn x(&self) -> f32 {
+        self.region.x
+    }
+
+    pub fn y(&self) -> f32 {
+        self.region.y
+    }
+
+    pub fn width(&self) -> f32 {
+        self.region.width
+    }
+
+    pub fn height(&self) -> f32 {
+        self.region.height
+    }
+}
+
+/// The buttons of the touch controls, as `TouchButton` objects
+#[wasm_bindgen]
+pub fn touch_layout() -> Array {
+    LAYOUT
+        .iter()
+        .map(|region| JsValue::from(TouchButton { region: *region }))
+        .collect()
+}
+
+/// The keys under the touch points, given as x and y pairs in overlay fractions
+pub(crate) fn touches_to_keyinput(points: &[f32]) -> u16 {
+    let mut keyinput = KEYINPUT_ALL_RELEASED;
+    for point in points.chunks_exact(2) {
+        for region in LAYOUT.iter().filter(|r| r.contains(point[0], point[1])) {
+            keyinput.set_bit(region.key as usize, false);
+        }
+    }
+    keyinput
+}